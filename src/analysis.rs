@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::{common::{AnomalyError, AnomalyLog, Args, IdBounds, InputError, SamHeader, TaxID}, filter::Mapq, gene_leaks::{GeneLeaks, MaskEntry, MaskPolicy, Panel}, id_to_label::{get_labels_map, get_lineages}, leakage::LeakageCounter, pairwise_leakage::{Leakage, NormGenes, NormalizationSchema, TinyTaxID}, placement::UnplacedCounter, reconcile::MultimapWeighting, reference::{reference_fingerprint, sam_header_keys}};
+
+/// Invalid option combinations (found by `build`) and failures while running an analysis.
+#[derive(Debug, Error)]
+pub enum AnalysisError {
+    #[error("No input given")]
+    MissingInput,
+    #[error("--threads must be at least 1")]
+    NoThreads,
+    #[error("--mask-off ({mask_off}) must be smaller than --mask-on ({mask_on})")]
+    MaskThresholds { mask_on: f64, mask_off: f64 },
+    #[error("--mask-above-percentile must lie within 0..=100, got {0}")]
+    Percentile(f64),
+    #[error("--equalize-depth must be at least 1")]
+    EqualizeDepth,
+    #[error("--equalize-depth-report requires --equalize-depth")]
+    DepthReportWithoutDepth,
+    #[error("--min_genes is no longer supported as its meaning was ambiguous: use --min-genes-remaining to never mask a species below that many genes, or --min-genes-initial to quarantine species that have fewer genes to begin with")]
+    MinGenes,
+    #[error("The analysis writes per-gene outputs and cannot run with --no-genes")]
+    NoGenes,
+    #[error("{0}")]
+    Mask(String),
+    #[error(transparent)]
+    Anomaly(#[from] AnomalyError),
+    #[error(transparent)]
+    Input(#[from] InputError),
+}
+
+/// Everything the analysis derives from a single pass over the SAM.
+pub struct AnalysisResults {
+    pub pairwise: Leakage,
+    pub normalized: HashMap<TinyTaxID, NormGenes>,
+    pub gene_leaks: GeneLeaks,
+    pub policy: MaskPolicy,
+    pub mask: Vec<MaskEntry>,
+    /// Fingerprint of the (taxid, gene) keys of the SAM header, None without such @SQ lines.
+    pub reference_fingerprint: Option<String>,
+    pub taxon_summary: HashMap<TaxID, LeakageCounter>,
+    /// Taxa absent from the label map, None without --map.
+    pub unplaced: Option<UnplacedCounter>,
+    pub anomalies: AnomalyLog,
+}
+
+/// Pairwise counting, normalization, gene leak aggregation, mask proposal and the taxon summary
+/// over one SAM. The `analyze` binary runs through this as well, and `pairwise_leakage`,
+/// `normalize_pairwise` and `mask_genes` run its stages.
+///
+/// ```
+/// use fix_gtdb_mg::{analysis::LeakageAnalysis, reconcile::MultimapWeighting};
+///
+/// let results = LeakageAnalysis::builder()
+///     .input("tests/data/leaks.sam")
+///     .min_mapq(10)
+///     .threads(2)
+///     .weighting(MultimapWeighting::Count)
+///     .run()
+///     .expect("analysis failed");
+/// println!("{} pairs, {} masked genes", results.pairwise.map.len(), results.mask.len());
+/// assert!(!results.pairwise.map.is_empty());
+/// ```
+#[derive(Debug)]
+pub struct LeakageAnalysis {
+    args: Args,
+}
+
+impl LeakageAnalysis {
+    pub fn builder() -> LeakageAnalysisBuilder {
+        LeakageAnalysisBuilder::default()
+    }
+
+    /// Validates options parsed by a binary.
+    pub fn from_args(args: Args) -> Result<Self, AnalysisError> {
+        if args.no_genes {
+            return Err(AnalysisError::NoGenes)
+        }
+        if args.min_genes.is_some() {
+            return Err(AnalysisError::MinGenes)
+        }
+        AnalysisOptions::from(&args).validate()?;
+        Ok(Self { args })
+    }
+
+    pub fn args(&self) -> &Args {
+        &self.args
+    }
+
+    /// Counts the pairs of the SAM, with the header of the SAM.
+    pub fn count_pairs(&self, anomalies: &mut AnomalyLog) -> Result<(Leakage, SamHeader), AnalysisError> {
+        Ok(Leakage::from_sam_with_header(&self.args, anomalies)?)
+    }
+
+    /// Normalizes the incoming leakage of every recipient by the outgoing totals of its donors.
+    pub fn normalize(&self, pairwise: &Leakage, anomalies: &mut AnomalyLog) -> Result<HashMap<TinyTaxID, NormGenes>, AnalysisError> {
+        let schema = NormalizationSchema::from_args(&self.args);
+        Ok(pairwise.normalize_incoming(&schema, self.args.deterministic, anomalies)?)
+    }
+
+    /// The mask policy of the options, before it has seen any gene leaks.
+    pub fn mask_policy(&self) -> Result<MaskPolicy, AnalysisError> {
+        MaskPolicy::from_args(&self.args).map_err(AnalysisError::Mask)
+    }
+
+    /// Fits `policy` to `gene_leaks`, which are annotated with their competition and whose
+    /// quarantined species and competition are reported. `baselines` computes the genus baselines
+    /// even without --mask-excess (for a baseline report).
+    pub fn fit_mask_policy(&self, policy: MaskPolicy, gene_leaks: &mut GeneLeaks, lineages: &[String], baselines: bool) -> MaskPolicy {
+        let mut policy = policy.with_distribution(gene_leaks);
+        if self.args.mask_excess.is_some() || baselines {
+            policy = policy.with_baselines(gene_leaks, lineages);
+        }
+        gene_leaks.annotate_competition(lineages);
+        gene_leaks.report_quarantined(&policy);
+        gene_leaks.report_competition(&policy);
+        policy
+    }
+
+    /// Lineages of the label map of --map by taxid, empty without a map.
+    pub fn lineages(&self) -> Result<Vec<String>, AnalysisError> {
+        match &self.args.map {
+            Some(map) => get_lineages(map).map_err(|e| AnalysisError::Mask(format!("Cannot read lineages of {}: {}", map, e))),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn run(&self) -> Result<AnalysisResults, AnalysisError> {
+        let args = &self.args;
+        let mut anomalies = AnomalyLog::from_args(args);
+
+        let (pairwise, header) = self.count_pairs(&mut anomalies)?;
+        let keys = sam_header_keys(&header, &args.name_format);
+        if keys.is_empty() {
+            eprintln!("Warning: the SAM header names no taxid_geneid sequences, the mask carries no reference fingerprint");
+        }
+        let reference_fingerprint = (!keys.is_empty()).then(|| reference_fingerprint(&keys));
+        let normalized = self.normalize(&pairwise, &mut anomalies)?;
+        let self_pairs = NormalizationSchema::from_args(args).self_pairs;
+        let mut gene_leaks = GeneLeaks::from_pairwise(&pairwise, true, self_pairs, args.deterministic).with_max_gene(IdBounds::from_args(args).max_gene);
+        if args.min_uniformity.is_some() {
+            eprintln!("Warning: the pairwise map has no positions, --min-uniformity is ignored (use mask_genes)");
+        }
+        let lineages = self.lineages()?;
+        let policy = self.fit_mask_policy(self.mask_policy()?, &mut gene_leaks, &lineages, false);
+        let mask = gene_leaks.propose_mask(&policy, &lineages);
+        let taxon_summary = pairwise.taxon_summary();
+        let unplaced = args.map.as_ref().map(|map| {
+            let id2lab = get_labels_map(map).0;
+            UnplacedCounter::from_pairs(&pairwise.map, |genes| genes.total(), &id2lab, None)
+        });
+
+        Ok(AnalysisResults { pairwise, normalized, gene_leaks, policy, mask, reference_fingerprint, taxon_summary, unplaced, anomalies })
+    }
+}
+
+/// Options of a `LeakageAnalysis` set through its builder. The defaults are those of the command
+/// line, every other option of the command line keeps its default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalysisOptions {
+    pub input: String,
+    /// More input files, read after `input` as one SAM
+    pub inputs: Vec<String>,
+    /// Label map (taxid<TAB>...<TAB>lineage), for lineages, the unplaced report and fingerprints
+    pub map: Option<String>,
+    pub min_mapq: Mapq,
+    pub threads: usize,
+    pub weighting: MultimapWeighting,
+    pub seed: u64,
+    pub deterministic: bool,
+    pub equalize_depth: Option<usize>,
+    pub equalize_depth_report: Option<String>,
+    pub min_donors_to_mask: usize,
+    pub mask_on: f64,
+    pub mask_off: Option<f64>,
+    pub previous_mask: Option<String>,
+    pub min_genes_remaining: usize,
+    pub min_genes_initial: usize,
+    pub mask_above_percentile: Option<f64>,
+    pub mask_excess: Option<f64>,
+    pub baseline_min_members: usize,
+    pub with_denominators: bool,
+    pub panel: Option<Panel>,
+    pub strict: bool,
+    pub skip_invalid: bool,
+}
+
+impl Default for AnalysisOptions {
+    fn default() -> Self {
+        Self::from(&Args::default())
+    }
+}
+
+impl From<&Args> for AnalysisOptions {
+    fn from(args: &Args) -> Self {
+        Self {
+            input: args.input.clone(),
+            inputs: args.inputs.clone(),
+            map: args.map.clone(),
+            min_mapq: args.min_mapq,
+            threads: args.threads,
+            weighting: args.multimap_weighting,
+            seed: args.seed,
+            deterministic: args.deterministic,
+            equalize_depth: args.equalize_depth,
+            equalize_depth_report: args.equalize_depth_report.clone(),
+            min_donors_to_mask: args.min_donors_to_mask,
+            mask_on: args.mask_on,
+            mask_off: args.mask_off,
+            previous_mask: args.previous_mask.clone(),
+            min_genes_remaining: args.min_genes_remaining,
+            min_genes_initial: args.min_genes_initial,
+            mask_above_percentile: args.mask_above_percentile,
+            mask_excess: args.mask_excess,
+            baseline_min_members: args.baseline_min_members,
+            with_denominators: args.with_denominators,
+            panel: args.panel.clone(),
+            strict: args.strict,
+            skip_invalid: args.skip_invalid,
+        }
+    }
+}
+
+impl AnalysisOptions {
+    /// The first invalid combination of options, if any.
+    pub fn validate(&self) -> Result<(), AnalysisError> {
+        if self.input.is_empty() && self.inputs.is_empty() {
+            return Err(AnalysisError::MissingInput)
+        }
+        if self.threads == 0 {
+            return Err(AnalysisError::NoThreads)
+        }
+        if let Some(mask_off) = self.mask_off {
+            if mask_off >= self.mask_on {
+                return Err(AnalysisError::MaskThresholds { mask_on: self.mask_on, mask_off })
+            }
+        }
+        if let Some(percentile) = self.mask_above_percentile {
+            if !(0.0..=100.0).contains(&percentile) {
+                return Err(AnalysisError::Percentile(percentile))
+            }
+        }
+        match (self.equalize_depth, &self.equalize_depth_report) {
+            (Some(0), _) => Err(AnalysisError::EqualizeDepth),
+            (None, Some(_)) => Err(AnalysisError::DepthReportWithoutDepth),
+            _ => Ok(()),
+        }
+    }
+
+    /// The command line options of the analysis, all others at their defaults.
+    fn into_args(self) -> Args {
+        Args {
+            input: self.input,
+            inputs: self.inputs,
+            map: self.map,
+            min_mapq: self.min_mapq,
+            threads: self.threads,
+            multimap_weighting: self.weighting,
+            seed: self.seed,
+            deterministic: self.deterministic,
+            equalize_depth: self.equalize_depth,
+            equalize_depth_report: self.equalize_depth_report,
+            min_donors_to_mask: self.min_donors_to_mask,
+            mask_on: self.mask_on,
+            mask_off: self.mask_off,
+            previous_mask: self.previous_mask,
+            min_genes_remaining: self.min_genes_remaining,
+            min_genes_initial: self.min_genes_initial,
+            mask_above_percentile: self.mask_above_percentile,
+            mask_excess: self.mask_excess,
+            baseline_min_members: self.baseline_min_members,
+            with_denominators: self.with_denominators,
+            panel: self.panel,
+            strict: self.strict,
+            skip_invalid: self.skip_invalid,
+            ..Args::default()
+        }
+    }
+}
+
+/// Builds a `LeakageAnalysis` from `AnalysisOptions`, all optional with the defaults of the
+/// command line.
+#[derive(Debug, Default)]
+pub struct LeakageAnalysisBuilder {
+    options: AnalysisOptions,
+}
+
+impl LeakageAnalysisBuilder {
+    pub fn input(mut self, path: impl Into<String>) -> Self {
+        self.options.input = path.into();
+        self
+    }
+
+    /// More input files, read after `input` as one SAM
+    pub fn inputs<S: Into<String>>(mut self, paths: impl IntoIterator<Item = S>) -> Self {
+        self.options.inputs = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Label map, see `AnalysisOptions::map`.
+    pub fn map(mut self, path: impl Into<String>) -> Self {
+        self.options.map = Some(path.into());
+        self
+    }
+
+    pub fn min_mapq(mut self, min_mapq: Mapq) -> Self {
+        self.options.min_mapq = min_mapq;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    /// How the alignments of multi-mapped reads are counted, see `MultimapWeighting`.
+    pub fn weighting(mut self, weighting: MultimapWeighting) -> Self {
+        self.options.weighting = weighting;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.options.seed = seed;
+        self
+    }
+
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.options.deterministic = deterministic;
+        self
+    }
+
+    pub fn equalize_depth(mut self, depth: usize) -> Self {
+        self.options.equalize_depth = Some(depth);
+        self
+    }
+
+    pub fn equalize_depth_report(mut self, path: impl Into<String>) -> Self {
+        self.options.equalize_depth_report = Some(path.into());
+        self
+    }
+
+    pub fn min_donors_to_mask(mut self, min_donors: usize) -> Self {
+        self.options.min_donors_to_mask = min_donors;
+        self
+    }
+
+    pub fn mask_on(mut self, threshold: f64) -> Self {
+        self.options.mask_on = threshold;
+        self
+    }
+
+    pub fn mask_off(mut self, threshold: f64) -> Self {
+        self.options.mask_off = Some(threshold);
+        self
+    }
+
+    pub fn previous_mask(mut self, path: impl Into<String>) -> Self {
+        self.options.previous_mask = Some(path.into());
+        self
+    }
+
+    pub fn min_genes_remaining(mut self, genes: usize) -> Self {
+        self.options.min_genes_remaining = genes;
+        self
+    }
+
+    pub fn min_genes_initial(mut self, genes: usize) -> Self {
+        self.options.min_genes_initial = genes;
+        self
+    }
+
+    pub fn mask_above_percentile(mut self, percentile: f64) -> Self {
+        self.options.mask_above_percentile = Some(percentile);
+        self
+    }
+
+    /// Mask only genes this many MADs above their genus baseline, see `GenusBaselines`.
+    pub fn mask_excess(mut self, excess: f64) -> Self {
+        self.options.mask_excess = Some(excess);
+        self
+    }
+
+    pub fn baseline_min_members(mut self, members: usize) -> Self {
+        self.options.baseline_min_members = members;
+        self
+    }
+
+    pub fn with_denominators(mut self, with_denominators: bool) -> Self {
+        self.options.with_denominators = with_denominators;
+        self
+    }
+
+    /// Marker gene panel, see `Panel`.
+    pub fn panel(mut self, panel: Panel) -> Self {
+        self.options.panel = Some(panel);
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        self
+    }
+
+    /// Skip SAM records that cannot be parsed instead of failing on the first one.
+    pub fn skip_invalid(mut self, skip_invalid: bool) -> Self {
+        self.options.skip_invalid = skip_invalid;
+        self
+    }
+
+    pub fn build(self) -> Result<LeakageAnalysis, AnalysisError> {
+        self.options.validate()?;
+        Ok(LeakageAnalysis { args: self.options.into_args() })
+    }
+
+    pub fn run(self) -> Result<AnalysisResults, AnalysisError> {
+        self.build()?.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn build(builder: LeakageAnalysisBuilder) -> Result<LeakageAnalysis, AnalysisError> {
+        builder.build()
+    }
+
+    #[test]
+    fn options_default_to_the_command_line_defaults() {
+        let parsed = Args::try_parse_from(["analyze", "--input", "in.sam"]).unwrap();
+        assert_eq!(AnalysisOptions { input: "in.sam".to_string(), ..AnalysisOptions::default() }, AnalysisOptions::from(&parsed));
+        let built = LeakageAnalysis::builder().input("in.sam").build().unwrap();
+        assert_eq!(format!("{:?}", built.args()), format!("{:?}", parsed));
+    }
+
+    #[test]
+    fn builder_sets_the_options_of_the_command_line() {
+        let parsed = Args::try_parse_from(["analyze", "--input", "a.sam", "--inputs", "b.sam", "--min_mapq", "10", "--threads", "8", "--multimap-weighting", "fraction", "--mask-on", "0.2", "--mask-off", "0.1", "--strict"]).unwrap();
+        let built = LeakageAnalysis::builder().input("a.sam").inputs(["b.sam"]).min_mapq(10).threads(8).weighting(MultimapWeighting::Fraction).mask_on(0.2).mask_off(0.1).strict(true).build().unwrap();
+        assert_eq!(format!("{:?}", built.args()), format!("{:?}", parsed));
+    }
+
+    #[test]
+    fn build_refuses_invalid_combinations() {
+        let sam = || LeakageAnalysis::builder().input("in.sam");
+        assert!(matches!(build(LeakageAnalysis::builder()), Err(AnalysisError::MissingInput)));
+        assert!(build(LeakageAnalysis::builder().inputs(["in.sam"])).is_ok());
+        assert!(matches!(build(sam().threads(0)), Err(AnalysisError::NoThreads)));
+        assert!(matches!(build(sam().mask_on(0.1).mask_off(0.1)), Err(AnalysisError::MaskThresholds { .. })));
+        assert!(build(sam().mask_on(0.1).mask_off(0.05)).is_ok());
+        assert!(matches!(build(sam().mask_above_percentile(100.5)), Err(AnalysisError::Percentile(_))));
+        assert!(build(sam().mask_above_percentile(100.0)).is_ok());
+        assert!(matches!(build(sam().equalize_depth(0)), Err(AnalysisError::EqualizeDepth)));
+        assert!(matches!(build(sam().equalize_depth_report("depth.tsv")), Err(AnalysisError::DepthReportWithoutDepth)));
+        assert!(build(sam().equalize_depth(5).equalize_depth_report("depth.tsv")).is_ok());
+    }
+
+    #[test]
+    fn from_args_refuses_what_the_analysis_cannot_run() {
+        let parse = |parts: &[&str]| Args::try_parse_from(["analyze", "--input", "in.sam"].iter().chain(parts)).unwrap();
+        assert!(matches!(LeakageAnalysis::from_args(parse(&["--no-genes"])), Err(AnalysisError::NoGenes)));
+        assert!(matches!(LeakageAnalysis::from_args(parse(&["--min_genes", "3"])), Err(AnalysisError::MinGenes)));
+        assert!(matches!(LeakageAnalysis::from_args(parse(&["--threads", "0"])), Err(AnalysisError::NoThreads)));
+        assert!(LeakageAnalysis::from_args(parse(&[])).is_ok());
+    }
+}
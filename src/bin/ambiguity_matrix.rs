@@ -0,0 +1,27 @@
+use std::io::{stdout, Write};
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, require_whole_reads, AnomalyLog, Args, DebugTaxa}, pairwise_leakage::{ambiguity_from_sam, SelfPairPolicy}, timing, utils::create_output};
+
+fn main() {
+    let args: Args = Args::parse();
+    let start = timing::start(args.timing);
+    or_exit(require_whole_reads(&args, "ambiguity_matrix"));
+    or_exit(DebugTaxa::check(&args));
+
+    let mut anomalies = AnomalyLog::from_args(&args);
+
+    let (matrix, skipped) = or_exit(ambiguity_from_sam(&args, &mut anomalies));
+    if skipped > 0 {
+        eprintln!("Skipped {} reads hitting more than {} taxa or with more than {} alignments", skipped, args.max_group_size, args.max_group_records);
+    }
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic)),
+        None => Box::new(stdout().lock()),
+    };
+    matrix.write_pairwise(SelfPairPolicy::from_args(&args), &mut writer).expect("Error writing ambiguity matrix");
+    writer.flush().expect("Error writing ambiguity matrix");
+    timing::finish(start);
+    or_exit(anomalies.finish(&args));
+}
@@ -0,0 +1,134 @@
+use std::{fs::create_dir_all, io::{BufWriter, Write}, path::{Path, PathBuf}, time::Duration};
+
+use clap::Parser;
+use fix_gtdb_mg::{analysis::LeakageAnalysis, common::{or_exit, Args, DebugTaxa, TaxID}, gene_leaks::{mask_to_v1, write_mask, write_mask_v2}, id_to_label::args_fingerprint_header, layout::{schema_header, Layout}, lock::DirLock, manifest::{Manifest, GENE_LEAKS_FILE, MASK_FILE, MASK_V1_FILE, NORMALIZED_FILE, PAIRWISE_FILE, TAXON_SUMMARY_FILE}, pairwise_leakage::{write_normalized, NormalizationSchema, SelfPairPolicy, TOTAL_BUCKETS}, timing, utils::{create_output, SafeWriter}};
+
+/// Runs pairwise counting, normalization, gene leak aggregation, mask proposal and the taxon
+/// summary in one process, reading the SAM exactly once.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct AnalyzeArgs {
+    #[command(flatten)]
+    args: Args,
+
+    /// Directory all outputs and the manifest are written into (created if missing)
+    #[arg(short = 'o', long = "output-dir")]
+    output_dir: String,
+}
+
+/// Creates an output of the current layout, led by its schema header and the map fingerprint
+/// header of --map, if given.
+fn create(dir: &Path, file: &str, fingerprint: Option<&str>, atomic: bool) -> BufWriter<SafeWriter> {
+    let path: PathBuf = dir.join(file);
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).unwrap_or_else(|e| panic!("Cannot create {}: {}", parent.display(), e));
+    }
+    let mut writer = BufWriter::new(SafeWriter::create(&path, atomic).unwrap_or_else(|e| panic!("{}", e)));
+    let output = Layout::current().by_file(file).expect("Outputs are listed in the current layout");
+    writeln!(writer, "{}", schema_header(output.schema)).unwrap_or_else(|e| panic!("Cannot write {}: {}", path.display(), e));
+    if let Some(header) = fingerprint {
+        writeln!(writer, "{}", header).unwrap_or_else(|e| panic!("Cannot write {}: {}", path.display(), e));
+    }
+    writer
+}
+
+/// Flushes an output and moves it into place, before the manifest fingerprints it.
+fn finish(writer: BufWriter<SafeWriter>, what: &str) {
+    writer.into_inner().map_err(|e| e.into_error()).and_then(SafeWriter::finish).unwrap_or_else(|e| panic!("Error writing {}: {}", what, e))
+}
+
+fn main() {
+    let AnalyzeArgs { args, output_dir } = AnalyzeArgs::parse();
+    let start = timing::start(args.timing);
+    or_exit(DebugTaxa::check(&args));
+    let dir = Path::new(&output_dir);
+    create_dir_all(dir).expect("Cannot create output directory");
+    let _lock = or_exit(DirLock::acquire(dir, Duration::from_secs(args.lock_max_age * 3600), args.force_unlock));
+    // The manifest certifies a complete run, an earlier one must not vouch for this run's outputs
+    or_exit(Manifest::invalidate(dir));
+
+    let mut manifest = Manifest::new(&args.input_files().into_iter().map(String::from).collect::<Vec<String>>());
+
+    let analysis = or_exit(LeakageAnalysis::from_args(args));
+    let results = or_exit(analysis.run());
+    let args = analysis.args();
+    let fingerprint = args_fingerprint_header(args);
+
+    // Rows of every output involving taxa absent from the map, counted before outputs are consumed
+    let unplaced_rows = results.unplaced.as_ref().map(|unplaced| {
+        let self_pairs = SelfPairPolicy::from_args(args);
+        let species = results.taxon_summary.keys().filter(|id| unplaced.is_unplaced(**id)).count();
+        [
+            (PAIRWISE_FILE, results.pairwise.map.keys().filter(|pair| self_pairs.in_output(pair) && (unplaced.is_unplaced(pair.from as TaxID) || unplaced.is_unplaced(pair.to as TaxID))).count()),
+            (NORMALIZED_FILE, results.normalized.keys().filter(|id| unplaced.is_unplaced(**id as TaxID)).count()),
+            (GENE_LEAKS_FILE, species),
+            (MASK_FILE, results.mask.iter().filter(|entry| unplaced.is_unplaced(entry.taxon)).count()),
+            (MASK_V1_FILE, mask_to_v1(&results.mask).iter().filter(|(id, _genes)| unplaced.is_unplaced(*id)).count()),
+            (TAXON_SUMMARY_FILE, species),
+        ]
+    });
+
+    eprintln!("Pair totals (reads: pairs, summed reads):");
+    for bucket in results.pairwise.total_histogram(&TOTAL_BUCKETS, SelfPairPolicy::from_args(args)) {
+        eprintln!("  {}\t{}\t{}", bucket, bucket.pairs, bucket.reads);
+        manifest.pair_totals.push((bucket.to_string(), bucket.pairs, bucket.reads));
+    }
+
+    let mut writer = create(dir, PAIRWISE_FILE, fingerprint.as_deref(), !args.no_atomic);
+    let rows = results.pairwise.write_pairwise(SelfPairPolicy::from_args(args), &mut writer).expect("Error writing pairwise leakage");
+    finish(writer, "pairwise leakage");
+    manifest.add_output(PAIRWISE_FILE, rows);
+
+    let mut writer = create(dir, NORMALIZED_FILE, fingerprint.as_deref(), !args.no_atomic);
+    let rows = write_normalized(results.normalized, &NormalizationSchema::from_args(args), &mut writer).expect("Error writing normalized leakage");
+    finish(writer, "normalized leakage");
+    manifest.add_output(NORMALIZED_FILE, rows);
+
+    let mut writer = create(dir, GENE_LEAKS_FILE, fingerprint.as_deref(), !args.no_atomic);
+    let rows = results.gene_leaks.write_report(&results.policy, &mut writer).expect("Error writing gene leaks");
+    finish(writer, "gene leaks");
+    manifest.add_output(GENE_LEAKS_FILE, rows);
+
+    let mut writer = create(dir, MASK_FILE, fingerprint.as_deref(), !args.no_atomic);
+    let rows = write_mask_v2(&results.mask, results.reference_fingerprint.as_deref(), &mut writer).expect("Error writing mask");
+    finish(writer, "mask");
+    manifest.add_output(MASK_FILE, rows);
+
+    let mut writer = create(dir, MASK_V1_FILE, fingerprint.as_deref(), !args.no_atomic);
+    let rows = write_mask(&mask_to_v1(&results.mask), &mut writer).expect("Error writing mask");
+    finish(writer, "mask");
+    manifest.add_output(MASK_V1_FILE, rows);
+
+    let mut summary = results.taxon_summary.into_iter().collect::<Vec<_>>();
+    summary.sort_by_key(|(id, _counter)| *id);
+    let mut writer = create(dir, TAXON_SUMMARY_FILE, fingerprint.as_deref(), !args.no_atomic);
+    for (id, counter) in &summary {
+        writeln!(writer, "{}\t{}", id, counter).expect("Error writing taxon summary");
+    }
+    finish(writer, "taxon summary");
+    manifest.add_output(TAXON_SUMMARY_FILE, summary.len());
+
+    for (file, rows) in unplaced_rows.into_iter().flatten() {
+        manifest.set_unplaced(file, rows);
+    }
+    match (&results.unplaced, &args.unplaced_report) {
+        (Some(unplaced), report) => {
+            eprintln!("{}", unplaced);
+            manifest.unplaced = unplaced.summary();
+            if let Some(path) = report {
+                let mut writer = or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic));
+                let rows = unplaced.write_report(&mut writer).expect("Error writing unplaced report");
+                writer.flush().expect("Error writing unplaced report");
+                eprintln!("{}\t{} unplaced taxa", path, rows);
+            }
+        },
+        (None, Some(_)) => eprintln!("Warning: --unplaced-report needs --map, no report written"),
+        (None, None) => (),
+    }
+
+    manifest.timing = timing::finish(start);
+    manifest.record_fingerprints(dir).expect("Error fingerprinting outputs");
+    manifest.write(dir, !args.no_atomic).expect("Error writing manifest");
+    or_exit(results.anomalies.finish(args));
+}
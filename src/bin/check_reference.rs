@@ -0,0 +1,99 @@
+use std::io::stdout;
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, IdBounds, NameFormat, SamReader}, id_to_label::get_labels_map, reference::{fasta_keys, read_reference_fingerprint, reference_fingerprint, sam_header_keys, KeyDifference, ReferenceCheck}, utils::open_reader};
+
+/// Checks that every header of a marker reference FASTA follows the naming convention (taxid_geneid
+/// unless --name-format says otherwise).
+/// Exits with code 1 on malformed headers, duplicate (taxid, gene) pairs, taxa without a label
+/// (with --map) or taxa with more genes than the panel (with --panel-size). With --mask, refuses a
+/// mask derived from another reference build before it is applied to this FASTA.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct CheckReferenceArgs {
+    /// Marker reference (.fasta|.fasta.gz)
+    #[arg(short = 'r', long = "reference")]
+    reference: String,
+
+    /// Expected number of marker genes per taxon
+    #[arg(long = "panel-size")]
+    panel_size: Option<usize>,
+
+    /// Label map (genome2tiid.tsv) every taxon of the reference must have a label in
+    #[arg(long = "map")]
+    map: Option<String>,
+
+    /// How taxid and gene id are read from sequence names: a delimiter or a template such as
+    /// `tid|{taxid}|g{gene}` (see pairwise_leakage --help)
+    #[arg(long = "name-format", default_value_t = NameFormat::default())]
+    name_format: NameFormat,
+
+    /// Names with a larger taxid are malformed
+    #[arg(long = "max-taxid", default_value_t = IdBounds::DEFAULT_MAX_TAXID)]
+    max_taxid: usize,
+
+    /// Offending names listed per violation class
+    #[arg(long = "examples", default_value_t = 5)]
+    examples: usize,
+
+    /// Mask (v2) to verify against the reference: its reference fingerprint must match the
+    /// (taxid, gene) keys of the FASTA
+    #[arg(long = "mask")]
+    mask: Option<String>,
+
+    /// SAM (or its header) the mask was derived from, to list the keys in only one reference on a mismatch
+    #[arg(long = "sam")]
+    sam: Option<String>,
+
+    /// Proceed on a fingerprint mismatch, reporting the keys in only one reference (with --sam)
+    #[arg(long = "force", default_value_t = false)]
+    force: bool,
+}
+
+/// Compares the reference fingerprint of the mask with the keys of the FASTA, an error on a
+/// mismatch unless `force`.
+fn check_mask(args: &CheckReferenceArgs, mask: &str) -> Result<(), String> {
+    let keys = fasta_keys(&args.reference, &args.name_format).map_err(|e| format!("Cannot read {}: {}", args.reference, e))?;
+    let expected = reference_fingerprint(&keys);
+    let Some(found) = read_reference_fingerprint(mask).map_err(|e| format!("Cannot read {}: {}", mask, e))? else {
+        eprintln!("Warning: {} has no reference fingerprint, cannot verify it was derived from {}", mask, args.reference);
+        return Ok(())
+    };
+    if found == expected {
+        return Ok(())
+    }
+    let message = format!("Reference fingerprint of {} ({}) does not match {} ({})", mask, found, args.reference, expected);
+    if !args.force {
+        return Err(format!("{}, the mask was derived from another reference build, pass --force to proceed anyway", message))
+    }
+    eprintln!("Warning: {}", message);
+    let Some(sam) = &args.sam else {
+        eprintln!("Pass --sam with the SAM the mask was derived from to list the keys in only one reference");
+        return Ok(())
+    };
+    let mut reader = SamReader::new(open_reader(sam).map_err(|e| format!("Cannot read {}: {}", sam, e))?);
+    reader.read_header().map_err(|e| format!("Cannot read {}: {}", sam, e))?;
+    let difference = KeyDifference::new(&sam_header_keys(&reader.header, &args.name_format), &keys);
+    if difference.is_empty() {
+        eprintln!("Warning: {} names the same keys as {}, it is not the SAM the mask was derived from", sam, args.reference);
+    }
+    difference.write_report("mask_reference", "fasta", &mut stdout().lock()).map_err(|e| format!("Error writing key difference: {}", e))
+}
+
+fn main() {
+    let args = CheckReferenceArgs::parse();
+
+    let id2lab = args.map.as_ref().map(|map| get_labels_map(map).0);
+    let bounds = IdBounds { max_taxid: args.max_taxid, max_gene: args.panel_size, format: args.name_format.clone() };
+    let check = or_exit(ReferenceCheck::run(&args.reference, &bounds, id2lab.as_ref(), args.examples));
+
+    check.write_report(args.panel_size, &mut stdout().lock()).expect("Error writing reference check");
+    if let Some(mask) = &args.mask {
+        or_exit(check_mask(&args, mask));
+    }
+    if check.has_violations(args.panel_size) {
+        eprintln!("Reference {} violates the naming convention", args.reference);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,68 @@
+#[cfg(feature = "tree")]
+use std::io::Write;
+
+#[cfg(feature = "tree")]
+use clap::Parser;
+#[cfg(feature = "tree")]
+use fix_gtdb_mg::{common::or_exit, tree::{load_gtdb_tree, resolve_polytomies, ResolvePolytomies, TreeHelper, TreeReport}, utils::SafeWriter};
+
+/// Reports the structure of a GTDB tree (polytomies by degree, unary nodes, zero-length branches,
+/// missing lengths) and optionally writes a binarized copy.
+#[cfg(feature = "tree")]
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct CheckTreeArgs {
+    /// Newick tree
+    #[arg(short = 't', long = "tree")]
+    tree: String,
+
+    /// Binarize polytomies with zero-length branches
+    #[arg(long = "resolve-polytomies", value_enum, default_value_t = ResolvePolytomies::None)]
+    resolve_polytomies: ResolvePolytomies,
+
+    /// Seed for --resolve-polytomies random
+    #[arg(long = "seed", default_value_t = 0)]
+    seed: u64,
+
+    /// Write the (resolved) tree as newick to this file
+    #[arg(short = 'o', long = "output")]
+    output: Option<String>,
+
+    /// Exit with code 1 unless the resulting tree is binary
+    #[arg(long = "require-binary", default_value_t = false)]
+    require_binary: bool,
+
+    /// Write the tree in place instead of to `<file>.tmp` renamed when complete
+    #[arg(long = "no-atomic", default_value_t = false)]
+    no_atomic: bool,
+}
+
+#[cfg(not(feature = "tree"))]
+fn main() {
+    eprintln!("check_tree was compiled without tree support (build with --features tree)");
+    std::process::exit(1);
+}
+
+#[cfg(feature = "tree")]
+fn main() {
+    let args = CheckTreeArgs::parse();
+
+    let (mut tree, report) = or_exit(load_gtdb_tree(&args.tree));
+    println!("{}", report);
+
+    let added = or_exit(resolve_polytomies(&mut tree, args.resolve_polytomies, args.seed));
+    if added > 0 {
+        eprintln!("Added {} zero-length branches, resolved tree:\n{}", added, TreeReport::from_tree(&tree));
+    }
+
+    if let Some(path) = &args.output {
+        let newick = tree.to_newick().unwrap_or_else(|e| panic!("Cannot write tree: {:?}", e));
+        let mut writer = or_exit(SafeWriter::create(path, !args.no_atomic));
+        writeln!(writer, "{}", newick).expect("Error writing tree");
+    }
+
+    if args.require_binary {
+        or_exit(tree.require_binary());
+    }
+}
@@ -0,0 +1,54 @@
+use std::io::{stdout, Write};
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, AnomalyLog, Args}, id_to_label::common_map_fingerprint, pairwise_leakage::{Leakage, LeakageTotals, SelfPairPolicy}, schema::check_release_mix, utils::create_output};
+
+/// Compares two pairwise tables pair by pair and gene by gene (pair totals only with --no-genes)
+/// and writes their differences. The value columns are labelled with the release tags of the
+/// table headers (before and after for untagged tables or a common tag); tagged and untagged
+/// tables are refused together, and so are tables made with different label maps.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct ComparePairwiseArgs {
+    #[command(flatten)]
+    args: Args,
+
+    /// Pairwise table of the earlier run or release
+    before: String,
+
+    /// Pairwise table of the later run or release
+    after: String,
+}
+
+fn main() {
+    let ComparePairwiseArgs { args, before, after } = ComparePairwiseArgs::parse();
+    let self_pairs = SelfPairPolicy::from_args(&args);
+    or_exit(common_map_fingerprint([before.as_str(), after.as_str()], args.ignore_map_fingerprint));
+    let mut anomalies = AnomalyLog::from_args(&args);
+
+    let (releases, differences) = if args.no_genes {
+        let (left, right) = (or_exit(LeakageTotals::read(&before, self_pairs, &mut anomalies)), or_exit(LeakageTotals::read(&after, self_pairs, &mut anomalies)));
+        ([left.release.clone(), right.release.clone()], left.diff(&right))
+    } else {
+        let (left, right) = (or_exit(Leakage::read(&before, self_pairs, &mut anomalies)), or_exit(Leakage::read(&after, self_pairs, &mut anomalies)));
+        ([left.release.clone(), right.release.clone()], left.diff(&right))
+    };
+    or_exit(check_release_mix([(before.as_str(), releases[0].as_deref()), (after.as_str(), releases[1].as_deref())]));
+    let labels = match releases {
+        [Some(left), Some(right)] if left != right => [left, right],
+        _ => ["before".to_string(), "after".to_string()],
+    };
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic)),
+        None => Box::new(stdout().lock()),
+    };
+    writeln!(writer, "pair\tfield\t{}\t{}", labels[0], labels[1]).expect("Error writing differences");
+    for difference in &differences {
+        writeln!(writer, "{}", difference).expect("Error writing differences");
+    }
+    writer.flush().expect("Error writing differences");
+    eprintln!("{} differences between {} and {}", differences.len(), labels[0], labels[1]);
+    or_exit(anomalies.finish(&args));
+}
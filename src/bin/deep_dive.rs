@@ -0,0 +1,140 @@
+use std::{collections::{BTreeSet, HashSet}, io::{stdout, Write}};
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, write_resolution_report, AnomalyLog, Args, DebugTaxa, TaxID}, id_to_label::{get_labels_map, get_lineages, lca_rank, Resolver}, pairwise_leakage::{LeakagePair, LeakageTotals, SelfPairPolicy, TinyTaxID}, placement::UnplacedCounter, schema::fmt_fixed, timing, tree::LabeledTree, utils::{create_output, file_lines, strip_cr}};
+
+/// Writes every ordered pair among a shortlist of taxa, including pairs without leakage, with
+/// reads, normalized rate, reverse reads, skew, LCA rank and tree distance. Annotations that
+/// cannot be computed (no --map, no --tree, unknown taxon) are written as NA, taxa missing from
+/// the map or tree are counted as unplaced.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct DeepDiveArgs {
+    #[command(flatten)]
+    args: Args,
+
+    /// Taxa to restrict to, one id (or label, with --map) per line
+    #[arg(long = "taxa")]
+    taxa: String,
+
+    /// --input is a pairwise table instead of a SAM
+    #[arg(long = "from-pairwise", default_value_t = false)]
+    from_pairwise: bool,
+
+    /// Newick tree with species labels as leaf names, for tree distances (needs --map)
+    #[arg(long = "tree")]
+    tree: Option<String>,
+}
+
+fn read_taxa(path: &str, resolver: Option<&Resolver>) -> Result<BTreeSet<TinyTaxID>, String> {
+    let mut taxa = BTreeSet::new();
+    for line in file_lines(path).map_err(|e| format!("Cannot read {}: {}", path, e))? {
+        let line = strip_cr(line.map_err(|e| format!("Cannot read {}: {}", path, e))?);
+        let taxon = line.trim();
+        if taxon.is_empty() || taxon.starts_with('#') { continue };
+        if let Ok(id) = taxon.parse::<TinyTaxID>() {
+            taxa.insert(id);
+            continue
+        }
+        let Some(resolver) = resolver else {
+            return Err(format!("Taxon {} in {} is not an id, labels need --map", taxon, path))
+        };
+        let id = resolver.get(taxon).ok_or_else(|| format!("Taxon {} in {} is not a label of the map (--label-normalize {})", taxon, path, resolver.mode()))?;
+        taxa.insert(id as TinyTaxID);
+    }
+    Ok(taxa)
+}
+
+fn na<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "NA".to_string(), |v| v.to_string())
+}
+
+fn main() {
+    let DeepDiveArgs { args, taxa, from_pairwise, tree } = DeepDiveArgs::parse();
+    let start = timing::start(args.timing);
+    or_exit(DebugTaxa::check(&args));
+    let mut anomalies = AnomalyLog::from_args(&args);
+
+    let resolver = args.map.as_ref().map(|map| or_exit(Resolver::from_args(&args, map)));
+    let taxa = or_exit(read_taxa(&taxa, resolver.as_ref()));
+    let normalized_joins = resolver.as_ref().map_or(0, |resolver| resolver.normalized_joins());
+    let subset: HashSet<TinyTaxID> = taxa.iter().copied().collect();
+
+    let totals = if from_pairwise {
+        let mut totals = or_exit(LeakageTotals::load(&args, &mut anomalies));
+        totals.retain_within(&subset);
+        totals
+    } else {
+        or_exit(LeakageTotals::from_sam_within(&args, &mut anomalies, &subset))
+    };
+    let self_pairs = SelfPairPolicy::from_args(&args);
+    let outgoing = totals.total_outgoing(self_pairs);
+
+    let id2lab = args.map.as_ref().map(|map| get_labels_map(map).0).unwrap_or_default();
+    let lineages = args.map.as_ref().map(|map| or_exit(get_lineages(map))).unwrap_or_default();
+    let label = |id: TinyTaxID| id2lab.get(id as TaxID).filter(|label| !label.is_empty());
+    let lineage = |id: TinyTaxID| lineages.get(id as TaxID).map(String::as_str).unwrap_or_default();
+
+    let tree = tree.map(|path| {
+        if args.map.is_none() {
+            eprintln!("Warning: --tree needs --map to find taxa among the leaves, tree distances are NA");
+        }
+        let tree = or_exit(LabeledTree::load(&path, args.label_normalize));
+        if tree.collisions() > 0 {
+            eprintln!("Warning: {} tree leaves share a normalized label with another leaf and cannot be joined", tree.collisions());
+        }
+        tree
+    });
+    let distance = |from: TinyTaxID, to: TinyTaxID| tree.as_ref()?.distance(label(from)?, label(to)?);
+
+    if args.map.is_some() {
+        let in_tree = |label: &str| tree.as_ref().is_none_or(|tree| tree.has_leaf(label));
+        let unplaced = UnplacedCounter::from_pairs(&totals.map, |total| *total, &id2lab, Some(&in_tree));
+        eprintln!("{}", unplaced);
+        if let Some(path) = &args.unplaced_report {
+            let mut writer = or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic));
+            let rows = unplaced.write_report(&mut writer).expect("Error writing unplaced report");
+            writer.flush().expect("Error writing unplaced report");
+            eprintln!("{}\t{} unplaced taxa", path, rows);
+        }
+    } else if args.unplaced_report.is_some() {
+        eprintln!("Warning: --unplaced-report needs --map, no report written");
+    }
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic)),
+        None => Box::new(stdout().lock()),
+    };
+    writeln!(writer, "{}", args.label_normalize.header()).expect("Error writing deep dive");
+    writeln!(writer, "{}", self_pairs).expect("Error writing deep dive");
+    writeln!(writer, "from\tto\tfrom_label\tto_label\treads\trate\treverse_reads\tskew\tlca_rank\ttree_distance").expect("Error writing deep dive");
+
+    for from in &taxa {
+        for to in taxa.iter().filter(|to| *to != from) {
+            let reads = totals.map.get(&LeakagePair::from(*from, *to)).copied().unwrap_or(0);
+            let reverse = totals.map.get(&LeakagePair::from(*to, *from)).copied().unwrap_or(0);
+            let rate = outgoing.get(from).filter(|total| **total > 0).map(|total| fmt_fixed(reads as f64 / *total as f64));
+            let skew = (reads + reverse > 0).then(|| fmt_fixed((reads as f64 - reverse as f64) / (reads + reverse) as f64));
+
+            writeln!(writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                from, to, na(label(*from)), na(label(*to)),
+                reads, na(rate), reverse, na(skew),
+                na(lca_rank(lineage(*from), lineage(*to))),
+                na(distance(*from, *to).map(fmt_fixed))).expect("Error writing deep dive");
+        }
+    }
+    writer.flush().expect("Error writing deep dive");
+
+    let normalized_joins = normalized_joins + tree.as_ref().map_or(0, |tree| tree.normalized_joins());
+    if normalized_joins > 0 {
+        eprintln!("{} label joins succeeded only after --label-normalize {}", normalized_joins, args.label_normalize);
+    }
+    timing::finish(start);
+    if let Some(resolver) = &resolver {
+        // --debug-taxon labels were resolved by the SAM pass too, this report replaces its own
+        args.debug_taxon.iter().filter(|taxon| taxon.parse::<TaxID>().is_err()).for_each(|taxon| { resolver.resolve(taxon); });
+        or_exit(write_resolution_report(resolver, &args));
+    }
+    or_exit(anomalies.finish(&args));
+}
@@ -0,0 +1,27 @@
+use std::io::{stdout, Write};
+
+use clap::{CommandFactory, Parser};
+use fix_gtdb_mg::{common::Args, defaults::{option_docs, write_option_json, write_option_table}};
+
+/// Prints every shared option with its default, type and description, read from the clap
+/// definitions, so a run's settings can be told apart from the defaults. Options of a single
+/// tool are listed by its --help.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct DefaultsArgs {
+    /// Print JSON instead of a table, hidden options included
+    #[arg(long = "json", default_value_t = false)]
+    json: bool,
+}
+
+fn main() {
+    let args = DefaultsArgs::parse();
+    let docs = option_docs(&Args::command());
+
+    let mut writer = stdout().lock();
+    match args.json {
+        true => write_option_json(&docs, &mut writer),
+        false => write_option_table(&docs, &mut writer),
+    }.expect("Error writing defaults");
+    writer.flush().expect("Error writing defaults");
+}
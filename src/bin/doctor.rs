@@ -0,0 +1,36 @@
+use clap::Parser;
+use fix_gtdb_mg::{common::or_exit, doctor::{audit, ResultsDir}};
+
+/// Audits a results directory written by `analyze`: every output listed in the manifest must
+/// exist and pass its checks (row counts, schema headers, cross-file sums). Directories of an
+/// earlier layout are checked under their own file names, with a hint to migrate them. Prints one
+/// line per check and exits with code 1 if any check fails.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct DoctorArgs {
+    /// Results directory containing manifest.json
+    #[arg(short = 'd', long = "dir")]
+    dir: String,
+}
+
+fn main() {
+    let args = DoctorArgs::parse();
+
+    let results = or_exit(ResultsDir::open(&args.dir));
+    if results.needs_migration() {
+        eprintln!("Warning: {} has layout {}, upgrade it with migrate_results --dir {}", args.dir, results.layout.version, args.dir);
+    }
+    let report = audit(&results);
+
+    println!("file\tcheck\tstatus\tdetails");
+    for result in &report {
+        println!("{}", result);
+    }
+
+    let failed = report.iter().filter(|r| !r.passed()).count();
+    if failed > 0 {
+        eprintln!("{} of {} checks failed", failed, report.len());
+        std::process::exit(1)
+    }
+}
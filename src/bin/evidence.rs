@@ -0,0 +1,369 @@
+use std::{collections::{BTreeMap, HashMap, VecDeque}, fs::create_dir_all, io::Write, path::{Path, PathBuf}, time::Duration};
+
+use clap::Parser;
+use fix_gtdb_mg::{analysis::LeakageAnalysis, common::{or_exit, TaxID}, gene_leaks::{read_mask_entries, MaskEntry}, id_to_label::{closest_labels, IdLabels, LabelMap, LabelNormalize, Resolver}, lock::{DirLock, DEFAULT_LOCK_MAX_AGE_HOURS}, manifest::{GENE_LEAKS_FILE, MASK_FILE, NORMALIZED_FILE, PAIRWISE_FILE, TAXON_SUMMARY_FILE}, pairwise_leakage::{write_normalized, NormalizationSchema, PairSchema, SelfPairPolicy}, tree::{LabeledTree, ResolvePolytomies}, utils::{file_lines, SafeWriter}};
+
+/// Gathers everything a results directory of `analyze` holds about one taxon into a folder of
+/// small TSVs: read counters, incoming and outgoing pairs, per-gene leak profile, gene
+/// completeness, leakage cluster, proposed mask and, with a tree, the leaves of the sister clade.
+/// Without a results directory the tables are computed from the SAM as `analyze` would write them.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct EvidenceArgs {
+    /// Results directory written by analyze
+    #[arg(short = 'd', long = "dir", required_unless_present = "input", conflicts_with = "input")]
+    dir: Option<String>,
+
+    /// SAM (or compressed) to compute the tables from instead of reading --dir, with the default
+    /// options of analyze
+    #[arg(short = 'i', long = "input")]
+    input: Option<String>,
+
+    /// More input files, read after --input as one SAM
+    #[arg(long = "inputs", num_args = 1.., requires = "input")]
+    inputs: Vec<String>,
+
+    /// Taxon id, or label with --map
+    #[arg(long = "taxon")]
+    taxon: String,
+
+    /// Folder the evidence tables are written into (created if missing)
+    #[arg(short = 'o', long = "output-dir")]
+    output_dir: String,
+
+    /// Label map (genome2tiid.tsv) for labels and label lookup
+    #[arg(long = "map")]
+    map: Option<String>,
+
+    /// Newick tree with species labels as leaf names, for tree neighbors (needs --map)
+    #[arg(long = "tree")]
+    tree: Option<String>,
+
+    /// Binarize polytomies of --tree with zero-length branches, sisters are only defined in a
+    /// binary tree
+    #[arg(long = "resolve-polytomies", value_enum, default_value_t = ResolvePolytomies::None)]
+    resolve_polytomies: ResolvePolytomies,
+
+    /// Seed for --resolve-polytomies random
+    #[arg(long = "seed", default_value_t = 0)]
+    seed: u64,
+
+    /// Normalization applied to labels before joining
+    #[arg(long = "label-normalize", value_enum, default_value_t = LabelNormalize::None)]
+    label_normalize: LabelNormalize,
+
+    /// GTDB metadata (bac120/ar53_metadata.tsv) resolving species labels the map lacks
+    #[arg(long = "metadata")]
+    metadata: Option<String>,
+
+    /// Alias table, `alias<TAB>label` per line, resolving labels through the map or metadata
+    #[arg(long = "aliases")]
+    aliases: Option<String>,
+
+    /// Pairs with at least this many reads (in either direction) link two taxa into one leakage
+    /// cluster
+    #[arg(long = "cluster-min-reads", default_value_t = 1)]
+    cluster_min_reads: u64,
+
+    /// Also write index.html showing every table of the folder on one page
+    #[arg(long = "html", default_value_t = false)]
+    html: bool,
+
+    /// Write how --taxon and the tree neighbors were resolved to ids
+    #[arg(long = "resolution-report")]
+    resolution_report: Option<String>,
+
+    /// Write the tables in place instead of to `<file>.tmp` renamed when complete
+    #[arg(long = "no-atomic", default_value_t = false)]
+    no_atomic: bool,
+
+    /// Take over the lock of --output-dir held by another run
+    #[arg(long = "force-unlock", default_value_t = false)]
+    force_unlock: bool,
+
+    /// Take over the lock of --output-dir once it is older than this many hours, or at once if
+    /// it was left by a process of this host that is gone
+    #[arg(long = "lock-max-age", value_name = "HOURS", default_value_t = DEFAULT_LOCK_MAX_AGE_HOURS)]
+    lock_max_age: u64,
+}
+
+/// Rows of a table, `#` headers and empty lines skipped.
+fn rows(lines: impl Iterator<Item = String>) -> Vec<Vec<String>> {
+    lines.filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split('\t').map(str::to_string).collect())
+        .collect()
+}
+
+/// Rows of a table written into a buffer.
+fn rendered(write: impl FnOnce(&mut Vec<u8>) -> std::io::Result<usize>) -> Vec<Vec<String>> {
+    let mut buffer = Vec::new();
+    write(&mut buffer).expect("Writing into memory cannot fail");
+    rows(String::from_utf8(buffer).expect("Tables are UTF-8").lines().map(str::to_string))
+}
+
+/// The tables the evidence is gathered from.
+struct Tables {
+    schema: PairSchema,
+    pairwise: Vec<Vec<String>>,
+    normalized: Vec<Vec<String>>,
+    gene_leaks: Vec<Vec<String>>,
+    summary: Vec<Vec<String>>,
+    mask: Vec<MaskEntry>,
+}
+
+impl Tables {
+    /// Reads the tables of a results directory, under their names in its layout.
+    fn read(dir: &str) -> Self {
+        let table = |file: &str| {
+            let lines = or_exit(file_lines(Path::new(dir).join(file)));
+            rows(lines.map(or_exit))
+        };
+        let lines = or_exit(file_lines(Path::new(dir).join(PAIRWISE_FILE)));
+        let header = lines.map(or_exit).take_while(|line| line.starts_with('#')).find_map(|line| PairSchema::parse(&line));
+        Self {
+            schema: header.map_or(PairSchema::default(), or_exit),
+            pairwise: table(PAIRWISE_FILE),
+            normalized: table(NORMALIZED_FILE),
+            gene_leaks: table(GENE_LEAKS_FILE),
+            summary: table(TAXON_SUMMARY_FILE),
+            mask: or_exit(read_mask_entries(Path::new(dir).join(MASK_FILE))),
+        }
+    }
+
+    /// Runs the analysis of `analyze` on the SAM and renders its tables as it writes them.
+    fn compute(input: &str, inputs: &[String], map: Option<&str>) -> Self {
+        let mut builder = LeakageAnalysis::builder().input(input).inputs(inputs.iter().cloned()).deterministic(true);
+        if let Some(map) = map {
+            builder = builder.map(map);
+        }
+        let analysis = or_exit(builder.build());
+        let mut results = or_exit(analysis.run());
+        let args = analysis.args();
+        let self_pairs = SelfPairPolicy::from_args(args);
+        let normalized = std::mem::take(&mut results.normalized);
+        let tables = Self {
+            schema: results.pairwise.schema,
+            pairwise: rendered(|writer| results.pairwise.write_pairwise(self_pairs, writer)),
+            normalized: rendered(|writer| write_normalized(normalized, &NormalizationSchema::from_args(args), writer)),
+            gene_leaks: rendered(|writer| results.gene_leaks.write_report(&results.policy, writer)),
+            summary: results.taxon_summary.iter().map(|(id, counter)| format!("{}\t{}", id, counter).split('\t').map(str::to_string).collect()).collect(),
+            mask: std::mem::take(&mut results.mask),
+        };
+        or_exit(results.anomalies.finish(args));
+        tables
+    }
+}
+
+/// The evidence folder, with every table written so far for the HTML page.
+struct Evidence {
+    dir: PathBuf,
+    atomic: bool,
+    tables: Vec<(String, String, Vec<String>)>,
+}
+
+impl Evidence {
+    fn write_table(&mut self, file: &str, header: &str, rows: Vec<String>) {
+        let path: PathBuf = self.dir.join(file);
+        let mut writer = std::io::BufWriter::new(or_exit(SafeWriter::create(&path, self.atomic)));
+        writeln!(writer, "{}", header).expect("Error writing evidence");
+        for row in &rows {
+            writeln!(writer, "{}", row).expect("Error writing evidence");
+        }
+        or_exit(writer.into_inner().map_err(|e| e.into_error()).and_then(SafeWriter::finish));
+        println!("{}\t{}", file, rows.len());
+        self.tables.push((file.to_string(), header.to_string(), rows));
+    }
+
+    /// Writes index.html with one HTML table per TSV, in the order they were written.
+    fn write_html(&self, title: &str) {
+        let cells = |line: &str, tag: &str| line.split('\t').map(|cell| format!("<{}>{}</{}>", tag, escape_html(cell), tag)).collect::<String>();
+        let mut writer = std::io::BufWriter::new(or_exit(SafeWriter::create(self.dir.join("index.html"), self.atomic)));
+        let mut write = || -> std::io::Result<()> {
+            writeln!(writer, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>", escape_html(title))?;
+            writeln!(writer, "<style>table {{ border-collapse: collapse; }} td, th {{ border: 1px solid #ccc; padding: 2px 6px; }}</style>\n</head>\n<body>\n<h1>{}</h1>", escape_html(title))?;
+            for (file, header, rows) in &self.tables {
+                writeln!(writer, "<h2><a href=\"{}\">{}</a> ({} rows)</h2>\n<table>\n<tr>{}</tr>", escape_html(file), escape_html(file), rows.len(), cells(header, "th"))?;
+                for row in rows {
+                    writeln!(writer, "<tr>{}</tr>", cells(row, "td"))?;
+                }
+                writeln!(writer, "</table>")?;
+            }
+            writeln!(writer, "</body>\n</html>")
+        };
+        write().expect("Error writing evidence page");
+        or_exit(writer.into_inner().map_err(|e| e.into_error()).and_then(SafeWriter::finish));
+        println!("index.html\t{}", self.tables.len());
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// The taxa linked to `taxon` through pairs of at least `min_reads` reads (summed over both
+/// directions), with their number of links from it and the reads they exchange with it directly.
+fn leakage_cluster(pairwise: &[Vec<String>], taxon: TaxID, min_reads: u64) -> Vec<(TaxID, usize, u64)> {
+    let mut reads: HashMap<(TaxID, TaxID), u64> = HashMap::new();
+    for row in pairwise {
+        let (Ok(from), Ok(to), Ok(total)) = (row[0].parse::<TaxID>(), row[1].parse::<TaxID>(), row[2].parse::<f64>()) else { continue };
+        if from != to {
+            *reads.entry((from.min(to), from.max(to))).or_default() += total.round() as u64;
+        }
+    }
+    let mut links: BTreeMap<TaxID, Vec<TaxID>> = BTreeMap::new();
+    for ((a, b), _reads) in reads.iter().filter(|(_pair, reads)| **reads >= min_reads) {
+        links.entry(*a).or_default().push(*b);
+        links.entry(*b).or_default().push(*a);
+    }
+
+    let mut hops = BTreeMap::from([(taxon, 0)]);
+    let mut queue = VecDeque::from([taxon]);
+    while let Some(member) = queue.pop_front() {
+        for next in links.get(&member).into_iter().flatten() {
+            if !hops.contains_key(next) {
+                hops.insert(*next, hops[&member] + 1);
+                queue.push_back(*next);
+            }
+        }
+    }
+    let mut result = hops.into_iter()
+        .map(|(member, hops)| (member, hops, reads.get(&(member.min(taxon), member.max(taxon))).copied().unwrap_or(0)))
+        .collect::<Vec<_>>();
+    result.sort_by_key(|(member, hops, _reads)| (*hops, *member));
+    result
+}
+
+fn resolve_taxon(taxon: &str, id2lab: Option<&IdLabels>, resolver: Option<&Resolver>) -> Result<TaxID, String> {
+    if let Ok(id) = taxon.parse::<TaxID>() {
+        return Ok(id)
+    }
+    let (Some(id2lab), Some(resolver)) = (id2lab, resolver) else {
+        return Err(format!("Taxon {} is not an id, labels need --map", taxon))
+    };
+    resolver.get(taxon).ok_or_else(|| {
+        format!("Taxon {} is not a label of the map (--label-normalize {}), closest labels: {}", taxon, resolver.mode(), closest_labels(taxon, id2lab.values(), 5).join(", "))
+    })
+}
+
+fn write_resolution_report(path: &str, resolver: &Resolver, atomic: bool) {
+    let mut writer = std::io::BufWriter::new(or_exit(SafeWriter::create(path, atomic)));
+    let rows = resolver.write_report(&mut writer).and_then(|rows| writer.flush().map(|_| rows)).expect("Error writing resolution report");
+    eprintln!("{}\t{} labels resolved through aliases, placeholders or conflicting sources", path, rows);
+}
+
+fn main() {
+    let args = EvidenceArgs::parse();
+
+    let (id2lab, resolver) = match &args.map {
+        Some(map) => {
+            let map = LabelMap::read(map);
+            let resolver = or_exit(Resolver::load(args.label_normalize, &map, args.metadata.as_deref(), args.aliases.as_deref()));
+            (Some(map.id2lab), Some(resolver))
+        },
+        None => (None, None),
+    };
+    let taxon = or_exit(resolve_taxon(&args.taxon, id2lab.as_ref(), resolver.as_ref()));
+    let label = |id: &str| -> String {
+        id.parse::<TaxID>().ok()
+            .and_then(|id| id2lab.as_ref()?.get(id).filter(|label| !label.is_empty()).cloned())
+            .unwrap_or_else(|| "NA".to_string())
+    };
+    let is_taxon = |id: &str| id.parse::<TaxID>().is_ok_and(|id| id == taxon);
+
+    let (tables, source) = match (&args.dir, &args.input) {
+        (Some(dir), _) => (Tables::read(dir), dir.clone()),
+        (None, Some(input)) => (Tables::compute(input, &args.inputs, args.map.as_deref()), input.clone()),
+        (None, None) => unreachable!("clap requires --dir or --input"),
+    };
+
+    let summary = tables.summary.iter().filter(|row| is_taxon(&row[0])).collect::<Vec<_>>();
+    if summary.is_empty() {
+        eprintln!("Taxon {} ({}) has no reads in {}", taxon, label(&taxon.to_string()), source);
+        std::process::exit(1);
+    }
+
+    let out = Path::new(&args.output_dir);
+    or_exit(create_dir_all(out));
+    let _lock = or_exit(DirLock::acquire(out, Duration::from_secs(args.lock_max_age * 3600), args.force_unlock));
+    let mut evidence = Evidence { dir: out.to_path_buf(), atomic: !args.no_atomic, tables: Vec::new() };
+
+    let schema = &tables.schema;
+    let summary_header = format!("taxon\tlabel\ttotal\tcorrect\tcorrect_frac\tout_incorrect\tout_frac\tin_incorrect\tin_frac{}", if schema.unmapped { "\tunmapped" } else { "" });
+    evidence.write_table("summary.tsv", &summary_header,
+        summary.iter().map(|row| format!("{}\t{}\t{}", row[0], label(&row[0]), row[1..].join("\t"))).collect());
+
+    let pairwise = &tables.pairwise;
+    let first_gene = schema.first_gene();
+    let genes = |row: &[String]| row[first_gene..].iter().filter(|count| *count != "-1").count();
+    let pairs = |partner: usize, own: usize| {
+        let mut rows = pairwise.iter()
+            .filter(|row| is_taxon(&row[own]) && !is_taxon(&row[partner]))
+            .map(|row| (row[2].parse::<u64>().unwrap_or(0), format!("{}\t{}\t{}\t{}", row[partner], label(&row[partner]), row[2], genes(row))))
+            .collect::<Vec<(u64, String)>>();
+        rows.sort_by(|a, b| b.cmp(a));
+        rows.into_iter().map(|(_reads, row)| row).collect::<Vec<String>>()
+    };
+    evidence.write_table("incoming.tsv", "donor\tdonor_label\treads\tgenes", pairs(0, 1));
+    evidence.write_table("outgoing.tsv", "recipient\trecipient_label\treads\tgenes", pairs(1, 0));
+
+    // Reads per gene of the taxon's own reads: assigned to itself (correct) or to other taxa.
+    let mut completeness: Vec<(u64, u64)> = Vec::new();
+    for row in pairwise.iter().filter(|row| is_taxon(&row[0])) {
+        for (gene, count) in row[first_gene..].iter().enumerate() {
+            let Ok(count) = count.parse::<u64>() else { continue };
+            if gene >= completeness.len() {
+                completeness.resize(gene + 1, (0, 0));
+            }
+            match is_taxon(&row[1]) {
+                true => completeness[gene].0 += count,
+                false => completeness[gene].1 += count,
+            }
+        }
+    }
+    evidence.write_table("completeness.tsv", "gene\tcorrect_reads\tleaked_reads",
+        completeness.iter().enumerate().map(|(gene, (correct, leaked))| format!("{}\t{}\t{}", gene + 1, correct, leaked)).collect());
+
+    let profile = tables.gene_leaks.iter().filter(|row| is_taxon(&row[0])).collect::<Vec<_>>();
+    let gene_columns = profile.iter().map(|row| row.len().saturating_sub(4)).max().unwrap_or(0);
+    let header = format!("taxon\tgood_genes\tleaked_on_genes\tmetric{}", (1..=gene_columns).map(|gene| format!("\tgene_{}", gene)).collect::<String>());
+    evidence.write_table("gene_profile.tsv", &header, profile.iter().map(|row| row.join("\t")).collect());
+
+    let normalized = tables.normalized.iter().filter(|row| is_taxon(&row[0])).collect::<Vec<_>>();
+    evidence.write_table("normalized.tsv", "recipient\ttotal\tgenes", normalized.iter().map(|row| format!("{}\t{}\t{}", row[0], row[1], row[2..].join(","))).collect());
+
+    let cluster = leakage_cluster(pairwise, taxon, args.cluster_min_reads);
+    evidence.write_table("cluster.tsv", "taxon\tlabel\thops\treads_with_taxon",
+        cluster.iter().map(|(member, hops, reads)| format!("{}\t{}\t{}\t{}", member, label(&member.to_string()), hops, reads)).collect());
+
+    let mask = tables.mask.iter().filter(|entry| entry.taxon == taxon).collect::<Vec<_>>();
+    evidence.write_table("mask.tsv", &MaskEntry::COLUMNS.join("\t"), mask.iter().map(|entry| entry.to_string()).collect());
+
+    if let Some(path) = &args.tree {
+        let mut tree = or_exit(LabeledTree::load(path, args.label_normalize));
+        eprintln!("Tree {}:\n{}", path, tree.report());
+        let added = or_exit(tree.resolve_polytomies(args.resolve_polytomies, args.seed));
+        if added > 0 {
+            eprintln!("Added {} zero-length branches resolving polytomies", added);
+        }
+        let own = label(&taxon.to_string());
+        match or_exit(tree.sister_leaves(&own)) {
+            Some(sisters) => {
+                let rows = sisters.into_iter().map(|(name, distance)| {
+                    let id = resolver.as_ref().and_then(|resolver| resolver.get(&name)).map_or("NA".to_string(), |id| id.to_string());
+                    format!("{}\t{}\t{}", id, name, distance.map_or("NA".to_string(), |d| d.to_string()))
+                }).collect::<Vec<String>>();
+                evidence.write_table("tree_neighbors.tsv", "taxon\tlabel\tdistance", rows);
+            },
+            None => eprintln!("Taxon {} ({}) is not a leaf of {}, no tree neighbors written", taxon, own, path),
+        }
+    }
+
+    if args.html {
+        evidence.write_html(&format!("Leakage evidence for {} ({})", taxon, label(&taxon.to_string())));
+    }
+
+    if let (Some(path), Some(resolver)) = (&args.resolution_report, &resolver) {
+        write_resolution_report(path, resolver, !args.no_atomic);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{collections::HashSet, io::{BufWriter, Write}};
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, GeneID, NameFormat}, gene_leaks::Panel, mask_import::{mask_changes, write_mask_changes, CuratedMask}, reference::{fasta_keys, read_reference_fingerprint, reference_fingerprint}, utils::SafeWriter};
+
+/// Re-ingests a v2 mask edited by curators: rows are validated against the panel and the
+/// reference, extra columns (e.g. comments) are kept verbatim, invalid rows go to a rejects file
+/// instead of failing the import, and the changes to the original mask are reported.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct ImportMaskArgs {
+    /// Edited v2 mask
+    #[arg(short = 'i', long = "input")]
+    input: String,
+
+    /// Mask the edits were made to, its reference fingerprint must match the edited one
+    #[arg(long = "original")]
+    original: String,
+
+    /// Validated mask, sorted by taxon and gene
+    #[arg(long = "output")]
+    output: String,
+
+    /// Rows added, removed and changed relative to --original
+    #[arg(long = "changes")]
+    changes: String,
+
+    /// Rows failing validation with the reason
+    #[arg(long = "rejects")]
+    rejects: String,
+
+    /// Marker reference (.fasta|.fasta.gz) of the mask: its fingerprint must match and every
+    /// row must name one of its (taxid, gene) keys
+    #[arg(short = 'r', long = "reference")]
+    reference: Option<String>,
+
+    /// How taxid and gene id are read from sequence names: a delimiter or a template such as
+    /// `tid|{taxid}|g{gene}` (see pairwise_leakage --help)
+    #[arg(long = "name-format", default_value_t = NameFormat::default())]
+    name_format: NameFormat,
+
+    /// Marker gene panel, an embedded one (bac120, ar53) or a panel.tsv, rows must name one of its genes
+    #[arg(long = "panel", value_parser = Panel::parse_arg)]
+    panel: Option<Panel>,
+
+    /// Number of marker genes, rows must name one of them unless --panel is given
+    #[arg(long = "n-genes")]
+    n_genes: Option<usize>,
+
+    /// First gene id of the panel
+    #[arg(long = "gene-id-base", default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=1))]
+    gene_id_base: u8,
+}
+
+fn create(path: &str) -> BufWriter<SafeWriter> {
+    BufWriter::new(or_exit(SafeWriter::create(path, true)))
+}
+
+fn main() {
+    let args = ImportMaskArgs::parse();
+    let base = args.gene_id_base as GeneID;
+
+    let original = or_exit(CuratedMask::read(&args.original).map_err(|e| format!("Cannot read {}: {}", args.original, e)));
+    if let Some(reject) = original.rejects.first() {
+        or_exit(Err(format!("{} line {}: {}", args.original, reject.line, reject.reason)))
+    }
+    let mut curated = or_exit(CuratedMask::read(&args.input).map_err(|e| format!("Cannot read {}: {}", args.input, e)));
+    if curated.reference != original.reference {
+        or_exit(Err(format!("Reference fingerprint of {} ({}) differs from {} ({}), the edits were made to a mask of another reference",
+            args.input, curated.reference.as_deref().unwrap_or("none"), args.original, original.reference.as_deref().unwrap_or("none"))))
+    }
+
+    let keys = args.reference.as_ref().map(|path| or_exit(fasta_keys(path, &args.name_format).map_err(|e| format!("Cannot read {}: {}", path, e))));
+    if let (Some(path), Some(keys)) = (&args.reference, &keys) {
+        let expected = reference_fingerprint(keys);
+        match or_exit(read_reference_fingerprint(&args.input)) {
+            Some(found) if found != expected => or_exit(Err(format!("Reference fingerprint of {} ({}) does not match {} ({})", args.input, found, path, expected))),
+            Some(_) => (),
+            None => {
+                eprintln!("Warning: {} has no reference fingerprint, recording that of {}", args.input, path);
+                curated.reference = Some(expected);
+            },
+        }
+    }
+
+    let genes = match (&args.panel, args.n_genes) {
+        (Some(panel), _) => Some(panel.gene_ids(base).into_iter().collect::<HashSet<GeneID>>()),
+        (None, Some(n)) => Some((base..base + n).collect()),
+        (None, None) => None,
+    };
+    if genes.is_none() {
+        eprintln!("Warning: no --panel or --n-genes, gene ids are not validated");
+    }
+    curated.validate(genes.as_ref(), keys.as_ref());
+
+    let originals = original.rows.iter().map(|row| row.entry.clone()).collect::<Vec<_>>();
+    let changes = mask_changes(&originals, &curated.rows);
+
+    let mut writer = create(&args.output);
+    let rows = curated.write(&mut writer).expect("Error writing mask");
+    writer.flush().expect("Error writing mask");
+    eprintln!("{}\t{} rows", args.output, rows);
+
+    let mut writer = create(&args.changes);
+    let rows = write_mask_changes(&changes, &mut writer).expect("Error writing mask changes");
+    writer.flush().expect("Error writing mask changes");
+    eprintln!("{}\t{} changes", args.changes, rows);
+
+    let mut writer = create(&args.rejects);
+    let rows = curated.write_rejects(&mut writer).expect("Error writing rejects");
+    writer.flush().expect("Error writing rejects");
+    eprintln!("{}\t{} rejected rows", args.rejects, rows);
+}
@@ -0,0 +1,58 @@
+use std::io::Write;
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, require_whole_reads, AnomalyLog, Args}, gene_leaks::read_mask, tracks::{write_mask_bed, LeakCoverage}, timing, utils::create_output};
+
+/// Exports leakage along the marker reference for a genome browser: a bedGraph of the depth of
+/// reads from other taxa on every reference sequence and, with --mask, a BED of the masked genes.
+/// Both use the @SQ lengths of the SAM, sequences without leaked reads or masked genes are absent.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct LeakTracksArgs {
+    #[command(flatten)]
+    args: Args,
+
+    /// bedGraph of leaked read depth per reference sequence
+    #[arg(long = "bedgraph")]
+    bedgraph: String,
+
+    /// Mask written by mask_genes or analyze, exported as whole-gene intervals
+    #[arg(long = "mask")]
+    mask: Option<String>,
+
+    /// BED of the masked genes (needs --mask)
+    #[arg(long = "mask-bed")]
+    mask_bed: Option<String>,
+}
+
+fn main() {
+    let LeakTracksArgs { args, bedgraph, mask, mask_bed } = LeakTracksArgs::parse();
+    let start = timing::start(args.timing);
+    or_exit(require_whole_reads(&args, "leak_tracks"));
+    let mut anomalies = AnomalyLog::from_args(&args);
+
+    let coverage = or_exit(LeakCoverage::from_sam(&args, &mut anomalies));
+    if coverage.header.sequences.is_empty() {
+        eprintln!("Warning: {} has no @SQ lines, no coordinates can be checked and the tracks are empty", args.input_label());
+    }
+
+    let mut writer = or_exit(create_output(&bedgraph, args.threads, args.compression_level, !args.no_atomic));
+    let rows = coverage.write_bedgraph(&mut writer).expect("Error writing bedGraph");
+    writer.flush().expect("Error writing bedGraph");
+    eprintln!("{}\t{} intervals", bedgraph, rows);
+
+    match (mask, mask_bed) {
+        (Some(mask), Some(path)) => {
+            let mask = or_exit(read_mask(&mask));
+            let mut writer = or_exit(create_output(&path, args.threads, args.compression_level, !args.no_atomic));
+            let rows = write_mask_bed(&mask, &coverage.header, &args.name_format, &mut writer).expect("Error writing mask BED");
+            writer.flush().expect("Error writing mask BED");
+            eprintln!("{}\t{} intervals", path, rows);
+        },
+        (None, Some(_)) => eprintln!("Warning: --mask-bed needs --mask, no mask BED written"),
+        _ => (),
+    }
+    timing::finish(start);
+    or_exit(anomalies.finish(&args));
+}
@@ -1,262 +1,211 @@
-
-use std::{collections::HashMap, fmt::Display, process::exit};
-
-use clap::Parser;
-use fix_gtdb_mg::common::{sam_file_iterator, taxid_geneid, Args, GeneID, TaxID};
-
-
-
-
-
-
-#[derive(Default)]
-pub struct Leaks {
-    pub correct: f64,
-    pub incoming: f64,
-    pub outgoing: f64,
+use std::io::{stdout, Write};
+
+use clap::{Parser, ValueEnum};
+use fix_gtdb_mg::{analysis::LeakageAnalysis, common::{or_exit, require_genes, require_whole_reads, AnomalyLog, Args, GeneID, TaxID}, gene_leaks::{get_normalized_gene_leaks, get_normalized_gene_leaks_by_read_group, get_species_total, get_species_total_by_read_group, GeneLeaks, MarkerNames, MaskPolicy}, id_to_label::{args_fingerprint_header, get_labels_map, get_lineages}, taxonomy::Rank, timing, utils::{create_output, part_path, SafeWriter}};
+
+/// What the report is sharded by.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum ShardKey {
+    /// Taxid modulo the number of shards
+    #[value(name = "taxid")]
+    Taxid,
+    /// First letter of the genus (needs --map), taxid for taxa without a genus
+    #[value(name = "genus")]
+    Genus,
 }
 
-#[derive(Default)]
-pub struct Species {
-    pub id: TaxID,
-    pub leaks: Vec<Option<Leaks>>,
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct MaskGenesArgs {
+    #[command(flatten)]
+    args: Args,
+
+    /// Split the report into N files next to --output, written concurrently, plus an index of the shard of every taxon
+    #[arg(long = "shard-by-prefix", value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    shard_by_prefix: Option<u32>,
+
+    /// Key taxa are sharded by
+    #[arg(long = "shard-key", value_enum, default_value_t = ShardKey::Taxid)]
+    shard_key: ShardKey,
+
+    /// Genes leaked on enough to mask but below --min-uniformity, with their incoming leakage and uniformity
+    #[arg(long = "suspect-report")]
+    suspect_report: Option<String>,
+
+    /// GTDB-Tk style marker summary per taxon (markers_summary.tsv columns plus leaked and masked
+    /// markers), needs --panel or --marker-names
+    #[arg(long = "marker-summary")]
+    marker_summary: Option<String>,
+
+    /// Marker names by gene id (gene<TAB>name), the panel and column names of --marker-summary
+    /// unless --panel names its genes
+    #[arg(long = "marker-names")]
+    marker_names: Option<String>,
+
+    /// Per genus and gene baselines of the incoming leakage (members, mean, median, MAD) and
+    /// whether the genus or the global baseline is used, as for --mask-excess
+    #[arg(long = "baseline-report")]
+    baseline_report: Option<String>,
 }
 
-impl Display for Species {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut s = String::default();
-
-        s.push_str(&format!("{}\t{}\t{}\tcorrect", self.id, self.num_good_genes(0.0), self.num_leaked_on_genes(0.0)));
-        self.leaks.iter().skip(1).for_each(|e: &Option<Leaks>| {
-            let tmp = match e {
-                Some(e) => { format!("\t{}", e.correct) },
-                None => "\tNone".to_string(),
-            };
-            s.push_str(&tmp)
-        });        
-        s.push_str(&format!("\n{}\t{}\t{}\tincoming", self.id, self.num_good_genes(0.0), self.num_leaked_on_genes(0.0)));
-        self.leaks.iter().skip(1).for_each(|e| {
-            let tmp = match e {
-                Some(e) => { format!("\t{}", e.incoming) },
-                None => "\tNone".to_string(),
-            };
-            s.push_str(&tmp)
-        });        
-        s.push_str(&format!("\n{}\t{}\t{}\toutgoing", self.id, self.num_good_genes(0.0), self.num_leaked_on_genes(0.0)));
-        self.leaks.iter().skip(1).for_each(|e| {
-            let tmp = match e {
-                Some(e) => { format!("\t{}", e.outgoing) },
-                None => "\tNone".to_string(),
-            };
-            s.push_str(&tmp)
-        });
-
-        
-
+/// First letter of the genus of a lineage, None without a named genus.
+fn genus_letter(lineage: &str) -> Option<char> {
+    lineage.split(';').find_map(|field| Rank::Genus.strip(field.trim())).and_then(|genus| genus.chars().next())
+}
 
-        write!(f, "{}", s)
-    }
+/// A read group of --by-read-group, the whole input without, with its gene leaks and the policy
+/// they are masked by.
+struct Group {
+    name: String,
+    leaks: GeneLeaks,
+    policy: MaskPolicy,
 }
 
-impl Species {
-    pub fn new(taxid: TaxID) -> Self {
-        Self {
-            id: taxid,
-            leaks: Vec::new(),
+/// Writes the table `write` writes for every group. With --by-read-group the tables of all read
+/// groups make one table, its column header written once with a leading read_group column and
+/// every row led by its read group.
+fn write_groups(by_read_group: bool, groups: &[Group], writer: &mut dyn Write, write: impl Fn(&Group, &mut dyn Write) -> std::io::Result<usize>) -> std::io::Result<usize> {
+    let mut rows = 0;
+    for (index, group) in groups.iter().enumerate() {
+        if !by_read_group {
+            rows += write(group, writer)?;
+            continue
         }
-    }
-
-    pub fn get(&mut self, geneid: GeneID) -> &mut Leaks {
-        if geneid >= self.leaks.len() || self.leaks[geneid].is_none() {
-            self.leaks.resize_with(geneid + 1, || None);
-            self.leaks[geneid] = Some(Leaks::default());
+        let mut buffer = Vec::new();
+        rows += write(group, &mut buffer)?;
+        let buffer = String::from_utf8(buffer).expect("Tables are UTF-8");
+        let mut lines = buffer.lines();
+        let header = lines.next().unwrap_or_default();
+        if index == 0 {
+            match header.strip_prefix('#') {
+                Some(header) => writeln!(writer, "#read_group\t{}", header)?,
+                None => writeln!(writer, "read_group\t{}", header)?,
+            }
+        }
+        for line in lines {
+            writeln!(writer, "{}\t{}", group.name, line)?;
         }
-        let len = self.leaks.len();
-        self.leaks[geneid].as_mut().expect(&format!("geneid {}, length leaks {}", geneid, len))
-    }
-
-    pub fn add_correct(&mut self, geneid: GeneID, increment: f64) {
-        let leaks = self.get(geneid);
-        leaks.correct += increment;
-    }
-
-    pub fn add_incorrect(&mut self, geneid: GeneID, incoming: bool, increment: f64) {
-        let leaks = self.get(geneid);
-        if incoming { leaks.incoming += increment } else { leaks.outgoing += increment};
-    }
-
-    pub fn num_genes(&self) -> usize {
-        self.leaks.iter().filter(|x| x.is_some()).count()
-    }
-    
-    pub fn num_leaked_on_genes(&self, threshold: f64) -> usize {
-        self.leaks.iter().
-            filter(|x| { match x {
-                Some(x) => x.incoming > threshold,
-                None => false,
-            }}).count()
-    }    
-
-    pub fn total_incoming_leaks(&self, threshold: f64) -> f64 {
-        self.leaks.iter().
-            filter(|x| { match x {
-                Some(x) => x.incoming > threshold,
-                None => false,
-            }}).fold(0.0, |acc, x| acc + x.as_ref().unwrap().incoming)
-    }
-
-    pub fn num_good_genes(&self, threshold: f64) -> usize {
-        self.num_genes() - self.num_leaked_on_genes(threshold)
-    }
-
-}
-
-pub struct GeneLeaks {
-    species: HashMap<TaxID, Species>,
-}
-
-// type DirectionalLeakageKey = (TaxID, TaxID);
-
-// pub struct DirectionalLeak {
-
-// }
-
-impl Default for GeneLeaks {
-    fn default() -> Self {
-        Self { species: Default::default() }
-    }
-}
-
-
-impl GeneLeaks {
-    pub fn count_correct(&mut self, species: TaxID, gene: GeneID, increment: f64) {
-        let entry = self.species.entry(species).or_insert(Species::new(species));
-        entry.add_correct(gene, increment);
-    }
-
-    pub fn count_incorrect(&mut self, species: TaxID, gene: GeneID, incoming: bool, increment: f64) {
-        let entry = self.species.entry(species).or_insert(Species::new(species));
-        entry.add_incorrect(gene, incoming, increment);
-    }
-
-    pub fn top_incoming(&self) -> Vec<(&TaxID, &Species)> {
-        let mut result = self.species.iter().collect::<Vec<(&TaxID, &Species)>>();
-
-        result.sort_by_key(|(_id, s)| { (-(s.num_leaked_on_genes(0.0) as isize), -(s.total_incoming_leaks(0.0) as isize)) } );
-
-        result
     }
+    Ok(rows)
 }
 
-
-
-pub fn get_species_total(args: &Args) -> HashMap<TaxID, Vec<Option<usize>>> {
-    let mut result = HashMap::default();
-
+fn main() {
+    let MaskGenesArgs { mut args, shard_by_prefix, shard_key, suspect_report, marker_summary, marker_names, baseline_report } = MaskGenesArgs::parse();
+    // Genes are counted in two passes over the input
+    let _spooled = or_exit(args.spool_stdin());
+    let start = timing::start(args.timing);
+    or_exit(require_genes(&args, "mask_genes"));
+    or_exit(require_whole_reads(&args, "mask_genes"));
+    if args.by_read_group && args.count_unmapped {
+        or_exit(Err("--count-unmapped cannot run with --by-read-group"))
+    }
+    if args.by_read_group && shard_by_prefix.is_some() {
+        or_exit(Err("--shard-by-prefix cannot run with --by-read-group"))
+    }
+    let mut anomalies = AnomalyLog::from_args(&args);
+    let analysis = or_exit(LeakageAnalysis::from_args(args.clone()));
+    let policy = or_exit(analysis.mask_policy());
     
-    let mut iter = sam_file_iterator(&args.input).expect("Cannot open file");
-
-
-    while let Some(Ok(sam)) = iter.next() {
-        // eprintln!("{:?}", sam);
-        if !sam.is_aligned() || sam.mapq < args.min_mapq {continue};
-
-        let (query_tid, query_gid) = taxid_geneid(&sam.qname).expect("Reference not parseable");
-
-        let entry: &mut Vec<Option<usize>> = result.entry(query_tid).or_insert(Vec::default());
-        if query_gid >= entry.len() || entry[query_gid].is_none() {
-            entry.resize_with(query_gid + 1, || None);
-            entry[query_gid] = Some(0);
+    // The second pass reads the same records, its anomalies would only duplicate those of the first
+    let counts = match args.by_read_group {
+        false => {
+            let total = or_exit(get_species_total(&args, &mut anomalies));
+            let leaks = or_exit(get_normalized_gene_leaks(&args, &total, &mut AnomalyLog::from_args(&args)));
+            vec![(String::new(), total, leaks)]
+        },
+        true => {
+            let (samples, totals) = or_exit(get_species_total_by_read_group(&args, &mut anomalies));
+            let leaks = or_exit(get_normalized_gene_leaks_by_read_group(&args, &samples, &totals, &mut AnomalyLog::from_args(&args)));
+            leaks.into_iter().zip(totals).map(|((name, leaks), total)| (name, total, leaks)).collect()
+        },
+    };
+    let lineages = args.map.as_ref().map(|map| or_exit(get_lineages(map))).unwrap_or_default();
+    // Every table made with a label map carries its fingerprint
+    let fingerprint = args_fingerprint_header(&args);
+    let create = |path: &dyn AsRef<std::path::Path>, threads: usize| -> std::io::Result<Box<dyn Write>> {
+        let mut writer = create_output(path, threads, args.compression_level, !args.no_atomic)?;
+        if let Some(header) = &fingerprint {
+            writeln!(writer, "{}", header)?;
         }
-        *entry[query_gid].as_mut().unwrap() += 1;
-    }
-
-    result
-}
-
-pub fn get_normalized_gene_leaks(args: &Args, total_counts: &HashMap<TaxID, Vec<Option<usize>>>) -> GeneLeaks {
-    let mut result = GeneLeaks::default();
-
-    let mut iter = sam_file_iterator(&args.input).expect("Cannot open file");
-
-
-    while let Some(Ok(sam)) = iter.next() {
-        // eprintln!("{:?}", sam);
-        if !sam.is_aligned() || sam.mapq < args.min_mapq {continue};
-
-        let (query_tid, query_gid) = taxid_geneid(&sam.qname).expect("Reference not parseable");
-        let (ref_tid, ref_gid) = taxid_geneid(&sam.rname).expect("Reference not parseable");
-        let correct = query_tid == ref_tid && query_gid == ref_gid;
-
-        let qt = total_counts.get(&query_tid);
-        let query_total = match qt {
-            Some(qt) => qt[query_gid].unwrap(),
-            None => 0,
-        };
-
-        let rt = total_counts.get(&ref_tid);
-        let ref_total = match qt {
-            Some(rt) => rt[ref_gid].unwrap(),
-            None => 0,
-        };
-
-        match correct {
-            true => result.count_correct(query_tid, query_gid, 1.0 / query_total as f64),
-            false => {
-                result.count_incorrect(ref_tid, ref_gid, true, 1.0 / query_total as f64);
-                result.count_incorrect(query_tid, query_gid, false, 1.0 / ref_total as f64);
-            },
+        Ok(writer)
+    };
+    let groups = counts.into_iter().map(|(name, total, mut leaks)| {
+        if args.by_read_group {
+            eprintln!("Read group {}", name);
         }
+        let policy = analysis.fit_mask_policy(policy.clone(), &mut leaks, &lineages, baseline_report.is_some());
+        let reads = total.values().flatten().flatten().sum::<usize>();
+        eprintln!("{} species, {} reads counted on their query genes", total.len(), reads);
+        Group { name, leaks, policy }
+    }).collect::<Vec<Group>>();
+
+    if let Some(path) = &suspect_report {
+        let mut writer = or_exit(create(path, args.threads));
+        let rows = write_groups(args.by_read_group, &groups, &mut writer, |group, mut writer| group.leaks.write_suspects(&group.policy, &mut writer)).expect("Error writing suspect report");
+        writer.flush().expect("Error writing suspect report");
+        eprintln!("{}\t{} suspect genes", path, rows);
+    }
+
+    if let Some(path) = &baseline_report {
+        groups.iter().filter_map(|group| group.policy.baselines.as_ref()).for_each(|baselines| baselines.report());
+        let mut writer = or_exit(create(path, args.threads));
+        let rows = write_groups(args.by_read_group, &groups, &mut writer, |group, mut writer| match &group.policy.baselines {
+            Some(baselines) => baselines.write_report(&mut writer),
+            None => Ok(0),
+        }).expect("Error writing baseline report");
+        writer.flush().expect("Error writing baseline report");
+        eprintln!("{}\t{} baselines", path, rows);
+    }
+
+    if let Some(path) = &marker_summary {
+        let base = args.gene_id_base as GeneID;
+        let markers = match (&args.panel, &marker_names) {
+            (Some(panel), _) if panel.has_names() => panel.marker_names(base),
+            (_, Some(names)) => or_exit(MarkerNames::read(names)),
+            (Some(panel), None) => panel.marker_names(base),
+            (None, None) => or_exit(Err("--marker-summary needs --panel or --marker-names")),
+        };
+        let labels = args.map.as_ref().map(|map| get_labels_map(map).0).unwrap_or_default();
+        let mut writer = or_exit(create(path, args.threads));
+        let rows = write_groups(args.by_read_group, &groups, &mut writer, |group, mut writer| group.leaks.write_marker_summary(&group.policy, &markers, &labels, &mut writer)).expect("Error writing marker summary");
+        writer.flush().expect("Error writing marker summary");
+        eprintln!("{}\t{} taxa", path, rows);
     }
 
-    
-    result
-}
-
-
-pub fn get_gene_leaks(args: &Args) -> GeneLeaks {
-    let mut result = GeneLeaks::default();
-
-    let mut iter = sam_file_iterator(&args.input).expect("Cannot open file");
-
-
-    while let Some(Ok(sam)) = iter.next() {
-        // eprintln!("{:?}", sam);
-        if !sam.is_aligned() || sam.mapq < args.min_mapq {continue};
-
-        let (query_tid, query_gid) = taxid_geneid(&sam.qname).expect("Reference not parseable");
-        let (ref_tid, ref_gid) = taxid_geneid(&sam.rname).expect("Reference not parseable");
-        let correct = query_tid == ref_tid && query_gid == ref_gid;
-
-        match correct {
-            true => result.count_correct(query_tid, query_gid, 1.0),
-            false => {
-                result.count_incorrect(ref_tid, ref_gid, true, 1.0);
-                result.count_incorrect(query_tid, query_gid, false, 1.0);
-            },
+    let Some(shards) = shard_by_prefix else {
+        let mut writer = stdout().lock();
+        if let Some(header) = &fingerprint {
+            writeln!(writer, "{}", header).expect("Error writing gene leaks");
         }
-    }
-
-    
-    result
+        write_groups(args.by_read_group, &groups, &mut writer, |group, mut writer| group.leaks.write_report(&group.policy, &mut writer)).expect("Error writing gene leaks");
+        timing::finish(start);
+        or_exit(anomalies.finish(&args));
+        return
+    };
+    let Group { leaks, policy, .. } = &groups[0];
+    let shards = shards as usize;
+    let output = or_exit(args.output.as_deref().ok_or("--shard-by-prefix needs --output"));
+    if shard_key == ShardKey::Genus && args.map.is_none() {
+        or_exit(Err("--shard-key genus needs --map"))
+    }
+    let shard = |id: TaxID| match lineages.get(id).and_then(|lineage| genus_letter(lineage)) {
+        Some(letter) => letter.to_ascii_uppercase() as usize,
+        None => id,
+    };
+    let width = (shards - 1).to_string().len();
+    let shard_path = |index: usize| part_path(output, &format!("shard{:0width$}", index, width = width));
+
+    let assignment = or_exit(leaks.write_report_sharded(policy, shards, args.threads, shard, |index| create(&shard_path(index), 1)));
+
+    let index_path = part_path(output.trim_end_matches(".gz"), "index");
+    let mut writer = std::io::BufWriter::new(or_exit(SafeWriter::create(&index_path, !args.no_atomic)));
+    writeln!(writer, "#taxid\tshard\tfile").expect("Error writing shard index");
+    for (id, index) in &assignment {
+        writeln!(writer, "{}\t{}\t{}", id, index, shard_path(*index).display()).expect("Error writing shard index");
+    }
+    writer.flush().expect("Error writing shard index");
+    eprintln!("{} taxa in {} shards, index {}", assignment.len(), shards, index_path.display());
+    timing::finish(start);
+    or_exit(anomalies.finish(&args));
 }
-
-
-fn main() {
-    let args: Args = Args::parse();
-    
-    let total = get_species_total(&args);
-
-    let leaks = get_normalized_gene_leaks(&args, &total);
-
-    eprintln!("{:?}", total);
-
-    for (_id, s) in leaks.top_incoming().iter().rev() {
-        println!("{}", s);
-        
-    }
-}
-
-// Functions
-
-
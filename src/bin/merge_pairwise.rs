@@ -0,0 +1,59 @@
+use std::io::{stdout, Write};
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, AnomalyLog, Args}, id_to_label::{common_map_fingerprint, FINGERPRINT_PREFIX}, pairwise_leakage::{Leakage, LeakageTotals, SelfPairPolicy}, utils::create_output};
+
+/// Merges pairwise tables, e.g. of several samples, into one. Pairs are keyed canonically by the
+/// directionality in the table headers and summed across tables, pairs listed twice within a
+/// table are summed with a warning. Tables of different directionality or gene base are refused
+/// unless --coerce is given, then they are merged into the layout of the first table. Tables
+/// tagged with --release-tag are merged release by release, every row led by its release, and
+/// are refused together with untagged ones. Tables made with different label maps (see their
+/// map fingerprint header) are refused as well, the merged table carries the fingerprint of its
+/// tables.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct MergePairwiseArgs {
+    #[command(flatten)]
+    args: Args,
+
+    /// Pairwise tables written by pairwise_leakage, analyze or ambiguity_matrix
+    #[arg(required = true)]
+    tables: Vec<String>,
+
+    /// Merge tables of different directionality or gene base into the layout of the first
+    #[arg(long = "coerce", default_value_t = false)]
+    coerce: bool,
+}
+
+fn main() {
+    let MergePairwiseArgs { args, tables, coerce } = MergePairwiseArgs::parse();
+    let self_pairs = SelfPairPolicy::from_args(&args);
+    let fingerprint = or_exit(common_map_fingerprint(tables.iter().map(String::as_str), args.ignore_map_fingerprint));
+    let mut anomalies = AnomalyLog::from_args(&args);
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic)),
+        None => Box::new(stdout().lock()),
+    };
+    if let Some(fingerprint) = &fingerprint {
+        writeln!(writer, "{}{}", FINGERPRINT_PREFIX, fingerprint).expect("Error writing merged pairwise leakage");
+    }
+    let rows = if args.no_genes {
+        let merged = or_exit(LeakageTotals::merge_by_release(tables.iter().map(|path| (path.clone(), or_exit(LeakageTotals::read(path, self_pairs, &mut anomalies)))), coerce));
+        match merged.first().is_some_and(|table| table.release.is_some()) {
+            true => LeakageTotals::write_releases(&merged, self_pairs, &mut writer),
+            false => merged[0].write_pairwise(self_pairs, &mut writer),
+        }.expect("Error writing merged pairwise leakage")
+    } else {
+        let merged = or_exit(Leakage::merge_by_release(tables.iter().map(|path| (path.clone(), or_exit(Leakage::read(path, self_pairs, &mut anomalies)))), coerce));
+        match merged.first().is_some_and(|table| table.release.is_some()) {
+            true => Leakage::write_releases(&merged, self_pairs, &mut writer),
+            false => merged[0].write_pairwise(self_pairs, &mut writer),
+        }.expect("Error writing merged pairwise leakage")
+    };
+    writer.flush().expect("Error writing merged pairwise leakage");
+    eprintln!("Merged {} tables into {} pairs", tables.len(), rows);
+    or_exit(anomalies.finish(&args));
+}
@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use clap::Parser;
+use fix_gtdb_mg::{common::or_exit, layout::{migrate, LAYOUT_VERSION}, lock::DEFAULT_LOCK_MAX_AGE_HOURS};
+
+/// Upgrades a results directory written by an earlier `analyze` to the current layout: outputs
+/// are moved to their current names with a schema header added where missing, and a manifest is
+/// written for the result (tool version and inputs kept from an old manifest, unknown without
+/// one). Run doctor on the directory afterwards.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct MigrateResultsArgs {
+    /// Results directory to upgrade in place
+    #[arg(short = 'd', long = "dir")]
+    dir: String,
+
+    /// Write the outputs in place instead of to `<file>.tmp` renamed when complete
+    #[arg(long = "no-atomic", default_value_t = false)]
+    no_atomic: bool,
+
+    /// Take over the lock of --dir held by another run
+    #[arg(long = "force-unlock", default_value_t = false)]
+    force_unlock: bool,
+
+    /// Take over the lock of --dir left by a process of this host that is gone, or held on
+    /// another host once it is older than this many hours
+    #[arg(long = "lock-max-age", value_name = "HOURS", default_value_t = DEFAULT_LOCK_MAX_AGE_HOURS)]
+    lock_max_age: u64,
+}
+
+fn main() {
+    let args = MigrateResultsArgs::parse();
+
+    let migration = or_exit(migrate(&args.dir, Duration::from_secs(args.lock_max_age * 3600), args.force_unlock, !args.no_atomic));
+    if migration.from == LAYOUT_VERSION {
+        eprintln!("{} already has layout {}, nothing to do", args.dir, LAYOUT_VERSION);
+        return
+    }
+    for (old, new) in &migration.moved {
+        eprintln!("{}\t->\t{}", old, new);
+    }
+    for file in &migration.missing {
+        eprintln!("Warning: {} of layout {} not found, left out of the manifest", file, migration.from);
+    }
+    eprintln!("{} upgraded from layout {} to {}: {} outputs moved, {} schema headers added", args.dir, migration.from, LAYOUT_VERSION, migration.moved.len(), migration.headers_added);
+}
@@ -1,18 +1,63 @@
-use std::cmp::Ordering;
+use std::io::{stdout, Write};
 
 use clap::Parser;
-use fix_gtdb_mg::{common::Args, pairwise_leakage::{Leakage, NormGenes, TinyTaxID}};
+use fix_gtdb_mg::{analysis::LeakageAnalysis, common::{or_exit, require_genes, AnomalyLog, Args}, id_to_label::{check_map_fingerprint, fingerprint_header, get_labels_map}, pairwise_leakage::{write_normalized, write_normalized_totals, write_top_donors, Leakage, LeakageTotals, NormalizationSchema, TopDonors}, utils::create_output};
 
 
 
 fn main() {
-    let args: Args = Args::parse();
-
-    let leakage = Leakage::load(&args);
-    let normalized_leakage = leakage.normalize_incoming();
-    let mut vec = normalized_leakage.into_iter().collect::<Vec<(TinyTaxID, NormGenes)>>();
-    vec.sort_by(|(a, ag), (b, bg)| ag.total().partial_cmp(&bg.total()).unwrap_or(Ordering::Equal));
-    for (l, g) in vec {
-        println!("{}\t{}", l, g)
+    let mut args: Args = Args::parse();
+
+    let id2lab = args.map.as_ref().map(|map| get_labels_map(map).0);
+    // The fingerprint is read off the table before the table itself
+    let _spooled = id2lab.is_some().then(|| or_exit(args.spool_stdin()));
+    if let Some(id2lab) = &id2lab {
+        or_exit(check_map_fingerprint(&args.input, id2lab, args.ignore_map_fingerprint));
+    }
+    let fingerprint = id2lab.as_ref().map(fingerprint_header);
+
+    let mut anomalies = AnomalyLog::from_args(&args);
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic)),
+        None => Box::new(stdout().lock()),
+    };
+    if let Some(header) = &fingerprint {
+        writeln!(writer, "{}", header).expect("Error writing normalized leakage");
+    }
+
+    if args.no_genes {
+        for (enabled, option) in [(args.streaming, "--streaming"), (args.with_denominators, "--with-denominators"), (args.donors_per_recipient.is_some(), "--donors-per-recipient")] {
+            if enabled { or_exit(require_genes(&args, option)) };
+        }
+        let schema = NormalizationSchema::from_args(&args);
+        let normalized = or_exit(or_exit(LeakageTotals::load(&args, &mut anomalies)).normalize_incoming(schema.self_pairs, args.deterministic, &mut anomalies));
+        write_normalized_totals(normalized, &schema, &mut writer).expect("Error writing normalized leakage");
+        writer.flush().expect("Error writing normalized leakage");
+        or_exit(anomalies.finish(&args));
+        return
+    }
+
+    let schema = NormalizationSchema::from_args(&args);
+    let mut top_donors = args.donors_per_recipient.map(TopDonors::new);
+    let normalized_leakage = if args.streaming {
+        or_exit(Leakage::normalize_incoming_streaming(&args, top_donors.as_mut(), &mut anomalies))
+    } else {
+        let leakage = or_exit(Leakage::load(&args, &mut anomalies));
+        if let Some(k) = args.donors_per_recipient {
+            top_donors = Some(leakage.top_donors(k, schema.self_pairs));
+        }
+        or_exit(or_exit(LeakageAnalysis::from_args(args.clone())).normalize(&leakage, &mut anomalies))
+    };
+    write_normalized(normalized_leakage, &schema, &mut writer).expect("Error writing normalized leakage");
+    writer.flush().expect("Error writing normalized leakage");
+
+    if let Some(top_donors) = top_donors {
+        let mut writer = or_exit(create_output(&args.donors_output, args.threads, args.compression_level, !args.no_atomic));
+        if let Some(header) = &fingerprint {
+            writeln!(writer, "{}", header).expect("Error writing donors per recipient");
+        }
+        write_top_donors(&top_donors.into_sorted(), id2lab.as_ref(), schema.clamp.as_ref(), &mut writer).expect("Error writing donors per recipient");
+        writer.flush().expect("Error writing donors per recipient");
     }
-}
\ No newline at end of file
+    or_exit(anomalies.finish(&args));
+}
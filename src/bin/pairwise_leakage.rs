@@ -1,16 +1,140 @@
-use std::cmp::Ordering;
+use std::io::{stdout, Write};
 
 use clap::Parser;
-use fix_gtdb_mg::{common::Args, pairwise_leakage::{Genes, Leakage, LeakagePair, NormGenes, TinyTaxID}};
+use fix_gtdb_mg::{analysis::LeakageAnalysis, common::{or_exit, require_genes, require_whole_reads, AnomalyLog, Args, DebugTaxa}, id_to_label::args_fingerprint_header, leakage::leakage_records_from_sam, pairwise_leakage::{Leakage, LeakageTotals, MapqHistogram, PairSchema, SelfPairPolicy}, reconcile::MultimapWeighting, samples::file_part, timing, utils::{create_output, part_path}};
+
+/// Writes the table of every sample of a --split-by run next to --output, as
+/// `<name>.<sample>.<ext>`, each led by the map `fingerprint` header if any, and returns the
+/// tables merged for --output.
+fn write_samples<T>(args: &Args, output: &str, fingerprint: Option<&str>, tables: Vec<(String, T)>, write: impl Fn(&T, &mut Box<dyn Write>) -> std::io::Result<usize>, merge: impl Fn(&mut T, T) -> Result<(), String>) -> Option<T> {
+    let mut combined: Option<T> = None;
+    for (sample, table) in tables {
+        let path = part_path(output, &file_part(&sample));
+        let mut writer = or_exit(create_output(&path, args.threads, args.compression_level, !args.no_atomic));
+        if let Some(header) = fingerprint {
+            writeln!(writer, "{}", header).expect("Error writing pairwise leakage");
+        }
+        let rows = write(&table, &mut writer).expect("Error writing pairwise leakage");
+        writer.flush().expect("Error writing pairwise leakage");
+        eprintln!("{}\t{}\t{} pairs", sample, path.display(), rows);
+        match combined.as_mut() {
+            Some(combined) => or_exit(merge(combined, table)),
+            None => combined = Some(table),
+        }
+    }
+    combined
+}
+
+/// Writes --leak-length-report, if given.
+fn write_leak_lengths(args: &Args, leakage: &Leakage) {
+    let Some(path) = &args.leak_length_report else { return };
+    let mut writer = or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic));
+    let (credible, low) = leakage.write_leak_lengths(args.min_median_leak_len, &mut writer).expect("Error writing leak length report");
+    writer.flush().expect("Error writing leak length report");
+    eprintln!("Leak lengths: {} pairs credible, {} low_credibility", credible, low);
+}
 
 fn main() {
     let args: Args = Args::parse();
+    let start = timing::start(args.timing);
+    or_exit(DebugTaxa::check(&args));
 
-    let leakage = Leakage::from_sam(&args);
+    let mut anomalies = AnomalyLog::from_args(&args);
+    let self_pairs = SelfPairPolicy::from_args(&args);
 
-    let mut vec = leakage.map.into_iter().collect::<Vec<(LeakagePair, Genes)>>();
-    vec.sort_by_key(|l| (l.0.to, l.1.total()));
-    for (l, g) in vec {
-        println!("{}\t{}\t{}", l.from, l.to, g)
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic)),
+        None if args.split_by.is_some() => or_exit(Err("--split-by needs --output to name the per-sample tables after")),
+        None => Box::new(stdout().lock()),
+    };
+    if args.mapq_hist {
+        or_exit(require_whole_reads(&args, "--mapq-hist"));
+        let histogram = or_exit(MapqHistogram::from_sam(&args, &mut anomalies));
+        histogram.write(&mut writer).and_then(|_| writer.flush()).expect("Error writing MAPQ histogram");
+        timing::finish(start);
+        or_exit(anomalies.finish(&args));
+        return
+    }
+    // Tables made with a label map carry its fingerprint, so they are not read with another map
+    let fingerprint = args_fingerprint_header(&args);
+    if let Some(header) = &fingerprint {
+        writeln!(writer, "{}", header).expect("Error writing pairwise leakage");
+    }
+    if args.leakage_records {
+        or_exit(require_whole_reads(&args, "--leakage-records"));
+        let records = or_exit(leakage_records_from_sam(&args, &mut anomalies, &mut writer));
+        writer.flush().expect("Error writing leakage records");
+        eprintln!("{} leakage records", records);
+        timing::finish(start);
+        or_exit(anomalies.finish(&args));
+        return
+    }
+    if args.count_unmapped {
+        or_exit(require_genes(&args, "--count-unmapped"));
+        if args.split_by.is_some() {
+            or_exit(Err("--count-unmapped cannot run with --split-by"))
+        }
+        if args.by_read_group {
+            or_exit(Err("--count-unmapped cannot run with --by-read-group"))
+        }
+    }
+    if args.multimap_weighting == MultimapWeighting::Fraction {
+        or_exit(require_genes(&args, "--multimap-weighting fraction"));
+    }
+    if args.strand_bias {
+        or_exit(require_genes(&args, "--strand-bias"));
+    }
+    if args.write_leaked_sam.is_some() && (args.reconcile || args.keeps_best_per_read() || args.multimap_weighting != MultimapWeighting::Count || args.equalize_depth.is_some()) {
+        or_exit(Err("--write-leaked-sam writes records as they are counted one by one, it cannot run with --reconcile, --best-per-read, --multimap-weighting or --equalize-depth"))
+    }
+    if args.no_genes && args.tracks_leak_lengths() {
+        eprintln!("Warning: leak lengths are not tracked with --no-genes, --leak-length-report and --min-median-leak-len are ignored");
+    }
+    match (args.no_genes, &args.output) {
+        (true, Some(output)) if args.split_by.is_some() => {
+            let tables = or_exit(LeakageTotals::from_sam_by_sample(&args, &mut anomalies));
+            let totals = write_samples(&args, output, fingerprint.as_deref(), tables, |table, writer| table.write_pairwise(self_pairs, writer), |combined, table| combined.merge(table, false))
+                .unwrap_or_else(|| LeakageTotals { map: Default::default(), schema: PairSchema::from_args(&args), release: args.release_tag.clone() });
+            totals.write_pairwise(self_pairs, &mut writer).expect("Error writing pairwise leakage");
+        },
+        (false, Some(output)) if args.split_by.is_some() => {
+            let tables = or_exit(Leakage::from_sam_by_sample(&args, &mut anomalies));
+            let leakage = write_samples(&args, output, fingerprint.as_deref(), tables, |table, writer| table.write_pairwise(self_pairs, writer), |combined, table| combined.merge(table, false))
+                .unwrap_or_else(|| Leakage { map: Default::default(), schema: PairSchema::from_args(&args), release: args.release_tag.clone(), lengths: Default::default(), unmapped: Default::default(), reverse: Default::default(), gene_mismatches: Default::default() });
+            leakage.write_pairwise(self_pairs, &mut writer).expect("Error writing pairwise leakage");
+            write_leak_lengths(&args, &leakage);
+        },
+        (true, _) if args.by_read_group => {
+            let tables = or_exit(LeakageTotals::from_sam_by_sample(&args, &mut anomalies));
+            let rows = LeakageTotals::write_read_groups(&tables, self_pairs, &mut writer).expect("Error writing pairwise leakage");
+            eprintln!("{} read groups, {} pairs", tables.len(), rows);
+        },
+        (false, _) if args.by_read_group => {
+            let tables = or_exit(Leakage::from_sam_by_sample(&args, &mut anomalies));
+            let rows = Leakage::write_read_groups(&tables, self_pairs, &mut writer).expect("Error writing pairwise leakage");
+            eprintln!("{} read groups, {} pairs", tables.len(), rows);
+            if let Some(leakage) = tables.into_iter().map(|(_group, table)| table).reduce(|mut combined, table| {
+                or_exit(combined.merge(table, false));
+                combined
+            }) {
+                write_leak_lengths(&args, &leakage);
+            }
+        },
+        (true, _) => {
+            let totals = or_exit(LeakageTotals::from_sam(&args, &mut anomalies));
+            totals.write_pairwise(self_pairs, &mut writer).expect("Error writing pairwise leakage");
+        },
+        (false, _) => {
+            let analysis = or_exit(LeakageAnalysis::from_args(args.clone()));
+            let (leakage, _header) = or_exit(analysis.count_pairs(&mut anomalies));
+            if args.timing {
+                eprintln!("{}", leakage.gene_memory());
+            }
+            leakage.write_pairwise(self_pairs, &mut writer).expect("Error writing pairwise leakage");
+            write_leak_lengths(&args, &leakage);
+        },
     }
-}
\ No newline at end of file
+    writer.flush().expect("Error writing pairwise leakage");
+    timing::finish(start);
+    or_exit(anomalies.finish(&args));
+}
@@ -0,0 +1,78 @@
+use std::io::{stdout, Write};
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, NameFormat}, pairwise_leakage::SelfPairPolicy, prescreen::{write_similarity, MinimizerIndex, SketchParams}, utils::SafeWriter};
+
+/// Estimates which species pairs are confusable on which marker genes without aligning reads:
+/// sketches every gene of the reference by its minimizers and counts the minimizers shared by the
+/// same gene of two species. Writes an undirected pairwise table whose gene columns hold shared
+/// minimizers instead of reads, as a cheap screen ahead of simulating and aligning.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct PrescreenArgs {
+    /// Marker reference (.fasta|.fasta.gz)
+    #[arg(short = 'r', long = "reference")]
+    reference: String,
+
+    /// k-mer size
+    #[arg(short = 'k', long = "kmer", default_value_t = 21, value_parser = clap::value_parser!(u8).range(1..=31))]
+    k: u8,
+
+    /// Window of consecutive k-mers a minimizer is picked from
+    #[arg(short = 'w', long = "window", default_value_t = 11, value_parser = clap::value_parser!(u16).range(1..))]
+    w: u16,
+
+    /// Skip minimizers held by more taxa than this for a gene (conserved or low complexity)
+    #[arg(long = "max-taxa", default_value_t = 100)]
+    max_taxa: usize,
+
+    /// How taxid and gene id are read from sequence names: a delimiter or a template such as
+    /// `tid|{taxid}|g{gene}` (see pairwise_leakage --help)
+    #[arg(long = "name-format", default_value_t = NameFormat::default())]
+    name_format: NameFormat,
+
+    /// Leave out pairs sharing fewer minimizers over all genes
+    #[arg(long = "min-shared", default_value_t = 1)]
+    min_shared: u64,
+
+    /// Pairwise table, stdout if not given
+    #[arg(short = 'o', long = "output")]
+    output: Option<String>,
+
+    /// Pairs with shared minimizers and Jaccard similarity over the genes both carry
+    #[arg(long = "similarity-output")]
+    similarity_output: Option<String>,
+
+    /// Write the outputs in place instead of to `<file>.tmp` renamed when complete
+    #[arg(long = "no-atomic", default_value_t = false)]
+    no_atomic: bool,
+}
+
+fn main() {
+    let args = PrescreenArgs::parse();
+    let params = SketchParams { k: args.k as usize, w: args.w as usize };
+
+    let index = or_exit(MinimizerIndex::from_fasta(&args.reference, params, &args.name_format));
+    if index.malformed > 0 {
+        eprintln!("Warning: {} of {} sequences without a taxid_geneid name left out (see check_reference)", index.malformed, index.sequences);
+    }
+    let (mut shared, skipped) = index.shared(args.max_taxa);
+    shared.map.retain(|_pair, genes| genes.total() >= args.min_shared);
+    eprintln!("Sketched {} sequences, {} minimizers held by more than {} taxa skipped", index.sequences, skipped, args.max_taxa);
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(std::io::BufWriter::new(or_exit(SafeWriter::create(path, !args.no_atomic)))),
+        None => Box::new(stdout().lock()),
+    };
+    writeln!(writer, "{}", params.header(args.max_taxa)).expect("Error writing prescreen");
+    let rows = shared.write_pairwise(SelfPairPolicy::default(), &mut writer).expect("Error writing prescreen");
+    writer.flush().expect("Error writing prescreen");
+    eprintln!("{} candidate pairs", rows);
+
+    if let Some(path) = &args.similarity_output {
+        let mut writer = std::io::BufWriter::new(or_exit(SafeWriter::create(path, !args.no_atomic)));
+        write_similarity(&index, &shared, &mut writer).expect("Error writing prescreen similarity");
+        writer.flush().expect("Error writing prescreen similarity");
+    }
+}
@@ -0,0 +1,72 @@
+use std::io::{stdout, Write};
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, sam_input, sam_to_ids, AnomalyLog, Args}, filter::{Decision, RecordFilter}, utils::HyperLogLog};
+
+/// Profiles a SAM in one streaming pass with fixed memory: exact record counts and mapq
+/// distribution, and approximate numbers of distinct query taxa, reference taxa and (query,
+/// reference) pairs among the records passing the filters. Cardinalities are given with a range
+/// of two standard errors; the upper end of the pairs is a safe --expected-pairs.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct ProfileArgs {
+    #[command(flatten)]
+    args: Args,
+
+    /// Distinct counters use 2^precision bytes each, relative error about 1.04 / sqrt(2^precision)
+    #[arg(long = "precision", default_value_t = 14, value_parser = clap::value_parser!(u32).range(HyperLogLog::MIN_PRECISION as i64..=HyperLogLog::MAX_PRECISION as i64))]
+    precision: u32,
+}
+
+fn main() {
+    let ProfileArgs { args, precision } = ProfileArgs::parse();
+    let mut anomalies = AnomalyLog::from_args(&args);
+    anomalies.set_input(&args.input_label());
+
+    let mut iter = or_exit(sam_input(&args));
+    let mut filter = RecordFilter::from_args(&args);
+    let (mut records, mut aligned, mut kept) = (0usize, 0usize, 0usize);
+    let mut mapqs = [0usize; 256];
+    let mut query_taxa = HyperLogLog::new(precision);
+    let mut reference_taxa = HyperLogLog::new(precision);
+    let mut pairs = HyperLogLog::new(precision);
+
+    let mut line = String::new();
+    while let Some(sam) = or_exit(iter.next_valid_ref(&mut line, &mut anomalies)) {
+        records += 1;
+        if sam.is_aligned() {
+            aligned += 1;
+            mapqs[sam.mapq as usize] += 1;
+        }
+        if filter.evaluate(&sam) != Decision::Keep { continue };
+        let Ok(fromto) = sam_to_ids(&sam, &filter.bounds, &mut filter.names) else { continue };
+        kept += 1;
+        query_taxa.insert(fromto.query);
+        reference_taxa.insert(fromto.reference);
+        pairs.insert((fromto.query, fromto.reference));
+    }
+    eprintln!("Records: {}", filter);
+
+    let mut writer = stdout().lock();
+    let error = pairs.relative_error();
+    writeln!(writer, "#profile\tprecision={}\trelative_error={:.4}", precision, error).expect("Error writing profile");
+    writeln!(writer, "metric\tvalue\tlow\thigh").expect("Error writing profile");
+    for (name, count) in [("records", records), ("aligned", aligned), ("kept", kept)] {
+        writeln!(writer, "{}\t{}\t{}\t{}", name, count, count, count).expect("Error writing profile");
+    }
+    for (name, counter) in [("query_taxa", &query_taxa), ("reference_taxa", &reference_taxa), ("pairs", &pairs)] {
+        let estimate = counter.estimate();
+        let low = (estimate * (1.0 - 2.0 * error)).floor().max(0.0) as usize;
+        let high = ((estimate * (1.0 + 2.0 * error)).ceil() as usize).min(kept);
+        writeln!(writer, "{}\t{}\t{}\t{}", name, estimate.round() as usize, low, high).expect("Error writing profile");
+    }
+    for (mapq, count) in mapqs.iter().enumerate().filter(|(_mapq, count)| **count > 0) {
+        writeln!(writer, "mapq_{}\t{}\t{}\t{}", mapq, count, count, count).expect("Error writing profile");
+    }
+    writer.flush().expect("Error writing profile");
+
+    let estimate = pairs.estimate();
+    eprintln!("Capacity hint: --expected-pairs {}", ((estimate * (1.0 + 2.0 * error)).ceil() as usize).min(kept));
+    or_exit(anomalies.finish(&args));
+}
@@ -0,0 +1,48 @@
+use std::io::{stdout, Write};
+
+use clap::Parser;
+use fix_gtdb_mg::{common::{or_exit, AnomalyLog, Args}, gene_leaks::read_mask, simulate::simulate_mask, timing, utils::create_output};
+
+/// Estimates how a mask would change read assignments: replays a name-grouped SAM, assigning
+/// every read by its best alignment with and without the alignments to masked genes, and reports
+/// per query taxon how many reads become correct, unassigned or leak elsewhere.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+struct SimulateMaskArgs {
+    #[command(flatten)]
+    args: Args,
+
+    /// Mask to simulate (v1 or v2)
+    #[arg(long = "mask")]
+    mask: String,
+
+    /// Reads by state before and after masking with the overall totals, on stderr if not given
+    #[arg(long = "confusion")]
+    confusion: Option<String>,
+}
+
+fn main() {
+    let SimulateMaskArgs { args, mask, confusion } = SimulateMaskArgs::parse();
+    let start = timing::start(args.timing);
+    let mut anomalies = AnomalyLog::from_args(&args);
+    let mask = or_exit(read_mask(&mask).map_err(|e| format!("Cannot read mask {}: {}", mask, e)));
+
+    let simulation = or_exit(simulate_mask(&args, &mask, &mut anomalies));
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic)),
+        None => Box::new(stdout().lock()),
+    };
+    simulation.write_taxa(&mut writer).expect("Error writing simulation");
+    writer.flush().expect("Error writing simulation");
+
+    let mut writer: Box<dyn Write> = match &confusion {
+        Some(path) => or_exit(create_output(path, args.threads, args.compression_level, !args.no_atomic)),
+        None => Box::new(std::io::stderr().lock()),
+    };
+    simulation.write_confusion(&mut writer).expect("Error writing confusion summary");
+    writer.flush().expect("Error writing confusion summary");
+    timing::finish(start);
+    or_exit(anomalies.finish(&args));
+}
@@ -1,143 +1,1573 @@
-use std::{collections::HashMap, ffi::OsStr, fs::File, io::{BufRead, BufReader}, path::Path};
+use std::{collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque}, fmt::Display, hash::{Hash, Hasher}, io::{BufRead, BufReader, Read, Write}, path::Path, str::FromStr, time::Instant};
 
-use clap::{command, Parser};
-use flate2::bufread::GzDecoder;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use thiserror::Error;
 
-use crate::pairwise_leakage::{TinyGeneID, TinyTaxID};
+use crate::{filter::Mapq, lock::DEFAULT_LOCK_MAX_AGE_HOURS, gene_leaks::Panel, id_to_label::{LabelNormalize, Resolver}, pairwise_leakage::{TinyGeneID, TinyTaxID}, reconcile::{AmbiguousReads, MultimapWeighting}, samples::{SampleID, SplitBy}, timing::{bytes_read, Phase, SlowRecords}, utils::{create_file, create_output, is_stdin, open_file, open_reader, ConcatReader, strip_cr, SafeWriter, SpooledStdin}};
 
 pub type TaxID = usize;
 pub type GeneID = usize;
 
 
-pub fn taxid_geneid(token: &str) -> Result<(usize, usize), Box<dyn std::error::Error>> {
-    let mut parts = token.split('_');
-    
-    // Use next() to get the first two parts and check for their existence
-    let first_part = parts.next().ok_or("Missing first part")?;
-    let second_part = parts.next().ok_or("Missing second part")?;
+/// Taxid and gene id of a name in the default `taxid_geneid` format, see `NameFormat`.
+pub fn taxid_geneid(token: &str) -> Result<(usize, usize), String> {
+    NameFormat::default().parse(token)
+}
+
+/// How taxid and gene id are read from read and reference names (--name-format).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameFormat {
+    /// Split on a delimiter: taxid and gene id are the first two fields, further ones are ignored
+    Delimiter(String),
+    /// Literal text and fields, each field running up to the literal text after it. Text after
+    /// the template (e.g. a read suffix) is ignored, as further fields are with a delimiter
+    Template(Vec<NamePart>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NamePart {
+    Literal(String),
+    TaxID,
+    Gene,
+    /// Any text, skipped
+    Skip,
+}
+
+impl Default for NameFormat {
+    fn default() -> Self {
+        NameFormat::Delimiter("_".to_string())
+    }
+}
+
+impl NameFormat {
+    /// Taxid and gene id of a name, the error naming the token that does not fit.
+    pub fn parse(&self, name: &str) -> Result<(TaxID, GeneID), String> {
+        match self {
+            NameFormat::Delimiter(delimiter) => {
+                let mut parts = name.split(delimiter.as_str());
+                let taxid = parts.next().ok_or_else(|| format!("{} has no taxid", name))?;
+                let gene = parts.next().ok_or_else(|| format!("{} has no gene id after '{}'", name, delimiter))?;
+                Ok((parse_id(taxid, "taxid")?, parse_id(gene, "gene id")?))
+            },
+            NameFormat::Template(parts) => {
+                let (mut taxid, mut gene) = (None, None);
+                let mut rest = name;
+                for (index, part) in parts.iter().enumerate() {
+                    if let NamePart::Literal(literal) = part {
+                        rest = rest.strip_prefix(literal.as_str()).ok_or_else(|| format!("{} does not match {}: expected '{}' at '{}'", name, self, literal, rest))?;
+                        continue
+                    }
+                    let end = match (parts.get(index + 1), part) {
+                        (Some(NamePart::Literal(literal)), _) => rest.find(literal.as_str()).ok_or_else(|| format!("{} does not match {}: no '{}' after '{}'", name, self, literal, rest))?,
+                        (_, NamePart::Skip) => rest.len(),
+                        // The digits of a last id field, the rest being ignored as a suffix
+                        _ => rest.find(|c: char| !c.is_ascii_digit()).filter(|digits| *digits > 0).unwrap_or(rest.len()),
+                    };
+                    let (token, tail) = rest.split_at(end);
+                    match part {
+                        NamePart::TaxID => taxid = Some(parse_id(token, "taxid")?),
+                        NamePart::Gene => gene = Some(parse_id(token, "gene id")?),
+                        _ => (),
+                    }
+                    rest = tail;
+                }
+                match (taxid, gene) {
+                    (Some(taxid), Some(gene)) => Ok((taxid, gene)),
+                    _ => Err(format!("{} cannot be read with {}: it needs a {{taxid}} and a {{gene}} field", name, self)),
+                }
+            },
+        }
+    }
+}
+
+fn parse_id(token: &str, what: &str) -> Result<usize, String> {
+    token.parse().map_err(|e| format!("'{}' is not a {}: {}", token, what, e))
+}
+
+impl FromStr for NameFormat {
+    type Err = String;
+
+    /// A template with `{taxid}`, `{gene}` and `{*}` fields, or else a delimiter.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.contains('{') {
+            return match s.is_empty() {
+                true => Err("Invalid --name-format: the delimiter is empty".to_string()),
+                false => Ok(NameFormat::Delimiter(s.to_string())),
+            }
+        }
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let Some(field) = rest.strip_prefix('{') else {
+                let end = rest.find('{').unwrap_or(rest.len());
+                parts.push(NamePart::Literal(rest[..end].to_string()));
+                rest = &rest[end..];
+                continue
+            };
+            let (field, tail) = field.split_once('}').ok_or_else(|| format!("Invalid --name-format {}: unclosed '{{'", s))?;
+            let part = match field {
+                "taxid" => NamePart::TaxID,
+                "gene" => NamePart::Gene,
+                "*" => NamePart::Skip,
+                _ => return Err(format!("Invalid --name-format {}: unknown field {{{}}} (expected {{taxid}}, {{gene}} or {{*}})", s, field)),
+            };
+            if parts.last().is_some_and(|last| !matches!(last, NamePart::Literal(_))) {
+                return Err(format!("Invalid --name-format {}: fields need text between them", s))
+            }
+            parts.push(part);
+            rest = tail;
+        }
+        for field in [NamePart::TaxID, NamePart::Gene] {
+            if parts.iter().filter(|part| **part == field).count() != 1 {
+                return Err(format!("Invalid --name-format {}: needs exactly one {{taxid}} and one {{gene}}", s))
+            }
+        }
+        Ok(NameFormat::Template(parts))
+    }
+}
+
+impl Display for NameFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameFormat::Delimiter(delimiter) => write!(f, "{}", delimiter),
+            NameFormat::Template(parts) => parts.iter().try_for_each(|part| match part {
+                NamePart::Literal(literal) => write!(f, "{}", literal),
+                NamePart::TaxID => write!(f, "{{taxid}}"),
+                NamePart::Gene => write!(f, "{{gene}}"),
+                NamePart::Skip => write!(f, "{{*}}"),
+            }),
+        }
+    }
+}
+
+/// Format of read and reference names and the largest ids accepted in them. Names beyond them are
+/// rejected where they are parsed, before a rogue id can size a per-taxon or per-gene table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdBounds {
+    pub max_taxid: TaxID,
+    /// Largest gene id, the panel size (so 0-based ids one past the end still reach the gene id
+    /// base check)
+    pub max_gene: Option<GeneID>,
+    pub format: NameFormat,
+}
+
+impl Default for IdBounds {
+    fn default() -> Self {
+        Self { max_taxid: Self::DEFAULT_MAX_TAXID, max_gene: None, format: NameFormat::default() }
+    }
+}
+
+impl IdBounds {
+    pub const DEFAULT_MAX_TAXID: TaxID = 10_000_000;
+
+    pub fn from_args(args: &Args) -> Self {
+        Self { max_taxid: args.max_taxid, max_gene: args.panel.as_ref().map(|panel| panel.max_gene(args.gene_id_base as GeneID)).or(args.n_genes), format: args.name_format.clone() }
+    }
+
+    /// Taxid and gene id of a name, an error naming the id out of bounds.
+    pub fn parse(&self, name: &str) -> Result<(TaxID, GeneID), String> {
+        let (taxid, gene) = self.format.parse(name)?;
+        let max_taxid = self.max_taxid.min(TinyTaxID::MAX as TaxID);
+        if taxid > max_taxid {
+            return Err(format!("taxid {} exceeds --max-taxid {}", taxid, max_taxid))
+        }
+        if let Some(max_gene) = self.max_gene.filter(|max_gene| gene > *max_gene) {
+            return Err(format!("gene id {} exceeds the gene panel (last gene {})", gene, max_gene))
+        }
+        Ok((taxid, gene))
+    }
+}
+
+/// Ids of the reference name parsed last. Coordinate sorted SAMs name one reference for a run of
+/// records, and comparing a name to the last one costs less than parsing it. A map of every
+/// reference name costs more than parsing: looking up a short name misses the CPU caches once a
+/// SAM names a few ten thousand references. Read names are not cached, they are unique.
+#[derive(Debug, Clone, Default)]
+pub struct NameCache {
+    name: String,
+    ids: Option<(TaxID, GeneID)>,
+}
+
+impl NameCache {
+    /// `IdBounds::parse` of a reference name, kept for the next record if it succeeds.
+    pub fn reference(&mut self, bounds: &IdBounds, name: &str) -> Result<(TaxID, GeneID), String> {
+        if let Some(ids) = self.ids.filter(|_ids| self.name == name) {
+            return Ok(ids)
+        }
+        let ids = bounds.parse(name)?;
+        self.name.clear();
+        self.name.push_str(name);
+        self.ids = Some(ids);
+        Ok(ids)
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+#[command(max_term_width = 120)] // term_width sets it fixed, max term_width can be smaller
+pub struct Args {
+    /// Input file (.sam, or compressed with gzip, zstd or bzip2)
+    #[arg(short = 'i', long = "input", default_value_t = String::default())]
+    pub input: String,
+
+    /// More input files (.sam, or compressed), read after --input as one SAM and counted into the same
+    /// leakage, e.g. one per sample or flowcell
+    #[arg(long = "inputs", num_args = 1..)]
+    pub inputs: Vec<String>,
+
+    /// Mapq threshold (filter everything strictly below)
+    #[arg(short = 'm', long = "min_mapq", default_value_t = 4)]
+    pub min_mapq: Mapq,
+
+    /// Skip secondary (0x100) and supplementary (0x800) alignments, e.g. of bowtie2 -k, so that
+    /// every aligned read counts once. Name-grouped modes then see one alignment per read
+    #[arg(long = "primary-only", default_value_t = false)]
+    pub primary_only: bool,
+
+    /// Skip records with an alignment score (AS tag) below this. Records without the tag are kept
+    #[arg(long = "min-alignment-score", allow_negative_numbers = true)]
+    pub min_alignment_score: Option<i32>,
+
+    /// Skip records with an edit distance (NM tag) above this. Records without the tag are kept
+    #[arg(long = "max-edit-distance")]
+    pub max_edit_distance: Option<u32>,
+
+    /// Skip records with fewer read bases aligned (M, I, = and X of the CIGAR), e.g. reads of
+    /// which only a fragment maps to a foreign gene. Records without a valid CIGAR are kept
+    #[arg(long = "min-aligned-length")]
+    pub min_aligned_length: Option<u32>,
+
+    /// Skip records with a larger share of soft clipped read bases (S over M, I, S, = and X of the
+    /// CIGAR, 0 to 1), e.g. reads of which only a short core maps. Records with CIGAR `*` are kept
+    #[arg(long = "max-softclip-frac")]
+    pub max_softclip_frac: Option<f64>,
+
+    /// Skip records of shorter reads, e.g. adapter fragments. The read length is that of SEQ, or
+    /// of the CIGAR (M, I, S, = and X) when SEQ is `*`; records with neither have length 0
+    #[arg(long = "min-read-len")]
+    pub min_read_len: Option<u32>,
+
+    /// Skip records of longer reads, read length as for --min-read-len
+    #[arg(long = "max-read-len")]
+    pub max_read_len: Option<u32>,
+
+    /// Skip records with a lower alignment identity (0 to 1, from the NM or MD tag and the CIGAR).
+    /// Records without NM and MD are kept and counted
+    #[arg(long = "min-identity")]
+    pub min_identity: Option<f64>,
+
+    /// First gene id of the reference: 1 if genes are numbered 1..=n, 0 if 0..n. Gene ids of the
+    /// input that do not fit are reported (fatal with --strict)
+    #[arg(long = "gene-id-base", default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=1))]
+    pub gene_id_base: u8,
+
+    /// Number of marker genes of the panel, sharpens the gene id base check. Records with larger
+    /// gene ids are skipped (fatal with --strict)
+    #[arg(long = "n-genes")]
+    pub n_genes: Option<usize>,
+
+    /// Marker gene panel, an embedded one (bac120, ar53) or a panel.tsv (gene_id, name,
+    /// expected_length, group). Supersedes --n-genes and --marker-names
+    #[arg(long = "panel", value_parser = Panel::parse_arg)]
+    pub panel: Option<Panel>,
+
+    /// How taxid and gene id are read from read and reference names: a delimiter, the first two
+    /// fields being taxid and gene id (e.g. `_` for 12345_7), or a template of literal text and
+    /// {taxid}, {gene} and {*} (skipped) fields, e.g. `tid|{taxid}|g{gene}` or
+    /// `GCF_{taxid}.{*}~gene{gene}`. Both ids must be numbers
+    #[arg(long = "name-format", default_value_t = NameFormat::default())]
+    pub name_format: NameFormat,
+
+    /// Skip records with a larger taxid in a read or reference name (fatal with --strict)
+    #[arg(long = "max-taxid", default_value_t = IdBounds::DEFAULT_MAX_TAXID)]
+    pub max_taxid: TaxID,
+
+    /// Replaced by --min-genes-remaining and --min-genes-initial, rejected with an error
+    #[arg(long = "min_genes", hide = true)]
+    pub min_genes: Option<i32>,
+
+    /// Never mask a species below this many remaining genes: masking stops once only this many
+    /// genes with reads are left, lowest weight (with --gene-weights) or highest incoming first
+    #[arg(long = "min-genes-remaining", default_value_t = 0)]
+    pub min_genes_remaining: usize,
+
+    /// Quarantine species with fewer genes with reads than this: they are not masked gene by gene
+    /// but flagged for removal as a whole
+    #[arg(long = "min-genes-initial", default_value_t = 0)]
+    pub min_genes_initial: usize,
+
+    /// Tolerate this many incoming leaked reads
+    #[arg(short = 'g', long = "genes", default_value_t = 10)]
+    pub max_leaked_reads: i32,
+
+    /// Only treat a gene as leaked on (maskable) if at least this many distinct donor taxa leak into it
+    #[arg(long = "min-donors-to-mask", default_value_t = 1)]
+    pub min_donors_to_mask: usize,
+
+    /// Mask a gene once its incoming leakage exceeds this value
+    #[arg(long = "mask-on", default_value_t = 0.0)]
+    pub mask_on: f64,
+
+    /// Keep a gene of --previous-mask masked until its incoming leakage falls below this value (must be < --mask-on)
+    #[arg(long = "mask-off")]
+    pub mask_off: Option<f64>,
+
+    /// Only mask a gene if leaked reads cover at least this fraction of its windows (needs
+    /// positions, i.e. gene leaks counted from SAM); genes below it go to the suspect report
+    #[arg(long = "min-uniformity")]
+    pub min_uniformity: Option<f64>,
+
+    /// Window size in bases for the coverage uniformity of leaked reads
+    #[arg(long = "uniformity-window", default_value_t = 80, value_parser = clap::value_parser!(u32).range(1..))]
+    pub uniformity_window: u32,
+
+    /// Pairs whose leaked reads have a median aligned length below this (approximate, 10 bp bins)
+    /// are listed as low_credibility in --leak-length-report, short leaks being mostly adapter or
+    /// low-complexity junk. Tracks leak lengths
+    #[arg(long = "min-median-leak-len", value_name = "BP")]
+    pub min_median_leak_len: Option<f64>,
+
+    /// Median aligned length of the leaked reads per pair (from, to, leaked reads, median), pairs
+    /// below --min-median-leak-len in a low_credibility section. Tracks leak lengths, which also
+    /// adds a median_leak_len row to gene leak reports
+    #[arg(long = "leak-length-report")]
+    pub leak_length_report: Option<String>,
+
+    /// Also write the records counted on a pair of two taxa to this SAM (gzip if it ends in
+    /// .gz), verbatim after the header lines of the input, as they are counted one by one
+    #[arg(long = "write-leaked-sam")]
+    pub write_leaked_sam: Option<String>,
+
+    /// Instead of the pairwise table, write the number of correct (same taxid and gene) and
+    /// incorrect records per MAPQ (mapq, correct, incorrect), to choose --min_mapq. Every other
+    /// record filter applies
+    #[arg(long = "mapq-hist", default_value_t = false)]
+    pub mapq_hist: bool,
+
+    /// Instead of the pairwise table, write one line per record kept by the record filters (read
+    /// id, from taxid_gene, to taxid_gene, correct, mapq), the per-read leakage file of summarize
+    #[arg(long = "leakage-records", default_value_t = false, conflicts_with = "mapq_hist")]
+    pub leakage_records: bool,
+
+    /// Count unmapped records by the taxid (and gene) of their read name, as an unmapped column
+    /// of the pairwise table and taxon summary and an unmapped row of gene leak reports, for
+    /// rates over all reads rather than the mapped ones. Not with --no-genes or --split-by
+    #[arg(long = "count-unmapped", default_value_t = false)]
+    pub count_unmapped: bool,
+
+    /// Track the strand (0x10) of counted alignments and write the fraction of the reads of every
+    /// pair on the reverse strand as a strand_bias column of the pairwise table. Not with --no-genes
+    #[arg(long = "strand-bias", default_value_t = false)]
+    pub strand_bias: bool,
+
+    /// Mask file of the previous release; its genes are only unmasked below --mask-off
+    #[arg(long = "previous-mask")]
+    pub previous_mask: Option<String>,
+
+    /// Per-gene importance weights (gene<TAB>weight). Masking capped by --min-genes-remaining then
+    /// spares high-weight genes first
+    #[arg(long = "gene-weights")]
+    pub gene_weights: Option<String>,
+
+    /// Normalize a pairwise table sorted by the from column donor by donor with bounded memory
+    /// (rows are still sorted by total before output, so the result matches the in-memory path)
+    #[arg(long = "streaming", default_value_t = false)]
+    pub streaming: bool,
+
+    /// Count only pair totals instead of per-gene counts (much less memory, no gene-level outputs)
+    #[arg(long = "no-genes", default_value_t = false)]
+    pub no_genes: bool,
+
+    /// Skip reads whose alignments hit more than this many distinct taxa in the ambiguity matrix
+    #[arg(long = "max-group-size", default_value_t = 100)]
+    pub max_group_size: usize,
+
+    /// Buffer at most this many alignments of a read in name-grouped modes (--reconcile,
+    /// --best-per-read, --multimap-weighting, the ambiguity matrix); larger reads are handled as
+    /// ambiguous (or by their buffered alignments) and counted as an anomaly
+    #[arg(long = "max-group-records", default_value_t = 1000)]
+    pub max_group_records: usize,
+
+    /// Trust that name-grouped modes get name-grouped input and skip the check that reads do not
+    /// reappear after their alignments ended
+    #[arg(long = "assume-grouped", default_value_t = false)]
+    pub assume_grouped: bool,
+
+    /// Follow every normalized value by the denominator it was divided by (NA for genes with
+    /// contributions of several donors, which were divided by several)
+    #[arg(long = "with-denominators", default_value_t = false)]
+    pub with_denominators: bool,
+
+    /// Write normalized values above this cap as the cap, marked by a `+` suffix (values in
+    /// memory and the totals they sum to stay exact)
+    #[arg(long = "clamp")]
+    pub clamp: Option<f64>,
+
+    /// Mark values clamped by --clamp by a 0/1 column after each instead of a suffix
+    #[arg(long = "clamp-flags", default_value_t = false, requires = "clamp")]
+    pub clamp_flags: bool,
+
+    /// Proceed even if a table's map fingerprint does not match the given label map or the
+    /// tables merged or compared with it
+    #[arg(long = "ignore-map-fingerprint", default_value_t = false)]
+    pub ignore_map_fingerprint: bool,
+
+    /// Release the input belongs to (e.g. r220), recorded in the header of pairwise tables so
+    /// merge_pairwise and compare_pairwise can tell releases apart
+    #[arg(long = "release-tag", value_parser = crate::schema::parse_release_tag)]
+    pub release_tag: Option<String>,
+
+    /// Only mask genes whose incoming leakage lies above this percentile of all non-zero gene slots of the run
+    #[arg(long = "mask-above-percentile")]
+    pub mask_above_percentile: Option<f64>,
+
+    /// Only mask genes whose incoming leakage lies more than this many MADs above the median of
+    /// the same gene in the taxon's genus (see --baseline-min-members, genera from --map)
+    #[arg(long = "mask-excess")]
+    pub mask_excess: Option<f64>,
+
+    /// Genera with fewer taxa carrying a gene use the baseline of all taxa for it
+    #[arg(long = "baseline-min-members", default_value_t = 3)]
+    pub baseline_min_members: usize,
+
+    /// Subsample every query taxon to at most this many passing reads before counting pairs, the
+    /// alignments of a read (grouped by name) kept or dropped together. Each donor's outgoing total
+    /// then counts at most N reads, which makes the normalize_incoming denominators comparable
+    /// across taxa of different depth
+    #[arg(long = "equalize-depth")]
+    pub equalize_depth: Option<usize>,
+
+    /// Write kept and total reads per query taxon of --equalize-depth to this file
+    #[arg(long = "equalize-depth-report")]
+    pub equalize_depth_report: Option<String>,
+
+    /// Seed for all random sampling
+    #[arg(long = "seed", default_value_t = 0)]
+    pub seed: u64,
+
+    /// Label map (genome2tiid.tsv) used to add labels to outputs
+    #[arg(long = "map")]
+    pub map: Option<String>,
+
+    /// List the taxa that cannot be placed in --map (or the tree) with their read counts
+    #[arg(long = "unplaced-report")]
+    pub unplaced_report: Option<String>,
+
+    /// Pre-size the pair map from the distinct taxa and pairs in the first MB megabytes of the SAM
+    #[arg(long = "estimate-capacity", value_name = "MB")]
+    pub estimate_capacity: Option<u64>,
+
+    /// Pre-size the pair map for this many pairs (takes precedence over --estimate-capacity)
+    #[arg(long = "expected-pairs")]
+    pub expected_pairs: Option<usize>,
+
+    /// Leave correct assignments (self-pairs) out of pairwise tables and normalized leakage
+    #[arg(long = "exclude-self-pairs", default_value_t = false)]
+    pub exclude_self_pairs: bool,
+
+    /// Leave self-pairs out of the donor outgoing totals normalized values are divided by
+    #[arg(long = "exclude-self-from-denominators", default_value_t = false)]
+    pub exclude_self_from_denominators: bool,
+
+    /// Normalization applied to labels before joining maps, trees and taxon lists
+    #[arg(long = "label-normalize", value_enum, default_value_t = LabelNormalize::None)]
+    pub label_normalize: LabelNormalize,
+
+    /// GTDB metadata (bac120/ar53_metadata.tsv) resolving species labels the map lacks
+    #[arg(long = "metadata")]
+    pub metadata: Option<String>,
+
+    /// Alias table, `alias<TAB>label` per line, resolving labels through the map or metadata
+    #[arg(long = "aliases")]
+    pub aliases: Option<String>,
+
+    /// Write how each label given on the command line or in a taxon list was resolved
+    #[arg(long = "resolution-report")]
+    pub resolution_report: Option<String>,
+
+    /// Classify every read once over all its alignments (name-grouped input, e.g. bowtie2 -k) and
+    /// count one pair per read: correct, leaked or ambiguous
+    #[arg(long = "reconcile", default_value_t = false)]
+    pub reconcile: bool,
+
+    /// Mapq difference within which a read with correct and foreign alignments is ambiguous
+    #[arg(long = "reconcile-margin", default_value_t = 0)]
+    pub reconcile_margin: Mapq,
+
+    /// Whether ambiguous reads are counted (by their best foreign alignment) or skipped
+    #[arg(long = "reconcile-ambiguous", value_enum, default_value_t = AmbiguousReads::Count)]
+    pub reconcile_ambiguous: AmbiguousReads,
+
+    /// Count only the best alignment of every read (name-grouped input, e.g. bowtie2 -k): highest
+    /// AS tag, then highest mapq, ties to the lowest reference name
+    #[arg(long = "best-per-read", default_value_t = false, conflicts_with = "reconcile")]
+    pub best_per_read: bool,
+
+    /// How the alignments of a multi-mapped read are counted (name-grouped input for fraction and
+    /// best): each as a whole read, 1/n each for a read with n alignments, or only the best one
+    /// (as --best-per-read). Fractional pairwise tables are written with fixed-point counts
+    #[arg(long = "multimap-weighting", value_enum, default_value_t = MultimapWeighting::Count, conflicts_with_all = ["reconcile", "best_per_read"])]
+    pub multimap_weighting: MultimapWeighting,
+
+    /// Also write up to K donors per recipient with their normalized contribution
+    #[arg(long = "donors-per-recipient")]
+    pub donors_per_recipient: Option<usize>,
+
+    /// Every N counted records, write the pairs with the largest totals so far to --preliminary-output
+    #[arg(long = "flush-every", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub flush_every: Option<u64>,
+
+    /// Output file for --flush-every, replaced atomically at every flush
+    #[arg(long = "preliminary-output", default_value_t = String::from("preliminary.tsv"))]
+    pub preliminary_output: String,
+
+    /// Output file for --donors-per-recipient
+    #[arg(long = "donors-output", default_value_t = String::from("donors_per_recipient.tsv"))]
+    pub donors_output: String,
+
+    /// Treat every anomaly (invalid record, unparseable name, flag/rname mismatch, truncated input,
+    /// non-finite normalization, gene id base mismatch) as a fatal error instead of logging and skipping it
+    #[arg(long = "strict", default_value_t = false)]
+    pub strict: bool,
+
+    /// Log SAM records that cannot be parsed to stderr and skip them (counted as invalid_record)
+    /// instead of failing on the first one
+    #[arg(long = "skip-invalid", default_value_t = false)]
+    pub skip_invalid: bool,
+
+    /// Accept SAM records with fields missing after MAPQ (e.g. trailing fields collapsed by a
+    /// converter): CIGAR, RNEXT, SEQ and QUAL default to `*`, PNEXT and TLEN to 0
+    #[arg(long = "lenient", default_value_t = false)]
+    pub lenient: bool,
+
+    /// Write the anomaly log (category, count, first example, input, record) to this file
+    #[arg(long = "anomaly-log")]
+    pub anomaly_log: Option<String>,
+
+    /// Log every record involving this taxon (id, or label with --map) with its parsed fields and
+    /// what was done with it to --debug-log. Repeatable
+    #[arg(long = "debug-taxon")]
+    pub debug_taxon: Vec<String>,
+
+    /// Output file for --debug-taxon
+    #[arg(long = "debug-log", default_value_t = String::from("debug_taxa.log"))]
+    pub debug_log: String,
+
+    /// Write the main table to this file instead of stdout (gzip compressed if it ends in .gz)
+    #[arg(long = "output")]
+    pub output: Option<String>,
+
+    /// Threads used to compress .gz outputs (independent gzip members compressed in parallel) and
+    /// to parse the SAM records counted into pairwise leakage, unless counting depends on record
+    /// order (--reconcile, --best-per-read, --multimap-weighting other than count, --equalize-depth,
+    /// --split-by, --debug-taxon, --flush-every). Several inputs are then read at the same time,
+    /// up to one per thread, and compressed ones parsed on no more threads than their
+    /// decompression keeps busy
+    #[arg(long = "threads", default_value_t = 1)]
+    pub threads: usize,
+
+    /// Gzip compression level of .gz outputs (0-9)
+    #[arg(long = "compression-level", default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+    pub compression_level: u32,
+
+    /// Time reading, decompression, parsing and counting of the input and report the breakdown,
+    /// and the memory of the gene counts of the pair map
+    #[arg(long = "timing", default_value_t = false)]
+    pub timing: bool,
+
+    /// With --timing, report this many of the slowest records to parse (line, qname, length, time)
+    #[arg(long = "slow-records", default_value_t = SamReader::DEFAULT_SLOW_RECORDS)]
+    pub slow_records: usize,
+
+    /// Report records read, records passing --min_mapq, records per second and input bytes read
+    /// to stderr every N million records (1 if given without N)
+    #[arg(long = "progress", value_name = "N", num_args = 0..=1, default_missing_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    pub progress: Option<u64>,
+
+    /// Skip SAM lines longer than this many bytes unparsed (counted as oversized_line, fatal with --strict)
+    #[arg(long = "max-line-bytes", default_value_t = SamReader::DEFAULT_MAX_LINE_BYTES)]
+    pub max_line_bytes: usize,
+
+    /// Add up normalized values over pairs in a fixed order, so that repeated runs on the same
+    /// input write byte-identical outputs (the manifest apart from its "run" field). Without it
+    /// row order, counts, sampling (seeded by --seed) and gzip output with any --threads are
+    /// already reproducible, normalized values may differ in the last digit
+    #[arg(long = "deterministic", default_value_t = false)]
+    pub deterministic: bool,
+
+    /// Count the samples of a multiplexed SAM separately, told apart by the RG tag (rg) or the
+    /// read name up to a separator (qname-prefix:<sep>). Records without one count as sample
+    /// "unknown". pairwise_leakage writes one table per sample next to --output
+    #[arg(long = "split-by")]
+    pub split_by: Option<SplitBy>,
+
+    /// Count the read groups (RG tag) of the input separately, records without one in read group
+    /// "none". pairwise_leakage and mask_genes lead every row of their tables with the read group
+    #[arg(long = "by-read-group", default_value_t = false, conflicts_with = "split_by")]
+    pub by_read_group: bool,
+
+    /// Write output files in place instead of to `<output>.tmp` renamed when complete, for
+    /// filesystems where rename is unreliable
+    #[arg(long = "no-atomic", default_value_t = false)]
+    pub no_atomic: bool,
+
+    /// Take over the lock of an output directory held by another run (see --lock-max-age)
+    #[arg(long = "force-unlock", default_value_t = false)]
+    pub force_unlock: bool,
+
+    /// Take over the lock of an output directory left by a process of this host that is gone, or
+    /// held on another host once it is older than this many hours
+    #[arg(long = "lock-max-age", value_name = "HOURS", default_value_t = DEFAULT_LOCK_MAX_AGE_HOURS)]
+    pub lock_max_age: u64,
+}
+
+
+/// Custom error type to handle different kinds of errors
+#[derive(Debug, Error)]
+pub enum SamFileError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Gzip decode error: {0}")]
+    Gzip(#[from] flate2::DecompressError),
+    #[error("{} @SQ reference names do not follow the name format (--name-format):\n  {}", .0.len(), .0.join("\n  "))]
+    InvalidReferences(Vec<String>),
+}
+
+/// A record of a SAM file, owning its line. Fields are kept as offsets into the line and sliced
+/// out on demand (see `view`), so SEQ and QUAL, tens of kilobases for long reads, are never
+/// copied out of it.
+#[derive(Debug, Clone)]
+pub struct Sam {
+    line: String,
+    fields: RecordFields,
+}
+
+impl Sam {
+    /// Create a new Sam struct from a SAM file line
+    pub fn from_line(line: &str) -> Result<Self, String> {
+        Self::from_owned_line(line.to_string())
+    }
+
+    /// As `from_line`, taking over the line instead of copying it.
+    pub fn from_owned_line(line: String) -> Result<Self, String> {
+        let fields = RecordFields::parse(&line)?;
+        Ok(Self { line, fields })
+    }
+
+    /// The record borrowed, for the functions that read records (e.g. `RecordFilter::evaluate`).
+    pub fn view(&self) -> SamRef<'_> {
+        self.fields.record(&self.line)
+    }
+
+    /// The read bases (SEQ), `*` if not stored.
+    pub fn seq(&self) -> &str {
+        self.fields.field(&self.line, 9)
+    }
+
+    /// The base qualities (QUAL), `*` if not stored.
+    pub fn qual(&self) -> &str {
+        self.fields.field(&self.line, 10)
+    }
+
+    /// Value of an optional field, e.g. `tag("RG")`.
+    pub fn tag(&self, tag: &str) -> Option<&str> {
+        self.view().tag(tag)
+    }
+
+    /// Alignment score of the aligner (`AS:i`), None if absent or not an integer.
+    pub fn alignment_score(&self) -> Option<i32> {
+        self.view().alignment_score()
+    }
+
+    /// Edit distance to the reference (`NM:i`), None if absent or not an integer.
+    pub fn edit_distance(&self) -> Option<u32> {
+        self.view().edit_distance()
+    }
+
+    /// Mismatching positions (`MD:Z`), None if absent.
+    pub fn mismatches(&self) -> Option<&str> {
+        self.view().mismatches()
+    }
+
+    /// Operations of the CIGAR, None for `*` or an invalid CIGAR.
+    pub fn cigar_ops(&self) -> Option<Vec<(u32, CigarOp)>> {
+        self.view().cigar_ops()
+    }
+
+    /// See `SamRef::read_length`.
+    pub fn read_length(&self) -> Option<u32> {
+        self.view().read_length()
+    }
+
+    /// See `SamRef::aligned_length`.
+    pub fn aligned_length(&self) -> Option<u32> {
+        self.view().aligned_length()
+    }
+
+    /// See `SamRef::matched_bases`.
+    pub fn matched_bases(&self) -> Option<u32> {
+        self.view().matched_bases()
+    }
+
+    /// See `SamRef::softclip_fraction`.
+    pub fn softclip_fraction(&self) -> Option<f64> {
+        self.view().softclip_fraction()
+    }
+
+    /// See `SamRef::identity`.
+    pub fn identity(&self) -> Option<f64> {
+        self.view().identity()
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.view().is_aligned()
+    }
+
+    pub fn is_unmapped(&self) -> bool {
+        self.view().is_unmapped()
+    }
+
+    pub fn is_reverse(&self) -> bool {
+        self.view().is_reverse()
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        self.view().is_secondary()
+    }
+
+    pub fn is_duplicate(&self) -> bool {
+        self.view().is_duplicate()
+    }
+
+    pub fn is_supplementary(&self) -> bool {
+        self.view().is_supplementary()
+    }
+
+    /// Neither secondary nor supplementary, the one line of a read per aligner hit list.
+    pub fn is_primary(&self) -> bool {
+        self.view().is_primary()
+    }
+}
+
+/// A SAM record borrowed from its line, parsed without copying the text fields. The counting
+/// loops read records this way (`SamReader::next_valid_ref`), `Sam` owns a copy.
+#[derive(Debug, Clone, Copy)]
+pub struct SamRef<'a> {
+    /// The line the record was parsed from, as read
+    pub line: &'a str,
+    pub qname: &'a str,
+    pub flag: u16,
+    pub rname: &'a str,
+    pub pos: u32,
+    pub mapq: Mapq,
+    pub cigar: &'a str,
+    pub rnext: &'a str,
+    pub pnext: u32,
+    pub tlen: i32,
+    pub seq: &'a str,
+    pub qual: &'a str,
+    /// Optional fields (`TAG:TYPE:VALUE`), tab separated
+    pub tags: &'a str,
+}
+
+impl<'a> SamRef<'a> {
+    /// Parses a SAM file line, the fields borrowed from it.
+    pub fn from_line(line: &'a str) -> Result<Self, String> {
+        Ok(RecordFields::parse(line)?.record(line))
+    }
+
+    /// As `from_line`, accepting lines with fields missing after MAPQ (--lenient): CIGAR, RNEXT,
+    /// SEQ and QUAL are then `*`, PNEXT and TLEN 0.
+    pub fn from_line_lenient(line: &'a str) -> Result<Self, String> {
+        Ok(RecordFields::parse_lenient(line)?.record(line))
+    }
+
+    /// The record with its fields copied into a line of its own.
+    pub fn to_owned(&self) -> Sam {
+        let mut line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", self.qname, self.flag, self.rname, self.pos, self.mapq, self.cigar, self.rnext, self.pnext, self.tlen, self.seq, self.qual);
+        if !self.tags.is_empty() {
+            line.push('\t');
+            line.push_str(self.tags);
+        }
+        Sam::from_owned_line(line).expect("A parsed record is written back as a valid line")
+    }
+
+    /// Value of an optional field, e.g. `tag("RG")`.
+    pub fn tag(&self, tag: &str) -> Option<&'a str> {
+        self.tags.split('\t').find_map(|field| {
+            let (name, rest) = field.split_once(':')?;
+            (name == tag).then(|| rest.split_once(':').map_or("", |(_type, value)| value))
+        })
+    }
+
+    /// Alignment score of the aligner (`AS:i`), None if absent or not an integer.
+    pub fn alignment_score(&self) -> Option<i32> {
+        self.tag("AS")?.parse().ok()
+    }
+
+    /// Edit distance to the reference (`NM:i`), None if absent or not an integer.
+    pub fn edit_distance(&self) -> Option<u32> {
+        self.tag("NM")?.parse().ok()
+    }
+
+    /// Mismatching positions (`MD:Z`), None if absent.
+    pub fn mismatches(&self) -> Option<&'a str> {
+        self.tag("MD")
+    }
+
+    /// Operations of the CIGAR, None for `*` or an invalid CIGAR.
+    pub fn cigar_ops(&self) -> Option<Vec<(u32, CigarOp)>> {
+        parse_cigar(self.cigar)
+    }
+
+    /// Length of the read: of SEQ, or of the CIGAR without hard clips when SEQ is `*` (e.g.
+    /// secondary alignments). 0 if both are `*`, None if SEQ is `*` and the CIGAR invalid.
+    pub fn read_length(&self) -> Option<u32> {
+        match (self.seq, self.cigar) {
+            ("*", "*") => Some(0),
+            ("*", _cigar) => self.cigar_ops()?.iter().filter(|(_length, op)| op.consumes_query()).try_fold(0u32, |sum, (length, _op)| sum.checked_add(*length)),
+            (seq, _cigar) => u32::try_from(seq.len()).ok(),
+        }
+    }
+
+    /// Read bases aligned to the reference (M, I, = and X), clipped bases left out.
+    pub fn aligned_length(&self) -> Option<u32> {
+        self.cigar_ops()?.iter().filter(|(_length, op)| op.consumes_query() && !op.is_clip()).try_fold(0u32, |sum, (length, _op)| sum.checked_add(*length))
+    }
+
+    /// Read bases aligned opposite a reference base (M, = and X). M does not tell matches from
+    /// mismatches, subtract the NM tag for identical bases.
+    pub fn matched_bases(&self) -> Option<u32> {
+        self.cigar_ops()?.iter().filter(|(_length, op)| op.consumes_query() && op.consumes_reference()).try_fold(0u32, |sum, (length, _op)| sum.checked_add(*length))
+    }
+
+    /// Soft clipped read bases over all read bases of the CIGAR (M, I, S, = and X). None for `*`,
+    /// an invalid CIGAR or one without read bases.
+    pub fn softclip_fraction(&self) -> Option<f64> {
+        let (clipped, read) = self.cigar_ops()?.iter().filter(|(_length, op)| op.consumes_query())
+            .fold((0u64, 0u64), |(clipped, read), (length, op)| match op {
+                CigarOp::SoftClip => (clipped + *length as u64, read + *length as u64),
+                _ => (clipped, read + *length as u64),
+            });
+        (read > 0).then(|| clipped as f64 / read as f64)
+    }
+
+    /// Share of the aligned read bases that are not edits, 1 - edits / `aligned_length`. Edits are
+    /// the NM tag or, without it, the mismatches and deletions of the MD tag plus the inserted
+    /// bases of the CIGAR. None without NM and MD or without a valid CIGAR.
+    pub fn identity(&self) -> Option<f64> {
+        let aligned = self.aligned_length().filter(|aligned| *aligned > 0)?;
+        let edits = match self.edit_distance() {
+            Some(edits) => edits,
+            None => {
+                let inserted = self.cigar_ops()?.iter().filter(|(_length, op)| *op == CigarOp::Insertion).map(|(length, _op)| *length).sum::<u32>();
+                md_edits(self.mismatches()?)? + inserted
+            },
+        };
+        Some((1.0 - edits as f64 / aligned as f64).max(0.0))
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.rname != "*"
+    }
+
+    pub fn is_unmapped(&self) -> bool {
+        self.flag & 0x4 != 0
+    }
+
+    pub fn is_reverse(&self) -> bool {
+        self.flag & 0x10 != 0
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        self.flag & 0x100 != 0
+    }
+
+    pub fn is_duplicate(&self) -> bool {
+        self.flag & 0x400 != 0
+    }
+
+    pub fn is_supplementary(&self) -> bool {
+        self.flag & 0x800 != 0
+    }
+
+    /// Neither secondary nor supplementary, the one line of a read per aligner hit list.
+    pub fn is_primary(&self) -> bool {
+        !self.is_secondary() && !self.is_supplementary()
+    }
+}
+
+/// Where the mandatory fields of a record line end, and its numeric fields, parsed without
+/// borrowing the line. A reader can so skip invalid lines into the same buffer before lending
+/// out a valid one (see `SamReader::next_valid_ref`).
+#[derive(Debug, Clone, Copy)]
+struct RecordFields {
+    ends: [usize; 11],
+    /// Fields of the line, fewer than 11 only for lines parsed leniently
+    present: usize,
+    flag: u16,
+    pos: u32,
+    mapq: Mapq,
+    pnext: u32,
+    tlen: i32,
+}
+
+impl RecordFields {
+    /// QNAME to MAPQ, the fields a lenient line cannot do without.
+    const MANDATORY: usize = 5;
+
+    fn parse(line: &str) -> Result<Self, String> {
+        Self::parse_fields(line, 11)
+    }
+
+    /// `parse` for --lenient, the fields after MAPQ optional.
+    fn parse_lenient(line: &str) -> Result<Self, String> {
+        Self::parse_fields(line, Self::MANDATORY)
+    }
+
+    /// Parses a line of at least `required` fields.
+    fn parse_fields(line: &str, required: usize) -> Result<Self, String> {
+        let mut ends = [0; 11];
+        let mut start = 0;
+        let mut present = 0;
+        let mut split = line.splitn(12, '\t');
+        for end in ends.iter_mut() {
+            let Some(field) = split.next() else { break };
+            *end = start + field.len();
+            start = *end + 1;
+            present += 1;
+        }
+        if present < required {
+            return Err(format!("Invalid SAM line: {}", line))
+        }
+        let mut result = Self { ends, present, flag: 0, pos: 0, mapq: 0, pnext: 0, tlen: 0 };
+        result.flag = result.field(line, 1).parse().map_err(|_| "Invalid flag")?;
+        result.pos = result.field(line, 3).parse().map_err(|_| "Invalid position")?;
+        result.mapq = result.field(line, 4).parse().map_err(|_| "Invalid mapping quality")?;
+        if present > 7 {
+            result.pnext = result.field(line, 7).parse().map_err(|_| "Invalid pnext")?;
+        }
+        if present > 8 {
+            result.tlen = result.field(line, 8).parse().map_err(|_| "Invalid template length")?;
+        }
+        Ok(result)
+    }
+
+    /// Field `index` of the line, `*` if the line lacks it.
+    fn field<'a>(&self, line: &'a str, index: usize) -> &'a str {
+        if index >= self.present { return "*" };
+        let start = if index == 0 { 0 } else { self.ends[index - 1] + 1 };
+        &line[start..self.ends[index]]
+    }
+
+    /// The record of the line these fields were parsed from.
+    fn record<'a>(&self, line: &'a str) -> SamRef<'a> {
+        SamRef {
+            line,
+            qname: self.field(line, 0),
+            flag: self.flag,
+            rname: self.field(line, 2),
+            pos: self.pos,
+            mapq: self.mapq,
+            cigar: self.field(line, 5),
+            rnext: self.field(line, 6),
+            pnext: self.pnext,
+            tlen: self.tlen,
+            seq: self.field(line, 9),
+            qual: self.field(line, 10),
+            tags: if self.present < 11 { "" } else { line.get(self.ends[10] + 1..).unwrap_or("") },
+        }
+    }
+}
+
+/// CIGAR operation of a SAM record.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CigarOp {
+    Match,
+    Insertion,
+    Deletion,
+    Skip,
+    SoftClip,
+    HardClip,
+    Padding,
+    Equal,
+    Diff,
+}
+
+impl CigarOp {
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            'M' => Some(CigarOp::Match),
+            'I' => Some(CigarOp::Insertion),
+            'D' => Some(CigarOp::Deletion),
+            'N' => Some(CigarOp::Skip),
+            'S' => Some(CigarOp::SoftClip),
+            'H' => Some(CigarOp::HardClip),
+            'P' => Some(CigarOp::Padding),
+            '=' => Some(CigarOp::Equal),
+            'X' => Some(CigarOp::Diff),
+            _ => None,
+        }
+    }
+
+    /// Whether the operation consumes bases of SEQ (hard clips are not in SEQ).
+    pub fn consumes_query(&self) -> bool {
+        matches!(self, CigarOp::Match | CigarOp::Insertion | CigarOp::SoftClip | CigarOp::Equal | CigarOp::Diff)
+    }
+
+    pub fn consumes_reference(&self) -> bool {
+        matches!(self, CigarOp::Match | CigarOp::Deletion | CigarOp::Skip | CigarOp::Equal | CigarOp::Diff)
+    }
+
+    pub fn is_clip(&self) -> bool {
+        matches!(self, CigarOp::SoftClip | CigarOp::HardClip)
+    }
+}
+
+/// Operations of a CIGAR string as (length, operation), None for `*`, an empty CIGAR, a missing
+/// or overflowing length or an unknown operation.
+pub fn parse_cigar(cigar: &str) -> Option<Vec<(u32, CigarOp)>> {
+    let mut ops = Vec::new();
+    let mut length: Option<u32> = None;
+    for c in cigar.chars() {
+        match c.to_digit(10) {
+            Some(digit) => length = Some(length.unwrap_or(0).checked_mul(10)?.checked_add(digit)?),
+            None => ops.push((length.take()?, CigarOp::from_char(c)?)),
+        }
+    }
+    (length.is_none() && !ops.is_empty()).then_some(ops)
+}
+
+/// Mismatched and deleted reference bases of an MD string (`10A5^AC6`: one mismatch, two
+/// deleted bases), None if it is malformed.
+pub fn md_edits(md: &str) -> Option<u32> {
+    let mut edits = 0u32;
+    let mut deletion = false;
+    for c in md.chars() {
+        match c {
+            '0'..='9' => deletion = false,
+            '^' if !deletion => deletion = true,
+            'A'..='Z' | 'a'..='z' => edits = edits.checked_add(1)?,
+            _ => return None,
+        }
+    }
+    Some(edits)
+}
+
+/// Header of a SAM stream. Concatenated SAMs carry several header blocks, all of which are merged
+/// here; @SQ entries are keyed by name and the first length seen for a name wins.
+#[derive(Debug, Default, Clone)]
+pub struct SamHeader {
+    pub lines: Vec<String>,
+    pub sequences: HashMap<String, u32>,
+    pub blocks: usize,
+    pub conflicts: usize,
+}
+
+impl SamHeader {
+    pub fn add_line(&mut self, line: &str) {
+        self.lines.push(line.to_string());
+
+        let Some(fields) = line.strip_prefix("@SQ\t") else { return };
+        let mut name = None;
+        let mut length = None;
+        for field in fields.split('\t') {
+            if let Some(sn) = field.strip_prefix("SN:") {
+                name = Some(sn);
+            } else if let Some(ln) = field.strip_prefix("LN:") {
+                length = ln.parse::<u32>().ok();
+            }
+        }
+
+        let (Some(name), Some(length)) = (name, length) else {
+            eprintln!("Skipping invalid @SQ line: {}", line);
+            return
+        };
+        match self.sequences.get(name) {
+            Some(previous) if *previous != length => {
+                eprintln!("Warning: conflicting LN for @SQ SN:{} ({} vs {}), keeping {}", name, previous, length, previous);
+                self.conflicts += 1;
+            },
+            Some(_) => (),
+            None => { self.sequences.insert(name.to_string(), length); },
+        }
+    }
+
+    /// Adds the header of an input read after this one, as the header block of a concatenation.
+    pub fn merge(&mut self, other: SamHeader) {
+        for _block in 0..other.blocks {
+            self.blocks += 1;
+            if self.blocks > 1 {
+                eprintln!("Merging header block {} found in the middle of the input", self.blocks);
+            }
+        }
+        for line in &other.lines {
+            self.add_line(line);
+        }
+    }
+
+    /// @SQ names that do not follow the name format, sorted.
+    pub fn invalid_references(&self, format: &NameFormat) -> Vec<String> {
+        let mut result = self.sequences.keys().filter(|name| format.parse(name).is_err()).cloned().collect::<Vec<String>>();
+        result.sort();
+        result
+    }
+
+    /// Length (LN) of every @SQ reference gene by (taxid, gene), e.g. to normalize by gene
+    /// length. Names not following the name format are left out.
+    pub fn gene_lengths(&self, format: &NameFormat) -> HashMap<(TaxID, GeneID), u32> {
+        self.sequences.iter().filter_map(|(name, length)| Some((format.parse(name).ok()?, *length))).collect()
+    }
+}
+
+/// Iterator over the records of a SAM file. Header lines are collected into `header` wherever
+/// they occur, so header blocks in the middle of concatenated files are merged rather than parsed as records.
+/// Lines longer than `max_line_bytes` are skipped unread.
+pub struct SamReader {
+    reader: Box<dyn BufRead + Send>,
+    buffer: Vec<u8>,
+    in_header: bool,
+    pub header: SamHeader,
+    /// 1-based number of the line read last.
+    pub line: usize,
+    /// 0-based byte offset in the decompressed input of the line read last.
+    pub offset: u64,
+    next_offset: u64,
+    /// Whether the line read last ended in a newline
+    terminated: bool,
+    max_line_bytes: usize,
+    /// Records may lack the fields after MAPQ (--lenient)
+    lenient: bool,
+    parse_timer: SlowRecords,
+    progress: Option<Progress>,
+}
+
+/// Progress of a SAM read, reported to stderr every `every` records (--progress).
+#[derive(Debug)]
+struct Progress {
+    every: usize,
+    min_mapq: Mapq,
+    records: usize,
+    /// Records parsed by the reader that are aligned with at least `min_mapq`, None if records
+    /// are parsed elsewhere
+    passing: Option<usize>,
+    start: Instant,
+    bytes_start: u64,
+}
+
+impl Progress {
+    fn new(millions: u64, min_mapq: Mapq) -> Self {
+        Self { every: millions as usize * 1_000_000, min_mapq, records: 0, passing: None, start: Instant::now(), bytes_start: bytes_read() }
+    }
+
+    fn record(&mut self) {
+        self.records += 1;
+        if self.records.is_multiple_of(self.every) {
+            eprintln!("Progress: {}", self);
+        }
+    }
+
+    fn parsed(&mut self, sam: &SamRef) {
+        let passing = self.passing.get_or_insert(0);
+        if sam.is_aligned() && sam.mapq >= self.min_mapq {
+            *passing += 1;
+        }
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.start.elapsed().as_secs_f64();
+        let passing = self.passing.map_or("NA".to_string(), |passing| passing.to_string());
+        write!(f, "{} records, {} with mapq >= {}, {:.0} records/s, {:.1} MB read", self.records, passing, self.min_mapq,
+            self.records as f64 / seconds.max(f64::EPSILON), (bytes_read() - self.bytes_start) as f64 / 1e6)
+    }
+}
+
+impl SamReader {
+    pub const DEFAULT_MAX_LINE_BYTES: usize = 10_000_000;
+    pub const DEFAULT_SLOW_RECORDS: usize = 10;
+
+    pub fn new(reader: Box<dyn BufRead + Send>) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            in_header: false,
+            header: SamHeader::default(),
+            line: 0,
+            offset: 0,
+            next_offset: 0,
+            terminated: true,
+            max_line_bytes: Self::DEFAULT_MAX_LINE_BYTES,
+            lenient: false,
+            parse_timer: SlowRecords::new(Self::DEFAULT_SLOW_RECORDS),
+            progress: None,
+        }
+    }
+
+    /// Applies --max-line-bytes, --lenient, --slow-records and --progress.
+    pub fn with_args(mut self, args: &Args) -> Self {
+        self.max_line_bytes = args.max_line_bytes;
+        self.lenient = args.lenient;
+        self.parse_timer = SlowRecords::new(args.slow_records);
+        self.progress = args.progress.map(|millions| Progress::new(millions, args.min_mapq));
+        self
+    }
+
+    /// Bytes of the input read so far, counting the newline that `ConcatReader` adds to an input
+    /// whose last line lacks one, as if the input were followed by another.
+    pub fn consumed(&self) -> u64 {
+        self.next_offset + !self.terminated as u64
+    }
+
+    /// Next record that could be parsed. Invalid records fail the read unless --skip-invalid, in
+    /// which case they are logged and skipped like the other anomalies. An I/O error
+    /// (e.g. a truncated gzip stream) is logged and ends the input. Records whose flag disagrees
+    /// with their rname are logged but still returned.
+    pub fn next_valid(&mut self, anomalies: &mut AnomalyLog) -> Result<Option<Sam>, AnomalyError> {
+        loop {
+            match self.next() {
+                None => return Ok(None),
+                Some(Ok(sam)) => {
+                    self.check_flag(&sam.view(), anomalies)?;
+                    return Ok(Some(sam))
+                },
+                Some(Err(e)) => if !self.skip_error(e, anomalies)? { return Ok(None) },
+            }
+        }
+    }
+
+    /// As `next_valid`, the record borrowed from `line`, which is reused from one record to the
+    /// next. Saves copying the fields of every record into a `Sam`.
+    pub fn next_valid_ref<'a>(&mut self, line: &'a mut String, anomalies: &mut AnomalyLog) -> Result<Option<SamRef<'a>>, AnomalyError> {
+        let fields = loop {
+            let fields = match self.next_record_into(line) {
+                None => return Ok(None),
+                Some(read) => read.and_then(|()| self.parse(line, self.fields_parser())),
+            };
+            match fields {
+                Ok(fields) => break fields,
+                Err(e) => if !self.skip_error(e, anomalies)? { return Ok(None) },
+            }
+        };
+        let sam = fields.record(line);
+        if let Some(progress) = self.progress.as_mut() {
+            progress.parsed(&sam);
+        }
+        self.check_flag(&sam, anomalies)?;
+        Ok(Some(sam))
+    }
+
+    /// Logs a record whose flag disagrees with its rname.
+    fn check_flag(&self, sam: &SamRef, anomalies: &mut AnomalyLog) -> Result<(), AnomalyError> {
+        if sam.is_unmapped() == sam.is_aligned() {
+            anomalies.record(Anomaly::FlagRnameMismatch, &format!("{} flag {} rname {}", sam.qname, sam.flag, sam.rname), Some(self.line))?;
+        }
+        Ok(())
+    }
+
+    /// Logs an error of the record just read, false if it ends the input.
+    fn skip_error(&self, e: std::io::Error, anomalies: &mut AnomalyLog) -> Result<bool, AnomalyError> {
+        let category = match e.kind() {
+            std::io::ErrorKind::InvalidData => Anomaly::InvalidRecord,
+            std::io::ErrorKind::FileTooLarge => Anomaly::OversizedLine,
+            _ => Anomaly::TruncatedInput,
+        };
+        anomalies.record(category, &e.to_string(), Some(self.line))?;
+        Ok(category != Anomaly::TruncatedInput)
+    }
+
+    /// Strict or lenient (--lenient) parsing of the fields of a record.
+    fn fields_parser(&self) -> fn(&str) -> Result<RecordFields, String> {
+        if self.lenient { RecordFields::parse_lenient } else { RecordFields::parse }
+    }
+
+    /// Parses the record line just read, timed with --timing.
+    fn parse<T>(&mut self, line: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> std::io::Result<T> {
+        self.parse_timer.time(Phase::Parse, self.line, line, || parse(line))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("byte {}: {}", self.offset, e)))
+    }
+
+    /// Next line without its line ending. A line longer than `max_line_bytes` is skipped without
+    /// being buffered and returned as a FileTooLarge error.
+    fn read_line(&mut self) -> Option<std::io::Result<String>> {
+        let mut line = String::new();
+        Some(self.read_line_into(&mut line)?.map(|()| line))
+    }
+
+    /// As `read_line`, into `line`, whose allocation is reused from one line to the next.
+    fn read_line_into(&mut self, line: &mut String) -> Option<std::io::Result<()>> {
+        self.buffer.clear();
+        self.offset = self.next_offset;
+        let limit = self.max_line_bytes as u64 + 1;
+        match (&mut self.reader).take(limit).read_until(b'\n', &mut self.buffer) {
+            Ok(0) => return None,
+            Ok(read) => self.next_offset += read as u64,
+            Err(e) => return Some(Err(e)),
+        }
+        if self.buffer.last() != Some(&b'\n') && self.buffer.len() > self.max_line_bytes {
+            let rest = match self.reader.skip_until(b'\n') {
+                Ok(rest) => rest,
+                Err(e) => return Some(Err(e)),
+            };
+            self.next_offset += rest as u64;
+            let start = String::from_utf8_lossy(&self.buffer[..self.buffer.len().min(40)]).into_owned();
+            let length = self.buffer.len() + rest.saturating_sub(1);
+            return Some(Err(std::io::Error::new(std::io::ErrorKind::FileTooLarge, format!("line of {} bytes exceeds --max-line-bytes {}: {}...", length, self.max_line_bytes, start))))
+        }
+        self.terminated = self.buffer.last() == Some(&b'\n');
+        if self.terminated {
+            self.buffer.pop();
+        }
+        if self.buffer.last() == Some(&b'\r') {
+            self.buffer.pop();
+        }
+        let offset = self.offset;
+        line.clear();
+        match std::str::from_utf8(&self.buffer) {
+            Ok(text) => line.push_str(text),
+            Err(e) => return Some(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("byte {}: {}", offset, e)))),
+        }
+        Some(Ok(()))
+    }
+}
+
+impl SamReader {
+    /// Reads the header block at the start of the input, so that it is known before the first
+    /// record is. Header blocks further on are merged as they are reached.
+    pub fn read_header(&mut self) -> std::io::Result<()> {
+        while self.reader.fill_buf()?.first() == Some(&b'@') {
+            self.line += 1;
+            match self.read_line() {
+                Some(Ok(line)) => self.add_header_line(&line),
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn add_header_line(&mut self, line: &str) {
+        if !self.in_header {
+            self.in_header = true;
+            self.header.blocks += 1;
+            if self.header.blocks > 1 {
+                eprintln!("Merging header block {} found in the middle of the input", self.header.blocks);
+            }
+        }
+        self.header.add_line(line);
+    }
+
+    /// Next line that is not a header line, unparsed, for records parsed elsewhere (e.g. on
+    /// worker threads). Header lines are collected as by the iterator.
+    pub fn next_record_line(&mut self) -> Option<std::io::Result<String>> {
+        let mut line = String::new();
+        Some(self.next_record_into(&mut line)?.map(|()| line))
+    }
+
+    /// As `next_record_line`, into `line`.
+    fn next_record_into(&mut self, line: &mut String) -> Option<std::io::Result<()>> {
+        loop {
+            self.line += 1;
+            if let Err(e) = self.read_line_into(line)? {
+                return Some(Err(e)) // Propagate the I/O error
+            }
+
+            if line.starts_with('@') {
+                self.add_header_line(line);
+                continue
+            }
+            self.in_header = false;
+            if let Some(progress) = self.progress.as_mut() {
+                progress.record();
+            }
+            return Some(Ok(()))
+        }
+    }
+}
+
+impl Iterator for SamReader {
+    type Item = Result<Sam, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.next_record_line()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        // Parse the line into a Sam struct, which keeps the line
+        let sam = self.parse(&line, self.fields_parser()).map(|fields| Sam { line, fields });
+        if let (Some(progress), Ok(sam)) = (self.progress.as_mut(), &sam) {
+            progress.parsed(&sam.view());
+        }
+        Some(sam)
+    }
+}
+
+/// Reports the slowest records to parse of a timed run once the input is done with.
+impl Drop for SamReader {
+    fn drop(&mut self) {
+        let slowest = self.parse_timer.slowest();
+        if slowest.is_empty() { return };
+        eprintln!("Slowest records to parse:");
+        for record in slowest {
+            eprintln!("  {}", record);
+        }
+    }
+}
+
+/// A function that returns an iterator over Sam structs from a SAM file, or stdin for `-`.
+/// Compression (gzip, zstd or bzip2) is detected by its magic bytes. The leading header is read up front and fails if any
+/// @SQ name does not follow the default taxid_geneid format, listing them all; a headerless SAM
+/// reads as before.
+pub fn sam_file_iterator<P: AsRef<Path>>(filename: P) -> Result<SamReader, SamFileError> {
+    checked_header(SamReader::new(open_reader(filename)?), &NameFormat::default())
+}
+
+fn checked_header(mut reader: SamReader, format: &NameFormat) -> Result<SamReader, SamFileError> {
+    reader.read_header()?;
+    let invalid = reader.header.invalid_references(format);
+    if !invalid.is_empty() {
+        return Err(SamFileError::InvalidReferences(invalid))
+    }
+    Ok(reader)
+}
+
+/// The --input and --inputs SAMs, read as one, with the reader options of the arguments. Every
+/// file is opened before any is read so a missing one fails before counting starts; record
+/// numbers run on across files.
+pub fn sam_input(args: &Args) -> Result<SamReader, SamFileError> {
+    let reader = match args.input_files()[..] {
+        [] => checked_header(SamReader::new(open_reader(&args.input)?), &args.name_format)?,
+        [file] => checked_header(SamReader::new(open_reader(file)?), &args.name_format)?,
+        ref files => checked_header(SamReader::new(Box::new(ConcatReader::new(files)?)), &args.name_format)?,
+    };
+    Ok(reader.with_args(args))
+}
 
-    Ok((first_part.parse()?, second_part.parse()?))
+/// The --input and --inputs SAMs each with a reader of its own, to be read at the same time.
+/// The header of the first is checked as `sam_input` checks the leading header, record numbers
+/// start at 1 in each.
+pub fn sam_inputs(args: &Args) -> Result<Vec<SamReader>, SamFileError> {
+    let files = match args.input_files() {
+        files if files.is_empty() => vec![args.input.as_str()],
+        files => files,
+    };
+    let mut readers = Vec::with_capacity(files.len());
+    for (index, file) in files.into_iter().enumerate() {
+        let mut reader = SamReader::new(open_reader(file)?);
+        match index {
+            0 => reader = checked_header(reader, &args.name_format)?,
+            _ => reader.read_header()?,
+        }
+        readers.push(reader.with_args(args));
+    }
+    Ok(readers)
 }
 
+/// The items of one read of name-grouped input, as collected by GroupByQname.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QnameGroup<T> {
+    pub sample: SampleID,
+    pub name: String,
+    pub items: Vec<T>,
+    /// Items past the buffer bound, dropped instead of collected
+    pub overflow: usize,
+}
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-#[command(arg_required_else_help(true))]
-#[command(max_term_width = 120)] // term_width sets it fixed, max term_width can be smaller
-pub struct Args {
-    /// Input file (.sam|.sam.gz)
-    #[arg(short = 'i', long = "input", default_value_t = String::default())]
-    pub input: String,
+impl<T> QnameGroup<T> {
+    pub fn is_oversized(&self) -> bool {
+        self.overflow > 0
+    }
+}
 
-    /// Mapq threshold (filter everything strictly below)
-    #[arg(short = 'm', long = "min_mapq", default_value_t = 4)]
-    pub min_mapq: u8,
+/// Hashes of the (sample, name) of the last `RecentNames::CAPACITY` closed groups. A read among
+/// them that shows up again means the input is not grouped by name.
+#[derive(Debug, Default)]
+struct RecentNames {
+    order: VecDeque<u64>,
+    set: HashSet<u64>,
+}
 
-    /// Minimum number of genes to keep.
-    #[arg(short = 'g', long = "min_genes", default_value_t = 60)]
-    pub min_genes: i32,
+impl RecentNames {
+    const CAPACITY: usize = 4096;
 
-    /// Tolerate this many incoming leaked reads
-    #[arg(short = 'g', long = "genes", default_value_t = 10)]
-    pub max_leaked_reads: i32,
-}
+    fn key(sample: SampleID, name: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (sample, name).hash(&mut hasher);
+        hasher.finish()
+    }
 
+    fn contains(&self, key: u64) -> bool {
+        self.set.contains(&key)
+    }
 
-/// Custom error type to handle different kinds of errors
-#[derive(Debug, Error)]
-pub enum SamFileError {
-    #[error("I/O error: {0}")]
-    Io(#[from] std::io::Error),
-    #[error("Gzip decode error: {0}")]
-    Gzip(#[from] flate2::DecompressError),
+    fn insert(&mut self, key: u64) {
+        if !self.set.insert(key) { return };
+        self.order.push_back(key);
+        if self.order.len() > Self::CAPACITY {
+            let oldest = self.order.pop_front().expect("recent names are not empty");
+            self.set.remove(&oldest);
+        }
+    }
 }
 
-/// Define a struct to represent a line in the SAM file
+/// Collects name-grouped input (all alignments of a read in a row, as bowtie2 -k writes them)
+/// into one group per read and sample. At most `max_records` items of a read are buffered, so a
+/// read with pathologically many alignments on a pipe cannot exhaust memory; the rest is only
+/// counted. Unless `--assume-grouped`, reads are checked to not reappear after their group closed.
 #[derive(Debug)]
-pub struct Sam {
-    pub qname: String,
-    pub flag: u16,
-    pub rname: String,
-    pub pos: u32,
-    pub mapq: u8,
-    pub cigar: String,
-    pub rnext: String,
-    pub pnext: u32,
-    pub tlen: i32,
-    pub seq: String,
-    pub qual: String,
+pub struct GroupByQname<T> {
+    pub max_records: usize,
+    current: Option<QnameGroup<T>>,
+    /// None with --assume-grouped
+    recent: Option<RecentNames>,
 }
 
-impl Sam {
-    /// Create a new Sam struct from a SAM file line
-    pub fn from_line(line: &str) -> Result<Self, String> {
-        let fields: Vec<&str> = line.split('\t').collect();
-
-        if fields.len() < 11 {
-            return Err(format!("Invalid SAM line: {}", line));
-        }
-
-        Ok(Sam {
-            qname: fields[0].to_string(),
-            flag: fields[1].parse().map_err(|_| "Invalid flag")?,
-            rname: fields[2].to_string(),
-            pos: fields[3].parse().map_err(|_| "Invalid position")?,
-            mapq: fields[4].parse().map_err(|_| "Invalid mapping quality")?,
-            cigar: fields[5].to_string(),
-            rnext: fields[6].to_string(),
-            pnext: fields[7].parse().map_err(|_| "Invalid pnext")?,
-            tlen: fields[8].parse().map_err(|_| "Invalid template length")?,
-            seq: fields[9].to_string(),
-            qual: fields[10].to_string(),
-        })
+impl<T> GroupByQname<T> {
+    pub fn new(max_records: usize, check: bool) -> Self {
+        Self { max_records, current: None, recent: check.then(RecentNames::default) }
     }
 
-    pub fn is_aligned(&self) -> bool {
-        return self.rname != "*";
+    pub fn from_args(args: &Args) -> Self {
+        Self::new(args.max_group_records, !args.assume_grouped)
     }
-}
-
-// type SamFileIterator = Result<impl Iterator<Item = Result<Sam, std::io::Error>>, SamFileError>;
 
-/// A function that returns an iterator over Sam structs from a SAM file
-pub fn sam_file_iterator<P: AsRef<Path>>(filename: P) ->  Result<impl Iterator<Item = Result<Sam, std::io::Error>>, SamFileError> {
-    // Open the file
-    let file = File::open(&filename)?;
-
-    // Determine if the file is gzipped based on extension
-    let reader: Box<dyn BufRead> = if filename.as_ref().extension() == Some(OsStr::new("gz")) {
-        let gz_decoder = GzDecoder::new(BufReader::new(file));
-        Box::new(BufReader::new(gz_decoder))
-    } else {
-        Box::new(BufReader::new(file))
-    };
-
-    // Create an iterator that processes each line into a Sam struct
-    Ok(reader.lines().filter_map(|line_result| {
-        match line_result {
-            Ok(line) => {
-                if line.starts_with('@') {
-                    // Skip header lines starting with '@'
-                    None
-                } else {
-                    // Parse the line into a Sam struct
-                    match Sam::from_line(&line) {
-                        Ok(sam) => Some(Ok(sam)),
-                        Err(e) => Some(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e))),
-                    }
-                }
+    /// Adds an item of read `name` of `sample`, read from input `record`. An item of another read
+    /// completes the group collected so far, which is returned. Fails with an ungrouped_input
+    /// error of the current input of `anomalies` if the read was among the recently closed ones,
+    /// as the input is then not grouped by name.
+    pub fn push(&mut self, sample: SampleID, name: &str, item: T, record: usize, anomalies: &AnomalyLog) -> Result<Option<QnameGroup<T>>, AnomalyError> {
+        if let Some(group) = self.current.as_mut().filter(|group| group.sample == sample && group.name == name) {
+            match group.items.len() < self.max_records {
+                true => group.items.push(item),
+                false => group.overflow += 1,
+            }
+            return Ok(None)
+        }
+        if let Some(recent) = self.recent.as_mut() {
+            if let Some(closed) = &self.current {
+                recent.insert(RecentNames::key(closed.sample, &closed.name));
+            }
+            if recent.contains(RecentNames::key(sample, name)) {
+                let example = format!("read {} reappears after its alignments ended, the input is not grouped by read name (group it, e.g. with samtools sort -n, or pass --assume-grouped)", name);
+                return Err(anomalies.fatal(Anomaly::UngroupedInput, &example, Some(record)))
             }
-            Err(e) => Some(Err(e)), // Propagate the I/O error
         }
-    }))
+        Ok(self.current.replace(QnameGroup { sample, name: name.to_string(), items: vec![item], overflow: 0 }))
+    }
+
+    /// Completes the group collected last, to be called once the input is exhausted.
+    pub fn finish(&mut self) -> Option<QnameGroup<T>> {
+        self.current.take()
+    }
 }
 
 
 /// Function to read a TSV file into a HashMap
 pub fn read_tsv_to_hashmap<P: AsRef<Path>>(filename: P) -> std::io::Result<HashMap<String, String>> {
     // Open the file
-    let file = File::open(filename)?;
+    let file = open_file(filename)?;
     let reader = BufReader::new(file);
 
     // Create an empty HashMap
@@ -145,7 +1575,7 @@ pub fn read_tsv_to_hashmap<P: AsRef<Path>>(filename: P) -> std::io::Result<HashM
 
     // Read the file line by line
     for line_result in reader.lines() {
-        let line = line_result?; // Handle any I/O error
+        let line = strip_cr(line_result?); // Handle any I/O error
         
         // Split the line by tab characters
         let mut columns = line.splitn(2, '\t');
@@ -161,22 +1591,857 @@ pub fn read_tsv_to_hashmap<P: AsRef<Path>>(filename: P) -> std::io::Result<HashM
     Ok(hashmap)
 }
 
+/// A single mismatch between two result structures, `None` marking a side where the key is missing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    pub key: String,
+    pub field: String,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+impl Difference {
+    pub fn new(key: impl Display, field: &str, left: Option<String>, right: Option<String>) -> Self {
+        Self {
+            key: key.to_string(),
+            field: field.to_string(),
+            left,
+            right,
+        }
+    }
+
+    /// Compares two values exactly, returning a Difference if they are not equal.
+    pub fn exact<T: PartialEq + Display>(key: impl Display, field: &str, left: T, right: T) -> Option<Self> {
+        if left == right {
+            return None
+        }
+        Some(Self::new(key, field, Some(left.to_string()), Some(right.to_string())))
+    }
+
+    /// Compares two floats within an absolute tolerance, NaN only being equal to NaN.
+    pub fn float(key: impl Display, field: &str, left: f64, right: f64, tolerance: f64) -> Option<Self> {
+        let equal = (left.is_nan() && right.is_nan()) || (left - right).abs() <= tolerance;
+        if equal {
+            return None
+        }
+        Some(Self::new(key, field, Some(left.to_string()), Some(right.to_string())))
+    }
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\t{}\t{}\t{}",
+            self.key,
+            self.field,
+            self.left.as_deref().unwrap_or("NA"),
+            self.right.as_deref().unwrap_or("NA"))
+    }
+}
+
+/// Diffs two maps independently of their iteration order. Keys are visited sorted so the
+/// resulting list is deterministic. Keys on one side only are reported with field `field` and
+/// `describe` of the present value, keys on both sides are handed to `diff_value`.
+pub fn diff_maps<K, V>(
+    left: &HashMap<K, V>,
+    right: &HashMap<K, V>,
+    field: &str,
+    describe: impl Fn(&V) -> String,
+    mut diff_value: impl FnMut(&K, &V, &V, &mut Vec<Difference>),
+) -> Vec<Difference>
+where K: Ord + Hash + Display {
+    let mut keys = left.keys().chain(right.keys().filter(|k| !left.contains_key(k))).collect::<Vec<&K>>();
+    keys.sort();
+
+    let mut result = Vec::new();
+    for key in keys {
+        match (left.get(key), right.get(key)) {
+            (Some(l), Some(r)) => diff_value(key, l, r, &mut result),
+            (Some(l), None) => result.push(Difference::new(key, field, Some(describe(l)), None)),
+            (None, Some(r)) => result.push(Difference::new(key, field, None, Some(describe(r)))),
+            (None, None) => unreachable!(),
+        }
+    }
+    result
+}
+
+#[derive(Debug, Copy, Clone)]
 pub struct FromTo {
     pub query: TinyTaxID,
     pub reference: TinyTaxID,
     pub query_gene: TinyGeneID,
     pub reference_gene: TinyGeneID,
+    /// Sample of the record with --split-by, 0 otherwise
+    pub sample: SampleID,
+    /// Read bases aligned (`Sam::aligned_length`) when leak lengths are tracked, None otherwise
+    pub aligned_length: Option<u32>,
+    /// Share of its read the alignment counts with --multimap-weighting fraction, None for a
+    /// whole read
+    pub weight: Option<f64>,
+    /// Whether the record aligned to the reverse strand when strands are tracked (--strand-bias),
+    /// None otherwise
+    pub reverse: Option<bool>,
 }
 
-pub fn sam_to_ids(sam: &Sam) -> FromTo {
-    let (query_tid, query_gid) = taxid_geneid(&sam.qname).expect("Reference not parseable");
-    let (ref_tid, ref_gid) = taxid_geneid(&sam.rname).expect("Reference not parseable");
-    let correct = query_tid == ref_tid && query_gid == ref_gid;
+/// Parses query and reference ids of a record within `bounds`, the error naming the unparseable
+/// token. Reference names are looked up in `names` before they are parsed.
+pub fn sam_to_ids(sam: &SamRef, bounds: &IdBounds, names: &mut NameCache) -> Result<FromTo, String> {
+    let (query_tid, query_gid) = bounds.parse(sam.qname).map_err(|e| format!("Query not parseable: {}: {}", sam.qname, e))?;
+    let (ref_tid, ref_gid) = names.reference(bounds, sam.rname).map_err(|e| format!("Reference not parseable: {}: {}", sam.rname, e))?;
 
-    FromTo {
+    Ok(FromTo {
         query: query_tid as TinyTaxID,
         reference: ref_tid as TinyTaxID,
         query_gene: query_gid as TinyGeneID,
         reference_gene: ref_gid as TinyGeneID,
+        sample: 0,
+        aligned_length: None,
+        weight: None,
+        reverse: None,
+    })
+}
+
+impl Default for Args {
+    /// Every option at the default of the command line, without an input.
+    fn default() -> Self {
+        let matches = Self::command().arg_required_else_help(false).get_matches_from([env!("CARGO_PKG_NAME")]);
+        Self::from_arg_matches(&matches).expect("Default arguments are valid")
+    }
+}
+
+impl Args {
+    /// Whether the aligned lengths of leaked reads are tracked, for --leak-length-report or
+    /// --min-median-leak-len.
+    pub fn tracks_leak_lengths(&self) -> bool {
+        self.leak_length_report.is_some() || self.min_median_leak_len.is_some()
+    }
+
+    /// Whether only the best alignment of every read is counted, with --best-per-read or
+    /// --multimap-weighting best.
+    pub fn keeps_best_per_read(&self) -> bool {
+        self.best_per_read || self.multimap_weighting == MultimapWeighting::Best
+    }
+
+    /// Number of genes of the panel, from --panel or else --n-genes.
+    pub fn n_genes(&self) -> Option<usize> {
+        self.panel.as_ref().map(Panel::len).or(self.n_genes)
+    }
+
+    /// --input then --inputs.
+    pub fn input_files(&self) -> Vec<&str> {
+        std::iter::once(self.input.as_str()).filter(|input| !input.is_empty()).chain(self.inputs.iter().map(String::as_str)).collect()
+    }
+
+    /// The input files as named in messages.
+    pub fn input_label(&self) -> String {
+        self.input_files().join(",")
+    }
+
+    /// Copies stdin (`--input -`) into a temporary file that --input then names, for tools that
+    /// read their input more than once. The copy is deleted when the returned guard is dropped.
+    pub fn spool_stdin(&mut self) -> std::io::Result<Option<SpooledStdin>> {
+        if !is_stdin(&self.input) {
+            return Ok(None)
+        }
+        let spooled = SpooledStdin::new()?;
+        self.input = spooled.path.to_string_lossy().into_owned();
+        Ok(Some(spooled))
+    }
+}
+
+/// Refuses outputs that need per-gene counts when running with --no-genes.
+pub fn require_genes(args: &Args, output: &str) -> Result<(), String> {
+    if args.no_genes {
+        return Err(format!("{} needs per-gene counts and cannot run with --no-genes", output))
+    }
+    Ok(())
+}
+
+/// Refuses --multimap-weighting fraction in tools that count every alignment as a whole read.
+pub fn require_whole_reads(args: &Args, tool: &str) -> Result<(), String> {
+    if args.multimap_weighting == MultimapWeighting::Fraction {
+        return Err(format!("{} counts whole reads and cannot run with --multimap-weighting fraction", tool))
+    }
+    Ok(())
+}
+
+/// Unwraps a result in a binary, printing the error and exiting with code 1 instead of panicking.
+pub fn or_exit<T, E: Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    })
+}
+
+/// Kinds of input problems that are tolerated (and counted) unless running with --strict.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Anomaly {
+    InvalidRecord,
+    UnparseableName,
+    FlagRnameMismatch,
+    TruncatedInput,
+    NonFiniteNormalization,
+    GeneIdBase,
+    OversizedGroup,
+    OversizedLine,
+    UngroupedInput,
+}
+
+impl Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Anomaly::InvalidRecord => "invalid_record",
+            Anomaly::UnparseableName => "unparseable_name",
+            Anomaly::FlagRnameMismatch => "flag_rname_mismatch",
+            Anomaly::TruncatedInput => "truncated_input",
+            Anomaly::NonFiniteNormalization => "non_finite_normalization",
+            Anomaly::GeneIdBase => "gene_id_base",
+            Anomaly::OversizedGroup => "oversized_group",
+            Anomaly::OversizedLine => "oversized_line",
+            Anomaly::UngroupedInput => "ungrouped_input",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A count that no longer fits its type after adding, e.g. when merging tables of many samples.
+#[derive(Debug, Error)]
+#[error("Count overflow: {0}")]
+pub struct CountOverflow(pub String);
+
+/// Sum of two counts, an error naming `what` instead of wrapping.
+pub fn checked_count(a: u64, b: u64, what: impl Display) -> Result<u64, CountOverflow> {
+    a.checked_add(b).ok_or_else(|| CountOverflow(format!("{} + {} for {}", a, b, what)))
+}
+
+/// An anomaly that was fatal because of --strict, or an invalid record without --skip-invalid.
+#[derive(Debug, Error)]
+#[error("{category} in {input} at record {record}: {example}", record = .record.map_or("NA".to_string(), |r| r.to_string()))]
+pub struct AnomalyError {
+    pub category: Anomaly,
+    pub example: String,
+    pub input: String,
+    pub record: Option<usize>,
+}
+
+/// Error of a reader: an input that cannot be used as it is, or an anomaly that was fatal.
+#[derive(Debug, Error)]
+pub enum InputError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Sam(#[from] SamFileError),
+    #[error(transparent)]
+    Anomaly(#[from] AnomalyError),
+}
+
+#[derive(Debug, Clone)]
+struct AnomalyEntry {
+    count: usize,
+    first_example: String,
+    input: String,
+    record: Option<usize>,
+}
+
+/// Single place all readers and builders report anomalies to. In strict mode the first anomaly
+/// is returned as an error, otherwise anomalies are counted per category with their first example.
+/// Invalid records are fatal unless skipped with --skip-invalid, and then logged one by one.
+#[derive(Debug, Default)]
+pub struct AnomalyLog {
+    strict: bool,
+    skip_invalid: bool,
+    input: String,
+    entries: HashMap<Anomaly, AnomalyEntry>,
+}
+
+impl AnomalyLog {
+    pub fn new(strict: bool, skip_invalid: bool) -> Self {
+        Self { strict, skip_invalid, ..Default::default() }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        Self::new(args.strict, args.skip_invalid)
+    }
+
+    /// Input file that subsequent anomalies are attributed to.
+    pub fn set_input(&mut self, input: &str) {
+        self.input = input.to_string();
+    }
+
+    /// Whether an anomaly of `category` fails the read: any with --strict, invalid records
+    /// without --skip-invalid.
+    pub fn is_fatal(&self, category: Anomaly) -> bool {
+        self.strict || (category == Anomaly::InvalidRecord && !self.skip_invalid)
+    }
+
+    pub fn record(&mut self, category: Anomaly, example: &str, record: Option<usize>) -> Result<(), AnomalyError> {
+        let invalid = category == Anomaly::InvalidRecord;
+        if self.is_fatal(category) {
+            return Err(AnomalyError { category, example: example.to_string(), input: self.input.clone(), record })
+        }
+        if invalid {
+            eprintln!("Skipping invalid record in {} at line {}: {}", self.input, record.map_or("NA".to_string(), |r| r.to_string()), example);
+        }
+
+        let entry = self.entries.entry(category).or_insert_with(|| AnomalyEntry {
+            count: 0,
+            first_example: example.to_string(),
+            input: self.input.clone(),
+            record,
+        });
+        entry.count += 1;
+        Ok(())
+    }
+
+    /// `record` of `count` anomalies of a category at once, the first of them `example` at `record`.
+    pub fn record_many(&mut self, category: Anomaly, example: &str, record: Option<usize>, count: usize) -> Result<(), AnomalyError> {
+        if count == 0 { return Ok(()) };
+        self.record(category, example, record)?;
+        self.entries.get_mut(&category).expect("The anomaly was just recorded").count += count - 1;
+        Ok(())
+    }
+
+    /// An error of `category` at the current input that no setting tolerates.
+    pub fn fatal(&self, category: Anomaly, example: &str, record: Option<usize>) -> AnomalyError {
+        AnomalyError { category, example: example.to_string(), input: self.input.clone(), record }
+    }
+
+    pub fn count(&self, category: Anomaly) -> usize {
+        self.entries.get(&category).map_or(0, |e| e.count)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn write_tsv(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut categories = self.entries.keys().collect::<Vec<&Anomaly>>();
+        categories.sort();
+
+        writeln!(writer, "category\tcount\tfirst_example\tinput\trecord")?;
+        for category in categories {
+            let e = &self.entries[category];
+            writeln!(writer, "{}\t{}\t{}\t{}\t{}", category, e.count, e.first_example.replace('\t', " "), e.input, e.record.map_or("NA".to_string(), |r| r.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the log to the --anomaly-log file if given, otherwise summarizes non-empty logs on stderr.
+    pub fn finish(&self, args: &Args) -> std::io::Result<()> {
+        let skipped = self.count(Anomaly::InvalidRecord);
+        if skipped > 0 {
+            eprintln!("Skipped {} invalid records (--skip-invalid)", skipped);
+        }
+        match &args.anomaly_log {
+            Some(path) => {
+                let mut writer = std::io::BufWriter::new(SafeWriter::create(path, !args.no_atomic)?);
+                self.write_tsv(&mut writer)?;
+                writer.into_inner().map_err(|e| e.into_error())?.finish()
+            },
+            None if !self.is_empty() => self.write_tsv(&mut std::io::stderr().lock()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes the --resolution-report of the labels resolved so far, if asked for.
+pub fn write_resolution_report(resolver: &Resolver, args: &Args) -> Result<(), String> {
+    let Some(path) = &args.resolution_report else { return Ok(()) };
+    let mut writer = create_output(path, args.threads, args.compression_level, !args.no_atomic).map_err(|e| format!("Cannot create {}: {}", path, e))?;
+    let rows = resolver.write_report(&mut writer).and_then(|rows| writer.flush().map(|_| rows)).map_err(|e| format!("Error writing {}: {}", path, e))?;
+    eprintln!("{}\t{} labels resolved through aliases, placeholders or conflicting sources", path, rows);
+    Ok(())
+}
+
+/// Per-record diagnostics for the taxa of --debug-taxon, written to --debug-log. When no taxon is
+/// given nothing is parsed or written, so the check in the counting path costs a branch.
+#[derive(Default)]
+pub struct DebugTaxa {
+    taxa: HashSet<TaxID>,
+    format: NameFormat,
+    writer: Option<Box<dyn Write>>,
+}
+
+impl DebugTaxa {
+    pub fn from_args(args: &Args) -> Result<Self, String> {
+        if args.debug_taxon.is_empty() {
+            return Ok(Self::default())
+        }
+        let (taxa, resolver) = Self::resolve(args)?;
+        if let Some(resolver) = &resolver {
+            write_resolution_report(resolver, args)?;
+        }
+        let mut writer: Box<dyn Write> = Box::new(std::io::BufWriter::new(create_file(&args.debug_log).map_err(|e| e.to_string())?));
+        writeln!(writer, "record\tqname\tflag\trname\tmapq\tquery_taxon\tquery_gene\treference_taxon\treference_gene\tdecision").map_err(|e| e.to_string())?;
+        Ok(Self { taxa, format: args.name_format.clone(), writer: Some(writer) })
+    }
+
+    /// Fails on a --debug-taxon that is neither an id nor a label of --map, for binaries to check
+    /// before reading any records.
+    pub fn check(args: &Args) -> Result<(), String> {
+        Self::resolve(args).map(|_resolved| ())
+    }
+
+    /// Ids of --debug-taxon, with the resolver of their labels if any needed --map.
+    fn resolve(args: &Args) -> Result<(HashSet<TaxID>, Option<Resolver>), String> {
+        let mut resolver = None;
+        let mut taxa = HashSet::new();
+        for taxon in &args.debug_taxon {
+            if let Ok(id) = taxon.parse::<TaxID>() {
+                taxa.insert(id);
+                continue
+            }
+            let Some(map) = &args.map else {
+                return Err(format!("--debug-taxon {} is not an id, labels need --map", taxon))
+            };
+            let resolver = match &mut resolver {
+                Some(resolver) => resolver,
+                None => resolver.insert(Resolver::from_args(args, map)?),
+            };
+            let id = resolver.get(taxon).ok_or_else(|| format!("--debug-taxon {} is not a label of {} (--label-normalize {})", taxon, map, args.label_normalize))?;
+            taxa.insert(id);
+        }
+        Ok((taxa, resolver))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// True if the query or the reference of the record belongs to a debugged taxon.
+    pub fn involves(&self, sam: &SamRef) -> bool {
+        self.is_enabled() && [sam.qname, sam.rname].iter()
+            .any(|name| self.format.parse(name).is_ok_and(|(taxid, _gene)| self.taxa.contains(&taxid)))
+    }
+
+    /// Logs a record with its parsed fields if it involves a debugged taxon.
+    pub fn record(&mut self, record: usize, sam: &SamRef, decision: impl FnOnce() -> String) {
+        if !self.involves(sam) { return };
+        let ids = |name: &str| self.format.parse(name).map_or(("NA".to_string(), "NA".to_string()), |(t, g)| (t.to_string(), g.to_string()));
+        let (query, query_gene) = ids(sam.qname);
+        let (reference, reference_gene) = ids(sam.rname);
+        let writer = self.writer.as_mut().unwrap();
+        writeln!(writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", record, sam.qname, sam.flag, sam.rname, sam.mapq, query, query_gene, reference, reference_gene, decision())
+            .expect("Error writing debug log");
+    }
+
+    /// Logs a pair counted without its record (after --equalize-depth subsampling).
+    pub fn pair(&mut self, fromto: &FromTo, decision: impl FnOnce() -> String) {
+        if !self.is_enabled() || !(self.taxa.contains(&(fromto.query as TaxID)) || self.taxa.contains(&(fromto.reference as TaxID))) { return };
+        let writer = self.writer.as_mut().unwrap();
+        writeln!(writer, "NA\tNA\tNA\tNA\tNA\t{}\t{}\t{}\t{}\t{}", fromto.query, fromto.query_gene, fromto.reference, fromto.reference_gene, decision())
+            .expect("Error writing debug log");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff_counts(left: &HashMap<u32, u64>, right: &HashMap<u32, u64>) -> Vec<Difference> {
+        diff_maps(left, right, "id", |count| count.to_string(), |id, l, r, result| result.extend(Difference::exact(id, "count", l, r)))
+    }
+
+    #[test]
+    fn diff_maps_reports_missing_extra_and_changed_keys() {
+        let left = HashMap::from([(1, 10), (2, 20), (3, 30)]);
+        let right = HashMap::from([(4, 40), (3, 31), (1, 10)]);
+        assert_eq!(diff_counts(&left, &right), vec![
+            Difference::new(2, "id", Some("20".to_string()), None),
+            Difference::new(3, "count", Some("30".to_string()), Some("31".to_string())),
+            Difference::new(4, "id", None, Some("40".to_string())),
+        ]);
+        assert!(diff_counts(&left, &left.clone()).is_empty());
+    }
+
+    /// Sample, name, items and overflow of a `QnameGroup`.
+    type Group = (SampleID, String, Vec<usize>, usize);
+
+    /// The groups of reads pushed in order, their items being the record numbers, from 1.
+    fn groups(reads: &[(SampleID, &str)], max_records: usize, check: bool) -> Result<Vec<Group>, AnomalyError> {
+        let mut grouping = GroupByQname::new(max_records, check);
+        let anomalies = AnomalyLog::default();
+        let mut result = Vec::new();
+        for (index, (sample, name)) in reads.iter().enumerate() {
+            result.extend(grouping.push(*sample, name, index + 1, index + 1, &anomalies)?);
+        }
+        result.extend(grouping.finish());
+        Ok(result.into_iter().map(|group| (group.sample, group.name, group.items, group.overflow)).collect())
+    }
+
+    fn group(sample: SampleID, name: &str, items: &[usize]) -> Group {
+        (sample, name.to_string(), items.to_vec(), 0)
+    }
+
+    #[test]
+    fn grouped_reads_form_one_group_per_read_and_sample() {
+        let reads = [(0, "a"), (0, "a"), (0, "b"), (1, "b"), (1, "c"), (1, "c"), (1, "c"), (0, "c")];
+        assert_eq!(groups(&reads, 10, true).unwrap(), [group(0, "a", &[1, 2]), group(0, "b", &[3]), group(1, "b", &[4]), group(1, "c", &[5, 6, 7]), group(0, "c", &[8])]);
+        assert_eq!(groups(&[], 10, true).unwrap(), []);
+    }
+
+    #[test]
+    fn groups_close_at_the_buffer_edge() {
+        let overflowing = |name: &str, items: &[usize], overflow: usize| (0, name.to_string(), items.to_vec(), overflow);
+        // A read of exactly max_records fits, one more alignment is counted but not kept
+        let reads = [(0, "a"), (0, "a"), (0, "a"), (0, "b"), (0, "b"), (0, "b"), (0, "b"), (0, "b"), (0, "c")];
+        assert_eq!(groups(&reads, 3, true).unwrap(), [group(0, "a", &[1, 2, 3]), overflowing("b", &[4, 5, 6], 2), group(0, "c", &[9])]);
+        assert_eq!(groups(&reads, 1, true).unwrap(), [overflowing("a", &[1], 2), overflowing("b", &[4], 4), group(0, "c", &[9])]);
+        assert!(groups(&reads, 5, true).unwrap().iter().all(|(_sample, _name, _items, overflow)| *overflow == 0));
+    }
+
+    #[test]
+    fn the_last_group_is_flushed_at_the_end_of_the_stream() {
+        let anomalies = AnomalyLog::default();
+        let mut grouping = GroupByQname::new(2, true);
+        assert_eq!(grouping.finish(), None);
+        for (record, name) in ["a", "b", "b", "b"].into_iter().enumerate() {
+            let closed = grouping.push(0, name, record, record, &anomalies).unwrap();
+            assert_eq!(closed.is_some(), record == 1);
+        }
+        let last = grouping.finish().unwrap();
+        assert!(last.is_oversized());
+        assert_eq!((last.name.as_str(), last.items, last.overflow), ("b", vec![1, 2], 1));
+        assert_eq!(grouping.finish(), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn interleaved_reads_are_refused_naming_read_and_record() {
+        let reads = [(0, "a"), (0, "b"), (0, "b"), (0, "a"), (0, "c")];
+        let error = groups(&reads, 10, true).unwrap_err();
+        assert_eq!((error.category, error.record), (Anomaly::UngroupedInput, Some(4)));
+        assert!(error.to_string().contains("read a reappears after its alignments ended"), "{}", error);
+        // Trusted with --assume-grouped
+        assert_eq!(groups(&reads, 10, false).unwrap(), [group(0, "a", &[1]), group(0, "b", &[2, 3]), group(0, "a", &[4]), group(0, "c", &[5])]);
+    }
+
+    #[test]
+    fn adversarially_repeated_names_are_refused() {
+        // Names that are prefixes of each other, and a name coming back in its sample only
+        for (reads, record) in [
+            (&[(0, "r1"), (0, "r10"), (0, "r1")][..], 3),
+            (&[(0, "r"), (1, "r"), (0, "r")][..], 3),
+            (&[(0, "r"), (1, "r"), (1, "s"), (1, "r")][..], 4),
+        ] {
+            let error = groups(reads, 10, true).unwrap_err();
+            assert_eq!((error.category, error.record), (Anomaly::UngroupedInput, Some(record)), "{:?}", reads);
+        }
+        // A read moved out of its group in a long grouped input is caught where it reappears
+        let mut random = crate::utils::SplitMix64::new(3);
+        let names = (0..2000).map(|read| format!("read{}", read)).collect::<Vec<String>>();
+        let mut reads = names.iter().flat_map(|name| vec![(0, name.as_str()); 2 + random.below(3) as usize]).collect::<Vec<(SampleID, &str)>>();
+        assert_eq!(groups(&reads, 10, true).unwrap().len(), names.len());
+        let moved = reads.iter().position(|(_sample, name)| *name == "read500").unwrap();
+        let target = reads.iter().position(|(_sample, name)| *name == "read1500").unwrap();
+        let read = reads.remove(moved);
+        reads.insert(target - 1, read);
+        let error = groups(&reads, 10, true).unwrap_err();
+        assert_eq!(error.record, Some(target), "{}", error);
+        assert!(error.example.starts_with("read read500 "), "{}", error);
+        // Reads are remembered for a bounded number of groups only
+        let names = (0..=RecentNames::CAPACITY).map(|read| format!("read{}", read)).collect::<Vec<String>>();
+        let mut reads = names.iter().map(|name| (0, name.as_str())).collect::<Vec<_>>();
+        reads.push((0, "read0"));
+        assert!(groups(&reads, 10, true).is_ok());
+    }
+
+    #[test]
+    fn names_parse_with_delimiters_and_templates() {
+        let parse = |format: &str, name: &str| format.parse::<NameFormat>().unwrap().parse(name);
+        assert_eq!(parse("_", "12345_7"), Ok((12345, 7)));
+        assert_eq!(parse("_", "12345_7_read42"), Ok((12345, 7)));
+        assert_eq!(parse("|", "12|3|x"), Ok((12, 3)));
+        assert!(parse("_", "12345").unwrap_err().contains("no gene id after '_'"));
+        assert!(parse("_", "x_7").unwrap_err().contains("'x' is not a taxid"));
+
+        assert_eq!(parse("tid|{taxid}|g{gene}", "tid|562|g14"), Ok((562, 14)));
+        // Text after the last field is a read suffix
+        assert_eq!(parse("tid|{taxid}|g{gene}", "tid|562|g14/1"), Ok((562, 14)));
+        assert!(parse("tid|{taxid}|g{gene}", "562|g14").unwrap_err().contains("expected 'tid|'"));
+        assert!(parse("tid|{taxid}|g{gene}", "tid|562g14").unwrap_err().contains("no '|g' after '562g14'"));
+
+        assert_eq!(parse("GCF_{taxid}.{*}~gene{gene}", "GCF_000123.1~gene7"), Ok((123, 7)));
+        assert_eq!(parse("GCF_{taxid}.{*}~gene{gene}", "GCF_42.2_ASM~gene3_r9"), Ok((42, 3)));
+        assert!(parse("GCF_{taxid}.{*}~gene{gene}", "GCF_42.2~genex").unwrap_err().contains("'x' is not a gene id"));
+
+        // Templates built by hand are not checked, parsing them is an error instead of a panic
+        let gene_only = NameFormat::Template(vec![NamePart::Literal("g".to_string()), NamePart::Gene]);
+        assert!(gene_only.parse("g3").unwrap_err().contains("needs a {taxid} and a {gene} field"));
+    }
+
+    #[test]
+    fn invalid_name_formats_are_refused() {
+        let error = |format: &str| format.parse::<NameFormat>().unwrap_err();
+        assert!(error("").contains("the delimiter is empty"));
+        assert!(error("tid|{taxid|g{gene}").contains("unknown field {taxid|g{gene}"));
+        assert!(error("tid|{taxid}|g{gene").contains("unclosed '{'"));
+        assert!(error("{taxid}{gene}").contains("fields need text between them"));
+        assert!(error("{taxid}_{*}{gene}").contains("fields need text between them"));
+        assert!(error("GCF_{*}~gene{gene}").contains("needs exactly one {taxid} and one {gene}"));
+        assert!(error("{taxid}_{gene}_{taxid}").contains("needs exactly one {taxid} and one {gene}"));
+        assert!(error("{taxid}_{genes}").contains("unknown field {genes}"));
+        // Formats print as they were written
+        for format in ["_", "tid|{taxid}|g{gene}", "GCF_{taxid}.{*}~gene{gene}"] {
+            assert_eq!(format.parse::<NameFormat>().unwrap().to_string(), format);
+        }
+    }
+
+    #[test]
+    fn the_last_reference_name_is_not_parsed_again() {
+        let (bounds, mut names) = (IdBounds::default(), NameCache::default());
+        assert_eq!(names.reference(&bounds, "12_3"), Ok((12, 3)));
+        assert_eq!((names.name.as_str(), names.ids), ("12_3", Some((12, 3))));
+        // A hit is not parsed again
+        names.ids = Some((7, 8));
+        assert_eq!(names.reference(&bounds, "12_3"), Ok((7, 8)));
+        // A miss is parsed and kept in its place
+        assert_eq!(names.reference(&bounds, "12_4"), Ok((12, 4)));
+        assert_eq!(names.reference(&bounds, "12_3"), Ok((12, 3)));
+        assert_eq!(names.reference(&bounds, "12_30"), Ok((12, 30)));
+    }
+
+    #[test]
+    fn unparseable_names_are_not_cached() {
+        let (bounds, mut names) = (IdBounds { max_taxid: 100, ..IdBounds::default() }, NameCache::default());
+        assert!(names.reference(&bounds, "x_3").is_err());
+        assert_eq!(names.ids, None);
+        assert_eq!(names.reference(&bounds, "100_3"), Ok((100, 3)));
+        for _ in 0..2 {
+            assert!(names.reference(&bounds, "101_3").unwrap_err().contains("exceeds --max-taxid 100"));
+        }
+        // The last name parsed is kept
+        assert_eq!((names.name.as_str(), names.ids), ("100_3", Some((100, 3))));
+        assert_eq!(names.reference(&bounds, "100_3"), Ok((100, 3)));
+    }
+
+    /// A million reference names of a coordinate sorted SAM, in runs of one reference, parsed
+    /// once per record and through the cache. Timed in release mode:
+    /// cargo test --release --lib -- --ignored --nocapture cached_names
+    #[test]
+    #[ignore]
+    fn cached_names_parse_faster() {
+        for (format, template) in [("_", "{}_{}"), ("tid|{taxid}|g{gene}", "tid|{}|g{}"), ("GCF_{*}~{taxid}~gene{gene}", "GCF_000123456.1~{}~gene{}")] {
+            let bounds = IdBounds { format: format.parse().unwrap(), ..IdBounds::default() };
+            let mut random = crate::utils::SplitMix64::new(3);
+            let mut lines = Vec::with_capacity(1_000_000);
+            while lines.len() < 1_000_000 {
+                let name = template.replacen("{}", &(random.below(100_000) + 1).to_string(), 1).replacen("{}", &(random.below(120) + 1).to_string(), 1);
+                lines.extend(std::iter::repeat_n(name, 1 + random.below(40) as usize));
+            }
+            let start = Instant::now();
+            let parsed = lines.iter().map(|name| bounds.parse(name).unwrap().0).sum::<usize>();
+            let uncached = start.elapsed();
+            let mut names = NameCache::default();
+            let start = Instant::now();
+            let cached = lines.iter().map(|name| names.reference(&bounds, name).unwrap().0).sum::<usize>();
+            let with_cache = start.elapsed();
+            eprintln!("{}: {} names parsed in {:?}, cached in {:?}", format, lines.len(), uncached, with_cache);
+            assert_eq!(parsed, cached);
+            assert!(with_cache < uncached, "{}: {:?} cached, {:?} parsed", format, with_cache, uncached);
+        }
+    }
+
+    const MANDATORY: &str = "1_1_r1\t0\t2_1\t7\t30\t50M\t*\t0\t0\t*\t*";
+
+    #[test]
+    fn optional_tags_are_parsed() {
+        let line = format!("{}\tAS:i:-12\tXS:i:-20\tNM:i:3\tMD:Z:10A5^AC20\tRG:Z:sample 1", MANDATORY);
+        let sam = SamRef::from_line(&line).unwrap();
+        assert_eq!((sam.alignment_score(), sam.edit_distance(), sam.mismatches()), (Some(-12), Some(3), Some("10A5^AC20")));
+        assert_eq!((sam.tag("XS"), sam.tag("RG"), sam.tag("XN")), (Some("-20"), Some("sample 1"), None));
+        // The owned record and its copy read the same tags
+        for owned in [Sam::from_line(&line).unwrap(), sam.to_owned()] {
+            assert_eq!((owned.alignment_score(), owned.edit_distance(), owned.mismatches(), owned.tag("RG")), (Some(-12), Some(3), Some("10A5^AC20"), Some("sample 1")));
+        }
+    }
+
+    #[test]
+    fn lines_without_or_with_unusable_tags_parse() {
+        let sam = SamRef::from_line(MANDATORY).unwrap();
+        assert_eq!((sam.tags, sam.rname, sam.mapq), ("", "2_1", 30));
+        assert_eq!((sam.alignment_score(), sam.edit_distance(), sam.mismatches(), sam.tag("RG")), (None, None, None, None));
+        assert_eq!(sam.to_owned().view().tags, "");
+        // Values of another type, tags only sharing a prefix and fields without a value
+        let line = format!("{}\tAS:i:high\tNM:i:-1\tMDX:Z:5\tXA", MANDATORY);
+        let sam = SamRef::from_line(&line).unwrap();
+        assert_eq!((sam.alignment_score(), sam.edit_distance(), sam.mismatches(), sam.tag("XA")), (None, None, None, None));
+        assert_eq!(sam.tag("MDX"), Some("5"));
+    }
+
+    /// The fields of a line split on tabs, as `Sam::from_line` copied them out of it before.
+    /// Aligned length and matched bases of a record with `cigar`.
+    fn cigar_bases(cigar: &str) -> (Option<u32>, Option<u32>) {
+        let line = format!("1_1_r1\t0\t2_1\t7\t30\t{}\t*\t0\t0\t*\t*", cigar);
+        let sam = SamRef::from_line(&line).unwrap();
+        (sam.aligned_length(), sam.matched_bases())
+    }
+
+    #[test]
+    fn cigars_parse_into_operations() {
+        use CigarOp::*;
+        assert_eq!(parse_cigar("50M"), Some(vec![(50, Match)]));
+        assert_eq!(parse_cigar("5S40M2I3D10N1P3=2X4H"), Some(vec![(5, SoftClip), (40, Match), (2, Insertion), (3, Deletion), (10, Skip), (1, Padding), (3, Equal), (2, Diff), (4, HardClip)]));
+        assert_eq!(parse_cigar(&format!("{}M", u32::MAX)), Some(vec![(u32::MAX, Match)]));
+        for invalid in ["*", "", "M", "50", "5S40", "50Q", "5M-3M", "4294967296M", "50m"] {
+            assert_eq!(parse_cigar(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn aligned_and_matched_bases_leave_out_clips_and_reference_gaps() {
+        assert_eq!(cigar_bases("50M"), (Some(50), Some(50)));
+        // Soft and hard clips are not aligned
+        assert_eq!(cigar_bases("5S40M5S"), (Some(40), Some(40)));
+        assert_eq!(cigar_bases("10H40M"), (Some(40), Some(40)));
+        assert_eq!(cigar_bases("3H5S40M2S"), (Some(40), Some(40)));
+        // Insertions are aligned read bases without a reference base, deletions and skips no read bases
+        assert_eq!(cigar_bases("20M2I28M"), (Some(50), Some(48)));
+        assert_eq!(cigar_bases("20M3D30M"), (Some(50), Some(50)));
+        assert_eq!(cigar_bases("20M1000N30M"), (Some(50), Some(50)));
+        assert_eq!(cigar_bases("10=1X9=2I5S"), (Some(22), Some(20)));
+        assert_eq!(cigar_bases("10S"), (Some(0), Some(0)));
+        // Overflowing sums
+        assert_eq!(cigar_bases(&format!("{}M1M", u32::MAX)), (None, None));
+        for invalid in ["*", "50", "50Q", "5S40"] {
+            assert_eq!(cigar_bases(invalid), (None, None), "{}", invalid);
+        }
+    }
+
+    fn split_fields(line: &str) -> (Vec<&str>, &str) {
+        let fields = line.splitn(12, '\t').collect::<Vec<&str>>();
+        (fields[..11].to_vec(), fields.get(11).copied().unwrap_or(""))
+    }
+
+    fn ref_fields<'a>(sam: &SamRef<'a>) -> (Vec<String>, &'a str) {
+        let fields = [sam.qname.to_string(), sam.flag.to_string(), sam.rname.to_string(), sam.pos.to_string(), sam.mapq.to_string(), sam.cigar.to_string(), sam.rnext.to_string(), sam.pnext.to_string(), sam.tlen.to_string(), sam.seq.to_string(), sam.qual.to_string()];
+        (fields.to_vec(), sam.tags)
+    }
+
+    #[test]
+    fn borrowed_records_read_the_fields_of_owned_ones() {
+        let lines = [
+            MANDATORY.to_string(),
+            format!("{}\tAS:i:-12\tRG:Z:sample 1", MANDATORY),
+            "2_3_r9\t2064\t4_3\t1500\t0\t5S40M2I3M\t=\t1700\t-250\tACGTACGTAC\tIIIIIIIIII\tNM:i:2".to_string(),
+            format!("3_1_r1\t16\t3_1\t1\t60\t10000M\t*\t0\t0\t{}\t{}", "A".repeat(10_000), "F".repeat(10_000)),
+        ];
+        let mut reader = SamReader::new(Box::new(std::io::Cursor::new(lines.join("\n"))));
+        let mut line = String::new();
+        let mut anomalies = AnomalyLog::default();
+        for expected in &lines {
+            let (fields, tags) = split_fields(expected);
+            let owned = Sam::from_line(expected).unwrap();
+            let borrowed = SamRef::from_line(expected).unwrap();
+            for sam in [borrowed, owned.view(), borrowed.to_owned().view()] {
+                assert_eq!(ref_fields(&sam), (fields.iter().map(|field| field.to_string()).collect(), tags), "{}", expected);
+            }
+            assert_eq!((owned.seq(), owned.qual()), (fields[9], fields[10]));
+            let read = reader.next_valid_ref(&mut line, &mut anomalies).unwrap().unwrap();
+            assert_eq!((read.line, ref_fields(&read)), (expected.as_str(), ref_fields(&borrowed)));
+        }
+        assert!(reader.next_valid_ref(&mut line, &mut anomalies).unwrap().is_none());
+    }
+
+    #[test]
+    fn owned_records_slice_their_line() {
+        let line = format!("3_1_r1\t16\t3_1\t1\t60\t40000M\t*\t0\t0\t{}\t{}\tRG:Z:long", "ACGT".repeat(10_000), "F".repeat(40_000));
+        let owned = line.clone();
+        let span = owned.as_ptr() as usize..owned.as_ptr() as usize + owned.len();
+        let sam = Sam::from_owned_line(owned).unwrap();
+        // SEQ and QUAL are slices of the line taken over, not copies
+        let view = sam.view();
+        assert_eq!(view.line.as_ptr() as usize, span.start);
+        for field in [view.qname, view.seq, view.qual, view.tags, sam.seq(), sam.qual()] {
+            assert!(span.contains(&(field.as_ptr() as usize)), "{}", &field[..field.len().min(10)]);
+        }
+        assert_eq!((sam.seq().len(), sam.qual().len(), sam.tag("RG")), (40_000, 40_000, Some("long")));
+
+        // So are the records a reader hands over
+        let mut reader = SamReader::new(Box::new(std::io::Cursor::new(format!("{}\n{}\n", MANDATORY, line))));
+        let mut anomalies = AnomalyLog::default();
+        for expected in [MANDATORY, line.as_str()] {
+            let sam = reader.next_valid(&mut anomalies).unwrap().unwrap();
+            let view = sam.view();
+            assert_eq!(view.line, expected);
+            assert!(view.line.as_bytes().as_ptr_range().contains(&sam.qual().as_ptr()));
+        }
+        assert!(reader.next_valid(&mut anomalies).unwrap().is_none());
+        assert!(Sam::from_owned_line("1_1_r1\t0\t1_1".to_string()).is_err());
+    }
+
+    /// 4000 records of 40 kb SEQ and QUAL (320 MB) read as owned `Sam`s and as bare lines. Timed in
+    /// release mode:
+    /// cargo test --release --lib -- --ignored --nocapture long_owned_records
+    #[test]
+    #[ignore]
+    fn long_owned_records_read_near_line_speed() {
+        let (seq, qual) = ("ACGT".repeat(10_000), "F".repeat(40_000));
+        let block = (0..4000).map(|read| format!("1_1_r{}\t0\t1_1\t1\t60\t40000M\t*\t0\t0\t{}\t{}\n", read, seq, qual)).collect::<String>();
+        let reader = || SamReader::new(Box::new(std::io::Cursor::new(block.clone())));
+        let mut anomalies = AnomalyLog::default();
+
+        let start = Instant::now();
+        let mut lines = 0;
+        let mut records = reader();
+        while let Some(line) = records.next_record_line() {
+            lines += line.unwrap().len();
+        }
+        let line_time = start.elapsed();
+        let start = Instant::now();
+        let mut owned = 0;
+        let mut records = reader();
+        while let Some(sam) = records.next_valid(&mut anomalies).unwrap() {
+            owned += sam.view().line.len();
+        }
+        let owned_time = start.elapsed();
+        let rate = |time: std::time::Duration| block.len() as f64 / time.as_secs_f64() / 1e6;
+        eprintln!("320 MB of long reads: {:.0} MB/s as lines, {:.0} MB/s owned", rate(line_time), rate(owned_time));
+        assert_eq!(owned, lines);
+        assert!(owned_time < line_time * 2, "{:?} owned, {:?} lines", owned_time, line_time);
+    }
+
+    /// Ten million records of 150 bases read as owned `Sam`s and as `SamRef`s borrowed from one
+    /// reused line. Timed in release mode:
+    /// cargo test --release --lib -- --ignored --nocapture borrowed_records
+    #[test]
+    #[ignore]
+    fn borrowed_records_read_faster() {
+        let mut random = crate::utils::SplitMix64::new(5);
+        let (seq, qual) = ("ACGT".repeat(38)[..150].to_string(), "F".repeat(150));
+        let block = (0..10_000).map(|read| format!("{}_{}_r{}\t0\t{}_{}\t{}\t{}\t150M\t*\t0\t0\t{}\t{}\tAS:i:-3\tNM:i:1\n",
+            random.below(500) + 1, random.below(120) + 1, read, random.below(500) + 1, random.below(120) + 1, random.below(1000) + 1, random.below(60), seq, qual)).collect::<String>();
+        let reader = || SamReader::new(Box::new(std::io::Cursor::new(block.clone())));
+        let mut anomalies = AnomalyLog::default();
+
+        let start = Instant::now();
+        let mut owned = 0;
+        for _ in 0..1000 {
+            let mut records = reader();
+            while let Some(sam) = records.next_valid(&mut anomalies).unwrap() {
+                owned += sam.view().mapq as usize;
+            }
+        }
+        let owned_time = start.elapsed();
+        let start = Instant::now();
+        let (mut borrowed, mut line) = (0, String::new());
+        for _ in 0..1000 {
+            let mut records = reader();
+            while let Some(sam) = records.next_valid_ref(&mut line, &mut anomalies).unwrap() {
+                borrowed += sam.mapq as usize;
+            }
+        }
+        let borrowed_time = start.elapsed();
+        eprintln!("10,000,000 records: {:?} owned, {:?} borrowed", owned_time, borrowed_time);
+        assert_eq!(owned, borrowed);
+        assert!(borrowed_time < owned_time, "{:?} borrowed, {:?} owned", borrowed_time, owned_time);
+    }
+
+    #[test]
+    fn float_difference_within_tolerance() {
+        assert_eq!(Difference::float("k", "v", 1.0, 1.05, 0.1), None);
+        assert_eq!(Difference::float("k", "v", 1.0, 1.2, 0.1), Some(Difference::new("k", "v", Some("1".to_string()), Some("1.2".to_string()))));
+        assert_eq!(Difference::float("k", "v", f64::NAN, f64::NAN, 0.0), None);
+        assert!(Difference::float("k", "v", f64::NAN, 1.0, 0.0).is_some());
+    }
+}
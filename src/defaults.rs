@@ -0,0 +1,90 @@
+use std::io::Write;
+
+use clap::{ArgAction, Command};
+
+use crate::manifest::json_string;
+
+/// One option of a command as documented by its clap definition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionDoc {
+    /// Command path, e.g. `fix_gtdb_mg` or `tool sub`
+    pub command: String,
+    pub id: String,
+    /// `--long`, `-s` or the value name of a positional argument
+    pub flag: String,
+    pub default: Option<String>,
+    /// `flag`, `choice` (with `choices`) or `value`
+    pub kind: &'static str,
+    pub choices: Vec<String>,
+    /// First line of the help text
+    pub description: String,
+    pub hidden: bool,
+}
+
+/// Documents every argument of `command` and its subcommands, walking the clap definitions so
+/// that no option can be left out. Clap's own help and version flags are not listed.
+pub fn option_docs(command: &Command) -> Vec<OptionDoc> {
+    let mut result = Vec::new();
+    collect(command, command.get_name(), &mut result);
+    result
+}
+
+fn collect(command: &Command, path: &str, result: &mut Vec<OptionDoc>) {
+    for arg in command.get_arguments() {
+        if matches!(arg.get_action(), ArgAction::Help | ArgAction::HelpShort | ArgAction::HelpLong | ArgAction::Version) {
+            continue
+        }
+        let flag = match (arg.get_long(), arg.get_short()) {
+            (Some(long), _) => format!("--{}", long),
+            (None, Some(short)) => format!("-{}", short),
+            (None, None) => arg.get_value_names().and_then(|names| names.first()).map_or(arg.get_id().to_string(), |name| format!("<{}>", name)),
+        };
+        let defaults = arg.get_default_values().iter().map(|value| value.to_string_lossy().into_owned()).collect::<Vec<String>>();
+        let choices = arg.get_possible_values().iter().filter(|value| !value.is_hide_set()).map(|value| value.get_name().to_string()).collect::<Vec<String>>();
+        let kind = match arg.get_action() {
+            ArgAction::SetTrue | ArgAction::SetFalse | ArgAction::Count => "flag",
+            _ if !choices.is_empty() => "choice",
+            _ => "value",
+        };
+        result.push(OptionDoc {
+            command: path.to_string(),
+            id: arg.get_id().to_string(),
+            flag,
+            default: (!defaults.is_empty()).then(|| defaults.join(",")),
+            kind,
+            choices: if kind == "choice" { choices } else { Vec::new() },
+            description: arg.get_help().map(|help| help.to_string()).unwrap_or_default().lines().next().unwrap_or_default().to_string(),
+            hidden: arg.is_hide_set(),
+        });
+    }
+    for subcommand in command.get_subcommands() {
+        collect(subcommand, &format!("{} {}", path, subcommand.get_name()), result);
+    }
+}
+
+/// Writes the options as a table (command, option, default, type, description); hidden options
+/// are left out.
+pub fn write_option_table(docs: &[OptionDoc], writer: &mut impl Write) -> std::io::Result<()> {
+    writeln!(writer, "command\toption\tdefault\ttype\tdescription")?;
+    for doc in docs.iter().filter(|doc| !doc.hidden) {
+        let kind = match doc.kind {
+            "choice" => format!("choice: {}", doc.choices.join("|")),
+            kind => kind.to_string(),
+        };
+        writeln!(writer, "{}\t{}\t{}\t{}\t{}", doc.command, doc.flag, doc.default.as_deref().unwrap_or("NA"), kind, doc.description)?;
+    }
+    Ok(())
+}
+
+/// Writes all options, hidden ones included, as a JSON array with one object per line.
+pub fn write_option_json(docs: &[OptionDoc], writer: &mut impl Write) -> std::io::Result<()> {
+    writeln!(writer, "[")?;
+    for (i, doc) in docs.iter().enumerate() {
+        let choices = itertools::join(doc.choices.iter().map(|choice| json_string(choice)), ", ");
+        let default = doc.default.as_deref().map_or("null".to_string(), json_string);
+        let separator = if i + 1 < docs.len() { "," } else { "" };
+        writeln!(writer, "  {{\"command\": {}, \"id\": {}, \"option\": {}, \"default\": {}, \"type\": {}, \"choices\": [{}], \"description\": {}, \"hidden\": {}}}{}",
+            json_string(&doc.command), json_string(&doc.id), json_string(&doc.flag), default, json_string(doc.kind), choices, json_string(&doc.description), doc.hidden, separator)?;
+    }
+    writeln!(writer, "]")
+}
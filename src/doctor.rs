@@ -0,0 +1,368 @@
+use std::{collections::HashSet, fmt::Display, path::{Path, PathBuf}};
+
+use crate::{gene_leaks::{mask_to_v1, read_mask_entries, MaskEntry, MASK_FORMAT_PREFIX}, id_to_label::read_map_fingerprint, layout::{read_schema, Layout, LAYOUT_VERSION}, manifest::{content_fingerprint, Manifest, OutputEntry, GENE_LEAKS_FILE, MANIFEST_FILE, MASK_FILE, MASK_V1_FILE, NORMALIZED_FILE, PAIRWISE_FILE, TAXON_SUMMARY_FILE}, pairwise_leakage::{NormGenes, NormalizationMode, NormalizationSchema, PairSchema, SelfPairPolicy}, schema::{self, Clamp}, utils::{file_lines, strip_cr}};
+
+/// A results directory opened for auditing, with the manifest written next to its outputs.
+/// Outputs are named as in the current layout and found under their name in the layout of the
+/// directory.
+pub struct ResultsDir {
+    pub dir: PathBuf,
+    pub manifest: Manifest,
+    pub layout: &'static Layout,
+}
+
+impl ResultsDir {
+    pub fn open(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let manifest = Manifest::read(&dir)?;
+        let layout = Layout::get(manifest.layout_version).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("{} has layout {}, this build knows layouts up to {}", dir.as_ref().display(), manifest.layout_version, LAYOUT_VERSION)))?;
+        Ok(Self { dir: dir.as_ref().to_path_buf(), manifest, layout })
+    }
+
+    /// Whether the directory predates the current layout and can be upgraded with migrate_results.
+    pub fn needs_migration(&self) -> bool {
+        self.layout.version < LAYOUT_VERSION
+    }
+
+    /// Path of an output named as in the current layout.
+    pub fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(self.layout.local(file))
+    }
+
+    /// All lines of an output, without line endings.
+    fn lines(&self, file: &str) -> Result<Vec<String>, String> {
+        let path = self.path(file);
+        let lines = file_lines(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        lines.map(|line| line.map(strip_cr).map_err(|e| format!("Cannot read {}: {}", path.display(), e))).collect()
+    }
+
+    /// Lines of an output that are not `#` headers.
+    fn data_lines(&self, file: &str) -> Result<Vec<String>, String> {
+        Ok(self.lines(file)?.into_iter().filter(|line| !line.starts_with('#')).collect())
+    }
+
+    fn manifest_rows(&self, file: &str) -> Result<usize, String> {
+        let file = self.layout.local(file);
+        self.manifest.outputs.iter().find(|o| o.file == file).map(|o| o.rows).ok_or_else(|| format!("{} is not listed in the manifest", file))
+    }
+}
+
+/// An invariant of an output, Err with a description of the violation.
+pub type Check = fn(&ResultsDir) -> Result<(), String>;
+
+/// The checks of one output format of `analyze`. Every format written into a results directory
+/// needs an entry here, outputs without one fail the audit.
+pub struct OutputChecks {
+    pub file: &'static str,
+    pub checks: &'static [(&'static str, Check)],
+}
+
+pub const OUTPUT_CHECKS: &[OutputChecks] = &[
+    OutputChecks { file: PAIRWISE_FILE, checks: &[("rows", |d| rows_match(d, PAIRWISE_FILE)), ("gene_totals", pairwise_gene_totals), ("self_pair_header", |d| self_pair_header(d, PAIRWISE_FILE))] },
+    OutputChecks { file: NORMALIZED_FILE, checks: &[("rows", |d| rows_match(d, NORMALIZED_FILE)), ("schema_header", normalized_schema), ("gene_totals", normalized_gene_totals), ("self_pair_header", |d| self_pair_header(d, NORMALIZED_FILE))] },
+    OutputChecks { file: GENE_LEAKS_FILE, checks: &[("rows", gene_leaks_species)] },
+    OutputChecks { file: MASK_FILE, checks: &[("rows", |d| rows_match(d, MASK_FILE)), ("format_header", mask_format), ("taxa_in_gene_leaks", mask_taxa_known)] },
+    OutputChecks { file: MASK_V1_FILE, checks: &[("rows", |d| rows_match(d, MASK_V1_FILE)), ("matches_mask", mask_v1_matches)] },
+    OutputChecks { file: TAXON_SUMMARY_FILE, checks: &[("rows", |d| rows_match(d, TAXON_SUMMARY_FILE)), ("totals_match_pairwise", summary_matches_pairwise)] },
+];
+
+/// Outcome of one check.
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub file: String,
+    pub check: String,
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl Display for CheckResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = if self.passed() { "pass" } else { "fail" };
+        write!(f, "{}\t{}\t{}\t{}", self.file, self.check, status, self.error.as_deref().unwrap_or(""))
+    }
+}
+
+/// Runs the checks of every output listed in the manifest and compares the map fingerprints of
+/// all outputs carrying one. Outputs without registered checks, missing files and registered
+/// outputs absent from the manifest are reported as failures. From layout 2 on, every output must
+/// carry the schema header of its layout entry. Outputs the manifest fingerprints must still have
+/// the bytes they were written with.
+pub fn audit(results: &ResultsDir) -> Vec<CheckResult> {
+    let result = |file: &str, check: &str, error: Option<String>| CheckResult { file: file.to_string(), check: check.to_string(), error };
+    let mut report = Vec::new();
+
+    for output in &results.manifest.outputs {
+        let exists = results.dir.join(&output.file).is_file();
+        report.push(result(&output.file, "exists", (!exists).then(|| "file is missing".to_string())));
+        if !exists { continue };
+
+        if output.fingerprint.is_some() {
+            report.push(result(&output.file, "fingerprint", fingerprint_matches(results, output).err()));
+        }
+        if results.layout.version >= 2 {
+            report.push(result(&output.file, "schema", schema_matches(results, &output.file).err()));
+        }
+        match OUTPUT_CHECKS.iter().find(|c| c.file == results.layout.upgrade(&output.file)) {
+            Some(checks) => report.extend(checks.checks.iter().map(|(name, check)| result(&output.file, name, check(results).err()))),
+            None => report.push(result(&output.file, "registered", Some("no doctor checks for this output".to_string()))),
+        }
+    }
+
+    for checks in OUTPUT_CHECKS {
+        if results.manifest.outputs.iter().all(|o| o.file != results.layout.local(checks.file)) {
+            report.push(result(checks.file, "in_manifest", Some("output is not listed in the manifest".to_string())));
+        }
+    }
+
+    report.push(result(MANIFEST_FILE, "map_fingerprints", fingerprints_agree(results).err()));
+    report
+}
+
+/// The schema header of an output names its schema in the layout, and so does the manifest.
+fn schema_matches(results: &ResultsDir, file: &str) -> Result<(), String> {
+    let expected = results.layout.by_file(file).ok_or_else(|| format!("not an output of layout {}", results.layout.version))?.schema;
+    let found = read_schema(results.dir.join(file)).map_err(|e| format!("Cannot read {}: {}", file, e))?;
+    match found {
+        Some(found) if found != expected => Err(format!("schema header {}, expected {}", found, expected)),
+        Some(_) => Ok(()),
+        None => Err(format!("no schema header, expected {}", expected)),
+    }?;
+    match results.manifest.outputs.iter().find(|o| o.file == file).and_then(|o| o.schema.as_deref()) {
+        Some(listed) if listed != expected => Err(format!("manifest lists schema {}, expected {}", listed, expected)),
+        _ => Ok(()),
+    }
+}
+
+/// The file has the bytes the manifest fingerprinted, it was not edited after the run.
+fn fingerprint_matches(results: &ResultsDir, output: &OutputEntry) -> Result<(), String> {
+    let found = content_fingerprint(results.dir.join(&output.file)).map_err(|e| e.to_string())?;
+    match &output.fingerprint {
+        Some(listed) if *listed != found => Err(format!("content fingerprint {}, the manifest lists {}", found, listed)),
+        _ => Ok(()),
+    }
+}
+
+/// Outputs made with a label map carry its fingerprint, all of them must name the same map.
+fn fingerprints_agree(results: &ResultsDir) -> Result<(), String> {
+    let mut found: Vec<(String, String)> = Vec::new();
+    for output in results.manifest.outputs.iter().filter(|o| results.dir.join(&o.file).is_file()) {
+        let fingerprint = read_map_fingerprint(results.dir.join(&output.file)).map_err(|e| format!("Cannot read {}: {}", output.file, e))?;
+        if let Some(fingerprint) = fingerprint {
+            found.push((output.file.clone(), fingerprint));
+        }
+    }
+    match found.iter().find(|(_file, fingerprint)| *fingerprint != found[0].1) {
+        Some((file, fingerprint)) => Err(format!("{} has map fingerprint {}, {} has {}", found[0].0, found[0].1, file, fingerprint)),
+        None => Ok(()),
+    }
+}
+
+fn rows_match(results: &ResultsDir, file: &str) -> Result<(), String> {
+    let expected = results.manifest_rows(file)?;
+    let found = results.data_lines(file)?.len();
+    if found != expected {
+        return Err(format!("manifest lists {} rows, found {}", expected, found))
+    }
+    Ok(())
+}
+
+/// Parses the pairwise table with its schema and checks that each total is the sum of its gene slots.
+fn pairwise_rows(results: &ResultsDir) -> Result<Vec<(u64, u64, u64)>, String> {
+    let lines = results.lines(PAIRWISE_FILE)?;
+    let pairs = match lines.iter().take_while(|line| line.starts_with('#')).find_map(|line| PairSchema::parse(line)) {
+        Some(pairs) => pairs?,
+        None => PairSchema::default(),
+    };
+    let table = pairs.table();
+    // Fractional columns are rounded to SIGNIFICANT_DIGITS digits each, their sum may be off by as much
+    let tolerance = if pairs.fractional { 1e-6 } else { 0.0 };
+    let mut rows = Vec::new();
+    for (index, line) in results.data_lines(PAIRWISE_FILE)?.iter().enumerate() {
+        let mut tokens = line.split('\t').collect::<Vec<&str>>();
+        if let Some(column) = pairs.strand_column().filter(|column| *column < tokens.len()) {
+            let bias: f64 = schema::STRAND_BIAS.parse(tokens.remove(column), index + 1).map_err(|e| e.to_string())?;
+            if !(0.0..=1.0).contains(&bias) {
+                return Err(format!("Line {}: strand bias {} outside of 0-1", index + 1, bias))
+            }
+        }
+        let values = tokens.iter().enumerate()
+            .map(|(column, token)| table.parse::<f64>(token, column, index + 1))
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| e.to_string())?;
+        if values.len() < table.columns.len() {
+            return Err(format!("Line {}: expected at least {} columns", index + 1, table.columns.len()))
+        }
+        rows.push((values[0] as u64, values[1] as u64, values[2].round() as u64));
+
+        let genes = values[table.columns.len()..].iter().filter(|v| **v >= 0.0).sum::<f64>();
+        if genes > 0.0 && (genes - values[2]).abs() > tolerance {
+            return Err(format!("Line {}: total {} differs from the gene sum {}", index + 1, values[2], genes))
+        }
+    }
+    Ok(rows)
+}
+
+fn pairwise_gene_totals(results: &ResultsDir) -> Result<(), String> {
+    pairwise_rows(results).map(|_| ())
+}
+
+fn normalized_schema(results: &ResultsDir) -> Result<(), String> {
+    let lines = results.lines(NORMALIZED_FILE)?;
+    match lines.iter().take_while(|line| line.starts_with('#')).find_map(|line| NormalizationSchema::parse(line)) {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(e)) => Err(e),
+        None => Err("no normalization schema header".to_string()),
+    }
+}
+
+/// Each total of the normalized table is the sum of its gene values. A clamped total is written as
+/// the cap, which the sum of the clamped values cannot fall below. Tables of --no-genes have no
+/// gene columns to sum.
+fn normalized_gene_totals(results: &ResultsDir) -> Result<(), String> {
+    let lines = results.lines(NORMALIZED_FILE)?;
+    let headers = || lines.iter().take_while(|line| line.starts_with('#'));
+    let mut schema = headers().find_map(|line| NormalizationSchema::parse(line)).ok_or("no normalization schema header")??;
+    if schema.mode == NormalizationMode::DonorOutgoingPairTotal { return Ok(()) };
+    schema.clamp = headers().find_map(|line| Clamp::parse(line)).transpose()?;
+
+    for (index, line) in lines.iter().enumerate().filter(|(_index, line)| !line.starts_with('#')) {
+        let (recipient, genes) = NormGenes::parse_line(line, &schema, index + 1).map_err(|e| e.to_string())?;
+        let tokens = line.split('\t').collect::<Vec<&str>>();
+        let clamped = match schema.clamp {
+            Some(Clamp { flags: true, .. }) => tokens.get(2) == Some(&"1"),
+            Some(_) => tokens[1].ends_with(Clamp::SUFFIX),
+            None => false,
+        };
+        let total: f64 = schema::NORMALIZED.parse(tokens[1].trim_end_matches(Clamp::SUFFIX), 1, index + 1).map_err(|e| e.to_string())?;
+        let sum = genes.total();
+        // Values are rounded to SIGNIFICANT_DIGITS digits each, their sum may be off by as much
+        let off = if clamped { total - sum } else { (total - sum).abs() };
+        if off > 1e-9 * sum.max(1.0) {
+            return Err(format!("Line {}: total {} of recipient {} differs from the gene sum {}", index + 1, total, recipient, sum))
+        }
+    }
+    Ok(())
+}
+
+/// The self-pair policy a table was written with.
+fn self_pairs(results: &ResultsDir, file: &str) -> Result<SelfPairPolicy, String> {
+    let lines = results.lines(file)?;
+    match lines.iter().take_while(|line| line.starts_with('#')).find_map(|line| SelfPairPolicy::parse(line)) {
+        Some(policy) => policy,
+        None => Err("no self-pair header".to_string()),
+    }
+}
+
+fn self_pair_header(results: &ResultsDir, file: &str) -> Result<(), String> {
+    self_pairs(results, file).map(|_| ())
+}
+
+fn gene_leaks_taxa(results: &ResultsDir) -> Result<HashSet<String>, String> {
+    Ok(results.data_lines(GENE_LEAKS_FILE)?.iter().filter_map(|line| line.split('\t').next().map(str::to_string)).collect())
+}
+
+/// The gene leaks report has several rows per species, the manifest counts species.
+fn gene_leaks_species(results: &ResultsDir) -> Result<(), String> {
+    let expected = results.manifest_rows(GENE_LEAKS_FILE)?;
+    let found = gene_leaks_taxa(results)?.len();
+    if found != expected {
+        return Err(format!("manifest lists {} species, found {}", expected, found))
+    }
+    Ok(())
+}
+
+fn mask(results: &ResultsDir, file: &str) -> Result<Vec<MaskEntry>, String> {
+    read_mask_entries(results.path(file)).map_err(|e| format!("Cannot read {}: {}", file, e))
+}
+
+fn mask_format(results: &ResultsDir) -> Result<(), String> {
+    let lines = results.lines(MASK_FILE)?;
+    match lines.iter().take_while(|line| line.starts_with('#')).find_map(|line| line.strip_prefix(MASK_FORMAT_PREFIX)) {
+        Some("2") => Ok(()),
+        Some(format) => Err(format!("mask format {}, expected 2", format)),
+        None => Err("no mask format header".to_string()),
+    }
+}
+
+fn mask_taxa_known(results: &ResultsDir) -> Result<(), String> {
+    let known = gene_leaks_taxa(results)?;
+    let mut unknown = mask(results, MASK_FILE)?.iter()
+        .map(|entry| entry.taxon.to_string())
+        .filter(|taxid| !known.contains(taxid))
+        .collect::<Vec<String>>();
+    unknown.dedup();
+    if !unknown.is_empty() {
+        return Err(format!("{} masked taxa missing from {}: {}", unknown.len(), GENE_LEAKS_FILE, itertools::join(unknown.iter().take(5), ", ")))
+    }
+    Ok(())
+}
+
+/// The v1 export lists exactly the genes of the v2 mask.
+fn mask_v1_matches(results: &ResultsDir) -> Result<(), String> {
+    let (v2, v1) = (mask_to_v1(&mask(results, MASK_FILE)?), mask_to_v1(&mask(results, MASK_V1_FILE)?));
+    match v2.iter().zip(&v1).find(|(a, b)| a != b) {
+        Some(((taxon, _genes), _)) => Err(format!("taxon {} differs from {}", taxon, MASK_FILE)),
+        None if v2.len() != v1.len() => Err(format!("{} taxa, {} has {}", v1.len(), MASK_FILE, v2.len())),
+        None => Ok(()),
+    }
+}
+
+/// Summed read counters of the taxon summary equal the sums over the pairwise table: totals,
+/// self-pairs as correct and every other pair once as outgoing and once as incoming. Reads of a
+/// self-pair between two of its genes are leaked in the summary, once outgoing and once incoming,
+/// so they move from the correct reads to both leaked counters. Totals and correct reads are only
+/// compared when the pairwise table includes self-pairs.
+fn summary_matches_pairwise(results: &ResultsDir) -> Result<(), String> {
+    let with_self_pairs = self_pairs(results, PAIRWISE_FILE).map_or(true, |policy| policy.include_in_output);
+    let (mut total, mut correct, mut leaked) = (0u64, 0u64, 0u64);
+    for (from, to, reads) in pairwise_rows(results)? {
+        total += reads;
+        if from == to { correct += reads } else { leaked += reads }
+    }
+
+    let mut sums = [0u64; 4];
+    for (index, line) in results.data_lines(TAXON_SUMMARY_FILE)?.iter().enumerate() {
+        let tokens = line.split('\t').collect::<Vec<&str>>();
+        for (sum, column) in sums.iter_mut().zip([1, 2, 4, 6]) {
+            *sum += tokens.get(column).and_then(|t| t.parse::<u64>().ok())
+                .ok_or_else(|| format!("Line {}: column {} is not a count", index + 1, column + 1))?;
+        }
+    }
+
+    let [summary_total, summary_correct, summary_out, summary_in] = sums;
+    if summary_out != summary_in {
+        return Err(format!("taxon summary has {} outgoing and {} incoming reads", summary_out, summary_in))
+    }
+    if summary_out < leaked {
+        return Err(format!("outgoing reads: pairwise {}, taxon summary {}", leaked, summary_out))
+    }
+    let between_genes = summary_out - leaked;
+    if !with_self_pairs { return Ok(()) };
+    for (name, pairwise, summary) in [("total", total, summary_total), ("correct", correct, summary_correct + between_genes)] {
+        if pairwise != summary {
+            return Err(format!("{} reads: pairwise {}, taxon summary {}", name, pairwise, summary))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_output_of_the_current_layout_has_checks() {
+        for output in Layout::current().files {
+            let checks = OUTPUT_CHECKS.iter().find(|checks| checks.file == output.file);
+            assert!(checks.is_some_and(|checks| !checks.checks.is_empty()), "{} has no doctor checks", output.file);
+        }
+        for checks in OUTPUT_CHECKS {
+            assert!(Layout::current().by_file(checks.file).is_some(), "{} is not an output of the current layout", checks.file);
+        }
+    }
+}
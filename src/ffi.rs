@@ -0,0 +1,119 @@
+//! C ABI over `query::ResultsQuery`, e.g. for ctypes or cffi from Python. Build the shared
+//! library with `cargo rustc --lib --release --features ffi --crate-type cdylib`. Panics are
+//! caught at the boundary and reported as a null handle or an empty array.
+
+use std::{ffi::{c_char, CStr}, panic::{catch_unwind, AssertUnwindSafe}};
+
+use crate::query::{Direction, ResultsQuery, QUERY_API_VERSION};
+
+/// Two parallel arrays of `len` values, e.g. partner ids and reads. Free with `fgm_array_free`.
+#[repr(C)]
+pub struct FgmArray {
+    pub ids: *mut u64,
+    pub values: *mut u64,
+    pub len: usize,
+}
+
+impl FgmArray {
+    fn empty() -> Self {
+        Self { ids: std::ptr::null_mut(), values: std::ptr::null_mut(), len: 0 }
+    }
+
+    fn from_pairs(pairs: impl IntoIterator<Item = (usize, u64)>) -> Self {
+        let (ids, values): (Vec<u64>, Vec<u64>) = pairs.into_iter().map(|(id, value)| (id as u64, value)).unzip();
+        let len = ids.len();
+        if len == 0 {
+            return Self::empty()
+        }
+        let ids = Box::into_raw(ids.into_boxed_slice()) as *mut u64;
+        let values = Box::into_raw(values.into_boxed_slice()) as *mut u64;
+        Self { ids, values, len }
+    }
+}
+
+/// Runs a query on a handle, an empty array for a null handle or a panic.
+fn query(handle: *const ResultsQuery, run: impl FnOnce(&ResultsQuery) -> FgmArray) -> FgmArray {
+    // SAFETY: handles are null or come from fgm_open, as required of every caller
+    let Some(results) = (unsafe { handle.as_ref() }) else { return FgmArray::empty() };
+    catch_unwind(AssertUnwindSafe(|| run(results))).unwrap_or_else(|_| FgmArray::empty())
+}
+
+#[no_mangle]
+pub extern "C" fn fgm_api_version() -> u32 {
+    QUERY_API_VERSION
+}
+
+/// Opens a results directory, null on error (the reason is printed to stderr).
+///
+/// # Safety
+/// `dir` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn fgm_open(dir: *const c_char) -> *mut ResultsQuery {
+    if dir.is_null() {
+        return std::ptr::null_mut()
+    }
+    let dir = unsafe { CStr::from_ptr(dir) }.to_string_lossy().into_owned();
+    match catch_unwind(|| ResultsQuery::open(&dir)) {
+        Ok(Ok(results)) => Box::into_raw(Box::new(results)),
+        Ok(Err(e)) => {
+            eprintln!("{}", e);
+            std::ptr::null_mut()
+        },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// `handle` must be null or come from `fgm_open`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn fgm_close(handle: *mut ResultsQuery) {
+    if !handle.is_null() {
+        drop(unsafe { Box::from_raw(handle) });
+    }
+}
+
+/// Donors of `taxid` (ids) with their reads (values), most reads first.
+///
+/// # Safety
+/// `handle` must be null or come from `fgm_open`.
+#[no_mangle]
+pub unsafe extern "C" fn fgm_incoming(handle: *const ResultsQuery, taxid: u64) -> FgmArray {
+    query(handle, |results| FgmArray::from_pairs(results.incoming(taxid as usize)))
+}
+
+/// Partners of `taxid` with at least `min_total` reads: donors for direction 0, recipients for
+/// direction 1. Any other direction yields an empty array.
+///
+/// # Safety
+/// `handle` must be null or come from `fgm_open`.
+#[no_mangle]
+pub unsafe extern "C" fn fgm_pairs_for(handle: *const ResultsQuery, taxid: u64, direction: u32, min_total: u64) -> FgmArray {
+    let direction = match direction {
+        0 => Direction::Incoming,
+        1 => Direction::Outgoing,
+        _ => return FgmArray::empty(),
+    };
+    query(handle, |results| FgmArray::from_pairs(results.pairs_for(taxid as usize, direction, min_total)))
+}
+
+/// Reads leaked into `taxid` per gene: gene ids (ids) and reads (values), by gene.
+///
+/// # Safety
+/// `handle` must be null or come from `fgm_open`.
+#[no_mangle]
+pub unsafe extern "C" fn fgm_gene_profile(handle: *const ResultsQuery, taxid: u64) -> FgmArray {
+    query(handle, |results| FgmArray::from_pairs(results.gene_profile(taxid as usize)))
+}
+
+/// # Safety
+/// `array` must come from one of the query functions and not be freed twice.
+#[no_mangle]
+pub unsafe extern "C" fn fgm_array_free(array: FgmArray) {
+    if array.len == 0 {
+        return
+    }
+    unsafe {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(array.ids, array.len)));
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(array.values, array.len)));
+    }
+}
@@ -0,0 +1,355 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt::Display};
+
+use crate::{common::{Anomaly, AnomalyError, AnomalyLog, Args, FromTo, GeneID, IdBounds, NameCache, SamRef, TaxID}, leakage, pairwise_leakage::TinyTaxID};
+
+/// Mapping quality as written in SAM column 5.
+pub type Mapq = u8;
+
+/// Why a record was dropped, in the order the predicates are evaluated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SkipReason {
+    Unaligned,
+    NotPrimary,
+    LowMapq,
+    ShortRead,
+    LongRead,
+    LowAlignmentScore,
+    HighEditDistance,
+    ShortAlignment,
+    HighSoftclip,
+    LowIdentity,
+    TaxonOutsideSubset,
+}
+
+impl SkipReason {
+    const ALL: [SkipReason; 11] = [SkipReason::Unaligned, SkipReason::NotPrimary, SkipReason::LowMapq, SkipReason::ShortRead, SkipReason::LongRead, SkipReason::LowAlignmentScore, SkipReason::HighEditDistance, SkipReason::ShortAlignment, SkipReason::HighSoftclip, SkipReason::LowIdentity, SkipReason::TaxonOutsideSubset];
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SkipReason::Unaligned => "unaligned",
+            SkipReason::NotPrimary => "not_primary",
+            SkipReason::LowMapq => "low_mapq",
+            SkipReason::ShortRead => "short_read",
+            SkipReason::LongRead => "long_read",
+            SkipReason::LowAlignmentScore => "low_alignment_score",
+            SkipReason::HighEditDistance => "high_edit_distance",
+            SkipReason::ShortAlignment => "short_alignment",
+            SkipReason::HighSoftclip => "high_softclip",
+            SkipReason::LowIdentity => "low_identity",
+            SkipReason::TaxonOutsideSubset => "taxon_outside_subset",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Keep,
+    Skip(SkipReason),
+}
+
+/// Gene ids seen in kept records, to catch references numbering genes from another base than
+/// the analysis assumes (--gene-id-base), which shifts every per-gene column by one.
+#[derive(Debug, Clone, Default)]
+pub struct GeneIds {
+    records: BTreeMap<GeneID, usize>,
+}
+
+impl GeneIds {
+    pub fn observe(&mut self, gene: GeneID) {
+        *self.records.entry(gene).or_default() += 1;
+    }
+
+    pub fn min(&self) -> Option<GeneID> {
+        self.records.keys().next().copied()
+    }
+
+    pub fn max(&self) -> Option<GeneID> {
+        self.records.keys().next_back().copied()
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (gene, records) in &other.records {
+            *self.records.entry(*gene).or_default() += records;
+        }
+    }
+
+    fn records_of(&self, gene: GeneID) -> usize {
+        self.records.get(&gene).copied().unwrap_or(0)
+    }
+
+    /// Describes a mismatch of the observed ids with genes numbered `base..base + n_genes`: gene
+    /// 0 with base 1, or gene `n_genes` with base 0 (e.g. 120 of a 120 gene panel). Without
+    /// `n_genes` only gene 0 with base 1 is detected. None if the ids fit.
+    pub fn base_mismatch(&self, base: GeneID, n_genes: Option<usize>) -> Option<String> {
+        let (min, max) = (self.min()?, self.max()?);
+        let zero = self.records_of(0);
+        let past_end = n_genes.map_or(0, |n| self.records_of(n));
+        let range = format!("{} gene ids seen ({}..={})", self.records.values().sum::<usize>(), min, max);
+        match (base, zero > 0, past_end > 0) {
+            (1, true, true) => Some(format!("{}, {} of gene 0 and {} of gene {}: the input mixes 0- and 1-based gene ids", range, zero, past_end, n_genes?)),
+            (1, true, false) => Some(format!("{}, {} of gene 0: gene ids look 0-based, run with --gene-id-base 0", range, zero)),
+            (0, false, true) => Some(format!("{}, {} of gene {} but none of gene 0: gene ids look 1-based, run with --gene-id-base 1", range, past_end, n_genes?)),
+            (0, true, true) => Some(format!("{}, {} of gene {} beyond the {} gene panel: the input mixes 0- and 1-based gene ids", range, past_end, n_genes?, n_genes?)),
+            _ => None,
+        }
+    }
+}
+
+/// All record predicates of a run with the number of records each one removed. Records are
+/// checked in `SkipReason` order and counted under the first predicate they fail.
+#[derive(Debug, Clone)]
+pub struct RecordFilter {
+    pub primary_only: bool,
+    pub min_mapq: Mapq,
+    /// Bounds on the read length, see `SamRef::read_length`; records with SEQ `*` and an invalid
+    /// CIGAR pass
+    pub min_read_len: Option<u32>,
+    pub max_read_len: Option<u32>,
+    /// Bounds on the AS and NM tags; records without the tag pass
+    pub min_alignment_score: Option<i32>,
+    pub max_edit_distance: Option<u32>,
+    /// Bounds on the CIGAR; records without a valid CIGAR pass
+    pub min_aligned_length: Option<u32>,
+    pub max_softclip_frac: Option<f64>,
+    /// Records without NM and MD pass, counted in `without_identity`
+    pub min_identity: Option<f64>,
+    /// Keep only pairs with both taxa in this set.
+    pub taxa: Option<HashSet<TinyTaxID>>,
+    /// First gene id of the reference and panel size, for the gene id check
+    pub gene_id_base: GeneID,
+    pub n_genes: Option<usize>,
+    /// Largest ids accepted when the names of a record are parsed
+    pub bounds: IdBounds,
+    /// Ids of the reference names parsed so far, see `sam_to_ids`
+    pub names: NameCache,
+    pub gene_ids: GeneIds,
+    /// Whether unmapped records are counted by the taxid and gene of their read name (--count-unmapped)
+    pub count_unmapped: bool,
+    pub unmapped: HashMap<(TaxID, GeneID), u64>,
+    unmapped_unparseable: usize,
+    kept: usize,
+    skipped: [usize; SkipReason::ALL.len()],
+    without_identity: usize,
+    without_read_len: usize,
+}
+
+impl RecordFilter {
+    pub fn new(min_mapq: Mapq) -> Self {
+        Self { primary_only: false, min_mapq, min_read_len: None, max_read_len: None, min_alignment_score: None, max_edit_distance: None, min_aligned_length: None, max_softclip_frac: None, min_identity: None, taxa: None, gene_id_base: 1, n_genes: None, bounds: IdBounds::default(), names: NameCache::default(), gene_ids: GeneIds::default(), count_unmapped: false, unmapped: HashMap::default(), unmapped_unparseable: 0, kept: 0, skipped: [0; SkipReason::ALL.len()], without_identity: 0, without_read_len: 0 }
+    }
+
+    pub fn from_args(args: &Args) -> Self {
+        Self { primary_only: args.primary_only, min_read_len: args.min_read_len, max_read_len: args.max_read_len, min_alignment_score: args.min_alignment_score, max_edit_distance: args.max_edit_distance, min_aligned_length: args.min_aligned_length, max_softclip_frac: args.max_softclip_frac, min_identity: args.min_identity, gene_id_base: args.gene_id_base as GeneID, n_genes: args.n_genes(), bounds: IdBounds::from_args(args), count_unmapped: args.count_unmapped, ..Self::new(args.min_mapq) }
+    }
+
+    pub fn with_taxa(mut self, taxa: HashSet<TinyTaxID>) -> Self {
+        self.taxa = Some(taxa);
+        self
+    }
+
+    /// Alignment, flag, mapq, tag and CIGAR predicates of a record, without counting.
+    pub fn check(&self, sam: &SamRef) -> Decision {
+        if !sam.is_aligned() {
+            return Decision::Skip(SkipReason::Unaligned)
+        }
+        if self.primary_only && !sam.is_primary() {
+            return Decision::Skip(SkipReason::NotPrimary)
+        }
+        if sam.mapq < self.min_mapq {
+            return Decision::Skip(SkipReason::LowMapq)
+        }
+        if self.min_read_len.is_some() || self.max_read_len.is_some() {
+            let length = sam.read_length();
+            if self.min_read_len.zip(length).is_some_and(|(min, length)| length < min) {
+                return Decision::Skip(SkipReason::ShortRead)
+            }
+            if self.max_read_len.zip(length).is_some_and(|(max, length)| length > max) {
+                return Decision::Skip(SkipReason::LongRead)
+            }
+        }
+        if let (Some(min), Some(score)) = (self.min_alignment_score, sam.alignment_score()) {
+            if score < min {
+                return Decision::Skip(SkipReason::LowAlignmentScore)
+            }
+        }
+        if let (Some(max), Some(distance)) = (self.max_edit_distance, sam.edit_distance()) {
+            if distance > max {
+                return Decision::Skip(SkipReason::HighEditDistance)
+            }
+        }
+        if let (Some(min), Some(length)) = (self.min_aligned_length, sam.aligned_length()) {
+            if length < min {
+                return Decision::Skip(SkipReason::ShortAlignment)
+            }
+        }
+        if let (Some(max), Some(fraction)) = (self.max_softclip_frac, sam.softclip_fraction()) {
+            if fraction > max {
+                return Decision::Skip(SkipReason::HighSoftclip)
+            }
+        }
+        if let (Some(min), Some(identity)) = (self.min_identity, sam.identity()) {
+            if identity < min {
+                return Decision::Skip(SkipReason::LowIdentity)
+            }
+        }
+        Decision::Keep
+    }
+
+    /// Predicates on the parsed ids of a record, without counting.
+    pub fn check_pair(&self, fromto: &FromTo) -> Decision {
+        self.check_taxa(fromto.query, fromto.reference)
+    }
+
+    /// Predicates of a record of a per-read leakage file, without counting.
+    pub fn check_record(&self, record: &leakage::Leakage) -> Decision {
+        match record.mapq < self.min_mapq {
+            true => Decision::Skip(SkipReason::LowMapq),
+            false => self.check_taxa(record.from as TinyTaxID, record.to as TinyTaxID),
+        }
+    }
+
+    fn check_taxa(&self, from: TinyTaxID, to: TinyTaxID) -> Decision {
+        match &self.taxa {
+            Some(taxa) if !(taxa.contains(&from) && taxa.contains(&to)) => Decision::Skip(SkipReason::TaxonOutsideSubset),
+            _ => Decision::Keep,
+        }
+    }
+
+    /// Checks and counts a record.
+    pub fn evaluate(&mut self, sam: &SamRef) -> Decision {
+        let decision = self.check(sam);
+        self.count(decision);
+        if decision == Decision::Keep && self.min_identity.is_some() && sam.identity().is_none() {
+            self.without_identity += 1;
+        }
+        if decision == Decision::Skip(SkipReason::Unaligned) && self.count_unmapped {
+            match self.bounds.parse(sam.qname) {
+                Ok(ids) => *self.unmapped.entry(ids).or_default() += 1,
+                Err(_e) => self.unmapped_unparseable += 1,
+            }
+        }
+        if decision != Decision::Skip(SkipReason::Unaligned) && (self.min_read_len.is_some() || self.max_read_len.is_some()) && sam.read_length() == Some(0) {
+            self.without_read_len += 1;
+        }
+        decision
+    }
+
+    /// Checks the ids of a record kept by `evaluate`, moving it from kept to skipped if it fails.
+    pub fn evaluate_pair(&mut self, fromto: &FromTo) -> Decision {
+        let decision = self.check_pair(fromto);
+        match decision {
+            Decision::Keep => {
+                self.gene_ids.observe(fromto.query_gene as GeneID);
+                self.gene_ids.observe(fromto.reference_gene as GeneID);
+            },
+            Decision::Skip(_) => {
+                self.kept -= 1;
+                self.count(decision);
+            },
+        }
+        decision
+    }
+
+    /// Warns if the gene ids of the kept records do not fit --gene-id-base (and --n-genes), an
+    /// anomaly that is fatal with --strict.
+    pub fn check_gene_ids(&self, anomalies: &mut AnomalyLog) -> Result<(), AnomalyError> {
+        let Some(mismatch) = self.gene_ids.base_mismatch(self.gene_id_base, self.n_genes) else { return Ok(()) };
+        eprintln!("Warning: gene id base mismatch (--gene-id-base {}): {}", self.gene_id_base, mismatch);
+        anomalies.record(Anomaly::GeneIdBase, &mismatch, None)
+    }
+
+    /// Checks and counts a record of a per-read leakage file.
+    pub fn evaluate_record(&mut self, record: &leakage::Leakage) -> Decision {
+        let decision = self.check_record(record);
+        self.count(decision);
+        decision
+    }
+
+    fn count(&mut self, decision: Decision) {
+        match decision {
+            Decision::Keep => self.kept += 1,
+            Decision::Skip(reason) => self.skipped[reason as usize] += 1,
+        }
+    }
+
+    pub fn kept(&self) -> usize {
+        self.kept
+    }
+
+    pub fn skipped(&self, reason: SkipReason) -> usize {
+        self.skipped[reason as usize]
+    }
+
+    /// Adds the counts of a filter that checked another part of the input (e.g. on another thread).
+    pub fn merge_counts(&mut self, other: &Self) {
+        self.kept += other.kept;
+        self.skipped.iter_mut().zip(other.skipped).for_each(|(skipped, other)| *skipped += other);
+        self.without_identity += other.without_identity;
+        self.without_read_len += other.without_read_len;
+        for (ids, records) in &other.unmapped {
+            *self.unmapped.entry(*ids).or_default() += records;
+        }
+        self.unmapped_unparseable += other.unmapped_unparseable;
+        self.gene_ids.merge(&other.gene_ids);
+    }
+
+    /// Aligned records with SEQ and CIGAR `*`, read length 0 for --min-read-len and --max-read-len.
+    pub fn without_read_len(&self) -> usize {
+        self.without_read_len
+    }
+
+    /// Unmapped records per taxid of their read name (--count-unmapped).
+    pub fn unmapped_by_taxon(&self) -> HashMap<TaxID, u64> {
+        let mut result = HashMap::default();
+        for ((taxid, _gene), records) in &self.unmapped {
+            *result.entry(*taxid).or_default() += records;
+        }
+        result
+    }
+
+    /// Records kept by --min-identity for lack of an NM or MD tag.
+    pub fn without_identity(&self) -> usize {
+        self.without_identity
+    }
+}
+
+/// One line summary of kept and skipped records, reasons without skips left out.
+impl Display for RecordFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "kept {} records", self.kept)?;
+        for reason in SkipReason::ALL.iter().filter(|reason| self.skipped(**reason) > 0) {
+            write!(f, ", {} {}", self.skipped(*reason), reason)?;
+        }
+        if self.without_identity > 0 {
+            write!(f, " (warning: {} kept without NM or MD tag, identity unknown)", self.without_identity)?;
+        }
+        if self.without_read_len > 0 {
+            write!(f, " (warning: {} aligned without SEQ or CIGAR, read length 0)", self.without_read_len)?;
+        }
+        if self.unmapped_unparseable > 0 {
+            write!(f, " (warning: {} unmapped with an unparseable read name, not counted by taxon)", self.unmapped_unparseable)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_filtered_on_their_tags() {
+        let filter = RecordFilter { min_alignment_score: Some(-10), max_edit_distance: Some(2), ..RecordFilter::new(0) };
+        let decision = |tags: &str| filter.check(&SamRef::from_line(&format!("1_1_r1\t0\t2_1\t7\t30\t50M\t*\t0\t0\t*\t*{}", tags)).unwrap());
+        assert_eq!(decision("\tAS:i:-10\tNM:i:2"), Decision::Keep);
+        assert_eq!(decision("\tAS:i:-11\tNM:i:0"), Decision::Skip(SkipReason::LowAlignmentScore));
+        assert_eq!(decision("\tAS:i:0\tNM:i:3"), Decision::Skip(SkipReason::HighEditDistance));
+        // Records without the tags pass
+        assert_eq!(decision(""), Decision::Keep);
+        assert_eq!(decision("\tNM:i:1"), Decision::Keep);
+        assert_eq!(RecordFilter::new(0).check(&SamRef::from_line("1_1_r1\t0\t2_1\t7\t30\t50M\t*\t0\t0\t*\t*\tAS:i:-500\tNM:i:40").unwrap()), Decision::Keep);
+    }
+}
@@ -0,0 +1,1708 @@
+
+use std::{cmp::Reverse, collections::{BTreeMap, HashMap, HashSet}, fmt::Display, io::Write, path::Path};
+
+use crate::{common::{diff_maps, sam_input, sam_to_ids, Anomaly, AnomalyError, AnomalyLog, Args, Difference, FromTo, GeneID, InputError, SamHeader, SamReader, SamRef, TaxID}, filter::{Decision, RecordFilter}, id_to_label::{lca_rank, IdLabels}, pairwise_leakage::{pair_entries, Leakage, LeakagePair, SelfPairPolicy, TinyTaxID}, reconcile::BestPerRead, reference::REFERENCE_FINGERPRINT_PREFIX, samples::{name_tables, sample_table, SampleID, Samples}, schema::fmt_fixed, taxonomy::Rank, tracks::{reference_span, LengthHistogram, WindowCoverage}, utils::{file_lines, strip_cr}};
+
+/// Distinct donor taxa leaking into a gene with the amount each leaked, saturating at `CAPACITY`
+/// donors to bound memory. Donors already present keep accumulating once saturated.
+#[derive(Default)]
+pub struct DonorSet {
+    donors: HashMap<TaxID, f64>,
+}
+
+impl DonorSet {
+    const CAPACITY: usize = 256;
+
+    pub fn insert(&mut self, donor: TaxID, amount: f64) {
+        if let Some(total) = self.donors.get_mut(&donor) {
+            *total += amount;
+            return
+        }
+        if self.is_saturated() { return };
+        self.donors.insert(donor, amount);
+    }
+
+    /// Donor that leaked the most, the lowest id among ties.
+    pub fn top(&self) -> Option<TaxID> {
+        self.donors.iter()
+            .max_by(|(a, a_amount), (b, b_amount)| a_amount.total_cmp(b_amount).then(b.cmp(a)))
+            .map(|(donor, _amount)| *donor)
+    }
+
+    pub fn count(&self) -> usize {
+        self.donors.len()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = TaxID> + '_ {
+        self.donors.keys().copied()
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.donors.len() >= Self::CAPACITY
+    }
+}
+
+#[derive(Default)]
+pub struct Leaks {
+    pub correct: f64,
+    pub incoming: f64,
+    pub outgoing: f64,
+    pub donors: DonorSet,
+    /// Windows covered by incoming leaked reads, None without positions (e.g. from a pairwise map).
+    pub coverage: Option<WindowCoverage>,
+    /// Aligned lengths of incoming leaked reads, None unless leak lengths are tracked.
+    pub leak_lengths: Option<LengthHistogram>,
+}
+
+impl Leaks {
+    pub fn uniformity(&self) -> Option<f64> {
+        self.coverage.as_ref().map(WindowCoverage::uniformity)
+    }
+
+    pub fn median_leak_len(&self) -> Option<f64> {
+        self.leak_lengths.as_ref()?.median()
+    }
+
+    pub fn diff(&self, other: &Self, key: &str, tolerance: f64) -> Vec<Difference> {
+        [
+            Difference::float(key, "correct", self.correct, other.correct, tolerance),
+            Difference::float(key, "incoming", self.incoming, other.incoming, tolerance),
+            Difference::float(key, "outgoing", self.outgoing, other.outgoing, tolerance),
+            Difference::exact(key, "donor_count", self.donors.count(), other.donors.count()),
+        ].into_iter().flatten().collect()
+    }
+}
+
+/// Empirical distribution of the incoming values of all occupied gene slots with incoming leakage.
+#[derive(Debug, Clone, Default)]
+pub struct IncomingDistribution {
+    sorted: Vec<f64>,
+}
+
+impl IncomingDistribution {
+    pub fn from_gene_leaks(gene_leaks: &GeneLeaks) -> Self {
+        let mut sorted = gene_leaks.species.values()
+            .flat_map(|s| s.leaks.iter().flatten())
+            .map(|l| l.incoming)
+            .filter(|incoming| *incoming > 0.0)
+            .collect::<Vec<f64>>();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        Self { sorted }
+    }
+
+    /// Percentage of non-zero slots with an incoming value less than or equal to `incoming`.
+    /// Slots without incoming leakage are at the 0th percentile by definition.
+    pub fn percentile(&self, incoming: f64) -> f64 {
+        if incoming <= 0.0 || self.sorted.is_empty() {
+            return 0.0
+        }
+        let at_or_below = self.sorted.partition_point(|x| *x <= incoming);
+        100.0 * at_or_below as f64 / self.sorted.len() as f64
+    }
+}
+
+/// Location and spread of the incoming leakage of one gene over a set of taxa.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Baseline {
+    pub members: usize,
+    pub mean: f64,
+    pub median: f64,
+    /// Median absolute deviation from the median
+    pub mad: f64,
+}
+
+impl Baseline {
+    /// Statistics of `values` (sorted in place), None without values.
+    pub fn from_values(values: &mut [f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None
+        }
+        values.sort_by(|a, b| a.total_cmp(b));
+        let median = median_of_sorted(values);
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let mut deviations = values.iter().map(|value| (value - median).abs()).collect::<Vec<f64>>();
+        deviations.sort_by(|a, b| a.total_cmp(b));
+        Some(Self { members: values.len(), mean, median, mad: median_of_sorted(&deviations) })
+    }
+
+    /// Distance of `incoming` from the median in MADs. With a MAD of 0 any distance is infinite.
+    pub fn excess(&self, incoming: f64) -> f64 {
+        let difference = incoming - self.median;
+        match (self.mad > 0.0, difference == 0.0) {
+            (true, _) => difference / self.mad,
+            (false, true) => 0.0,
+            (false, false) => difference.signum() * f64::INFINITY,
+        }
+    }
+}
+
+fn median_of_sorted(values: &[f64]) -> f64 {
+    let mid = values.len() / 2;
+    match values.len() % 2 {
+        0 => (values[mid - 1] + values[mid]) / 2.0,
+        _ => values[mid],
+    }
+}
+
+/// Which baseline the excess of a gene is measured against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaselineSource {
+    Genus,
+    /// All taxa, for taxa without a genus or of a genus with too few members
+    Global,
+}
+
+impl Display for BaselineSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            BaselineSource::Genus => "genus",
+            BaselineSource::Global => "global",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Per-gene baselines of the incoming leakage within every genus, to tell a gene leaked on more
+/// than in the taxon's relatives from one that is confusable throughout the genus. The members
+/// of a (genus, gene) are the taxa of the genus carrying the gene; with fewer than `min_members`
+/// the baseline of all taxa carrying the gene is used instead.
+#[derive(Debug, Clone)]
+pub struct GenusBaselines {
+    min_members: usize,
+    genus: HashMap<TaxID, String>,
+    genera: BTreeMap<(String, GeneID), Baseline>,
+    global: BTreeMap<GeneID, Baseline>,
+}
+
+impl GenusBaselines {
+    /// Baselines of the incoming leakage of `gene_leaks`, genera taken from `lineages` (by taxid,
+    /// may be empty, then every taxon falls back to the global baseline).
+    pub fn from_gene_leaks(gene_leaks: &GeneLeaks, lineages: &[String], min_members: usize) -> Self {
+        let mut genus = HashMap::new();
+        let mut genera: BTreeMap<(String, GeneID), Vec<f64>> = BTreeMap::new();
+        let mut global: BTreeMap<GeneID, Vec<f64>> = BTreeMap::new();
+        for species in gene_leaks.species.values() {
+            let name = genus_of(lineages, species.id);
+            if let Some(name) = name {
+                genus.insert(species.id, name.to_string());
+            }
+            for (gene, leaks) in species.leaks.iter().enumerate().filter_map(|(gene, leaks)| Some((gene, leaks.as_ref()?))) {
+                global.entry(gene).or_default().push(leaks.incoming);
+                if let Some(name) = name {
+                    genera.entry((name.to_string(), gene)).or_default().push(leaks.incoming);
+                }
+            }
+        }
+        let baselines = |values: &mut Vec<f64>| Baseline::from_values(values).expect("Baselines have members");
+        Self {
+            min_members,
+            genus,
+            genera: genera.into_iter().map(|(key, mut values)| (key, baselines(&mut values))).collect(),
+            global: global.into_iter().map(|(gene, mut values)| (gene, baselines(&mut values))).collect(),
+        }
+    }
+
+    /// Baseline of a gene of a taxon and where it comes from, None for a gene no taxon carries.
+    pub fn baseline(&self, taxon: TaxID, gene: GeneID) -> Option<(BaselineSource, &Baseline)> {
+        let genus = self.genus.get(&taxon).and_then(|genus| self.genera.get(&(genus.clone(), gene)));
+        match genus {
+            Some(baseline) if baseline.members >= self.min_members => Some((BaselineSource::Genus, baseline)),
+            _ => self.global.get(&gene).map(|baseline| (BaselineSource::Global, baseline)),
+        }
+    }
+
+    /// Incoming leakage of a gene of a taxon above its baseline in MADs, see `Baseline::excess`.
+    pub fn excess(&self, taxon: TaxID, gene: GeneID, incoming: f64) -> Option<f64> {
+        self.baseline(taxon, gene).map(|(_source, baseline)| baseline.excess(incoming))
+    }
+
+    /// (genus, gene) baselines with fewer than `min_members` members.
+    pub fn fallbacks(&self) -> usize {
+        self.genera.values().filter(|baseline| baseline.members < self.min_members).count()
+    }
+
+    /// Writes the global baseline of every gene (genus `*`), then the baseline of every genus and
+    /// gene with the baseline its taxa are measured against. Returns the number of rows.
+    pub fn write_report(&self, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "#genus\tgene\tmembers\tmean\tmedian\tmad\tbaseline")?;
+        let rows = self.global.iter().map(|(gene, baseline)| ("*", *gene, baseline, BaselineSource::Global))
+            .chain(self.genera.iter().map(|((genus, gene), baseline)| {
+                let source = if baseline.members >= self.min_members { BaselineSource::Genus } else { BaselineSource::Global };
+                (genus.as_str(), *gene, baseline, source)
+            }));
+        let mut count = 0;
+        for (genus, gene, baseline, source) in rows {
+            writeln!(writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}", genus, gene, baseline.members, fmt_fixed(baseline.mean), fmt_fixed(baseline.median), fmt_fixed(baseline.mad), source)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Number of baselines and fallbacks on stderr.
+    pub fn report(&self) {
+        eprintln!("Baselines: {} genus gene baselines, {} with fewer than {} members fall back to the global baseline", self.genera.len(), self.fallbacks(), self.min_members);
+    }
+}
+
+/// Genus of a taxon by its lineage, None without a named genus.
+fn genus_of(lineages: &[String], taxon: TaxID) -> Option<&str> {
+    lineages.get(taxon)?.split(';').find_map(|field| Rank::Genus.strip(field.trim())).filter(|genus| !genus.is_empty())
+}
+
+/// Mask transition of a gene relative to the previous release's mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskState {
+    NewlyMasked,
+    KeptMasked,
+    Unmasked,
+    NeverMasked,
+}
+
+impl MaskState {
+    pub fn is_masked(&self) -> bool {
+        matches!(self, MaskState::NewlyMasked | MaskState::KeptMasked)
+    }
+}
+
+impl Display for MaskState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            MaskState::NewlyMasked => "newly_masked",
+            MaskState::KeptMasked => "kept_masked",
+            MaskState::Unmasked => "unmasked",
+            MaskState::NeverMasked => "never_masked",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Importance of marker genes for placement (`--gene-weights`, gene<TAB>weight). Unlisted genes weigh 1.
+#[derive(Debug, Clone, Default)]
+pub struct GeneWeights {
+    weights: HashMap<GeneID, f64>,
+}
+
+impl GeneWeights {
+    pub fn read(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut weights = HashMap::new();
+        for line in file_lines(path)? {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') { continue };
+            let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid gene weight row '{}'", line));
+            let (gene, weight) = line.split_once('\t').ok_or_else(invalid)?;
+            let gene: GeneID = gene.parse().map_err(|_| invalid())?;
+            let weight: f64 = weight.trim().parse().map_err(|_| invalid())?;
+            if !weight.is_finite() { return Err(invalid()) };
+            weights.insert(gene, weight);
+        }
+        Ok(Self { weights })
+    }
+
+    pub fn get(&self, gene: GeneID) -> f64 {
+        self.weights.get(&gene).copied().unwrap_or(1.0)
+    }
+}
+
+/// Marker names by gene id (`--marker-names`, gene<TAB>name, e.g. `1<TAB>PF00380.20`), the panel of
+/// the GTDB-Tk style marker summary.
+#[derive(Debug, Clone, Default)]
+pub struct MarkerNames {
+    names: BTreeMap<GeneID, String>,
+}
+
+impl MarkerNames {
+    pub fn read(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut names = BTreeMap::new();
+        for line in file_lines(path)? {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') { continue };
+            let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid marker name row '{}'", line));
+            let (gene, name) = line.split_once('\t').ok_or_else(invalid)?;
+            let gene: GeneID = gene.parse().map_err(|_| invalid())?;
+            let name = name.trim();
+            if name.is_empty() { return Err(invalid()) };
+            names.insert(gene, name.to_string());
+        }
+        Ok(Self { names })
+    }
+
+    /// Name of a gene, its id for genes outside the table.
+    pub fn name(&self, gene: GeneID) -> String {
+        self.names.get(&gene).cloned().unwrap_or_else(|| gene.to_string())
+    }
+}
+
+/// One gene of a panel definition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PanelGene {
+    pub name: String,
+    pub expected_length: Option<u32>,
+    pub group: Option<String>,
+}
+
+/// Marker gene panel (`--panel`), the single source of the number of genes, their names, groups and
+/// expected lengths. Read from a `panel.tsv` (gene_id, name, expected_length, group; `NA` or empty
+/// for unknown lengths and groups) or one of the embedded panels by name. Embedded panels only
+/// know their size: gene ids are positions in the reference build, not fixed markers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Panel {
+    pub name: String,
+    size: usize,
+    genes: BTreeMap<GeneID, PanelGene>,
+}
+
+impl Panel {
+    pub const COLUMNS: [&'static str; 4] = ["gene_id", "name", "expected_length", "group"];
+    /// Embedded panels by name with their number of genes.
+    pub const BUILTIN: [(&'static str, usize); 2] = [("bac120", 120), ("ar53", 53)];
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        Self::BUILTIN.iter().find(|(builtin, _size)| *builtin == name).map(|(name, size)| Self { name: name.to_string(), size: *size, genes: BTreeMap::new() })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut genes = BTreeMap::new();
+        for line in file_lines(&path)? {
+            let line = strip_cr(line?);
+            if line.is_empty() || line.starts_with('#') || line.split('\t').eq(Self::COLUMNS) { continue };
+            let invalid = |reason: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid panel row '{}': {}", line, reason));
+            let fields = line.split('\t').map(str::trim).collect::<Vec<&str>>();
+            if fields.len() != Self::COLUMNS.len() { return Err(invalid("expected gene_id, name, expected_length and group")) };
+            let gene: GeneID = fields[0].parse().map_err(|_| invalid("gene id is not a number"))?;
+            if fields[1].is_empty() { return Err(invalid("empty name")) };
+            let known = |field: &str| (!field.is_empty() && field != "NA").then(|| field.to_string());
+            let expected_length = known(fields[2]).map(|length| length.parse::<u32>()).transpose().map_err(|_| invalid("expected length is not a number"))?;
+            let entry = PanelGene { name: fields[1].to_string(), expected_length, group: known(fields[3]) };
+            if genes.insert(gene, entry).is_some() { return Err(invalid("gene id listed twice")) };
+        }
+        if genes.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Panel has no genes"))
+        }
+        let name = path.as_ref().file_stem().map_or("panel".to_string(), |stem| stem.to_string_lossy().into_owned());
+        Ok(Self { name, size: genes.len(), genes })
+    }
+
+    /// An embedded panel name or a panel file, as `--panel` takes it.
+    pub fn parse_arg(value: &str) -> Result<Self, String> {
+        match Self::builtin(value) {
+            Some(panel) => Ok(panel),
+            None => Self::read(value).map_err(|e| format!("Cannot read panel {} (embedded panels: {}): {}", value, Self::BUILTIN.map(|(name, _size)| name).join(", "), e)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Largest gene id of the panel, the last of `base..base + len` for embedded panels.
+    pub fn max_gene(&self, base: GeneID) -> GeneID {
+        self.genes.keys().next_back().copied().unwrap_or(base + self.size - 1)
+    }
+
+    /// Gene ids of the panel, `base..base + len` for embedded panels.
+    pub fn gene_ids(&self, base: GeneID) -> Vec<GeneID> {
+        match self.genes.is_empty() {
+            true => (base..base + self.size).collect(),
+            false => self.genes.keys().copied().collect(),
+        }
+    }
+
+    pub fn gene(&self, gene: GeneID) -> Option<&PanelGene> {
+        self.genes.get(&gene)
+    }
+
+    /// Group of a gene, the panel name for genes without one.
+    pub fn group(&self, gene: GeneID) -> &str {
+        self.gene(gene).and_then(|gene| gene.group.as_deref()).unwrap_or(&self.name)
+    }
+
+    pub fn expected_length(&self, gene: GeneID) -> Option<u32> {
+        self.gene(gene)?.expected_length
+    }
+
+    /// True if the panel names its genes, i.e. was read from a file.
+    pub fn has_names(&self) -> bool {
+        !self.genes.is_empty()
+    }
+
+    /// Marker names of the panel genes, the gene id for embedded panels.
+    pub fn marker_names(&self, base: GeneID) -> MarkerNames {
+        let names = self.gene_ids(base).into_iter().map(|gene| (gene, self.gene(gene).map_or_else(|| gene.to_string(), |entry| entry.name.clone()))).collect();
+        MarkerNames { names }
+    }
+}
+
+/// Decides which genes of a species count as leaked on (and are candidates for masking).
+/// A percentile threshold only applies once the policy is resolved against a run's distribution.
+/// Genes of the previous mask stay masked until their incoming leakage falls below `mask_off`.
+/// At most all but `min_genes_remaining` genes of a species are masked (lowest weight first with
+/// gene weights), and species with fewer than `min_genes_initial` genes are quarantined instead.
+/// Genes whose incoming leaked reads cover less than `min_uniformity` of their windows are not
+/// masked but reported as suspect; genes without positions are not checked.
+#[derive(Debug, Clone)]
+pub struct MaskPolicy {
+    pub threshold: f64,
+    pub mask_off: Option<f64>,
+    pub min_donors: usize,
+    pub min_uniformity: Option<f64>,
+    pub above_percentile: Option<f64>,
+    pub distribution: Option<IncomingDistribution>,
+    pub mask_excess: Option<f64>,
+    pub baseline_min_members: usize,
+    pub baselines: Option<GenusBaselines>,
+    pub previous: Option<HashMap<TaxID, HashSet<GeneID>>>,
+    pub gene_weights: Option<GeneWeights>,
+    pub min_genes_remaining: usize,
+    pub min_genes_initial: usize,
+    /// Whether gene reports carry the median_leak_len row
+    pub leak_lengths: bool,
+}
+
+impl Default for MaskPolicy {
+    fn default() -> Self {
+        Self { threshold: 0.0, mask_off: None, min_donors: 1, min_uniformity: None, above_percentile: None, distribution: None, mask_excess: None, baseline_min_members: 3, baselines: None, previous: None, gene_weights: None, min_genes_remaining: 0, min_genes_initial: 0, leak_lengths: false }
+    }
+}
+
+impl MaskPolicy {
+    pub fn from_args(args: &Args) -> Result<Self, String> {
+        if args.min_genes.is_some() {
+            return Err("--min_genes is no longer supported as its meaning was ambiguous: use --min-genes-remaining to never mask a species below that many genes, or --min-genes-initial to quarantine species that have fewer genes to begin with".to_string())
+        }
+        if let Some(mask_off) = args.mask_off {
+            if mask_off >= args.mask_on {
+                return Err(format!("--mask-off ({}) must be smaller than --mask-on ({})", mask_off, args.mask_on))
+            }
+        }
+        let previous = match &args.previous_mask {
+            Some(path) => Some(read_mask(path).map_err(|e| format!("Cannot read previous mask {}: {}", path, e))?),
+            None => None,
+        };
+        let gene_weights = match &args.gene_weights {
+            Some(path) => Some(GeneWeights::read(path).map_err(|e| format!("Cannot read gene weights {}: {}", path, e))?),
+            None => None,
+        };
+        Ok(Self {
+            threshold: args.mask_on,
+            min_genes_remaining: args.min_genes_remaining,
+            min_genes_initial: args.min_genes_initial,
+            gene_weights,
+            mask_off: args.mask_off,
+            min_donors: args.min_donors_to_mask,
+            min_uniformity: args.min_uniformity,
+            leak_lengths: args.tracks_leak_lengths(),
+            above_percentile: args.mask_above_percentile,
+            mask_excess: args.mask_excess,
+            baseline_min_members: args.baseline_min_members,
+            previous,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_distribution(mut self, gene_leaks: &GeneLeaks) -> Self {
+        self.distribution = Some(IncomingDistribution::from_gene_leaks(gene_leaks));
+        self
+    }
+
+    /// Adds the genus baselines of `gene_leaks`, genera taken from `lineages` (by taxid, may be empty).
+    pub fn with_baselines(mut self, gene_leaks: &GeneLeaks, lineages: &[String]) -> Self {
+        self.baselines = Some(GenusBaselines::from_gene_leaks(gene_leaks, lineages, self.baseline_min_members));
+        self
+    }
+
+    fn was_masked(&self, taxon: TaxID, gene: GeneID) -> bool {
+        self.previous.as_ref()
+            .and_then(|previous| previous.get(&taxon))
+            .is_some_and(|genes| genes.contains(&gene))
+    }
+
+    /// Whether the gene qualifies for a new mask, ignoring the previous release.
+    fn qualifies(&self, taxon: TaxID, gene: GeneID, leaks: &Leaks) -> bool {
+        self.leaked_enough(taxon, gene, leaks) && !self.low_uniformity(leaks)
+    }
+
+    /// Incoming leakage, donor, percentile and excess criteria of a new mask.
+    fn leaked_enough(&self, taxon: TaxID, gene: GeneID, leaks: &Leaks) -> bool {
+        let above_percentile = match (self.above_percentile, &self.distribution) {
+            (Some(percentile), Some(distribution)) => distribution.percentile(leaks.incoming) > percentile,
+            _ => true,
+        };
+        let above_excess = match (self.mask_excess, &self.baselines) {
+            (Some(min), Some(baselines)) => baselines.excess(taxon, gene, leaks.incoming).is_some_and(|excess| excess > min),
+            _ => true,
+        };
+        leaks.incoming > self.threshold && leaks.donors.count() >= self.min_donors && above_percentile && above_excess
+    }
+
+    fn low_uniformity(&self, leaks: &Leaks) -> bool {
+        matches!((self.min_uniformity, leaks.uniformity()), (Some(min), Some(uniformity)) if uniformity < min)
+    }
+
+    /// A gene leaked on enough to be masked whose leaked reads pile up in too few windows.
+    pub fn is_suspect(&self, taxon: TaxID, gene: GeneID, leaks: &Leaks) -> bool {
+        self.leaked_enough(taxon, gene, leaks) && self.low_uniformity(leaks) && !self.masks(taxon, gene, leaks)
+    }
+
+    pub fn decide(&self, taxon: TaxID, gene: GeneID, leaks: &Leaks) -> MaskState {
+        let qualifies = self.qualifies(taxon, gene, leaks);
+        if !self.was_masked(taxon, gene) {
+            return if qualifies { MaskState::NewlyMasked } else { MaskState::NeverMasked }
+        }
+        let mask_off = self.mask_off.unwrap_or(self.threshold);
+        if qualifies || leaks.incoming >= mask_off {
+            MaskState::KeptMasked
+        } else {
+            MaskState::Unmasked
+        }
+    }
+
+    pub fn masks(&self, taxon: TaxID, gene: GeneID, leaks: &Leaks) -> bool {
+        self.decide(taxon, gene, leaks).is_masked()
+    }
+
+    /// Name of the rule masking a gene and the threshold it compared incoming leakage with, None
+    /// for genes the policy does not mask.
+    pub fn rule(&self, taxon: TaxID, gene: GeneID, leaks: &Leaks) -> Option<(&'static str, f64)> {
+        match self.decide(taxon, gene, leaks) {
+            MaskState::NewlyMasked if self.above_percentile.is_some() => Some(("mask_on_above_percentile", self.threshold)),
+            MaskState::NewlyMasked if self.mask_excess.is_some() => Some(("mask_on_above_excess", self.threshold)),
+            MaskState::NewlyMasked => Some(("mask_on", self.threshold)),
+            MaskState::KeptMasked if self.qualifies(taxon, gene, leaks) => Some(("kept_above_mask_on", self.threshold)),
+            MaskState::KeptMasked => Some(("kept_above_mask_off", self.mask_off.unwrap_or(self.threshold))),
+            MaskState::Unmasked | MaskState::NeverMasked => None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Species {
+    pub id: TaxID,
+    pub leaks: Vec<Option<Leaks>>,
+    /// Genes no donor candidate carries, so they cannot leak (see `annotate_competition`).
+    /// None until annotated.
+    pub uncontested: Option<HashSet<GeneID>>,
+    /// Unmapped reads by gene of the read name (--count-unmapped), None when not counted
+    pub unmapped: Option<HashMap<GeneID, u64>>,
+}
+
+/// Wide per-gene rows of a species, with the gene counts evaluated under a mask policy.
+pub struct SpeciesReport<'a> {
+    pub species: &'a Species,
+    pub policy: &'a MaskPolicy,
+}
+
+impl SpeciesReport<'_> {
+    /// Appends one metric row: species id, good and leaked on gene counts, metric name, one value per gene.
+    fn push_row(&self, s: &mut String, metric: &str, value: impl Fn(GeneID, &Leaks) -> String) {
+        let species = self.species;
+        if !s.is_empty() { s.push('\n') };
+        s.push_str(&format!("{}\t{}\t{}\t{}", species.id, species.num_good_genes(self.policy), species.num_leaked_on_genes(self.policy), metric));
+        species.leaks.iter().enumerate().skip(1).for_each(|(gene, e)| {
+            let tmp = match e {
+                Some(e) => format!("\t{}", value(gene, e)),
+                None => "\tNone".to_string(),
+            };
+            s.push_str(&tmp)
+        });
+    }
+}
+
+impl Display for SpeciesReport<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = String::default();
+
+        self.push_row(&mut s, "correct", |_, e| e.correct.to_string());
+        self.push_row(&mut s, "incoming", |_, e| e.incoming.to_string());
+        self.push_row(&mut s, "outgoing", |_, e| e.outgoing.to_string());
+        self.push_row(&mut s, "donor_count", |_, e| e.donors.count().to_string());
+        self.push_row(&mut s, "uniformity", |_, e| e.uniformity().map_or("NA".to_string(), fmt_fixed));
+        if self.policy.leak_lengths {
+            self.push_row(&mut s, "median_leak_len", |_, e| e.median_leak_len().map_or("NA".to_string(), fmt_fixed));
+        }
+        self.push_row(&mut s, "pctl_incoming", |_, e| match &self.policy.distribution {
+            Some(distribution) => distribution.percentile(e.incoming).to_string(),
+            None => "NA".to_string(),
+        });
+        if let Some(baselines) = &self.policy.baselines {
+            self.push_row(&mut s, "excess", |gene, e| baselines.excess(self.species.id, gene, e.incoming).map_or("NA".to_string(), fmt_fixed));
+        }
+        if let Some(unmapped) = &self.species.unmapped {
+            self.push_row(&mut s, "unmapped", |gene, _| unmapped.get(&gene).copied().unwrap_or(0).to_string());
+        }
+        if let Some(uncontested) = &self.species.uncontested {
+            self.push_row(&mut s, "uncontested", |gene, _| (uncontested.contains(&gene) as u8).to_string());
+        }
+        let masked = self.species.masked_genes(self.policy).into_iter().collect::<HashSet<GeneID>>();
+        let quarantined = self.species.is_quarantined(self.policy);
+        self.push_row(&mut s, "mask_state", |gene, e| match self.policy.decide(self.species.id, gene, e) {
+            _ if quarantined => "quarantined".to_string(),
+            state if state.is_masked() && !masked.contains(&gene) => "capped_by_min_genes".to_string(),
+            _ if self.policy.is_suspect(self.species.id, gene, e) => "suspect_low_uniformity".to_string(),
+            state => state.to_string(),
+        });
+
+        write!(f, "{}", s)
+    }
+}
+
+impl Species {
+    pub fn new(taxid: TaxID) -> Self {
+        Self {
+            id: taxid,
+            leaks: Vec::new(),
+            uncontested: None,
+            unmapped: None,
+        }
+    }
+
+    pub fn get(&mut self, geneid: GeneID) -> &mut Leaks {
+        if geneid >= self.leaks.len() {
+            self.leaks.resize_with(geneid + 1, || None);
+        }
+        self.leaks[geneid].get_or_insert_with(Leaks::default)
+    }
+
+    pub fn add_correct(&mut self, geneid: GeneID, increment: f64) {
+        let leaks = self.get(geneid);
+        leaks.correct += increment;
+    }
+
+    /// `partner` is the other taxon of the leak; for incoming leaks it is recorded as a donor.
+    pub fn add_incorrect(&mut self, geneid: GeneID, incoming: bool, partner: TaxID, increment: f64) {
+        let leaks = self.get(geneid);
+        if incoming {
+            leaks.incoming += increment;
+            leaks.donors.insert(partner, increment);
+        } else {
+            leaks.outgoing += increment
+        };
+    }
+
+    /// Marks the windows of a gene covered by an incoming leaked read, `length` being the length
+    /// of the gene's reference sequence.
+    pub fn cover_incoming(&mut self, geneid: GeneID, length: u32, window: u32, start: u32, span: u32) {
+        let leaks = self.get(geneid);
+        leaks.coverage.get_or_insert_with(|| WindowCoverage::new(length, window)).add(start, span);
+    }
+
+    pub fn add_leak_length(&mut self, geneid: GeneID, length: u32) {
+        self.get(geneid).leak_lengths.get_or_insert_with(LengthHistogram::default).add(length);
+    }
+
+    pub fn num_genes(&self) -> usize {
+        self.leaks.iter().filter(|x| x.is_some()).count()
+    }
+    
+    pub fn num_leaked_on_genes(&self, policy: &MaskPolicy) -> usize {
+        self.masked_genes(policy).len()
+    }    
+
+    pub fn total_incoming_leaks(&self, policy: &MaskPolicy) -> f64 {
+        self.masked_genes(policy).iter()
+            .fold(0.0, |acc, gene| acc + self.leaks[*gene].as_ref().unwrap().incoming)
+    }
+
+    pub fn num_good_genes(&self, policy: &MaskPolicy) -> usize {
+        self.num_genes() - self.num_leaked_on_genes(policy)
+    }
+
+    pub fn diff(&self, other: &Self, tolerance: f64) -> Vec<Difference> {
+        let mut result = Vec::new();
+        for gene in 0..self.leaks.len().max(other.leaks.len()) {
+            let key = format!("{}:{}", self.id, gene);
+            let left = self.leaks.get(gene).and_then(|l| l.as_ref());
+            let right = other.leaks.get(gene).and_then(|l| l.as_ref());
+            match (left, right) {
+                (Some(l), Some(r)) => result.extend(l.diff(r, &key, tolerance)),
+                (Some(l), None) => result.push(Difference::new(&key, "gene", Some(l.incoming.to_string()), None)),
+                (None, Some(r)) => result.push(Difference::new(&key, "gene", None, Some(r.incoming.to_string()))),
+                (None, None) => (),
+            }
+        }
+        result
+    }
+
+    /// Whether the species has too few genes to be masked gene by gene (`min_genes_initial`).
+    pub fn is_quarantined(&self, policy: &MaskPolicy) -> bool {
+        self.num_genes() < policy.min_genes_initial
+    }
+
+    /// Genes that the policy considers leaked on, in ascending order, none for quarantined species.
+    /// Only as many are kept as leave `min_genes_remaining` genes: lowest weight first (all genes
+    /// weigh the same without gene weights), then highest incoming, then lowest id.
+    pub fn masked_genes(&self, policy: &MaskPolicy) -> Vec<GeneID> {
+        if self.is_quarantined(policy) {
+            return Vec::new()
+        }
+        let mut candidates = self.leaks.iter().enumerate()
+            .filter_map(|(gene, leaks)| leaks.as_ref().filter(|l| policy.masks(self.id, gene, l)).map(|l| (gene, l)))
+            .collect::<Vec<(GeneID, &Leaks)>>();
+
+        let budget = self.num_genes().saturating_sub(policy.min_genes_remaining);
+        if candidates.len() > budget {
+            let weight = |gene: GeneID| policy.gene_weights.as_ref().map_or(1.0, |weights| weights.get(gene));
+            candidates.sort_by(|(a, a_leaks), (b, b_leaks)| weight(*a).total_cmp(&weight(*b))
+                .then(b_leaks.incoming.total_cmp(&a_leaks.incoming))
+                .then(a.cmp(b)));
+            candidates.truncate(budget);
+            candidates.sort_by_key(|(gene, _leaks)| *gene);
+        }
+        candidates.into_iter().map(|(gene, _leaks)| gene).collect()
+    }
+
+    /// The masked genes with the rule, threshold and top donor behind each. `lineages` (by taxid,
+    /// may be empty) gives the LCA rank to the top donor.
+    pub fn mask_entries(&self, policy: &MaskPolicy, lineages: &[String]) -> Vec<MaskEntry> {
+        let lineage = |id: TaxID| lineages.get(id).map(String::as_str).unwrap_or_default();
+        self.masked_genes(policy).into_iter().map(|gene| {
+            let leaks = self.leaks[gene].as_ref().expect("Masked genes have leaks");
+            let (rule, threshold) = policy.rule(self.id, gene, leaks).expect("Masked genes have a rule");
+            let top_donor = leaks.donors.top();
+            MaskEntry {
+                taxon: self.id,
+                gene,
+                metric: Some("incoming".to_string()),
+                value: Some(leaks.incoming),
+                threshold: Some(threshold),
+                top_donor,
+                lca_rank: top_donor.and_then(|donor| lca_rank(lineage(self.id), lineage(donor))).map(str::to_string),
+                rule: Some(rule.to_string()),
+            }
+        }).collect()
+    }
+
+    /// Genes whose incoming leakage exceeds the policy's threshold, masked or not, in ascending order.
+    pub fn leaked_genes(&self, policy: &MaskPolicy) -> Vec<GeneID> {
+        self.leaks.iter().enumerate()
+            .filter(|(gene, leaks)| leaks.as_ref().is_some_and(|l| policy.leaked_enough(self.id, *gene, l)))
+            .map(|(gene, _leaks)| gene)
+            .collect()
+    }
+
+    /// Genes the policy reports as suspect instead of masking them, in ascending order.
+    pub fn suspect_genes(&self, policy: &MaskPolicy) -> Vec<GeneID> {
+        self.leaks.iter().enumerate()
+            .filter(|(gene, leaks)| leaks.as_ref().is_some_and(|l| policy.is_suspect(self.id, *gene, l)))
+            .map(|(gene, _leaks)| gene)
+            .collect()
+    }
+
+    pub fn report<'a>(&'a self, policy: &'a MaskPolicy) -> SpeciesReport<'a> {
+        SpeciesReport { species: self, policy }
+    }
+
+}
+
+/// Which taxa carry each gene, one bitset over taxa per gene. A taxon carries a gene if it has
+/// reads on it at all, as the source of reads or as their reference.
+pub struct GenePresence {
+    index: HashMap<TaxID, usize>,
+    genes: Vec<Vec<u64>>,
+}
+
+impl GenePresence {
+    pub fn from_gene_leaks(gene_leaks: &GeneLeaks) -> Self {
+        let mut taxa = gene_leaks.species.keys().copied().collect::<Vec<TaxID>>();
+        taxa.sort_unstable();
+        let index = taxa.iter().enumerate().map(|(i, taxon)| (*taxon, i)).collect::<HashMap<TaxID, usize>>();
+        let words = taxa.len().div_ceil(64);
+        let mut genes: Vec<Vec<u64>> = Vec::new();
+        for species in gene_leaks.species.values() {
+            let i = index[&species.id];
+            for gene in species.leaks.iter().enumerate().filter(|(_gene, leaks)| leaks.is_some()).map(|(gene, _leaks)| gene) {
+                if gene >= genes.len() {
+                    genes.resize_with(gene + 1, || vec![0; words]);
+                }
+                genes[gene][i / 64] |= 1 << (i % 64);
+            }
+        }
+        Self { index, genes }
+    }
+
+    pub fn has(&self, taxon: TaxID, gene: GeneID) -> bool {
+        let (Some(i), Some(bits)) = (self.index.get(&taxon), self.genes.get(gene)) else { return false };
+        bits[i / 64] & (1 << (i % 64)) != 0
+    }
+}
+
+#[derive(Default)]
+pub struct GeneLeaks {
+    species: HashMap<TaxID, Species>,
+    /// Last gene of the panel (--panel or --n-genes), the report has a column up to it even
+    /// without reads on it.
+    max_gene: Option<GeneID>,
+}
+
+// type DirectionalLeakageKey = (TaxID, TaxID);
+
+// pub struct DirectionalLeak {
+
+// }
+
+impl PartialEq for GeneLeaks {
+    fn eq(&self, other: &Self) -> bool {
+        self.diff(other, 0.0).is_empty()
+    }
+}
+
+
+impl GeneLeaks {
+    pub fn with_max_gene(mut self, max_gene: Option<GeneID>) -> Self {
+        self.max_gene = max_gene;
+        self
+    }
+
+    pub fn count_correct(&mut self, species: TaxID, gene: GeneID, increment: f64) {
+        let entry = self.species.entry(species).or_insert(Species::new(species));
+        entry.add_correct(gene, increment);
+    }
+
+    pub fn count_incorrect(&mut self, species: TaxID, gene: GeneID, incoming: bool, partner: TaxID, increment: f64) {
+        let entry = self.species.entry(species).or_insert(Species::new(species));
+        entry.add_incorrect(gene, incoming, partner, increment);
+    }
+
+    /// Adds the span of an incoming leaked record to the window coverage of its reference gene.
+    /// Records on sequences without an @SQ length or without a reference span are left out.
+    pub fn cover_incoming(&mut self, species: TaxID, gene: GeneID, sam: &SamRef, header: &SamHeader, window: u32) {
+        let (Some(length), Some(span)) = (header.sequences.get(sam.rname), reference_span(sam)) else { return };
+        let entry = self.species.entry(species).or_insert(Species::new(species));
+        entry.cover_incoming(gene, *length, window, sam.pos.saturating_sub(1), span);
+    }
+
+    /// Adds the aligned length of an incoming leaked record to its reference gene, if it has one.
+    pub fn add_leak_length(&mut self, species: TaxID, gene: GeneID, sam: &SamRef) {
+        let Some(length) = sam.aligned_length() else { return };
+        self.species.entry(species).or_insert(Species::new(species)).add_leak_length(gene, length);
+    }
+
+    /// Sets the unmapped reads by (taxid, gene) of every species, e.g. as counted by a
+    /// `RecordFilter` with --count-unmapped. Species without mapped reads are left out, genes
+    /// with unmapped reads only have no column to show them in.
+    pub fn set_unmapped(&mut self, unmapped: &HashMap<(TaxID, GeneID), u64>) {
+        for species in self.species.values_mut() {
+            species.unmapped = Some(HashMap::new());
+        }
+        for ((taxid, gene), records) in unmapped {
+            if let Some(species) = self.species.get_mut(taxid) {
+                species.unmapped.get_or_insert_with(HashMap::new).insert(*gene, *records);
+            }
+        }
+    }
+
+    /// Order-insensitive differences per species and gene, floats compared within `tolerance`.
+    pub fn diff(&self, other: &Self, tolerance: f64) -> Vec<Difference> {
+        diff_maps(&self.species, &other.species, "species", |s| s.num_genes().to_string(), |_id, l, r, result| {
+            result.extend(l.diff(r, tolerance));
+        })
+    }
+
+    /// Builds gene leaks from a pairwise map instead of re-reading the SAM. Self-pairs count as
+    /// correct and reads leak on their reference gene, but for the reads of
+    /// `Leakage::gene_mismatches`, which leak from their query gene to their reference gene as in
+    /// `get_gene_leaks`; a table read from a file has none of them. With `normalize` every read is
+    /// weighted by the donor's reads on its query gene, as in `get_normalized_gene_leaks`, the
+    /// donor's reads counting self-pairs as `self_pairs` says. Reads without a denominator are
+    /// skipped. With `ordered` the pairs are added in (from, to) order, see `pair_entries`.
+    pub fn from_pairwise(leakage: &Leakage, normalize: bool, self_pairs: SelfPairPolicy, ordered: bool) -> Self {
+        let totals = leakage.total_outgoing(self_pairs, ordered);
+        let mut mismatches = leakage.gene_mismatches.iter().collect::<Vec<_>>();
+        mismatches.sort_by_key(|(key, _reads)| **key);
+        // Reads of a pair on a reference gene that were mapped from another gene, and the donor
+        // reads moved from the reference gene to the query gene of those reads
+        let mut mismatched: HashMap<(LeakagePair, GeneID), f64> = HashMap::new();
+        let mut moved: HashMap<(TinyTaxID, GeneID), f64> = HashMap::new();
+        for (&(pair, query_gene, reference_gene), &reads) in &mismatches {
+            *mismatched.entry((pair, reference_gene)).or_default() += reads;
+            if self_pairs.in_denominators(&pair) {
+                *moved.entry((pair.from, reference_gene)).or_default() -= reads;
+                *moved.entry((pair.from, query_gene)).or_default() += reads;
+            }
+        }
+        let weight = |donor: TinyTaxID, gene: GeneID, reads: f64| match normalize {
+            true => {
+                let total = match (totals[&donor].get(gene), moved.get(&(donor, gene))) {
+                    (None, None) => return None,
+                    (total, moved) => total.unwrap_or(0) as f64 + moved.copied().unwrap_or(0.0),
+                };
+                (total > 0.0).then(|| reads / total)
+            },
+            false => Some(reads),
+        };
+        let mut result = Self::default();
+
+        // The donor's reads weight the incoming leak by the query gene and, as `get_normalized_gene_leaks`
+        // does, the outgoing one by the reference gene
+        for (&(pair, query_gene, reference_gene), &reads) in mismatches {
+            let (from, to) = (pair.from as TaxID, pair.to as TaxID);
+            if let Some(increment) = weight(pair.from, query_gene, reads) {
+                result.count_incorrect(to, reference_gene, true, from, increment);
+            }
+            if let Some(increment) = weight(pair.from, reference_gene, reads) {
+                result.count_incorrect(from, query_gene, false, to, increment);
+            }
+        }
+
+        for (pair, genes) in pair_entries(&leakage.map, ordered) {
+            let (from, to) = (pair.from as TaxID, pair.to as TaxID);
+            for (gene, count) in genes.iter() {
+                let reads = count as f64 - mismatched.get(&(*pair, gene)).copied().unwrap_or(0.0);
+                if reads <= 0.0 { continue };
+                let Some(increment) = weight(pair.from, gene, reads) else { continue };
+                match from == to {
+                    true => result.count_correct(from, gene, increment),
+                    false => {
+                        result.count_incorrect(to, gene, true, from, increment);
+                        result.count_incorrect(from, gene, false, to, increment);
+                    },
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Proposed mask as one entry per masked gene, sorted by taxon and gene. `lineages` is passed
+    /// on to `Species::mask_entries`.
+    pub fn propose_mask(&self, policy: &MaskPolicy, lineages: &[String]) -> Vec<MaskEntry> {
+        let mut result = self.species.values()
+            .flat_map(|s| s.mask_entries(policy, lineages))
+            .collect::<Vec<MaskEntry>>();
+        result.sort_by_key(|entry| (entry.taxon, entry.gene));
+        result
+    }
+
+    /// Column header of the report, gene columns numbered from 1 up to the most genes of any species
+    /// or the last gene of the panel.
+    /// Last gene of the report columns: `max_gene` or the last gene with reads, if later.
+    pub fn gene_columns(&self) -> GeneID {
+        self.species.values().map(|s| s.leaks.len().saturating_sub(1)).chain(self.max_gene).max().unwrap_or(0)
+    }
+
+    pub fn report_header(&self) -> String {
+        let genes = self.gene_columns();
+        format!("#taxid\tgood_genes\tleaked_on_genes\tmetric{}", (1..=genes).map(|gene| format!("\tgene_{}", gene)).collect::<String>())
+    }
+
+    /// Writes the column header and the wide per-gene rows of all species, most leaked on species last.
+    pub fn write_report(&self, policy: &MaskPolicy, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "{}", self.report_header())?;
+        let species = self.top_incoming(policy);
+        for (_id, s) in species.iter().rev() {
+            writeln!(writer, "{}", s.report(policy))?;
+        }
+        Ok(species.len())
+    }
+
+    /// Writes the report split into `shards` files, a species going to shard `shard(taxid)`. Every
+    /// shard starts with the column header and keeps the row order of `write_report`, so the
+    /// shards together hold exactly its rows. Shards are written concurrently by up to `threads`
+    /// threads, each creating its writers with `create(shard)`. Returns the shard of every species
+    /// in report order.
+    pub fn write_report_sharded<W: Write>(&self, policy: &MaskPolicy, shards: usize, threads: usize, shard: impl Fn(TaxID) -> usize + Sync, create: impl Fn(usize) -> std::io::Result<W> + Sync) -> std::io::Result<Vec<(TaxID, usize)>> {
+        let order = self.top_incoming(policy).into_iter().rev()
+            .map(|(id, s)| (*id, shard(*id) % shards, s))
+            .collect::<Vec<(TaxID, usize, &Species)>>();
+        let header = self.report_header();
+
+        let write_shard = |index: usize| -> std::io::Result<()> {
+            let mut writer = create(index)?;
+            writeln!(writer, "{}", header)?;
+            for (_id, _shard, s) in order.iter().filter(|(_id, shard, _s)| *shard == index) {
+                writeln!(writer, "{}", s.report(policy))?;
+            }
+            writer.flush()
+        };
+
+        let write_shard = &write_shard;
+        let threads = threads.clamp(1, shards.max(1));
+        std::thread::scope(|scope| {
+            let workers = (0..threads)
+                .map(|worker| scope.spawn(move || (worker..shards).step_by(threads).try_for_each(write_shard)))
+                .collect::<Vec<_>>();
+            workers.into_iter().try_for_each(|worker| worker.join().expect("Shard writer panicked"))
+        })?;
+
+        Ok(order.into_iter().map(|(id, shard, _s)| (id, shard)).collect())
+    }
+
+    /// Writes one row per suspect gene (taxid, gene, incoming, uniformity, donor count), sorted by
+    /// taxon and gene. Returns the number of rows.
+    pub fn write_suspects(&self, policy: &MaskPolicy, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "#taxid\tgene\tincoming\tuniformity\tdonor_count")?;
+        let mut species = self.species.values().collect::<Vec<&Species>>();
+        species.sort_by_key(|s| s.id);
+        let mut rows = 0;
+        for s in species {
+            for gene in s.suspect_genes(policy) {
+                let leaks = s.leaks[gene].as_ref().expect("Suspect genes have leaks");
+                let uniformity = leaks.uniformity().expect("Suspect genes have coverage");
+                writeln!(writer, "{}\t{}\t{}\t{}\t{}", s.id, gene, fmt_fixed(leaks.incoming), fmt_fixed(uniformity), leaks.donors.count())?;
+                rows += 1;
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Writes one GTDB-Tk style marker summary row per species: the nine columns of GTDB-Tk's
+    /// `markers_summary.tsv` followed by the leaked and masked markers. Genes seen in a species
+    /// count as unique, panel genes not seen as missing; duplicates are not known here, so those
+    /// columns stay empty. Species are named by `labels` (by taxid, may be empty) or their taxid,
+    /// marker lists are sorted by name. Returns the number of rows.
+    pub fn write_marker_summary(&self, policy: &MaskPolicy, markers: &MarkerNames, labels: &IdLabels, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "name\tnumber_unique_genes\tnumber_multiple_genes\tnumber_multiple_unique_genes\tnumber_missing_genes\tlist_unique_genes\tlist_multiple_genes\tlist_multiple_unique_genes\tlist_missing_genes\tnumber_leaked_genes\tnumber_masked_genes\tlist_leaked_genes\tlist_masked_genes")?;
+        let names = |genes: &mut dyn Iterator<Item = GeneID>| {
+            let mut names = genes.map(|gene| markers.name(gene)).collect::<Vec<String>>();
+            names.sort();
+            names
+        };
+        let mut species = self.species.values().collect::<Vec<&Species>>();
+        species.sort_by_key(|s| s.id);
+        for s in &species {
+            let present = |gene: &GeneID| s.leaks.get(*gene).is_some_and(Option::is_some);
+            let unique = names(&mut (0..s.leaks.len()).filter(present));
+            let missing = names(&mut markers.names.keys().copied().filter(|gene| !present(gene)));
+            let leaked = names(&mut s.leaked_genes(policy).into_iter());
+            let masked = names(&mut s.masked_genes(policy).into_iter());
+            let name = labels.get(s.id).filter(|label| !label.is_empty()).cloned().unwrap_or_else(|| s.id.to_string());
+            writeln!(writer, "{}\t{}\t0\t0\t{}\t{}\t\t\t{}\t{}\t{}\t{}\t{}", name, unique.len(), missing.len(), unique.join(","), missing.join(","),
+                leaked.len(), masked.len(), leaked.join(","), masked.join(","))?;
+        }
+        Ok(species.len())
+    }
+
+    /// Species quarantined by `min_genes_initial`, sorted by id.
+    pub fn quarantined(&self, policy: &MaskPolicy) -> Vec<TaxID> {
+        let mut result = self.species.values().filter(|s| s.is_quarantined(policy)).map(|s| s.id).collect::<Vec<TaxID>>();
+        result.sort_unstable();
+        result
+    }
+
+    /// Marks the genes of every species that no donor candidate carries: neither a taxon that
+    /// leaked into the species on any gene nor, with `lineages` (by id, may be empty), a taxon of
+    /// the same genus. Such genes cannot leak, so their lack of leakage says nothing.
+    pub fn annotate_competition(&mut self, lineages: &[String]) {
+        let presence = GenePresence::from_gene_leaks(self);
+        let genus = |taxon: TaxID| genus_of(lineages, taxon);
+        let mut genera: HashMap<&str, Vec<TaxID>> = HashMap::new();
+        for taxon in self.species.keys() {
+            if let Some(genus) = genus(*taxon) {
+                genera.entry(genus).or_default().push(*taxon);
+            }
+        }
+
+        for species in self.species.values_mut() {
+            let mut candidates = species.leaks.iter().flatten().flat_map(|leaks| leaks.donors.ids()).collect::<HashSet<TaxID>>();
+            candidates.extend(genus(species.id).and_then(|genus| genera.get(genus)).into_iter().flatten());
+            candidates.remove(&species.id);
+            let uncontested = species.leaks.iter().enumerate()
+                .filter(|(gene, leaks)| leaks.is_some() && !candidates.iter().any(|donor| presence.has(*donor, *gene)))
+                .map(|(gene, _leaks)| gene)
+                .collect();
+            species.uncontested = Some(uncontested);
+        }
+    }
+
+    /// Splits the genes left unmasked into clean because uncontested and clean despite
+    /// competition on stderr, once annotated.
+    pub fn report_competition(&self, policy: &MaskPolicy) {
+        let (mut uncontested, mut contested) = (0, 0);
+        for species in self.species.values() {
+            let Some(genes) = &species.uncontested else { return };
+            let masked = species.masked_genes(policy);
+            for gene in species.leaks.iter().enumerate().filter(|(gene, leaks)| leaks.is_some() && !masked.contains(gene)).map(|(gene, _leaks)| gene) {
+                match genes.contains(&gene) {
+                    true => uncontested += 1,
+                    false => contested += 1,
+                }
+            }
+        }
+        eprintln!("Unmasked genes: {} clean because uncontested, {} clean despite competition", uncontested, contested);
+    }
+
+    /// Lists the quarantined species on stderr, if any.
+    pub fn report_quarantined(&self, policy: &MaskPolicy) {
+        let quarantined = self.quarantined(policy);
+        if !quarantined.is_empty() {
+            eprintln!("Quarantined {} species with fewer than {} genes, not masked: {}", quarantined.len(), policy.min_genes_initial, itertools::join(&quarantined, ","));
+        }
+    }
+
+    /// Species by leaked genes and incoming leaks, most first, ties by id.
+    pub fn top_incoming(&self, policy: &MaskPolicy) -> Vec<(&TaxID, &Species)> {
+        let mut result = self.species.iter().collect::<Vec<(&TaxID, &Species)>>();
+
+        result.sort_by_key(|(id, s)| (Reverse(s.num_leaked_on_genes(policy)), Reverse(s.total_incoming_leaks(policy) as u64), **id));
+
+        result
+    }
+}
+
+
+
+/// Reads per gene of every species, by taxid and gene of the query.
+pub type SpeciesTotals = HashMap<TaxID, Vec<Option<usize>>>;
+
+/// Hands the ids of every alignment passing `filter` to `add` with the record, or with
+/// --best-per-read those of the best alignment of every read. The sample of the ids is told by
+/// `samples`.
+fn count_gene_leaks(args: &Args, iter: &mut SamReader, filter: &mut RecordFilter, samples: &mut Samples, anomalies: &mut AnomalyLog, add: &mut impl FnMut(&FromTo, &SamRef, &SamHeader)) -> Result<(), AnomalyError> {
+    let mut best_per_read = BestPerRead::from_args(args);
+    let mut line = String::new();
+    while let Some(mut sam) = iter.next_valid_ref(&mut line, anomalies)? {
+        if filter.evaluate(&sam) != Decision::Keep {continue};
+
+        let sample = samples.assign(&mut sam);
+        let ids = match sam_to_ids(&sam, &filter.bounds, &mut filter.names) {
+            Ok(ids) => FromTo { sample, ..ids },
+            Err(e) => {
+                anomalies.record(Anomaly::UnparseableName, &e, Some(iter.line))?;
+                continue
+            },
+        };
+        match best_per_read.as_mut() {
+            Some(best_per_read) => if let Some((ids, sam)) = best_per_read.push(sample, &sam, (ids, sam.to_owned()), iter.line, anomalies)? {
+                add(&ids, &sam.view(), &iter.header);
+            },
+            None => add(&ids, &sam, &iter.header),
+        }
+    }
+    if let Some(best_per_read) = best_per_read.as_mut() {
+        if let Some((ids, sam)) = best_per_read.finish(anomalies)? {
+            add(&ids, &sam.view(), &iter.header);
+        }
+        eprintln!("Reads: {}", best_per_read);
+    }
+    Ok(())
+}
+
+pub fn get_species_total(args: &Args, anomalies: &mut AnomalyLog) -> Result<SpeciesTotals, InputError> {
+    let mut totals = species_totals(args, &mut Samples::default(), anomalies)?;
+    Ok(totals.pop().unwrap_or_default())
+}
+
+/// `get_species_total` per read group (--by-read-group), by read group in order of first
+/// appearance.
+pub fn get_species_total_by_read_group(args: &Args, anomalies: &mut AnomalyLog) -> Result<(Samples, Vec<SpeciesTotals>), InputError> {
+    let mut samples = Samples::by_read_group();
+    let mut totals = species_totals(args, &mut samples, anomalies)?;
+    totals.resize_with(samples.names.len(), Default::default);
+    Ok((samples, totals))
+}
+
+/// Species totals by sample of `samples`.
+fn species_totals(args: &Args, samples: &mut Samples, anomalies: &mut AnomalyLog) -> Result<Vec<SpeciesTotals>, InputError> {
+    let mut result: Vec<SpeciesTotals> = Vec::new();
+
+    
+    let mut iter = sam_input(args)?;
+    anomalies.set_input(&args.input_label());
+    let mut filter = RecordFilter::from_args(args);
+    // A read counts once with --best-per-read, whichever of its alignments is best
+    let mut best_per_read = BestPerRead::from_args(args);
+    let mut add = |(sample, query_tid, query_gid): (SampleID, TaxID, GeneID)| {
+        let entry: &mut Vec<Option<usize>> = sample_table(&mut result, sample, SpeciesTotals::default).entry(query_tid).or_insert(Vec::default());
+        if query_gid >= entry.len() || entry[query_gid].is_none() {
+            entry.resize_with(query_gid + 1, || None);
+            entry[query_gid] = Some(0);
+        }
+        *entry[query_gid].as_mut().unwrap() += 1;
+    };
+
+
+    let mut line = String::new();
+    while let Some(mut sam) = iter.next_valid_ref(&mut line, anomalies)? {
+        // eprintln!("{:?}", sam);
+        if filter.evaluate(&sam) != Decision::Keep {continue};
+
+        let sample = samples.assign(&mut sam);
+        let (query_tid, query_gid) = match filter.bounds.parse(sam.qname) {
+            Ok(ids) => ids,
+            Err(e) => {
+                anomalies.record(Anomaly::UnparseableName, &format!("Query not parseable: {}: {}", sam.qname, e), Some(iter.line))?;
+                continue
+            },
+        };
+
+        filter.gene_ids.observe(query_gid);
+        match best_per_read.as_mut() {
+            Some(best_per_read) => best_per_read.push(sample, &sam, (sample, query_tid, query_gid), iter.line, anomalies)?.into_iter().for_each(&mut add),
+            None => add((sample, query_tid, query_gid)),
+        }
+    }
+    if let Some(best_per_read) = best_per_read.as_mut() {
+        best_per_read.finish(anomalies)?.into_iter().for_each(&mut add);
+    }
+    eprintln!("Records: {}", filter);
+    filter.check_gene_ids(anomalies)?;
+
+    Ok(result)
+}
+
+pub fn get_normalized_gene_leaks(args: &Args, total_counts: &SpeciesTotals, anomalies: &mut AnomalyLog) -> Result<GeneLeaks, InputError> {
+    let mut leaks = normalized_gene_leaks(args, &mut Samples::default(), std::slice::from_ref(total_counts), anomalies)?;
+    Ok(leaks.remove(0))
+}
+
+/// `get_normalized_gene_leaks` per read group of `get_species_total_by_read_group`, every read
+/// group normalized by its own totals. All read groups get the gene columns of the widest one.
+pub fn get_normalized_gene_leaks_by_read_group(args: &Args, samples: &Samples, totals: &[SpeciesTotals], anomalies: &mut AnomalyLog) -> Result<Vec<(String, GeneLeaks)>, InputError> {
+    let mut samples = samples.clone();
+    let leaks = normalized_gene_leaks(args, &mut samples, totals, anomalies)?;
+    let mut result = name_tables(samples, leaks, GeneLeaks::default);
+    let genes = result.iter().map(|(_group, leaks)| leaks.gene_columns()).max();
+    for (_group, leaks) in &mut result {
+        leaks.max_gene = genes;
+    }
+    Ok(result)
+}
+
+/// Gene leaks by sample of `samples`, each normalized by the totals of its sample.
+fn normalized_gene_leaks(args: &Args, samples: &mut Samples, totals: &[SpeciesTotals], anomalies: &mut AnomalyLog) -> Result<Vec<GeneLeaks>, InputError> {
+    let mut iter = sam_input(args)?;
+    anomalies.set_input(&args.input_label());
+    let mut filter = RecordFilter::from_args(args);
+    let max_gene = filter.bounds.max_gene;
+    let empty = || GeneLeaks { max_gene, ..Default::default() };
+    let mut result = totals.iter().map(|_total| empty()).collect::<Vec<GeneLeaks>>();
+
+
+    let mut add = |ids: &FromTo, sam: &SamRef, header: &SamHeader| {
+        let (query_tid, query_gid, ref_tid, ref_gid) = (ids.query as TaxID, ids.query_gene as GeneID, ids.reference as TaxID, ids.reference_gene as GeneID);
+        let correct = query_tid == ref_tid && query_gid == ref_gid;
+        let total_counts = totals.get(ids.sample as usize);
+        let result = sample_table(&mut result, ids.sample, empty);
+
+        let qt = total_counts.and_then(|total_counts| total_counts.get(&query_tid));
+        let query_total = match qt {
+            Some(qt) => qt[query_gid].unwrap(),
+            None => 0,
+        };
+
+        let rt = total_counts.and_then(|total_counts| total_counts.get(&ref_tid));
+        let ref_total = match rt {
+            Some(rt) => rt[ref_gid].unwrap(),
+            None => 0,
+        };
+
+        match correct {
+            true => result.count_correct(query_tid, query_gid, 1.0 / query_total as f64),
+            false => {
+                result.count_incorrect(ref_tid, ref_gid, true, query_tid, 1.0 / query_total as f64);
+                result.cover_incoming(ref_tid, ref_gid, sam, header, args.uniformity_window);
+                if args.tracks_leak_lengths() {
+                    result.add_leak_length(ref_tid, ref_gid, sam);
+                }
+                result.count_incorrect(query_tid, query_gid, false, ref_tid, 1.0 / ref_total as f64);
+            },
+        }
+    };
+    count_gene_leaks(args, &mut iter, &mut filter, samples, anomalies, &mut add)?;
+    eprintln!("Records: {}", filter);
+    if args.count_unmapped {
+        for leaks in &mut result {
+            leaks.set_unmapped(&filter.unmapped);
+        }
+    }
+
+    
+    Ok(result)
+}
+
+
+pub fn get_gene_leaks(args: &Args, anomalies: &mut AnomalyLog) -> Result<GeneLeaks, InputError> {
+    let mut result = GeneLeaks::default();
+
+    let mut iter = sam_input(args)?;
+    anomalies.set_input(&args.input_label());
+    let mut filter = RecordFilter::from_args(args);
+    result.max_gene = filter.bounds.max_gene;
+
+
+    let mut add = |ids: &FromTo, sam: &SamRef, header: &SamHeader| {
+        let (query_tid, query_gid, ref_tid, ref_gid) = (ids.query as TaxID, ids.query_gene as GeneID, ids.reference as TaxID, ids.reference_gene as GeneID);
+        let correct = query_tid == ref_tid && query_gid == ref_gid;
+
+        match correct {
+            true => result.count_correct(query_tid, query_gid, 1.0),
+            false => {
+                result.count_incorrect(ref_tid, ref_gid, true, query_tid, 1.0);
+                result.cover_incoming(ref_tid, ref_gid, sam, header, args.uniformity_window);
+                if args.tracks_leak_lengths() {
+                    result.add_leak_length(ref_tid, ref_gid, sam);
+                }
+                result.count_incorrect(query_tid, query_gid, false, ref_tid, 1.0);
+            },
+        }
+    };
+    count_gene_leaks(args, &mut iter, &mut filter, &mut Samples::default(), anomalies, &mut add)?;
+    eprintln!("Records: {}", filter);
+    if args.count_unmapped {
+        result.set_unmapped(&filter.unmapped);
+    }
+
+    
+    Ok(result)
+}
+
+pub const MASK_FORMAT_PREFIX: &str = "#mask_format\t";
+
+/// One masked gene of a v2 mask: the metric that triggered the mask with its value and threshold,
+/// the top donor, the LCA rank to it and the rule that decided. Masks read from v1 files only
+/// carry taxon and gene, the other fields are None (NA in the file).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaskEntry {
+    pub taxon: TaxID,
+    pub gene: GeneID,
+    pub metric: Option<String>,
+    pub value: Option<f64>,
+    pub threshold: Option<f64>,
+    pub top_donor: Option<TaxID>,
+    pub lca_rank: Option<String>,
+    pub rule: Option<String>,
+}
+
+impl MaskEntry {
+    pub const COLUMNS: [&'static str; 8] = ["taxid", "gene", "metric", "value", "threshold", "top_donor", "lca_rank", "rule"];
+
+    pub fn from_v1(taxon: TaxID, gene: GeneID) -> Self {
+        Self { taxon, gene, metric: None, value: None, threshold: None, top_donor: None, lca_rank: None, rule: None }
+    }
+
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let tokens = line.split('\t').collect::<Vec<&str>>();
+        if tokens.len() != Self::COLUMNS.len() {
+            return Err(format!("Expected {} columns, found {}: {}", Self::COLUMNS.len(), tokens.len(), line))
+        }
+        fn field<T: std::str::FromStr>(token: &str, column: &str) -> Result<Option<T>, String> {
+            match token {
+                "NA" => Ok(None),
+                token => token.parse().map(Some).map_err(|_| format!("Invalid {} '{}'", column, token)),
+            }
+        }
+        Ok(Self {
+            taxon: tokens[0].parse().map_err(|_| format!("Invalid taxon '{}'", tokens[0]))?,
+            gene: tokens[1].parse().map_err(|_| format!("Invalid gene '{}'", tokens[1]))?,
+            metric: field(tokens[2], "metric")?,
+            value: field(tokens[3], "value")?,
+            threshold: field(tokens[4], "threshold")?,
+            top_donor: field(tokens[5], "top_donor")?,
+            lca_rank: field(tokens[6], "lca_rank")?,
+            rule: field(tokens[7], "rule")?,
+        })
+    }
+}
+
+impl Display for MaskEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn na<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map_or_else(|| "NA".to_string(), |v| v.to_string())
+        }
+        write!(f, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", self.taxon, self.gene, na(&self.metric),
+            na(&self.value.map(fmt_fixed)), na(&self.threshold.map(fmt_fixed)), na(&self.top_donor), na(&self.lca_rank), na(&self.rule))
+    }
+}
+
+/// Groups mask entries into v1 (taxon, genes) rows sorted by taxon and gene. Lossy: everything
+/// but taxon and gene is dropped.
+pub fn mask_to_v1(entries: &[MaskEntry]) -> Vec<(TaxID, Vec<GeneID>)> {
+    let mut grouped: HashMap<TaxID, Vec<GeneID>> = HashMap::new();
+    for entry in entries {
+        grouped.entry(entry.taxon).or_default().push(entry.gene);
+    }
+    let mut result = grouped.into_iter().collect::<Vec<(TaxID, Vec<GeneID>)>>();
+    for (_taxon, genes) in result.iter_mut() {
+        genes.sort();
+        genes.dedup();
+    }
+    result.sort_by_key(|(taxon, _genes)| *taxon);
+    result
+}
+
+/// One entry per gene of v1 rows, without metrics.
+pub fn mask_from_v1(mask: &[(TaxID, Vec<GeneID>)]) -> Vec<MaskEntry> {
+    mask.iter().flat_map(|(taxon, genes)| genes.iter().map(|gene| MaskEntry::from_v1(*taxon, *gene))).collect()
+}
+
+/// Writes a v2 mask: the format header, the fingerprint of the reference the mask was derived
+/// from if known, a column header and one row per masked gene.
+pub fn write_mask_v2(entries: &[MaskEntry], reference: Option<&str>, writer: &mut impl Write) -> std::io::Result<usize> {
+    writeln!(writer, "{}2", MASK_FORMAT_PREFIX)?;
+    if let Some(fingerprint) = reference {
+        writeln!(writer, "{}{}", REFERENCE_FINGERPRINT_PREFIX, fingerprint)?;
+    }
+    writeln!(writer, "#{}", MaskEntry::COLUMNS.join("\t"))?;
+    for entry in entries {
+        writeln!(writer, "{}", entry)?;
+    }
+    Ok(entries.len())
+}
+
+/// Writes a mask as one `taxid<TAB>gene,gene,...` row per taxon (v1, no header), the format apply-mask reads.
+pub fn write_mask(mask: &[(TaxID, Vec<GeneID>)], writer: &mut impl Write) -> std::io::Result<usize> {
+    for (id, genes) in mask {
+        writeln!(writer, "{}\t{}", id, itertools::join(genes, ","))?;
+    }
+    Ok(mask.len())
+}
+
+/// Reads a mask of either version, v2 being recognized by its format header.
+pub fn read_mask_entries(path: impl AsRef<Path>) -> std::io::Result<Vec<MaskEntry>> {
+    let invalid = |line: usize, e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Line {}: {}", line, e));
+    let mut version = 1;
+    let mut entries = Vec::new();
+    for (index, line) in file_lines(path)?.enumerate() {
+        let line = strip_cr(line?);
+        if let Some(format) = line.strip_prefix(MASK_FORMAT_PREFIX) {
+            version = match format.trim() {
+                "2" => 2,
+                format => return Err(invalid(index + 1, format!("Unknown mask format '{}'", format))),
+            };
+        }
+        if line.is_empty() || line.starts_with('#') { continue };
+        if version == 2 {
+            entries.push(MaskEntry::parse(&line).map_err(|e| invalid(index + 1, e))?);
+            continue
+        }
+        let (id, genes) = line.split_once('\t').unwrap_or((&line, ""));
+        let id: TaxID = id.parse().map_err(|_| invalid(index + 1, format!("Invalid taxon '{}'", id)))?;
+        for gene in genes.split(',').filter(|g| !g.is_empty()) {
+            let gene: GeneID = gene.parse().map_err(|_| invalid(index + 1, format!("Invalid gene '{}' for taxon {}", gene, id)))?;
+            entries.push(MaskEntry::from_v1(id, gene));
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads a mask of either version into the masked genes per taxon.
+pub fn read_mask(path: impl AsRef<Path>) -> std::io::Result<HashMap<TaxID, HashSet<GeneID>>> {
+    let mut mask: HashMap<TaxID, HashSet<GeneID>> = HashMap::new();
+    for entry in read_mask_entries(path)? {
+        mask.entry(entry.taxon).or_default().insert(entry.gene);
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pairwise_leakage::TinyGeneID;
+
+    fn some(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn gene_leaks_diff_reports_missing_extra_and_changed_values() {
+        let mut left = GeneLeaks::default();
+        left.count_correct(1, 1, 1.0);
+        left.count_correct(1, 3, 0.5);
+        left.count_incorrect(2, 2, true, 1, 0.25);
+        let mut right = GeneLeaks::default();
+        right.count_correct(1, 1, 2.0);
+        right.count_correct(1, 2, 1.0);
+        right.count_correct(1, 3, 0.55);
+        right.count_incorrect(3, 1, true, 1, 0.25);
+        assert_eq!(left.diff(&right, 0.1), vec![
+            Difference::new("1:1", "correct", some("1"), some("2")),
+            Difference::new("1:2", "gene", None, some("0")),
+            Difference::new(2, "species", some("1"), None),
+            Difference::new(3, "species", None, some("1")),
+        ]);
+        assert!(left != right);
+        assert!(left.diff(&right, 1.0).len() == 3);
+    }
+
+    fn fromto(query: TinyTaxID, query_gene: TinyGeneID, reference: TinyTaxID, reference_gene: TinyGeneID) -> FromTo {
+        FromTo { query, reference, query_gene, reference_gene, sample: 0, aligned_length: None, weight: None, reverse: None }
+    }
+
+    /// Two correct reads of taxon 1, one between two of its genes and one leaked to taxon 2.
+    fn pairwise() -> Leakage {
+        let mut leakage = Leakage::default();
+        for record in [fromto(1, 1, 1, 1), fromto(1, 1, 1, 1), fromto(1, 2, 1, 1), fromto(1, 1, 2, 1)] {
+            leakage.add(&record);
+        }
+        leakage
+    }
+
+    #[test]
+    fn from_pairwise_counts_self_pairs_between_genes_as_incorrect() {
+        let mut expected = GeneLeaks::default();
+        expected.count_correct(1, 1, 2.0);
+        expected.count_incorrect(1, 1, true, 1, 1.0);
+        expected.count_incorrect(1, 2, false, 1, 1.0);
+        expected.count_incorrect(2, 1, true, 1, 1.0);
+        expected.count_incorrect(1, 1, false, 2, 1.0);
+        assert_eq!(GeneLeaks::from_pairwise(&pairwise(), false, SelfPairPolicy::default(), true).diff(&expected, 1e-12), Vec::new());
+    }
+
+    #[test]
+    fn from_pairwise_normalizes_by_the_donor_reads_on_the_query_gene() {
+        // Taxon 1 has 3 reads from gene 1 and 1 from gene 2
+        let mut expected = GeneLeaks::default();
+        expected.count_correct(1, 1, 2.0 / 3.0);
+        expected.count_incorrect(1, 1, true, 1, 1.0);
+        expected.count_incorrect(1, 2, false, 1, 1.0 / 3.0);
+        expected.count_incorrect(2, 1, true, 1, 1.0 / 3.0);
+        expected.count_incorrect(1, 1, false, 2, 1.0 / 3.0);
+        assert_eq!(GeneLeaks::from_pairwise(&pairwise(), true, SelfPairPolicy::default(), true).diff(&expected, 1e-12), Vec::new());
+    }
+
+    /// Taxon 1 with five genes, four leaked on from taxon 2 with decreasing incoming leakage.
+    fn leaked_species() -> Species {
+        let mut species = Species::new(1);
+        for (gene, incoming) in [(1, 0.4), (2, 0.3), (3, 0.2), (4, 0.1), (5, 0.0)] {
+            species.add_correct(gene, 1.0);
+            if incoming > 0.0 {
+                species.add_incorrect(gene, true, 2, incoming);
+            }
+        }
+        species
+    }
+
+    fn weights(name: &str, content: &str) -> GeneWeights {
+        let path = crate::utils::test_path(name);
+        std::fs::write(&path, content).unwrap();
+        GeneWeights::read(&path).unwrap()
+    }
+
+    #[test]
+    fn gene_weights_choose_which_genes_the_budget_masks() {
+        let species = leaked_species();
+        let policy = MaskPolicy { threshold: 0.05, min_genes_remaining: 3, ..MaskPolicy::default() };
+        // Two of the four candidates fit the budget, unweighted the most leaked on
+        assert_eq!(species.masked_genes(&policy), vec![1, 2]);
+
+        // Genes 1 and 2 are load-bearing, the low-weight genes are masked instead
+        let weighted = MaskPolicy { gene_weights: Some(weights("weights.tsv", "# gene\tweight\n1\t5\n2\t2\n3\t0.5\n4\t0.5\n")), ..policy.clone() };
+        assert_eq!(species.masked_genes(&weighted), vec![3, 4]);
+
+        // Unlisted genes weigh 1, lighter genes go first
+        let partial = MaskPolicy { gene_weights: Some(weights("partial_weights.tsv", "1\t0.1\n4\t0.2\n")), ..policy.clone() };
+        assert_eq!(species.masked_genes(&partial), vec![1, 4]);
+
+        // Without a binding budget the weights change nothing
+        let unbounded = MaskPolicy { min_genes_remaining: 0, ..weighted.clone() };
+        assert_eq!(species.masked_genes(&unbounded), vec![1, 2, 3, 4]);
+        assert_eq!(species.masked_genes(&MaskPolicy { min_genes_remaining: 0, ..policy }), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn gene_weight_ties_break_by_incoming_then_gene() {
+        let species = leaked_species();
+        let policy = MaskPolicy { threshold: 0.05, min_genes_remaining: 4, gene_weights: Some(weights("tied_weights.tsv", "3\t0.5\n4\t0.5\n")), ..MaskPolicy::default() };
+        assert_eq!(species.masked_genes(&policy), vec![3]);
+
+        let mut even = Species::new(1);
+        for gene in 1..=3 {
+            even.add_correct(gene, 1.0);
+            even.add_incorrect(gene, true, 2, 0.2);
+        }
+        let policy = MaskPolicy { threshold: 0.05, min_genes_remaining: 2, gene_weights: Some(GeneWeights::default()), ..MaskPolicy::default() };
+        assert_eq!(even.masked_genes(&policy), vec![1]);
+    }
+
+    #[test]
+    fn gene_weights_refuse_invalid_rows() {
+        for content in ["1\n", "x\t1\n", "1\tNaN\n", "1\theavy\n"] {
+            let path = crate::utils::test_path("invalid_weights.tsv");
+            std::fs::write(&path, content).unwrap();
+            assert!(GeneWeights::read(&path).is_err(), "{:?}", content);
+        }
+    }
+
+    /// Taxon 2 leaks into taxon 1 on gene 1, taxa 3 and 4 share gene 4 without leaking.
+    fn competing_species() -> GeneLeaks {
+        let mut leaks = GeneLeaks::default();
+        for (taxon, genes) in [(1, &[1, 2, 3][..]), (2, &[1, 2]), (3, &[3, 4]), (4, &[4])] {
+            genes.iter().for_each(|gene| leaks.count_correct(taxon, *gene, 1.0));
+        }
+        leaks.count_incorrect(1, 1, true, 2, 0.5);
+        leaks.count_incorrect(2, 1, false, 1, 0.5);
+        leaks
+    }
+
+    fn uncontested(leaks: &GeneLeaks, taxon: TaxID) -> Vec<GeneID> {
+        let mut genes = leaks.species[&taxon].uncontested.clone().unwrap().into_iter().collect::<Vec<GeneID>>();
+        genes.sort_unstable();
+        genes
+    }
+
+    #[test]
+    fn genes_no_donor_candidate_carries_are_uncontested() {
+        let mut leaks = competing_species();
+        let presence = GenePresence::from_gene_leaks(&leaks);
+        assert!(presence.has(1, 3) && presence.has(2, 1) && presence.has(4, 4));
+        assert!(!presence.has(2, 3) && !presence.has(1, 4) && !presence.has(5, 1) && !presence.has(1, 9));
+
+        // Only donors that leaked in compete: taxon 2 carries genes 1 and 2 of taxon 1
+        leaks.annotate_competition(&[]);
+        assert_eq!(uncontested(&leaks, 1), vec![3]);
+        for (taxon, genes) in [(2, vec![1, 2]), (3, vec![3, 4]), (4, vec![4])] {
+            assert_eq!(uncontested(&leaks, taxon), genes, "{}", taxon);
+        }
+        let report = leaks.species[&1].report(&MaskPolicy::default()).to_string();
+        assert!(report.lines().any(|line| line == "1\t2\t1\tuncontested\t0\t0\t1"), "{}", report);
+
+        // Taxa of a genus compete for each other's genes: taxon 3 carries gene 3 of taxon 1
+        let mut lineages = vec![String::new(); 5];
+        lineages[1] = "d__Bacteria;p__P;c__C;o__O;f__F;g__Alpha;s__Alpha one".to_string();
+        lineages[3] = "d__Bacteria;p__P;c__C;o__O;f__F;g__Alpha;s__Alpha three".to_string();
+        lineages[4] = "d__Bacteria;p__P;c__C;o__O;f__F;g__;s__".to_string();
+        leaks.annotate_competition(&lineages);
+        assert_eq!(uncontested(&leaks, 1), Vec::<GeneID>::new());
+        assert_eq!(uncontested(&leaks, 3), vec![4]);
+        assert_eq!(uncontested(&leaks, 4), vec![4]);
+    }
+
+    fn panel(name: &str, content: &str) -> std::io::Result<Panel> {
+        let path = crate::utils::test_path(name);
+        std::fs::write(&path, content).unwrap();
+        Panel::read(&path)
+    }
+
+    #[test]
+    fn panels_read_from_files_and_embedded_by_name() {
+        let custom = panel("custom.tsv", "gene_id\tname\texpected_length\tgroup\r\n2\tPF00380.20\t450\tribosomal\n# comment\n\n5\tTIGR00001\tNA\t\n3\tPF00410.14\t\tribosomal\n").unwrap();
+        // Named after the file stem
+        assert!(custom.name.ends_with("custom"), "{}", custom.name);
+        assert_eq!((custom.len(), custom.max_gene(1), custom.gene_ids(1)), (3, 5, vec![2, 3, 5]));
+        assert_eq!(custom.gene(2), Some(&PanelGene { name: "PF00380.20".to_string(), expected_length: Some(450), group: Some("ribosomal".to_string()) }));
+        assert_eq!((custom.expected_length(3), custom.expected_length(5), custom.expected_length(4)), (None, None, None));
+        assert_eq!((custom.group(3), custom.group(5)), ("ribosomal", custom.name.as_str()));
+        assert!(custom.has_names());
+        let names = custom.marker_names(1);
+        assert_eq!(names.names.values().collect::<Vec<&String>>(), ["PF00380.20", "PF00410.14", "TIGR00001"]);
+        assert_eq!(names.name(4), "4");
+
+        // Embedded panels know their size only, gene ids count from the gene id base
+        let bac120 = Panel::parse_arg("bac120").unwrap();
+        assert_eq!((bac120.len(), bac120.max_gene(1), bac120.max_gene(0)), (120, 120, 119));
+        assert_eq!(bac120.gene_ids(0), (0..120).collect::<Vec<GeneID>>());
+        assert!(!bac120.has_names() && bac120.gene(1).is_none());
+        assert_eq!((bac120.group(1), bac120.marker_names(1).name(7)), ("bac120", "7".to_string()));
+        assert_eq!(Panel::parse_arg("ar53").unwrap().max_gene(1), 53);
+        let missing = Panel::parse_arg("ar122").unwrap_err();
+        assert!(missing.starts_with("Cannot read panel ar122 (embedded panels: bac120, ar53): "), "{}", missing);
+    }
+
+    #[test]
+    fn panels_refuse_invalid_rows() {
+        for (content, reason) in [
+            ("1\tPF00380.20\t450\n", "expected gene_id, name, expected_length and group"),
+            ("one\tPF00380.20\t450\tribosomal\n", "gene id is not a number"),
+            ("1\t\t450\tribosomal\n", "empty name"),
+            ("1\tPF00380.20\tlong\tribosomal\n", "expected length is not a number"),
+            ("1\tPF00380.20\t450\tribosomal\n1\tPF00410.14\t300\tribosomal\n", "gene id listed twice"),
+            ("gene_id\tname\texpected_length\tgroup\n# no genes\n", "Panel has no genes"),
+        ] {
+            let error = panel("invalid_panel.tsv", content).unwrap_err().to_string();
+            assert!(error.ends_with(reason), "{:?}: {}", content, error);
+        }
+    }
+}
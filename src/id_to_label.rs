@@ -1,34 +1,806 @@
-use std::{collections::HashMap, fs::{read_to_string, File}, io::{self, BufRead}, path::Path};
+use std::{cell::{Cell, RefCell}, collections::HashMap, fmt::Display, fs::File, io::{self, BufRead, Write}, ops::Index, path::Path};
+
+use clap::ValueEnum;
+
+use crate::{common::Args, taxonomy::Rank, utils::{file_lines, is_stdin, open_file, strip_cr}};
+
+pub const FINGERPRINT_PREFIX: &str = "#map_fingerprint\t";
 
 // The output is wrapped in a Result to allow matching on errors.
 // Returns an Iterator to the Reader of the lines of the file.
 pub fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
 where P: AsRef<Path>, {
-    let file = File::open(filename)?;
+    let file = open_file(filename)?;
     Ok(io::BufReader::new(file).lines())
 }
 
-pub fn get_labels_map(file: impl AsRef<Path>) -> (Vec<String>, HashMap<String, usize>) {
-    let mut id2lab = Vec::new();
-    let mut lab2id = HashMap::default();
+/// Labels by taxid, empty for ids without a label. Indexed by id as long as ids stay at most
+/// `DENSE_MAX_ID`, beyond that a HashMap, so a single rogue id in a map cannot allocate a table
+/// of its size.
+#[derive(Debug, Clone)]
+pub enum IdLabels {
+    Dense(Vec<String>),
+    Sparse(HashMap<usize, String>),
+}
+
+impl Default for IdLabels {
+    fn default() -> Self {
+        IdLabels::Dense(Vec::new())
+    }
+}
+
+impl IdLabels {
+    pub const DENSE_MAX_ID: usize = 1 << 24;
+
+    pub fn get(&self, id: usize) -> Option<&String> {
+        match self {
+            IdLabels::Dense(labels) => labels.get(id),
+            IdLabels::Sparse(labels) => labels.get(&id),
+        }
+    }
+
+    /// Label of an id, created empty if missing. Switches to sparse on the first id above
+    /// `DENSE_MAX_ID`.
+    fn get_mut(&mut self, id: usize) -> &mut String {
+        if let IdLabels::Dense(labels) = self {
+            if id > Self::DENSE_MAX_ID {
+                eprintln!("Warning: taxid {} in the label map, labels are kept sparse", id);
+                *self = IdLabels::Sparse(std::mem::take(labels).into_iter().enumerate().filter(|(_id, label)| !label.is_empty()).collect());
+            }
+        }
+        match self {
+            IdLabels::Dense(labels) => {
+                if id >= labels.len() {
+                    labels.resize_with(id + 1, String::default);
+                }
+                &mut labels[id]
+            },
+            IdLabels::Sparse(labels) => labels.entry(id).or_default(),
+        }
+    }
+
+    /// Non-empty labels as (id, label) by id.
+    pub fn entries(&self) -> Vec<(usize, &String)> {
+        let mut result = match self {
+            IdLabels::Dense(labels) => labels.iter().enumerate().filter(|(_id, label)| !label.is_empty()).collect::<Vec<(usize, &String)>>(),
+            IdLabels::Sparse(labels) => labels.iter().filter(|(_id, label)| !label.is_empty()).map(|(id, label)| (*id, label)).collect(),
+        };
+        if let IdLabels::Sparse(_) = self {
+            result.sort_unstable_by_key(|(id, _label)| *id);
+        }
+        result
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &String> {
+        self.entries().into_iter().map(|(_id, label)| label)
+    }
+}
+
+impl Index<usize> for IdLabels {
+    type Output = String;
+
+    fn index(&self, id: usize) -> &String {
+        self.get(id).unwrap_or_else(|| panic!("No label for taxid {}", id))
+    }
+}
+
+/// Labels of a genome2tiid map together with the rank each label was taken from.
+#[derive(Debug, Default, Clone)]
+pub struct LabelMap {
+    pub id2lab: IdLabels,
+    pub lab2id: HashMap<String, usize>,
+    /// Rank name per id ("species" for complete lineages, "unknown" without a rank prefix), absent
+    /// for ids without a lineage.
+    pub ranks: HashMap<usize, &'static str>,
+    /// Entries whose lineage ends above species and were given a placeholder label.
+    pub truncated: usize,
+    /// Entries with an empty lineage, left unlabeled.
+    pub empty: usize,
+    /// Ids by genome accession, without the GTDB `RS_`/`GB_` prefix.
+    pub accessions: HashMap<String, usize>,
+}
+
+impl LabelMap {
+    pub fn read(file: impl AsRef<Path>) -> Self {
+        let mut map = Self::default();
+
+        if let Ok(lines) = read_lines(&file) {
+            for line in lines {
+                let line = strip_cr(line.expect("Corrupt file"));
+
+                let tokens = line.split("\t").collect::<Vec<&str>>();
+                let id: usize = tokens[1].parse().unwrap();
+                let (species, rank) = species_label(tokens[3], id);
+                match rank {
+                    None => map.empty += 1,
+                    Some(rank) if rank != "species" && rank != "unknown" => map.truncated += 1,
+                    Some(_) => (),
+                }
+
+                map.accessions.insert(bare_accession(tokens[0]).to_string(), id);
+                map.id2lab.get_mut(id).push_str(&species);
+                match rank {
+                    Some(rank) => map.ranks.insert(id, rank),
+                    None => map.ranks.remove(&id),
+                };
+                if !species.is_empty() {
+                    map.lab2id.insert(species, id);
+                }
+            }
+        }
+
+        if map.truncated > 0 || map.empty > 0 {
+            eprintln!("Warning: {} lineages in {} end above species and are labeled <rank>_sp_<id>, {} are empty and left unlabeled",
+                map.truncated, file.as_ref().display(), map.empty);
+        }
+        map
+    }
+
+    pub fn rank(&self, id: usize) -> Option<&'static str> {
+        self.ranks.get(&id).copied()
+    }
+
+    /// True if the label of the id is a `<field>_sp_<id>` placeholder rather than read from the map.
+    pub fn is_generated(&self, id: usize) -> bool {
+        self.rank(id).is_some_and(|rank| rank != "species" && rank != "unknown")
+    }
+}
+
+/// Genome accession without the `RS_`/`GB_` prefix GTDB puts in front of RefSeq and GenBank ids.
+fn bare_accession(accession: &str) -> &str {
+    accession.strip_prefix("RS_").or_else(|| accession.strip_prefix("GB_")).unwrap_or(accession)
+}
+
+/// Rank name of a lineage field by its prefix, "unknown" without a GTDB prefix.
+fn field_rank(field: &str) -> &'static str {
+    Rank::of_field(field).map_or("unknown", |rank| rank.name())
+}
+
+/// Species label of a lineage and the rank it was taken from: the last field with a name (`s__`
+/// alone does not count). A lineage ending above species gets the placeholder
+/// `<field>_sp_<id>`, e.g. `g__Bacillus_sp_12`, so it is never taken for a species. An empty
+/// lineage gives an empty label and no rank.
+pub fn species_label(lineage: &str, id: usize) -> (String, Option<&'static str>) {
+    let Some(field) = lineage.split(';').map(str::trim).rev().find(|field| !field.is_empty() && Rank::from_prefix(field).is_none()) else {
+        return (String::new(), None)
+    };
+    match field_rank(field) {
+        rank @ ("species" | "unknown") => (field.to_string(), Some(rank)),
+        rank => (format!("{}_sp_{}", field, id), Some(rank)),
+    }
+}
+
+pub fn get_labels_map(file: impl AsRef<Path>) -> (IdLabels, HashMap<String, usize>) {
+    let map = LabelMap::read(file);
+    (map.id2lab, map.lab2id)
+}
+
+/// How labels are normalized before they are joined across maps, trees and taxon lists.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LabelNormalize {
+    /// Labels must match exactly
+    #[value(name = "none")]
+    None,
+    /// Decode %XX escapes and treat underscores as spaces
+    #[value(name = "underscore")]
+    Underscore,
+    /// As underscore, and also strip the rank prefix (s__), fold case and collapse whitespace
+    #[value(name = "strict")]
+    Strict,
+}
+
+impl Display for LabelNormalize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().expect("No skipped values").get_name())
+    }
+}
+
+impl LabelNormalize {
+    pub const HEADER_PREFIX: &'static str = "#label_normalize\t";
+
+    pub fn apply(&self, label: &str) -> String {
+        if *self == LabelNormalize::None {
+            return label.to_string()
+        }
+        let decoded = percent_decode(label);
+        let mut label = decoded.trim();
+        if *self == LabelNormalize::Strict {
+            if let Some((_rank, rest)) = label.split_once("__").filter(|(rank, _rest)| rank.len() == 1) {
+                label = rest;
+            }
+        }
+        let label = label.replace('_', " ");
+        match self {
+            LabelNormalize::Strict => label.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase(),
+            _ => label.trim().to_string(),
+        }
+    }
+
+    /// Header line recording the normalization of an output.
+    pub fn header(&self) -> String {
+        format!("{}{}", Self::HEADER_PREFIX, self)
+    }
+}
+
+/// Decodes %XX escapes, invalid escapes are kept as they are.
+fn percent_decode(label: &str) -> String {
+    let bytes = label.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%').then(|| label.get(i + 1..i + 3)).flatten().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escape {
+            Some(byte) => { decoded.push(byte); i += 3 },
+            None => { decoded.push(bytes[i]); i += 1 },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Labels with their ids, as a `LabelIndex` is built from.
+type LabelIds = Vec<(String, usize)>;
+
+/// Lookup by label that falls back to normalized labels, counting the lookups that only
+/// succeeded after normalization.
+pub struct LabelIndex<T> {
+    mode: LabelNormalize,
+    exact: HashMap<String, T>,
+    normalized: HashMap<String, T>,
+    /// Labels that normalize to a key already taken by another label, only the first is kept.
+    pub collisions: usize,
+    normalized_joins: Cell<usize>,
+}
+
+impl<T: Copy> LabelIndex<T> {
+    pub fn new(mode: LabelNormalize, labels: impl IntoIterator<Item = (String, T)>) -> Self {
+        let mut result = Self { mode, exact: HashMap::new(), normalized: HashMap::new(), collisions: 0, normalized_joins: Cell::new(0) };
+        for (label, value) in labels {
+            if mode != LabelNormalize::None {
+                let key = mode.apply(&label);
+                match result.normalized.get(&key) {
+                    Some(_) => result.collisions += 1,
+                    None => { result.normalized.insert(key, value); },
+                }
+            }
+            result.exact.insert(label, value);
+        }
+        result
+    }
+
+    pub fn get(&self, label: &str) -> Option<T> {
+        if let Some(value) = self.exact.get(label) {
+            return Some(*value)
+        }
+        if self.mode == LabelNormalize::None {
+            return None
+        }
+        let value = self.normalized.get(&self.mode.apply(label)).copied();
+        if value.is_some() {
+            self.normalized_joins.set(self.normalized_joins.get() + 1);
+        }
+        value
+    }
+
+    pub fn mode(&self) -> LabelNormalize {
+        self.mode
+    }
+
+    /// Lookups that failed on the exact label and succeeded on the normalized one.
+    pub fn normalized_joins(&self) -> usize {
+        self.normalized_joins.get()
+    }
+}
+
+/// Source a label was resolved through, in priority order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Provenance {
+    /// Species label of the genome2tiid map
+    PrimaryMap,
+    /// Species of the GTDB metadata for a genome of the map
+    Metadata,
+    /// Alias table entry, resolved through the map or the metadata
+    Alias,
+    /// `<field>_sp_<id>` placeholder given to a lineage ending above species
+    Generated,
+    Unresolved,
+}
+
+impl Provenance {
+    pub const ALL: [Provenance; 5] = [Provenance::PrimaryMap, Provenance::Metadata, Provenance::Alias, Provenance::Generated, Provenance::Unresolved];
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Provenance::PrimaryMap => "primary_map",
+            Provenance::Metadata => "metadata",
+            Provenance::Alias => "alias",
+            Provenance::Generated => "generated",
+            Provenance::Unresolved => "unresolved",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Outcome of resolving one label.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    pub id: Option<usize>,
+    pub provenance: Provenance,
+    /// Label an alias pointed to.
+    pub via: Option<String>,
+    /// Lower priority sources that gave a different id, with that id.
+    pub conflicts: Vec<(Provenance, usize)>,
+}
+
+/// Label to id lookup over the configured sources in priority order: the map, the GTDB metadata,
+/// the alias table and the placeholder labels. The first source knowing a label wins, the others
+/// are kept as conflicts when they disagree. Results are cached, so each label is resolved once.
+pub struct Resolver {
+    map: LabelIndex<usize>,
+    metadata: Option<LabelIndex<usize>>,
+    /// Alias to index into `targets`.
+    aliases: Option<LabelIndex<usize>>,
+    targets: Vec<String>,
+    generated: LabelIndex<usize>,
+    cache: RefCell<HashMap<String, Resolution>>,
+    lookups: Cell<usize>,
+}
+
+impl Resolver {
+    pub fn new(mode: LabelNormalize, map: &LabelMap) -> Self {
+        let (generated, species): (LabelIds, LabelIds) = map.lab2id.iter()
+            .map(|(label, id)| (label.clone(), *id))
+            .partition(|(_label, id)| map.is_generated(*id));
+        Self {
+            map: LabelIndex::new(mode, species),
+            metadata: None,
+            aliases: None,
+            targets: Vec::new(),
+            generated: LabelIndex::new(mode, generated),
+            cache: RefCell::new(HashMap::new()),
+            lookups: Cell::new(0),
+        }
+    }
+
+    /// Map, optional GTDB metadata (bac120/ar53_metadata.tsv) and optional alias table.
+    pub fn load(mode: LabelNormalize, map: &LabelMap, metadata: Option<&str>, aliases: Option<&str>) -> Result<Self, String> {
+        let mut resolver = Self::new(mode, map);
+        if let Some(path) = metadata {
+            resolver.metadata = Some(LabelIndex::new(mode, read_metadata_species(path, map)?));
+        }
+        if let Some(path) = aliases {
+            let mut index = Vec::new();
+            for (alias, target) in read_aliases(path)? {
+                index.push((alias, resolver.targets.len()));
+                resolver.targets.push(target);
+            }
+            resolver.aliases = Some(LabelIndex::new(mode, index));
+        }
+        Ok(resolver)
+    }
+
+    /// Resolver over `map` with the --metadata, --aliases and --label-normalize of the arguments.
+    pub fn from_args(args: &Args, map: &str) -> Result<Self, String> {
+        Self::load(args.label_normalize, &LabelMap::read(map), args.metadata.as_deref(), args.aliases.as_deref())
+    }
+
+    pub fn mode(&self) -> LabelNormalize {
+        self.map.mode()
+    }
+
+    pub fn get(&self, label: &str) -> Option<usize> {
+        self.resolve(label).id
+    }
+
+    pub fn resolve(&self, label: &str) -> Resolution {
+        self.lookups.set(self.lookups.get() + 1);
+        if let Some(resolution) = self.cache.borrow().get(label) {
+            return resolution.clone()
+        }
+        let resolution = self.resolve_uncached(label);
+        self.cache.borrow_mut().insert(label.to_string(), resolution.clone());
+        resolution
+    }
+
+    /// Label through the map and the metadata only, as alias targets are.
+    fn direct(&self, label: &str) -> Vec<(Provenance, usize)> {
+        [(Provenance::PrimaryMap, Some(&self.map)), (Provenance::Metadata, self.metadata.as_ref()), (Provenance::Generated, Some(&self.generated))].into_iter()
+            .filter_map(|(provenance, index)| Some((provenance, index?.get(label)?)))
+            .collect()
+    }
+
+    fn resolve_uncached(&self, label: &str) -> Resolution {
+        let mut found = self.direct(label).into_iter().filter(|(provenance, _id)| *provenance != Provenance::Generated).collect::<Vec<_>>();
+        let mut via = None;
+        if let Some(target) = self.aliases.as_ref().and_then(|aliases| aliases.get(label)).map(|i| &self.targets[i]) {
+            if let Some((_provenance, id)) = self.direct(target).first() {
+                found.push((Provenance::Alias, *id));
+                via = Some(target.clone());
+            }
+        }
+        found.extend(self.generated.get(label).map(|id| (Provenance::Generated, id)));
+
+        let Some(&(provenance, id)) = found.first() else {
+            return Resolution { id: None, provenance: Provenance::Unresolved, via: None, conflicts: Vec::new() }
+        };
+        let conflicts = found[1..].iter().filter(|(_provenance, other)| *other != id).copied().collect();
+        Resolution { id: Some(id), provenance, via: via.filter(|_| provenance == Provenance::Alias), conflicts }
+    }
+
+    /// Lookups that failed on the exact label and succeeded on the normalized one, over all sources.
+    pub fn normalized_joins(&self) -> usize {
+        [Some(&self.map), self.metadata.as_ref(), self.aliases.as_ref(), Some(&self.generated)].into_iter().flatten()
+            .map(|index| index.normalized_joins())
+            .sum()
+    }
+
+    /// Distinct labels resolved per provenance.
+    pub fn counts(&self) -> Vec<(Provenance, usize)> {
+        let cache = self.cache.borrow();
+        Provenance::ALL.iter().map(|provenance| (*provenance, cache.values().filter(|resolution| resolution.provenance == *provenance).count())).collect()
+    }
+
+    /// Writes `#provenance` count headers and one row per label resolved through an alias or a
+    /// placeholder, or with conflicting sources. Returns the number of rows.
+    pub fn write_report(&self, writer: &mut impl Write) -> io::Result<usize> {
+        writeln!(writer, "{}", self.mode().header())?;
+        writeln!(writer, "#lookups\t{}", self.lookups.get())?;
+        for (provenance, count) in self.counts() {
+            writeln!(writer, "#provenance\t{}\t{}", provenance, count)?;
+        }
+        writeln!(writer, "label\tprovenance\tid\tvia\tconflicts")?;
+        let cache = self.cache.borrow();
+        let mut rows = cache.iter()
+            .filter(|(_label, resolution)| matches!(resolution.provenance, Provenance::Alias | Provenance::Generated) || !resolution.conflicts.is_empty())
+            .collect::<Vec<_>>();
+        rows.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        for (label, resolution) in &rows {
+            let conflicts = itertools::join(resolution.conflicts.iter().map(|(provenance, id)| format!("{}:{}", provenance, id)), ",");
+            writeln!(writer, "{}\t{}\t{}\t{}\t{}", label, resolution.provenance, resolution.id.map_or("NA".to_string(), |id| id.to_string()),
+                resolution.via.as_deref().unwrap_or("NA"), if conflicts.is_empty() { "NA" } else { &conflicts })?;
+        }
+        Ok(rows.len())
+    }
+}
+
+/// Species labels of the GTDB metadata (`accession` and `gtdb_taxonomy` columns) for the genomes
+/// of the map, with the map's ids. Genomes missing from the map are skipped.
+fn read_metadata_species(path: &str, map: &LabelMap) -> Result<Vec<(String, usize)>, String> {
+    let error = |e: io::Error| format!("Cannot read {}: {}", path, e);
+    let mut lines = read_lines(path).map_err(error)?;
+    let header = strip_cr(lines.next().transpose().map_err(error)?.unwrap_or_default());
+    let column = |name: &str| header.split('\t').position(|column| column == name).ok_or_else(|| format!("{} has no {} column", path, name));
+    let (accession, taxonomy) = (column("accession")?, column("gtdb_taxonomy")?);
+
+    let mut result = Vec::new();
+    for line in lines {
+        let line = strip_cr(line.map_err(error)?);
+        let tokens = line.split('\t').collect::<Vec<&str>>();
+        let (Some(genome), Some(lineage)) = (tokens.get(accession), tokens.get(taxonomy)) else { continue };
+        let Some(&id) = map.accessions.get(bare_accession(genome)) else { continue };
+        let (species, rank) = species_label(lineage, id);
+        if rank.is_some_and(|rank| rank == "species" || rank == "unknown") {
+            result.push((species, id));
+        }
+    }
+    Ok(result)
+}
+
+/// Alias table, `alias<TAB>label` per line, `#` lines skipped.
+fn read_aliases(path: &str) -> Result<Vec<(String, String)>, String> {
+    let error = |e: io::Error| format!("Cannot read {}: {}", path, e);
+    let mut result = Vec::new();
+    for line in read_lines(path).map_err(error)? {
+        let line = strip_cr(line.map_err(error)?);
+        if line.trim().is_empty() || line.starts_with('#') { continue };
+        let Some((alias, target)) = line.split_once('\t') else {
+            return Err(format!("Alias line without a tab in {}: {}", path, line))
+        };
+        result.push((alias.trim().to_string(), target.trim().to_string()));
+    }
+    Ok(result)
+}
+
+/// Edit distance between two labels in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
 
-    if let Ok(lines) = read_lines(file) {
-        for line in lines {
-            let line = line.expect("Corrupt file");
+/// Up to `n` labels closest to `label` by edit distance after strict normalization, closest first.
+pub fn closest_labels<'a>(label: &str, labels: impl IntoIterator<Item = &'a String>, n: usize) -> Vec<&'a str> {
+    let key = LabelNormalize::Strict.apply(label);
+    let mut scored = labels.into_iter()
+        .filter(|candidate| !candidate.is_empty())
+        .map(|candidate| (edit_distance(&key, &LabelNormalize::Strict.apply(candidate)), candidate.as_str()))
+        .collect::<Vec<(usize, &str)>>();
+    scored.sort();
+    scored.into_iter().take(n).map(|(_distance, candidate)| candidate).collect()
+}
 
-            let tokens = line.split("\t").collect::<Vec<&str>>();
-            let id: usize = tokens[1].parse().unwrap();
-            let lineage = tokens[3];
+/// Full GTDB lineages (`d__...;p__...;...;s__...`) indexed by id, empty for ids without an entry.
+pub fn get_lineages(file: impl AsRef<Path>) -> io::Result<Vec<String>> {
+    let mut id2lineage: Vec<String> = Vec::new();
+    for line in read_lines(file)? {
+        let line = strip_cr(line?);
+        let tokens = line.split('\t').collect::<Vec<&str>>();
+        let (Some(id), Some(lineage)) = (tokens.get(1).and_then(|id| id.parse::<usize>().ok()), tokens.get(3)) else { continue };
+        if id >= id2lineage.len() {
+            id2lineage.resize_with(id + 1, String::default);
+        }
+        id2lineage[id] = lineage.to_string();
+    }
+    Ok(id2lineage)
+}
 
-            let species = lineage.split(";").last().unwrap();
+/// Rank of the lowest common ancestor of two GTDB lineages, "root" if they share no rank and
+/// None if either lineage is empty.
+pub fn lca_rank(a: &str, b: &str) -> Option<&'static str> {
+    if a.is_empty() || b.is_empty() {
+        return None
+    }
+    let shared = a.split(';').zip(b.split(';')).take_while(|(a, b)| a == b).last();
+    Some(shared.map_or("root", |(rank, _)| field_rank(rank)))
+}
 
-            if id >= id2lab.len() { 
-                id2lab.resize_with(id+1, || String::default());
-            };
-            id2lab[id].push_str(species);
-            lab2id.insert(species.to_string(), id);
+/// Short hash of the (id, label) pairs of a label map, stable across runs and platforms (64 bit FNV-1a).
+/// Tables carry it in their header so they are never mixed with a map that assigns ids differently.
+pub fn map_fingerprint(id2lab: &IdLabels) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
         }
+    };
+
+    for (id, label) in id2lab.entries() {
+        feed(&id.to_le_bytes());
+        feed(label.as_bytes());
+        feed(&[0]);
     }
 
-    (id2lab, lab2id)
-}
\ No newline at end of file
+    format!("{:016x}", hash)
+}
+
+/// Header line of the fingerprint of a label map, led by `FINGERPRINT_PREFIX`.
+pub fn fingerprint_header(id2lab: &IdLabels) -> String {
+    format!("{}{}", FINGERPRINT_PREFIX, map_fingerprint(id2lab))
+}
+
+/// Fingerprint header of the label map of --map, which every table made with a map carries among
+/// its leading `#` lines. None without a map.
+pub fn args_fingerprint_header(args: &Args) -> Option<String> {
+    args.map.as_ref().map(|map| fingerprint_header(&get_labels_map(map).0))
+}
+
+/// Reads the map fingerprint from the leading `#` lines of a table, plain or compressed. None if
+/// the table has no fingerprint header or is read from stdin.
+pub fn read_map_fingerprint(path: impl AsRef<Path>) -> io::Result<Option<String>> {
+    // The table on stdin can be read only once, by its reader
+    if is_stdin(&path) {
+        return Ok(None)
+    }
+    for line in file_lines(path)? {
+        let line = line?;
+        if !line.starts_with('#') {
+            break
+        }
+        if let Some(fingerprint) = line.strip_prefix(FINGERPRINT_PREFIX) {
+            return Ok(Some(fingerprint.trim().to_string()))
+        }
+    }
+    Ok(None)
+}
+
+/// Refuses a table whose fingerprint header does not match the given map, unless `ignore` is set.
+pub fn check_map_fingerprint(path: impl AsRef<Path>, id2lab: &IdLabels, ignore: bool) -> Result<(), String> {
+    let expected = map_fingerprint(id2lab);
+    let found = read_map_fingerprint(&path).map_err(|e| format!("Cannot read {}: {}", path.as_ref().display(), e))?;
+
+    match found {
+        Some(found) if found != expected => {
+            let message = format!("Map fingerprint of {} ({}) does not match the label map ({})", path.as_ref().display(), found, expected);
+            if !ignore {
+                return Err(format!("{}, pass --ignore-map-fingerprint to proceed anyway", message))
+            }
+            eprintln!("Warning: {}", message);
+        },
+        Some(_) => (),
+        None => eprintln!("Warning: {} has no map fingerprint, cannot verify it was made with this label map", path.as_ref().display()),
+    }
+    Ok(())
+}
+
+/// Map fingerprint shared by tables that are combined or compared, None if none of them has one.
+/// Tables made with different label maps are refused unless `ignore` is set, then they share no
+/// fingerprint. Tables without a fingerprint are taken with a warning.
+pub fn common_map_fingerprint<'a>(paths: impl IntoIterator<Item = &'a str>, ignore: bool) -> Result<Option<String>, String> {
+    let mut common: Option<(&str, String)> = None;
+    for path in paths {
+        let Some(found) = read_map_fingerprint(path).map_err(|e| format!("Cannot read {}: {}", path, e))? else {
+            eprintln!("Warning: {} has no map fingerprint, cannot verify it was made with the same label map", path);
+            continue
+        };
+        match &common {
+            Some((first, expected)) if *expected != found => {
+                let message = format!("Map fingerprint of {} ({}) does not match that of {} ({})", path, found, first, expected);
+                if !ignore {
+                    return Err(format!("{}, the tables were made with different label maps (pass --ignore-map-fingerprint to proceed anyway)", message))
+                }
+                eprintln!("Warning: {}", message);
+                return Ok(None)
+            },
+            Some(_) => (),
+            None => common = Some((path, found)),
+        }
+    }
+    Ok(common.map(|(_path, fingerprint)| fingerprint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_path;
+
+    fn labels(labels: &[&str]) -> IdLabels {
+        IdLabels::Dense(labels.iter().map(|label| label.to_string()).collect())
+    }
+
+    /// A table led by the fingerprint header of `id2lab`, if given.
+    fn table(name: &str, id2lab: Option<&IdLabels>) -> String {
+        let path = test_path(name);
+        let header = id2lab.map(|id2lab| format!("{}\n", fingerprint_header(id2lab))).unwrap_or_default();
+        std::fs::write(&path, format!("{}#pairs\tdirected\tgene_base=1\n1\t2\t1\t1\n", header)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn fingerprint_depends_on_ids_and_labels() {
+        let map = labels(&["", "A", "B"]);
+        assert_eq!(map_fingerprint(&map), map_fingerprint(&labels(&["", "A", "B"])));
+        assert_ne!(map_fingerprint(&map), map_fingerprint(&labels(&["", "A", "C"])));
+        assert_ne!(map_fingerprint(&map), map_fingerprint(&labels(&["", "B", "A"])));
+        assert_ne!(map_fingerprint(&map), map_fingerprint(&labels(&["", "A"])));
+    }
+
+    #[test]
+    fn fingerprint_is_read_from_the_header_lines() {
+        let map = labels(&["", "A"]);
+        assert_eq!(read_map_fingerprint(table("fingerprint.tsv", Some(&map))).unwrap(), Some(map_fingerprint(&map)));
+        assert_eq!(read_map_fingerprint(table("no_fingerprint.tsv", None)).unwrap(), None);
+    }
+
+    #[test]
+    fn tables_of_another_map_are_refused() {
+        let (map, other) = (labels(&["", "A"]), labels(&["", "B"]));
+        let path = table("checked.tsv", Some(&map));
+        assert!(check_map_fingerprint(&path, &map, false).is_ok());
+        assert!(check_map_fingerprint(&path, &other, false).unwrap_err().contains("does not match the label map"));
+        assert!(check_map_fingerprint(&path, &other, true).is_ok());
+        assert!(check_map_fingerprint(table("unchecked.tsv", None), &other, false).is_ok());
+    }
+
+    #[test]
+    fn combined_tables_share_their_fingerprint() {
+        let (map, other) = (labels(&["", "A"]), labels(&["", "B"]));
+        let (first, second, third, bare) = (table("first.tsv", Some(&map)), table("second.tsv", Some(&map)), table("third.tsv", Some(&other)), table("bare.tsv", None));
+        assert_eq!(common_map_fingerprint([first.as_str(), bare.as_str(), second.as_str()], false), Ok(Some(map_fingerprint(&map))));
+        assert_eq!(common_map_fingerprint([bare.as_str()], false), Ok(None));
+        assert!(common_map_fingerprint([first.as_str(), third.as_str()], false).unwrap_err().contains("different label maps"));
+        assert_eq!(common_map_fingerprint([first.as_str(), third.as_str()], true), Ok(None));
+    }
+    #[test]
+    fn labels_differing_in_separators_and_case_normalize_alike() {
+        let underscore = LabelNormalize::Underscore.apply("s__Escherichia coli");
+        for label in ["s__Escherichia_coli", "s__Escherichia%20coli", " s__Escherichia coli_"] {
+            assert_eq!(LabelNormalize::Underscore.apply(label), underscore, "{:?}", label);
+            assert_eq!(LabelNormalize::Strict.apply(label), "escherichia coli", "{:?}", label);
+        }
+        assert_ne!(LabelNormalize::Underscore.apply("s__escherichia coli"), underscore);
+        assert_eq!(LabelNormalize::Strict.apply("S__ESCHERICHIA__coli"), "escherichia coli");
+        assert_eq!(LabelNormalize::None.apply("s__Escherichia_coli"), "s__Escherichia_coli");
+        // Invalid escapes are kept
+        assert_eq!(LabelNormalize::Underscore.apply("A%zz_b%2"), "A%zz b%2");
+    }
+
+    #[test]
+    fn index_counts_joins_made_by_normalization() {
+        let entries = || [("s__Escherichia coli".to_string(), 1), ("s__Bacillus subtilis".to_string(), 2)];
+        let exact = LabelIndex::new(LabelNormalize::None, entries());
+        assert_eq!(exact.get("s__Escherichia coli"), Some(1));
+        assert_eq!(exact.get("s__Escherichia_coli"), None);
+
+        let underscore = LabelIndex::new(LabelNormalize::Underscore, entries());
+        assert_eq!(underscore.get("s__Escherichia coli"), Some(1));
+        assert_eq!(underscore.normalized_joins(), 0);
+        assert_eq!(underscore.get("s__Escherichia_coli"), Some(1));
+        assert_eq!(underscore.get("s__Bacillus%20subtilis"), Some(2));
+        assert_eq!(underscore.get("s__escherichia_coli"), None);
+        assert_eq!(underscore.normalized_joins(), 2);
+
+        let strict = LabelIndex::new(LabelNormalize::Strict, entries());
+        assert_eq!(strict.get("ESCHERICHIA_COLI"), Some(1));
+        assert_eq!(strict.get("g__Bacillus  Subtilis"), Some(2));
+        assert_eq!(strict.normalized_joins(), 2);
+
+        // Labels differing only in case collide under strict, the first one is kept
+        let colliding = LabelIndex::new(LabelNormalize::Strict, [("s__A b".to_string(), 1), ("s__a_B".to_string(), 2)]);
+        assert_eq!(colliding.collisions, 1);
+        assert_eq!((colliding.get("s__a_B"), colliding.get("A B")), (Some(2), Some(1)));
+    }
+
+    fn write(name: &str, content: &str) -> String {
+        let path = test_path(name);
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn resolution(id: Option<usize>, provenance: Provenance, via: Option<&str>, conflicts: &[(Provenance, usize)]) -> Resolution {
+        Resolution { id, provenance, via: via.map(str::to_string), conflicts: conflicts.to_vec() }
+    }
+
+    #[test]
+    fn labels_resolve_through_their_first_source_with_conflicts_kept() {
+        let map = LabelMap::read(write("resolver_map.tsv", concat!(
+            "RS_GCF_1.1\t1\tx\td__B;g__Alpha;s__Alpha one\n",
+            "RS_GCF_2.1\t2\tx\td__B;g__Alpha;s__Alpha two\n",
+            "GB_GCA_3.1\t3\tx\td__B;g__Beta;s__\n",
+            "RS_GCF_4.1\t4\tx\td__B;g__Gamma;s__Gamma four\n",
+        )));
+        // Agrees with the map on genome 1, names the species of genome 3, disagrees on genome 4
+        let metadata = write("resolver_metadata.tsv", concat!(
+            "accession\tcheckm_completeness\tgtdb_taxonomy\n",
+            "RS_GCF_1.1\t99\td__B;g__Alpha;s__Alpha one\n",
+            "GB_GCA_3.1\t98\td__B;g__Beta;s__Beta three\n",
+            "RS_GCF_4.1\t97\td__B;g__Alpha;s__Alpha two\n",
+            "RS_GCF_9.1\t96\td__B;g__Delta;s__Delta nine\n",
+        ));
+        let aliases = write("resolver_aliases.tsv", "# alias\tlabel\nOld alpha\ts__Alpha one\ns__Beta old\ts__Beta three\ns__Alpha two\ts__Gamma four\ndangling\ts__Nobody\n");
+        let resolver = Resolver::load(LabelNormalize::None, &map, Some(&metadata), Some(&aliases)).unwrap();
+
+        assert_eq!(resolver.resolve("s__Alpha one"), resolution(Some(1), Provenance::PrimaryMap, None, &[]));
+        assert_eq!(resolver.resolve("s__Beta three"), resolution(Some(3), Provenance::Metadata, None, &[]));
+        assert_eq!(resolver.resolve("g__Beta_sp_3"), resolution(Some(3), Provenance::Generated, None, &[]));
+        assert_eq!(resolver.resolve("Old alpha"), resolution(Some(1), Provenance::Alias, Some("s__Alpha one"), &[]));
+        assert_eq!(resolver.resolve("s__Beta old"), resolution(Some(3), Provenance::Alias, Some("s__Beta three"), &[]));
+        // The map wins, the metadata and the alias disagreeing with it are conflicts
+        assert_eq!(resolver.resolve("s__Alpha two"), resolution(Some(2), Provenance::PrimaryMap, None, &[(Provenance::Metadata, 4), (Provenance::Alias, 4)]));
+        for label in ["dangling", "s__Delta nine", "s__alpha one"] {
+            assert_eq!(resolver.resolve(label), resolution(None, Provenance::Unresolved, None, &[]), "{}", label);
+        }
+        // Resolutions are cached, repeated lookups count but are not resolved again
+        assert_eq!(resolver.get("Old alpha"), Some(1));
+        assert_eq!(resolver.counts(), vec![(Provenance::PrimaryMap, 2), (Provenance::Metadata, 1), (Provenance::Alias, 2), (Provenance::Generated, 1), (Provenance::Unresolved, 3)]);
+
+        let mut report = Vec::new();
+        assert_eq!(resolver.write_report(&mut report).unwrap(), 4);
+        assert_eq!(String::from_utf8(report).unwrap(), concat!(
+            "#label_normalize\tnone\n#lookups\t10\n",
+            "#provenance\tprimary_map\t2\n#provenance\tmetadata\t1\n#provenance\talias\t2\n#provenance\tgenerated\t1\n#provenance\tunresolved\t3\n",
+            "label\tprovenance\tid\tvia\tconflicts\n",
+            "Old alpha\talias\t1\ts__Alpha one\tNA\n",
+            "g__Beta_sp_3\tgenerated\t3\tNA\tNA\n",
+            "s__Alpha two\tprimary_map\t2\tNA\tmetadata:4,alias:4\n",
+            "s__Beta old\talias\t3\ts__Beta three\tNA\n",
+        ));
+
+        // Normalized labels join every source
+        let strict = Resolver::load(LabelNormalize::Strict, &map, Some(&metadata), Some(&aliases)).unwrap();
+        assert_eq!(strict.resolve("ALPHA_ONE"), resolution(Some(1), Provenance::PrimaryMap, None, &[]));
+        assert_eq!(strict.resolve("beta three"), resolution(Some(3), Provenance::Metadata, None, &[]));
+        assert_eq!(strict.resolve("old_ALPHA"), resolution(Some(1), Provenance::Alias, Some("s__Alpha one"), &[]));
+        assert!(strict.normalized_joins() >= 3);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{collections::HashSet, io::{BufWriter, Write}, path::Path};
+
+use crate::{lock::DirLock, manifest::{Manifest, GENE_LEAKS_FILE, MASK_FILE, MASK_V1_FILE, NORMALIZED_FILE, PAIRWISE_FILE, TAXON_SUMMARY_FILE}, utils::{file_lines, strip_cr, SafeWriter}};
+
+/// Version of the results directory layout `analyze` writes, recorded in the manifest. Manifests
+/// without one are layout 1.
+pub const LAYOUT_VERSION: u32 = 2;
+
+/// Header naming the schema of an output, its first line from layout 2 on.
+pub const SCHEMA_PREFIX: &str = "#schema\t";
+
+/// What the manifest counts as the rows of an output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RowCount {
+    /// Lines that are not `#` headers
+    Lines,
+    /// Distinct values of the first column, e.g. the species of the several rows each of the gene
+    /// leaks report
+    Distinct,
+}
+
+/// An output of a results directory. Outputs of the same schema are the same output in every
+/// layout, which is how `migrate` tells where a file moves.
+#[derive(Debug)]
+pub struct LayoutFile {
+    pub file: &'static str,
+    pub schema: &'static str,
+    pub description: &'static str,
+    pub rows: RowCount,
+}
+
+#[derive(Debug)]
+pub struct Layout {
+    pub version: u32,
+    pub files: &'static [LayoutFile],
+}
+
+const fn output(file: &'static str, schema: &'static str, description: &'static str, rows: RowCount) -> LayoutFile {
+    LayoutFile { file, schema, description, rows }
+}
+
+/// The outputs of every layout, oldest first. `doctor` checks a directory against the layout of
+/// its manifest.
+pub const LAYOUTS: &[Layout] = &[
+    // The flat directory of the first analyze releases, outputs without a schema header
+    Layout { version: 1, files: &[
+        output("pairwise.tsv", "pairwise", "pairwise leakage per (from, to) pair and gene", RowCount::Lines),
+        output("normalized.tsv", "normalized", "incoming leakage normalized by donor outgoing totals", RowCount::Lines),
+        output("gene_leaks.tsv", "gene_leaks", "per-species per-gene correct, incoming, outgoing and donor rows", RowCount::Distinct),
+        output("mask.tsv", "mask", "proposed mask, one row per masked gene with metric, threshold, top donor and rule", RowCount::Lines),
+        output("mask.v1.tsv", "mask_v1", "proposed mask in v1 format, taxid and masked genes", RowCount::Lines),
+        output("taxon_summary.tsv", "taxon_summary", "per-taxon total, correct, outgoing and incoming read counters", RowCount::Lines),
+    ] },
+    Layout { version: 2, files: &[
+        output(PAIRWISE_FILE, "pairwise", "pairwise leakage per (from, to) pair and gene", RowCount::Lines),
+        output(NORMALIZED_FILE, "normalized", "incoming leakage normalized by donor outgoing totals", RowCount::Lines),
+        output(GENE_LEAKS_FILE, "gene_leaks", "per-species per-gene correct, incoming, outgoing and donor rows", RowCount::Distinct),
+        output(MASK_FILE, "mask", "proposed mask, one row per masked gene with metric, threshold, top donor and rule", RowCount::Lines),
+        output(MASK_V1_FILE, "mask_v1", "proposed mask in v1 format, taxid and masked genes", RowCount::Lines),
+        output(TAXON_SUMMARY_FILE, "taxon_summary", "per-taxon total, correct, outgoing and incoming read counters", RowCount::Lines),
+    ] },
+];
+
+impl Layout {
+    pub fn get(version: u32) -> Option<&'static Layout> {
+        LAYOUTS.iter().find(|layout| layout.version == version)
+    }
+
+    /// The layout `analyze` writes.
+    pub fn current() -> &'static Layout {
+        Self::get(LAYOUT_VERSION).expect("The current layout is listed")
+    }
+
+    pub fn by_file(&self, file: &str) -> Option<&'static LayoutFile> {
+        self.files.iter().find(|output| output.file == file)
+    }
+
+    pub fn by_schema(&self, schema: &str) -> Option<&'static LayoutFile> {
+        self.files.iter().find(|output| output.schema == schema)
+    }
+
+    /// Name in this layout of a file of the current layout, the name itself if it has none.
+    pub fn local<'a>(&self, current: &'a str) -> &'a str {
+        match Self::current().by_file(current).and_then(|output| self.by_schema(output.schema)) {
+            Some(output) => output.file,
+            None => current,
+        }
+    }
+
+    /// Name in the current layout of a file of this layout, the name itself if it has none.
+    pub fn upgrade<'a>(&self, file: &'a str) -> &'a str {
+        match self.by_file(file).and_then(|output| Self::current().by_schema(output.schema)) {
+            Some(output) => output.file,
+            None => file,
+        }
+    }
+}
+
+pub fn schema_header(schema: &str) -> String {
+    format!("{}{}", SCHEMA_PREFIX, schema)
+}
+
+/// Schema named by the `#schema` header among the leading `#` lines of a file, if any.
+pub fn read_schema(path: impl AsRef<Path>) -> std::io::Result<Option<String>> {
+    for line in file_lines(path)? {
+        let line = strip_cr(line?);
+        if !line.starts_with('#') {
+            break
+        }
+        if let Some(schema) = line.strip_prefix(SCHEMA_PREFIX) {
+            return Ok(Some(schema.trim().to_string()))
+        }
+    }
+    Ok(None)
+}
+
+/// Rows of an output as counted in the manifest.
+pub fn count_rows(lines: &[String], rows: RowCount) -> usize {
+    let data = lines.iter().filter(|line| !line.is_empty() && !line.starts_with('#'));
+    match rows {
+        RowCount::Lines => data.count(),
+        RowCount::Distinct => data.filter_map(|line| line.split('\t').next()).collect::<HashSet<&str>>().len(),
+    }
+}
+
+/// What `migrate` did to a results directory.
+#[derive(Debug, Default)]
+pub struct Migration {
+    pub from: u32,
+    /// Outputs moved as (old name, new name)
+    pub moved: Vec<(String, String)>,
+    /// Outputs that were given a schema header
+    pub headers_added: usize,
+    /// Outputs of the old layout that were not found
+    pub missing: Vec<String>,
+}
+
+/// Upgrades a results directory to the current layout: outputs are moved to their current names
+/// with a schema header added where missing, and a manifest is written for the result. Tool
+/// version, inputs, command and descriptions of an old manifest are kept; without one the
+/// directory counts as layout 1 and they are unknown. The old manifest is removed first, so an
+/// interrupted migration leaves a directory that no manifest vouches for. Takes the directory
+/// lock like `analyze`.
+pub fn migrate(dir: impl AsRef<Path>, max_lock_age: std::time::Duration, force_unlock: bool, atomic: bool) -> Result<Migration, String> {
+    let dir = dir.as_ref();
+    let _lock = DirLock::acquire(dir, max_lock_age, force_unlock).map_err(|e| e.to_string())?;
+    let old = match Manifest::read(dir) {
+        Ok(manifest) => Some(manifest),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.to_string()),
+    };
+    let from = old.as_ref().map_or(1, |manifest| manifest.layout_version);
+    let mut migration = Migration { from, ..Default::default() };
+    if from == LAYOUT_VERSION {
+        return Ok(migration)
+    }
+    let source = Layout::get(from).filter(|_layout| from < LAYOUT_VERSION)
+        .ok_or_else(|| format!("{} has layout {}, this build knows layouts up to {}", dir.display(), from, LAYOUT_VERSION))?;
+    if old.is_none() && source.files.iter().all(|output| !dir.join(output.file).is_file()) {
+        return Err(format!("{} has neither a manifest nor any output of layout {}", dir.display(), from))
+    }
+
+    Manifest::invalidate(dir).map_err(|e| e.to_string())?;
+    let mut manifest = Manifest::new(&old.as_ref().map(|old| old.inputs.clone()).unwrap_or_default());
+    manifest.migrated_from = Some(from);
+    if let Some(old) = &old {
+        manifest.tool_version = old.tool_version.clone();
+        manifest.command = old.command.clone();
+    } else {
+        manifest.tool_version = "unknown".to_string();
+        manifest.command = Vec::new();
+    }
+
+    for output in source.files {
+        let path = dir.join(output.file);
+        if !path.is_file() {
+            migration.missing.push(output.file.to_string());
+            continue
+        }
+        let target = Layout::current().by_schema(output.schema).ok_or_else(|| format!("Schema {} of {} has no file in layout {}", output.schema, output.file, LAYOUT_VERSION))?;
+        let lines = file_lines(&path).and_then(|lines| lines.map(|line| line.map(strip_cr)).collect::<std::io::Result<Vec<String>>>())
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        let has_header = lines.iter().take_while(|line| line.starts_with('#')).any(|line| line.starts_with(SCHEMA_PREFIX));
+
+        let target_path = dir.join(target.file);
+        if let Some(parent) = target_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("Cannot create {}: {}", parent.display(), e))?;
+        }
+        let write = || -> std::io::Result<()> {
+            let mut writer = BufWriter::new(SafeWriter::create(&target_path, atomic)?);
+            if !has_header {
+                writeln!(writer, "{}", schema_header(target.schema))?;
+            }
+            for line in &lines {
+                writeln!(writer, "{}", line)?;
+            }
+            writer.into_inner().map_err(|e| e.into_error())?.finish()
+        };
+        write().map_err(|e| format!("Cannot write {}: {}", target_path.display(), e))?;
+        if target_path != path {
+            std::fs::remove_file(&path).map_err(|e| format!("Cannot remove {}: {}", path.display(), e))?;
+            migration.moved.push((output.file.to_string(), target.file.to_string()));
+        }
+        migration.headers_added += !has_header as usize;
+
+        manifest.add_output(target.file, count_rows(&lines, target.rows));
+        if let (Some(previous), Some(entry)) = (old.as_ref().and_then(|old| old.outputs.iter().find(|entry| entry.file == output.file)), manifest.outputs.last_mut()) {
+            entry.description = previous.description.clone();
+            entry.unplaced = previous.unplaced;
+        }
+    }
+
+    manifest.record_fingerprints(dir).map_err(|e| e.to_string())?;
+    manifest.write(dir, atomic).map_err(|e| format!("Cannot write the manifest of {}: {}", dir.display(), e))?;
+    Ok(migration)
+}
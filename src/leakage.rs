@@ -1,54 +1,121 @@
-use std::{cmp::{max, min}, collections::HashMap, fmt::Display, fs::File, io::{BufRead, BufReader, Error}, iter::Map, path::Path};
+use std::{cmp::{max, min}, collections::HashMap, fmt::Display, io::{BufRead, Write}, path::Path};
 
-use phylotree::tree::NodeId;
-
-use crate::id_to_label::read_lines;
+use crate::{common::{checked_count, diff_maps, sam_input, sam_to_ids, Anomaly, AnomalyLog, Args, CountOverflow, Difference, GeneID, InputError, TaxID}, filter::{Decision, Mapq, RecordFilter}, utils::{open_file, strip_cr}};
 
 
 pub struct Leakage {
-    pub from: NodeId,
-    pub from_gene: NodeId,
-    pub to: NodeId,
-    pub to_gene: NodeId,
+    pub from: TaxID,
+    pub from_gene: GeneID,
+    pub to: TaxID,
+    pub to_gene: GeneID,
     pub correct: bool,
-    pub mapq: usize,
+    pub mapq: Mapq,
 }
 
 impl Leakage {
-    pub fn key(&self) -> (NodeId, NodeId) {
-        return (min(self.from, self.to), max(self.from, self.to))
+    pub fn key(&self) -> (TaxID, TaxID) {
+        (min(self.from, self.to), max(self.from, self.to))
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct LeakageCounter {
-    pub total: usize,
-    pub correct: usize,
-    pub out_incorrect: usize,
-    pub in_incorrect: usize,
+    pub total: u64,
+    pub correct: u64,
+    pub out_incorrect: u64,
+    pub in_incorrect: u64,
+    /// Unmapped reads of this taxon when counted (--count-unmapped), written as a last column
+    pub unmapped: Option<u64>,
+}
+
+impl LeakageCounter {
+    /// Fraction of reads from this taxon that were assigned correctly, None if there are no reads.
+    pub fn correct_frac(&self) -> Option<f64> {
+        self.frac(self.correct)
+    }
+
+    /// Fraction of reads from this taxon that leaked into other taxa, None if there are no reads.
+    pub fn out_frac(&self) -> Option<f64> {
+        self.frac(self.out_incorrect)
+    }
+
+    /// Incoming leaked reads relative to the reads of this taxon, None if there are no reads.
+    pub fn in_frac(&self) -> Option<f64> {
+        self.frac(self.in_incorrect)
+    }
+
+    fn frac(&self, count: u64) -> Option<f64> {
+        if self.total == 0 {
+            return None
+        }
+        Some(count as f64 / self.total as f64)
+    }
+
+    /// Adds the counters of another run, an error instead of wrapping if one overflows.
+    pub fn merge(&mut self, other: &Self) -> Result<(), CountOverflow> {
+        self.total = checked_count(self.total, other.total, "total")?;
+        self.correct = checked_count(self.correct, other.correct, "correct")?;
+        self.out_incorrect = checked_count(self.out_incorrect, other.out_incorrect, "out_incorrect")?;
+        self.in_incorrect = checked_count(self.in_incorrect, other.in_incorrect, "in_incorrect")?;
+        if let Some(unmapped) = other.unmapped {
+            self.unmapped = Some(checked_count(self.unmapped.unwrap_or(0), unmapped, "unmapped")?);
+        }
+        Ok(())
+    }
+
+    pub fn diff(&self, other: &Self, key: impl Display) -> Vec<Difference> {
+        let key = key.to_string();
+        [
+            Difference::exact(&key, "total", self.total, other.total),
+            Difference::exact(&key, "correct", self.correct, other.correct),
+            Difference::exact(&key, "out_incorrect", self.out_incorrect, other.out_incorrect),
+            Difference::exact(&key, "in_incorrect", self.in_incorrect, other.in_incorrect),
+            Difference::exact(&key, "unmapped", self.unmapped.unwrap_or(0), other.unmapped.unwrap_or(0)),
+        ].into_iter().flatten().collect()
+    }
+}
+
+/// Order-insensitive diff of two per-taxon counter maps.
+pub fn diff_leakage_counters(left: &HashMap<TaxID, LeakageCounter>, right: &HashMap<TaxID, LeakageCounter>) -> Vec<Difference> {
+    diff_maps(left, right, "taxon", |counter| counter.to_string(), |id, l, r, result| {
+        result.extend(l.diff(r, id));
+    })
+}
+
+fn fmt_frac(frac: Option<f64>) -> String {
+    match frac {
+        Some(frac) => frac.to_string(),
+        None => "NA".to_string(),
+    }
 }
 
 impl Display for LeakageCounter {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}\t{}\t{}\t{}\t{}\t{}\t{}",
             self.total, 
-            self.correct, self.correct as f64 / self.total as f64,
-            self.out_incorrect, self.out_incorrect as f64 / self.total as f64,
-            self.in_incorrect, self.in_incorrect as f64 / self.total as f64)
+            self.correct, fmt_frac(self.correct_frac()),
+            self.out_incorrect, fmt_frac(self.out_frac()),
+            self.in_incorrect, fmt_frac(self.in_frac()))?;
+        if let Some(unmapped) = self.unmapped {
+            write!(f, "\t{}", unmapped)?;
+        }
+        Ok(())
     }
 }
 
 
 // The output is wrapped in a Result to allow matching on errors.
 // Returns an Iterator to the Reader of the lines of the file.
-pub fn read_leakage_records<P>(filename: P) -> Map<std::io::Lines<BufReader<File>>, impl FnMut(Result<String, Error>) -> Leakage>
+// Header lines starting with '#' (e.g. the map fingerprint) are skipped.
+pub fn read_leakage_records<P>(filename: P) -> std::io::Result<impl Iterator<Item = Leakage>>
 where P: AsRef<Path>, {
-    let file = File::open(filename).unwrap();
+    let file = open_file(filename)?;
 
-    std::io::BufReader::new(file)
+    Ok(std::io::BufReader::new(file)
         .lines()
+        .map(|line| strip_cr(line.expect("Corrupt file")))
+        .filter(|line| !line.starts_with('#'))
         .map(|line| {
-            let line = line.expect("Corrupt file");
 
             let tokens = line.split("\t").collect::<Vec<&str>>();
             let from_tokens = tokens[1].split("_").collect::<Vec<&str>>();
@@ -69,25 +136,67 @@ where P: AsRef<Path>, {
                 correct,
                 mapq,
             }
-        }).into_iter()
+        }))
+}
+
+
+/// Writes (read id, record) records as lines of a per-read leakage file (read id, from
+/// taxid_gene, to taxid_gene, correct, mapq), as `read_leakage_records` reads them. Returns the
+/// number of lines written.
+pub fn write_leakage_records<'a>(records: impl IntoIterator<Item = (&'a str, Leakage)>, writer: &mut impl Write) -> std::io::Result<usize> {
+    let mut lines = 0;
+    for (read, l) in records {
+        writeln!(writer, "{}\t{}_{}\t{}_{}\t{}\t{}", read, l.from, l.from_gene, l.to, l.to_gene, l.correct, l.mapq)?;
+        lines += 1;
+    }
+    Ok(lines)
 }
 
+/// Writes the records of the input kept by the record filters as a per-read leakage file, see
+/// `write_leakage_records`. Returns the number of records written.
+pub fn leakage_records_from_sam(args: &Args, anomalies: &mut AnomalyLog, writer: &mut impl Write) -> Result<usize, InputError> {
+    let mut iter = sam_input(args)?;
+    anomalies.set_input(&args.input_label());
+    let mut filter = RecordFilter::from_args(args);
+    let mut lines = 0;
+
+    let mut line = String::new();
+    while let Some(sam) = iter.next_valid_ref(&mut line, anomalies)? {
+        if filter.evaluate(&sam) != Decision::Keep {continue};
+        let fromto = match sam_to_ids(&sam, &filter.bounds, &mut filter.names) {
+            Ok(fromto) => fromto,
+            Err(e) => {
+                anomalies.record(Anomaly::UnparseableName, &e, Some(iter.line))?;
+                continue
+            },
+        };
+        if filter.evaluate_pair(&fromto) != Decision::Keep {continue};
+        let (from, from_gene, to, to_gene) = (fromto.query as TaxID, fromto.query_gene as GeneID, fromto.reference as TaxID, fromto.reference_gene as GeneID);
+        let record = Leakage { from, from_gene, to, to_gene, correct: from == to && from_gene == to_gene, mapq: sam.mapq };
+        lines += write_leakage_records([(sam.qname, record)], writer)?;
+    }
+    eprintln!("Records: {}", filter);
+    filter.check_gene_ids(anomalies)?;
+    Ok(lines)
+}
 
-pub fn read_leakage_file(path: impl AsRef<Path>) -> Vec<Leakage> {
+pub fn read_leakage_file(path: impl AsRef<Path>) -> std::io::Result<Vec<Leakage>> {
     let mut result = Vec::default();
-    let records = read_leakage_records(path);
+    let records = read_leakage_records(path)?;
 
     for record in records {
         result.push(record);
     }
-    result
+    Ok(result)
 }
 
-pub fn read_leakage_counter(path: impl AsRef<Path>) -> HashMap<NodeId, LeakageCounter> {
+/// Per-taxon counters of the records passing `filter`, which counts them once.
+pub fn read_leakage_counter(path: impl AsRef<Path>, filter: &mut RecordFilter) -> std::io::Result<HashMap<TaxID, LeakageCounter>> {
     let mut map = HashMap::new();
 
-    let records = read_leakage_records(&path);
+    let records = read_leakage_records(&path)?;
     for l in records {
+        if filter.evaluate_record(&l) != Decision::Keep {continue};
         let from = map.entry(l.from).or_insert( LeakageCounter::default() );
 
         match l.correct {
@@ -102,17 +211,17 @@ pub fn read_leakage_counter(path: impl AsRef<Path>) -> HashMap<NodeId, LeakageCo
         }
     }
 
-    let records = read_leakage_records(path);
+    let records = read_leakage_records(path)?;
     for l in records {
-        if l.correct {continue};
+        if l.correct || filter.check_record(&l) != Decision::Keep {continue};
         let to = map.entry(l.to).or_insert( LeakageCounter::default() );
         to.in_incorrect += 1;
     }
 
-    map
+    Ok(map)
 }
 
-pub fn get_leakage_counter(leakage: &[Leakage]) -> HashMap<NodeId, LeakageCounter> {
+pub fn get_leakage_counter(leakage: &[Leakage]) -> HashMap<TaxID, LeakageCounter> {
     let mut map = HashMap::new();
 
     for l in leakage {
@@ -137,4 +246,57 @@ pub fn get_leakage_counter(leakage: &[Leakage]) -> HashMap<NodeId, LeakageCounte
     }
 
     map
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(total: u64, correct: u64, out_incorrect: u64, in_incorrect: u64) -> LeakageCounter {
+        LeakageCounter { total, correct, out_incorrect, in_incorrect, unmapped: None }
+    }
+
+    #[test]
+    fn zero_total_has_no_rates() {
+        let empty = counter(0, 0, 0, 3);
+        assert_eq!(empty.correct_frac(), None);
+        assert_eq!(empty.out_frac(), None);
+        assert_eq!(empty.in_frac(), None);
+        assert_eq!(empty.to_string(), "0\t0\tNA\t0\tNA\t3\tNA");
+    }
+
+    #[test]
+    fn rates_relative_to_total() {
+        let c = counter(4, 3, 1, 2);
+        assert_eq!(c.correct_frac(), Some(0.75));
+        assert_eq!(c.out_frac(), Some(0.25));
+        assert_eq!(c.in_frac(), Some(0.5));
+        assert_eq!(c.to_string(), "4\t3\t0.75\t1\t0.25\t2\t0.5");
+    }
+
+    #[test]
+    fn merge_adds_every_counter() {
+        let mut c = counter(4, 3, 1, 2);
+        c.merge(&LeakageCounter { unmapped: Some(5), ..counter(6, 2, 4, 1) }).unwrap();
+        assert_eq!(c, LeakageCounter { unmapped: Some(5), ..counter(10, 5, 5, 3) });
+        c.merge(&counter(1, 1, 0, 0)).unwrap();
+        assert_eq!(c, LeakageCounter { unmapped: Some(5), ..counter(11, 6, 5, 3) });
+    }
+
+    #[test]
+    fn counter_maps_diff_by_taxon_and_field() {
+        let left = HashMap::from([(1, counter(4, 3, 1, 2)), (2, counter(1, 1, 0, 0))]);
+        let right = HashMap::from([(1, counter(4, 2, 2, 2)), (3, counter(2, 2, 0, 0))]);
+        assert_eq!(diff_leakage_counters(&left, &right), vec![
+            Difference::new(1, "correct", Some("3".to_string()), Some("2".to_string())),
+            Difference::new(1, "out_incorrect", Some("1".to_string()), Some("2".to_string())),
+            Difference::new(2, "taxon", Some(counter(1, 1, 0, 0).to_string()), None),
+            Difference::new(3, "taxon", None, Some(counter(2, 2, 0, 0).to_string())),
+        ]);
+    }
+
+    #[test]
+    fn merge_refuses_overflow() {
+        let mut c = counter(u64::MAX, 0, 0, 0);
+        assert!(c.merge(&counter(1, 0, 0, 0)).is_err());
+    }
+}
@@ -2,6 +2,35 @@
 #![feature(trait_alias)]
 #![feature(iter_collect_into)]
 
+pub mod analysis;
 pub mod common;
+pub mod defaults;
+pub mod doctor;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+pub mod gene_leaks;
+pub mod id_to_label;
+pub mod layout;
+pub mod leakage;
+pub mod lock;
+pub mod manifest;
+pub mod mask_import;
 pub mod pairwise_leakage;
+pub mod placement;
+pub mod prescreen;
+pub mod query;
+pub mod reconcile;
+pub mod reference;
+pub mod samples;
+pub mod schema;
+pub mod simulate;
+pub mod taxonomy;
+pub mod timing;
+pub mod tracks;
+#[cfg(feature = "tree")]
+pub mod tree;
+#[cfg(not(feature = "tree"))]
+#[path = "no_tree.rs"]
+pub mod tree;
 pub mod utils;
\ No newline at end of file
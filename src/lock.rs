@@ -0,0 +1,207 @@
+use std::{fmt::Display, fs::OpenOptions, io::Write, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use thiserror::Error;
+
+/// Lock file of a directory some run is writing into.
+pub const LOCK_FILE: &str = ".fix_gtdb_mg.lock";
+
+/// Default age after which a lock of another host is taken over as left behind by a run that died.
+pub const DEFAULT_LOCK_MAX_AGE_HOURS: u64 = 48;
+
+/// Who holds a lock, as written into the lock file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+    /// Seconds since the epoch
+    pub started: u64,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        Self { pid: std::process::id(), host: hostname(), started: now() }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let field = |key: &str| text.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix('\t')).map(str::trim);
+        Some(Self { pid: field("pid")?.parse().ok()?, host: field("host")?.to_string(), started: field("started")?.parse().ok()? })
+    }
+
+    fn age(&self, now: u64) -> Duration {
+        Duration::from_secs(now.saturating_sub(self.started))
+    }
+}
+
+impl Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pid\t{}\nhost\t{}\nstarted\t{}", self.pid, self.host, self.started)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LockError {
+    #[error("{path} is held by pid {pid} on {host} for {age_hours:.1} hours, another run writes into the directory (wait for it, or pass --force-unlock if it died)", pid = .owner.pid, host = .owner.host)]
+    Held { path: String, owner: LockOwner, age_hours: f64 },
+    #[error("Cannot lock {path}: {message}")]
+    Io { path: String, message: String },
+}
+
+/// Why a lock found in place may be taken over.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Takeover {
+    Forced,
+    /// Older than the maximum age, held on another host or by an unknown owner
+    Expired,
+    /// Held by a process of this host that is gone
+    DeadOwner,
+}
+
+/// Whether a lock held by `owner` may be taken over `now` by a run on `host`: with `force`, on
+/// the same host if `alive(pid)` says its process is gone, or past `max_age`. A process of this
+/// host that `alive` finds running keeps its lock however old; only locks of other hosts, unknown
+/// owners or processes `alive` cannot tell about (None) expire.
+pub fn takeover(owner: &LockOwner, host: &str, now: u64, max_age: Duration, force: bool, alive: impl Fn(u32) -> Option<bool>) -> Option<Takeover> {
+    if force {
+        return Some(Takeover::Forced)
+    }
+    if owner.host == host && owner.host != UNKNOWN_HOST {
+        match alive(owner.pid) {
+            Some(true) => return None,
+            Some(false) => return Some(Takeover::DeadOwner),
+            None => (),
+        }
+    }
+    (owner.age(now) > max_age).then_some(Takeover::Expired)
+}
+
+/// Exclusive claim on a directory, so that two runs do not interleave their outputs in it. The
+/// lock file is removed when the guard is dropped, also when unwinding from a panic; a run that
+/// exits without unwinding leaves it for the next run to take over as stale.
+#[derive(Debug)]
+pub struct DirLock {
+    path: PathBuf,
+}
+
+impl DirLock {
+    /// Creates the lock file of `dir`, taking over a lock in place if `takeover` allows it.
+    pub fn acquire(dir: impl AsRef<Path>, max_age: Duration, force: bool) -> Result<Self, LockError> {
+        let path = dir.as_ref().join(LOCK_FILE);
+        let io_error = |e: std::io::Error| LockError::Io { path: path.display().to_string(), message: e.to_string() };
+        let owner = LockOwner::current();
+        // One retry: a lock taken over can be claimed by another run between removal and creation
+        for _attempt in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    writeln!(file, "{}", owner).map_err(io_error)?;
+                    return Ok(Self { path })
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(io_error(e)),
+            }
+            let held = held_by(&path).map_err(io_error)?;
+            let Some(reason) = takeover(&held, &owner.host, owner.started, max_age, force, process_alive) else {
+                return Err(LockError::Held { path: path.display().to_string(), age_hours: held.age(owner.started).as_secs_f64() / 3600.0, owner: held })
+            };
+            eprintln!("Warning: taking over {} of pid {} on {} ({:?})", path.display(), held.pid, held.host, reason);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(io_error(e)),
+                _ => (),
+            }
+        }
+        let held = held_by(&path).map_err(io_error)?;
+        Err(LockError::Held { path: path.display().to_string(), age_hours: held.age(owner.started).as_secs_f64() / 3600.0, owner: held })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            eprintln!("Warning: cannot remove lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Owner of an existing lock file. A file that cannot be parsed (e.g. caught half written) is
+/// dated by its modification time with an unknown owner.
+fn held_by(path: &Path) -> std::io::Result<LockOwner> {
+    let text = std::fs::read_to_string(path)?;
+    if let Some(owner) = LockOwner::parse(&text) {
+        return Ok(owner)
+    }
+    let modified = std::fs::metadata(path)?.modified()?;
+    Ok(LockOwner { pid: 0, host: UNKNOWN_HOST.to_string(), started: modified.duration_since(UNIX_EPOCH).map_or(0, |age| age.as_secs()) })
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+/// Host of a lock whose owner cannot be told, never taken for this host.
+const UNKNOWN_HOST: &str = "unknown";
+
+/// Name of this host, `UNKNOWN_HOST` where it cannot be read.
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname").ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| UNKNOWN_HOST.to_string())
+}
+
+/// Whether a process of this host is running, None without /proc where it cannot be told,
+/// leaving such locks to expire by age.
+fn process_alive(pid: u32) -> Option<bool> {
+    let proc = Path::new("/proc");
+    proc.join("self").exists().then(|| proc.join(pid.to_string()).exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+
+    fn owner(host: &str, started: u64) -> LockOwner {
+        LockOwner { pid: 42, host: host.to_string(), started }
+    }
+
+    #[test]
+    fn locks_of_this_host_follow_their_process() {
+        let max_age = Duration::from_secs(48 * HOUR);
+        let (fresh, old) = (owner("node1", 100 * HOUR), owner("node1", HOUR));
+        let now = 101 * HOUR;
+        assert_eq!(takeover(&fresh, "node1", now, max_age, false, |_pid| Some(true)), None);
+        assert_eq!(takeover(&fresh, "node1", now, max_age, false, |_pid| Some(false)), Some(Takeover::DeadOwner));
+        // A running process keeps its lock past the maximum age
+        assert_eq!(takeover(&old, "node1", now, max_age, false, |_pid| Some(true)), None);
+        assert_eq!(takeover(&old, "node1", now, max_age, false, |_pid| Some(false)), Some(Takeover::DeadOwner));
+        // Where processes cannot be told about, locks expire by age
+        assert_eq!(takeover(&fresh, "node1", now, max_age, false, |_pid| None), None);
+        assert_eq!(takeover(&old, "node1", now, max_age, false, |_pid| None), Some(Takeover::Expired));
+        assert_eq!(takeover(&old, "node1", now, max_age, true, |_pid| Some(true)), Some(Takeover::Forced));
+    }
+
+    #[test]
+    fn locks_of_other_hosts_and_unknown_owners_expire() {
+        let max_age = Duration::from_secs(48 * HOUR);
+        let now = 101 * HOUR;
+        let asked = |_pid: u32| -> Option<bool> { panic!("Processes of other hosts cannot be asked about") };
+        assert_eq!(takeover(&owner("node2", 100 * HOUR), "node1", now, max_age, false, asked), None);
+        assert_eq!(takeover(&owner("node2", HOUR), "node1", now, max_age, false, asked), Some(Takeover::Expired));
+        assert_eq!(takeover(&owner("node2", 100 * HOUR), "node1", now, max_age, true, asked), Some(Takeover::Forced));
+        // A host that cannot be read is not this host, even where this host cannot be read either
+        assert_eq!(takeover(&owner(UNKNOWN_HOST, 100 * HOUR), UNKNOWN_HOST, now, max_age, false, asked), None);
+        assert_eq!(takeover(&owner(UNKNOWN_HOST, HOUR), UNKNOWN_HOST, now, max_age, false, asked), Some(Takeover::Expired));
+    }
+
+    #[test]
+    fn owners_read_back_as_written() {
+        let owner = owner("node1", 1234);
+        assert_eq!(LockOwner::parse(&owner.to_string()), Some(owner));
+        assert_eq!(LockOwner::parse("pid\t42\nhost\tnode1\n"), None);
+    }
+}
@@ -0,0 +1,266 @@
+use std::{io::{BufWriter, Read, Write}, path::Path};
+
+use crate::{layout::{Layout, LAYOUT_VERSION}, timing::Breakdown, utils::SafeWriter};
+
+// Outputs of the current layout, see `layout::LAYOUTS` for those of earlier ones
+pub const PAIRWISE_FILE: &str = "tables/pairwise.tsv";
+pub const NORMALIZED_FILE: &str = "tables/normalized.tsv";
+pub const GENE_LEAKS_FILE: &str = "genes/gene_leaks.tsv";
+pub const MASK_FILE: &str = "mask/mask.tsv";
+pub const MASK_V1_FILE: &str = "mask/mask.v1.tsv";
+pub const TAXON_SUMMARY_FILE: &str = "tables/taxon_summary.tsv";
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// One file written into a results directory.
+#[derive(Debug, Clone)]
+pub struct OutputEntry {
+    pub file: String,
+    /// Schema id of the output in its layout, None in layout 1 manifests
+    pub schema: Option<String>,
+    pub description: String,
+    /// Rows involving taxa that could not be placed in the label map or tree, if checked.
+    pub unplaced: Option<usize>,
+    /// Fingerprint of the file's bytes as written, None in manifests that predate fingerprints
+    pub fingerprint: Option<String>,
+    pub rows: usize,
+}
+
+/// Describes the contents of a results directory, written as `manifest.json`. What differs
+/// between runs on the same input (command line, timings) is kept to the single `"run"` line, so
+/// that runs can be compared with that line left out.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    /// Layout of the directory, 1 for manifests written before layouts were versioned
+    pub layout_version: u32,
+    /// Layout a migrated directory was upgraded from (see `layout::migrate`), whose tool version
+    /// and inputs may be unknown
+    pub migrated_from: Option<u32>,
+    pub tool_version: String,
+    pub command: Vec<String>,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<OutputEntry>,
+    /// Run-wide counts of unplaced taxa, pairs and reads as (name, count), empty if not checked.
+    pub unplaced: Vec<(String, u64)>,
+    /// Pairs and summed reads per bucket of pair totals as (bucket, pairs, reads), empty if not
+    /// computed.
+    pub pair_totals: Vec<(String, usize, u64)>,
+    /// Time per phase of the run, with --timing.
+    pub timing: Option<Breakdown>,
+}
+
+/// FNV-1a hash of the bytes of a file, as a hex string. Compressed outputs are hashed as stored.
+pub fn content_fingerprint(path: impl AsRef<Path>) -> std::io::Result<String> {
+    let path = path.as_ref();
+    let mut file = std::fs::File::open(path).map_err(|e| std::io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e)))?;
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 { break };
+        for byte in &buffer[..read] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+/// Quotes and escapes a string as a JSON string literal.
+pub fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Parses the JSON string literal at the start of `s`, returning it and the rest of `s`.
+fn parse_json_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut result = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((result, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => result.push('\n'),
+                'r' => result.push('\r'),
+                't' => result.push('\t'),
+                'u' => {
+                    let hex = (0..4).map(|_| chars.next().map(|(_, c)| c)).collect::<Option<String>>()?;
+                    result.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                },
+                c => result.push(c),
+            },
+            c => result.push(c),
+        }
+    }
+    None
+}
+
+/// Value of `"key": <string>` in `s`.
+fn json_field_string(s: &str, key: &str) -> Option<String> {
+    let start = s.find(&format!("\"{}\": ", key))? + key.len() + 4;
+    parse_json_string(&s[start..]).map(|(value, _rest)| value)
+}
+
+/// Value of `"key": <number>` in `s`.
+fn json_field_number(s: &str, key: &str) -> Option<u64> {
+    let start = s.find(&format!("\"{}\": ", key))? + key.len() + 4;
+    let digits = s[start..].split(|c: char| !c.is_ascii_digit()).next()?;
+    digits.parse().ok()
+}
+
+/// Value of `"key": [<string>, ...]` in `s`.
+fn json_field_list(s: &str, key: &str) -> Option<Vec<String>> {
+    let start = s.find(&format!("\"{}\": [", key))? + key.len() + 5;
+    let mut rest = s[start..].trim_start();
+    let mut result = Vec::new();
+    while !rest.starts_with(']') {
+        let (value, tail) = parse_json_string(rest)?;
+        result.push(value);
+        rest = tail.trim_start().strip_prefix(',').unwrap_or(tail).trim_start();
+    }
+    Some(result)
+}
+
+fn json_string_list(list: &[String]) -> String {
+    format!("[{}]", itertools::join(list.iter().map(|s| json_string(s)), ", "))
+}
+
+impl Manifest {
+    pub fn new(inputs: &[String]) -> Self {
+        Self {
+            layout_version: LAYOUT_VERSION,
+            migrated_from: None,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            command: std::env::args().collect(),
+            inputs: inputs.to_vec(),
+            outputs: Vec::new(),
+            unplaced: Vec::new(),
+            pair_totals: Vec::new(),
+            timing: None,
+        }
+    }
+
+    /// Adds an output of the current layout, described by its entry there.
+    pub fn add_output(&mut self, file: &str, rows: usize) {
+        let output = Layout::current().by_file(file);
+        self.outputs.push(OutputEntry {
+            file: file.to_string(),
+            schema: output.map(|output| output.schema.to_string()),
+            description: output.map_or(String::new(), |output| output.description.to_string()),
+            unplaced: None,
+            fingerprint: None,
+            rows,
+        });
+    }
+
+    /// Fingerprints the files of all outputs in `dir`, once they are written.
+    pub fn record_fingerprints(&mut self, dir: impl AsRef<Path>) -> std::io::Result<()> {
+        for output in &mut self.outputs {
+            output.fingerprint = Some(content_fingerprint(dir.as_ref().join(&output.file))?);
+        }
+        Ok(())
+    }
+
+    /// Records the number of rows of an output added before that involve unplaced taxa.
+    pub fn set_unplaced(&mut self, file: &str, rows: usize) {
+        if let Some(output) = self.outputs.iter_mut().find(|output| output.file == file) {
+            output.unplaced = Some(rows);
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let outputs = self.outputs.iter().map(|o| {
+            let unplaced = o.unplaced.map_or(String::new(), |rows| format!("\"unplaced\": {}, ", rows));
+            let schema = o.schema.as_ref().map_or(String::new(), |schema| format!("\"schema\": {}, ", json_string(schema)));
+            let fingerprint = o.fingerprint.as_ref().map_or(String::new(), |fingerprint| format!("\"fingerprint\": {}, ", json_string(fingerprint)));
+            format!("    {{\"file\": {}, {}\"description\": {}, {}{}\"rows\": {}}}", json_string(&o.file), schema, json_string(&o.description), unplaced, fingerprint, o.rows)
+        });
+        let unplaced = match self.unplaced.is_empty() {
+            true => String::new(),
+            false => format!(",\n  \"unplaced\": {{{}}}", itertools::join(self.unplaced.iter().map(|(name, count)| format!("{}: {}", json_string(name), count)), ", ")),
+        };
+
+        let pair_totals = match self.pair_totals.is_empty() {
+            true => String::new(),
+            false => format!(",\n  \"pair_totals\": {{{}}}", itertools::join(self.pair_totals.iter().map(|(bucket, pairs, reads)| format!("{}: {{\"pairs\": {}, \"reads\": {}}}", json_string(bucket), pairs, reads)), ", ")),
+        };
+
+        let timing = self.timing.as_ref().map_or(String::new(), |timing| {
+            let phases = timing.phases.iter().map(|(name, duration)| format!("{}: {{\"ns\": {}, \"percent\": {:.1}}}", json_string(name), duration.as_nanos(), timing.percent(*duration)));
+            format!(", \"timing\": {{\"wall_ns\": {}, \"phases\": {{{}}}}}", timing.wall.as_nanos(), itertools::join(phases, ", "))
+        });
+
+        let migrated = self.migrated_from.map_or(String::new(), |from| format!("\n  \"migrated_from_layout\": {},", from));
+
+        format!("{{\n  \"layout_version\": {},{}\n  \"tool_version\": {},\n  \"inputs\": {},\n  \"outputs\": [\n{}\n  ]{}{},\n  \"run\": {{\"command\": {}{}}}\n}}\n",
+            self.layout_version,
+            migrated,
+            json_string(&self.tool_version),
+            json_string_list(&self.inputs),
+            itertools::join(outputs, ",\n"),
+            unplaced,
+            pair_totals,
+            json_string_list(&self.command),
+            timing)
+    }
+
+    /// Reads the layout version, tool version, inputs, command and outputs of a manifest written by
+    /// `write`. Only this layout (one output object per line) is understood, it is not a general
+    /// JSON parser.
+    pub fn read(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = dir.as_ref().join(MANIFEST_FILE);
+        let json = std::fs::read_to_string(&path).map_err(|e| std::io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e)))?;
+        let invalid = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path.display(), what));
+
+        let mut manifest = Self {
+            layout_version: json_field_number(&json, "layout_version").map_or(1, |version| version as u32),
+            migrated_from: json_field_number(&json, "migrated_from_layout").map(|version| version as u32),
+            tool_version: json_field_string(&json, "tool_version").ok_or_else(|| invalid("no tool_version"))?,
+            inputs: json_field_list(&json, "inputs").unwrap_or_default(),
+            command: json_field_list(&json, "command").unwrap_or_default(),
+            ..Default::default()
+        };
+        for line in json.lines().filter(|line| line.trim_start().starts_with("{\"file\"")) {
+            let rows = line.rsplit_once("\"rows\": ")
+                .and_then(|(_, rows)| rows.trim_end_matches(|c: char| c == '}' || c == ',' || c.is_whitespace()).parse().ok())
+                .ok_or_else(|| invalid("output without rows"))?;
+            manifest.outputs.push(OutputEntry {
+                file: json_field_string(line, "file").ok_or_else(|| invalid("output without file"))?,
+                schema: json_field_string(line, "schema"),
+                description: json_field_string(line, "description").unwrap_or_default(),
+                unplaced: line.split_once("\"unplaced\": ").and_then(|(_, rest)| rest.split(',').next()?.trim().parse().ok()),
+                fingerprint: json_field_string(line, "fingerprint"),
+                rows,
+            });
+        }
+        Ok(manifest)
+    }
+
+    /// Written after every output of the run, so its presence certifies a complete directory.
+    pub fn write(&self, dir: impl AsRef<Path>, atomic: bool) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(SafeWriter::create(dir.as_ref().join(MANIFEST_FILE), atomic)?);
+        writer.write_all(self.to_json().as_bytes())?;
+        writer.into_inner().map_err(|e| e.into_error())?.finish()
+    }
+
+    /// Removes the manifest of an earlier run from `dir`, if any.
+    pub fn invalidate(dir: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = dir.as_ref().join(MANIFEST_FILE);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(std::io::Error::new(e.kind(), format!("Cannot remove {}: {}", path.display(), e))),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::{collections::{HashMap, HashSet}, io::Write, path::Path};
+
+use crate::{common::{GeneID, TaxID}, gene_leaks::{MaskEntry, MASK_FORMAT_PREFIX}, reference::{ReferenceKeys, REFERENCE_FINGERPRINT_PREFIX}, utils::{file_lines, strip_cr}};
+
+/// A row of a curated v2 mask: the mask columns and any columns a curator appended (e.g. a
+/// comment), kept verbatim.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CuratedRow {
+    /// 1-based line in the file
+    pub line: usize,
+    pub entry: MaskEntry,
+    pub extra: Vec<String>,
+    /// The row as found
+    pub raw: String,
+}
+
+/// A row left out of an import and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reject {
+    pub line: usize,
+    pub reason: String,
+    pub row: String,
+}
+
+/// A v2 mask as edited by curators. Rows that cannot be parsed are rejected instead of failing
+/// the whole file.
+#[derive(Debug, Clone, Default)]
+pub struct CuratedMask {
+    pub reference: Option<String>,
+    /// Names of the columns after the mask columns, from the column header
+    pub extra_columns: Vec<String>,
+    pub rows: Vec<CuratedRow>,
+    pub rejects: Vec<Reject>,
+}
+
+impl CuratedMask {
+    /// Reads a v2 mask with optional extra columns. Fails only on an unreadable file or a mask
+    /// format other than 2.
+    pub fn read(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+        let mut result = Self::default();
+        let mut format = None;
+        for (index, line) in file_lines(path)?.enumerate() {
+            let line = strip_cr(line?);
+            if let Some(version) = line.strip_prefix(MASK_FORMAT_PREFIX) {
+                format = Some(version.trim().to_string());
+                continue
+            }
+            if let Some(fingerprint) = line.strip_prefix(REFERENCE_FINGERPRINT_PREFIX) {
+                result.reference = Some(fingerprint.trim().to_string());
+                continue
+            }
+            if let Some(header) = line.strip_prefix('#') {
+                result.extra_columns = header.split('\t').skip(MaskEntry::COLUMNS.len()).map(str::to_string).collect();
+                continue
+            }
+            if line.trim().is_empty() { continue };
+            let tokens = line.split('\t').collect::<Vec<&str>>();
+            if tokens.len() < MaskEntry::COLUMNS.len() {
+                result.reject(index + 1, format!("expected at least {} columns, found {}", MaskEntry::COLUMNS.len(), tokens.len()), &line);
+                continue
+            }
+            let (columns, extra) = tokens.split_at(MaskEntry::COLUMNS.len());
+            match MaskEntry::parse(&columns.join("\t")) {
+                Ok(entry) => result.rows.push(CuratedRow { line: index + 1, entry, extra: extra.iter().map(|token| token.to_string()).collect(), raw: line.clone() }),
+                Err(e) => result.reject(index + 1, e, &line),
+            }
+        }
+        match format.as_deref() {
+            Some("2") => Ok(result),
+            Some(format) => Err(invalid(format!("Unknown mask format '{}', curated masks must be v2", format))),
+            None => Err(invalid("No mask format header, curated masks must be v2".to_string())),
+        }
+    }
+
+    fn reject(&mut self, line: usize, reason: String, row: &str) {
+        self.rejects.push(Reject { line, reason, row: row.to_string() });
+    }
+
+    /// Moves rows failing validation to the rejects: genes outside `genes` (the panel, if
+    /// known), keys absent from `keys` (the reference, if known) and repeated (taxon, gene) keys,
+    /// of which the first row is kept.
+    pub fn validate(&mut self, genes: Option<&HashSet<GeneID>>, keys: Option<&ReferenceKeys>) {
+        let mut seen: HashMap<(TaxID, GeneID), usize> = HashMap::new();
+        let rows = std::mem::take(&mut self.rows);
+        for row in rows {
+            let key = (row.entry.taxon, row.entry.gene);
+            let reason = match seen.get(&key) {
+                Some(first) => Some(format!("duplicate of line {}", first)),
+                None if genes.is_some_and(|genes| !genes.contains(&key.1)) => Some(format!("gene {} is not in the panel", key.1)),
+                None if keys.is_some_and(|keys| !keys.contains(&key)) => Some(format!("{}_{} is not in the reference", key.0, key.1)),
+                None => None,
+            };
+            match reason {
+                Some(reason) => self.reject(row.line, reason, &row.raw),
+                None => {
+                    seen.insert(key, row.line);
+                    self.rows.push(row);
+                },
+            }
+        }
+        self.rejects.sort_by_key(|reject| reject.line);
+    }
+
+    /// Writes the rows as a v2 mask, sorted by taxon and gene, with the reference fingerprint and
+    /// the extra columns of the curators. Returns the number of rows.
+    pub fn write(&self, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "{}2", MASK_FORMAT_PREFIX)?;
+        if let Some(fingerprint) = &self.reference {
+            writeln!(writer, "{}{}", REFERENCE_FINGERPRINT_PREFIX, fingerprint)?;
+        }
+        let columns = MaskEntry::COLUMNS.iter().copied().chain(self.extra_columns.iter().map(String::as_str));
+        writeln!(writer, "#{}", itertools::join(columns, "\t"))?;
+        let mut rows = self.rows.iter().collect::<Vec<&CuratedRow>>();
+        rows.sort_by_key(|row| (row.entry.taxon, row.entry.gene));
+        for row in &rows {
+            writeln!(writer, "{}{}", row.entry, row.extra.iter().map(|token| format!("\t{}", token)).collect::<String>())?;
+        }
+        Ok(rows.len())
+    }
+
+    /// Writes the rejects (line, reason, row as found). Returns their number.
+    pub fn write_rejects(&self, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "#line\treason\trow")?;
+        for reject in &self.rejects {
+            writeln!(writer, "{}\t{}\t{}", reject.line, reject.reason, reject.row)?;
+        }
+        Ok(self.rejects.len())
+    }
+}
+
+/// A difference of a curated mask to the mask it was edited from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MaskChange {
+    Added(MaskEntry),
+    Removed(MaskEntry),
+    /// A mask column of a kept (taxon, gene) with its old and new value
+    Changed { taxon: TaxID, gene: GeneID, column: &'static str, old: String, new: String },
+}
+
+/// Rows added, removed and changed by the curators, sorted by taxon and gene. Extra columns are
+/// not compared.
+pub fn mask_changes(original: &[MaskEntry], curated: &[CuratedRow]) -> Vec<MaskChange> {
+    let old = original.iter().map(|entry| ((entry.taxon, entry.gene), entry)).collect::<HashMap<(TaxID, GeneID), &MaskEntry>>();
+    let new = curated.iter().map(|row| ((row.entry.taxon, row.entry.gene), &row.entry)).collect::<HashMap<(TaxID, GeneID), &MaskEntry>>();
+    let mut keys = old.keys().chain(new.keys()).copied().collect::<Vec<(TaxID, GeneID)>>();
+    keys.sort_unstable();
+    keys.dedup();
+
+    let mut result = Vec::new();
+    for key in keys {
+        match (old.get(&key), new.get(&key)) {
+            (Some(old), None) => result.push(MaskChange::Removed((*old).clone())),
+            (None, Some(new)) => result.push(MaskChange::Added((*new).clone())),
+            (Some(old), Some(new)) => {
+                let (old_row, new_row) = (old.to_string(), new.to_string());
+                for ((column, old), new) in MaskEntry::COLUMNS.iter().zip(old_row.split('\t')).zip(new_row.split('\t')).filter(|((_column, old), new)| old != new) {
+                    result.push(MaskChange::Changed { taxon: key.0, gene: key.1, column, old: old.to_string(), new: new.to_string() });
+                }
+            },
+            (None, None) => (),
+        }
+    }
+    result
+}
+
+/// Writes one row per change (change, taxid, gene, column, old, new). Returns the number of rows.
+pub fn write_mask_changes(changes: &[MaskChange], writer: &mut impl Write) -> std::io::Result<usize> {
+    writeln!(writer, "#change\ttaxid\tgene\tcolumn\told\tnew")?;
+    for change in changes {
+        match change {
+            MaskChange::Added(entry) => writeln!(writer, "added\t{}\t{}\tNA\tNA\t{}", entry.taxon, entry.gene, entry.to_string().replace('\t', " "))?,
+            MaskChange::Removed(entry) => writeln!(writer, "removed\t{}\t{}\tNA\t{}\tNA", entry.taxon, entry.gene, entry.to_string().replace('\t', " "))?,
+            MaskChange::Changed { taxon, gene, column, old, new } => writeln!(writer, "changed\t{}\t{}\t{}\t{}\t{}", taxon, gene, column, old, new)?,
+        }
+    }
+    Ok(changes.len())
+}
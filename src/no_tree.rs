@@ -0,0 +1,85 @@
+//! Stand-in for the tree module in builds without the `tree` feature. Loading a tree fails with
+//! a clear error, so binaries keep their tree options and report them as unsupported.
+
+use std::{convert::Infallible, fmt::Display, path::Path};
+
+use clap::ValueEnum;
+use thiserror::Error;
+
+use crate::id_to_label::LabelNormalize;
+
+pub type Edge = f64;
+
+#[derive(Debug, Error)]
+pub enum TopologyError {
+    #[error("Cannot read tree {0}: compiled without tree support (build with --features tree)")]
+    NoTreeSupport(String),
+}
+
+/// How polytomies are handled before analyses that need a binary tree.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ResolvePolytomies {
+    /// Keep polytomies, binary-only analyses fail with UnsupportedTopology
+    #[value(name = "none")]
+    None,
+    /// Binarize by joining randomly chosen children under new nodes with zero-length branches
+    #[value(name = "random")]
+    Random,
+}
+
+/// Never constructed without the `tree` feature.
+pub struct TreeReport {
+    never: Infallible,
+}
+
+impl Display for TreeReport {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.never {}
+    }
+}
+
+/// Names of leaves with their distance to another leaf, see `LabeledTree::sister_leaves`.
+pub type SisterLeaves = Vec<(String, Option<Edge>)>;
+
+/// Never constructed without the `tree` feature.
+pub struct LabeledTree {
+    never: Infallible,
+}
+
+impl LabeledTree {
+    pub fn load(path: impl AsRef<Path>, _normalize: LabelNormalize) -> Result<Self, TopologyError> {
+        Err(TopologyError::NoTreeSupport(path.as_ref().display().to_string()))
+    }
+
+    pub fn report(&self) -> &TreeReport {
+        match self.never {}
+    }
+
+    pub fn resolve_polytomies(&mut self, _mode: ResolvePolytomies, _seed: u64) -> Result<usize, TopologyError> {
+        match self.never {}
+    }
+
+    pub fn prune(&mut self, _label: &str) -> Result<bool, TopologyError> {
+        match self.never {}
+    }
+
+    pub fn collisions(&self) -> usize {
+        match self.never {}
+    }
+
+    pub fn normalized_joins(&self) -> usize {
+        match self.never {}
+    }
+
+    pub fn has_leaf(&self, _label: &str) -> bool {
+        match self.never {}
+    }
+
+    pub fn distance(&self, _a: &str, _b: &str) -> Option<Edge> {
+        match self.never {}
+    }
+
+    pub fn sister_leaves(&self, _label: &str) -> Result<Option<SisterLeaves>, TopologyError> {
+        match self.never {}
+    }
+}
@@ -1,13 +1,15 @@
-use std::{cmp::max, collections::HashMap, fmt::Display, path::Path};
+use std::{cmp::{max, Ordering, Reverse}, collections::{hash_map::Entry, BinaryHeap, HashMap, HashSet, VecDeque}, fmt::Display, io::Write, path::Path};
 
-use crate::{common::{sam_file_iterator, sam_to_ids, Args, GeneID}, utils::file_lines};
+use itertools::Either;
+
+use crate::{common::{checked_count, diff_maps, CountOverflow, DebugTaxa, GroupByQname, InputError, QnameGroup, SamHeader, SamReader, SamRef, sam_input, sam_inputs, sam_to_ids, Anomaly, AnomalyError, AnomalyLog, Args, Difference, FromTo, GeneID, TaxID}, filter::{Decision, Mapq, RecordFilter}, id_to_label::IdLabels, leakage::LeakageCounter, reconcile::{BestPerRead, FractionPerRead, MultimapWeighting, ReadClass, Reconciler}, samples::{name_tables, sample_table, Samples, READ_GROUPS_PREFIX}, schema::{self, fmt_clamped, fmt_fixed, Clamp, NumericError, ReleaseHeader, RowError, TableSchema}, timing::{Phase, Sampler}, tracks::LengthHistogram, utils::{create_output, estimate_capacity, file_lines, is_stdin, write_atomically, Codec, Reservoir}};
 
 
 
 pub type TinyTaxID = u32;
 pub type TinyGeneID = u32;
 
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
 pub struct LeakagePair {
     pub from: TinyTaxID,
     pub to: TinyTaxID,
@@ -22,58 +24,521 @@ impl LeakagePair {
     }
 }
 
-#[derive(Default)]
+/// Whether the pairs of a table are directed (donor, recipient) or unordered, keyed canonically
+/// with from <= to like the ambiguity matrix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Directionality {
+    #[default]
+    Directed,
+    Undirected,
+}
+
+impl Display for Directionality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Directionality::Directed => write!(f, "directed"),
+            Directionality::Undirected => write!(f, "undirected"),
+        }
+    }
+}
+
+impl std::str::FromStr for Directionality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "directed" => Ok(Directionality::Directed),
+            "undirected" => Ok(Directionality::Undirected),
+            _ => Err(format!("Unknown pair directionality '{}'", s)),
+        }
+    }
+}
+
+/// Layout of a pairwise table: the directionality of its pairs, the gene id of its first gene
+/// column, whether unmapped and strand bias columns follow the total and whether counts are
+/// fractional. Written as `#pairs<TAB>directed|undirected<TAB>gene_base=N[<TAB>unmapped=1]
+/// [<TAB>strand_bias=1][<TAB>weights=fraction]` after the self-pair header, tables without it are
+/// directed with genes starting at 1, no unmapped or strand bias column and whole read counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairSchema {
+    pub directionality: Directionality,
+    pub gene_base: GeneID,
+    /// Rows carry the unmapped records of their `from` taxon (--count-unmapped)
+    pub unmapped: bool,
+    /// Totals and genes are fractional reads in fixed notation (--multimap-weighting fraction)
+    pub fractional: bool,
+    /// Rows carry the fraction of their reads on the reverse strand (--strand-bias)
+    pub strand_bias: bool,
+}
+
+impl Default for PairSchema {
+    fn default() -> Self {
+        Self { directionality: Directionality::Directed, gene_base: 1, unmapped: false, fractional: false, strand_bias: false }
+    }
+}
+
+impl PairSchema {
+    pub const PREFIX: &'static str = "#pairs\t";
+
+    /// Directed, genes starting at --gene-id-base, fractional with --multimap-weighting fraction,
+    /// with a strand bias column with --strand-bias.
+    pub fn from_args(args: &Args) -> Self {
+        Self { gene_base: args.gene_id_base as GeneID, fractional: args.multimap_weighting == MultimapWeighting::Fraction, strand_bias: args.strand_bias, ..Self::default() }
+    }
+
+    /// Numeric formats of the columns other than the strand bias.
+    pub fn table(&self) -> TableSchema {
+        match (self.fractional, self.unmapped) {
+            (false, false) => schema::PAIRWISE,
+            (false, true) => schema::PAIRWISE_UNMAPPED,
+            (true, false) => schema::PAIRWISE_FRACTION,
+            (true, true) => schema::PAIRWISE_FRACTION_UNMAPPED,
+        }
+    }
+
+    /// Column of the strand bias, after the total and unmapped columns, None without it.
+    pub fn strand_column(&self) -> Option<usize> {
+        self.strand_bias.then(|| self.table().columns.len())
+    }
+
+    /// Column of the first gene.
+    pub fn first_gene(&self) -> usize {
+        self.table().columns.len() + self.strand_bias as usize
+    }
+
+    pub fn undirected() -> Self {
+        Self { directionality: Directionality::Undirected, ..Self::default() }
+    }
+
+    /// The key a pair is stored under: unchanged for directed tables, (min, max) for undirected ones.
+    pub fn canonical(&self, pair: LeakagePair) -> LeakagePair {
+        match self.directionality {
+            Directionality::Undirected if pair.from > pair.to => LeakagePair::from(pair.to, pair.from),
+            _ => pair,
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<Result<Self, String>> {
+        let fields = line.strip_prefix(Self::PREFIX)?;
+        let mut fields = fields.split('\t');
+        let directionality = match fields.next().unwrap_or_default().parse() {
+            Ok(directionality) => directionality,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut result = Self { directionality, ..Self::default() };
+        for field in fields {
+            let parsed = match field.split_once('=') {
+                Some(("gene_base", value)) => value.parse().map(|base| result.gene_base = base).map_err(|_| format!("Invalid gene base '{}'", value)),
+                Some(("unmapped", value @ ("0" | "1"))) => {
+                    result.unmapped = value == "1";
+                    Ok(())
+                },
+                Some(("strand_bias", value @ ("0" | "1"))) => {
+                    result.strand_bias = value == "1";
+                    Ok(())
+                },
+                Some(("weights", value @ ("count" | "fraction"))) => {
+                    result.fractional = value == "fraction";
+                    Ok(())
+                },
+                _ => Err(format!("Unknown pair setting '{}'", field)),
+            };
+            if let Err(e) = parsed { return Some(Err(e)) };
+        }
+        Some(Ok(result))
+    }
+
+    /// Differences that keep two tables from being merged, None if they match.
+    fn mismatch(&self, other: &Self, with_genes: bool) -> Option<String> {
+        if self.directionality != other.directionality {
+            return Some(format!("{} and {} pairs", self.directionality, other.directionality))
+        }
+        if with_genes && self.gene_base != other.gene_base {
+            return Some(format!("genes starting at {} and {}", self.gene_base, other.gene_base))
+        }
+        if self.unmapped != other.unmapped {
+            return Some("and without unmapped columns".to_string())
+        }
+        if self.fractional != other.fractional {
+            return Some("fractional and whole read counts".to_string())
+        }
+        if self.strand_bias != other.strand_bias {
+            return Some("and without strand bias columns".to_string())
+        }
+        None
+    }
+}
+
+impl Display for PairSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}\tgene_base={}", Self::PREFIX, self.directionality, self.gene_base)?;
+        if self.unmapped {
+            write!(f, "\tunmapped=1")?;
+        }
+        if self.strand_bias {
+            write!(f, "\tstrand_bias=1")?;
+        }
+        if self.fractional {
+            write!(f, "\tweights=fraction")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the header lines of a pairwise table: warns about its self-pair policy (see
+/// `check_self_pair_header`), sets `schema` from a pair header and `release` from a release
+/// header. Tables merged from several releases are refused.
+fn read_pairwise_header(line: &str, policy: SelfPairPolicy, schema: &mut PairSchema, release: &mut Option<ReleaseHeader>, path: &str) -> Result<(), InputError> {
+    let invalid = |e: String| InputError::Invalid(format!("{}: {}", path, e));
+    check_self_pair_header(line, policy, path)?;
+    if let Some(parsed) = PairSchema::parse(line) {
+        *schema = parsed.map_err(invalid)?;
+    }
+    if let Some(parsed) = ReleaseHeader::parse(line) {
+        let parsed = parsed.map_err(invalid)?;
+        parsed.single().map_err(invalid)?;
+        *release = Some(parsed);
+    }
+    Ok(())
+}
+
+/// A row of a pairwise table without the source column of tables merged from tagged releases.
+fn pairwise_row<'a>(line: &'a str, release: &Option<ReleaseHeader>, line_no: usize, path: &str) -> Result<&'a str, InputError> {
+    match release {
+        Some(release) => release.strip_source(line, line_no).map_err(|e| InputError::Invalid(format!("{}: {}", path, e))),
+        None => Ok(line),
+    }
+}
+
+/// The next line of a table, None at its end. A read error (e.g. of a truncated gz table) ends
+/// the table as well and is logged as truncated input, which is an error with --strict.
+fn table_line(line: Option<std::io::Result<String>>, line_no: usize, anomalies: &mut AnomalyLog) -> Result<Option<String>, AnomalyError> {
+    match line {
+        Some(Ok(line)) => Ok(Some(line)),
+        Some(Err(e)) => {
+            anomalies.record(Anomaly::TruncatedInput, &e.to_string(), Some(line_no))?;
+            Ok(None)
+        },
+        None => Ok(None),
+    }
+}
+
+/// Release tag of a table read with `read_pairwise_header`.
+fn release_tag(release: Option<ReleaseHeader>) -> Option<String> {
+    release.and_then(|release| release.single().ok().map(str::to_string))
+}
+
+/// Refuses to merge tables of different releases, untagged tables being a release of their own.
+fn check_same_release(release: &Option<String>, other: &Option<String>) -> Result<(), String> {
+    let describe = |release: &Option<String>| release.as_ref().map_or("untagged".to_string(), |tag| format!("release {}", tag));
+    match release == other {
+        true => Ok(()),
+        false => Err(format!("Cannot merge tables of {} and {} (merge_pairwise keeps releases apart)", describe(release), describe(other))),
+    }
+}
+
+/// Checks that a table can be merged into one of `schema`. Mismatches are refused unless
+/// `coerce` is set, then only warned about.
+fn check_mergeable(schema: &PairSchema, other: &PairSchema, with_genes: bool, coerce: bool) -> Result<(), String> {
+    let Some(mismatch) = schema.mismatch(other, with_genes) else { return Ok(()) };
+    match coerce {
+        true => {
+            eprintln!("Warning: merging tables with {}, coerced to {} pairs with genes starting at {}", mismatch, schema.directionality, schema.gene_base);
+            Ok(())
+        },
+        false => Err(format!("Cannot merge tables with {} (use --coerce to merge anyway)", mismatch)),
+    }
+}
+
+impl Display for LeakagePair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}->{}", self.from, self.to)
+    }
+}
+
+/// Read counts per gene. Most vectors have only a few occupied slots, so they start out as sorted
+/// (gene, count) pairs and are upgraded to a dense vector indexed by gene once more than
+/// `SPARSE_CAPACITY` genes are occupied (or a gene id or count does not fit the pairs). Genes
+/// given a fractional read (--multimap-weighting fraction) hold floats from then on.
+#[derive(Debug)]
 pub struct Genes {
-    pub data: Vec::<isize>,
+    slots: Slots,
+}
+
+#[derive(Debug)]
+enum Slots {
+    /// Occupied genes sorted by id; `len` is the number of slots including trailing empty ones.
+    Sparse { len: usize, entries: Vec<(u8, u32)> },
+    /// Counts indexed by gene, `Genes::EMPTY` for unoccupied slots.
+    Dense(Vec<i64>),
+    /// Fractional reads indexed by gene, `Genes::EMPTY_WEIGHT` for unoccupied slots.
+    Weighted(Vec<f64>),
 }
 
+impl Default for Genes {
+    fn default() -> Self {
+        Self { slots: Slots::Sparse { len: 0, entries: Vec::new() } }
+    }
+}
+
+/// Normalized values per gene. If `denominators` is set, the denominator of every gene is recorded
+/// alongside its value. A gene with contributions of several donors was divided by several
+/// denominators, its denominator is NaN and written as NA.
 #[derive(Default)]
 pub struct NormGenes {
     pub data: Vec::<f64>,
+    pub denominators: Option<Vec<f64>>,
 }
 
 impl Display for Genes {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = itertools::join(self.data.iter().skip(1), "\t");
-        write!(f, "{}\t{}", self.total(), s)
+        write!(f, "{}", self.row(1))
     }
 }
 
+/// Values per gene, each followed by its denominator if denominators are recorded.
 impl Display for NormGenes {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = itertools::join(self.data.iter().skip(1), "\t");
-        write!(f, "{}\t{}", self.total(), s)
+        write!(f, "{}", self.render(None, &mut 0))
     }
 }
 
-impl NormGenes {
-    const EMPTY: f64 = -1.0;
+/// What normalized values were divided by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalizationMode {
+    /// Reads of the gene leaked by a donor over all reads of that gene from the donor.
+    DonorOutgoingTotal,
+    /// Reads leaked by a donor over all reads from the donor, summed over genes (--no-genes).
+    /// Tables of this mode hold one total per recipient and no gene columns.
+    DonorOutgoingPairTotal,
+}
+
+impl Display for NormalizationMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NormalizationMode::DonorOutgoingTotal => write!(f, "donor_outgoing_total"),
+            NormalizationMode::DonorOutgoingPairTotal => write!(f, "donor_outgoing_pair_total"),
+        }
+    }
+}
+
+impl std::str::FromStr for NormalizationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "donor_outgoing_total" => Ok(NormalizationMode::DonorOutgoingTotal),
+            "donor_outgoing_pair_total" => Ok(NormalizationMode::DonorOutgoingPairTotal),
+            _ => Err(format!("Unknown normalization mode '{}'", s)),
+        }
+    }
+}
+
+/// Whether correct assignments (self-pairs, from == to) appear in outputs and in donor outgoing
+/// totals. Written as `#self_pairs<TAB>output=..<TAB>denominators=..` into every pairwise and
+/// normalized table. Both are included by default; taxon summaries and gene leaks always count
+/// self-pairs as correct reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfPairPolicy {
+    /// Self-pairs are rows of pairwise tables and contribute to a recipient's normalized leakage.
+    pub include_in_output: bool,
+    /// Self-pairs count towards the donor outgoing totals normalized values are divided by.
+    pub include_in_denominators: bool,
+}
+
+impl Default for SelfPairPolicy {
+    fn default() -> Self {
+        Self { include_in_output: true, include_in_denominators: true }
+    }
+}
+
+impl SelfPairPolicy {
+    pub const PREFIX: &'static str = "#self_pairs\t";
+
+    pub fn from_args(args: &Args) -> Self {
+        Self { include_in_output: !args.exclude_self_pairs, include_in_denominators: !args.exclude_self_from_denominators }
+    }
+
+    pub fn in_output(&self, pair: &LeakagePair) -> bool {
+        self.include_in_output || pair.from != pair.to
+    }
+
+    pub fn in_denominators(&self, pair: &LeakagePair) -> bool {
+        self.include_in_denominators || pair.from != pair.to
+    }
+
+    pub fn parse(line: &str) -> Option<Result<Self, String>> {
+        let fields = line.strip_prefix(Self::PREFIX)?;
+        let mut result = Self::default();
+        for field in fields.split('\t') {
+            let parsed = match field.split_once('=') {
+                Some(("output", value)) => parse_inclusion(value).map(|include| result.include_in_output = include),
+                Some(("denominators", value)) => parse_inclusion(value).map(|include| result.include_in_denominators = include),
+                _ => Err(format!("Unknown self-pair setting '{}'", field)),
+            };
+            if let Err(e) = parsed { return Some(Err(e)) };
+        }
+        Some(Ok(result))
+    }
+}
+
+fn parse_inclusion(value: &str) -> Result<bool, String> {
+    match value {
+        "included" => Ok(true),
+        "excluded" => Ok(false),
+        _ => Err(format!("Unknown self-pair inclusion '{}'", value)),
+    }
+}
 
-    pub fn merge_normalized_from_counts(&mut self, other: &Genes, normalizer: &Genes) {
-        for (gene, count) in other.data.iter().enumerate() {
-            if *count == Genes::EMPTY { continue };
+impl Display for SelfPairPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inclusion = |include: bool| if include { "included" } else { "excluded" };
+        write!(f, "{}output={}\tdenominators={}", Self::PREFIX, inclusion(self.include_in_output), inclusion(self.include_in_denominators))
+    }
+}
+
+/// Warns when a pairwise table written without self-pairs is normalized with self-pairs in the
+/// denominators, which then silently fall back to totals without them.
+fn check_self_pair_header(line: &str, policy: SelfPairPolicy, path: &str) -> Result<(), InputError> {
+    let Some(header) = SelfPairPolicy::parse(line) else { return Ok(()) };
+    let header = header.map_err(|e| InputError::Invalid(format!("{}: {}", path, e)))?;
+    if !header.include_in_output && policy.include_in_denominators {
+        eprintln!("Warning: {} was written without self-pairs, denominators cannot include them", path);
+    }
+    Ok(())
+}
+
+/// Schema header of a normalized table: `#normalization<TAB>mode<TAB>values|values_and_denominators`,
+/// followed by the self-pair policy and, under --clamp, the clamp header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NormalizationSchema {
+    pub mode: NormalizationMode,
+    pub with_denominators: bool,
+    pub self_pairs: SelfPairPolicy,
+    pub clamp: Option<Clamp>,
+}
+
+impl NormalizationSchema {
+    pub const PREFIX: &'static str = "#normalization\t";
+
+    pub fn from_args(args: &Args) -> Self {
+        let clamp = args.clamp.map(|limit| Clamp { limit, flags: args.clamp_flags });
+        let mode = if args.no_genes { NormalizationMode::DonorOutgoingPairTotal } else { NormalizationMode::DonorOutgoingTotal };
+        Self { mode, with_denominators: args.with_denominators, self_pairs: SelfPairPolicy::from_args(args), clamp }
+    }
+
+    pub fn parse(line: &str) -> Option<Result<Self, String>> {
+        let fields = line.strip_prefix(Self::PREFIX)?;
+        let (mode, columns) = fields.split_once('\t').unwrap_or((fields, "values"));
+        let with_denominators = match columns {
+            "values" => false,
+            "values_and_denominators" => true,
+            _ => return Some(Err(format!("Unknown normalization columns '{}'", columns))),
+        };
+        Some(mode.parse().map(|mode| Self { mode, with_denominators, self_pairs: SelfPairPolicy::default(), clamp: None }))
+    }
+}
+
+impl Display for NormalizationSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let columns = if self.with_denominators { "values_and_denominators" } else { "values" };
+        write!(f, "{}{}\t{}\n{}", Self::PREFIX, self.mode, columns, self.self_pairs)?;
+        match &self.clamp {
+            Some(clamp) => write!(f, "\n{}", clamp),
+            None => Ok(()),
+        }
+    }
+}
 
+impl NormGenes {
+    const EMPTY: f64 = -1.0;
 
+    pub fn new(with_denominators: bool) -> Self {
+        Self {
+            data: Vec::new(),
+            denominators: with_denominators.then(Vec::new),
+        }
+    }
 
+    /// Adds count / normalizer per gene. Non-finite ratios (a zero normalizer) are skipped
+    /// and their number returned.
+    pub fn merge_normalized_from_counts(&mut self, other: &Genes, normalizer: &Genes) -> usize {
+        let mut non_finite = 0;
+        for (gene, count) in other.weights() {
             if gene >= self.data.len() {
                 self.data.resize_with(gene + 1, || Self::EMPTY);
             }
             if self.data[gene] == Self::EMPTY { self.data[gene] = 0.0 };
 
-            let res = *count as f64 / normalizer.data[gene] as f64;
+            let denominator = normalizer.weight(gene).unwrap_or(0.0);
+            let res = count / denominator;
 
-            assert!(res > 0.0);
-
-            if res.is_nan() {
-                eprintln!("Result: {}/{} = {}", *count as f64, normalizer.data[gene] as f64,  *count as f64 / normalizer.data[gene] as f64);
+            if !res.is_finite() {
+                non_finite += 1;
+                continue
             }
 
             self.data[gene] += res;
+            if let Some(denominators) = &mut self.denominators {
+                if gene >= denominators.len() {
+                    denominators.resize_with(gene + 1, || Self::EMPTY);
+                }
+                denominators[gene] = match denominators[gene] == Self::EMPTY {
+                    true => denominator,
+                    false => f64::NAN,
+                };
+            }
+        }
+        non_finite
+    }
+    /// The total and gene columns (value and denominator per gene with denominators), values
+    /// through `clamp` if given.
+    pub fn render(&self, clamp: Option<&Clamp>, clamped: &mut usize) -> String {
+        let mut value = |value: f64| fmt_clamped(value, clamp, clamped);
+        let total = value(self.total());
+        let s = match &self.denominators {
+            Some(denominators) => itertools::join(self.data.iter().zip(denominators).skip(1).map(|(v, denominator)| format!("{}\t{}", value(*v), Self::render_denominator(*denominator))), "\t"),
+            None => itertools::join(self.data.iter().skip(1).map(|v| value(*v)), "\t"),
+        };
+        format!("{}\t{}", total, s)
+    }
+
+    fn render_denominator(denominator: f64) -> String {
+        match denominator.is_nan() {
+            true => "NA".to_string(),
+            false => fmt_fixed(denominator),
+        }
+    }
+
+    /// Parses a row written by `write_normalized` into (recipient, genes). Denominator and clamp
+    /// flag columns are skipped, clamped values read as the cap, the total column is recomputed
+    /// from the values.
+    pub fn parse_line(line: &str, schema: &NormalizationSchema, line_no: usize) -> Result<(TinyTaxID, Self), RowError> {
+        let tokens = line.split('\t').collect::<Vec<&str>>();
+        schema::NORMALIZED.check_columns(&tokens, line_no)?;
+        let recipient = schema::NORMALIZED.parse(tokens[0], 0, line_no)?;
+        let width = schema.clamp.map_or(1, |clamp| clamp.width());
+        let step = width + schema.with_denominators as usize;
+        let first = 1 + width;
+        let mut data = vec![Self::EMPTY];
+        for (column, value) in tokens.iter().enumerate().skip(1) {
+            if schema.with_denominators && column >= first && (column - first) % step == width {
+                continue
+            }
+            let value = match schema.clamp {
+                Some(_) => value.strip_suffix(Clamp::SUFFIX).unwrap_or(value),
+                None => value,
+            };
+            let value: f64 = schema::NORMALIZED.parse(value, column, line_no)?;
+            if column >= first && (column - first).is_multiple_of(step) {
+                data.push(value);
+            }
         }
+        Ok((recipient, Self { data, denominators: None }))
     }
+
     pub fn total(&self) -> f64 {
-        let res = self.data.iter().fold(0.0, |acc, x| acc + if *x < 0.0 || *x == std::f64::NAN { 0.0 } else { *x }); //
-        eprintln!("-- {} ... {} ... {:?}", res, res.is_nan(), self.data);
+        let res = self.data.iter().fold(0.0, |acc, x| acc + if *x < 0.0 || x.is_nan() { 0.0 } else { *x });
         assert!(res >= 0.0);
 
         res
@@ -81,128 +546,2301 @@ impl NormGenes {
 }
 
 impl Genes {
-    const EMPTY: isize = -1;
+    const EMPTY: i64 = -1;
+    const EMPTY_WEIGHT: f64 = -1.0;
+    /// Occupied genes up to which the sparse representation is kept.
+    const SPARSE_CAPACITY: usize = 16;
 
     pub fn increment(&mut self, gene: GeneID) {
-        if gene >= self.data.len() {
-            self.data.resize_with(gene + 1, || Self::EMPTY);
+        self.add(gene, 1).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Adds a fractional read to a gene, turning the genes into weighted ones.
+    pub fn add_weight(&mut self, gene: GeneID, weight: f64) {
+        self.make_weighted();
+        let Slots::Weighted(data) = &mut self.slots else { unreachable!("genes were made weighted") };
+        if gene >= data.len() {
+            data.resize_with(gene + 1, || Self::EMPTY_WEIGHT);
         }
-        if self.data[gene] == Self::EMPTY { self.data[gene] = 0 };
-        self.data[gene] += 1
+        data[gene] = data[gene].max(0.0) + weight;
     }
 
-    pub fn get(&self, gene: GeneID) -> Option<usize> {
-        let count = self.data[gene];
+    /// Adds `count` reads to a gene, occupying its slot if it was empty. A count beyond `i64::MAX`
+    /// is an error, the gene keeps its count.
+    fn add(&mut self, gene: GeneID, count: u64) -> Result<(), CountOverflow> {
+        if self.is_weighted() {
+            self.add_weight(gene, count as f64);
+            return Ok(())
+        }
+        if let Slots::Sparse { len, entries } = &mut self.slots {
+            let key = u8::try_from(gene).ok();
+            let count32 = u32::try_from(count).ok();
+            if let (Some(key), Some(count32)) = (key, count32) {
+                match entries.binary_search_by_key(&key, |(gene, _count)| *gene) {
+                    Ok(i) => if let Some(sum) = entries[i].1.checked_add(count32) {
+                        entries[i].1 = sum;
+                        return Ok(())
+                    },
+                    Err(i) if entries.len() < Self::SPARSE_CAPACITY => {
+                        entries.insert(i, (key, count32));
+                        *len = (*len).max(gene + 1);
+                        return Ok(())
+                    },
+                    Err(_) => (),
+                }
+            }
+            self.make_dense();
+        }
 
-        if count == Self::EMPTY {
-            return None
+        let Slots::Dense(data) = &mut self.slots else { unreachable!("sparse genes were made dense") };
+        if gene >= data.len() {
+            data.resize_with(gene + 1, || Self::EMPTY);
         }
-        return Some(count as usize)
+        let overflow = || CountOverflow(format!("{} + {} reads on gene {}", data[gene].max(0), count, gene));
+        let sum = i64::try_from(count).ok().and_then(|count| data[gene].max(0).checked_add(count)).ok_or_else(overflow)?;
+        data[gene] = sum;
+        Ok(())
     }
 
-    pub fn total(&self) -> usize {
-        self.data.iter().fold(0, |acc, x| acc + max(*x, 0)  as usize)
+    fn make_dense(&mut self) {
+        if let Slots::Sparse { len, entries } = &self.slots {
+            let mut data = vec![Self::EMPTY; *len];
+            for (gene, count) in entries {
+                data[*gene as usize] = *count as i64;
+            }
+            self.slots = Slots::Dense(data);
+        }
     }
 
-    pub fn merge_from(&mut self, other: &Self) {
-        for (gene, count) in other.data.iter().enumerate() {
-            if *count == Self::EMPTY { continue };
-            assert!(*count > 0);
+    fn make_weighted(&mut self) {
+        if !self.is_weighted() {
+            let data = (0..self.len()).map(|gene| self.slot(gene).map_or(Self::EMPTY_WEIGHT, |count| count as f64)).collect();
+            self.slots = Slots::Weighted(data);
+        }
+    }
 
-            if gene >= self.data.len() {
-                self.data.resize_with(gene + 1, || Self::EMPTY);
+    pub fn is_dense(&self) -> bool {
+        matches!(self.slots, Slots::Dense(_))
+    }
+
+    /// Whether the genes hold fractional reads.
+    pub fn is_weighted(&self) -> bool {
+        matches!(self.slots, Slots::Weighted(_))
+    }
+
+    /// Number of gene slots, i.e. the largest gene id + 1 including trailing empty slots.
+    pub fn len(&self) -> usize {
+        match &self.slots {
+            Slots::Sparse { len, .. } => *len,
+            Slots::Dense(data) => data.len(),
+            Slots::Weighted(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, gene: GeneID) -> Option<u64> {
+        self.slot(gene).map(|count| count as u64)
+    }
+
+    /// Reads of a gene, fractional for weighted genes.
+    pub fn weight(&self, gene: GeneID) -> Option<f64> {
+        match &self.slots {
+            Slots::Weighted(data) => data.get(gene).copied().filter(|weight| *weight != Self::EMPTY_WEIGHT),
+            _ => self.slot(gene).map(|count| count as f64),
+        }
+    }
+
+    /// Reads over all genes, panics if they do not fit a u64. Weighted genes are rounded to
+    /// whole reads.
+    pub fn total(&self) -> u64 {
+        if self.is_weighted() {
+            return self.weight_total().round() as u64
+        }
+        self.iter().try_fold(0u64, |total, (_gene, count)| total.checked_add(count))
+            .unwrap_or_else(|| panic!("{}", CountOverflow(format!("total of genes {}", self))))
+    }
+
+    /// Reads over all genes, fractional for weighted genes.
+    pub fn weight_total(&self) -> f64 {
+        self.weights().map(|(_gene, weight)| weight).sum()
+    }
+
+    /// Adds the reads of every gene of `other`. Stops at the first gene that would overflow, the
+    /// genes before it are already added.
+    pub fn merge_from(&mut self, other: &Self) -> Result<(), CountOverflow> {
+        if other.is_weighted() {
+            other.weights().for_each(|(gene, weight)| self.add_weight(gene, weight));
+            return Ok(())
+        }
+        for (gene, count) in other.iter() {
+            assert!(count > 0);
+            self.add(gene, count)?;
+        }
+        Ok(())
+    }
+
+    /// Total followed by the counts of genes `base..`, `EMPTY` for unoccupied slots. Weighted
+    /// genes are written in fixed notation.
+    pub fn row(&self, base: GeneID) -> String {
+        if self.is_weighted() {
+            let s = itertools::join((base..self.len()).map(|gene| self.weight(gene).map_or(Self::EMPTY.to_string(), fmt_fixed)), "\t");
+            return format!("{}\t{}", fmt_fixed(self.weight_total()), s)
+        }
+        let s = itertools::join((base..self.len()).map(|gene| self.slot(gene).unwrap_or(Self::EMPTY)), "\t");
+        format!("{}\t{}", self.total(), s)
+    }
+
+    /// Moves every gene from a table starting at gene `from` to one starting at `to`, genes
+    /// before the new start are dropped.
+    pub fn rebased(&self, from: GeneID, to: GeneID) -> Self {
+        let mut result = Self::default();
+        if self.is_weighted() {
+            for (gene, weight) in self.weights() {
+                if let Some(gene) = (gene + to).checked_sub(from) {
+                    result.add_weight(gene, weight);
+                }
+            }
+            return result
+        }
+        for (gene, count) in self.iter() {
+            if let Some(gene) = (gene + to).checked_sub(from) {
+                result.add(gene, count).expect("Rebasing moves each count to its own gene");
             }
-            if self.data[gene] == Self::EMPTY { self.data[gene] = 0 };
+        }
+        result
+    }
 
-            self.data[gene] += count;
-            assert!(self.data[gene] > 0);
+    /// Builds genes from a row of counts indexed by gene, `EMPTY` marking unoccupied slots.
+    pub fn from_slice(slice: &[i64]) -> Self {
+        let occupied = slice.iter().filter(|count| **count != Self::EMPTY).count();
+        let fits_sparse = slice.len() <= u8::MAX as usize + 1
+            && slice.iter().all(|count| *count == Self::EMPTY || u32::try_from(*count).is_ok());
+        if occupied > Self::SPARSE_CAPACITY || !fits_sparse {
+            return Self { slots: Slots::Dense(Vec::from(slice)) }
         }
+        let entries = slice.iter().enumerate()
+            .filter(|(_gene, count)| **count != Self::EMPTY)
+            .map(|(gene, count)| (gene as u8, *count as u32))
+            .collect();
+        Self { slots: Slots::Sparse { len: slice.len(), entries } }
     }
 
-    pub fn from_slice(slice: &[isize]) -> Self {
-        Self {
-            data: Vec::from(slice),
+    /// Builds weighted genes from a row of fractional reads indexed by gene, `EMPTY_WEIGHT`
+    /// marking unoccupied slots.
+    pub fn from_weights(slice: &[f64]) -> Self {
+        Self { slots: Slots::Weighted(Vec::from(slice)) }
+    }
+
+    /// Iterates over the occupied gene slots as (gene, count), weighted genes rounded to whole reads.
+    pub fn iter(&self) -> impl Iterator<Item = (GeneID, u64)> + '_ {
+        match &self.slots {
+            Slots::Sparse { entries, .. } => Either::Left(entries.iter()
+                .map(|(gene, count)| (*gene as GeneID, *count as u64))),
+            Slots::Dense(data) => Either::Right(Either::Left(data.iter().enumerate()
+                .filter(|(_gene, count)| **count != Self::EMPTY)
+                .map(|(gene, count)| (gene, *count as u64)))),
+            Slots::Weighted(data) => Either::Right(Either::Right(data.iter().enumerate()
+                .filter(|(_gene, weight)| **weight != Self::EMPTY_WEIGHT)
+                .map(|(gene, weight)| (gene, weight.round() as u64)))),
+        }
+    }
+
+    /// Iterates over the occupied gene slots as (gene, reads), fractional for weighted genes.
+    pub fn weights(&self) -> impl Iterator<Item = (GeneID, f64)> + '_ {
+        match &self.slots {
+            Slots::Weighted(data) => Either::Left(data.iter().enumerate()
+                .filter(|(_gene, weight)| **weight != Self::EMPTY_WEIGHT)
+                .map(|(gene, weight)| (gene, *weight))),
+            _ => Either::Right(self.iter().map(|(gene, count)| (gene, count as f64))),
+        }
+    }
+
+    /// Count of a gene, None for empty slots and genes beyond the end of the vector.
+    fn slot(&self, gene: GeneID) -> Option<i64> {
+        match &self.slots {
+            Slots::Sparse { entries, .. } => {
+                let key = u8::try_from(gene).ok()?;
+                entries.binary_search_by_key(&key, |(gene, _count)| *gene).ok().map(|i| entries[i].1 as i64)
+            },
+            Slots::Dense(data) => data.get(gene).copied().filter(|count| *count != Self::EMPTY),
+            Slots::Weighted(data) => data.get(gene).copied().filter(|weight| *weight != Self::EMPTY_WEIGHT).map(|weight| weight.round() as i64),
         }
     }
+
+    /// Bytes of the genes, inline and on the heap.
+    pub fn memory(&self) -> usize {
+        let heap = match &self.slots {
+            Slots::Sparse { entries, .. } => entries.capacity() * std::mem::size_of::<(u8, u32)>(),
+            Slots::Dense(data) => data.capacity() * std::mem::size_of::<i64>(),
+            Slots::Weighted(data) => data.capacity() * std::mem::size_of::<f64>(),
+        };
+        std::mem::size_of::<Self>() + heap
+    }
+
+    pub fn diff(&self, other: &Self, key: impl Display) -> Vec<Difference> {
+        let key = key.to_string();
+        (0..max(self.len(), other.len()))
+            .filter_map(|gene| {
+                let (left, right) = (self.weight(gene), other.weight(gene));
+                if left == right { return None };
+                Some(Difference::new(&key, &format!("gene_{}", gene), left.map(|c| c.to_string()), right.map(|c| c.to_string())))
+            }).collect()
+    }
 }
 
-#[derive(Default)]
-pub struct Leakage {
-    pub map: HashMap<LeakagePair, Genes>
+/// Equal if all occupied gene slots match, regardless of trailing empty slots.
+impl PartialEq for Genes {
+    fn eq(&self, other: &Self) -> bool {
+        (0..max(self.len(), other.len())).all(|gene| self.weight(gene) == other.weight(gene))
+    }
 }
 
+/// Memory of the genes of a pair map (reported with --timing), against the same genes stored as
+/// dense `i64` vectors and as fixed arrays of `FIXED_GENES` u32 counts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GeneMemory {
+    pub pairs: usize,
+    pub sparse: usize,
+    pub bytes: usize,
+    pub dense_bytes: usize,
+    pub fixed_bytes: usize,
+}
 
-impl Leakage {
-    pub fn from_sam(args: &Args) -> Self {
-        let mut iter = sam_file_iterator(&args.input).expect("Cannot open file");
+impl GeneMemory {
+    pub const FIXED_GENES: usize = 120;
 
-        let mut res = Leakage::default();
+    pub fn add(&mut self, genes: &Genes) {
+        self.pairs += 1;
+        self.sparse += matches!(genes.slots, Slots::Sparse { .. }) as usize;
+        self.bytes += genes.memory();
+        self.dense_bytes += std::mem::size_of::<Vec<i64>>() + genes.len() * std::mem::size_of::<i64>();
+        self.fixed_bytes += Self::FIXED_GENES * std::mem::size_of::<u32>();
+    }
+}
 
-        while let Some(sam_res) = iter.next() {
-            let sam = sam_res.expect("Invalid sam");
-            if !sam.is_aligned() || sam.mapq < args.min_mapq {continue};
-            let fromto = sam_to_ids(&sam);
-            
-            let key = LeakagePair::from(fromto.query, fromto.reference);
+impl Display for GeneMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mb = |bytes: usize| bytes as f64 / (1 << 20) as f64;
+        write!(f, "Genes of {} pairs ({} sparse): {:.1} MB, {:.1} MB as dense vectors, {:.1} MB as fixed arrays of {} genes",
+            self.pairs, self.sparse, mb(self.bytes), mb(self.dense_bytes), mb(self.fixed_bytes), Self::FIXED_GENES)
+    }
+}
 
-            let mut entry = res.map.entry(key).or_default();
+/// Lower bounds of the log-sized buckets of pair totals in the run summary: 1, 2-9, 10-99,
+/// 100-999 and 1000+ reads.
+pub const TOTAL_BUCKETS: [u64; 5] = [1, 2, 10, 100, 1000];
 
-            if fromto.query_gene != fromto.reference_gene {
-                eprintln!("Gene mismatch for Query Taxon: {} Gene: {} to Reference Taxon: {} Gene: {}", fromto.query, fromto.query_gene, fromto.reference, fromto.reference_gene);
-            }
+/// Pairs whose total falls in `min..=max` (`min..` for the last bucket) and their summed reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TotalBucket {
+    pub min: u64,
+    pub max: Option<u64>,
+    pub pairs: usize,
+    pub reads: u64,
+}
 
-            entry.increment(fromto.reference_gene as GeneID);
+/// Range of the bucket, e.g. `1`, `2-9` or `1000+`.
+impl Display for TotalBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{}-{}", self.min, max),
+            None => write!(f, "{}+", self.min),
         }
-        res
     }
-    
-    pub fn load(args: &Args) -> Self {
-        let mut result = Self::default();
-        let mut tokens = Vec::<String>::new();
-        let mut iter = file_lines(&args.input).expect("Unable to construct line iterator over file");
-        while let Some(Ok(line)) = iter.next() {
-            let mut tokens = line.split("\t").map(|x| x.parse().expect("Cannot parse string into i32")).collect::<Vec<isize>>();
+}
+
+#[derive(Default, PartialEq)]
+pub struct Leakage {
+    pub map: HashMap<LeakagePair, Genes>,
+    pub schema: PairSchema,
+    /// Release tag of the table (--release-tag)
+    pub release: Option<String>,
+    /// Aligned lengths of the leaked reads of every pair when tracked (see
+    /// `Args::tracks_leak_lengths`), not part of the pairwise table
+    pub lengths: HashMap<LeakagePair, LengthHistogram>,
+    /// Unmapped records per taxid of the read name, written with the rows of the taxon as donor
+    /// when the schema has the unmapped column
+    pub unmapped: HashMap<TinyTaxID, u64>,
+    /// Reads of every pair on the reverse strand when strands are tracked (--strand-bias),
+    /// fractional like the genes of the pair. Pairs without any are absent
+    pub reverse: HashMap<LeakagePair, f64>,
+    /// Reads whose query gene is not their reference gene, by (pair, query gene, reference gene).
+    /// They are among the genes of the pair on the reference gene; not part of the pairwise table
+    pub gene_mismatches: HashMap<(LeakagePair, GeneID, GeneID), f64>,
+}
 
-            // eprintln!("{:?}", tokens);
+/// Columns of a pairwise row besides the pair and its genes, None for tables without them.
+#[derive(Debug, Default, Clone, Copy)]
+struct RowColumns {
+    unmapped: Option<u64>,
+    strand_bias: Option<f64>,
+}
 
-            let from = tokens[0] as u32;
-            let to = tokens[1] as u32;
 
-            eprintln!("{:?}", &tokens[3..]);
-            assert!(&tokens[3..].iter().all(|x| *x != 0));
+impl Leakage {
+    pub fn from_sam(args: &Args, anomalies: &mut AnomalyLog) -> Result<Self, InputError> {
+        Self::from_sam_with_header(args, anomalies).map(|(leakage, _header)| leakage)
+    }
 
-            let key = LeakagePair::from(from, to);
-            result.map.insert(key, Genes::from_slice(&tokens[3..]));
+    /// As `from_sam`, with the header of the SAM (the reference it was mapped against).
+    pub fn from_sam_with_header(args: &Args, anomalies: &mut AnomalyLog) -> Result<(Self, SamHeader), InputError> {
+        let expected = expected_pairs(args)?;
+        let mut res = Leakage { map: HashMap::with_capacity(expected.unwrap_or(0)), schema: PairSchema::from_args(args), release: args.release_tag.clone(), lengths: HashMap::default(), unmapped: HashMap::default(), reverse: HashMap::default(), gene_mismatches: HashMap::default() };
+        let mut filter = RecordFilter::from_args(args);
+        let threads = match counts_in_parallel(args) {
+            true => args_input_threads(args)?,
+            false => InputThreads { readers: 1, parsers: 1 },
+        };
+        if threads.readers > 1 || threads.parsers > 1 {
+            let header = count_pairs_parallel(args, threads, anomalies, &mut filter, Leakage::default, Leakage::add, |table| {
+                for (pair, genes) in table.map {
+                    res.map.entry(pair).or_default().merge_from(&genes).unwrap_or_else(|e| panic!("{}", e));
+                }
+                for (pair, lengths) in table.lengths {
+                    res.lengths.entry(pair).or_default().merge(&lengths);
+                }
+                for (pair, reverse) in table.reverse {
+                    *res.reverse.entry(pair).or_default() += reverse;
+                }
+                for (key, reads) in table.gene_mismatches {
+                    *res.gene_mismatches.entry(key).or_default() += reads;
+                }
+            })?;
+            report_capacity(expected, res.map.len());
+            report_gene_mismatches([&res]);
+            res.set_unmapped(args, &filter);
+            return Ok((res, header))
         }
+        let mut flush = PreliminaryFlush::from_args(args);
+        let (_samples, header) = count_pairs(args, anomalies, &mut filter, |fromto| {
+            res.add(fromto);
+            flush.tick(|| top_pairs(&res.map, |genes| genes.total()).into_iter()
+                .map(|(pair, genes)| format!("{}\t{}\t{}", pair.from, pair.to, genes.row(res.schema.gene_base)))
+                .collect(), &res.schema);
+        })?;
+        report_capacity(expected, res.map.len());
+        report_gene_mismatches([&res]);
+        res.set_unmapped(args, &filter);
+        Ok((res, header))
+    }
 
-        result
+    /// Takes the unmapped records counted by `filter` into the table under --count-unmapped.
+    fn set_unmapped(&mut self, args: &Args, filter: &RecordFilter) {
+        if !args.count_unmapped { return };
+        self.schema.unmapped = true;
+        self.unmapped = filter.unmapped_by_taxon().into_iter().map(|(taxid, records)| (taxid as TinyTaxID, records)).collect();
     }
 
-    pub fn total_outgoing(&self) -> HashMap<TinyTaxID, Genes> {
-        let mut result = HashMap::default();
+    /// Counts every sample of a multiplexed SAM (--split-by) into a table of its own, as (sample,
+    /// table) in order of first appearance.
+    pub fn from_sam_by_sample(args: &Args, anomalies: &mut AnomalyLog) -> Result<Vec<(String, Self)>, InputError> {
+        let empty = || Leakage { map: HashMap::default(), schema: PairSchema::from_args(args), release: args.release_tag.clone(), lengths: HashMap::default(), unmapped: HashMap::default(), reverse: HashMap::default(), gene_mismatches: HashMap::default() };
+        let mut tables = Vec::new();
+        let (samples, _header) = count_pairs(args, anomalies, &mut RecordFilter::from_args(args), |fromto| sample_table(&mut tables, fromto.sample, empty).add(fromto))?;
+        report_gene_mismatches(&tables);
+        Ok(name_tables(samples, tables, empty))
+    }
 
-        for (pair, genes) in &self.map {
-            let from = pair.from;
-            let entry: &mut Genes = result.entry(from).or_default();
-            entry.merge_from(genes);
+    /// Counts a single alignment on the reference gene of its pair.
+    pub fn add(&mut self, fromto: &FromTo) {
+        let key = LeakagePair::from(fromto.query, fromto.reference);
+
+        let entry = self.map.entry(key).or_default();
+
+        if fromto.query_gene != fromto.reference_gene {
+            *self.gene_mismatches.entry((key, fromto.query_gene as GeneID, fromto.reference_gene as GeneID)).or_default() += fromto.weight.unwrap_or(1.0);
         }
 
-        result
+        match fromto.weight {
+            Some(weight) => entry.add_weight(fromto.reference_gene as GeneID, weight),
+            None => entry.increment(fromto.reference_gene as GeneID),
+        }
+        if fromto.reverse == Some(true) {
+            *self.reverse.entry(key).or_default() += fromto.weight.unwrap_or(1.0);
+        }
+        if let Some(length) = fromto.aligned_length.filter(|_length| fromto.query != fromto.reference) {
+            self.lengths.entry(key).or_default().add(length);
+        }
+    }
+    
+    /// Parses one row of the pairwise table (from, to, total, [unmapped,] [strand_bias,] genes...),
+    /// the first gene column being gene `gene_base` of `pairs`.
+    fn parse_line(line: &str, pairs: &PairSchema, line_no: usize) -> Result<(LeakagePair, Genes, RowColumns), RowError> {
+        let mut tokens = line.split('\t').collect::<Vec<&str>>();
+        let strand_bias = match pairs.strand_column().filter(|column| *column < tokens.len()) {
+            Some(column) => Some(schema::STRAND_BIAS.parse(tokens.remove(column), line_no)?),
+            None => None,
+        };
+        let (pair, genes, unmapped) = match pairs.fractional {
+            true => Self::parse_fractional_tokens(&tokens, pairs, line_no)?,
+            false => Self::parse_tokens(&tokens, pairs, line_no)?,
+        };
+        Ok((pair, genes, RowColumns { unmapped, strand_bias }))
     }
 
-    pub fn normalize_incoming(&self) -> HashMap<TinyTaxID, NormGenes>{
-        let total_out = self.total_outgoing();
-        let mut result = HashMap::default();
+    /// The pair, genes and unmapped count of a row without its strand bias column.
+    fn parse_tokens(tokens: &[&str], pairs: &PairSchema, line_no: usize) -> Result<(LeakagePair, Genes, Option<u64>), RowError> {
+        let table = pairs.table();
+        table.check_columns(tokens, line_no)?;
+        let tokens = tokens.iter().enumerate()
+            .map(|(column, x)| table.parse(x, column, line_no))
+            .collect::<Result<Vec<i64>, NumericError>>()?;
 
-        for (pair, genes) in &self.map {
-            let to: u32 = pair.to;
-            let normalizer = &total_out[&pair.from];
+        // eprintln!("{:?}", tokens);
+
+        let from = tokens[0] as u32;
+        let to = tokens[1] as u32;
+        let first_gene = table.columns.len();
+        let unmapped = pairs.unmapped.then(|| tokens[3] as u64);
+
+        let mut slots = vec![Genes::EMPTY; pairs.gene_base];
+        slots.extend_from_slice(&tokens[first_gene..]);
+        Ok((LeakagePair::from(from, to), Genes::from_slice(&slots), unmapped))
+    }
 
-            let entry: &mut NormGenes = result.entry(to).or_default();
-            entry.merge_normalized_from_counts(genes, normalizer);
+    /// `parse_tokens` for a table of fractional reads, parsed into weighted genes.
+    fn parse_fractional_tokens(tokens: &[&str], pairs: &PairSchema, line_no: usize) -> Result<(LeakagePair, Genes, Option<u64>), RowError> {
+        let table = pairs.table();
+        table.check_columns(tokens, line_no)?;
+        let first_gene = table.columns.len();
+        let from = table.parse(tokens[0], 0, line_no)?;
+        let to = table.parse(tokens[1], 1, line_no)?;
+        // The total is validated but recomputed from the genes
+        table.parse::<f64>(tokens[2], 2, line_no)?;
+        let unmapped = match pairs.unmapped {
+            true => Some(table.parse(tokens[3], 3, line_no)?),
+            false => None,
+        };
 
+        let mut slots = vec![Genes::EMPTY_WEIGHT; pairs.gene_base];
+        for (column, token) in tokens.iter().enumerate().skip(first_gene) {
+            slots.push(table.parse(token, column, line_no)?);
         }
+        Ok((LeakagePair::from(from, to), Genes::from_weights(&slots), unmapped))
+    }
 
-        result
+    pub fn load(args: &Args, anomalies: &mut AnomalyLog) -> Result<Self, InputError> {
+        Self::read(&args.input, SelfPairPolicy::from_args(args), anomalies)
     }
-}
\ No newline at end of file
+
+    /// Reads a pairwise table. Keys are canonicalized by the directionality of its pair header and
+    /// pairs listed more than once are summed with a warning. A read error ends the table as
+    /// truncated input, see `table_line`.
+    pub fn read(path: &str, self_pairs: SelfPairPolicy, anomalies: &mut AnomalyLog) -> Result<Self, InputError> {
+        let mut result = Self::default();
+        let mut iter = file_lines(path)?;
+        anomalies.set_input(path);
+        let mut line_no = 0;
+        let mut duplicates = 0;
+        let mut release = None;
+        while let Some(line) = table_line(iter.next(), line_no + 1, anomalies)? {
+            line_no += 1;
+            if line.starts_with('#') {
+                read_pairwise_header(&line, self_pairs, &mut result.schema, &mut release, path)?;
+                continue
+            }
+            let row = pairwise_row(&line, &release, line_no, path)?;
+            let (key, genes, columns) = Self::parse_line(row, &result.schema, line_no).map_err(|e| InputError::Invalid(format!("{}: {}", path, e)))?;
+            if let Some(unmapped) = columns.unmapped {
+                result.unmapped.insert(key.from, unmapped);
+            }
+            if let Some(strand_bias) = columns.strand_bias.filter(|bias| *bias > 0.0) {
+                *result.reverse.entry(result.schema.canonical(key)).or_default() += strand_bias * genes.weight_total();
+            }
+            if result.insert(key, genes).map_err(|e| InputError::Invalid(format!("{}: line {}: {}", path, line_no, e)))? {
+                duplicates += 1;
+            }
+        }
+        if duplicates > 0 {
+            eprintln!("Warning: {} lists {} pairs more than once, their counts were summed", path, duplicates);
+        }
+        result.release = release_tag(release);
+
+        Ok(result)
+    }
+
+    /// Adds the genes of a pair under its canonical key, true if the key was already present.
+    fn insert(&mut self, pair: LeakagePair, genes: Genes) -> Result<bool, CountOverflow> {
+        match self.map.entry(self.schema.canonical(pair)) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().merge_from(&genes).map_err(|e| CountOverflow(format!("{} of pair {}", e.0, pair)))?;
+                Ok(true)
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(genes);
+                Ok(false)
+            },
+        }
+    }
+
+    /// Adds the pairs of another table, e.g. of another sample. Tables of another release are
+    /// refused, tables of different directionality or gene base are refused unless `coerce` is set, then their pairs are
+    /// canonicalized and their genes rebased to this table's schema.
+    pub fn merge(&mut self, other: Self, coerce: bool) -> Result<(), String> {
+        check_same_release(&self.release, &other.release)?;
+        check_mergeable(&self.schema, &other.schema, true, coerce)?;
+        self.schema.fractional |= other.schema.fractional;
+        self.schema.strand_bias &= other.schema.strand_bias;
+        for (pair, genes) in other.map {
+            let genes = match other.schema.gene_base == self.schema.gene_base {
+                true => genes,
+                false => genes.rebased(other.schema.gene_base, self.schema.gene_base),
+            };
+            self.insert(pair, genes).map_err(|e| e.to_string())?;
+        }
+        for (pair, lengths) in other.lengths {
+            self.lengths.entry(pair).or_default().merge(&lengths);
+        }
+        for (pair, reverse) in other.reverse {
+            *self.reverse.entry(self.schema.canonical(pair)).or_default() += reverse;
+        }
+        for (key, reads) in other.gene_mismatches {
+            *self.gene_mismatches.entry(key).or_default() += reads;
+        }
+        for (taxid, records) in other.unmapped {
+            let merged = self.unmapped.entry(taxid).or_default();
+            *merged = checked_count(*merged, records, format_args!("unmapped of {}", taxid)).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Same result as `load` followed by `normalize_incoming`, but for a pairwise table sorted by
+    /// the `from` column. Only one donor's pairs are held in memory at a time, so memory is bounded
+    /// by the number of recipients rather than the number of pairs. Input that is not sorted is
+    /// an error.
+    pub fn normalize_incoming_streaming(args: &Args, mut top_donors: Option<&mut TopDonors>, anomalies: &mut AnomalyLog) -> Result<HashMap<TinyTaxID, NormGenes>, InputError> {
+        let schema = NormalizationSchema::from_args(args);
+        let mut pairs = PairSchema::default();
+        let mut release = None;
+        let mut result = HashMap::default();
+        let mut group: Vec<(LeakagePair, Genes)> = Vec::new();
+        let mut iter = file_lines(&args.input)?;
+        anomalies.set_input(&args.input);
+
+        let mut line_no = 0;
+        while let Some(line) = table_line(iter.next(), line_no + 1, anomalies)? {
+            line_no += 1;
+            if line.starts_with('#') {
+                read_pairwise_header(&line, schema.self_pairs, &mut pairs, &mut release, &args.input)?;
+                continue
+            }
+            let row = pairwise_row(&line, &release, line_no, &args.input)?;
+            let (key, genes, _columns) = Self::parse_line(row, &pairs, line_no).map_err(|e| InputError::Invalid(format!("{}: {}", args.input, e)))?;
+            if let Some((last, _)) = group.last() {
+                if key.from < last.from {
+                    return Err(InputError::Invalid(format!("{}: line {}: not sorted by the from column ({} follows {}), --streaming needs the rows sorted by their first column (sort -k1,1n)", args.input, line_no, key.from, last.from)))
+                }
+                if key.from != last.from {
+                    Self::normalize_donor_into(&group, &mut result, &schema, top_donors.as_deref_mut(), anomalies)?;
+                    group.clear();
+                }
+            }
+            group.push((key, genes));
+        }
+        Self::normalize_donor_into(&group, &mut result, &schema, top_donors, anomalies)?;
+
+        Ok(result)
+    }
+
+    /// Adds the normalized contributions of all pairs of a single donor to `result`.
+    fn normalize_donor_into(group: &[(LeakagePair, Genes)], result: &mut HashMap<TinyTaxID, NormGenes>, schema: &NormalizationSchema, mut top_donors: Option<&mut TopDonors>, anomalies: &mut AnomalyLog) -> Result<(), AnomalyError> {
+        let mut normalizer = Genes::default();
+        for (pair, genes) in group.iter().filter(|(pair, _genes)| schema.self_pairs.in_denominators(pair)) {
+            normalizer.merge_from(genes).unwrap_or_else(|e| panic!("{} of donor {}", e, pair.from));
+        }
+
+        for (pair, genes) in group.iter().filter(|(pair, _genes)| schema.self_pairs.in_output(pair)) {
+            let entry: &mut NormGenes = result.entry(pair.to).or_insert_with(|| NormGenes::new(schema.with_denominators));
+            if entry.merge_normalized_from_counts(genes, &normalizer) > 0 {
+                anomalies.record(Anomaly::NonFiniteNormalization, &format!("pair {}", pair), None)?;
+            }
+            if let Some(top_donors) = top_donors.as_deref_mut() {
+                top_donors.offer(pair, genes, &normalizer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the `k` donors with the largest normalized contribution for every recipient.
+    pub fn top_donors(&self, k: usize, self_pairs: SelfPairPolicy) -> TopDonors {
+        let total_out = self.total_outgoing(self_pairs, false);
+        let mut result = TopDonors::new(k);
+
+        for (pair, genes) in &self.map {
+            result.offer(pair, genes, &total_out[&pair.from]);
+        }
+
+        result
+    }
+
+    /// Differences per pair and gene, pairs present on one side only are reported with their total.
+    pub fn diff(&self, other: &Self) -> Vec<Difference> {
+        diff_maps(&self.map, &other.map, "pair", |genes| genes.total().to_string(), |pair, l, r, result| {
+            result.extend(l.diff(r, pair));
+        })
+    }
+
+    /// Writes the self-pair, pair and release headers and the pairwise table (from, to, total,
+    /// genes...) sorted by recipient, total and donor.
+    pub fn write_pairwise(&self, self_pairs: SelfPairPolicy, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "{}", self_pairs)?;
+        writeln!(writer, "{}", self.schema)?;
+        if let Some(tag) = &self.release {
+            writeln!(writer, "{}", ReleaseHeader::Tag(tag.clone()))?;
+        }
+        self.write_rows(self_pairs, "", writer)
+    }
+
+    pub fn gene_memory(&self) -> GeneMemory {
+        let mut memory = GeneMemory::default();
+        self.map.values().for_each(|genes| memory.add(genes));
+        memory
+    }
+
+    /// Number of pairs of the output and their summed reads per bucket of pair totals, buckets
+    /// given by their ascending lower bounds (e.g. `TOTAL_BUCKETS`). Totals below the first bound
+    /// are left out.
+    pub fn total_histogram(&self, buckets: &[u64], self_pairs: SelfPairPolicy) -> Vec<TotalBucket> {
+        let mut result = buckets.iter().zip(buckets.iter().skip(1).map(|next| Some(next - 1)).chain([None]))
+            .map(|(min, max)| TotalBucket { min: *min, max, pairs: 0, reads: 0 })
+            .collect::<Vec<TotalBucket>>();
+        for genes in self.map.iter().filter(|(pair, _genes)| self_pairs.in_output(pair)).map(|(_pair, genes)| genes) {
+            let total = genes.total();
+            let Some(bucket) = result.iter_mut().rev().find(|bucket| bucket.min <= total) else { continue };
+            bucket.pairs += 1;
+            bucket.reads += total;
+        }
+        result
+    }
+
+    /// Writes the leaked reads of every pair with tracked lengths and their median aligned length
+    /// (from, to, leaked_reads, median_leak_len), sorted by pair. Pairs with a median below
+    /// `min_median` follow under a `#low_credibility` line instead of being left out. Returns the
+    /// number of credible and low credibility pairs.
+    pub fn write_leak_lengths(&self, min_median: Option<f64>, writer: &mut impl Write) -> std::io::Result<(usize, usize)> {
+        let mut pairs = self.lengths.iter().filter_map(|(pair, lengths)| Some((pair, lengths.count(), lengths.median()?))).collect::<Vec<_>>();
+        pairs.sort_by_key(|(pair, _reads, _median)| (pair.from, pair.to));
+        let (credible, low): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|(_pair, _reads, median)| min_median.is_none_or(|min| *median >= min));
+        writeln!(writer, "#from\tto\tleaked_reads\tmedian_leak_len")?;
+        for (section, rows) in [(None, &credible), (Some("#low_credibility"), &low)] {
+            if let Some(section) = section.filter(|_section| min_median.is_some()) {
+                writeln!(writer, "{}", section)?;
+            }
+            for (pair, reads, median) in rows {
+                writeln!(writer, "{}\t{}\t{}\t{}", pair.from, pair.to, reads, fmt_fixed(*median))?;
+            }
+        }
+        Ok((credible.len(), low.len()))
+    }
+
+    /// Writes the rows of the table, each led by `source`.
+    fn write_rows(&self, self_pairs: SelfPairPolicy, source: &str, writer: &mut impl Write) -> std::io::Result<usize> {
+        let mut vec = self.map.iter().filter(|(pair, _genes)| self_pairs.in_output(pair)).collect::<Vec<(&LeakagePair, &Genes)>>();
+        vec.sort_by_key(|l| (l.0.to, l.1.total(), l.0.from));
+        for (l, g) in &vec {
+            let row = g.row(self.schema.gene_base);
+            let (total, genes) = row.split_once('\t').unwrap_or((&row, ""));
+            let mut columns = String::new();
+            if self.schema.unmapped {
+                columns += &format!("\t{}", self.unmapped.get(&l.from).copied().unwrap_or(0));
+            }
+            if self.schema.strand_bias {
+                columns += &format!("\t{}", fmt_fixed(self.strand_bias(l, g)));
+            }
+            writeln!(writer, "{}{}\t{}\t{}{}\t{}", source, l.from, l.to, total, columns, genes)?
+        }
+        Ok(vec.len())
+    }
+
+    /// Fraction of the reads of a pair on the reverse strand, 0 for pairs without reads.
+    pub fn strand_bias(&self, pair: &LeakagePair, genes: &Genes) -> f64 {
+        let total = genes.weight_total();
+        match total > 0.0 {
+            true => self.reverse.get(pair).copied().unwrap_or(0.0) / total,
+            false => 0.0,
+        }
+    }
+
+    /// Merges (path, table) release by release in order of first appearance, see `merge`. The
+    /// tables of all releases are brought into the layout of the first table, tagged and untagged
+    /// tables are refused together.
+    pub fn merge_by_release(tables: impl IntoIterator<Item = (String, Self)>, coerce: bool) -> Result<Vec<Self>, String> {
+        let mut result: Vec<Self> = Vec::new();
+        let mut first: Option<(String, PairSchema, Option<String>)> = None;
+        for (path, table) in tables {
+            let (first_path, schema, first_release) = first.get_or_insert_with(|| (path.clone(), table.schema, table.release.clone()));
+            schema::check_release_mix([(first_path.as_str(), first_release.as_deref()), (path.as_str(), table.release.as_deref())])?;
+            let schema = *schema;
+            let index = match result.iter().position(|merged| merged.release == table.release) {
+                Some(index) => index,
+                None => {
+                    result.push(Self { map: HashMap::default(), schema, release: table.release.clone(), lengths: HashMap::default(), unmapped: HashMap::default(), reverse: HashMap::default(), gene_mismatches: HashMap::default() });
+                    result.len() - 1
+                },
+            };
+            result[index].merge(table, coerce).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        Ok(result)
+    }
+
+    /// Writes tables of tagged releases (see `merge_by_release`) as one: the headers of the
+    /// first table, the releases and the rows of every table led by its release.
+    pub fn write_releases(tables: &[Self], self_pairs: SelfPairPolicy, writer: &mut impl Write) -> std::io::Result<usize> {
+        let tags = tables.iter().map(|table| table.release.clone().unwrap_or_default()).collect::<Vec<String>>();
+        writeln!(writer, "{}", self_pairs)?;
+        writeln!(writer, "{}", tables.first().map(|table| table.schema).unwrap_or_default())?;
+        writeln!(writer, "{}", ReleaseHeader::PerRow(tags.clone()))?;
+        let mut rows = 0;
+        for (table, tag) in tables.iter().zip(&tags) {
+            rows += table.write_rows(self_pairs, &format!("{}\t", tag), writer)?;
+        }
+        Ok(rows)
+    }
+
+    /// Writes the (read group, table) tables of --by-read-group as one table with the read
+    /// groups listed in a `#read_groups` header, every row led by its read group.
+    pub fn write_read_groups(tables: &[(String, Self)], self_pairs: SelfPairPolicy, writer: &mut impl Write) -> std::io::Result<usize> {
+        let first = tables.first().map(|(_group, table)| table);
+        writeln!(writer, "{}", self_pairs)?;
+        writeln!(writer, "{}", first.map(|table| table.schema).unwrap_or_default())?;
+        if let Some(tag) = first.and_then(|table| table.release.as_ref()) {
+            writeln!(writer, "{}", ReleaseHeader::Tag(tag.clone()))?;
+        }
+        writeln!(writer, "{}{}", READ_GROUPS_PREFIX, itertools::join(tables.iter().map(|(group, _table)| group), "\t"))?;
+        let mut rows = 0;
+        for (group, table) in tables {
+            rows += table.write_rows(self_pairs, &format!("{}\t", group), writer)?;
+        }
+        Ok(rows)
+    }
+
+    /// Per-taxon read counters derived from the pairwise map, self-pairs counting as correct but
+    /// for their reads between two genes (`gene_mismatches`), which leak from the taxon to itself.
+    pub fn taxon_summary(&self) -> HashMap<TaxID, LeakageCounter> {
+        let mut result: HashMap<TaxID, LeakageCounter> = HashMap::default();
+        let mut mismatched: HashMap<TinyTaxID, f64> = HashMap::default();
+        for ((pair, _query_gene, _reference_gene), reads) in &self.gene_mismatches {
+            if pair.from == pair.to {
+                *mismatched.entry(pair.from).or_default() += reads;
+            }
+        }
+
+        for (pair, genes) in &self.map {
+            let total = genes.total();
+            let from = result.entry(pair.from as TaxID).or_default();
+            from.total += total;
+            if pair.from == pair.to {
+                let leaked = mismatched.get(&pair.from).map_or(0, |reads| reads.round() as u64).min(total);
+                from.correct += total - leaked;
+                from.out_incorrect += leaked;
+                from.in_incorrect += leaked;
+                continue
+            }
+            from.out_incorrect += total;
+            result.entry(pair.to as TaxID).or_default().in_incorrect += total;
+        }
+        if self.schema.unmapped {
+            for (taxid, records) in &self.unmapped {
+                result.entry(*taxid as TaxID).or_default().unmapped = Some(*records);
+            }
+            result.values_mut().for_each(|counter| { counter.unmapped.get_or_insert(0); });
+        }
+
+        result
+    }
+
+    /// Reads per donor and gene. Every donor has an entry, possibly empty when its self-pair is
+    /// excluded from the denominators. `ordered` as for `normalize_incoming`: fractional reads are
+    /// summed in pair order.
+    pub fn total_outgoing(&self, self_pairs: SelfPairPolicy, ordered: bool) -> HashMap<TinyTaxID, Genes> {
+        let mut result = HashMap::default();
+
+        for (pair, genes) in pair_entries(&self.map, ordered) {
+            let from = pair.from;
+            let entry: &mut Genes = result.entry(from).or_default();
+            if self_pairs.in_denominators(pair) {
+                entry.merge_from(genes).unwrap_or_else(|e| panic!("{} of donor {}", e, from));
+            }
+        }
+
+        result
+    }
+
+    /// Normalized incoming leakage per recipient. With `ordered` the pairs are added in (from, to)
+    /// order, so the float sums are the same in every run (--deterministic).
+    pub fn normalize_incoming(&self, schema: &NormalizationSchema, ordered: bool, anomalies: &mut AnomalyLog) -> Result<HashMap<TinyTaxID, NormGenes>, AnomalyError> {
+        let total_out = self.total_outgoing(schema.self_pairs, ordered);
+        let mut result = HashMap::default();
+
+        for (pair, genes) in pair_entries(&self.map, ordered).into_iter().filter(|(pair, _genes)| schema.self_pairs.in_output(pair)) {
+            let to: u32 = pair.to;
+            let normalizer = &total_out[&pair.from];
+
+            let entry: &mut NormGenes = result.entry(to).or_insert_with(|| NormGenes::new(schema.with_denominators));
+            if entry.merge_normalized_from_counts(genes, normalizer) > 0 {
+                anomalies.record(Anomaly::NonFiniteNormalization, &format!("pair {}", pair), None)?;
+            }
+
+        }
+
+        Ok(result)
+    }
+}
+
+/// Entries of a pair map in (from, to) order when `ordered`, else in map order, which differs
+/// between runs. Float sums over pairs depend on the order they are added in.
+pub fn pair_entries<V>(map: &HashMap<LeakagePair, V>, ordered: bool) -> Vec<(&LeakagePair, &V)> {
+    let mut result = map.iter().collect::<Vec<(&LeakagePair, &V)>>();
+    if ordered {
+        result.sort_unstable_by_key(|(pair, _value)| **pair);
+    }
+    result
+}
+
+/// Writes the schema header and normalized incoming leakage per recipient, sorted ascending by
+/// total and then by recipient. Values are clamped at output time under --clamp.
+pub fn write_normalized(normalized: HashMap<TinyTaxID, NormGenes>, schema: &NormalizationSchema, writer: &mut impl Write) -> std::io::Result<usize> {
+    writeln!(writer, "{}", schema)?;
+    let mut vec = normalized.into_iter().collect::<Vec<(TinyTaxID, NormGenes)>>();
+    vec.sort_by(|(a, ag), (b, bg)| ag.total().partial_cmp(&bg.total()).unwrap_or(Ordering::Equal).then(a.cmp(b)));
+    let mut clamped = 0;
+    for (l, g) in &vec {
+        writeln!(writer, "{}\t{}", l, g.render(schema.clamp.as_ref(), &mut clamped))?;
+    }
+    report_clamped(schema.clamp.as_ref(), clamped);
+    Ok(vec.len())
+}
+
+/// Reads a table written by `write_normalized`, ignoring denominator columns. Tables without
+/// a schema header are read as plain values normalized by donor outgoing totals, tables of
+/// `write_normalized_totals` are refused.
+pub fn read_normalized(path: impl AsRef<Path>) -> Result<(NormalizationSchema, HashMap<TinyTaxID, NormGenes>), String> {
+    let mut schema = NormalizationSchema { mode: NormalizationMode::DonorOutgoingTotal, with_denominators: false, self_pairs: SelfPairPolicy::default(), clamp: None };
+    let mut result = HashMap::default();
+    let lines = file_lines(&path).map_err(|e| e.to_string())?;
+    for (line_no, line) in lines.enumerate() {
+        let line = line.map_err(|e| format!("Cannot read {}: {}", path.as_ref().display(), e))?;
+        if let Some(parsed) = NormalizationSchema::parse(&line) {
+            schema = NormalizationSchema { self_pairs: schema.self_pairs, clamp: schema.clamp, ..parsed? };
+            if schema.mode == NormalizationMode::DonorOutgoingPairTotal {
+                return Err(format!("{} holds normalized pair totals (--no-genes) without gene columns", path.as_ref().display()))
+            }
+            continue
+        }
+        if let Some(parsed) = Clamp::parse(&line) {
+            schema.clamp = Some(parsed?);
+            continue
+        }
+        if let Some(parsed) = SelfPairPolicy::parse(&line) {
+            schema.self_pairs = parsed?;
+            continue
+        }
+        if line.starts_with('#') || line.is_empty() { continue };
+        let (recipient, genes) = NormGenes::parse_line(&line, &schema, line_no + 1).map_err(|e| format!("{}: {}", path.as_ref().display(), e))?;
+        result.insert(recipient, genes);
+    }
+    Ok((schema, result))
+}
+
+/// Number of pairs to pre-size pair maps with: --expected-pairs, or the estimate over the
+/// first --estimate-capacity megabytes of the SAM. None leaves the maps to grow on demand.
+fn expected_pairs(args: &Args) -> std::io::Result<Option<usize>> {
+    if args.expected_pairs.is_some() {
+        return Ok(args.expected_pairs)
+    }
+    let Some(megabytes) = args.estimate_capacity else { return Ok(None) };
+    if is_stdin(&args.input) {
+        eprintln!("Warning: --estimate-capacity cannot sample stdin ahead of counting, the pair map grows on demand");
+        return Ok(None)
+    }
+    let mut filter = RecordFilter::from_args(args);
+    let parse = sam_parser(args);
+    let estimate = estimate_capacity(&args.input, megabytes << 20, |line| {
+        if line.starts_with('@') { return None };
+        let sam = parse(line).ok()?;
+        if filter.check(&sam) != Decision::Keep { return None };
+        sam_to_ids(&sam, &filter.bounds, &mut filter.names).ok().map(|fromto| (fromto.query, fromto.reference))
+    })?;
+    eprintln!("Capacity estimate from {} sampled records: {} taxa, {} pairs in sample, {} records and {} pairs expected",
+        estimate.sampled_records, estimate.distinct_taxa, estimate.distinct_pairs, estimate.records, estimate.pairs);
+    Ok(Some(estimate.pairs))
+}
+
+/// Writes the pairs with the largest totals to `--preliminary-output` every `--flush-every`
+/// counted records while a pairwise table is being counted. The table starts with
+/// `#partial<TAB>records=N<TAB>pairs=K` followed by the usual pairwise headers. Write errors are
+/// only warned about, the run goes on.
+struct PreliminaryFlush {
+    every: Option<u64>,
+    counted: u64,
+    path: String,
+    self_pairs: SelfPairPolicy,
+}
+
+impl PreliminaryFlush {
+    const PREFIX: &'static str = "#partial\t";
+    const PAIRS: usize = 100;
+
+    fn from_args(args: &Args) -> Self {
+        Self { every: args.flush_every, counted: 0, path: args.preliminary_output.clone(), self_pairs: SelfPairPolicy::from_args(args) }
+    }
+
+    /// Counts a record and, at flush points, writes the rows built by `rows`.
+    fn tick(&mut self, rows: impl FnOnce() -> Vec<String>, schema: &PairSchema) {
+        self.counted += 1;
+        let Some(every) = self.every else { return };
+        if !self.counted.is_multiple_of(every) { return };
+        let rows = rows();
+        let written = write_atomically(&self.path, |writer| {
+            writeln!(writer, "{}records={}\tpairs={}", Self::PREFIX, self.counted, rows.len())?;
+            writeln!(writer, "{}", self.self_pairs)?;
+            writeln!(writer, "{}", schema)?;
+            rows.iter().try_for_each(|row| writeln!(writer, "{}", row))
+        });
+        if let Err(e) = written {
+            eprintln!("Warning: cannot write preliminary pairs: {}", e);
+        }
+    }
+}
+
+/// The `PreliminaryFlush::PAIRS` pairs with the largest totals, largest first, ties by pair.
+fn top_pairs<T>(map: &HashMap<LeakagePair, T>, total: impl Fn(&T) -> u64) -> Vec<(&LeakagePair, &T)> {
+    let mut heap = BinaryHeap::with_capacity(PreliminaryFlush::PAIRS + 1);
+    for (pair, value) in map {
+        heap.push(Reverse((total(value), Reverse(*pair))));
+        if heap.len() > PreliminaryFlush::PAIRS {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec().into_iter()
+        .map(|Reverse((_total, Reverse(pair)))| map.get_key_value(&pair).expect("Top pairs are keys of the map"))
+        .collect()
+}
+
+fn report_capacity(expected: Option<usize>, actual: usize) {
+    if let Some(expected) = expected {
+        eprintln!("Pair map sized for {} pairs, counted {}", expected, actual);
+    }
+}
+
+/// Reports once, after counting, the reads whose query gene is not their reference gene.
+fn report_gene_mismatches<'a>(tables: impl IntoIterator<Item = &'a Leakage>) {
+    let (mut reads, mut gene_pairs) = (0.0, 0);
+    for table in tables {
+        reads += table.gene_mismatches.values().sum::<f64>();
+        gene_pairs += table.gene_mismatches.len();
+    }
+    if gene_pairs > 0 {
+        eprintln!("Gene mismatch: {} reads aligned to a gene other than their query gene ({} distinct gene pairs), counted on the reference gene", reads, gene_pairs);
+    }
+}
+
+/// Reads kept for a query taxon, each as the pairs it contributes.
+type ReadReservoir = Reservoir<Vec<FromTo>>;
+
+/// Reads subsampled per query taxon with --equalize-depth: every taxon keeps at most `depth` of
+/// its reads, each with all the pairs it contributes. Pairs of single records are grouped into
+/// reads by `push` as `GroupByQname` groups them, the pairs of a read complete already (as
+/// --reconcile, --best-per-read and --multimap-weighting fraction hand them over) go to `offer`.
+struct DepthSampler {
+    depth: usize,
+    seed: u64,
+    groups: GroupByQname<FromTo>,
+    reservoirs: HashMap<TinyTaxID, ReadReservoir>,
+}
+
+impl DepthSampler {
+    /// None unless running with --equalize-depth.
+    fn from_args(args: &Args) -> Option<Self> {
+        let depth = args.equalize_depth?;
+        Some(Self { depth, seed: args.seed, groups: GroupByQname::from_args(args), reservoirs: HashMap::default() })
+    }
+
+    /// Adds the pair of a record of read `name`, read from input `record`. The pairs of the
+    /// previous read are offered once a record of another read arrives.
+    fn push(&mut self, name: &str, fromto: FromTo, record: usize, anomalies: &mut AnomalyLog) -> Result<(), AnomalyError> {
+        match self.groups.push(fromto.sample, name, fromto, record, anomalies)? {
+            Some(group) => self.complete(group, anomalies),
+            None => Ok(()),
+        }
+    }
+
+    fn complete(&mut self, group: QnameGroup<FromTo>, anomalies: &mut AnomalyLog) -> Result<(), AnomalyError> {
+        if group.is_oversized() {
+            let example = format!("read {}: {} alignments past --max-group-records {}, sampled with the buffered ones only", group.name, group.overflow, self.groups.max_records);
+            anomalies.record(Anomaly::OversizedGroup, &example, None)?;
+        }
+        self.offer(group.items);
+        Ok(())
+    }
+
+    /// Offers the pairs of one read to the reservoir of its query taxon.
+    fn offer(&mut self, pairs: Vec<FromTo>) {
+        let Some(query) = pairs.first().map(|fromto| fromto.query) else { return };
+        // Each taxon samples with its own generator so its reservoir does not depend on
+        // how its reads interleave with those of other taxa.
+        let (depth, seed) = (self.depth, self.seed);
+        self.reservoirs.entry(query)
+            .or_insert_with(|| Reservoir::new(depth, seed ^ (query as u64).wrapping_mul(0x9E3779B97F4A7C15)))
+            .offer(pairs);
+    }
+
+    /// Offers the read collected last and returns the reservoirs by taxon.
+    fn finish(mut self, anomalies: &mut AnomalyLog) -> Result<Vec<(TinyTaxID, ReadReservoir)>, AnomalyError> {
+        if let Some(group) = self.groups.finish() {
+            self.complete(group, anomalies)?;
+        }
+        let mut reservoirs = self.reservoirs.into_iter().collect::<Vec<(TinyTaxID, ReadReservoir)>>();
+        reservoirs.sort_by_key(|(taxon, _reservoir)| *taxon);
+        Ok(reservoirs)
+    }
+}
+
+/// Hands a pair to `add`, or to the depth sampler with --equalize-depth. The pair of a `record`
+/// (line and record) is logged against it and sampled with the other records of its read, a bare
+/// pair is the one pair of its read.
+fn offer_pair(fromto: FromTo, record: Option<(usize, &SamRef)>, debug: &mut DebugTaxa, sampler: &mut Option<DepthSampler>, anomalies: &mut AnomalyLog, add: &mut impl FnMut(&FromTo)) -> Result<(), AnomalyError> {
+    let Some((line, sam)) = record else {
+        offer_read(vec![fromto], debug, sampler, add);
+        return Ok(())
+    };
+    match sampler.as_mut() {
+        Some(sampler) => {
+            debug.record(line, sam, || format!("offered to reservoir of taxon {} with the alignments of its read", fromto.query));
+            sampler.push(sam.qname, fromto, line, anomalies)
+        },
+        None => {
+            debug.record(line, sam, || format!("counted pair {} gene {}", LeakagePair::from(fromto.query, fromto.reference), fromto.reference_gene));
+            add(&fromto);
+            Ok(())
+        },
+    }
+}
+
+/// Hands the pairs of a read to `add`, or to the depth sampler as one read with --equalize-depth.
+fn offer_read(pairs: Vec<FromTo>, debug: &mut DebugTaxa, sampler: &mut Option<DepthSampler>, add: &mut impl FnMut(&FromTo)) {
+    match sampler.as_mut() {
+        Some(sampler) => {
+            pairs.iter().for_each(|fromto| debug.pair(fromto, || format!("offered to reservoir of taxon {}", fromto.query)));
+            sampler.offer(pairs);
+        },
+        None => pairs.iter().for_each(|fromto| {
+            debug.pair(fromto, || format!("counted pair {} gene {}", LeakagePair::from(fromto.query, fromto.reference), fromto.reference_gene));
+            add(fromto);
+        }),
+    }
+}
+
+/// Offers the one pair a read classified by --reconcile contributes, if any.
+fn offer_reconciled(class: ReadClass, fromto: Option<FromTo>, debug: &mut DebugTaxa, sampler: &mut Option<DepthSampler>, add: &mut impl FnMut(&FromTo)) {
+    let Some(fromto) = fromto else { return };
+    debug.pair(&fromto, || format!("read classified {}", class));
+    offer_read(vec![fromto], debug, sampler, add);
+}
+
+/// Writer of --write-leaked-sam: the records counted on a pair of two taxa, verbatim after the
+/// header lines read before the first of them.
+struct LeakedSam<'a> {
+    path: &'a str,
+    writer: Box<dyn Write>,
+    header: usize,
+    records: u64,
+}
+
+impl<'a> LeakedSam<'a> {
+    fn from_args(args: &'a Args) -> Option<Self> {
+        let path = args.write_leaked_sam.as_deref()?;
+        let writer = create_output(path, args.threads, args.compression_level, !args.no_atomic).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        Some(Self { path, writer, header: 0, records: 0 })
+    }
+
+    /// Writes `sam` if its query and reference taxa differ, the header lines first.
+    fn offer(&mut self, fromto: &FromTo, sam: &SamRef, header: &SamHeader) {
+        if fromto.query == fromto.reference {
+            return
+        }
+        if self.records == 0 {
+            self.write_header(header);
+        }
+        writeln!(self.writer, "{}", sam.line).unwrap_or_else(|e| panic!("Error writing {}: {}", self.path, e));
+        self.records += 1;
+    }
+
+    fn write_header(&mut self, header: &SamHeader) {
+        for line in &header.lines {
+            writeln!(self.writer, "{}", line).unwrap_or_else(|e| panic!("Error writing {}: {}", self.path, e));
+        }
+        self.header = header.lines.len();
+    }
+
+    /// Flushes the file, with only the header if no record leaked, and reports its records.
+    fn finish(mut self, header: &SamHeader) {
+        if self.records == 0 {
+            self.write_header(header);
+        }
+        self.writer.flush().unwrap_or_else(|e| panic!("Error writing {}: {}", self.path, e));
+        if header.lines.len() > self.header {
+            eprintln!("Warning: {} header lines read after the first leaked record are missing from {}", header.lines.len() - self.header, self.path);
+        }
+        eprintln!("{}\t{} leaked records", self.path, self.records);
+    }
+}
+
+/// Reads the alignments of the SAM passing `filter` and hands their ids to `add`, subsampled per
+/// query taxon with --equalize-depth. With --reconcile each read contributes a single pair chosen
+/// over all of its alignments, with --best-per-read the pair of its best alignment, with
+/// --multimap-weighting fraction the pairs of all its alignments weighted 1/n.
+fn count_pairs(args: &Args, anomalies: &mut AnomalyLog, filter: &mut RecordFilter, mut add: impl FnMut(&FromTo)) -> Result<(Samples, SamHeader), InputError> {
+    let mut count_timer = Sampler::default();
+    let mut add = |fromto: &FromTo| count_timer.time(Phase::Count, || add(fromto));
+    let mut iter = sam_input(args)?;
+    anomalies.set_input(&args.input_label());
+    let mut debug = DebugTaxa::from_args(args).map_err(InputError::Invalid)?;
+
+    let mut sampler = DepthSampler::from_args(args);
+    let mut reconciler = Reconciler::from_args(args);
+    let mut best_per_read = BestPerRead::from_args(args);
+    let mut fraction_per_read = FractionPerRead::from_args(args);
+    let mut samples = Samples::from_args(args);
+    let mut leaked_sam = LeakedSam::from_args(args);
+
+    let mut line = String::new();
+    while let Some(mut sam) = iter.next_valid_ref(&mut line, anomalies)? {
+        if let Decision::Skip(reason) = filter.evaluate(&sam) {
+            debug.record(iter.line, &sam, || format!("skipped: {} (mapq {}, min {})", reason, sam.mapq, filter.min_mapq));
+            continue
+        };
+        let sample = samples.assign(&mut sam);
+        let fromto = match sam_to_ids(&sam, &filter.bounds, &mut filter.names) {
+            Ok(fromto) => FromTo { sample, aligned_length: args.tracks_leak_lengths().then(|| sam.aligned_length()).flatten(), reverse: args.strand_bias.then(|| sam.is_reverse()), ..fromto },
+            Err(e) => {
+                debug.record(iter.line, &sam, || format!("skipped: {}", e));
+                anomalies.record(Anomaly::UnparseableName, &e, Some(iter.line))?;
+                continue
+            },
+        };
+        if let Decision::Skip(reason) = filter.evaluate_pair(&fromto) {
+            debug.record(iter.line, &sam, || format!("skipped: {}", reason));
+            continue
+        };
+
+        if let Some(best_per_read) = best_per_read.as_mut() {
+            debug.record(iter.line, &sam, || "grouped with the alignments of its read (--best-per-read)".to_string());
+            if let Some(fromto) = best_per_read.push(fromto.sample, &sam, fromto, iter.line, anomalies)? {
+                offer_read(vec![fromto], &mut debug, &mut sampler, &mut add);
+            }
+            continue
+        }
+        if let Some(fraction_per_read) = fraction_per_read.as_mut() {
+            debug.record(iter.line, &sam, || "grouped with the alignments of its read (--multimap-weighting fraction)".to_string());
+            let pairs = fraction_per_read.push(fromto.sample, sam.qname, fromto, iter.line, anomalies)?;
+            offer_read(pairs.into_iter().map(|(fromto, weight)| FromTo { weight: Some(weight), ..fromto }).collect(), &mut debug, &mut sampler, &mut add);
+            continue
+        }
+        let Some(reconciler) = reconciler.as_mut() else {
+            if let Some(leaked_sam) = leaked_sam.as_mut() {
+                leaked_sam.offer(&fromto, &sam, &iter.header);
+            }
+            offer_pair(fromto, Some((iter.line, &sam)), &mut debug, &mut sampler, anomalies, &mut add)?;
+            continue
+        };
+        debug.record(iter.line, &sam, || "grouped with the alignments of its read (--reconcile)".to_string());
+        if let Some((class, fromto)) = reconciler.push(sam.qname, fromto, sam.mapq, iter.line, anomalies)? {
+            offer_reconciled(class, fromto, &mut debug, &mut sampler, &mut add);
+        }
+    }
+    if let Some(reconciler) = reconciler.as_mut() {
+        if let Some((class, fromto)) = reconciler.finish(anomalies)? {
+            offer_reconciled(class, fromto, &mut debug, &mut sampler, &mut add);
+        }
+        eprintln!("Reads: {}", reconciler);
+    }
+    if let Some(best_per_read) = best_per_read.as_mut() {
+        if let Some(fromto) = best_per_read.finish(anomalies)? {
+            offer_read(vec![fromto], &mut debug, &mut sampler, &mut add);
+        }
+        eprintln!("Reads: {}", best_per_read);
+    }
+    if let Some(fraction_per_read) = fraction_per_read.as_mut() {
+        let pairs = fraction_per_read.finish(anomalies)?;
+        offer_read(pairs.into_iter().map(|(fromto, weight)| FromTo { weight: Some(weight), ..fromto }).collect(), &mut debug, &mut sampler, &mut add);
+        eprintln!("Reads: {}", fraction_per_read);
+    }
+
+    if let Some(sampler) = sampler {
+        let reservoirs = sampler.finish(anomalies)?;
+
+        if let Some(path) = &args.equalize_depth_report {
+            let mut writer = create_output(path, args.threads, args.compression_level, !args.no_atomic).unwrap_or_else(|e| panic!("{}", e));
+            for (taxon, reservoir) in &reservoirs {
+                writeln!(writer, "{}\t{}\t{}", taxon, reservoir.items().len(), reservoir.seen()).expect("Error writing depth report");
+            }
+        }
+
+        for (_taxon, reservoir) in &reservoirs {
+            reservoir.items().iter().flatten().for_each(|fromto| {
+                debug.pair(fromto, || format!("counted pair {} gene {} after subsampling", LeakagePair::from(fromto.query, fromto.reference), fromto.reference_gene));
+                add(fromto)
+            });
+        }
+    }
+    if let Some(leaked_sam) = leaked_sam {
+        leaked_sam.finish(&iter.header);
+    }
+    eprintln!("Records: {}", filter);
+    filter.check_gene_ids(anomalies)?;
+    Ok((samples, std::mem::take(&mut iter.header)))
+}
+
+/// Parses a record line strictly or, with --lenient, accepting missing fields after MAPQ.
+fn sam_parser(args: &Args) -> fn(&str) -> Result<SamRef<'_>, String> {
+    match args.lenient {
+        true => |line| SamRef::from_line_lenient(line),
+        false => |line| SamRef::from_line(line),
+    }
+}
+
+/// Records per chunk handed from the reader to the parsing threads.
+const PARSE_CHUNK: usize = 10_000;
+
+/// (line, byte offset, line) of the records of a chunk, with the index of their input.
+type Chunk = (usize, Vec<(usize, u64, String)>);
+
+/// Whether `count_pairs_parallel` can stand in for `count_pairs`: more than one thread and none
+/// of the options that depend on the order of the records (--reconcile, --best-per-read,
+/// --multimap-weighting other than count, --equalize-depth, --split-by, --debug-taxon, --flush-every).
+fn counts_in_parallel(args: &Args) -> bool {
+    args.threads > 1 && !args.reconcile && args.multimap_weighting == MultimapWeighting::Count && !args.keeps_best_per_read() && args.equalize_depth.is_none() && args.split_by.is_none() && !args.by_read_group && args.write_leaked_sam.is_none() && args.debug_taxon.is_empty() && args.flush_every.is_none()
+}
+
+/// Threads of `count_pairs_parallel`: inputs read at the same time, a thread decompressing each,
+/// and threads parsing and counting their records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputThreads {
+    pub readers: usize,
+    pub parsers: usize,
+}
+
+/// Threads for `requested` --threads over inputs of `codecs`, None for stdin, whose codec is not
+/// known before it is read. Inputs are read in parallel first, up to one per requested thread.
+/// When every input read at a time is compressed, decompression bounds the throughput: parsing
+/// threads past those the readers keep busy would only wait.
+pub fn input_threads(requested: usize, codecs: &[Option<Codec>]) -> InputThreads {
+    let requested = requested.max(1);
+    let readers = codecs.len().clamp(1, requested);
+    let mut kept_busy = codecs.iter().map(|codec| match codec {
+        None | Some(Codec::Plain) => usize::MAX,
+        Some(Codec::Zstd) => 4,
+        Some(Codec::Gzip) => 2,
+        Some(Codec::Bzip2) => 1,
+    }).collect::<Vec<usize>>();
+    // The inputs keeping the most threads busy, as the readers are read in turn
+    kept_busy.sort_unstable_by(|left, right| right.cmp(left));
+    let kept_busy = match codecs.is_empty() {
+        true => usize::MAX,
+        false => kept_busy.iter().take(readers).fold(0, |sum: usize, busy| sum.saturating_add(*busy)),
+    };
+    InputThreads { readers, parsers: requested.min(kept_busy) }
+}
+
+/// `input_threads` for the inputs of `args`, saying so when fewer than --threads parse records.
+fn args_input_threads(args: &Args) -> Result<InputThreads, InputError> {
+    let codecs = args.input_files().into_iter()
+        .map(|path| if is_stdin(path) { Ok(None) } else { Codec::of_file(path).map(Some) })
+        .collect::<std::io::Result<Vec<Option<Codec>>>>()?;
+    let threads = input_threads(args.threads, &codecs);
+    if threads.readers > 1 {
+        eprintln!("Reading {} of {} inputs at a time", threads.readers, codecs.len());
+    }
+    if threads.parsers < args.threads {
+        eprintln!("Parsing records on {} of --threads {} threads: every input is compressed, and the {} decompressing them cannot keep more busy",
+            threads.parsers, args.threads, if threads.readers > 1 { format!("{} threads", threads.readers) } else { "thread".to_string() });
+    }
+    Ok(threads)
+}
+
+/// An anomaly found by a reading or parsing thread, at a record of one of the inputs. Logged once
+/// every input is read, when the records of the inputs before it are counted.
+struct Found {
+    input: usize,
+    line: usize,
+    category: Anomaly,
+    /// Byte offset of the record in its input, for the example to start with
+    offset: Option<u64>,
+    example: String,
+}
+
+/// The anomalies a thread found, kept as the summary of `AnomalyLog` keeps them: per category
+/// their count and the first in record order. Memory does not grow with the anomalies of an input
+/// read with --skip-invalid.
+#[derive(Default)]
+struct FoundAnomalies(HashMap<Anomaly, (usize, Found)>);
+
+impl FoundAnomalies {
+    fn push(&mut self, found: Found) {
+        self.add(1, found)
+    }
+
+    fn add(&mut self, count: usize, found: Found) {
+        match self.0.entry(found.category) {
+            Entry::Occupied(mut entry) => {
+                let (total, first) = entry.get_mut();
+                *total += count;
+                if (found.input, found.line) < (first.input, first.line) {
+                    *first = found;
+                }
+            },
+            Entry::Vacant(entry) => { entry.insert((count, found)); },
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (_category, (count, found)) in other.0 {
+            self.add(count, found);
+        }
+    }
+
+    /// (count, first) per category, in the order of the first anomalies.
+    fn into_sorted(self) -> Vec<(usize, Found)> {
+        let mut result = self.0.into_values().collect::<Vec<_>>();
+        result.sort_by_key(|(_count, found)| (found.input, found.line));
+        result
+    }
+}
+
+/// `count_pairs` with the inputs read by `threads.readers` threads, each decompressing one input
+/// at a time, and their records parsed and counted on `threads.parsers` threads: each counts into
+/// a table of its own made by `empty`, the tables are merged with `merge` at the end. Anomalies
+/// are logged once all records are counted, per category with their count and the first in
+/// record order, numbered and placed as if the inputs were read one after another. An anomaly
+/// that fails the read (see `AnomalyLog::is_fatal`) stops reading its input and the ones after,
+/// the inputs before are still read for an earlier one, so the run fails with the same anomaly as
+/// a serial one. A truncated input ends only itself, a serial run ends all inputs with it.
+fn count_pairs_parallel<T: Send>(args: &Args, threads: InputThreads, anomalies: &mut AnomalyLog, filter: &mut RecordFilter, empty: impl Fn() -> T + Sync, add: impl Fn(&mut T, &FromTo) + Sync, mut merge: impl FnMut(T)) -> Result<SamHeader, InputError> {
+    let inputs = sam_inputs(args)?;
+    anomalies.set_input(&args.input_label());
+
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<Chunk>(threads.parsers * 2);
+    let receiver = std::sync::Mutex::new(receiver);
+    let pending = std::sync::Mutex::new(inputs.into_iter().enumerate().collect::<VecDeque<(usize, SamReader)>>());
+    // The first input with a fatal anomaly, the reading threads read none after it
+    let stop = std::sync::atomic::AtomicUsize::new(usize::MAX);
+    let fatal = |found: &Found| if anomalies.is_fatal(found.category) {
+        stop.fetch_min(found.input, std::sync::atomic::Ordering::Relaxed);
+    };
+    let template = filter.clone();
+    let parse = sam_parser(args);
+    let work = || {
+        let (mut table, mut filter, mut found) = (empty(), template.clone(), FoundAnomalies::default());
+        let mut push = |anomaly: Found| {
+            fatal(&anomaly);
+            found.push(anomaly)
+        };
+        loop {
+            // The lock is released before the chunk is parsed
+            let next = receiver.lock().expect("Parsing thread panicked").recv();
+            let Ok((input, chunk)) = next else { break };
+            for (line_no, offset, line) in chunk {
+                let sam = match parse(&line) {
+                    Ok(sam) => sam,
+                    Err(e) => {
+                        push(Found { input, line: line_no, category: Anomaly::InvalidRecord, offset: Some(offset), example: e });
+                        continue
+                    },
+                };
+                if sam.is_unmapped() == sam.is_aligned() {
+                    push(Found { input, line: line_no, category: Anomaly::FlagRnameMismatch, offset: None, example: format!("{} flag {} rname {}", sam.qname, sam.flag, sam.rname) });
+                }
+                if filter.evaluate(&sam) != Decision::Keep { continue };
+                let fromto = match sam_to_ids(&sam, &filter.bounds, &mut filter.names) {
+                    Ok(fromto) => FromTo { aligned_length: args.tracks_leak_lengths().then(|| sam.aligned_length()).flatten(), reverse: args.strand_bias.then(|| sam.is_reverse()), ..fromto },
+                    Err(e) => {
+                        push(Found { input, line: line_no, category: Anomaly::UnparseableName, offset: None, example: e });
+                        continue
+                    },
+                };
+                if filter.evaluate_pair(&fromto) != Decision::Keep { continue };
+                add(&mut table, &fromto);
+            }
+        }
+        (table, filter, found)
+    };
+    // Reads the inputs left one at a time, returning each reader once it is done with
+    let read = |sender: std::sync::mpsc::SyncSender<Chunk>| {
+        let (mut done_with, mut found) = (Vec::new(), FoundAnomalies::default());
+        while let Some((input, mut iter)) = { let next = pending.lock().expect("Reading thread panicked").pop_front(); next } {
+            let mut done = false;
+            while !done && input < stop.load(std::sync::atomic::Ordering::Relaxed) {
+                let mut chunk = Vec::with_capacity(PARSE_CHUNK);
+                while chunk.len() < PARSE_CHUNK {
+                    let category = match iter.next_record_line() {
+                        None => { done = true; break },
+                        Some(Ok(line)) => { chunk.push((iter.line, iter.offset, line)); continue },
+                        Some(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => (Anomaly::InvalidRecord, e),
+                        Some(Err(e)) if e.kind() == std::io::ErrorKind::FileTooLarge => (Anomaly::OversizedLine, e),
+                        Some(Err(e)) => (Anomaly::TruncatedInput, e),
+                    };
+                    let (category, e) = category;
+                    // Invalid UTF-8 is reported at the offset of its line, placed in all inputs below
+                    let e = e.to_string();
+                    let (offset, example) = match e.strip_prefix(&format!("byte {}: ", iter.offset)) {
+                        Some(example) if category == Anomaly::InvalidRecord => (Some(iter.offset), example.to_string()),
+                        _ => (None, e.clone()),
+                    };
+                    let anomaly = Found { input, line: iter.line, category, offset, example };
+                    fatal(&anomaly);
+                    found.push(anomaly);
+                    if category == Anomaly::TruncatedInput {
+                        done = true;
+                        break
+                    }
+                }
+                // A closed channel means a parsing thread panicked, which its join below propagates
+                if sender.send((input, chunk)).is_err() { break };
+            }
+            done_with.push((input, iter));
+        }
+        (done_with, found)
+    };
+
+    let (results, readers) = std::thread::scope(|scope| {
+        let workers = (0..threads.parsers).map(|_| scope.spawn(work)).collect::<Vec<_>>();
+        let readers = (0..threads.readers).map(|_| {
+            let sender = sender.clone();
+            scope.spawn(|| read(sender))
+        }).collect::<Vec<_>>();
+        drop(sender);
+        let readers = readers.into_iter().map(|reader| reader.join().unwrap_or_else(|e| std::panic::resume_unwind(e))).collect::<Vec<_>>();
+        let results = workers.into_iter().map(|worker| worker.join().unwrap_or_else(|e| std::panic::resume_unwind(e))).collect::<Vec<_>>();
+        (results, readers)
+    });
+
+    let (mut inputs, mut found) = (Vec::new(), FoundAnomalies::default());
+    for (done_with, reader_found) in readers {
+        inputs.extend(done_with);
+        found.merge(reader_found);
+    }
+    inputs.sort_by_key(|(input, _iter)| *input);
+    for (table, worker_filter, worker_found) in results {
+        merge(table);
+        filter.merge_counts(&worker_filter);
+        found.merge(worker_found);
+    }
+    // Records and bytes of the inputs before each, as numbered in one stream
+    let (mut lines, mut bytes) = (vec![0], vec![0]);
+    for (_input, iter) in &inputs {
+        // The line counter has moved past the end of the input
+        lines.push(lines.last().unwrap() + iter.line.saturating_sub(1));
+        bytes.push(bytes.last().unwrap() + iter.consumed());
+    }
+    for (count, found) in found.into_sorted() {
+        let example = match found.offset {
+            Some(offset) => format!("byte {}: {}", bytes[found.input] + offset, found.example),
+            None => found.example,
+        };
+        anomalies.record_many(found.category, &example, Some(lines[found.input] + found.line), count)?;
+    }
+    eprintln!("Records: {}", filter);
+    filter.check_gene_ids(anomalies)?;
+    let mut header = SamHeader::default();
+    for (_input, mut iter) in inputs {
+        header.merge(std::mem::take(&mut iter.header));
+    }
+    Ok(header)
+}
+
+/// Pair totals without gene resolution (--no-genes), a fraction of the memory of `Leakage`.
+#[derive(Default, PartialEq, Debug)]
+pub struct LeakageTotals {
+    pub map: HashMap<LeakagePair, u64>,
+    pub schema: PairSchema,
+    /// Release tag of the table (--release-tag)
+    pub release: Option<String>,
+}
+
+impl LeakageTotals {
+    pub fn from_sam(args: &Args, anomalies: &mut AnomalyLog) -> Result<Self, InputError> {
+        let expected = expected_pairs(args)?;
+        let mut res = Self { map: HashMap::with_capacity(expected.unwrap_or(0)), schema: PairSchema::from_args(args), release: args.release_tag.clone() };
+        let mut flush = PreliminaryFlush::from_args(args);
+        count_pairs(args, anomalies, &mut RecordFilter::from_args(args), |fromto| {
+            res.add(fromto);
+            flush.tick(|| top_pairs(&res.map, |total| *total).into_iter()
+                .map(|(pair, total)| format!("{}\t{}\t{}", pair.from, pair.to, total))
+                .collect(), &res.schema);
+        })?;
+        report_capacity(expected, res.map.len());
+        Ok(res)
+    }
+
+    /// Same as `Leakage::from_sam_by_sample`.
+    pub fn from_sam_by_sample(args: &Args, anomalies: &mut AnomalyLog) -> Result<Vec<(String, Self)>, InputError> {
+        let empty = || Self { map: HashMap::default(), schema: PairSchema::from_args(args), release: args.release_tag.clone() };
+        let mut tables = Vec::new();
+        let (samples, _header) = count_pairs(args, anomalies, &mut RecordFilter::from_args(args), |fromto| sample_table(&mut tables, fromto.sample, empty).add(fromto))?;
+        Ok(name_tables(samples, tables, empty))
+    }
+
+    /// Counts only records whose query and reference both belong to `taxa`.
+    pub fn from_sam_within(args: &Args, anomalies: &mut AnomalyLog, taxa: &HashSet<TinyTaxID>) -> Result<Self, InputError> {
+        let mut res = Self::default();
+        let mut filter = RecordFilter::from_args(args).with_taxa(taxa.clone());
+        count_pairs(args, anomalies, &mut filter, |fromto| res.add(fromto))?;
+        Ok(res)
+    }
+
+    pub fn add(&mut self, fromto: &FromTo) {
+        *self.map.entry(LeakagePair::from(fromto.query, fromto.reference)).or_default() += 1;
+    }
+
+    /// Drops all pairs with a taxon outside of `taxa`.
+    pub fn retain_within(&mut self, taxa: &HashSet<TinyTaxID>) {
+        self.map.retain(|pair, _total| taxa.contains(&pair.from) && taxa.contains(&pair.to));
+    }
+
+    pub fn load(args: &Args, anomalies: &mut AnomalyLog) -> Result<Self, InputError> {
+        Self::read(&args.input, SelfPairPolicy::from_args(args), anomalies)
+    }
+
+    /// Reads the from, to and total columns of a pairwise table, gene columns are ignored. Keys are
+    /// canonicalized by the directionality of its pair header and pairs listed more than once are
+    /// summed with a warning. A read error ends the table as truncated input, see `table_line`.
+    pub fn read(path: &str, self_pairs: SelfPairPolicy, anomalies: &mut AnomalyLog) -> Result<Self, InputError> {
+        let mut result = Self::default();
+        let mut duplicates = 0;
+        let mut release = None;
+        let mut lines = file_lines(path)?;
+        anomalies.set_input(path);
+        let mut line_no = 0;
+        while let Some(line) = table_line(lines.next(), line_no + 1, anomalies)? {
+            line_no += 1;
+            if line.starts_with('#') {
+                read_pairwise_header(&line, self_pairs, &mut result.schema, &mut release, path)?;
+                continue
+            }
+            if line.is_empty() { continue };
+            let tokens = pairwise_row(&line, &release, line_no, path)?.split('\t').take(3).enumerate()
+                .map(|(column, x)| schema::PAIRWISE.parse::<u64>(x, column, line_no))
+                .collect::<Result<Vec<u64>, NumericError>>()
+                .map_err(|e| InputError::Invalid(format!("{}: {}", path, e)))?;
+            if tokens.len() < 3 {
+                return Err(InputError::Invalid(format!("{}: line {} has fewer than 3 columns", path, line_no)))
+            }
+            if result.insert(LeakagePair::from(tokens[0] as TinyTaxID, tokens[1] as TinyTaxID), tokens[2]).map_err(|e| InputError::Invalid(format!("{}: line {}: {}", path, line_no, e)))? {
+                duplicates += 1;
+            }
+        }
+        if duplicates > 0 {
+            eprintln!("Warning: {} lists {} pairs more than once, their totals were summed", path, duplicates);
+        }
+        result.release = release_tag(release);
+        Ok(result)
+    }
+
+    /// Adds a total under the canonical key of a pair, true if the key was already present.
+    fn insert(&mut self, pair: LeakagePair, total: u64) -> Result<bool, CountOverflow> {
+        let key = self.schema.canonical(pair);
+        let present = self.map.contains_key(&key);
+        let entry = self.map.entry(key).or_default();
+        *entry = checked_count(*entry, total, format_args!("pair {}", pair))?;
+        Ok(present)
+    }
+
+    /// Adds the pairs of another table, e.g. of another sample. Tables of another release are
+    /// refused, tables of different directionality are refused unless `coerce` is set, then their pairs are canonicalized to
+    /// this table's schema.
+    pub fn merge(&mut self, other: Self, coerce: bool) -> Result<(), String> {
+        check_same_release(&self.release, &other.release)?;
+        check_mergeable(&self.schema, &other.schema, false, coerce)?;
+        for (pair, total) in other.map {
+            self.insert(pair, total).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the self-pair, pair and release headers and the pairwise table (from, to, total)
+    /// sorted by recipient, total and donor.
+    pub fn write_pairwise(&self, self_pairs: SelfPairPolicy, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "{}", self_pairs)?;
+        writeln!(writer, "{}", self.schema)?;
+        if let Some(tag) = &self.release {
+            writeln!(writer, "{}", ReleaseHeader::Tag(tag.clone()))?;
+        }
+        self.write_rows(self_pairs, "", writer)
+    }
+
+    /// Writes the rows of the table, each led by `source`.
+    fn write_rows(&self, self_pairs: SelfPairPolicy, source: &str, writer: &mut impl Write) -> std::io::Result<usize> {
+        let mut vec = self.map.iter().filter(|(pair, _total)| self_pairs.in_output(pair)).collect::<Vec<(&LeakagePair, &u64)>>();
+        vec.sort_by_key(|(pair, total)| (pair.to, **total, pair.from));
+        for (pair, total) in &vec {
+            writeln!(writer, "{}{}\t{}\t{}", source, pair.from, pair.to, total)?;
+        }
+        Ok(vec.len())
+    }
+
+    /// Same as `Leakage::merge_by_release`.
+    pub fn merge_by_release(tables: impl IntoIterator<Item = (String, Self)>, coerce: bool) -> Result<Vec<Self>, String> {
+        let mut result: Vec<Self> = Vec::new();
+        let mut first: Option<(String, PairSchema, Option<String>)> = None;
+        for (path, table) in tables {
+            let (first_path, schema, first_release) = first.get_or_insert_with(|| (path.clone(), table.schema, table.release.clone()));
+            schema::check_release_mix([(first_path.as_str(), first_release.as_deref()), (path.as_str(), table.release.as_deref())])?;
+            let schema = *schema;
+            let index = match result.iter().position(|merged| merged.release == table.release) {
+                Some(index) => index,
+                None => {
+                    result.push(Self { map: HashMap::default(), schema, release: table.release.clone() });
+                    result.len() - 1
+                },
+            };
+            result[index].merge(table, coerce).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        Ok(result)
+    }
+
+    /// Same as `Leakage::write_releases`.
+    pub fn write_releases(tables: &[Self], self_pairs: SelfPairPolicy, writer: &mut impl Write) -> std::io::Result<usize> {
+        let tags = tables.iter().map(|table| table.release.clone().unwrap_or_default()).collect::<Vec<String>>();
+        writeln!(writer, "{}", self_pairs)?;
+        writeln!(writer, "{}", tables.first().map(|table| table.schema).unwrap_or_default())?;
+        writeln!(writer, "{}", ReleaseHeader::PerRow(tags.clone()))?;
+        let mut rows = 0;
+        for (table, tag) in tables.iter().zip(&tags) {
+            rows += table.write_rows(self_pairs, &format!("{}\t", tag), writer)?;
+        }
+        Ok(rows)
+    }
+
+    /// Same as `Leakage::write_read_groups`.
+    pub fn write_read_groups(tables: &[(String, Self)], self_pairs: SelfPairPolicy, writer: &mut impl Write) -> std::io::Result<usize> {
+        let first = tables.first().map(|(_group, table)| table);
+        writeln!(writer, "{}", self_pairs)?;
+        writeln!(writer, "{}", first.map(|table| table.schema).unwrap_or_default())?;
+        if let Some(tag) = first.and_then(|table| table.release.as_ref()) {
+            writeln!(writer, "{}", ReleaseHeader::Tag(tag.clone()))?;
+        }
+        writeln!(writer, "{}{}", READ_GROUPS_PREFIX, itertools::join(tables.iter().map(|(group, _table)| group), "\t"))?;
+        let mut rows = 0;
+        for (group, table) in tables {
+            rows += table.write_rows(self_pairs, &format!("{}\t", group), writer)?;
+        }
+        Ok(rows)
+    }
+
+    /// Per-taxon read counters, self-pairs counting as correct. Same as `Leakage::taxon_summary`.
+    pub fn taxon_summary(&self) -> HashMap<TaxID, LeakageCounter> {
+        let mut result: HashMap<TaxID, LeakageCounter> = HashMap::default();
+
+        for (pair, total) in &self.map {
+            let total = *total;
+            let from = result.entry(pair.from as TaxID).or_default();
+            from.total += total;
+            if pair.from == pair.to {
+                from.correct += total;
+                continue
+            }
+            from.out_incorrect += total;
+            result.entry(pair.to as TaxID).or_default().in_incorrect += total;
+        }
+
+        result
+    }
+
+    /// Reads per donor, every donor has an entry (zero when only its self-pair is excluded).
+    pub fn total_outgoing(&self, self_pairs: SelfPairPolicy) -> HashMap<TinyTaxID, u64> {
+        let mut result: HashMap<TinyTaxID, u64> = HashMap::default();
+        for (pair, total) in &self.map {
+            let entry = result.entry(pair.from).or_default();
+            if self_pairs.in_denominators(pair) {
+                *entry += total;
+            }
+        }
+        result
+    }
+
+    /// Incoming leakage per recipient as the sum of pair total / donor outgoing total. Unlike the
+    /// per-gene normalization this divides whole pair totals, so genes are not weighted equally.
+    /// `ordered` as for `Leakage::normalize_incoming`.
+    pub fn normalize_incoming(&self, self_pairs: SelfPairPolicy, ordered: bool, anomalies: &mut AnomalyLog) -> Result<HashMap<TinyTaxID, f64>, AnomalyError> {
+        let total_out = self.total_outgoing(self_pairs);
+        let mut result: HashMap<TinyTaxID, f64> = HashMap::default();
+
+        for (pair, total) in pair_entries(&self.map, ordered).into_iter().filter(|(pair, _total)| self_pairs.in_output(pair)) {
+            let res = *total as f64 / total_out[&pair.from] as f64;
+            let entry = result.entry(pair.to).or_default();
+            if !res.is_finite() {
+                anomalies.record(Anomaly::NonFiniteNormalization, &format!("pair {}", pair), None)?;
+                continue
+            }
+            *entry += res;
+        }
+
+        Ok(result)
+    }
+
+    pub fn diff(&self, other: &Self) -> Vec<Difference> {
+        diff_maps(&self.map, &other.map, "pair", |total| total.to_string(), |pair, l, r, result| {
+            result.extend(Difference::exact(pair, "total", l, r));
+        })
+    }
+}
+
+impl From<&Leakage> for LeakageTotals {
+    fn from(leakage: &Leakage) -> Self {
+        Self { map: leakage.map.iter().map(|(pair, genes)| (*pair, genes.total())).collect(), schema: leakage.schema, release: leakage.release.clone() }
+    }
+}
+
+/// Symmetric ambiguity matrix: for every unordered pair of taxa (keyed canonically, from < to), the
+/// number of reads with alignments against both. Expects name-grouped input, i.e. all alignments of
+/// a read in a row as bowtie2 -k writes them. Reads hitting more than `--max-group-size` distinct
+/// taxa or with more than `--max-group-records` alignments are skipped; their number is returned
+/// alongside the matrix.
+pub fn ambiguity_from_sam(args: &Args, anomalies: &mut AnomalyLog) -> Result<(LeakageTotals, usize), InputError> {
+    let mut iter = sam_input(args)?;
+    anomalies.set_input(&args.input_label());
+
+    let mut filter = RecordFilter::from_args(args);
+    let mut result = LeakageTotals { schema: PairSchema::undirected(), release: args.release_tag.clone(), ..LeakageTotals::default() };
+    let mut skipped = 0;
+    let mut groups: GroupByQname<TinyTaxID> = GroupByQname::from_args(args);
+
+    let mut flush = |group: Option<QnameGroup<TinyTaxID>>, result: &mut LeakageTotals, anomalies: &mut AnomalyLog| -> Result<(), AnomalyError> {
+        let Some(mut group) = group else { return Ok(()) };
+        if group.is_oversized() {
+            let example = format!("read {}: {} alignments past --max-group-records {}, skipped as ambiguous", group.name, group.overflow, args.max_group_records);
+            anomalies.record(Anomaly::OversizedGroup, &example, None)?;
+            skipped += 1;
+            return Ok(())
+        }
+        let taxa = &mut group.items;
+        taxa.sort_unstable();
+        taxa.dedup();
+        if taxa.len() > args.max_group_size {
+            skipped += 1;
+        } else {
+            for (i, a) in taxa.iter().enumerate() {
+                for b in &taxa[i + 1..] {
+                    *result.map.entry(LeakagePair::from(*a, *b)).or_default() += 1;
+                }
+            }
+        }
+        Ok(())
+    };
+
+    let mut line = String::new();
+    while let Some(sam) = iter.next_valid_ref(&mut line, anomalies)? {
+        if filter.evaluate(&sam) != Decision::Keep {continue};
+        match filter.bounds.parse(sam.rname) {
+            Ok((taxid, _gene)) => flush(groups.push(0, sam.qname, taxid as TinyTaxID, iter.line, anomalies)?, &mut result, anomalies)?,
+            Err(e) => anomalies.record(Anomaly::UnparseableName, &format!("Reference not parseable: {}: {}", sam.rname, e), Some(iter.line))?,
+        }
+    }
+    flush(groups.finish(), &mut result, anomalies)?;
+    eprintln!("Records: {}", filter);
+
+    Ok((result, skipped))
+}
+
+/// Records per MAPQ, split by whether the read was placed on its own taxon and gene.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapqHistogram {
+    /// (correct, incorrect) by MAPQ
+    counts: Vec<(u64, u64)>,
+}
+
+impl Default for MapqHistogram {
+    fn default() -> Self {
+        Self { counts: vec![(0, 0); Self::MAX_USUAL as usize + 1] }
+    }
+}
+
+impl MapqHistogram {
+    /// Highest MAPQ of the usual aligners (bowtie2 42, bwa 60), every value up to it gets a row.
+    pub const MAX_USUAL: Mapq = 60;
+
+    pub fn add(&mut self, mapq: Mapq, correct: bool) {
+        let mapq = mapq as usize;
+        if mapq >= self.counts.len() {
+            self.counts.resize(mapq + 1, (0, 0));
+        }
+        match correct {
+            true => self.counts[mapq].0 += 1,
+            false => self.counts[mapq].1 += 1,
+        }
+    }
+
+    pub fn get(&self, mapq: Mapq) -> (u64, u64) {
+        self.counts.get(mapq as usize).copied().unwrap_or_default()
+    }
+
+    /// Counts the records of the input kept by every record filter but --min_mapq.
+    pub fn from_sam(args: &Args, anomalies: &mut AnomalyLog) -> Result<Self, InputError> {
+        let mut iter = sam_input(args)?;
+        anomalies.set_input(&args.input_label());
+        let mut filter = RecordFilter::from_args(args);
+        filter.min_mapq = 0;
+        let mut result = Self::default();
+
+        let mut line = String::new();
+        while let Some(sam) = iter.next_valid_ref(&mut line, anomalies)? {
+            if filter.evaluate(&sam) != Decision::Keep {continue};
+            let fromto = match sam_to_ids(&sam, &filter.bounds, &mut filter.names) {
+                Ok(fromto) => fromto,
+                Err(e) => {
+                    anomalies.record(Anomaly::UnparseableName, &e, Some(iter.line))?;
+                    continue
+                },
+            };
+            if filter.evaluate_pair(&fromto) != Decision::Keep {continue};
+            result.add(sam.mapq, fromto.query == fromto.reference && fromto.query_gene == fromto.reference_gene);
+        }
+        eprintln!("Records: {}", filter);
+        Ok(result)
+    }
+
+    /// Writes `mapq correct incorrect` rows for MAPQ 0 to `MAX_USUAL` and any higher value seen
+    /// (e.g. 255, MAPQ unavailable). Returns the number of rows written.
+    pub fn write(&self, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "mapq\tcorrect\tincorrect")?;
+        let mut rows = 0;
+        for (mapq, (correct, incorrect)) in self.counts.iter().enumerate() {
+            if mapq > Self::MAX_USUAL as usize && correct + incorrect == 0 {continue};
+            writeln!(writer, "{}\t{}\t{}", mapq, correct, incorrect)?;
+            rows += 1;
+        }
+        Ok(rows)
+    }
+}
+
+/// Writes the schema header and the normalized totals per recipient, sorted ascending by total
+/// and then by recipient. Totals are clamped at output time under --clamp.
+pub fn write_normalized_totals(normalized: HashMap<TinyTaxID, f64>, schema: &NormalizationSchema, writer: &mut impl Write) -> std::io::Result<usize> {
+    writeln!(writer, "{}", schema)?;
+    let mut vec = normalized.into_iter().collect::<Vec<(TinyTaxID, f64)>>();
+    vec.sort_by(|(a, a_total), (b, b_total)| a_total.partial_cmp(b_total).unwrap_or(Ordering::Equal).then(a.cmp(b)));
+    let mut clamped = 0;
+    for (to, total) in &vec {
+        writeln!(writer, "{}\t{}", to, fmt_clamped(*total, schema.clamp.as_ref(), &mut clamped))?;
+    }
+    report_clamped(schema.clamp.as_ref(), clamped);
+    Ok(vec.len())
+}
+
+/// Reports the number of cells a writer clamped under --clamp.
+fn report_clamped(clamp: Option<&Clamp>, clamped: usize) {
+    if let Some(clamp) = clamp {
+        eprintln!("Clamped {} normalized cells above {}", clamped, clamp.limit);
+    }
+}
+
+/// Contribution of a single donor to the incoming leakage of a recipient.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DonorContribution {
+    pub recipient: TinyTaxID,
+    pub donor: TinyTaxID,
+    pub normalized: f64,
+    pub reads: u64,
+    pub genes: usize,
+}
+
+impl Eq for DonorContribution {}
+
+/// Ordered by normalized contribution, ties broken towards the smaller donor id.
+impl Ord for DonorContribution {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.normalized.total_cmp(&other.normalized).then(other.donor.cmp(&self.donor))
+    }
+}
+
+impl PartialOrd for DonorContribution {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Per-recipient min-heaps bounded to `k` entries, so only the top donors are ever held.
+/// Self-pairs are correct assignments rather than donors and are not offered.
+pub struct TopDonors {
+    k: usize,
+    heaps: HashMap<TinyTaxID, BinaryHeap<Reverse<DonorContribution>>>,
+}
+
+impl TopDonors {
+    pub fn new(k: usize) -> Self {
+        Self { k, heaps: HashMap::default() }
+    }
+
+    pub fn offer(&mut self, pair: &LeakagePair, genes: &Genes, normalizer: &Genes) {
+        if pair.from == pair.to || self.k == 0 { return };
+
+        let contribution = DonorContribution {
+            recipient: pair.to,
+            donor: pair.from,
+            normalized: genes.weights().map(|(gene, count)| count / normalizer.weight(gene).unwrap()).sum(),
+            reads: genes.total(),
+            genes: genes.iter().count(),
+        };
+
+        let heap = self.heaps.entry(pair.to).or_default();
+        heap.push(Reverse(contribution));
+        if heap.len() > self.k {
+            heap.pop();
+        }
+    }
+
+    /// All kept contributions, sorted by recipient and descending contribution.
+    pub fn into_sorted(self) -> Vec<DonorContribution> {
+        let mut result = self.heaps.into_values()
+            .flat_map(|heap| heap.into_iter().map(|Reverse(c)| c))
+            .collect::<Vec<DonorContribution>>();
+        result.sort_by(|a, b| a.recipient.cmp(&b.recipient).then(b.cmp(a)));
+        result
+    }
+}
+
+/// Writes (recipient, donor, normalized, reads, genes) rows, with label columns after
+/// the ids when a label map is given and the normalized column through `clamp` if given.
+pub fn write_top_donors(rows: &[DonorContribution], id2lab: Option<&IdLabels>, clamp: Option<&Clamp>, writer: &mut impl Write) -> std::io::Result<usize> {
+    let label = |id: TinyTaxID| -> &str {
+        id2lab.and_then(|labels| labels.get(id as usize)).filter(|l| !l.is_empty()).map_or("NA", |l| l.as_str())
+    };
+
+    let mut clamped = 0;
+    for row in rows {
+        let normalized = match clamp {
+            Some(clamp) => clamp.render(row.normalized, &mut clamped),
+            None => row.normalized.to_string(),
+        };
+        match id2lab {
+            Some(_) => writeln!(writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}", row.recipient, label(row.recipient), row.donor, label(row.donor), normalized, row.reads, row.genes)?,
+            None => writeln!(writer, "{}\t{}\t{}\t{}\t{}", row.recipient, row.donor, normalized, row.reads, row.genes)?,
+        }
+    }
+    report_clamped(clamp, clamped);
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::utils::test_path;
+
+    fn args(parts: &[&str]) -> Args {
+        Args::try_parse_from(["test"].iter().chain(parts)).unwrap()
+    }
+
+    /// Table of (from, to, gene, reads) entries.
+    fn leakage(entries: &[(TinyTaxID, TinyTaxID, GeneID, u32)]) -> Leakage {
+        let mut result = Leakage::default();
+        for (from, to, gene, reads) in entries {
+            let genes = result.map.entry(LeakagePair::from(*from, *to)).or_default();
+            (0..*reads).for_each(|_read| genes.increment(*gene));
+        }
+        result
+    }
+
+    fn some(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn leakage_diff_reports_missing_extra_and_changed_pairs() {
+        let left = leakage(&[(1, 2, 1, 2), (1, 3, 2, 1), (2, 2, 1, 4)]);
+        let right = leakage(&[(1, 2, 1, 3), (2, 1, 1, 1), (2, 2, 1, 4)]);
+        assert_eq!(left.diff(&right), vec![
+            Difference::new("1->2", "gene_1", some("2"), some("3")),
+            Difference::new("1->3", "pair", some("1"), None),
+            Difference::new("2->1", "pair", None, some("1")),
+        ]);
+        assert!(left != right);
+        assert!(left == leakage(&[(2, 2, 1, 4), (1, 3, 2, 1), (1, 2, 1, 2)]));
+    }
+
+    #[test]
+    fn genes_diff_ignores_trailing_empty_slots() {
+        let genes = |ids: &[GeneID]| {
+            let mut genes = Genes::default();
+            ids.iter().for_each(|gene| genes.increment(*gene));
+            genes
+        };
+        assert_eq!(genes(&[1]).diff(&genes(&[1, 3]), "p"), vec![Difference::new("p", "gene_3", None, some("1"))]);
+        assert_eq!(genes(&[1]).diff(&genes(&[1]), "p"), Vec::new());
+    }
+
+    /// Checks every accessor of `genes` against counts indexed by gene.
+    fn assert_genes(genes: &Genes, expected: &[u64]) {
+        assert_eq!(genes.len(), expected.len());
+        assert_eq!(genes.total(), expected.iter().sum::<u64>());
+        let occupied = expected.iter().enumerate().filter(|(_gene, count)| **count > 0).map(|(gene, count)| (gene, *count)).collect::<Vec<_>>();
+        assert_eq!(genes.iter().collect::<Vec<_>>(), occupied);
+        for (gene, count) in expected.iter().enumerate().chain([(expected.len(), &0)]) {
+            assert_eq!(genes.get(gene), (*count > 0).then_some(*count), "gene {}", gene);
+        }
+    }
+
+    #[test]
+    fn genes_turn_dense_past_the_sparse_capacity() {
+        let mut genes = Genes::default();
+        let mut expected = Vec::new();
+        // Every other gene, so slots stay empty in between
+        for i in 0..=Genes::SPARSE_CAPACITY {
+            let gene = 2 * i + 1;
+            genes.increment(gene);
+            genes.increment(gene);
+            expected.resize(gene + 1, 0);
+            expected[gene] += 2;
+            assert_eq!(genes.is_dense(), i == Genes::SPARSE_CAPACITY, "{} genes", i + 1);
+            assert_genes(&genes, &expected);
+        }
+        // Genes already present do not upgrade sparse genes
+        let mut full = Genes::default();
+        (0..Genes::SPARSE_CAPACITY).for_each(|gene| full.increment(gene));
+        (0..Genes::SPARSE_CAPACITY).for_each(|gene| full.increment(gene));
+        assert!(!full.is_dense());
+        assert_genes(&full, &[2; Genes::SPARSE_CAPACITY]);
+    }
+
+    #[test]
+    fn genes_turn_dense_for_ids_and_counts_beyond_the_sparse_types() {
+        let mut genes = Genes::default();
+        genes.increment(3);
+        genes.increment(256);
+        assert!(genes.is_dense());
+        let mut expected = vec![0; 257];
+        expected[3] = 1;
+        expected[256] = 1;
+        assert_genes(&genes, &expected);
+
+        let mut genes = Genes::default();
+        genes.increment(2);
+        genes.merge_from(&Genes::from_slice(&[-1, -1, u32::MAX as i64])).unwrap();
+        assert!(genes.is_dense());
+        assert_genes(&genes, &[0, 0, u32::MAX as u64 + 1]);
+    }
+
+    #[test]
+    fn gene_merges_refuse_counts_beyond_i64() {
+        // Sparse counts near u32::MAX move to dense ones instead of wrapping
+        let near = Genes::from_slice(&[-1, u32::MAX as i64 - 1, 5]);
+        let mut genes = Genes::default();
+        genes.increment(1);
+        genes.merge_from(&near).unwrap();
+        assert_genes(&genes, &[0, u32::MAX as u64, 5]);
+        genes.merge_from(&near).unwrap();
+        assert!(genes.is_dense());
+        assert_genes(&genes, &[0, 2 * u32::MAX as u64 - 1, 10]);
+
+        let mut genes = Genes::from_slice(&[3, i64::MAX - 1, -1]);
+        genes.merge_from(&Genes::from_slice(&[1, 1])).unwrap();
+        assert_genes(&genes, &[4, i64::MAX as u64, 0]);
+        // Stops at the gene that overflows, the genes before it are added
+        let error = genes.merge_from(&Genes::from_slice(&[2, 1, 7])).unwrap_err();
+        assert_eq!(error.0, format!("{} + 1 reads on gene 1", i64::MAX));
+        assert_genes(&genes, &[6, i64::MAX as u64, 0]);
+    }
+
+    #[test]
+    fn table_inserts_and_merges_refuse_overflow() {
+        let pair = LeakagePair::from(1, 2);
+        let mut table = Leakage::default();
+        assert!(!table.insert(pair, Genes::from_slice(&[-1, i64::MAX])).unwrap());
+        assert!(table.insert(pair, Genes::from_slice(&[-1, -1, 1])).unwrap());
+        let error = table.insert(pair, Genes::from_slice(&[-1, 1])).unwrap_err();
+        assert_eq!(error.0, format!("{} + 1 reads on gene 1 of pair {}", i64::MAX, pair));
+        let mut other = Leakage::default();
+        other.insert(pair, Genes::from_slice(&[-1, 1])).unwrap();
+        assert_eq!(table.merge(other, false), Err(format!("Count overflow: {}", error.0)));
+
+        let mut totals = LeakageTotals::default();
+        assert!(!totals.insert(pair, u64::MAX - 1).unwrap());
+        assert!(totals.insert(pair, 1).unwrap());
+        let error = totals.insert(pair, 1).unwrap_err();
+        assert_eq!(error.0, format!("{} + 1 for pair {}", u64::MAX, pair));
+        assert_eq!(totals.map[&pair], u64::MAX);
+        let other = LeakageTotals { map: HashMap::from([(pair, 1)]), ..Default::default() };
+        assert_eq!(totals.merge(other, false), Err(format!("Count overflow: {}", error.0)));
+    }
+
+    #[test]
+    fn genes_match_dense_counts_across_merges() {
+        let mut rng = crate::utils::SplitMix64::new(7);
+        for round in 0..200 {
+            let (mut left, mut right) = (Genes::default(), Genes::default());
+            let mut expected = vec![0; 120];
+            // Around the capacity, so merges cross it from either side
+            for genes in [&mut left, &mut right] {
+                for _ in 0..rng.below(2 * Genes::SPARSE_CAPACITY as u64) {
+                    let gene = rng.below(120) as usize;
+                    genes.increment(gene);
+                    expected[gene] += 1;
+                }
+            }
+            left.merge_from(&right).unwrap();
+            let len = expected.iter().rposition(|count| *count > 0).map_or(0, |last| last + 1);
+            assert_eq!(left.is_dense(), expected.iter().filter(|count| **count > 0).count() > Genes::SPARSE_CAPACITY, "round {}", round);
+            assert_genes(&left, &expected[..len]);
+            let rebuilt = Genes::from_slice(&(0..left.len()).map(|gene| left.get(gene).map_or(Genes::EMPTY, |count| count as i64)).collect::<Vec<i64>>());
+            assert!(rebuilt == left && rebuilt.is_dense() == left.is_dense(), "round {}", round);
+        }
+    }
+
+    #[test]
+    fn sparse_genes_take_less_memory_than_dense_and_fixed_ones() {
+        // Pairs with a few of 120 genes occupied, and dense self-pairs
+        let mut table = Leakage::default();
+        let mut rng = crate::utils::SplitMix64::new(11);
+        for pair in 0..2000 {
+            let genes = table.map.entry(LeakagePair::from(pair % 50, pair / 50 + 50)).or_default();
+            for _ in 0..1 + rng.below(5) {
+                genes.increment(1 + rng.below(120) as usize);
+            }
+        }
+        for taxon in 0..50 {
+            let genes = table.map.entry(LeakagePair::from(taxon, taxon)).or_default();
+            (1..=120).for_each(|gene| genes.increment(gene));
+        }
+        let memory = table.gene_memory();
+        assert_eq!((memory.pairs, memory.sparse), (2050, 2000));
+        assert!(memory.bytes * 2 < memory.dense_bytes, "{}", memory);
+        assert!(memory.bytes * 4 < memory.fixed_bytes, "{}", memory);
+    }
+
+    #[test]
+    fn totals_diff_reports_missing_extra_and_changed_pairs() {
+        let left = LeakageTotals { map: HashMap::from([(LeakagePair::from(1, 2), 5), (LeakagePair::from(1, 3), 1)]), ..Default::default() };
+        let right = LeakageTotals { map: HashMap::from([(LeakagePair::from(1, 2), 6), (LeakagePair::from(3, 1), 2)]), ..Default::default() };
+        assert_eq!(left.diff(&right), vec![
+            Difference::new("1->2", "total", some("5"), some("6")),
+            Difference::new("1->3", "pair", some("1"), None),
+            Difference::new("3->1", "pair", None, some("2")),
+        ]);
+    }
+
+    #[test]
+    fn streaming_normalization_matches_in_memory() {
+        let path = test_path("sorted.tsv");
+        std::fs::write(&path, "#pairs\tdirected\tgene_base=1\n1\t1\t3\t2\t1\n1\t2\t2\t1\t1\n2\t1\t2\t1\t1\n2\t2\t4\t2\t2\n").unwrap();
+        let args = args(&["--input", path.to_str().unwrap()]);
+        let schema = NormalizationSchema::from_args(&args);
+        let expected = Leakage::load(&args, &mut AnomalyLog::default()).unwrap().normalize_incoming(&schema, false, &mut AnomalyLog::default()).unwrap();
+        let streamed = Leakage::normalize_incoming_streaming(&args, None, &mut AnomalyLog::default()).unwrap();
+        assert_eq!(streamed.keys().collect::<std::collections::BTreeSet<_>>(), expected.keys().collect());
+        for (to, genes) in &expected {
+            assert_eq!(streamed[to].data, genes.data, "recipient {}", to);
+        }
+    }
+
+    #[test]
+    fn no_genes_totals_match_the_full_mode() {
+        let full = Leakage::from_sam(&args(&["--input", "tests/data/leaks.sam"]), &mut AnomalyLog::default()).unwrap();
+        let no_genes = args(&["--input", "tests/data/leaks.sam", "--no-genes"]);
+        let totals = LeakageTotals::from_sam(&no_genes, &mut AnomalyLog::default()).unwrap();
+        assert!(!totals.map.is_empty());
+        assert_eq!(totals.map, LeakageTotals::from(&full).map);
+        for (pair, genes) in &full.map {
+            assert_eq!(totals.map[pair], genes.total(), "pair {}", pair);
+        }
+
+        // Pair totals over the gene totals of the donor, added in the same order
+        let outgoing = full.total_outgoing(SelfPairPolicy::default(), true);
+        let mut expected: HashMap<TinyTaxID, f64> = HashMap::default();
+        for (pair, genes) in pair_entries(&full.map, true) {
+            *expected.entry(pair.to).or_default() += genes.total() as f64 / outgoing[&pair.from].total() as f64;
+        }
+        let normalized = totals.normalize_incoming(SelfPairPolicy::default(), true, &mut AnomalyLog::default()).unwrap();
+        assert_eq!(normalized, expected);
+
+        let path = test_path("pair_totals.tsv");
+        let mut writer = std::fs::File::create(&path).unwrap();
+        write_normalized_totals(normalized, &NormalizationSchema::from_args(&no_genes), &mut writer).unwrap();
+        drop(writer);
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("#normalization\tdonor_outgoing_pair_total\tvalues\n"), "{}", content);
+        assert!(matches!(read_normalized(&path), Err(e) if e.contains("--no-genes")));
+    }
+
+    #[test]
+    fn streaming_normalization_refuses_unsorted_input() {
+        let path = test_path("unsorted.tsv");
+        std::fs::write(&path, "#pairs\tdirected\tgene_base=1\n2\t1\t1\t1\n1\t2\t1\t1\n").unwrap();
+        let result = Leakage::normalize_incoming_streaming(&args(&["--input", path.to_str().unwrap()]), None, &mut AnomalyLog::default());
+        match result {
+            Err(InputError::Invalid(message)) => assert!(message.contains("line 3: not sorted by the from column (1 follows 2)"), "{}", message),
+            other => panic!("expected an unsorted input error, got {:?}", other.map(|normalized| normalized.len())),
+        }
+    }
+
+    #[test]
+    fn denominators_are_written_per_donor_contribution() {
+        // Recipient 3 gets gene 1 from donors 1 and 2, gene 2 from donor 2 only
+        let table = leakage(&[(1, 3, 1, 2), (1, 1, 1, 2), (2, 3, 1, 1), (2, 3, 2, 1), (2, 2, 1, 3), (2, 2, 2, 3)]);
+        let schema = NormalizationSchema::from_args(&args(&["--input", "-", "--with-denominators"]));
+        let normalized = table.normalize_incoming(&schema, true, &mut AnomalyLog::default()).unwrap();
+        let recipient = &normalized[&3];
+        assert!(recipient.denominators.as_ref().unwrap()[1].is_nan());
+        assert_eq!(recipient.denominators.as_ref().unwrap()[2], 4.0);
+
+        let row = recipient.render(None, &mut 0);
+        let columns = row.split('\t').collect::<Vec<&str>>();
+        assert_eq!(columns[2], "NA");
+        assert_eq!(columns[4].parse::<f64>().unwrap(), 4.0);
+
+        let (_recipient, parsed) = NormGenes::parse_line(&format!("3\t{}", row), &schema, 1).unwrap();
+        assert_eq!(parsed.data, recipient.data);
+    }
+
+    #[test]
+    fn equalize_depth_samples_reads_with_all_their_alignments() {
+        let path = test_path("multimapped.sam");
+        let mut sam = String::from("@SQ\tSN:1_1\tLN:100\n@SQ\tSN:2_1\tLN:100\n@SQ\tSN:3_1\tLN:100\n");
+        for read in 0..5 {
+            for (flag, reference) in [(0, 1), (256, 2), (256, 3)] {
+                sam.push_str(&format!("1_1_r{}\t{}\t{}_1\t1\t30\t4M\t*\t0\t0\tACGT\tIIII\n", read, flag, reference));
+            }
+        }
+        std::fs::write(&path, sam).unwrap();
+        let report = test_path("depth_report.tsv");
+        let args = args(&["--input", path.to_str().unwrap(), "--equalize-depth", "2", "--equalize-depth-report", report.to_str().unwrap()]);
+        let leakage = Leakage::from_sam(&args, &mut AnomalyLog::default()).unwrap();
+        for reference in 1..=3 {
+            assert_eq!(leakage.map[&LeakagePair::from(1, reference)].total(), 2, "pair 1->{}", reference);
+        }
+        assert_eq!(std::fs::read_to_string(&report).unwrap(), "1\t2\t5\n");
+    }
+
+    #[test]
+    fn inputs_are_read_in_parallel_and_parsing_capped_by_decompression() {
+        let (plain, gzip, zstd, bzip2) = (Some(Codec::Plain), Some(Codec::Gzip), Some(Codec::Zstd), Some(Codec::Bzip2));
+        // (readers, parsers) for 1, 2, 3 and 8 requested
+        type Threads = [(usize, usize); 4];
+        let cases: [(&[Option<Codec>], Threads); 13] = [
+            (&[plain], [(1, 1), (1, 2), (1, 3), (1, 8)]),
+            (&[None], [(1, 1), (1, 2), (1, 3), (1, 8)]),
+            (&[gzip], [(1, 1), (1, 2), (1, 2), (1, 2)]),
+            (&[zstd], [(1, 1), (1, 2), (1, 3), (1, 4)]),
+            (&[bzip2], [(1, 1), (1, 1), (1, 1), (1, 1)]),
+            // Every compressed input read at a time keeps more threads busy
+            (&[gzip, gzip, gzip], [(1, 1), (2, 2), (3, 3), (3, 6)]),
+            (&[bzip2, bzip2, bzip2, bzip2], [(1, 1), (2, 2), (3, 3), (4, 4)]),
+            (&[bzip2, gzip], [(1, 1), (2, 2), (2, 3), (2, 3)]),
+            (&[bzip2, zstd, gzip], [(1, 1), (2, 2), (3, 3), (3, 7)]),
+            (&[gzip, plain], [(1, 1), (2, 2), (2, 3), (2, 8)]),
+            (&[bzip2, None], [(1, 1), (2, 2), (2, 3), (2, 8)]),
+            (&[gzip; 12], [(1, 1), (2, 2), (3, 3), (8, 8)]),
+            (&[], [(1, 1), (1, 2), (1, 3), (1, 8)]),
+        ];
+        for (codecs, expected) in cases {
+            let found = [1, 2, 3, 8].map(|requested| input_threads(requested, codecs)).map(|threads| (threads.readers, threads.parsers));
+            assert_eq!(found, expected, "{:?}", codecs);
+        }
+        assert_eq!(input_threads(0, &[plain, plain]), InputThreads { readers: 1, parsers: 1 });
+    }
+}
@@ -0,0 +1,115 @@
+use std::{collections::HashMap, fmt::Display, io::Write};
+
+use crate::{common::TaxID, id_to_label::IdLabels, pairwise_leakage::LeakagePair};
+
+/// Where a taxon of the reference could not be placed, e.g. a genome newer than the map or tree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Unplaced {
+    /// No label in the label map
+    Map,
+    /// Labeled, but the label is not a leaf of the tree
+    Tree,
+}
+
+impl Display for Unplaced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Unplaced::Map => write!(f, "map"),
+            Unplaced::Tree => write!(f, "tree"),
+        }
+    }
+}
+
+/// Reads of one unplaced taxon, counted over all pairs it is part of.
+#[derive(Debug, Copy, Clone)]
+pub struct UnplacedTaxon {
+    pub missing_from: Unplaced,
+    pub reads: u64,
+    pub pairs: usize,
+}
+
+/// Taxa of a pairwise map that cannot be placed in the label map (or tree), with the reads and
+/// pairs that involve at least one of them.
+#[derive(Debug, Default)]
+pub struct UnplacedCounter {
+    taxa: HashMap<TaxID, UnplacedTaxon>,
+    pub reads: u64,
+    pub pairs: usize,
+}
+
+impl UnplacedCounter {
+    /// Checks both taxa of every pair: taxa without a label in `id2lab` are missing from the map,
+    /// labeled taxa whose label `in_tree` rejects are missing from the tree.
+    pub fn from_pairs<T>(map: &HashMap<LeakagePair, T>, total: impl Fn(&T) -> u64, id2lab: &IdLabels, in_tree: Option<&dyn Fn(&str) -> bool>) -> Self {
+        let placement = |id: TaxID| -> Option<Unplaced> {
+            let label = id2lab.get(id).filter(|label| !label.is_empty());
+            match (label, in_tree) {
+                (None, _) => Some(Unplaced::Map),
+                (Some(label), Some(in_tree)) if !in_tree(label) => Some(Unplaced::Tree),
+                _ => None,
+            }
+        };
+
+        let mut result = Self::default();
+        for (pair, value) in map {
+            let reads = total(value);
+            let mut unplaced = false;
+            for id in std::iter::once(pair.from).chain((pair.from != pair.to).then_some(pair.to)) {
+                let Some(missing_from) = placement(id as TaxID) else { continue };
+                let taxon = result.taxa.entry(id as TaxID).or_insert(UnplacedTaxon { missing_from, reads: 0, pairs: 0 });
+                taxon.reads += reads;
+                taxon.pairs += 1;
+                unplaced = true;
+            }
+            if unplaced {
+                result.reads += reads;
+                result.pairs += 1;
+            }
+        }
+        result
+    }
+
+    pub fn is_unplaced(&self, id: TaxID) -> bool {
+        self.taxa.contains_key(&id)
+    }
+
+    pub fn taxa(&self) -> usize {
+        self.taxa.len()
+    }
+
+    /// Unplaced taxa missing from `where`.
+    pub fn missing_from(&self, missing_from: Unplaced) -> usize {
+        self.taxa.values().filter(|taxon| taxon.missing_from == missing_from).count()
+    }
+
+    /// Run-wide counts as (name, count), e.g. for the manifest.
+    pub fn summary(&self) -> Vec<(String, u64)> {
+        vec![
+            ("taxa".to_string(), self.taxa() as u64),
+            ("missing_from_map".to_string(), self.missing_from(Unplaced::Map) as u64),
+            ("missing_from_tree".to_string(), self.missing_from(Unplaced::Tree) as u64),
+            ("pairs".to_string(), self.pairs as u64),
+            ("reads".to_string(), self.reads),
+        ]
+    }
+
+    /// Writes one row per unplaced taxon (taxid, missing_from, reads, pairs), most reads first.
+    /// Returns the number of rows.
+    pub fn write_report(&self, writer: &mut impl Write) -> std::io::Result<usize> {
+        writeln!(writer, "#taxid\tmissing_from\treads\tpairs")?;
+        let mut rows = self.taxa.iter().collect::<Vec<(&TaxID, &UnplacedTaxon)>>();
+        rows.sort_by(|(a_id, a), (b_id, b)| b.reads.cmp(&a.reads).then(a_id.cmp(b_id)));
+        for (id, taxon) in &rows {
+            writeln!(writer, "{}\t{}\t{}\t{}", id, taxon.missing_from, taxon.reads, taxon.pairs)?;
+        }
+        Ok(rows.len())
+    }
+}
+
+/// One line summary, e.g. for stderr.
+impl Display for UnplacedCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} unplaced taxa ({} missing from the map, {} from the tree) in {} pairs with {} reads",
+            self.taxa(), self.missing_from(Unplaced::Map), self.missing_from(Unplaced::Tree), self.pairs, self.reads)
+    }
+}
@@ -0,0 +1,157 @@
+use std::{cmp::Ordering, collections::{HashMap, VecDeque}, io::Write, path::Path};
+
+use crate::{common::{GeneID, NameFormat}, pairwise_leakage::{Genes, Leakage, LeakagePair, PairSchema, TinyTaxID}, reference::fasta_records, schema::fmt_fixed};
+
+/// k-mer size and window (in k-mers) of the minimizer sketches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SketchParams {
+    pub k: usize,
+    pub w: usize,
+}
+
+impl SketchParams {
+    /// Header line of the tables written from these sketches.
+    pub fn header(&self, max_taxa: usize) -> String {
+        format!("#prescreen\tk={}\tw={}\tmax_taxa={}", self.k, self.w, max_taxa)
+    }
+}
+
+fn encode(base: u8) -> Option<u64> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// Finalizer of splitmix64, spreads k-mer codes so the minimum is not biased towards poly-A.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Sorted, distinct minimizers of a sequence: the smallest hash of the canonical k-mers (the
+/// smaller of a k-mer and its reverse complement) of every window of `w` consecutive k-mers.
+/// Bases other than ACGT break k-mers, sequences shorter than one window have no minimizers.
+/// `k` must lie within 1..=31.
+pub fn minimizers(sequence: &[u8], params: SketchParams) -> Vec<u64> {
+    let SketchParams { k, w } = params;
+    let mask = (1u64 << (2 * k)) - 1;
+    let shift = 2 * (k - 1);
+    let (mut forward, mut reverse, mut valid) = (0u64, 0u64, 0usize);
+    let mut window: VecDeque<(usize, u64)> = VecDeque::new();
+    let mut result = Vec::new();
+
+    for (i, base) in sequence.iter().enumerate() {
+        let Some(code) = encode(*base) else {
+            valid = 0;
+            window.clear();
+            continue
+        };
+        forward = ((forward << 2) | code) & mask;
+        reverse = (reverse >> 2) | ((3 - code) << shift);
+        valid += 1;
+        if valid < k { continue };
+
+        let hash = mix(forward.min(reverse));
+        while window.back().is_some_and(|(_i, back)| *back >= hash) {
+            window.pop_back();
+        }
+        window.push_back((i, hash));
+        while window.front().is_some_and(|(front, _hash)| front + w <= i) {
+            window.pop_front();
+        }
+        if valid >= k + w - 1 {
+            result.push(window.front().expect("The window holds the current k-mer").1);
+        }
+    }
+    result.sort_unstable();
+    result.dedup();
+    result
+}
+
+/// Inverted index from (gene, minimizer) to the taxa whose copy of the gene holds it. Memory grows
+/// with the number of distinct minimizers per gene, not with the number of taxon pairs.
+#[derive(Debug, Default)]
+pub struct MinimizerIndex {
+    postings: HashMap<(GeneID, u64), Vec<TinyTaxID>>,
+    /// Minimizers per gene of every taxon.
+    sizes: HashMap<TinyTaxID, HashMap<GeneID, usize>>,
+    pub sequences: usize,
+    /// Sequences whose name does not follow the name format, left out.
+    pub malformed: usize,
+}
+
+impl MinimizerIndex {
+    pub fn from_fasta(path: impl AsRef<Path>, params: SketchParams, format: &NameFormat) -> std::io::Result<Self> {
+        let mut result = Self::default();
+        for record in fasta_records(path)? {
+            let (name, sequence) = record?;
+            result.sequences += 1;
+            let Ok((taxid, gene)) = format.parse(&name) else {
+                result.malformed += 1;
+                continue
+            };
+            let sketch = minimizers(sequence.as_bytes(), params);
+            *result.sizes.entry(taxid as TinyTaxID).or_default().entry(gene).or_default() += sketch.len();
+            for minimizer in sketch {
+                result.postings.entry((gene, minimizer)).or_default().push(taxid as TinyTaxID);
+            }
+        }
+        for taxa in result.postings.values_mut() {
+            taxa.sort_unstable();
+            taxa.dedup();
+        }
+        Ok(result)
+    }
+
+    /// Shared minimizers per unordered pair of taxa and gene, as an undirected pairwise map.
+    /// Minimizers held by more than `max_taxa` taxa (low complexity, conserved motifs) are
+    /// skipped, their number is returned alongside the map.
+    pub fn shared(&self, max_taxa: usize) -> (Leakage, usize) {
+        let mut result = Leakage { map: HashMap::new(), schema: PairSchema::undirected(), release: None, lengths: HashMap::new(), unmapped: HashMap::new(), reverse: HashMap::new(), gene_mismatches: HashMap::new() };
+        let mut skipped = 0;
+        for ((gene, _minimizer), taxa) in &self.postings {
+            if taxa.len() > max_taxa {
+                skipped += 1;
+                continue
+            }
+            for (i, a) in taxa.iter().enumerate() {
+                for b in &taxa[i + 1..] {
+                    result.map.entry(LeakagePair::from(*a, *b)).or_default().increment(*gene);
+                }
+            }
+        }
+        (result, skipped)
+    }
+
+    /// Jaccard similarity of the sketches of two taxa over the genes both carry, `shared` holding
+    /// their shared minimizers per gene.
+    pub fn jaccard(&self, pair: &LeakagePair, shared: &Genes) -> f64 {
+        let (Some(a), Some(b)) = (self.sizes.get(&pair.from), self.sizes.get(&pair.to)) else { return 0.0 };
+        let union = a.iter()
+            .filter_map(|(gene, a_size)| b.get(gene).map(|b_size| (a_size + b_size) as u64 - shared.get(*gene).unwrap_or(0)))
+            .sum::<u64>();
+        match union {
+            0 => 0.0,
+            union => shared.total() as f64 / union as f64,
+        }
+    }
+}
+
+/// Writes the candidate pairs (from, to, shared minimizers, Jaccard similarity) sorted by
+/// descending similarity. Returns the number of rows.
+pub fn write_similarity(index: &MinimizerIndex, shared: &Leakage, writer: &mut impl Write) -> std::io::Result<usize> {
+    writeln!(writer, "#from\tto\tshared\tjaccard")?;
+    let mut rows = shared.map.iter()
+        .map(|(pair, genes)| (pair, genes.total(), index.jaccard(pair, genes)))
+        .collect::<Vec<(&LeakagePair, u64, f64)>>();
+    rows.sort_by(|(a, _, a_jaccard), (b, _, b_jaccard)| b_jaccard.partial_cmp(a_jaccard).unwrap_or(Ordering::Equal).then(a.cmp(b)));
+    for (pair, total, jaccard) in &rows {
+        writeln!(writer, "{}\t{}\t{}\t{}", pair.from, pair.to, total, fmt_fixed(*jaccard))?;
+    }
+    Ok(rows.len())
+}
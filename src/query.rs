@@ -0,0 +1,118 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::{common::{AnomalyLog, GeneID, InputError, TaxID}, doctor::ResultsDir, manifest::PAIRWISE_FILE, pairwise_leakage::{Leakage, LeakagePair, SelfPairPolicy, TinyTaxID}};
+
+/// Version of the query surface (here and in `ffi`), bumped on any change of its functions.
+pub const QUERY_API_VERSION: u32 = 1;
+
+/// Side of a pair the queried taxon is on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Pairs with the taxon as recipient, partners are donors
+    Incoming,
+    /// Pairs with the taxon as donor, partners are recipients
+    Outgoing,
+}
+
+/// The pairwise table of a results directory held in memory and indexed by taxon, for repeated
+/// lookups (e.g. from a notebook) without reparsing the TSVs.
+pub struct ResultsQuery {
+    pairwise: Leakage,
+    by_recipient: HashMap<TinyTaxID, Vec<LeakagePair>>,
+    by_donor: HashMap<TinyTaxID, Vec<LeakagePair>>,
+}
+
+impl ResultsQuery {
+    /// Opens a results directory written by `analyze`. Its manifest must be present, i.e. the run
+    /// completed, and a truncated pairwise table is an error.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, InputError> {
+        let results = ResultsDir::open(&dir)?;
+        let path = results.path(PAIRWISE_FILE);
+        Ok(Self::new(Leakage::read(&path.to_string_lossy(), SelfPairPolicy::default(), &mut AnomalyLog::new(true, false))?))
+    }
+
+    pub fn new(pairwise: Leakage) -> Self {
+        let mut by_recipient: HashMap<TinyTaxID, Vec<LeakagePair>> = HashMap::new();
+        let mut by_donor: HashMap<TinyTaxID, Vec<LeakagePair>> = HashMap::new();
+        for pair in pairwise.map.keys().filter(|pair| pair.from != pair.to) {
+            by_recipient.entry(pair.to).or_default().push(*pair);
+            by_donor.entry(pair.from).or_default().push(*pair);
+        }
+        Self { pairwise, by_recipient, by_donor }
+    }
+
+    /// Partners of a taxon as (partner, reads) with at least `min_total` reads, most reads first.
+    /// Self-pairs are left out.
+    pub fn pairs_for(&self, taxon: TaxID, direction: Direction, min_total: u64) -> Vec<(TaxID, u64)> {
+        let (index, partner): (_, fn(&LeakagePair) -> TinyTaxID) = match direction {
+            Direction::Incoming => (&self.by_recipient, |pair| pair.from),
+            Direction::Outgoing => (&self.by_donor, |pair| pair.to),
+        };
+        let Some(pairs) = index.get(&(taxon as TinyTaxID)) else { return Vec::new() };
+        let mut result = pairs.iter()
+            .map(|pair| (partner(pair) as TaxID, self.pairwise.map[pair].total()))
+            .filter(|(_partner, total)| *total >= min_total)
+            .collect::<Vec<(TaxID, u64)>>();
+        result.sort_by(|(a, a_total), (b, b_total)| b_total.cmp(a_total).then(a.cmp(b)));
+        result
+    }
+
+    /// Donors of a taxon as (donor, reads), most reads first.
+    pub fn incoming(&self, taxon: TaxID) -> Vec<(TaxID, u64)> {
+        self.pairs_for(taxon, Direction::Incoming, 0)
+    }
+
+    /// Reads leaked into a taxon per gene as (gene, reads), summed over its donors, by gene.
+    pub fn gene_profile(&self, taxon: TaxID) -> Vec<(GeneID, u64)> {
+        let mut result: HashMap<GeneID, u64> = HashMap::new();
+        for pair in self.by_recipient.get(&(taxon as TinyTaxID)).into_iter().flatten() {
+            for (gene, count) in self.pairwise.map[pair].iter() {
+                *result.entry(gene).or_default() += count;
+            }
+        }
+        let mut result = result.into_iter().collect::<Vec<(GeneID, u64)>>();
+        result.sort_unstable();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{common::FromTo, pairwise_leakage::TinyGeneID};
+
+    fn fromto(query: TinyTaxID, query_gene: TinyGeneID, reference: TinyTaxID, reference_gene: TinyGeneID) -> FromTo {
+        FromTo { query, reference, query_gene, reference_gene, sample: 0, aligned_length: None, weight: None, reverse: None }
+    }
+
+    /// Taxon 1 receives 3 reads from 2 (on genes 1 and 2) and 1 from 3, and leaks 2 into 3.
+    fn results() -> ResultsQuery {
+        let mut pairwise = Leakage::default();
+        for (from, gene, to, count) in [(2, 1, 1, 2), (2, 2, 1, 1), (3, 2, 1, 1), (1, 1, 3, 2), (1, 1, 1, 5)] {
+            (0..count).for_each(|_read| pairwise.add(&fromto(from, gene, to, gene)));
+        }
+        ResultsQuery::new(pairwise)
+    }
+
+    #[test]
+    fn pairs_are_found_by_either_side_most_reads_first() {
+        let results = results();
+        assert_eq!(results.incoming(1), vec![(2, 3), (3, 1)]);
+        assert_eq!(results.pairs_for(1, Direction::Incoming, 2), vec![(2, 3)]);
+        assert_eq!(results.pairs_for(1, Direction::Outgoing, 0), vec![(3, 2)]);
+        assert_eq!(results.pairs_for(3, Direction::Incoming, 0), vec![(1, 2)]);
+        assert_eq!(results.pairs_for(3, Direction::Outgoing, 0), vec![(1, 1)]);
+        // Self-pairs are left out, unknown taxa have no partners
+        assert_eq!(results.pairs_for(2, Direction::Incoming, 0), Vec::new());
+        assert_eq!(results.incoming(9), Vec::new());
+        assert_eq!(results.pairs_for(1, Direction::Incoming, 4), Vec::new());
+    }
+
+    #[test]
+    fn gene_profiles_sum_the_donors_of_every_gene() {
+        let results = results();
+        assert_eq!(results.gene_profile(1), vec![(1, 2), (2, 2)]);
+        assert_eq!(results.gene_profile(3), vec![(1, 2)]);
+        assert_eq!(results.gene_profile(2), Vec::new());
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt::Display;
+
+use clap::ValueEnum;
+
+use crate::{common::{Anomaly, AnomalyError, AnomalyLog, Args, FromTo, GroupByQname, QnameGroup, SamRef}, filter::Mapq, samples::SampleID};
+
+/// Outcome of a read over all of its alignments (--reconcile).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadClass {
+    /// A correct alignment beats every foreign one by more than the margin
+    Correct,
+    /// Only foreign alignments, or a foreign one beats every correct one by more than the margin
+    Leaked,
+    /// Correct and foreign alignments within the margin of each other
+    Ambiguous,
+}
+
+impl Display for ReadClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ReadClass::Correct => "correct",
+            ReadClass::Leaked => "leaked",
+            ReadClass::Ambiguous => "ambiguous",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What ambiguous reads contribute to the pair counts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AmbiguousReads {
+    /// Count the best foreign alignment, like a leaked read
+    #[value(name = "count")]
+    Count,
+    /// Leave the read out of all pair counts
+    #[value(name = "skip")]
+    Skip,
+}
+
+/// How the alignments of a multi-mapped read are counted (--multimap-weighting).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MultimapWeighting {
+    /// Every alignment counts as a whole read
+    #[value(name = "count")]
+    Count,
+    /// Each of the n alignments of a read counts 1/n
+    #[value(name = "fraction")]
+    Fraction,
+    /// Only the best alignment of a read counts, as with --best-per-read
+    #[value(name = "best")]
+    Best,
+}
+
+/// Best alignment of a read (highest mapq, ties to the lowest reference taxon and gene) among its
+/// correct or its foreign ones.
+fn best(alignments: &[(FromTo, Mapq)], correct: bool) -> Option<(FromTo, Mapq)> {
+    alignments.iter()
+        .filter(|(fromto, _mapq)| (fromto.query == fromto.reference) == correct)
+        .min_by_key(|(fromto, mapq)| (std::cmp::Reverse(*mapq), fromto.reference, fromto.reference_gene))
+        .copied()
+}
+
+/// Classifies a read from its alignments as (pair, mapq) and picks the one pair it contributes:
+/// the best correct alignment for correct reads, the best foreign one otherwise. Ties go to the
+/// lowest reference taxon and gene. None for a read without alignments.
+pub fn classify(alignments: &[(FromTo, Mapq)], margin: Mapq) -> Option<(ReadClass, FromTo)> {
+    match (best(alignments, true), best(alignments, false)) {
+        (None, None) => None,
+        (Some((correct, _mapq)), None) => Some((ReadClass::Correct, correct)),
+        (None, Some((foreign, _mapq))) => Some((ReadClass::Leaked, foreign)),
+        (Some((correct, correct_mapq)), Some((_foreign, foreign_mapq))) if correct_mapq as u16 > foreign_mapq as u16 + margin as u16 => Some((ReadClass::Correct, correct)),
+        (Some((_correct, correct_mapq)), Some((foreign, foreign_mapq))) if foreign_mapq as u16 > correct_mapq as u16 + margin as u16 => Some((ReadClass::Leaked, foreign)),
+        (Some(_), Some((foreign, _mapq))) => Some((ReadClass::Ambiguous, foreign)),
+    }
+}
+
+/// Collects the alignments of name-grouped input read by read and hands out the single pair each
+/// read contributes, counting reads per class. Reads with more alignments than --max-group-records
+/// are only partly buffered and handled as ambiguous, by their best buffered foreign alignment
+/// (or correct one, without any), recorded as an oversized_group anomaly.
+#[derive(Debug)]
+pub struct Reconciler {
+    pub margin: Mapq,
+    pub ambiguous: AmbiguousReads,
+    groups: GroupByQname<(FromTo, Mapq)>,
+    pub correct: usize,
+    pub leaked: usize,
+    pub ambiguous_reads: usize,
+}
+
+impl Reconciler {
+    pub fn new(margin: Mapq, ambiguous: AmbiguousReads, groups: GroupByQname<(FromTo, Mapq)>) -> Self {
+        Self { margin, ambiguous, groups, correct: 0, leaked: 0, ambiguous_reads: 0 }
+    }
+
+    /// None unless running with --reconcile.
+    pub fn from_args(args: &Args) -> Option<Self> {
+        args.reconcile.then(|| Self::new(args.reconcile_margin, args.reconcile_ambiguous, GroupByQname::from_args(args)))
+    }
+
+    /// Adds an alignment of read `name`, read from input `record`. An alignment of a new read
+    /// (another name or sample) completes the previous one, whose class and pair are returned (no
+    /// pair for skipped ambiguous reads). Fails on input not grouped by name, see `GroupByQname`.
+    pub fn push(&mut self, name: &str, fromto: FromTo, mapq: Mapq, record: usize, anomalies: &mut AnomalyLog) -> Result<Option<(ReadClass, Option<FromTo>)>, AnomalyError> {
+        match self.groups.push(fromto.sample, name, (fromto, mapq), record, anomalies)? {
+            Some(group) => self.complete(group, anomalies),
+            None => Ok(None),
+        }
+    }
+
+    /// Completes the read collected last, to be called once the input is exhausted.
+    pub fn finish(&mut self, anomalies: &mut AnomalyLog) -> Result<Option<(ReadClass, Option<FromTo>)>, AnomalyError> {
+        match self.groups.finish() {
+            Some(group) => self.complete(group, anomalies),
+            None => Ok(None),
+        }
+    }
+
+    fn complete(&mut self, group: QnameGroup<(FromTo, Mapq)>, anomalies: &mut AnomalyLog) -> Result<Option<(ReadClass, Option<FromTo>)>, AnomalyError> {
+        let classified = match group.is_oversized() {
+            false => classify(&group.items, self.margin),
+            true => {
+                let example = format!("read {}: {} alignments past --max-group-records {}, handled as ambiguous", group.name, group.overflow, self.groups.max_records);
+                anomalies.record(Anomaly::OversizedGroup, &example, None)?;
+                best(&group.items, false).or(best(&group.items, true)).map(|(fromto, _mapq)| (ReadClass::Ambiguous, fromto))
+            },
+        };
+        let Some((class, fromto)) = classified else { return Ok(None) };
+        match class {
+            ReadClass::Correct => self.correct += 1,
+            ReadClass::Leaked => self.leaked += 1,
+            ReadClass::Ambiguous => self.ambiguous_reads += 1,
+        }
+        let counted = class != ReadClass::Ambiguous || self.ambiguous == AmbiguousReads::Count;
+        Ok(Some((class, counted.then_some(fromto))))
+    }
+}
+
+/// One line summary of the read classes.
+impl Display for Reconciler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.ambiguous {
+            AmbiguousReads::Count => "counted",
+            AmbiguousReads::Skip => "skipped",
+        };
+        write!(f, "{} correct, {} leaked, {} ambiguous ({})", self.correct, self.leaked, self.ambiguous_reads, action)
+    }
+}
+
+/// An alignment of a read under --best-per-read with what it is ranked by.
+#[derive(Debug)]
+struct Candidate<T> {
+    alignment_score: Option<i32>,
+    mapq: Mapq,
+    rname: String,
+    item: T,
+}
+
+/// Keeps one alignment of every read of name-grouped input (--best-per-read): the one with the
+/// highest AS tag, alignments without one ranked below those with one, then the highest mapq,
+/// ties to the lowest reference name so that reruns pick the same. Each alignment is handed in
+/// with an item (e.g. its pair), the item of the best one is handed out once the read is
+/// complete. Reads past --max-group-records keep their best buffered alignment, recorded as an
+/// oversized_group anomaly. Fails on input not grouped by name, see `GroupByQname`.
+#[derive(Debug)]
+pub struct BestPerRead<T> {
+    groups: GroupByQname<Candidate<T>>,
+    pub reads: usize,
+    pub dropped: usize,
+}
+
+impl<T> BestPerRead<T> {
+    pub fn new(max_records: usize, check: bool) -> Self {
+        Self { groups: GroupByQname::new(max_records, check), reads: 0, dropped: 0 }
+    }
+
+    /// None unless running with --best-per-read.
+    pub fn from_args(args: &Args) -> Option<Self> {
+        args.keeps_best_per_read().then(|| Self { groups: GroupByQname::from_args(args), reads: 0, dropped: 0 })
+    }
+
+    /// Adds an alignment of `sample` read from input `record`. An alignment of a new read
+    /// completes the previous one, whose best item is returned.
+    pub fn push(&mut self, sample: SampleID, sam: &SamRef, item: T, record: usize, anomalies: &mut AnomalyLog) -> Result<Option<T>, AnomalyError> {
+        let candidate = Candidate { alignment_score: sam.alignment_score(), mapq: sam.mapq, rname: sam.rname.to_string(), item };
+        match self.groups.push(sample, sam.qname, candidate, record, anomalies)? {
+            Some(group) => self.complete(group, anomalies),
+            None => Ok(None),
+        }
+    }
+
+    /// Completes the read collected last, to be called once the input is exhausted.
+    pub fn finish(&mut self, anomalies: &mut AnomalyLog) -> Result<Option<T>, AnomalyError> {
+        match self.groups.finish() {
+            Some(group) => self.complete(group, anomalies),
+            None => Ok(None),
+        }
+    }
+
+    fn complete(&mut self, group: QnameGroup<Candidate<T>>, anomalies: &mut AnomalyLog) -> Result<Option<T>, AnomalyError> {
+        if group.is_oversized() {
+            let example = format!("read {}: {} alignments past --max-group-records {}, best of the buffered ones kept", group.name, group.overflow, self.groups.max_records);
+            anomalies.record(Anomaly::OversizedGroup, &example, None)?;
+        }
+        self.reads += 1;
+        self.dropped += group.items.len() + group.overflow - 1;
+        let best = group.items.into_iter()
+            .min_by(|a, b| b.alignment_score.cmp(&a.alignment_score).then(b.mapq.cmp(&a.mapq)).then_with(|| a.rname.cmp(&b.rname)));
+        Ok(best.map(|candidate| candidate.item))
+    }
+}
+
+/// One line summary of the reads and the alignments left out.
+impl<T> Display for BestPerRead<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} reads, best alignment kept, {} other alignments dropped (--best-per-read)", self.reads, self.dropped)
+    }
+}
+
+/// Splits every read of name-grouped input evenly over its alignments (--multimap-weighting
+/// fraction): each alignment is handed in with an item (e.g. its pair), the items of a read are
+/// handed out with weight 1/n once the read is complete, n being its number of alignments. Reads
+/// past --max-group-records are split over their buffered alignments, recorded as an
+/// oversized_group anomaly. Fails on input not grouped by name, see `GroupByQname`.
+#[derive(Debug)]
+pub struct FractionPerRead<T> {
+    groups: GroupByQname<T>,
+    pub reads: usize,
+    /// Reads with more than one alignment
+    pub multimapped: usize,
+}
+
+impl<T> FractionPerRead<T> {
+    /// None unless running with --multimap-weighting fraction.
+    pub fn from_args(args: &Args) -> Option<Self> {
+        (args.multimap_weighting == MultimapWeighting::Fraction).then(|| Self { groups: GroupByQname::from_args(args), reads: 0, multimapped: 0 })
+    }
+
+    /// Adds an alignment of `sample` read from input `record`. An alignment of a new read
+    /// completes the previous one, whose weighted items are returned.
+    pub fn push(&mut self, sample: SampleID, qname: &str, item: T, record: usize, anomalies: &mut AnomalyLog) -> Result<Vec<(T, f64)>, AnomalyError> {
+        match self.groups.push(sample, qname, item, record, anomalies)? {
+            Some(group) => self.complete(group, anomalies),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Completes the read collected last, to be called once the input is exhausted.
+    pub fn finish(&mut self, anomalies: &mut AnomalyLog) -> Result<Vec<(T, f64)>, AnomalyError> {
+        match self.groups.finish() {
+            Some(group) => self.complete(group, anomalies),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn complete(&mut self, group: QnameGroup<T>, anomalies: &mut AnomalyLog) -> Result<Vec<(T, f64)>, AnomalyError> {
+        if group.is_oversized() {
+            let example = format!("read {}: {} alignments past --max-group-records {}, split over the buffered ones", group.name, group.overflow, self.groups.max_records);
+            anomalies.record(Anomaly::OversizedGroup, &example, None)?;
+        }
+        self.reads += 1;
+        if group.items.len() + group.overflow > 1 {
+            self.multimapped += 1;
+        }
+        let weight = 1.0 / group.items.len() as f64;
+        Ok(group.items.into_iter().map(|item| (item, weight)).collect())
+    }
+}
+
+/// One line summary of the reads and how many were split.
+impl<T> Display for FractionPerRead<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} reads, {} multi-mapped split evenly over their alignments (--multimap-weighting fraction)", self.reads, self.multimapped)
+    }
+}
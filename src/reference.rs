@@ -0,0 +1,263 @@
+use std::{collections::{BTreeSet, HashMap, HashSet}, io::Write, path::Path};
+
+use crate::{common::{GeneID, IdBounds, NameFormat, SamHeader, TaxID}, id_to_label::IdLabels, utils::{file_lines, strip_cr}};
+
+pub const REFERENCE_FINGERPRINT_PREFIX: &str = "#reference_fingerprint\t";
+
+/// (taxid, gene) keys of the sequences of a reference, sorted.
+pub type ReferenceKeys = BTreeSet<(TaxID, GeneID)>;
+
+/// Streams the sequence names of a FASTA file: the first word of every `>` header, without the `>`.
+pub fn fasta_names(path: impl AsRef<Path>) -> std::io::Result<impl Iterator<Item = std::io::Result<String>>> {
+    let lines = file_lines(path)?;
+    Ok(lines.filter_map(|line| match line {
+        Ok(line) => line.strip_prefix('>').map(|header| Ok(header.split_whitespace().next().unwrap_or_default().to_string())),
+        Err(e) => Some(Err(e)),
+    }))
+}
+
+/// Streams the records of a FASTA file as (name, sequence), the name as in `fasta_names` and the
+/// sequence lines joined. Lines before the first header are ignored.
+pub fn fasta_records(path: impl AsRef<Path>) -> std::io::Result<impl Iterator<Item = std::io::Result<(String, String)>>> {
+    let mut lines = file_lines(path)?.peekable();
+    Ok(std::iter::from_fn(move || {
+        let name = loop {
+            match lines.next()? {
+                Ok(line) => if let Some(header) = line.strip_prefix('>') {
+                    break header.split_whitespace().next().unwrap_or_default().to_string()
+                },
+                Err(e) => return Some(Err(e)),
+            }
+        };
+        let mut sequence = String::new();
+        while let Some(line) = lines.next_if(|line| line.as_ref().map_or(true, |line| !line.starts_with('>'))) {
+            match line {
+                Ok(line) => sequence.push_str(line.trim_end()),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok((name, sequence)))
+    }))
+}
+
+/// Count of a violation class with up to `max_examples` offending names.
+#[derive(Debug, Default, Clone)]
+pub struct Violations {
+    pub count: usize,
+    pub examples: Vec<String>,
+}
+
+impl Violations {
+    fn add(&mut self, example: String, max_examples: usize) {
+        self.count += 1;
+        if self.examples.len() < max_examples {
+            self.examples.push(example);
+        }
+    }
+}
+
+/// Result of checking the headers of a marker reference against the `taxid_geneid` naming convention.
+#[derive(Debug, Default)]
+pub struct ReferenceCheck {
+    pub sequences: usize,
+    pub malformed: Violations,
+    pub duplicates: Violations,
+    pub genes_per_taxon: HashMap<TaxID, usize>,
+    pub unlabeled: Vec<TaxID>,
+    pub max_examples: usize,
+}
+
+impl ReferenceCheck {
+    /// Streams the reference once. Names with ids beyond `bounds` count as malformed. With a label
+    /// map, taxa without a label are collected as well.
+    pub fn run(path: impl AsRef<Path>, bounds: &IdBounds, id2lab: Option<&IdLabels>, max_examples: usize) -> std::io::Result<Self> {
+        let mut result = Self { max_examples, ..Default::default() };
+        let mut seen: HashSet<(TaxID, GeneID)> = HashSet::new();
+
+        for name in fasta_names(path)? {
+            let name = name?;
+            result.sequences += 1;
+
+            let (taxid, gene) = match bounds.parse(&name) {
+                Ok(ids) => ids,
+                Err(e) => {
+                    result.malformed.add(format!("{} ({})", name, e), max_examples);
+                    continue
+                },
+            };
+            if !seen.insert((taxid, gene)) {
+                result.duplicates.add(name, max_examples);
+                continue
+            }
+            *result.genes_per_taxon.entry(taxid).or_default() += 1;
+        }
+
+        if let Some(id2lab) = id2lab {
+            result.unlabeled = result.genes_per_taxon.keys()
+                .filter(|taxid| id2lab.get(**taxid).is_none_or(|label| label.is_empty()))
+                .copied()
+                .collect();
+            result.unlabeled.sort();
+        }
+
+        Ok(result)
+    }
+
+    /// Taxa whose gene count differs from the panel size as (taxid, genes), sorted by taxid.
+    pub fn panel_mismatches(&self, panel_size: usize) -> Vec<(TaxID, usize)> {
+        let mut result = self.genes_per_taxon.iter()
+            .filter(|(_taxid, genes)| **genes != panel_size)
+            .map(|(taxid, genes)| (*taxid, *genes))
+            .collect::<Vec<(TaxID, usize)>>();
+        result.sort();
+        result
+    }
+
+    /// Malformed headers, duplicate (taxid, gene) pairs, unlabeled taxa and taxa with more genes
+    /// than the panel are hard violations. Taxa with fewer genes are only reported.
+    pub fn has_violations(&self, panel_size: Option<usize>) -> bool {
+        let over_panel = panel_size.is_some_and(|size| self.genes_per_taxon.values().any(|genes| *genes > size));
+        self.malformed.count > 0 || self.duplicates.count > 0 || !self.unlabeled.is_empty() || over_panel
+    }
+
+    pub fn write_report(&self, panel_size: Option<usize>, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "sequences\t{}", self.sequences)?;
+        writeln!(writer, "taxa\t{}", self.genes_per_taxon.len())?;
+        writeln!(writer, "malformed_headers\t{}\t{}", self.malformed.count, self.malformed.examples.join(", "))?;
+        writeln!(writer, "duplicate_pairs\t{}\t{}", self.duplicates.count, self.duplicates.examples.join(", "))?;
+        writeln!(writer, "unlabeled_taxa\t{}\t{}", self.unlabeled.len(), itertools::join(self.unlabeled.iter().take(self.max_examples), ", "))?;
+        if let Some(size) = panel_size {
+            let mismatches = self.panel_mismatches(size);
+            let (over, under): (Vec<_>, Vec<_>) = mismatches.iter().partition(|(_taxid, genes)| *genes > size);
+            writeln!(writer, "taxa_over_panel\t{}\t{}", over.len(), itertools::join(over.iter().take(self.max_examples).map(|(taxid, genes)| format!("{}:{}", taxid, genes)), ", "))?;
+            writeln!(writer, "taxa_under_panel\t{}\t{}", under.len(), itertools::join(under.iter().take(self.max_examples).map(|(taxid, genes)| format!("{}:{}", taxid, genes)), ", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Keys of the sequence names of a FASTA. Names not following the name format are left out.
+pub fn fasta_keys(path: impl AsRef<Path>, format: &NameFormat) -> std::io::Result<ReferenceKeys> {
+    let mut keys = ReferenceKeys::new();
+    for name in fasta_names(path)? {
+        keys.extend(format.parse(&name?).ok());
+    }
+    Ok(keys)
+}
+
+/// Keys of the @SQ names of a SAM header, the reference the SAM was mapped against. Names not
+/// following the name format are left out.
+pub fn sam_header_keys(header: &SamHeader, format: &NameFormat) -> ReferenceKeys {
+    header.sequences.keys().filter_map(|name| format.parse(name).ok()).collect()
+}
+
+/// Short hash of the sorted keys of a reference, stable across runs and platforms (64 bit
+/// FNV-1a). Masks carry it in their header so they are not applied to another reference build.
+pub fn reference_fingerprint(keys: &ReferenceKeys) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+
+    for (taxid, gene) in keys {
+        feed(&(*taxid as u64).to_le_bytes());
+        feed(&(*gene as u64).to_le_bytes());
+    }
+
+    format!("{:016x}", hash)
+}
+
+/// Reads the reference fingerprint from the leading comment lines of a table, None if it has none.
+pub fn read_reference_fingerprint(path: impl AsRef<Path>) -> std::io::Result<Option<String>> {
+    for line in file_lines(path)? {
+        let line = strip_cr(line?);
+        if !line.starts_with('#') {
+            break
+        }
+        if let Some(fingerprint) = line.strip_prefix(REFERENCE_FINGERPRINT_PREFIX) {
+            return Ok(Some(fingerprint.trim().to_string()))
+        }
+    }
+    Ok(None)
+}
+
+/// Keys of two references present in only one of them, sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyDifference {
+    pub only_left: Vec<(TaxID, GeneID)>,
+    pub only_right: Vec<(TaxID, GeneID)>,
+}
+
+impl KeyDifference {
+    pub fn new(left: &ReferenceKeys, right: &ReferenceKeys) -> Self {
+        Self {
+            only_left: left.difference(right).copied().collect(),
+            only_right: right.difference(left).copied().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.only_left.is_empty() && self.only_right.is_empty()
+    }
+
+    /// Writes one row per side (`only_<name>`, count, keys as taxid_gene), every key listed.
+    pub fn write_report(&self, left: &str, right: &str, writer: &mut impl Write) -> std::io::Result<()> {
+        for (name, keys) in [(left, &self.only_left), (right, &self.only_right)] {
+            writeln!(writer, "only_{}\t{}\t{}", name, keys.len(), itertools::join(keys.iter().map(|(taxid, gene)| format!("{}_{}", taxid, gene)), ","))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(pairs: &[(TaxID, GeneID)]) -> ReferenceKeys {
+        pairs.iter().copied().collect()
+    }
+
+    #[test]
+    fn fingerprint_depends_on_the_keys_only() {
+        let reference = keys(&[(1, 1), (1, 2), (2, 1)]);
+        assert_eq!(reference_fingerprint(&reference), reference_fingerprint(&keys(&[(2, 1), (1, 2), (1, 1)])));
+        assert_eq!(reference_fingerprint(&reference).len(), 16);
+        assert_ne!(reference_fingerprint(&reference), reference_fingerprint(&keys(&[(1, 1), (1, 2)])));
+        assert_ne!(reference_fingerprint(&reference), reference_fingerprint(&keys(&[(1, 1), (2, 1), (2, 2)])));
+        assert_ne!(reference_fingerprint(&keys(&[(1, 2)])), reference_fingerprint(&keys(&[(2, 1)])));
+    }
+
+    #[test]
+    fn fingerprint_is_read_from_the_header_lines() {
+        let path = crate::utils::test_path("reference_fingerprint.tsv");
+        std::fs::write(&path, format!("#format\tv2\n{}0123456789abcdef\n1\t2\n", REFERENCE_FINGERPRINT_PREFIX)).unwrap();
+        assert_eq!(read_reference_fingerprint(&path).unwrap().as_deref(), Some("0123456789abcdef"));
+        // Only the leading comment lines are headers
+        std::fs::write(&path, format!("#format\tv2\n1\t2\n{}0123456789abcdef\n", REFERENCE_FINGERPRINT_PREFIX)).unwrap();
+        assert_eq!(read_reference_fingerprint(&path).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fasta_keys_skip_names_of_another_format() {
+        let path = crate::utils::test_path("reference_keys.fasta");
+        std::fs::write(&path, ">2_1 description\nACGT\nACGT\n>1_2\nACGT\n>plasmid\nACGT\n>1_1\nACGT\n").unwrap();
+        assert_eq!(fasta_keys(&path, &NameFormat::default()).unwrap(), keys(&[(1, 1), (1, 2), (2, 1)]));
+        assert_eq!(fasta_records(&path).unwrap().next().unwrap().unwrap(), ("2_1".to_string(), "ACGTACGT".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn key_differences_list_both_sides() {
+        let difference = KeyDifference::new(&keys(&[(1, 1), (1, 2), (3, 1)]), &keys(&[(1, 1), (2, 1), (2, 2)]));
+        assert_eq!(difference.only_left, [(1, 2), (3, 1)]);
+        assert_eq!(difference.only_right, [(2, 1), (2, 2)]);
+        let mut report = Vec::new();
+        difference.write_report("mask_reference", "fasta", &mut report).unwrap();
+        assert_eq!(String::from_utf8(report).unwrap(), "only_mask_reference\t2\t1_2,3_1\nonly_fasta\t2\t2_1,2_2\n");
+        assert!(KeyDifference::new(&keys(&[(1, 1)]), &keys(&[(1, 1)])).is_empty());
+    }
+}
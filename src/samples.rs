@@ -0,0 +1,114 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use crate::common::{Args, SamRef};
+
+pub type SampleID = u32;
+
+/// Sample of the records whose sample cannot be told (no RG tag, no separator in the read name).
+pub const UNKNOWN_SAMPLE: &str = "unknown";
+
+/// Read group of the records without an RG tag with --by-read-group.
+pub const NO_READ_GROUP: &str = "none";
+
+/// Header line of a table stratified by --by-read-group, naming its read groups in order.
+pub const READ_GROUPS_PREFIX: &str = "#read_groups\t";
+
+/// How the sample of a record of a multiplexed SAM is told (--split-by).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SplitBy {
+    /// The RG tag of the record
+    ReadGroup,
+    /// The read name up to the first separator, which is stripped before the ids are parsed
+    QnamePrefix(String),
+}
+
+impl FromStr for SplitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "rg" => Ok(SplitBy::ReadGroup),
+            Some(("qname-prefix", separator)) if !separator.is_empty() => Ok(SplitBy::QnamePrefix(separator.to_string())),
+            _ => Err(format!("Invalid --split-by {} (expected rg or qname-prefix:<separator>)", s)),
+        }
+    }
+}
+
+impl Display for SplitBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SplitBy::ReadGroup => write!(f, "rg"),
+            SplitBy::QnamePrefix(separator) => write!(f, "qname-prefix:{}", separator),
+        }
+    }
+}
+
+/// Samples of a run by id, in order of first appearance. Without --split-by every record is
+/// sample 0 and no names are kept.
+#[derive(Debug, Default, Clone)]
+pub struct Samples {
+    split_by: Option<SplitBy>,
+    /// Sample of the records whose sample cannot be told
+    unknown: &'static str,
+    pub names: Vec<String>,
+    index: HashMap<String, SampleID>,
+}
+
+impl Samples {
+    pub fn new(split_by: Option<SplitBy>) -> Self {
+        Self { split_by, unknown: UNKNOWN_SAMPLE, ..Default::default() }
+    }
+
+    /// Samples by the RG tag, records without one going to read group "none".
+    pub fn by_read_group() -> Self {
+        Self { split_by: Some(SplitBy::ReadGroup), unknown: NO_READ_GROUP, ..Default::default() }
+    }
+
+    /// Samples by --by-read-group or --split-by.
+    pub fn from_args(args: &Args) -> Self {
+        match args.by_read_group {
+            true => Self::by_read_group(),
+            false => Self::new(args.split_by.clone()),
+        }
+    }
+
+    /// Sample of a record, stripping a sample prefix from its read name.
+    pub fn assign(&mut self, sam: &mut SamRef) -> SampleID {
+        let Some(split_by) = &self.split_by else { return 0 };
+        let name = match split_by {
+            SplitBy::ReadGroup => sam.tag("RG").map(str::to_string),
+            SplitBy::QnamePrefix(separator) => sam.qname.split_once(separator.as_str()).map(|(sample, rest)| {
+                sam.qname = rest;
+                sample.to_string()
+            }),
+        };
+        let name = name.filter(|name| !name.is_empty()).unwrap_or_else(|| self.unknown.to_string());
+        if let Some(id) = self.index.get(&name) {
+            return *id
+        }
+        let id = self.names.len() as SampleID;
+        self.index.insert(name.clone(), id);
+        self.names.push(name);
+        id
+    }
+}
+
+/// Sample name made safe to be part of a file name.
+pub fn file_part(sample: &str) -> String {
+    sample.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+/// Table of a sample, tables up to it created with `empty` as needed.
+pub fn sample_table<T>(tables: &mut Vec<T>, sample: SampleID, empty: impl Fn() -> T) -> &mut T {
+    let sample = sample as usize;
+    if sample >= tables.len() {
+        tables.resize_with(sample + 1, empty);
+    }
+    &mut tables[sample]
+}
+
+/// Names the tables of `samples`, empty ones for samples none of whose records were counted.
+pub fn name_tables<T>(samples: Samples, mut tables: Vec<T>, empty: impl Fn() -> T) -> Vec<(String, T)> {
+    tables.resize_with(samples.names.len(), empty);
+    samples.names.into_iter().zip(tables).collect()
+}
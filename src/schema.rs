@@ -0,0 +1,414 @@
+use std::fmt::Display;
+
+use thiserror::Error;
+
+/// Significant digits of every float column, as many as an f64 holds exactly. Floats are always
+/// written in fixed notation so a reader never sees an exponent, the digits after the decimal
+/// point follow from the magnitude so small values keep their precision.
+pub const SIGNIFICANT_DIGITS: i32 = f64::DIGITS as i32;
+
+/// What a numeric column may contain.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NumericClass {
+    /// Non-negative integer, digits only.
+    Count,
+    /// A positive count or -1 for an empty gene slot.
+    Slot,
+    /// Fixed-point decimal with an optional leading minus, no exponent.
+    Fixed,
+}
+
+impl Display for NumericClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            NumericClass::Count => "an integer count",
+            NumericClass::Slot => "a positive integer count or -1",
+            NumericClass::Fixed => "a fixed-point number",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Line {line}, column {column}: expected {expected}, found '{token}'")]
+pub struct NumericError {
+    pub line: usize,
+    pub column: String,
+    pub expected: NumericClass,
+    pub token: String,
+}
+
+/// A row of a table that cannot be read.
+#[derive(Debug, Error)]
+pub enum RowError {
+    #[error(transparent)]
+    Numeric(#[from] NumericError),
+    #[error("Line {line}: expected at least {expected} columns, found {found}")]
+    Columns { line: usize, expected: usize, found: usize },
+}
+
+/// Name and class of a column.
+#[derive(Debug, Copy, Clone)]
+pub struct ColumnFormat {
+    pub name: &'static str,
+    pub class: NumericClass,
+}
+
+impl ColumnFormat {
+    /// Validates a token against the class of the column and parses it.
+    pub fn parse<T: std::str::FromStr>(&self, token: &str, line: usize) -> Result<T, NumericError> {
+        parse_token(token, self.class, || self.name.to_string(), line)
+    }
+}
+
+/// Fraction of the reads of a pair on the reverse strand, written after the total and unmapped
+/// columns of a pairwise table (--strand-bias).
+pub const STRAND_BIAS: ColumnFormat = ColumnFormat { name: "strand_bias", class: NumericClass::Fixed };
+
+/// Numeric formats of a table: leading fixed columns, then any number of `repeated` gene columns.
+#[derive(Debug, Copy, Clone)]
+pub struct TableSchema {
+    pub columns: &'static [ColumnFormat],
+    pub repeated: ColumnFormat,
+}
+
+pub const PAIRWISE: TableSchema = TableSchema {
+    columns: &[
+        ColumnFormat { name: "from", class: NumericClass::Count },
+        ColumnFormat { name: "to", class: NumericClass::Count },
+        ColumnFormat { name: "total", class: NumericClass::Count },
+    ],
+    repeated: ColumnFormat { name: "gene", class: NumericClass::Slot },
+};
+
+/// A pairwise table with the unmapped records of the donor after the total (--count-unmapped).
+pub const PAIRWISE_UNMAPPED: TableSchema = TableSchema {
+    columns: &[
+        ColumnFormat { name: "from", class: NumericClass::Count },
+        ColumnFormat { name: "to", class: NumericClass::Count },
+        ColumnFormat { name: "total", class: NumericClass::Count },
+        ColumnFormat { name: "unmapped", class: NumericClass::Count },
+    ],
+    repeated: ColumnFormat { name: "gene", class: NumericClass::Slot },
+};
+
+/// A pairwise table of fractional reads (--multimap-weighting fraction).
+pub const PAIRWISE_FRACTION: TableSchema = TableSchema {
+    columns: &[
+        ColumnFormat { name: "from", class: NumericClass::Count },
+        ColumnFormat { name: "to", class: NumericClass::Count },
+        ColumnFormat { name: "total", class: NumericClass::Fixed },
+    ],
+    repeated: ColumnFormat { name: "gene", class: NumericClass::Fixed },
+};
+
+/// A pairwise table of fractional reads with the unmapped records of the donor after the total.
+pub const PAIRWISE_FRACTION_UNMAPPED: TableSchema = TableSchema {
+    columns: &[
+        ColumnFormat { name: "from", class: NumericClass::Count },
+        ColumnFormat { name: "to", class: NumericClass::Count },
+        ColumnFormat { name: "total", class: NumericClass::Fixed },
+        ColumnFormat { name: "unmapped", class: NumericClass::Count },
+    ],
+    repeated: ColumnFormat { name: "gene", class: NumericClass::Fixed },
+};
+
+pub const NORMALIZED: TableSchema = TableSchema {
+    columns: &[
+        ColumnFormat { name: "recipient", class: NumericClass::Count },
+        ColumnFormat { name: "total", class: NumericClass::Fixed },
+    ],
+    repeated: ColumnFormat { name: "gene", class: NumericClass::Fixed },
+};
+
+impl TableSchema {
+    pub fn column(&self, index: usize) -> ColumnFormat {
+        self.columns.get(index).copied().unwrap_or(self.repeated)
+    }
+
+    fn column_name(&self, index: usize) -> String {
+        match self.columns.get(index) {
+            Some(column) => column.name.to_string(),
+            None => format!("{}_{}", self.repeated.name, index + 1 - self.columns.len()),
+        }
+    }
+
+    /// Validates a token against the class of its column and parses it.
+    pub fn parse<T: std::str::FromStr>(&self, token: &str, index: usize, line: usize) -> Result<T, NumericError> {
+        parse_token(token, self.column(index).class, || self.column_name(index), line)
+    }
+
+    /// Fails on a row of fewer tokens than leading columns.
+    pub fn check_columns(&self, tokens: &[&str], line: usize) -> Result<(), RowError> {
+        match tokens.len() < self.columns.len() {
+            true => Err(RowError::Columns { line, expected: self.columns.len(), found: tokens.len() }),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Parses a token of a column of `class`, the column named by `column` in the error.
+fn parse_token<T: std::str::FromStr>(token: &str, class: NumericClass, column: impl Fn() -> String, line: usize) -> Result<T, NumericError> {
+    let error = || NumericError { line, column: column(), expected: class, token: token.to_string() };
+    if !is_valid(token, class) {
+        return Err(error())
+    }
+    token.parse().map_err(|_| error())
+}
+
+fn is_valid(token: &str, class: NumericClass) -> bool {
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match class {
+        NumericClass::Count => digits(token),
+        NumericClass::Slot => (digits(token) && token.bytes().any(|b| b != b'0')) || token == "-1",
+        NumericClass::Fixed => {
+            let unsigned = token.strip_prefix('-').unwrap_or(token);
+            match unsigned.split_once('.') {
+                Some((int, frac)) => digits(int) && digits(frac),
+                None => digits(unsigned),
+            }
+        },
+    }
+}
+
+/// Formats a float column value in fixed notation with `SIGNIFICANT_DIGITS` significant digits,
+/// without trailing zeros.
+pub fn fmt_fixed(value: f64) -> String {
+    if value == 0.0 {
+        return "0".to_string()
+    }
+    if !value.is_finite() {
+        return value.to_string()
+    }
+    let magnitude = value.abs().log10().floor() as i32;
+    let precision = (SIGNIFICANT_DIGITS - 1 - magnitude).max(0) as usize;
+    let fixed = format!("{:.*}", precision, value);
+    match fixed.contains('.') {
+        true => fixed.trim_end_matches('0').trim_end_matches('.').to_string(),
+        false => fixed,
+    }
+}
+
+/// Release provenance header of a table (--release-tag, e.g. the GTDB version). A table of one
+/// release carries `#release<TAB>tag`. merge_pairwise writes tables of tagged releases with
+/// `#releases<TAB>tag<TAB>tag...` and leads every row with a source column naming its release.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReleaseHeader {
+    Tag(String),
+    PerRow(Vec<String>),
+}
+
+impl ReleaseHeader {
+    pub const PREFIX: &'static str = "#release\t";
+    pub const PER_ROW_PREFIX: &'static str = "#releases\t";
+
+    pub fn parse(line: &str) -> Option<Result<Self, String>> {
+        if let Some(tag) = line.strip_prefix(Self::PREFIX) {
+            return Some(parse_release_tag(tag).map(ReleaseHeader::Tag))
+        }
+        let tags = line.strip_prefix(Self::PER_ROW_PREFIX)?;
+        Some(tags.split('\t').map(parse_release_tag).collect::<Result<Vec<String>, String>>().map(ReleaseHeader::PerRow))
+    }
+
+    /// The one release of the table, an error naming them all if it holds several.
+    pub fn single(&self) -> Result<&str, String> {
+        match self {
+            ReleaseHeader::Tag(tag) => Ok(tag),
+            ReleaseHeader::PerRow(tags) if tags.len() == 1 => Ok(&tags[0]),
+            ReleaseHeader::PerRow(tags) => Err(format!("holds the releases {}, split it by its source column first", tags.join(", "))),
+        }
+    }
+
+    /// A row of a table with a source column without it, an error for a release missing from
+    /// the header. Rows of other tables are returned unchanged.
+    pub fn strip_source<'a>(&self, line: &'a str, line_no: usize) -> Result<&'a str, String> {
+        let ReleaseHeader::PerRow(tags) = self else { return Ok(line) };
+        match line.split_once('\t') {
+            Some((tag, row)) if tags.iter().any(|t| t == tag) => Ok(row),
+            Some((tag, _row)) => Err(format!("Line {}: release '{}' is not listed in the {} header", line_no, tag, Self::PER_ROW_PREFIX.trim_end())),
+            None => Err(format!("Line {}: missing the release source column", line_no)),
+        }
+    }
+}
+
+impl Display for ReleaseHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReleaseHeader::Tag(tag) => write!(f, "{}{}", Self::PREFIX, tag),
+            ReleaseHeader::PerRow(tags) => write!(f, "{}{}", Self::PER_ROW_PREFIX, tags.join("\t")),
+        }
+    }
+}
+
+/// Validates a release tag (--release-tag): non-empty, a single column.
+pub fn parse_release_tag(tag: &str) -> Result<String, String> {
+    match tag.is_empty() || tag.contains(['\t', '\n', '\r']) {
+        true => Err(format!("Invalid release tag '{}' (must be non-empty without tabs or line breaks)", tag)),
+        false => Ok(tag.to_string()),
+    }
+}
+
+/// Refuses to combine tagged with untagged tables, given as (path, release tag).
+pub fn check_release_mix<'a>(tables: impl IntoIterator<Item = (&'a str, Option<&'a str>)>) -> Result<(), String> {
+    let (tagged, untagged): (Vec<_>, Vec<_>) = tables.into_iter().partition(|(_path, tag)| tag.is_some());
+    match (tagged.first(), untagged.first()) {
+        (Some((tagged, Some(tag))), Some((untagged, None))) => Err(format!("{} has no release tag but {} is tagged {}; tag all tables (--release-tag) or none", untagged, tagged, tag)),
+        _ => Ok(()),
+    }
+}
+
+/// Output-time cap on normalized values (--clamp). Larger values are written as the cap and marked,
+/// by a `+` suffix or (--clamp-flags) by a 0/1 column after every clamped column. Only the
+/// rendering changes, the values in memory stay exact. Announced by `#clamp<TAB>limit<TAB>suffix|flags`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Clamp {
+    pub limit: f64,
+    pub flags: bool,
+}
+
+impl Clamp {
+    pub const PREFIX: &'static str = "#clamp\t";
+    pub const SUFFIX: char = '+';
+
+    pub fn parse(line: &str) -> Option<Result<Self, String>> {
+        let fields = line.strip_prefix(Self::PREFIX)?;
+        let (limit, marks) = fields.split_once('\t').unwrap_or((fields, "suffix"));
+        let flags = match marks {
+            "suffix" => false,
+            "flags" => true,
+            _ => return Some(Err(format!("Unknown clamp marks '{}'", marks))),
+        };
+        Some(limit.parse().map(|limit| Self { limit, flags }).map_err(|_| format!("Invalid clamp limit '{}'", limit)))
+    }
+
+    /// Formats a value in fixed notation, capped and marked if it exceeds the limit. Clamped
+    /// values are counted in `clamped`.
+    pub fn render(&self, value: f64, clamped: &mut usize) -> String {
+        let over = value > self.limit;
+        *clamped += over as usize;
+        let shown = fmt_fixed(if over { self.limit } else { value });
+        match (self.flags, over) {
+            (true, _) => format!("{}\t{}", shown, over as u8),
+            (false, true) => format!("{}{}", shown, Self::SUFFIX),
+            (false, false) => shown,
+        }
+    }
+
+    /// Number of columns a rendered value takes.
+    pub fn width(&self) -> usize {
+        1 + self.flags as usize
+    }
+}
+
+impl Display for Clamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}\t{}", Self::PREFIX, fmt_fixed(self.limit), if self.flags { "flags" } else { "suffix" })
+    }
+}
+
+/// Formats a normalized value, through `clamp` if given.
+pub fn fmt_clamped(value: f64, clamp: Option<&Clamp>, clamped: &mut usize) -> String {
+    match clamp {
+        Some(clamp) => clamp.render(value, clamped),
+        None => fmt_fixed(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SplitMix64;
+
+    #[test]
+    fn release_headers_read_back_as_written() {
+        for header in [ReleaseHeader::Tag("r220".to_string()), ReleaseHeader::PerRow(vec!["r214".to_string(), "r220".to_string()])] {
+            assert_eq!(ReleaseHeader::parse(&header.to_string()), Some(Ok(header.clone())));
+        }
+        assert_eq!(ReleaseHeader::parse("#pairs\tdirected\tgene_base=1"), None);
+        assert!(matches!(ReleaseHeader::parse("#release\t"), Some(Err(_))));
+        assert!(matches!(ReleaseHeader::parse("#releases\tr214\t"), Some(Err(_))));
+        assert!(parse_release_tag("r2\t20").is_err() && parse_release_tag("r220\n").is_err());
+
+        let tag = ReleaseHeader::Tag("r220".to_string());
+        let per_row = ReleaseHeader::PerRow(vec!["r214".to_string(), "r220".to_string()]);
+        assert_eq!(tag.single(), Ok("r220"));
+        assert_eq!(ReleaseHeader::PerRow(vec!["r214".to_string()]).single(), Ok("r214"));
+        assert_eq!(per_row.single(), Err("holds the releases r214, r220, split it by its source column first".to_string()));
+
+        // Only tables of several releases lead their rows with the release
+        assert_eq!(tag.strip_source("1\t2\t3", 4), Ok("1\t2\t3"));
+        assert_eq!(per_row.strip_source("r214\t1\t2\t3", 4), Ok("1\t2\t3"));
+        assert_eq!(per_row.strip_source("r207\t1\t2\t3", 4), Err("Line 4: release 'r207' is not listed in the #releases header".to_string()));
+        assert_eq!(per_row.strip_source("r214", 5), Err("Line 5: missing the release source column".to_string()));
+    }
+
+    #[test]
+    fn tagged_and_untagged_tables_are_not_mixed() {
+        assert!(check_release_mix([("a.tsv", Some("r214")), ("b.tsv", Some("r220"))]).is_ok());
+        assert!(check_release_mix([("a.tsv", None), ("b.tsv", None)]).is_ok());
+        assert_eq!(check_release_mix([("a.tsv", None), ("b.tsv", Some("r220")), ("c.tsv", None)]),
+            Err("a.tsv has no release tag but b.tsv is tagged r220; tag all tables (--release-tag) or none".to_string()));
+    }
+
+    #[test]
+    fn fixed_notation_keeps_small_values() {
+        assert_eq!(fmt_fixed(0.0), "0");
+        assert_eq!(fmt_fixed(-0.0), "0");
+        assert_eq!(fmt_fixed(0.5), "0.5");
+        assert_eq!(fmt_fixed(3.0), "3");
+        assert_eq!(fmt_fixed(-2.25), "-2.25");
+        assert_eq!(fmt_fixed(1e-7), "0.0000001");
+        assert_eq!(fmt_fixed(1.5e-12), "0.0000000000015");
+        assert_eq!(fmt_fixed(123456.789), "123456.789");
+        assert_eq!(fmt_fixed(1e20), "100000000000000000000");
+        assert_eq!(fmt_fixed(1.0 / 3.0), "0.333333333333333");
+    }
+
+    #[test]
+    fn fixed_values_read_back_as_fixed_columns() {
+        let mut rng = SplitMix64::new(713);
+        for _ in 0..10_000 {
+            let mantissa = rng.below(1 << 53) as f64 / (1u64 << 53) as f64;
+            let value = mantissa * 10f64.powi(rng.below(40) as i32 - 30);
+            let written = fmt_fixed(value);
+            let read: f64 = NORMALIZED.parse(&written, 1, 1).unwrap_or_else(|e| panic!("{}: {}", value, e));
+            assert!(value == 0.0 || ((read - value) / value).abs() < 1e-14, "{} written as {}", value, written);
+        }
+    }
+
+    #[test]
+    fn numeric_classes_pin_down_accepted_tokens() {
+        let accepts = |class: NumericClass, token: &str| is_valid(token, class);
+        for token in ["0", "7", "0012"] {
+            assert!(accepts(NumericClass::Count, token), "{}", token);
+        }
+        for token in ["1", "15", "-1"] {
+            assert!(accepts(NumericClass::Slot, token), "{}", token);
+        }
+        for token in ["0", "-3", "0.5", "-0.25", "12.0"] {
+            assert!(accepts(NumericClass::Fixed, token), "{}", token);
+        }
+        // Scientific notation, signs, separators, blanks and partial decimals
+        let rejected = ["1e-7", "1E3", "2.5e1", "+1", "+0.5", "1,000", "1_000", "1 000", "1.000,5", " 1", "1 ", "", "-", ".5", "5.", "1.2.3", "NaN", "inf", "0x10"];
+        for class in [NumericClass::Count, NumericClass::Slot, NumericClass::Fixed] {
+            for token in rejected {
+                assert!(!accepts(class, token), "{} accepts '{}'", class, token);
+            }
+        }
+        assert!(!accepts(NumericClass::Count, "-1"));
+        assert!(!accepts(NumericClass::Count, "1.5"));
+        assert!(!accepts(NumericClass::Slot, "0"));
+        assert!(!accepts(NumericClass::Slot, "-2"));
+        assert!(!accepts(NumericClass::Slot, "1.0"));
+    }
+
+    #[test]
+    fn errors_name_the_column_and_line() {
+        let error = PAIRWISE.parse::<u64>("1e3", 2, 7).unwrap_err();
+        assert_eq!(error.to_string(), "Line 7, column total: expected an integer count, found '1e3'");
+        let error = PAIRWISE.parse::<i64>("+4", 5, 3).unwrap_err();
+        assert_eq!(error.to_string(), "Line 3, column gene_3: expected a positive integer count or -1, found '+4'");
+        let error = PAIRWISE.check_columns(&["1", "2"], 4).unwrap_err();
+        assert_eq!(error.to_string(), "Line 4: expected at least 3 columns, found 2");
+    }
+}
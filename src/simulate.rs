@@ -0,0 +1,243 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt::Display, io::Write};
+
+use crate::{common::{sam_input, sam_to_ids, Anomaly, AnomalyError, AnomalyLog, Args, FromTo, GeneID, GroupByQname, InputError, QnameGroup, TaxID}, filter::{Decision, Mapq, RecordFilter}, pairwise_leakage::TinyTaxID, reconcile::{classify, ReadClass}};
+
+/// Assignment of a read by its best alignment.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadState {
+    Correct,
+    Leaked,
+    Ambiguous,
+    /// Every alignment of the read was masked
+    Unassigned,
+}
+
+impl ReadState {
+    pub const ALL: [ReadState; 4] = [ReadState::Correct, ReadState::Leaked, ReadState::Ambiguous, ReadState::Unassigned];
+
+    fn of(class: Option<ReadClass>) -> Self {
+        match class {
+            Some(ReadClass::Correct) => ReadState::Correct,
+            Some(ReadClass::Leaked) => ReadState::Leaked,
+            Some(ReadClass::Ambiguous) => ReadState::Ambiguous,
+            None => ReadState::Unassigned,
+        }
+    }
+
+    fn is_foreign(&self) -> bool {
+        matches!(self, ReadState::Leaked | ReadState::Ambiguous)
+    }
+}
+
+impl Display for ReadState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ReadState::Correct => "correct",
+            ReadState::Leaked => "leaked",
+            ReadState::Ambiguous => "ambiguous",
+            ReadState::Unassigned => "unassigned",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Reads of a query taxon by state before and after masking.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaxonSimulation {
+    pub before: [usize; ReadState::ALL.len()],
+    pub after: [usize; ReadState::ALL.len()],
+    /// Leaked or ambiguous reads that become correct
+    pub rescued: usize,
+    /// Leaked or ambiguous reads that stay so, their best foreign alignment moving to another taxon
+    pub leaked_elsewhere: usize,
+    /// Correct reads that do not stay correct
+    pub lost: usize,
+}
+
+impl TaxonSimulation {
+    pub fn reads(&self) -> usize {
+        self.before.iter().sum()
+    }
+}
+
+/// Effect of a mask on the assignment of the reads of a name-grouped SAM: every read is assigned
+/// by its best alignment (see `classify`) once with all of its alignments and once without those
+/// to masked (taxid, gene) references.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaskSimulation {
+    pub taxa: BTreeMap<TinyTaxID, TaxonSimulation>,
+    /// Reads by state before (rows) and after (columns)
+    pub confusion: [[usize; ReadState::ALL.len()]; ReadState::ALL.len()],
+    pub masked_alignments: usize,
+    /// Reads with more alignments than --max-group-records, left out
+    pub skipped: usize,
+}
+
+impl MaskSimulation {
+    /// Adds a read from its alignments as (pair, mapq), `margin` as in `classify`.
+    pub fn add_read(&mut self, alignments: &[(FromTo, Mapq)], mask: &HashMap<TaxID, HashSet<GeneID>>, margin: Mapq) {
+        let Some((first, _mapq)) = alignments.first() else { return };
+        let masked = |fromto: &FromTo| mask.get(&(fromto.reference as TaxID)).is_some_and(|genes| genes.contains(&(fromto.reference_gene as GeneID)));
+        let kept = alignments.iter().filter(|(fromto, _mapq)| !masked(fromto)).copied().collect::<Vec<(FromTo, Mapq)>>();
+        self.masked_alignments += alignments.len() - kept.len();
+
+        let (before, after) = (classify(alignments, margin), classify(&kept, margin));
+        let state = |classified: Option<(ReadClass, FromTo)>| ReadState::of(classified.map(|(class, _fromto)| class));
+        let (state_before, state_after) = (state(before), state(after));
+        self.confusion[state_before as usize][state_after as usize] += 1;
+
+        let taxon = self.taxa.entry(first.query).or_default();
+        taxon.before[state_before as usize] += 1;
+        taxon.after[state_after as usize] += 1;
+        if state_before.is_foreign() && state_after == ReadState::Correct {
+            taxon.rescued += 1;
+        }
+        if state_before == ReadState::Correct && state_after != ReadState::Correct {
+            taxon.lost += 1;
+        }
+        if let (Some((_, old)), Some((_, new))) = (before, after) {
+            if state_before.is_foreign() && state_after.is_foreign() && old.reference != new.reference {
+                taxon.leaked_elsewhere += 1;
+            }
+        }
+    }
+
+    /// Writes one row per query taxon: reads, reads per state before and after, rescued, leaked
+    /// elsewhere and lost reads. Returns the number of rows.
+    pub fn write_taxa(&self, writer: &mut impl Write) -> std::io::Result<usize> {
+        let states = |suffix: &str| ReadState::ALL.iter().map(|state| format!("\t{}_{}", state, suffix)).collect::<String>();
+        writeln!(writer, "#taxid\treads{}{}\trescued\tleaked_elsewhere\tlost", states("before"), states("after"))?;
+        let counts = |counts: &[usize]| counts.iter().map(|count| format!("\t{}", count)).collect::<String>();
+        for (taxid, taxon) in &self.taxa {
+            writeln!(writer, "{}\t{}{}{}\t{}\t{}\t{}", taxid, taxon.reads(), counts(&taxon.before), counts(&taxon.after), taxon.rescued, taxon.leaked_elsewhere, taxon.lost)?;
+        }
+        Ok(self.taxa.len())
+    }
+
+    /// Writes the reads by state before (rows) and after masking (columns), then the totals of
+    /// rescued, leaked elsewhere and lost reads and of masked alignments.
+    pub fn write_confusion(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "#before{}", ReadState::ALL.iter().map(|state| format!("\t{}", state)).collect::<String>())?;
+        for state in ReadState::ALL {
+            writeln!(writer, "{}{}", state, self.confusion[state as usize].iter().map(|count| format!("\t{}", count)).collect::<String>())?;
+        }
+        let total = |value: fn(&TaxonSimulation) -> usize| self.taxa.values().map(value).sum::<usize>();
+        writeln!(writer, "#rescued\t{}", total(|taxon| taxon.rescued))?;
+        writeln!(writer, "#leaked_elsewhere\t{}", total(|taxon| taxon.leaked_elsewhere))?;
+        writeln!(writer, "#lost\t{}", total(|taxon| taxon.lost))?;
+        writeln!(writer, "#masked_alignments\t{}", self.masked_alignments)?;
+        writeln!(writer, "#skipped_reads\t{}", self.skipped)
+    }
+}
+
+/// Replays a name-grouped SAM (all alignments of a read in a row) with and without the alignments
+/// to masked genes. Records are filtered as for counting; reads with more alignments than
+/// --max-group-records are left out as an oversized_group anomaly.
+pub fn simulate_mask(args: &Args, mask: &HashMap<TaxID, HashSet<GeneID>>, anomalies: &mut AnomalyLog) -> Result<MaskSimulation, InputError> {
+    let mut iter = sam_input(args)?;
+    anomalies.set_input(&args.input_label());
+
+    let mut filter = RecordFilter::from_args(args);
+    let mut result = MaskSimulation::default();
+    let mut groups: GroupByQname<(FromTo, Mapq)> = GroupByQname::from_args(args);
+
+    let flush = |group: Option<QnameGroup<(FromTo, Mapq)>>, result: &mut MaskSimulation, anomalies: &mut AnomalyLog| -> Result<(), AnomalyError> {
+        let Some(group) = group else { return Ok(()) };
+        if group.is_oversized() {
+            let example = format!("read {}: {} alignments past --max-group-records {}, left out of the simulation", group.name, group.overflow, args.max_group_records);
+            anomalies.record(Anomaly::OversizedGroup, &example, None)?;
+            result.skipped += 1;
+            return Ok(())
+        }
+        result.add_read(&group.items, mask, args.reconcile_margin);
+        Ok(())
+    };
+
+    let mut line = String::new();
+    while let Some(sam) = iter.next_valid_ref(&mut line, anomalies)? {
+        if filter.evaluate(&sam) != Decision::Keep { continue };
+        match sam_to_ids(&sam, &filter.bounds, &mut filter.names) {
+            Ok(fromto) => flush(groups.push(fromto.sample, sam.qname, (fromto, sam.mapq), iter.line, anomalies)?, &mut result, anomalies)?,
+            Err(e) => anomalies.record(Anomaly::UnparseableName, &e, Some(iter.line))?,
+        }
+    }
+    flush(groups.finish(), &mut result, anomalies)?;
+    eprintln!("Records: {}", filter);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pairwise_leakage::TinyGeneID;
+
+    fn alignment(query: TinyTaxID, reference: TinyTaxID, reference_gene: TinyGeneID, mapq: Mapq) -> (FromTo, Mapq) {
+        (FromTo { query, reference, query_gene: 1, reference_gene, sample: 0, aligned_length: None, weight: None, reverse: None }, mapq)
+    }
+
+    /// A read rescued, one unassigned, one leaking elsewhere, one correct and one ambiguous
+    /// throughout, under a mask of gene 1 of taxa 2 and 3.
+    fn simulated() -> MaskSimulation {
+        let mask = HashMap::from([(2, HashSet::from([1])), (3, HashSet::from([1]))]);
+        let mut simulation = MaskSimulation::default();
+        for read in [
+            vec![alignment(1, 2, 1, 40), alignment(1, 1, 1, 20)],
+            vec![alignment(2, 2, 1, 30)],
+            vec![alignment(1, 2, 1, 40), alignment(1, 3, 2, 30)],
+            vec![alignment(1, 1, 2, 30)],
+            vec![alignment(3, 3, 2, 30), alignment(3, 1, 1, 30)],
+            vec![],
+        ] {
+            simulation.add_read(&read, &mask, 0);
+        }
+        simulation
+    }
+
+    #[test]
+    fn masked_alignments_change_the_assignment_of_reads() {
+        let simulation = simulated();
+        let (correct, leaked, ambiguous, unassigned) = (ReadState::Correct as usize, ReadState::Leaked as usize, ReadState::Ambiguous as usize, ReadState::Unassigned as usize);
+        assert_eq!(simulation.taxa[&1], TaxonSimulation { before: [1, 2, 0, 0], after: [2, 1, 0, 0], rescued: 1, leaked_elsewhere: 1, lost: 0 });
+        assert_eq!(simulation.taxa[&2], TaxonSimulation { before: [1, 0, 0, 0], after: [0, 0, 0, 1], rescued: 0, leaked_elsewhere: 0, lost: 1 });
+        assert_eq!(simulation.taxa[&3], TaxonSimulation { before: [0, 0, 1, 0], after: [0, 0, 1, 0], rescued: 0, leaked_elsewhere: 0, lost: 0 });
+        let mut confusion = [[0; ReadState::ALL.len()]; ReadState::ALL.len()];
+        for (before, after) in [(correct, correct), (leaked, correct), (leaked, leaked), (correct, unassigned), (ambiguous, ambiguous)] {
+            confusion[before][after] += 1;
+        }
+        assert_eq!(simulation.confusion, confusion);
+        assert_eq!(simulation.masked_alignments, 3);
+
+        // Without a mask nothing changes
+        let mut unmasked = MaskSimulation::default();
+        unmasked.add_read(&[alignment(1, 2, 1, 40), alignment(1, 1, 1, 20)], &HashMap::new(), 0);
+        assert_eq!(unmasked.taxa[&1], TaxonSimulation { before: [0, 1, 0, 0], after: [0, 1, 0, 0], rescued: 0, leaked_elsewhere: 0, lost: 0 });
+    }
+
+    #[test]
+    fn simulations_are_written_per_taxon_and_as_a_confusion_matrix() {
+        let simulation = simulated();
+        let mut taxa = Vec::new();
+        assert_eq!(simulation.write_taxa(&mut taxa).unwrap(), 3);
+        assert_eq!(String::from_utf8(taxa).unwrap(), "\
+#taxid\treads\tcorrect_before\tleaked_before\tambiguous_before\tunassigned_before\tcorrect_after\tleaked_after\tambiguous_after\tunassigned_after\trescued\tleaked_elsewhere\tlost
+1\t3\t1\t2\t0\t0\t2\t1\t0\t0\t1\t1\t0
+2\t1\t1\t0\t0\t0\t0\t0\t0\t1\t0\t0\t1
+3\t1\t0\t0\t1\t0\t0\t0\t1\t0\t0\t0\t0
+");
+        let mut confusion = Vec::new();
+        simulation.write_confusion(&mut confusion).unwrap();
+        assert_eq!(String::from_utf8(confusion).unwrap(), "\
+#before\tcorrect\tleaked\tambiguous\tunassigned
+correct\t1\t0\t0\t1
+leaked\t1\t1\t0\t0
+ambiguous\t0\t0\t1\t0
+unassigned\t0\t0\t0\t0
+#rescued\t1
+#leaked_elsewhere\t1
+#lost\t1
+#masked_alignments\t3
+#skipped_reads\t0
+");
+    }
+}
@@ -0,0 +1,119 @@
+use std::{fmt::Display, str::FromStr};
+
+/// GTDB ranks, ordered by height: `Rank::Domain > Rank::Genus > Rank::Species`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rank {
+    Species,
+    Genus,
+    Family,
+    Order,
+    Class,
+    Phylum,
+    Domain,
+}
+
+impl Rank {
+    /// All ranks from domain down to species, the order of a lineage.
+    pub const ALL: [Rank; 7] = [Rank::Domain, Rank::Phylum, Rank::Class, Rank::Order, Rank::Family, Rank::Genus, Rank::Species];
+
+    pub fn iter() -> impl Iterator<Item = Rank> {
+        Self::ALL.into_iter()
+    }
+
+    /// Lineage field prefix, e.g. `g__`.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Rank::Domain => "d__",
+            Rank::Phylum => "p__",
+            Rank::Class => "c__",
+            Rank::Order => "o__",
+            Rank::Family => "f__",
+            Rank::Genus => "g__",
+            Rank::Species => "s__",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rank::Domain => "domain",
+            Rank::Phylum => "phylum",
+            Rank::Class => "class",
+            Rank::Order => "order",
+            Rank::Family => "family",
+            Rank::Genus => "genus",
+            Rank::Species => "species",
+        }
+    }
+
+    /// Rank of a bare prefix (`g__`), None for anything else.
+    pub fn from_prefix(prefix: &str) -> Option<Self> {
+        Self::iter().find(|rank| rank.prefix() == prefix)
+    }
+
+    /// Rank of a lineage field by its prefix (`g__Bacillus`), None without a GTDB prefix.
+    pub fn of_field(field: &str) -> Option<Self> {
+        Self::iter().find(|rank| field.starts_with(rank.prefix()))
+    }
+
+    /// Name of a lineage field without its prefix, None for a field of another rank.
+    pub fn strip<'a>(&self, field: &'a str) -> Option<&'a str> {
+        field.strip_prefix(self.prefix())
+    }
+}
+
+impl Display for Rank {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Parses full names (`genus`, any case), prefixes (`g__`) and bare prefix letters (`g`).
+impl FromStr for Rank {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = s.trim().to_lowercase();
+        Self::iter()
+            .find(|rank| key == rank.name() || key == rank.prefix() || key == rank.prefix()[..1])
+            .ok_or_else(|| format!("Unknown rank {} (expected one of {})", s, itertools::join(Self::iter(), ", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_order_by_height_and_list_as_a_lineage() {
+        assert!(Rank::Domain > Rank::Phylum && Rank::Genus > Rank::Species);
+        let mut sorted = Rank::ALL;
+        sorted.sort();
+        assert_eq!(sorted.into_iter().rev().collect::<Vec<Rank>>(), Rank::iter().collect::<Vec<Rank>>());
+        let lineage = "d__Bacteria;p__Bacillota;c__Bacilli;o__Bacillales;f__Bacillaceae;g__Bacillus;s__Bacillus subtilis";
+        assert_eq!(lineage.split(';').map(Rank::of_field).collect::<Vec<Option<Rank>>>(), Rank::iter().map(Some).collect::<Vec<Option<Rank>>>());
+        assert_eq!(Rank::iter().map(|rank| rank.to_string()).collect::<Vec<String>>(), ["domain", "phylum", "class", "order", "family", "genus", "species"]);
+    }
+
+    #[test]
+    fn rank_prefixes_and_fields() {
+        assert_eq!(Rank::from_prefix("g__"), Some(Rank::Genus));
+        assert_eq!(Rank::from_prefix("g__Bacillus"), None);
+        assert_eq!(Rank::from_prefix("x__"), None);
+        assert_eq!(Rank::of_field("s__Bacillus subtilis"), Some(Rank::Species));
+        assert_eq!(Rank::of_field("Bacillus"), None);
+        assert_eq!(Rank::Genus.strip("g__Bacillus"), Some("Bacillus"));
+        assert_eq!(Rank::Genus.strip("g__"), Some(""));
+        assert_eq!(Rank::Genus.strip("f__Bacillaceae"), None);
+    }
+
+    #[test]
+    fn ranks_parse_from_names_prefixes_and_letters() {
+        for rank in Rank::iter() {
+            for key in [rank.name().to_string(), rank.name().to_uppercase(), rank.prefix().to_string(), rank.prefix()[..1].to_string(), format!(" {} ", rank.name())] {
+                assert_eq!(key.parse::<Rank>(), Ok(rank), "{:?}", key);
+            }
+        }
+        assert_eq!("strain".parse::<Rank>(), Err("Unknown rank strain (expected one of domain, phylum, class, order, family, genus, species)".to_string()));
+        assert!("".parse::<Rank>().is_err() && "ge".parse::<Rank>().is_err());
+    }
+}
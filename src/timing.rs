@@ -0,0 +1,235 @@
+use std::{cmp::Reverse, collections::BinaryHeap, fmt::Display, io::Read, sync::atomic::{AtomicBool, AtomicU64, Ordering}, time::{Duration, Instant}};
+
+/// Phases of reading and counting a SAM input that --timing attributes time to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading the file
+    Read,
+    /// Gzip decompression, without the reads of the compressed file
+    Decompress,
+    /// Parsing records (`SamRef::from_line`, `Sam::from_line`)
+    Parse,
+    /// Updating the counts
+    Count,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [Phase::Read, Phase::Decompress, Phase::Parse, Phase::Count];
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Phase::Read => "read",
+            Phase::Decompress => "decompress",
+            Phase::Parse => "parse",
+            Phase::Count => "count",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Nanoseconds per phase, Decompress including the reads below the decoder.
+static NANOS: [AtomicU64; Phase::ALL.len()] = [const { AtomicU64::new(0) }; Phase::ALL.len()];
+
+/// Bytes read from input files (below any decompression), for --progress. Counted whether or not
+/// the timers are on.
+static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+
+pub fn bytes_read() -> u64 {
+    BYTES_READ.load(Ordering::Relaxed)
+}
+
+/// Turns the timers on (--timing). Until then every timer is a single relaxed load.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn add(phase: Phase, elapsed: Duration) {
+    NANOS[phase as usize].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Enables the timers with --timing and returns the start of the run for `finish`.
+pub fn start(timing: bool) -> Instant {
+    if timing {
+        enable();
+    }
+    Instant::now()
+}
+
+/// Prints the breakdown of a timed run to stderr and returns it, None without --timing.
+pub fn finish(start: Instant) -> Option<Breakdown> {
+    let breakdown = enabled().then(|| Breakdown::since(start))?;
+    eprintln!("Timing: {}", breakdown);
+    Some(breakdown)
+}
+
+/// Times one in `EVERY` calls of a per-record phase and counts it `EVERY` times, so the clock is
+/// read rarely enough not to distort the timings of cheap phases.
+#[derive(Debug, Default)]
+pub struct Sampler {
+    calls: u64,
+}
+
+impl Sampler {
+    pub const EVERY: u64 = 64;
+
+    #[inline]
+    pub fn time<T>(&mut self, phase: Phase, run: impl FnOnce() -> T) -> T {
+        if !enabled() {
+            return run()
+        }
+        self.calls += 1;
+        if !self.calls.is_multiple_of(Self::EVERY) {
+            return run()
+        }
+        let start = Instant::now();
+        let result = run();
+        add(phase, start.elapsed() * Self::EVERY as u32);
+        result
+    }
+}
+
+/// One record among the slowest of a run.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SlowRecord {
+    pub elapsed: Duration,
+    pub line: usize,
+    pub length: usize,
+    pub qname: String,
+}
+
+impl Display for SlowRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}\t{}\t{} bytes\t{:.3}ms", self.line, self.qname, self.length, self.elapsed.as_secs_f64() * 1000.0)
+    }
+}
+
+/// Times every record of a phase and keeps the `capacity` slowest, to find the pathological lines
+/// (huge CIGARs or sequences) that stall a run. Unlike `Sampler` every call reads the clock, so
+/// only a phase costly per record should use it; without --timing it is a single relaxed load.
+#[derive(Debug)]
+pub struct SlowRecords {
+    capacity: usize,
+    slowest: BinaryHeap<Reverse<SlowRecord>>,
+}
+
+impl SlowRecords {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, slowest: BinaryHeap::with_capacity(capacity + 1) }
+    }
+
+    /// Runs `run` on the record at `line`, timed into `phase`. The qname is taken from the first
+    /// field of the line, only for records slow enough to be kept.
+    #[inline]
+    pub fn time<T>(&mut self, phase: Phase, line: usize, record: &str, run: impl FnOnce() -> T) -> T {
+        if !enabled() {
+            return run()
+        }
+        let start = Instant::now();
+        let result = run();
+        let elapsed = start.elapsed();
+        add(phase, elapsed);
+        self.observe(elapsed, line, record);
+        result
+    }
+
+    fn observe(&mut self, elapsed: Duration, line: usize, record: &str) {
+        if self.capacity == 0 { return };
+        if self.slowest.len() == self.capacity && self.slowest.peek().is_some_and(|Reverse(fastest)| fastest.elapsed >= elapsed) {
+            return
+        }
+        let qname = record.split('\t').next().unwrap_or_default().to_string();
+        self.slowest.push(Reverse(SlowRecord { elapsed, line, length: record.len(), qname }));
+        if self.slowest.len() > self.capacity {
+            self.slowest.pop();
+        }
+    }
+
+    /// The slowest records, slowest first.
+    pub fn slowest(&self) -> Vec<SlowRecord> {
+        let mut result = self.slowest.iter().map(|Reverse(record)| record.clone()).collect::<Vec<SlowRecord>>();
+        result.sort_by(|a, b| b.cmp(a));
+        result
+    }
+}
+
+/// Reader that attributes the time of every read to a phase. Reads fill whole buffers, so each
+/// one is timed.
+pub struct TimedRead<R: Read> {
+    inner: R,
+    phase: Phase,
+}
+
+impl<R: Read> TimedRead<R> {
+    pub fn new(inner: R, phase: Phase) -> Self {
+        Self { inner, phase }
+    }
+}
+
+impl<R: Read> Read for TimedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let result = match enabled() {
+            false => self.inner.read(buf),
+            true => {
+                let start = Instant::now();
+                let result = self.inner.read(buf);
+                add(self.phase, start.elapsed());
+                result
+            },
+        };
+        if let (Phase::Read, Ok(bytes)) = (self.phase, &result) {
+            BYTES_READ.fetch_add(*bytes as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Time spent per phase since the start of a run, the remainder of the wall time as other.
+#[derive(Debug, Clone)]
+pub struct Breakdown {
+    pub wall: Duration,
+    pub phases: Vec<(String, Duration)>,
+}
+
+impl Breakdown {
+    pub fn since(start: Instant) -> Self {
+        let nanos = |phase: Phase| NANOS[phase as usize].load(Ordering::Relaxed);
+        let mut phases = Phase::ALL.iter().map(|phase| {
+            let phase_nanos = match phase {
+                Phase::Decompress => nanos(Phase::Decompress).saturating_sub(nanos(Phase::Read)),
+                phase => nanos(*phase),
+            };
+            (phase.to_string(), Duration::from_nanos(phase_nanos))
+        }).collect::<Vec<(String, Duration)>>();
+        let wall = start.elapsed();
+        let measured = phases.iter().map(|(_name, duration)| *duration).sum::<Duration>();
+        phases.push(("other".to_string(), wall.saturating_sub(measured)));
+        Self { wall, phases }
+    }
+
+    /// Share of the wall time in percent.
+    pub fn percent(&self, duration: Duration) -> f64 {
+        match self.wall.is_zero() {
+            true => 0.0,
+            false => 100.0 * duration.as_secs_f64() / self.wall.as_secs_f64(),
+        }
+    }
+}
+
+/// One line summary, e.g. for stderr.
+impl Display for Breakdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.3}s", self.wall.as_secs_f64())?;
+        for (name, duration) in &self.phases {
+            write!(f, ", {} {:.3}s ({:.1}%)", name, duration.as_secs_f64(), self.percent(*duration))?;
+        }
+        Ok(())
+    }
+}
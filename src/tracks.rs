@@ -0,0 +1,230 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, io::Write};
+
+use crate::{common::{sam_input, sam_to_ids, Anomaly, AnomalyLog, Args, GeneID, InputError, NameFormat, SamHeader, SamRef, TaxID}, filter::{Decision, RecordFilter}};
+
+/// Number of reference bases an alignment covers: the M, D, N, = and X operations of its CIGAR.
+pub fn reference_span(sam: &SamRef) -> Option<u32> {
+    let span = sam.cigar_ops()?.iter().filter(|(_length, op)| op.consumes_reference()).try_fold(0u32, |sum, (length, _op)| sum.checked_add(*length))?;
+    (span > 0).then_some(span)
+}
+
+/// Which fixed-size windows of a sequence are covered by at least one alignment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowCoverage {
+    window: u32,
+    covered: Vec<bool>,
+}
+
+impl WindowCoverage {
+    pub fn new(length: u32, window: u32) -> Self {
+        Self { window, covered: vec![false; length.div_ceil(window).max(1) as usize] }
+    }
+
+    /// Marks the windows overlapping the 0-based interval [start, start + span), clipped to the sequence.
+    pub fn add(&mut self, start: u32, span: u32) {
+        let last = self.covered.len() - 1;
+        let first = (start / self.window) as usize;
+        let end = (start.saturating_add(span).saturating_sub(1) / self.window) as usize;
+        for window in first.min(last)..=end.min(last) {
+            self.covered[window] = true;
+        }
+    }
+
+    /// Fraction of windows covered: 1 for reads spread over the whole sequence, down to one
+    /// window's share for reads piled up in a single window.
+    pub fn uniformity(&self) -> f64 {
+        self.covered.iter().filter(|covered| **covered).count() as f64 / self.covered.len() as f64
+    }
+}
+
+/// Aligned lengths of reads in `BIN` bp bins, lengths past the last bin counted in it, for an
+/// approximate median in a few bytes per pair or gene instead of every length.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LengthHistogram {
+    counts: Vec<u64>,
+}
+
+impl LengthHistogram {
+    pub const BIN: u32 = 10;
+    pub const BINS: usize = 64;
+
+    pub fn add(&mut self, length: u32) {
+        let bin = ((length / Self::BIN) as usize).min(Self::BINS - 1);
+        if bin >= self.counts.len() {
+            self.counts.resize(bin + 1, 0);
+        }
+        self.counts[bin] += 1;
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        self.counts.iter_mut().zip(&other.counts).for_each(|(count, other)| *count += other);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Median length, interpolated within the bin holding it (off by less than `BIN` from the
+    /// exact median below the last bin). None without lengths.
+    pub fn median(&self) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None
+        }
+        let half = count as f64 / 2.0;
+        let mut below = 0;
+        for (bin, bin_count) in self.counts.iter().enumerate().filter(|(_bin, bin_count)| **bin_count > 0) {
+            if (below + bin_count) as f64 >= half {
+                let fraction = (half - below as f64) / *bin_count as f64;
+                return Some((bin as f64 + fraction) * Self::BIN as f64)
+            }
+            below += bin_count;
+        }
+        None
+    }
+}
+
+/// Depth of reads from other taxa along each reference sequence, as +1/-1 events at 0-based
+/// alignment starts and ends.
+#[derive(Debug, Default)]
+pub struct LeakCoverage {
+    events: HashMap<String, BTreeMap<u32, i64>>,
+    pub header: SamHeader,
+}
+
+impl LeakCoverage {
+    /// Adds the span of every leaked record, i.e. one whose query taxon differs from its reference taxon.
+    pub fn from_sam(args: &Args, anomalies: &mut AnomalyLog) -> Result<Self, InputError> {
+        let mut iter = sam_input(args)?;
+        anomalies.set_input(&args.input_label());
+        let mut filter = RecordFilter::from_args(args);
+        let mut result = Self::default();
+
+        let mut line = String::new();
+        while let Some(sam) = iter.next_valid_ref(&mut line, anomalies)? {
+            if filter.evaluate(&sam) != Decision::Keep {continue};
+            let fromto = match sam_to_ids(&sam, &filter.bounds, &mut filter.names) {
+                Ok(fromto) => fromto,
+                Err(e) => {
+                    anomalies.record(Anomaly::UnparseableName, &e, Some(iter.line))?;
+                    continue
+                },
+            };
+            if fromto.query == fromto.reference {continue};
+
+            let Some(span) = reference_span(&sam) else {
+                anomalies.record(Anomaly::InvalidRecord, &format!("{} has no reference span in CIGAR {}", sam.qname, sam.cigar), Some(iter.line))?;
+                continue
+            };
+            let start = sam.pos.saturating_sub(1);
+            let events = result.events.entry(sam.rname.to_string()).or_default();
+            *events.entry(start).or_default() += 1;
+            *events.entry(start.saturating_add(span)).or_default() -= 1;
+        }
+        eprintln!("Records: {}", filter);
+
+        result.header = std::mem::take(&mut iter.header);
+        Ok(result)
+    }
+
+    /// Writes `name start end depth` rows of constant, non-zero depth, sorted by sequence then
+    /// start and clipped to the @SQ length. Sequences without an @SQ length are left out with a
+    /// warning. Returns the number of rows written.
+    pub fn write_bedgraph(&self, writer: &mut impl Write) -> std::io::Result<usize> {
+        let mut names = self.events.keys().collect::<Vec<&String>>();
+        names.sort();
+
+        let mut rows = 0;
+        let mut unknown = 0;
+        for name in names {
+            let Some(length) = self.header.sequences.get(name).copied() else {
+                unknown += 1;
+                continue
+            };
+            let mut depth = 0;
+            let mut start = 0;
+            for (position, change) in &self.events[name] {
+                let position = (*position).min(length);
+                if depth > 0 && position > start {
+                    writeln!(writer, "{}\t{}\t{}\t{}", name, start, position, depth)?;
+                    rows += 1;
+                }
+                depth += change;
+                start = position;
+            }
+        }
+        if unknown > 0 {
+            eprintln!("Warning: {} sequences with leaked reads have no @SQ length, left out of the bedGraph", unknown);
+        }
+        Ok(rows)
+    }
+}
+
+/// Writes one BED interval over the whole sequence of every masked gene, sorted by sequence
+/// name. Sequences are found by their name in `format` among the @SQ lines, masked genes without
+/// one are left out with a warning. Returns the number of intervals written.
+pub fn write_mask_bed(mask: &HashMap<TaxID, HashSet<GeneID>>, header: &SamHeader, format: &NameFormat, writer: &mut impl Write) -> std::io::Result<usize> {
+    let mut intervals = header.sequences.iter()
+        .filter(|(name, _length)| format.parse(name).is_ok_and(|(taxid, gene)| mask.get(&taxid).is_some_and(|genes| genes.contains(&gene))))
+        .collect::<Vec<(&String, &u32)>>();
+    intervals.sort();
+
+    let masked = mask.values().map(HashSet::len).sum::<usize>();
+    if intervals.len() < masked {
+        eprintln!("Warning: {} of {} masked genes have no @SQ line, left out of the BED", masked - intervals.len(), masked);
+    }
+    for (name, length) in &intervals {
+        writeln!(writer, "{}\t0\t{}\tmasked", name, length)?;
+    }
+    Ok(intervals.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SplitMix64;
+
+    /// Median of the lengths, the mean of the middle two for an even number.
+    fn exact_median(lengths: &mut [u32]) -> f64 {
+        lengths.sort_unstable();
+        let middle = lengths.len() / 2;
+        match lengths.len() % 2 {
+            1 => lengths[middle] as f64,
+            _ => (lengths[middle - 1] + lengths[middle]) as f64 / 2.0,
+        }
+    }
+
+    #[test]
+    fn median_leak_lengths_are_within_a_bin_of_the_exact_median() {
+        assert_eq!(LengthHistogram::default().median(), None);
+        let mut random = SplitMix64::new(7);
+        // Uniform, short junk with a few long reads, and a narrow peak
+        for (reads, min, spread) in [(1001, 30, 570), (400, 20, 50), (2000, 148, 5)] {
+            let mut lengths = (0..reads).map(|read| match read % 10 {
+                0 => 600 + random.below(1000) as u32,
+                _ => min + random.below(spread) as u32,
+            }).collect::<Vec<u32>>();
+            let (mut histogram, mut halves) = (LengthHistogram::default(), [LengthHistogram::default(), LengthHistogram::default()]);
+            for (read, length) in lengths.iter().enumerate() {
+                histogram.add(*length);
+                halves[read % 2].add(*length);
+            }
+            let median = histogram.median().unwrap();
+            let exact = exact_median(&mut lengths);
+            assert!((median - exact).abs() < LengthHistogram::BIN as f64, "{} reads from {}: {} for {}", reads, min, median, exact);
+            assert_eq!(histogram.count(), reads as u64);
+
+            let [mut merged, other] = halves;
+            merged.merge(&other);
+            assert_eq!(merged, histogram);
+        }
+
+        // Lengths past the last bin count in it
+        let mut long = LengthHistogram::default();
+        [50_000, 640, 630].into_iter().for_each(|length| long.add(length));
+        assert_eq!((long.count(), long.median()), (3, Some(635.0)));
+    }
+}
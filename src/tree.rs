@@ -0,0 +1,401 @@
+use std::{collections::BTreeMap, fmt::Display, path::Path};
+
+use clap::ValueEnum;
+use phylotree::tree::{Edge, Node, NodeId, Tree, TreeError};
+use thiserror::Error;
+
+use crate::{id_to_label::{LabelIndex, LabelNormalize}, utils::SplitMix64};
+
+#[derive(Debug, Error)]
+pub enum TopologyError {
+    #[error("Node {node} ({name}) has {degree} children, only binary trees are supported (see --resolve-polytomies)")]
+    UnsupportedTopology { node: NodeId, name: String, degree: usize },
+    #[error("Cannot read tree {path}: {message}")]
+    Load { path: String, message: String },
+    #[error("Tree error: {0:?}")]
+    Tree(TreeError),
+    #[error("Cannot edit node {node}: {message}")]
+    Edit { node: NodeId, message: String },
+    #[error("Node {node} not found while {context}, the tree may have changed since its ids were taken")]
+    MissingNode { node: NodeId, context: String },
+}
+
+impl From<TreeError> for TopologyError {
+    fn from(e: TreeError) -> Self {
+        TopologyError::Tree(e)
+    }
+}
+
+/// How polytomies are handled before analyses that need a binary tree.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ResolvePolytomies {
+    /// Keep polytomies, binary-only analyses fail with UnsupportedTopology
+    #[value(name = "none")]
+    None,
+    /// Binarize by joining randomly chosen children under new nodes with zero-length branches
+    #[value(name = "random")]
+    Random,
+}
+
+/// Structure of a tree as loaded, before any polytomies are resolved.
+#[derive(Debug, Default, Clone)]
+pub struct TreeReport {
+    pub nodes: usize,
+    pub leaves: usize,
+    /// Number of nodes per degree for nodes with more than two children.
+    pub polytomies: BTreeMap<usize, usize>,
+    pub unary: usize,
+    pub zero_length: usize,
+    pub missing_length: usize,
+}
+
+impl TreeReport {
+    pub fn from_tree(tree: &Tree) -> Self {
+        let mut result = Self::default();
+        for id in tree.search_nodes(|_| true) {
+            let Ok(node) = tree.get(&id) else { continue };
+            result.nodes += 1;
+            match node.children.len() {
+                0 => result.leaves += 1,
+                1 => result.unary += 1,
+                2 => (),
+                degree => *result.polytomies.entry(degree).or_default() += 1,
+            }
+            if node.is_root() { continue };
+            match node.parent_edge {
+                Some(0.0) => result.zero_length += 1,
+                Some(_) => (),
+                None => result.missing_length += 1,
+            }
+        }
+        result
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.polytomies.is_empty() && self.unary == 0
+    }
+}
+
+impl Display for TreeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "nodes\t{}", self.nodes)?;
+        writeln!(f, "leaves\t{}", self.leaves)?;
+        writeln!(f, "polytomies\t{}", self.polytomies.values().sum::<usize>())?;
+        for (degree, count) in &self.polytomies {
+            writeln!(f, "polytomies_degree_{}\t{}", degree, count)?;
+        }
+        writeln!(f, "unary_nodes\t{}", self.unary)?;
+        writeln!(f, "zero_length_branches\t{}", self.zero_length)?;
+        write!(f, "missing_lengths\t{}", self.missing_length)
+    }
+}
+
+/// Node of an id, an error naming the id and what it was needed for (e.g. a stale id of a
+/// pruned node) instead of a bare TreeError.
+pub fn get_node<'a>(tree: &'a Tree, id: NodeId, context: &str) -> Result<&'a Node, TopologyError> {
+    tree.get(&id).map_err(|_| TopologyError::MissingNode { node: id, context: context.to_string() })
+}
+
+/// Mutable `get_node`.
+pub fn get_node_mut<'a>(tree: &'a mut Tree, id: NodeId, context: &str) -> Result<&'a mut Node, TopologyError> {
+    tree.get_mut(&id).map_err(|_| TopologyError::MissingNode { node: id, context: context.to_string() })
+}
+
+/// Removes double quotes GTDB puts around labels.
+pub fn clean_labels(tree: &mut Tree) -> Result<(), TopologyError> {
+    for nid in tree.search_nodes(|_| true) {
+        let node = get_node_mut(tree, nid, "cleaning labels")?;
+        if let Some(name) = node.name.as_mut() {
+            if name.contains('"') {
+                *name = name.replace('"', "");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a GTDB newick tree (single quoted labels are accepted) and reports its structure.
+pub fn load_gtdb_tree(path: impl AsRef<Path>) -> Result<(Tree, TreeReport), TopologyError> {
+    let load_error = |message: String| TopologyError::Load { path: path.as_ref().display().to_string(), message };
+
+    let mut newick = std::fs::read_to_string(&path).map_err(|e| load_error(e.to_string()))?;
+    let single_quotes = newick.chars().filter(|c| *c == '\'').count();
+    let double_quotes = newick.chars().filter(|c| *c == '"').count();
+    if single_quotes > 0 && double_quotes == 0 {
+        newick = newick.replace('\'', "\"");
+    }
+
+    let mut tree = Tree::from_newick(&newick).map_err(|e| load_error(e.to_string()))?;
+    clean_labels(&mut tree)?;
+
+    let report = TreeReport::from_tree(&tree);
+    Ok((tree, report))
+}
+
+/// Leaf ids by label, looked up with the given normalization.
+pub fn leaf_ids(tree: &Tree, normalize: LabelNormalize) -> LabelIndex<NodeId> {
+    let leaves = tree.get_leaves().into_iter()
+        .filter_map(|id| tree.get(&id).ok().and_then(|node| node.name.clone()).map(|name| (name, id)));
+    LabelIndex::new(normalize, leaves)
+}
+
+/// Names of leaves with their distance to another leaf, see `LabeledTree::sister_leaves`.
+pub type SisterLeaves = Vec<(String, Option<Edge>)>;
+
+/// A loaded tree with its leaves indexed by label, the view binaries need to annotate taxa by label.
+/// The tree is only edited through it, so the index never holds ids of pruned nodes.
+pub struct LabeledTree {
+    tree: Tree,
+    leaves: LabelIndex<NodeId>,
+    normalize: LabelNormalize,
+    report: TreeReport,
+}
+
+impl LabeledTree {
+    pub fn load(path: impl AsRef<Path>, normalize: LabelNormalize) -> Result<Self, TopologyError> {
+        let (tree, report) = load_gtdb_tree(path)?;
+        let leaves = leaf_ids(&tree, normalize);
+        Ok(Self { tree, leaves, normalize, report })
+    }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Structure of the tree as loaded.
+    pub fn report(&self) -> &TreeReport {
+        &self.report
+    }
+
+    /// See `resolve_polytomies`. Only inner nodes are added, the leaf index stays valid.
+    pub fn resolve_polytomies(&mut self, mode: ResolvePolytomies, seed: u64) -> Result<usize, TopologyError> {
+        resolve_polytomies(&mut self.tree, mode, seed)
+    }
+
+    /// Removes the clade of a label's leaf and re-indexes the leaves. Returns false if the label
+    /// is not a leaf.
+    pub fn prune(&mut self, label: &str) -> Result<bool, TopologyError> {
+        let Some(leaf) = self.leaves.get(label) else { return Ok(false) };
+        get_node(&self.tree, leaf, &format!("pruning {}", label))?;
+        self.tree.prune(&leaf)?;
+        self.leaves = leaf_ids(&self.tree, self.normalize);
+        Ok(true)
+    }
+
+    /// Leaves sharing a normalized label with another leaf, which cannot be joined.
+    pub fn collisions(&self) -> usize {
+        self.leaves.collisions
+    }
+
+    pub fn normalized_joins(&self) -> usize {
+        self.leaves.normalized_joins()
+    }
+
+    pub fn has_leaf(&self, label: &str) -> bool {
+        self.leaves.get(label).is_some()
+    }
+
+    /// Branch length distance between the leaves of two labels, None if either is not a leaf.
+    pub fn distance(&self, a: &str, b: &str) -> Option<Edge> {
+        let (a, b) = (self.leaves.get(a)?, self.leaves.get(b)?);
+        self.tree.get_distance(&a, &b).ok()?.0
+    }
+
+    /// Names of the leaves of the sister clade of a label's leaf with their distance to it. None
+    /// if the label is not a leaf, empty for the root and only children.
+    pub fn sister_leaves(&self, label: &str) -> Result<Option<SisterLeaves>, TopologyError> {
+        let Some(leaf) = self.leaves.get(label) else { return Ok(None) };
+        let Some((sister, _length)) = self.tree.get_neighbor(leaf)? else { return Ok(Some(Vec::new())) };
+        let mut result = Vec::new();
+        for other in self.tree.get_subtree_leaves(&sister)? {
+            let name = get_node(&self.tree, other, &format!("listing the sister leaves of {}", label))?.name.clone().unwrap_or_else(|| "NA".to_string());
+            let distance = self.tree.get_distance(&leaf, &other).ok().and_then(|(distance, _edges)| distance);
+            result.push((name, distance));
+        }
+        Ok(Some(result))
+    }
+}
+
+fn node_name(node: &Node) -> String {
+    node.name.clone().unwrap_or_else(|| "unnamed".to_string())
+}
+
+pub trait TreeHelper {
+    /// Sibling of a node and the summed length of the branches to both siblings, None for the root
+    /// and only children. Fails with UnsupportedTopology if the parent has more than two children.
+    fn get_neighbor(&self, id: NodeId) -> Result<Option<(NodeId, Edge)>, TopologyError>;
+
+    /// Fails with UnsupportedTopology naming the first node with more than two children.
+    fn require_binary(&self) -> Result<(), TopologyError>;
+}
+
+impl TreeHelper for Tree {
+    fn get_neighbor(&self, id: NodeId) -> Result<Option<(NodeId, Edge)>, TopologyError> {
+        let node = get_node(self, id, "finding a sister")?;
+
+        let Some(parent) = node.parent else { return Ok(None) };
+        let parent_node = get_node(self, parent, "finding a sister")?;
+
+        if parent_node.children.len() > 2 {
+            return Err(TopologyError::UnsupportedTopology { node: parent, name: node_name(parent_node), degree: parent_node.children.len() })
+        }
+
+        match parent_node.children.iter().find(|n| **n != id) {
+            Some(child) => {
+                let dist = parent_node.children.iter().fold(0f64, |acc, child_id| acc + parent_node.get_child_edge(child_id).unwrap_or(0.0));
+                Ok(Some((*child, dist)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn require_binary(&self) -> Result<(), TopologyError> {
+        let mut ids = self.search_nodes(|node| node.children.len() > 2);
+        ids.sort();
+        match ids.first() {
+            Some(id) => {
+                let node = get_node(self, *id, "checking the tree is binary")?;
+                Err(TopologyError::UnsupportedTopology { node: *id, name: node_name(node), degree: node.children.len() })
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// Binarizes every polytomy by repeatedly joining two of its children (chosen by the seeded
+/// generator) under a new node attached with a zero-length branch. Returns the number of nodes added.
+pub fn resolve_polytomies(tree: &mut Tree, mode: ResolvePolytomies, seed: u64) -> Result<usize, TopologyError> {
+    if mode == ResolvePolytomies::None {
+        return Ok(0)
+    }
+    let mut rng = SplitMix64::new(seed);
+    let mut polytomies = tree.search_nodes(|node| node.children.len() > 2);
+    polytomies.sort();
+
+    let mut added = 0;
+    for parent in polytomies {
+        loop {
+            let mut children = get_node(tree, parent, "resolving polytomies")?.children.clone();
+            if children.len() <= 2 { break };
+
+            let first = children.swap_remove(rng.below(children.len() as u64) as usize);
+            let second = children.swap_remove(rng.below(children.len() as u64) as usize);
+
+            let joined = tree.add_child(Node::new(), parent, Some(0.0))?;
+            for child in [first, second] {
+                move_subtree(tree, child, parent, joined)?;
+            }
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+/// Re-attaches `child` from `parent` to `new_parent` with its branch length and fixes the depths below it.
+fn move_subtree(tree: &mut Tree, child: NodeId, parent: NodeId, new_parent: NodeId) -> Result<(), TopologyError> {
+    let context = "moving a subtree";
+    let edge = get_node(tree, child, context)?.parent_edge;
+    get_node_mut(tree, parent, context)?.remove_child(&child).map_err(|e| TopologyError::Edit { node: parent, message: format!("{:?}", e) })?;
+    get_node_mut(tree, new_parent, context)?.add_child(child, edge);
+    get_node_mut(tree, child, context)?.set_parent(new_parent, edge);
+
+    let mut stack = vec![(child, get_node(tree, new_parent, context)?.get_depth() + 1)];
+    while let Some((id, depth)) = stack.pop() {
+        let node = get_node_mut(tree, id, context)?;
+        node.set_depth(depth);
+        stack.extend(node.children.iter().map(|c| (*c, depth + 1)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Quoted GTDB labels, a polytomy of degree 3 and two of degree 4 (one the root), a unary
+    /// node, a zero-length branch and three missing lengths.
+    const POLYTOMIES: &str = "(('s__Alpha a':1,'s__Alpha b':1,'s__Alpha c':1):0.5,(D:0,E:1,F:1,G:1):1,(H),I);";
+    const BINARY: &str = "((A:1,B:2):0.5,(C:1,D:1):1);";
+
+    fn load(name: &str, newick: &str) -> (Tree, TreeReport) {
+        let path = crate::utils::test_path(name);
+        std::fs::write(&path, newick).unwrap();
+        load_gtdb_tree(&path).unwrap()
+    }
+
+    fn leaf(tree: &Tree, name: &str) -> NodeId {
+        tree.search_nodes(|node| node.name.as_deref() == Some(name))[0]
+    }
+
+    #[test]
+    fn reports_the_structure_of_fixture_trees() {
+        let (tree, report) = load("polytomies.tree", POLYTOMIES);
+        assert_eq!((report.nodes, report.leaves), (13, 9));
+        assert_eq!(report.polytomies, BTreeMap::from([(3, 1), (4, 2)]));
+        assert_eq!((report.unary, report.zero_length, report.missing_length), (1, 1, 3));
+        assert!(!report.is_binary());
+        assert!(tree.search_nodes(|node| node.name.as_deref() == Some("s__Alpha a")).len() == 1, "labels are unquoted");
+
+        let (_tree, report) = load("binary.tree", BINARY);
+        assert_eq!((report.nodes, report.leaves), (7, 4));
+        assert!(report.is_binary());
+        assert_eq!((report.zero_length, report.missing_length), (0, 0));
+        assert!(report.to_string().contains("polytomies\t0\n"));
+    }
+
+    #[test]
+    fn polytomies_fail_sister_lookups_naming_the_node() {
+        let (tree, _report) = load("polytomies_sisters.tree", POLYTOMIES);
+        let error = tree.get_neighbor(leaf(&tree, "E")).unwrap_err();
+        assert!(matches!(error, TopologyError::UnsupportedTopology { degree: 4, .. }), "{}", error);
+        assert!(matches!(tree.require_binary(), Err(TopologyError::UnsupportedTopology { .. })));
+        // The only child of a unary node has no sister
+        assert_eq!(tree.get_neighbor(leaf(&tree, "H")).unwrap(), None);
+
+        let (tree, _report) = load("binary_sisters.tree", BINARY);
+        let (sister, length) = tree.get_neighbor(leaf(&tree, "A")).unwrap().unwrap();
+        assert_eq!((sister, length), (leaf(&tree, "B"), 3.0));
+        assert!(tree.require_binary().is_ok());
+    }
+
+    #[test]
+    fn resolving_binarizes_with_zero_length_branches() {
+        let (mut tree, before) = load("polytomies_resolved.tree", POLYTOMIES);
+        assert_eq!(resolve_polytomies(&mut tree, ResolvePolytomies::None, 1).unwrap(), 0);
+        // One node per child beyond the second
+        assert_eq!(resolve_polytomies(&mut tree, ResolvePolytomies::Random, 1).unwrap(), 5);
+        let after = TreeReport::from_tree(&tree);
+        assert!(after.polytomies.is_empty());
+        assert!(tree.require_binary().is_ok());
+        assert_eq!((after.nodes, after.leaves, after.unary), (before.nodes + 5, before.leaves, before.unary));
+        assert_eq!(after.zero_length, before.zero_length + 5);
+        for name in ["E", "s__Alpha a", "I"] {
+            assert!(tree.get_neighbor(leaf(&tree, name)).unwrap().is_some(), "{}", name);
+        }
+        // Distances between leaves keep their lengths
+        let distance = tree.get_distance(&leaf(&tree, "E"), &leaf(&tree, "F")).unwrap().0.unwrap();
+        assert_eq!(distance, 2.0);
+
+        let resolved = |seed: u64| {
+            let (mut tree, _report) = load("polytomies_seeded.tree", POLYTOMIES);
+            resolve_polytomies(&mut tree, ResolvePolytomies::Random, seed).unwrap();
+            tree.to_newick().unwrap()
+        };
+        assert_eq!(resolved(7), resolved(7));
+    }
+    #[test]
+    fn leaves_join_labels_by_normalization() {
+        let path = crate::utils::test_path("normalized_leaves.tree");
+        std::fs::write(&path, "((Escherichia_coli:1,'s__Bacillus subtilis':2):1,s__Alpha%20one:3);").unwrap();
+        let tree = LabeledTree::load(&path, LabelNormalize::None).unwrap();
+        assert!(!tree.has_leaf("s__Escherichia coli"));
+        assert_eq!(tree.distance("Escherichia_coli", "s__Bacillus subtilis"), Some(3.0));
+
+        let tree = LabeledTree::load(&path, LabelNormalize::Strict).unwrap();
+        assert_eq!(tree.distance("s__Escherichia coli", "S__BACILLUS_SUBTILIS"), Some(3.0));
+        assert_eq!(tree.distance("s__alpha one", "s__Escherichia coli"), Some(5.0));
+        assert_eq!(tree.normalized_joins(), 4);
+        let tree = LabeledTree::load(&path, LabelNormalize::Underscore).unwrap();
+        assert!(tree.has_leaf("s__Alpha one") && !tree.has_leaf("s__Escherichia coli"));
+    }
+}
@@ -1,26 +1,648 @@
-use std::{fs::File, io::{BufRead, BufReader, Read, Seek, SeekFrom}, path::Path};
+use std::{fs::File, io::{BufRead, BufReader, BufWriter, Cursor, Read, Write}, path::{Path, PathBuf}};
 
-use flate2::bufread::GzDecoder;
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder, Compression};
 
-/// Returns an iterator over the lines of a given file, handling both plain text and gzipped files.
+use crate::timing::{Phase, TimedRead};
+
+/// Returns an iterator over the lines of a given file (or stdin for `-`), plain text or
+/// compressed in any format `open_reader` detects.
 pub fn file_lines<P: AsRef<Path>>(path: P) -> std::io::Result<Box<dyn Iterator<Item = std::io::Result<String>>>> {
-    let file = File::open(&path)?;
-    // Check if the file is gzipped by looking at its first two bytes
-    let mut buf_reader = BufReader::new(file);
-    let mut first_two_bytes = [0u8; 2];
-    
-    buf_reader.read_exact(&mut first_two_bytes)?;
-
-    // Reset the reader to start again
-    buf_reader.seek(SeekFrom::Start(0));
-    let reader: Box<dyn BufRead> = if first_two_bytes == [0x1F, 0x8B] {
-        // It's a gzipped file
-        let decoder = GzDecoder::new(buf_reader);
-        Box::new(BufReader::new(decoder))
-    } else {
-        // It's a regular text file
-        Box::new(buf_reader)
+    let reader = open_reader(&path)?;
+    Ok(Box::new(reader.lines().map(|line| line.map(strip_cr))))
+}
+
+/// Input path that stands for stdin.
+pub const STDIN_PATH: &str = "-";
+
+pub fn is_stdin(path: impl AsRef<Path>) -> bool {
+    path.as_ref() == Path::new(STDIN_PATH)
+}
+
+/// Compression of an input, told by its magic bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Codec {
+    Plain,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl Codec {
+    /// Bytes `from_magic` looks at.
+    const MAGIC_LEN: usize = 4;
+
+    pub fn from_magic(magic: &[u8]) -> Self {
+        match magic {
+            [0x1F, 0x8B, ..] => Codec::Gzip,
+            [0x28, 0xB5, 0x2F, 0xFD, ..] => Codec::Zstd,
+            [b'B', b'Z', b'h', ..] => Codec::Bzip2,
+            _ => Codec::Plain,
+        }
+    }
+
+    /// Codec of a file, from its first bytes rather than its extension.
+    pub fn of_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut magic = Vec::with_capacity(Self::MAGIC_LEN);
+        open_file(path)?.take(Self::MAGIC_LEN as u64).read_to_end(&mut magic)?;
+        Ok(Self::from_magic(&magic))
+    }
+}
+
+/// Buffered reader over a file, or stdin for `-`, decompressed if it starts with the magic bytes
+/// of gzip, zstd or bzip2 whatever its extension (pipes have none to go by). Concatenated gzip
+/// members, zstd frames and bzip2 streams are all read. Reads are timed as the read phase,
+/// decompression as the decompress phase. Empty input reads as plain text without lines.
+pub fn open_reader(path: impl AsRef<Path>) -> std::io::Result<Box<dyn BufRead + Send>> {
+    let input: Box<dyn Read + Send> = match is_stdin(&path) {
+        true => Box::new(std::io::stdin()),
+        false => Box::new(open_file(&path)?),
     };
-    
-    Ok(Box::new(reader.lines()))
+    let mut reader = BufReader::new(TimedRead::new(input, Phase::Read));
+    // The magic bytes are read off the front and put back, stdin cannot seek
+    let mut magic = Vec::with_capacity(Codec::MAGIC_LEN);
+    (&mut reader).take(Codec::MAGIC_LEN as u64).read_to_end(&mut magic)?;
+    let codec = Codec::from_magic(&magic);
+    let reader = Cursor::new(magic).chain(reader);
+    Ok(match codec {
+        Codec::Plain => Box::new(reader),
+        Codec::Gzip => Box::new(BufReader::new(TimedRead::new(MultiGzDecoder::new(reader), Phase::Decompress))),
+        Codec::Zstd => Box::new(BufReader::new(TimedRead::new(zstd::stream::read::Decoder::with_buffer(reader)?, Phase::Decompress))),
+        Codec::Bzip2 => Box::new(BufReader::new(TimedRead::new(bzip2::bufread::MultiBzDecoder::new(reader), Phase::Decompress))),
+    })
+}
+
+/// Several inputs read one after the other as one stream, each opened with `open_reader` when the
+/// one before is exhausted, so plain and gzipped files mix and only one is open at a time. An input
+/// not ending in a newline gets one, its last line would otherwise run into the next input.
+pub struct ConcatReader {
+    paths: std::collections::VecDeque<String>,
+    current: Option<Box<dyn BufRead + Send>>,
+    last: Option<u8>,
+    newline: bool,
+}
+
+impl ConcatReader {
+    /// Fails on the first path that cannot be opened before any input is read, stdin aside.
+    pub fn new(paths: &[&str]) -> std::io::Result<Self> {
+        for path in paths.iter().filter(|path| !is_stdin(path)) {
+            open_file(path)?;
+        }
+        Ok(Self { paths: paths.iter().map(|path| path.to_string()).collect(), current: None, last: None, newline: false })
+    }
+}
+
+impl BufRead for ConcatReader {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        loop {
+            if self.newline {
+                return Ok(b"\n")
+            }
+            let Some(reader) = self.current.as_mut() else {
+                match self.paths.pop_front() {
+                    Some(path) => self.current = Some(open_reader(&path)?),
+                    None => return Ok(&[]),
+                }
+                continue
+            };
+            if !reader.fill_buf()?.is_empty() {
+                break
+            }
+            self.current = None;
+            self.newline = self.last.is_some_and(|byte| byte != b'\n');
+            self.last = None;
+        }
+        self.current.as_mut().expect("current input").fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if amount == 0 { return };
+        if self.newline {
+            self.newline = false;
+            return
+        }
+        if let Some(reader) = self.current.as_mut() {
+            // The buffer is still filled, this does no I/O
+            self.last = reader.fill_buf().ok().and_then(|buffer| buffer.get(amount - 1).copied());
+            reader.consume(amount);
+        }
+    }
+}
+
+impl Read for ConcatReader {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let amount = available.len().min(buffer.len());
+        buffer[..amount].copy_from_slice(&available[..amount]);
+        self.consume(amount);
+        Ok(amount)
+    }
+}
+
+/// Copy of stdin in a temporary file, deleted when dropped, for tools that read their input more
+/// than once.
+pub struct SpooledStdin {
+    pub path: PathBuf,
+}
+
+impl SpooledStdin {
+    pub fn new() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("{}-{}.stdin", env!("CARGO_PKG_NAME"), std::process::id()));
+        let mut writer = BufWriter::new(create_file(&path)?);
+        std::io::copy(&mut std::io::stdin().lock(), &mut writer)
+            .and_then(|_| writer.flush())
+            .map_err(|e| std::io::Error::new(e.kind(), format!("Cannot copy stdin to {}: {}", path.display(), e)))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for SpooledStdin {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Opens a file for reading, with the path in the error message.
+pub fn open_file(path: impl AsRef<Path>) -> std::io::Result<File> {
+    File::open(&path).map_err(|e| std::io::Error::new(e.kind(), format!("Cannot open {}: {}", path.as_ref().display(), e)))
+}
+
+/// Creates a file for writing, with the path in the error message.
+pub fn create_file(path: impl AsRef<Path>) -> std::io::Result<File> {
+    File::create(&path).map_err(|e| std::io::Error::new(e.kind(), format!("Cannot create {}: {}", path.as_ref().display(), e)))
+}
+
+/// Output file written to `<path>.tmp` in the destination directory, synced and renamed into
+/// place when dropped after a complete write, so a failed or killed run never leaves a truncated
+/// file under the final name. After a write error, or when dropped during a panic, the temp file
+/// is deleted instead. Without `atomic` the destination is written directly.
+pub struct SafeWriter {
+    file: Option<File>,
+    path: PathBuf,
+    tmp: Option<PathBuf>,
+    failed: bool,
+}
+
+impl SafeWriter {
+    pub fn create(path: impl AsRef<Path>, atomic: bool) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let tmp = atomic.then(|| {
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            PathBuf::from(tmp)
+        });
+        let file = create_file(tmp.as_ref().unwrap_or(&path))?;
+        Ok(Self { file: Some(file), path, tmp, failed: false })
+    }
+
+    /// Completes the file like dropping it does, but returns the sync or rename error.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        let result = self.commit();
+        if result.is_err() {
+            self.discard();
+        }
+        result
+    }
+
+    fn commit(&mut self) -> std::io::Result<()> {
+        let Some(file) = self.file.take() else { return Ok(()) };
+        let Some(tmp) = &self.tmp else { return Ok(()) };
+        file.sync_all().map_err(|e| std::io::Error::new(e.kind(), format!("Cannot sync {}: {}", tmp.display(), e)))?;
+        drop(file);
+        std::fs::rename(tmp, &self.path).map_err(|e| std::io::Error::new(e.kind(), format!("Cannot rename {} to {}: {}", tmp.display(), self.path.display(), e)))
+    }
+
+    fn discard(&mut self) {
+        self.file = None;
+        if let Some(tmp) = &self.tmp {
+            let _ = std::fs::remove_file(tmp);
+        }
+    }
+}
+
+impl Write for SafeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let file = self.file.as_mut().expect("SafeWriter written after finish");
+        file.write(buf).inspect_err(|_| self.failed = true)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let file = self.file.as_mut().expect("SafeWriter written after finish");
+        file.flush().inspect_err(|_| self.failed = true)
+    }
+}
+
+impl Drop for SafeWriter {
+    fn drop(&mut self) {
+        if self.failed || std::thread::panicking() {
+            return self.discard()
+        }
+        if let Err(e) = self.commit() {
+            eprintln!("{}", e);
+            self.discard();
+        }
+    }
+}
+
+/// Writes a file through `write` into `<path>.tmp` and renames it into place, so readers never see
+/// a partially written file. The temp file is deleted if `write` fails.
+pub fn write_atomically<T>(path: impl AsRef<Path>, write: impl FnOnce(&mut BufWriter<SafeWriter>) -> std::io::Result<T>) -> std::io::Result<T> {
+    let mut writer = BufWriter::new(SafeWriter::create(path, true)?);
+    let result = write(&mut writer).inspect_err(|_| writer.get_mut().failed = true)?;
+    writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+    Ok(result)
+}
+
+/// Creates a buffered output file, gzip compressed if the path ends in `.gz`.
+/// With more than one thread the compression runs in parallel via `ParallelGzWriter`. The file is
+/// a `SafeWriter`, complete under its name once the returned writer is dropped.
+pub fn create_output(path: impl AsRef<Path>, threads: usize, level: u32, atomic: bool) -> std::io::Result<Box<dyn Write>> {
+    let file = SafeWriter::create(&path, atomic)?;
+    if !has_gz_extension(&path) {
+        return Ok(Box::new(BufWriter::new(file)))
+    }
+    if threads > 1 {
+        return Ok(Box::new(BufWriter::new(ParallelGzWriter::new(file, threads, Compression::new(level)))))
+    }
+    Ok(Box::new(BufWriter::new(GzEncoder::new(file, Compression::new(level)))))
+}
+
+/// Gzip writer that splits its input into chunks compressed concurrently as independent gzip members.
+/// The concatenated members form a valid multi-member gzip stream that decompresses to the input.
+pub struct ParallelGzWriter<W: Write> {
+    inner: W,
+    threads: usize,
+    level: Compression,
+    chunk_size: usize,
+    current: Vec<u8>,
+    pending: Vec<Vec<u8>>,
+    members: usize,
+}
+
+impl<W: Write> ParallelGzWriter<W> {
+    pub const CHUNK_SIZE: usize = 8 << 20;
+
+    pub fn new(inner: W, threads: usize, level: Compression) -> Self {
+        Self::with_chunk_size(inner, threads, level, Self::CHUNK_SIZE)
+    }
+
+    pub fn with_chunk_size(inner: W, threads: usize, level: Compression, chunk_size: usize) -> Self {
+        Self {
+            inner,
+            threads: threads.max(1),
+            level,
+            chunk_size: chunk_size.max(1),
+            current: Vec::with_capacity(chunk_size),
+            pending: Vec::new(),
+            members: 0,
+        }
+    }
+
+    /// Compresses the pending chunks on one thread each and writes the members in input order.
+    fn compress_pending(&mut self) -> std::io::Result<()> {
+        let level = self.level;
+        let chunks = std::mem::take(&mut self.pending);
+        let members = std::thread::scope(|scope| {
+            let handles = chunks.iter()
+                .map(|chunk| scope.spawn(move || {
+                    let mut encoder = GzEncoder::new(Vec::with_capacity(chunk.len() / 2), level);
+                    encoder.write_all(chunk)?;
+                    encoder.finish()
+                }))
+                .collect::<Vec<_>>();
+            handles.into_iter()
+                .map(|handle| handle.join().expect("Compression thread panicked"))
+                .collect::<std::io::Result<Vec<Vec<u8>>>>()
+        })?;
+        for member in members {
+            self.inner.write_all(&member)?;
+            self.members += 1;
+        }
+        Ok(())
+    }
+
+    fn push_current(&mut self) {
+        if self.current.is_empty() { return };
+        let chunk = std::mem::replace(&mut self.current, Vec::with_capacity(self.chunk_size));
+        self.pending.push(chunk);
+    }
+
+    /// Compresses everything written so far. An empty stream still yields one (empty) gzip member.
+    pub fn try_finish(&mut self) -> std::io::Result<()> {
+        self.push_current();
+        if self.pending.is_empty() && self.members == 0 {
+            self.pending.push(Vec::new());
+        }
+        if !self.pending.is_empty() {
+            self.compress_pending()?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ParallelGzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let take = buf.len().min(self.chunk_size - self.current.len());
+        self.current.extend_from_slice(&buf[..take]);
+        if self.current.len() == self.chunk_size {
+            self.push_current();
+            if self.pending.len() >= self.threads {
+                self.compress_pending()?;
+            }
+        }
+        Ok(take)
+    }
+
+    /// Flushing only flushes the inner writer, members are cut at chunk boundaries so the
+    /// output does not depend on how often the caller flushes.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for ParallelGzWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.try_finish();
+    }
+}
+
+/// True if the path ends in `.gz`, in any case (`.GZ` from Windows tools).
+pub fn has_gz_extension(path: impl AsRef<Path>) -> bool {
+    path.as_ref().extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+}
+
+/// Removes the trailing `\r` of a line read from a file with CRLF line endings.
+pub fn strip_cr(mut line: String) -> String {
+    if line.ends_with('\r') {
+        line.pop();
+    }
+    line
+}
+
+/// Small seeded SplitMix64 generator, enough for reproducible sampling without another dependency.
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+/// Uniform sample of at most `capacity` items from a stream of unknown length (algorithm R).
+pub struct Reservoir<T> {
+    capacity: usize,
+    seen: usize,
+    items: Vec<T>,
+    rng: SplitMix64,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(capacity: usize, seed: u64) -> Self {
+        Self {
+            capacity,
+            seen: 0,
+            items: Vec::new(),
+            rng: SplitMix64::new(seed),
+        }
+    }
+
+    pub fn offer(&mut self, item: T) {
+        self.seen += 1;
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+            return
+        }
+        let slot = self.rng.below(self.seen as u64) as usize;
+        if slot < self.capacity {
+            self.items[slot] = item;
+        }
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Number of items offered so far.
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+}
+
+/// Approximate distinct counter (HyperLogLog) in 2^precision one-byte registers: fixed memory
+/// whatever the number of items, with a relative standard error of about 1.04 / sqrt(2^precision).
+pub struct HyperLogLog {
+    precision: u32,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub const MIN_PRECISION: u32 = 4;
+    pub const MAX_PRECISION: u32 = 18;
+
+    pub fn new(precision: u32) -> Self {
+        let precision = precision.clamp(Self::MIN_PRECISION, Self::MAX_PRECISION);
+        Self { precision, registers: vec![0; 1 << precision] }
+    }
+
+    pub fn insert(&mut self, item: impl std::hash::Hash) {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        item.hash(&mut hasher);
+        self.insert_hash(std::hash::Hasher::finish(&hasher));
+    }
+
+    /// Adds an item by its 64-bit hash: the first `precision` bits pick the register, which keeps
+    /// the highest rank (leading zeros + 1) of the remaining bits.
+    pub fn insert_hash(&mut self, hash: u64) {
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    /// Estimated number of distinct items, by linear counting while registers are still empty.
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum = self.registers.iter().map(|rank| 2f64.powi(-(*rank as i32))).sum::<f64>();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|rank| **rank == 0).count();
+        match raw <= 2.5 * m && zeros > 0 {
+            true => m * (m / zeros as f64).ln(),
+            false => raw,
+        }
+    }
+
+    /// Relative standard error of the estimate.
+    pub fn relative_error(&self) -> f64 {
+        1.04 / (self.registers.len() as f64).sqrt()
+    }
+}
+
+/// Assumed decompression ratio of compressed inputs when extrapolating a sample to the whole file.
+const COMPRESSION_RATIO: u64 = 4;
+
+/// Cardinality estimate from the first bytes of an input, used to pre-size hash maps.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CapacityEstimate {
+    pub sampled_records: usize,
+    pub distinct_taxa: usize,
+    pub distinct_pairs: usize,
+    /// Records of the whole file, extrapolated from the sample by file size.
+    pub records: usize,
+    /// Expected number of distinct pairs: distinct taxa squared, capped by the records, and
+    /// never below the pairs already seen in the sample.
+    pub pairs: usize,
+}
+
+/// Reads up to `sample_bytes` (decompressed) of `path` and counts the distinct taxa and pairs
+/// returned by `pair` for each line, lines returning None are ignored.
+pub fn estimate_capacity(path: impl AsRef<Path>, sample_bytes: u64, mut pair: impl FnMut(&str) -> Option<(u32, u32)>) -> std::io::Result<CapacityEstimate> {
+    let file_size = open_file(&path)?.metadata()?.len();
+    let mut taxa = std::collections::HashSet::new();
+    let mut pairs = std::collections::HashSet::new();
+    let mut result = CapacityEstimate::default();
+
+    let mut sampled = 0u64;
+    let mut complete = true;
+    for line in file_lines(&path)? {
+        let line = line?;
+        if sampled >= sample_bytes {
+            complete = false;
+            break
+        }
+        sampled += line.len() as u64 + 1;
+        let Some((from, to)) = pair(&line) else { continue };
+        result.sampled_records += 1;
+        taxa.insert(from);
+        taxa.insert(to);
+        pairs.insert((from, to));
+    }
+
+    result.distinct_taxa = taxa.len();
+    result.distinct_pairs = pairs.len();
+    result.records = match complete {
+        true => result.sampled_records,
+        false => {
+            let size = if Codec::of_file(&path)? != Codec::Plain { file_size.saturating_mul(COMPRESSION_RATIO) } else { file_size };
+            (result.sampled_records as u128 * size as u128 / sampled.max(1) as u128) as usize
+        },
+    };
+    result.pairs = result.distinct_taxa.saturating_mul(result.distinct_taxa).min(result.records).max(result.distinct_pairs);
+    Ok(result)
+}
+
+/// `dir/name.tsv.gz` becomes `dir/name.<part>.tsv.gz`: the part goes before the first extension
+/// so compression is still recognized by the suffix.
+pub fn part_path(output: &str, part: &str) -> PathBuf {
+    let path = Path::new(output);
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let name = match name.split_once('.') {
+        Some((stem, extensions)) => format!("{}.{}.{}", stem, part, extensions),
+        None => format!("{}.{}", name, part),
+    };
+    path.with_file_name(name)
+}
+
+/// Path of a file in the temporary directory for a test, unique per process and name. Any file
+/// left there by an earlier run is removed.
+#[cfg(test)]
+pub(crate) fn test_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("fix_gtdb_mg_{}_{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `from<TAB>to` lines of fixed width and returns the path.
+    fn pairs_file(name: &str, pairs: impl IntoIterator<Item = (u32, u32)>) -> PathBuf {
+        let path = test_path(name);
+        let content = pairs.into_iter().map(|(from, to)| format!("{:05}\t{:05}\n", from, to)).collect::<String>();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn parse(line: &str) -> Option<(u32, u32)> {
+        let (from, to) = line.split_once('\t')?;
+        Some((from.parse().ok()?, to.parse().ok()?))
+    }
+
+    #[test]
+    fn distinct_counts_are_estimated_within_their_error() {
+        assert_eq!(HyperLogLog::new(0).registers.len(), 1 << HyperLogLog::MIN_PRECISION);
+        assert_eq!(HyperLogLog::new(30).registers.len(), 1 << HyperLogLog::MAX_PRECISION);
+        assert_eq!(HyperLogLog::new(14).estimate(), 0.0);
+        for precision in [4, 10, 14] {
+            for distinct in [1u64, 10, 100, 1_000, 10_000, 300_000] {
+                let mut counter = HyperLogLog::new(precision);
+                // Every item three times, as pairs are seen again and again
+                (0..3).for_each(|_| (0..distinct).for_each(|item| counter.insert((item, item % 7))));
+                let error = (counter.estimate() - distinct as f64).abs() / distinct as f64;
+                // Three standard errors, linear counting is close to exact while registers are empty
+                let allowed = 3.0 * counter.relative_error();
+                assert!(error <= allowed, "precision {}, {} distinct: estimate {}, error {} > {}", precision, distinct, counter.estimate(), error, allowed);
+                if distinct * 10 < 1 << precision {
+                    assert!(error < 0.05, "precision {}, {} distinct: estimate {}", precision, distinct, counter.estimate());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn complete_samples_count_exactly() {
+        // Every ordered pair of 20 taxa, three times over
+        let all = (0..3).flat_map(|_| (0..20).flat_map(|from| (0..20).map(move |to| (from, to)))).collect::<Vec<_>>();
+        let path = pairs_file("capacity_dense.tsv", all);
+        let estimate = estimate_capacity(&path, u64::MAX, parse).unwrap();
+        assert_eq!((estimate.sampled_records, estimate.records), (1200, 1200));
+        assert_eq!((estimate.distinct_taxa, estimate.distinct_pairs, estimate.pairs), (20, 400, 400));
+
+        // 1000 taxa in 500 records: the square is capped by the records
+        let path = pairs_file("capacity_sparse.tsv", (0..500).map(|i| (2 * i, 2 * i + 1)));
+        let estimate = estimate_capacity(&path, u64::MAX, parse).unwrap();
+        assert_eq!((estimate.distinct_taxa, estimate.distinct_pairs, estimate.records, estimate.pairs), (1000, 500, 500, 500));
+
+        // Lines the parser rejects are not records
+        let path = test_path("capacity_headers.tsv");
+        std::fs::write(&path, "@HD\tVN:1.6\n00001\t00002\n@CO\tx\n00002\t00001\n").unwrap();
+        let estimate = estimate_capacity(&path, u64::MAX, parse).unwrap();
+        assert_eq!((estimate.sampled_records, estimate.distinct_taxa, estimate.pairs), (2, 2, 2));
+    }
+
+    #[test]
+    fn partial_samples_extrapolate_by_file_size() {
+        // 10000 records over 50 taxa, each line 12 bytes; the first 1200 bytes hold 100 records
+        let mut random = SplitMix64::new(3);
+        let records = (0..10_000).map(|_| (random.below(50) as u32, random.below(50) as u32)).collect::<Vec<_>>();
+        let distinct = records.iter().collect::<std::collections::HashSet<_>>().len();
+        let path = pairs_file("capacity_partial.tsv", records);
+        let estimate = estimate_capacity(&path, 1200, parse).unwrap();
+        assert_eq!(estimate.sampled_records, 100);
+        assert_eq!(estimate.records, 10_000);
+        assert!(estimate.distinct_taxa <= 50 && estimate.distinct_pairs <= 100);
+        assert_eq!(estimate.pairs, estimate.distinct_taxa * estimate.distinct_taxa);
+        // The square of the taxa seen is within a factor of two of the pairs of the whole file
+        assert!(estimate.pairs * 2 >= distinct && estimate.pairs <= distinct * 2, "{} pairs estimated, {} present", estimate.pairs, distinct);
+    }
 }
@@ -0,0 +1,65 @@
+//! `analyze` against the pipeline of separate binaries on the fixture SAM.
+
+mod common;
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use common::{arg, read, run, scratch, SAM};
+
+/// An output of `analyze` without the schema header it leads with.
+fn analyzed(dir: &Path, file: &str) -> String {
+    let content = read(dir.join(file));
+    let (header, rest) = content.split_once('\n').unwrap();
+    assert!(header.starts_with("#schema\t"), "{} starts with {}", file, header);
+    rest.to_string()
+}
+
+/// Values of a gene leaks report by (taxid, metric), without the uniformity the pairwise map
+/// has no positions for.
+fn gene_leak_rows(report: &str) -> BTreeMap<(String, String), Vec<String>> {
+    report.lines().skip(1)
+        .map(|line| line.split('\t').map(String::from).collect::<Vec<String>>())
+        .filter(|fields| fields[3] != "uniformity")
+        .map(|fields| ((fields[0].clone(), fields[3].clone()), fields[1..].to_vec()))
+        .collect()
+}
+
+fn same_value(left: &str, right: &str) -> bool {
+    match (left.parse::<f64>(), right.parse::<f64>()) {
+        (Ok(left), Ok(right)) => (left - right).abs() <= 1e-12,
+        _ => left == right,
+    }
+}
+
+#[test]
+fn analyze_matches_the_separate_binaries() {
+    let dir = scratch("analyze");
+    let out = |file: &str| arg(&dir, file);
+    run(env!("CARGO_BIN_EXE_analyze"), &["--input", SAM, "-o", &out("analysis")]);
+    let analysis = dir.join("analysis");
+
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--output", &out("pairwise.tsv")]);
+    assert_eq!(analyzed(&analysis, "tables/pairwise.tsv"), read(dir.join("pairwise.tsv")));
+
+    run(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &out("pairwise.tsv"), "--output", &out("normalized.tsv")]);
+    assert_eq!(analyzed(&analysis, "tables/normalized.tsv"), read(dir.join("normalized.tsv")));
+
+    // Normalized per record instead of per pair, the sums differ in the last digits
+    let expected = gene_leak_rows(&run(env!("CARGO_BIN_EXE_mask_genes"), &["--input", SAM]));
+    let actual = gene_leak_rows(&analyzed(&analysis, "genes/gene_leaks.tsv"));
+    assert_eq!(actual.keys().collect::<Vec<_>>(), expected.keys().collect::<Vec<_>>());
+    for (key, values) in &actual {
+        assert!(values.len() == expected[key].len() && values.iter().zip(&expected[key]).all(|(l, r)| same_value(l, r)), "{:?}: {:?} != {:?}", key, values, expected[key]);
+    }
+
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--leakage-records", "--output", &out("records.tsv")]);
+    run(env!("CARGO_BIN_EXE_fix_gtdb_mg"), &[&out("records.tsv"), &out("summary.tsv")]);
+    let sorted = |content: String| {
+        let mut lines = content.lines().map(String::from).collect::<Vec<String>>();
+        lines.sort();
+        lines
+    };
+    assert_eq!(sorted(analyzed(&analysis, "tables/taxon_summary.tsv")), sorted(read(dir.join("summary.tsv"))));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
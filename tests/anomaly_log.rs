@@ -0,0 +1,32 @@
+//! The anomaly log is written to --anomaly-log, or summarized on stderr; a log that cannot be
+//! written fails the run with a message instead of a panic.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, read, scratch, SAM};
+
+#[test]
+fn anomaly_logs_that_cannot_be_written_fail_the_run() {
+    let dir = scratch("anomaly_log");
+    let sam = arg(&dir, "reads.sam");
+    fs::write(&sam, read(SAM) + "1_1_r99\tnot_a_flag\t2_1\t1\t30\t50M\t*\t0\t0\t*\t*\n").unwrap();
+    let log = arg(&dir, "anomalies.tsv");
+    let result = output(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &sam, "--skip-invalid", "--anomaly-log", &log]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let written = read(&log);
+    assert!(written.starts_with("category\tcount\tfirst_example\tinput\trecord\n") && written.lines().count() == 2, "{}", written);
+    assert!(!dir.join("anomalies.tsv.tmp").exists());
+
+    // Without --anomaly-log the log goes to stderr
+    let result = output(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &sam, "--skip-invalid"]);
+    assert!(result.status.success() && String::from_utf8_lossy(&result.stderr).contains("category\tcount\tfirst_example"));
+
+    let missing = arg(&dir, "missing/anomalies.tsv");
+    let result = output(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &sam, "--skip-invalid", "--anomaly-log", &missing]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("Cannot create") && !stderr.contains("panicked"), "{}", stderr);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,37 @@
+//! Helpers shared by the integration tests, which run the binaries on the fixtures of tests/data.
+
+#![allow(dead_code)]
+
+use std::{fs, path::{Path, PathBuf}, process::{Command, Output}};
+
+pub const SAM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/leaks.sam");
+pub const LABELS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/labels.tsv");
+
+/// Runs a binary to completion, whatever its exit status.
+pub fn output(binary: &str, args: &[&str]) -> Output {
+    Command::new(binary).args(args).output().unwrap_or_else(|e| panic!("Cannot run {}: {}", binary, e))
+}
+
+/// Runs a binary that must succeed, returns its standard output.
+pub fn run(binary: &str, args: &[&str]) -> String {
+    let output = output(binary, args);
+    assert!(output.status.success(), "{} {:?} failed: {}", binary, args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).expect("Output is UTF-8")
+}
+
+/// Empty directory for the files of a test, unique per process and name.
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fix_gtdb_mg_{}_{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Path of a file in a scratch directory as an argument.
+pub fn arg(dir: &Path, file: &str) -> String {
+    dir.join(file).to_str().expect("Temporary paths are UTF-8").to_string()
+}
+
+pub fn read(path: impl AsRef<Path>) -> String {
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.as_ref().display(), e))
+}
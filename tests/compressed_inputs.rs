@@ -0,0 +1,60 @@
+//! gzip, zstd and bzip2 inputs read like their plain counterparts, told apart by their magic bytes
+//! whatever their extension, with concatenated members, frames and streams read in full.
+
+mod common;
+
+use std::{fs, io::Write};
+
+use common::{arg, read, run, scratch, SAM};
+
+fn gzip(content: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(content).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn zstd(content: &[u8]) -> Vec<u8> {
+    zstd::encode_all(content, 3).unwrap()
+}
+
+fn bzip2(content: &[u8]) -> Vec<u8> {
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+    encoder.write_all(content).unwrap();
+    encoder.finish().unwrap()
+}
+
+type Compress = fn(&[u8]) -> Vec<u8>;
+
+/// `content` compressed in two parts split at a line, one member, frame or stream each.
+fn concatenated(compress: Compress, content: &str) -> Vec<u8> {
+    let middle = content[..content.len() / 2].rfind('\n').unwrap() + 1;
+    let (first, second) = content.as_bytes().split_at(middle);
+    [compress(first), compress(second)].concat()
+}
+
+#[test]
+fn compressed_inputs_count_like_plain_ones() {
+    let dir = scratch("compressed_inputs");
+    let out = |file: &str| arg(&dir, file);
+    let sam = read(SAM);
+    let plain = run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM]);
+    fs::write(out("pairwise.tsv"), &plain).unwrap();
+    let normalized = run(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &out("pairwise.tsv")]);
+
+    let codecs: [(&str, Compress); 3] = [("gz", gzip), ("zst", zstd), ("bz2", bzip2)];
+    for (extension, compress) in codecs {
+        for (file, content) in [
+            (format!("leaks.sam.{}", extension), compress(sam.as_bytes())),
+            (format!("concatenated.sam.{}", extension), concatenated(compress, &sam)),
+            (format!("{}_named_plain.sam", extension), compress(sam.as_bytes())),
+        ] {
+            fs::write(out(&file), content).unwrap();
+            assert_eq!(run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &out(&file)]), plain, "{}", file);
+        }
+
+        let file = format!("pairwise.tsv.{}", extension);
+        fs::write(out(&file), compress(plain.as_bytes())).unwrap();
+        assert_eq!(run(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &out(&file)]), normalized, "{}", file);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,50 @@
+//! merge_pairwise sums counts up to the largest their tables hold, and refuses a sum beyond it
+//! naming the table and pair instead of wrapping.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, run, scratch};
+
+const HEADER: &str = "#self_pairs\toutput=included\tdenominators=included\n#pairs\tdirected\tgene_base=1\n";
+
+fn table(dir: &std::path::Path, file: &str, rows: &[String]) -> String {
+    let path = arg(dir, file);
+    fs::write(&path, format!("{}{}\n", HEADER, rows.join("\n"))).unwrap();
+    path
+}
+
+/// Rows of the merged table, without headers.
+fn rows(table: &str) -> Vec<&str> {
+    table.lines().filter(|line| !line.starts_with('#')).collect()
+}
+
+#[test]
+fn merges_refuse_counts_beyond_their_type() {
+    let dir = scratch("count_overflow");
+    let merge = env!("CARGO_BIN_EXE_merge_pairwise");
+    let (half, max) = (i64::MAX / 2, i64::MAX);
+    // Gene counts are i64: two halves fit, one more read does not
+    let halves = table(&dir, "halves.tsv", &[format!("1\t2\t{}\t{}", half, half), "2\t2\t5\t5".to_string()]);
+    let one = table(&dir, "one.tsv", &["1\t2\t1\t1".to_string()]);
+    let merged = run(merge, &[&halves, &halves]);
+    assert_eq!(rows(&merged), ["2\t2\t10\t10", &format!("1\t2\t{}\t{}", max - 1, max - 1)]);
+    let merged = run(merge, &[&halves, &halves, &one]);
+    assert_eq!(rows(&merged)[1], format!("1\t2\t{}\t{}", max, max));
+
+    let result = output(merge, &[&halves, &halves, &one, &one]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains(&format!("{}: Count overflow: {} + 1 reads on gene 1 of pair 1->2", one, max)), "{}", stderr);
+
+    // Totals without genes are u64
+    let totals = table(&dir, "totals.tsv", &[format!("1\t2\t{}", u64::MAX - 1)]);
+    let merged = run(merge, &["--no-genes", &totals, &one]);
+    assert_eq!(rows(&merged), [format!("1\t2\t{}", u64::MAX)]);
+    let result = output(merge, &["--no-genes", &totals, &one, &one]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains(&format!("{}: Count overflow: {} + 1 for pair 1->2", one, u64::MAX)), "{}", stderr);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,62 @@
+//! --debug-taxon logs exactly the records of its taxa, and unknown taxa end the run with an error
+//! before any record is read.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, read, run, scratch, LABELS, SAM};
+
+/// (record, qname) of every row of a debug log.
+fn logged(path: &str) -> Vec<(usize, String)> {
+    read(path).lines().skip(1).map(|line| {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        (fields[0].parse().unwrap(), fields[1].to_string())
+    }).collect()
+}
+
+#[test]
+fn debug_log_holds_exactly_the_records_of_the_taxon() {
+    let dir = scratch("debug_taxon");
+    let by_id = arg(&dir, "by_id.tsv");
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--output", &arg(&dir, "pairwise.tsv"), "--debug-taxon", "3", "--debug-log", &by_id]);
+
+    // Records are numbered by their line, the query or the reference names taxon 3
+    let expected = read(SAM).lines().enumerate()
+        .filter(|(_index, line)| !line.starts_with('@'))
+        .filter(|(_index, line)| {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            fields[0].starts_with("3_") || fields[2].starts_with("3_")
+        })
+        .map(|(index, line)| (index + 1, line.split('\t').next().unwrap().to_string()))
+        .collect::<Vec<_>>();
+    assert!(!expected.is_empty());
+    assert_eq!(logged(&by_id), expected);
+
+    let by_label = arg(&dir, "by_label.tsv");
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--output", &arg(&dir, "pairwise.tsv"), "--map", LABELS, "--debug-taxon", "s__Beta three", "--debug-log", &by_label]);
+    assert_eq!(read(&by_label), read(&by_id));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unknown_debug_taxa_are_errors_not_panics() {
+    let dir = scratch("debug_taxon_unknown");
+    let debug = ["--debug-taxon", "s__Gamma unknown", "--map", LABELS, "--debug-log", &arg(&dir, "debug.tsv")];
+    let runs: [(&str, Vec<&str>); 3] = [
+        (env!("CARGO_BIN_EXE_pairwise_leakage"), [&["--input", SAM][..], &debug].concat()),
+        (env!("CARGO_BIN_EXE_ambiguity_matrix"), [&["--input", SAM][..], &debug].concat()),
+        (env!("CARGO_BIN_EXE_analyze"), [&["--input", SAM, "-o", dir.to_str().unwrap()][..], &debug].concat()),
+    ];
+    for (binary, args) in runs {
+        let result = output(binary, &args);
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert_eq!(result.status.code(), Some(1), "{}: {}", binary, stderr);
+        assert!(stderr.contains("--debug-taxon s__Gamma unknown is not a label of"), "{}: {}", binary, stderr);
+        assert!(!stderr.contains("panicked"), "{}: {}", binary, stderr);
+    }
+    let result = output(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--debug-taxon", "s__Beta three"]);
+    assert!(String::from_utf8_lossy(&result.stderr).contains("labels need --map"));
+    assert_eq!(result.status.code(), Some(1));
+    fs::remove_dir_all(&dir).unwrap();
+}
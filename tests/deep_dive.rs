@@ -0,0 +1,88 @@
+//! deep_dive writes every ordered pair of its taxa, with or without leakage, from a SAM or a
+//! pairwise table alike.
+
+mod common;
+
+use std::{collections::HashMap, fs};
+
+use common::{arg, output, read, run, scratch, LABELS, SAM};
+
+/// Rows of a deep dive by (from, to), as column name to value.
+fn rows(table: &str) -> Vec<((String, String), HashMap<String, String>)> {
+    let mut lines = table.lines().filter(|line| !line.starts_with('#'));
+    let header = lines.next().unwrap().split('\t').map(str::to_string).collect::<Vec<String>>();
+    lines.map(|line| {
+        let row = header.iter().cloned().zip(line.split('\t').map(str::to_string)).collect::<HashMap<String, String>>();
+        ((row["from"].clone(), row["to"].clone()), row)
+    }).collect()
+}
+
+#[test]
+fn every_ordered_pair_is_written() {
+    let dir = scratch("deep_dive");
+    let pairwise = arg(&dir, "pairwise.tsv");
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--output", &pairwise]);
+    // An id, a label and a taxon without any reads
+    let taxa = arg(&dir, "taxa.txt");
+    fs::write(&taxa, "1\n3\ns__Alpha two\n9\n").unwrap();
+
+    let from_sam = run(env!("CARGO_BIN_EXE_deep_dive"), &["--input", SAM, "--map", LABELS, "--taxa", &taxa]);
+    let from_table = run(env!("CARGO_BIN_EXE_deep_dive"), &["--input", &pairwise, "--from-pairwise", "--map", LABELS, "--taxa", &taxa]);
+    assert_eq!(from_sam, from_table);
+
+    let leaked = read(&pairwise).lines().filter(|line| !line.starts_with('#'))
+        .map(|line| line.split('\t').take(3).map(str::to_string).collect::<Vec<String>>())
+        .map(|fields| ((fields[0].clone(), fields[1].clone()), fields[2].clone()))
+        .collect::<HashMap<(String, String), String>>();
+
+    let rows = rows(&from_sam);
+    let ids = ["1", "2", "3", "9"];
+    let expected = ids.iter().flat_map(|from| ids.iter().filter(move |to| *to != from).map(move |to| (from.to_string(), to.to_string()))).collect::<Vec<_>>();
+    assert_eq!(rows.iter().map(|(pair, _row)| pair.clone()).collect::<Vec<_>>(), expected);
+    for ((from, to), row) in &rows {
+        let reads = leaked.get(&(from.clone(), to.clone())).map_or("0", String::as_str);
+        assert_eq!(row["reads"], reads, "{} -> {}", from, to);
+        let unknown = from == "9" || to == "9";
+        assert_eq!(row["lca_rank"] == "NA", unknown, "{} -> {}", from, to);
+        assert_eq!(row["tree_distance"], "NA");
+    }
+    assert!(rows.iter().any(|(_pair, row)| row["reads"] == "0" && row["reverse_reads"] == "0" && row["skew"] == "NA"));
+    assert_eq!(rows.iter().find(|(pair, _row)| *pair == ("9".to_string(), "1".to_string())).unwrap().1["from_label"], "NA");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn taxa_differing_in_separators_and_case_join_under_label_normalize() {
+    let dir = scratch("deep_dive_normalize");
+    let taxa = arg(&dir, "taxa.txt");
+    fs::write(&taxa, "s__Alpha one\nS__ALPHA_TWO\nBeta%20three\n").unwrap();
+
+    let exact = output(env!("CARGO_BIN_EXE_deep_dive"), &["--input", SAM, "--map", LABELS, "--taxa", &taxa]);
+    assert_eq!(exact.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&exact.stderr).contains("Taxon S__ALPHA_TWO"));
+
+    let strict = output(env!("CARGO_BIN_EXE_deep_dive"), &["--input", SAM, "--map", LABELS, "--taxa", &taxa, "--label-normalize", "strict"]);
+    let (stdout, stderr) = (String::from_utf8_lossy(&strict.stdout), String::from_utf8_lossy(&strict.stderr));
+    assert!(strict.status.success(), "{}", stderr);
+    assert!(stdout.starts_with("#label_normalize\tstrict\n"));
+    assert_eq!(rows(&stdout).len(), 6);
+    assert!(stderr.contains("2 label joins succeeded only after --label-normalize strict"), "{}", stderr);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "tree")]
+#[test]
+fn tree_distances_join_the_labels() {
+    let dir = scratch("deep_dive_tree");
+    let taxa = arg(&dir, "taxa.txt");
+    let tree = arg(&dir, "tree.nwk");
+    fs::write(&taxa, "1\n2\n3\n").unwrap();
+    fs::write(&tree, "(('s__Alpha one':1,'s__Alpha two':2):1,'s__Beta three':3);\n").unwrap();
+    let table = run(env!("CARGO_BIN_EXE_deep_dive"), &["--input", SAM, "--map", LABELS, "--taxa", &taxa, "--tree", &tree]);
+    let distances = rows(&table).into_iter().map(|((from, to), row)| (from + ">" + &to, row["tree_distance"].clone())).collect::<HashMap<String, String>>();
+    assert_eq!(distances.len(), 6);
+    for (pair, distance) in [("1>2", "3"), ("2>1", "3"), ("1>3", "5"), ("3>2", "6")] {
+        assert_eq!(distances[pair], distance, "{}", pair);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,44 @@
+//! The defaults command lists every shared option of the clap definitions, hidden ones in JSON,
+//! with the defaults clap applies.
+
+mod common;
+
+use std::collections::BTreeMap;
+
+use clap::{ArgAction, CommandFactory};
+use common::run;
+use fix_gtdb_mg::common::Args;
+
+/// Value of a string field of a JSON line of `defaults --json`, None for null.
+fn field(line: &str, name: &str) -> Option<String> {
+    let rest = line.split_once(&format!("\"{}\": ", name)).unwrap_or_else(|| panic!("No {} in {}", name, line)).1;
+    let value = rest.strip_prefix('"')?;
+    // Option help and defaults hold no escaped quotes
+    Some(value[..value.find('"').unwrap()].to_string())
+}
+
+#[test]
+fn json_defaults_cover_every_clap_argument() {
+    let command = Args::command();
+    let expected = command.get_arguments()
+        .filter(|arg| !matches!(arg.get_action(), ArgAction::Help | ArgAction::HelpShort | ArgAction::HelpLong | ArgAction::Version))
+        .map(|arg| {
+            let default = arg.get_default_values().iter().map(|value| value.to_string_lossy().into_owned()).collect::<Vec<String>>();
+            (arg.get_id().to_string(), ((!default.is_empty()).then(|| default.join(",")), arg.is_hide_set()))
+        })
+        .collect::<BTreeMap<String, (Option<String>, bool)>>();
+    assert!(expected.len() > 50 && expected.values().any(|(_default, hidden)| *hidden));
+
+    let json = run(env!("CARGO_BIN_EXE_defaults"), &["--json"]);
+    assert!(json.starts_with("[\n") && json.ends_with("]\n"));
+    let listed = json.lines().filter(|line| line.starts_with("  {")).map(|line| {
+        (field(line, "id").unwrap(), (field(line, "default"), line.contains("\"hidden\": true")))
+    }).collect::<Vec<_>>();
+    assert_eq!(listed.len(), expected.len(), "an option is listed twice");
+    assert_eq!(listed.into_iter().collect::<BTreeMap<_, _>>(), expected);
+
+    // The table leaves hidden options out
+    let table = run(env!("CARGO_BIN_EXE_defaults"), &[]);
+    assert_eq!(table.lines().count() - 1, expected.values().filter(|(_default, hidden)| !hidden).count());
+    assert!(table.lines().any(|line| line.starts_with("fix_gtdb_mg\t--min_mapq\t4\t")), "{}", table);
+}
@@ -0,0 +1,77 @@
+//! Under --deterministic, repeated analyze runs on the same input write byte-identical results
+//! directories, the "run" line of the manifest apart.
+
+mod common;
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use common::{run, scratch};
+use fix_gtdb_mg::utils::SplitMix64;
+
+/// A SAM of many taxa with leaked and multi-mapped reads, so that hash map order and float
+/// summation order would show in the outputs.
+fn synthetic_sam(path: &Path) {
+    let (taxa, genes) = (40, 5);
+    let mut random = SplitMix64::new(11);
+    let mut sam = String::new();
+    for taxon in 1..=taxa {
+        for gene in 1..=genes {
+            sam += &format!("@SQ\tSN:{}_{}\tLN:200\n", taxon, gene);
+        }
+    }
+    for read in 0..3000 {
+        let (taxon, gene) = (random.below(taxa) + 1, random.below(genes) + 1);
+        let alignments = 1 + (random.below(4) == 0) as u64 + (random.below(8) == 0) as u64;
+        for alignment in 0..alignments {
+            let leaked = alignment > 0 || random.below(5) == 0;
+            let target = if leaked { random.below(taxa) + 1 } else { taxon };
+            let flag = if alignment > 0 { 256 } else { 0 };
+            sam += &format!("{}_{}_r{}\t{}\t{}_{}\t{}\t42\t50M\t*\t0\t0\t*\t*\n", taxon, gene, read, flag, target, gene, random.below(150) + 1);
+        }
+    }
+    fs::write(path, sam).unwrap();
+}
+
+/// Every file of a directory by its path relative to it, the manifest without its "run" line.
+fn contents(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+    let mut result = BTreeMap::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                pending.push(path);
+                continue
+            }
+            let name = path.strip_prefix(dir).unwrap().to_string_lossy().into_owned();
+            let mut content = fs::read(&path).unwrap();
+            if name == "manifest.json" {
+                let text = String::from_utf8(content).unwrap();
+                assert!(text.contains("\n  \"run\": "), "{}", text);
+                content = text.lines().filter(|line| !line.starts_with("  \"run\": ")).collect::<Vec<&str>>().join("\n").into_bytes();
+            }
+            result.insert(name, content);
+        }
+    }
+    result
+}
+
+#[test]
+fn repeated_runs_write_identical_directories() {
+    let dir = scratch("determinism");
+    let sam = dir.join("synthetic.sam");
+    synthetic_sam(&sam);
+    for (index, options) in [&[][..], &["--multimap-weighting", "fraction"], &["--equalize-depth", "20", "--seed", "5"]].into_iter().enumerate() {
+        let runs = ["first", "second"].map(|name| {
+            let results = dir.join(format!("{}_{}", name, index));
+            run(env!("CARGO_BIN_EXE_analyze"), &[&["--input", sam.to_str().unwrap(), "--deterministic", "--threads", "4", "-o", results.to_str().unwrap()][..], options].concat());
+            contents(&results)
+        });
+        assert_eq!(runs[0].keys().collect::<Vec<_>>(), runs[1].keys().collect::<Vec<_>>(), "{:?}", options);
+        assert!(runs[0].len() >= 7, "{:?}", runs[0].keys());
+        for (file, content) in &runs[0] {
+            assert!(runs[1][file] == *content, "{:?}: {} differs between runs", options, file);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
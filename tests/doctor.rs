@@ -0,0 +1,51 @@
+//! doctor passes a fresh results directory and names the check an edited output fails.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{arg, output, read, run, scratch, SAM};
+
+/// Failed (file, check) pairs of a doctor run, with its exit code.
+fn failures(dir: &Path) -> (Option<i32>, Vec<(String, String)>) {
+    let result = output(env!("CARGO_BIN_EXE_doctor"), &["--dir", dir.to_str().unwrap()]);
+    let failed = String::from_utf8_lossy(&result.stdout).lines().skip(1)
+        .map(|line| line.split('\t').collect::<Vec<&str>>())
+        .filter(|fields| fields[2] == "fail")
+        .map(|fields| (fields[0].to_string(), fields[1].to_string()))
+        .collect();
+    (result.status.code(), failed)
+}
+
+#[test]
+fn edited_outputs_fail_their_fingerprint_and_invariants() {
+    let dir = scratch("doctor");
+    run(env!("CARGO_BIN_EXE_analyze"), &["--input", SAM, "-o", dir.to_str().unwrap()]);
+    assert!(read(dir.join("manifest.json")).contains("\"fingerprint\": "));
+    assert_eq!(failures(&dir), (Some(0), Vec::new()));
+
+    // Doubling the first normalized total breaks its sum over the genes
+    let normalized = arg(&dir, "tables/normalized.tsv");
+    let edited = read(&normalized).lines().map(|line| {
+        let mut fields = line.split('\t').map(str::to_string).collect::<Vec<String>>();
+        if !line.starts_with('#') && fields.len() > 2 {
+            fields[1] = format!("{}", fields[1].parse::<f64>().unwrap() * 2.0 + 1.0);
+        }
+        fields.join("\t") + "\n"
+    }).collect::<String>();
+    fs::write(&normalized, edited).unwrap();
+    let (code, failed) = failures(&dir);
+    assert_eq!(code, Some(1));
+    assert_eq!(failed, vec![
+        ("tables/normalized.tsv".to_string(), "fingerprint".to_string()),
+        ("tables/normalized.tsv".to_string(), "gene_totals".to_string()),
+    ]);
+
+    // A comment line keeps every invariant, only the fingerprint notices
+    let summary = arg(&dir, "tables/taxon_summary.tsv");
+    fs::write(&summary, read(&summary) + "# checked by hand\n").unwrap();
+    let (_code, failed) = failures(&dir);
+    assert!(failed.contains(&("tables/taxon_summary.tsv".to_string(), "fingerprint".to_string())), "{:?}", failed);
+    assert_eq!(failed.len(), 3, "{:?}", failed);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,100 @@
+//! evidence writes the tables of one fixture taxon with their headers and rows, the same from a
+//! results directory as computed from the SAM, and suggests close labels for unknown taxa.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{arg, output, read, run, scratch, LABELS, SAM};
+
+/// (header, data rows) of an evidence table.
+fn table(dir: &Path, file: &str) -> (String, Vec<String>) {
+    let content = read(dir.join(file));
+    let mut lines = content.lines().map(str::to_string);
+    (lines.next().unwrap(), lines.collect())
+}
+
+/// Data rows of a results table of `analyze`.
+fn results_rows(dir: &Path, file: &str) -> Vec<Vec<String>> {
+    read(dir.join(file)).lines().filter(|line| !line.starts_with('#')).map(|line| line.split('\t').map(str::to_string).collect()).collect()
+}
+
+#[test]
+fn evidence_tables_of_a_fixture_taxon() {
+    let dir = scratch("evidence");
+    let results = dir.join("results");
+    run(env!("CARGO_BIN_EXE_analyze"), &["--input", SAM, "--map", LABELS, "--deterministic", "-o", results.to_str().unwrap()]);
+    let from_dir = dir.join("from_dir");
+    let listed = run(env!("CARGO_BIN_EXE_evidence"), &["--dir", results.to_str().unwrap(), "--taxon", "s__Beta three", "--map", LABELS, "-o", from_dir.to_str().unwrap(), "--html"]);
+
+    let pairwise = results_rows(&results, "tables/pairwise.tsv");
+    let other_pairs = |own: usize| pairwise.iter().filter(|row| row[own] == "3" && row[1 - own] != "3").count();
+    let masked = results_rows(&results, "mask/mask.tsv").iter().filter(|row| row[0] == "3").count();
+    let expected = [
+        ("summary.tsv", "taxon\tlabel\ttotal\tcorrect\tcorrect_frac\tout_incorrect\tout_frac\tin_incorrect\tin_frac", 1),
+        ("incoming.tsv", "donor\tdonor_label\treads\tgenes", other_pairs(1)),
+        ("outgoing.tsv", "recipient\trecipient_label\treads\tgenes", other_pairs(0)),
+        ("completeness.tsv", "gene\tcorrect_reads\tleaked_reads", 3),
+        ("gene_profile.tsv", "taxon\tgood_genes\tleaked_on_genes\tmetric\tgene_1\tgene_2\tgene_3", results_rows(&results, "genes/gene_leaks.tsv").iter().filter(|row| row[0] == "3").count()),
+        ("normalized.tsv", "recipient\ttotal\tgenes", 1),
+        ("cluster.tsv", "taxon\tlabel\thops\treads_with_taxon", 3),
+        ("mask.tsv", "taxid\tgene\tmetric\tvalue\tthreshold\ttop_donor\tlca_rank\trule", masked),
+    ];
+    assert!(expected.iter().all(|(_file, _header, rows)| *rows > 0));
+    for (file, header, rows) in expected {
+        let (found_header, found_rows) = table(&from_dir, file);
+        assert_eq!(found_header, header, "{}", file);
+        assert_eq!(found_rows.len(), rows, "{}: {:?}", file, found_rows);
+        assert!(listed.contains(&format!("{}\t{}\n", file, rows)), "{}", listed);
+    }
+    assert!(table(&from_dir, "summary.tsv").1[0].starts_with("3\ts__Beta three\t"));
+    // The taxon itself, its direct partner and the partner's partner
+    assert_eq!(table(&from_dir, "cluster.tsv").1.iter().map(|row| row.split('\t').take(3).collect::<Vec<&str>>().join(" ")).collect::<Vec<_>>(),
+        ["3 s__Beta three 0", "2 s__Alpha two 1", "1 s__Alpha one 2"]);
+    let page = read(from_dir.join("index.html"));
+    assert!(page.contains("<title>Leakage evidence for 3 (s__Beta three)</title>"));
+    for (file, _header, _rows) in expected {
+        assert!(page.contains(&format!("<a href=\"{}\">", file)), "{}", file);
+    }
+
+    // Computed from the SAM with the options analyze ran with
+    let from_sam = dir.join("from_sam");
+    run(env!("CARGO_BIN_EXE_evidence"), &["--input", SAM, "--taxon", "3", "--map", LABELS, "-o", from_sam.to_str().unwrap(), "--html"]);
+    for (file, _header, _rows) in expected {
+        assert_eq!(read(from_sam.join(file)), read(from_dir.join(file)), "{}", file);
+    }
+    assert_eq!(read(from_sam.join("index.html")), page);
+
+    // A stricter cluster keeps the taxon only
+    let strict = dir.join("strict");
+    run(env!("CARGO_BIN_EXE_evidence"), &["--dir", results.to_str().unwrap(), "--taxon", "3", "-o", strict.to_str().unwrap(), "--cluster-min-reads", "100"]);
+    assert_eq!(table(&strict, "cluster.tsv").1, ["3\tNA\t0\t0"]);
+    assert!(!strict.join("index.html").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unknown_taxa_list_close_labels() {
+    let dir = scratch("evidence_unknown");
+    let result = output(env!("CARGO_BIN_EXE_evidence"), &["--input", SAM, "--taxon", "s__Beta thre", "--map", LABELS, "-o", &arg(&dir, "out")]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("closest labels: s__Beta three"), "{}", stderr);
+
+    let result = output(env!("CARGO_BIN_EXE_evidence"), &["--input", SAM, "--taxon", "9", "-o", &arg(&dir, "out")]);
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Taxon 9 (NA) has no reads in"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "tree")]
+#[test]
+fn tree_neighbors_are_the_sister_clade() {
+    let dir = scratch("evidence_tree");
+    let tree = arg(&dir, "tree.nwk");
+    fs::write(&tree, "(('s__Alpha one':1,'s__Alpha two':2):1,'s__Beta three':3);\n").unwrap();
+    let out = dir.join("out");
+    run(env!("CARGO_BIN_EXE_evidence"), &["--input", SAM, "--taxon", "3", "--map", LABELS, "--tree", &tree, "-o", out.to_str().unwrap()]);
+    assert_eq!(table(&out, "tree_neighbors.tsv"), ("taxon\tlabel\tdistance".to_string(), vec!["1\ts__Alpha one\t5".to_string(), "2\ts__Alpha two\t6".to_string()]));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,35 @@
+//! Reads aligned to a gene other than their query gene are counted on the reference gene and
+//! reported once, after counting.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, scratch};
+
+const SAM: &str = "\
+@SQ\tSN:1_1\tLN:500
+@SQ\tSN:1_2\tLN:500
+@SQ\tSN:2_1\tLN:500
+@SQ\tSN:2_2\tLN:500
+1_1_r1\t0\t2_2\t1\t30\t50M\t*\t0\t0\t*\t*
+1_1_r2\t0\t2_2\t1\t30\t50M\t*\t0\t0\t*\t*
+1_2_r3\t0\t1_1\t1\t30\t50M\t*\t0\t0\t*\t*
+2_1_r4\t0\t1_1\t1\t30\t50M\t*\t0\t0\t*\t*
+2_2_r5\t0\t2_2\t1\t30\t50M\t*\t0\t0\t*\t*
+";
+
+#[test]
+fn gene_mismatches_are_reported_once() {
+    let dir = scratch("gene_mismatch");
+    let sam = arg(&dir, "reads.sam");
+    fs::write(&sam, SAM).unwrap();
+    let result = output(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &sam]);
+    assert!(result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    let reports = stderr.lines().filter(|line| line.starts_with("Gene mismatch")).collect::<Vec<&str>>();
+    assert_eq!(reports, ["Gene mismatch: 3 reads aligned to a gene other than their query gene (2 distinct gene pairs), counted on the reference gene"], "{}", stderr);
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(stdout.lines().any(|line| line == "1\t2\t2\t-1\t2"), "{}", stdout);
+    fs::remove_dir_all(&dir).unwrap();
+}
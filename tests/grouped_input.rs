@@ -0,0 +1,70 @@
+//! Name-grouped modes stop with an error naming the read and line when a read reappears after
+//! its alignments ended, and read the same input with --assume-grouped. Reads with more than
+//! --max-group-records alignments keep the buffered ones and are counted as oversized_group.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, read, run, scratch, SAM};
+
+#[test]
+fn interleaved_reads_stop_name_grouped_modes() {
+    let dir = scratch("grouped_input");
+    // The first read of the fixture again after the last one
+    let sam = read(SAM);
+    let first = sam.lines().find(|line| !line.starts_with('@')).unwrap();
+    let interleaved = arg(&dir, "interleaved.sam");
+    fs::write(&interleaved, format!("{}{}\n", sam, first)).unwrap();
+    let line = sam.lines().count() + 1;
+    let name = first.split('\t').next().unwrap();
+
+    let modes = [
+        (env!("CARGO_BIN_EXE_pairwise_leakage"), &["--multimap-weighting", "fraction"][..]),
+        (env!("CARGO_BIN_EXE_pairwise_leakage"), &["--best-per-read"]),
+        (env!("CARGO_BIN_EXE_pairwise_leakage"), &["--reconcile"]),
+        (env!("CARGO_BIN_EXE_ambiguity_matrix"), &[]),
+    ];
+    for (binary, flags) in modes {
+        let result = output(binary, &[&["--input", interleaved.as_str()][..], flags].concat());
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert_eq!(result.status.code(), Some(1), "{:?}: {}", flags, stderr);
+        assert!(stderr.contains(&format!("ungrouped_input in {} at record {}: read {} reappears", interleaved, line, name)), "{:?}: {}", flags, stderr);
+        assert!(!stderr.contains("panicked"), "{:?}: {}", flags, stderr);
+        run(binary, &[&["--input", interleaved.as_str(), "--assume-grouped"][..], flags].concat());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A read of three alignments between a single one and a last read of two.
+fn multimapped_sam() -> String {
+    let header = read(SAM).lines().filter(|line| line.starts_with('@')).map(|line| format!("{}\n", line)).collect::<String>();
+    let records = [("1_1_r1", 0, "1_1"), ("1_1_r1", 256, "2_1"), ("1_1_r1", 256, "3_1"), ("2_2_r2", 0, "2_2"), ("3_3_r3", 0, "3_3"), ("3_3_r3", 256, "1_3")];
+    header + &records.map(|(name, flag, reference)| format!("{}\t{}\t{}\t1\t30\t50M\t*\t0\t0\t*\t*\n", name, flag, reference)).concat()
+}
+
+#[test]
+fn oversized_reads_keep_their_buffered_alignments() {
+    let dir = scratch("grouped_oversized");
+    let sam = arg(&dir, "multimapped.sam");
+    fs::write(&sam, multimapped_sam()).unwrap();
+    let fraction = |max: &str, strict: &[&str]| output(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&["--input", sam.as_str(), "--multimap-weighting", "fraction", "--max-group-records", max][..], strict].concat());
+    let rows = |stdout: &[u8]| String::from_utf8_lossy(stdout).lines().filter(|line| !line.starts_with('#')).map(str::to_string).collect::<Vec<String>>();
+
+    // At the buffer edge every alignment counts, the last read is flushed at the end of the input
+    let fits = fraction("3", &[]);
+    assert_eq!(rows(&fits.stdout), ["1\t1\t0.333333333333333\t0.333333333333333", "3\t1\t0.5\t-1\t-1\t0.5", "1\t2\t0.333333333333333\t0.333333333333333", "2\t2\t1\t-1\t1", "1\t3\t0.333333333333333\t0.333333333333333", "3\t3\t0.5\t-1\t-1\t0.5"]);
+    assert!(!String::from_utf8_lossy(&fits.stderr).contains("oversized_group"));
+
+    // One past it the read is split over the buffered alignments only
+    let oversized = fraction("2", &[]);
+    assert_eq!(oversized.status.code(), Some(0));
+    assert_eq!(rows(&oversized.stdout), ["1\t1\t0.5\t0.5", "3\t1\t0.5\t-1\t-1\t0.5", "1\t2\t0.5\t0.5", "2\t2\t1\t-1\t1", "3\t3\t0.5\t-1\t-1\t0.5"]);
+    assert!(String::from_utf8_lossy(&oversized.stderr).contains("oversized_group\t1\tread 1_1_r1: 1 alignments past --max-group-records 2"));
+
+    let strict = fraction("2", &["--strict"]);
+    assert_eq!(strict.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&strict.stderr).contains("oversized_group in"));
+    assert!(rows(&strict.stdout).is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
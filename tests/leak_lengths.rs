@@ -0,0 +1,79 @@
+//! --leak-length-report writes the median aligned length of the leaked reads of every pair, and
+//! --min-median-leak-len moves pairs of short leaks to its #low_credibility section.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, read, run, scratch};
+use fix_gtdb_mg::utils::SplitMix64;
+
+/// Leaked reads of taxon 1 on taxa 2 (short, 20 to 50 bp aligned) and 3 (100 to 200 bp), soft
+/// clipped to 250 bases, and reads of every gene on itself. Returns the SAM and the aligned
+/// lengths of the two pairs.
+fn leaks_sam() -> (String, [Vec<u32>; 2]) {
+    let mut random = SplitMix64::new(3);
+    let mut sam = (1..=3).flat_map(|taxon| (1..=2).map(move |gene| format!("@SQ\tSN:{}_{}\tLN:1000\n", taxon, gene))).collect::<String>();
+    let mut lengths = [Vec::new(), Vec::new()];
+    for read in 0..600 {
+        let (to, length) = match read % 3 {
+            0 => (2, 20 + random.below(31) as u32),
+            1 => (3, 100 + random.below(101) as u32),
+            _ => {
+                let (taxon, gene) = (read / 3 % 3 + 1, read / 9 % 2 + 1);
+                sam += &format!("{}_{}_s{}\t0\t{}_{}\t1\t30\t150M\t*\t0\t0\t*\t*\n", taxon, gene, read, taxon, gene);
+                continue
+            },
+        };
+        lengths[to - 2].push(length);
+        let gene = random.below(2) + 1;
+        sam += &format!("1_{}_r{}\t0\t{}_{}\t1\t30\t{}S{}M\t*\t0\t0\t*\t*\n", gene, read, to, gene, 250 - length, length);
+    }
+    (sam, lengths)
+}
+
+fn exact_median(lengths: &mut [u32]) -> f64 {
+    lengths.sort_unstable();
+    let middle = lengths.len() / 2;
+    match lengths.len() % 2 {
+        1 => lengths[middle] as f64,
+        _ => (lengths[middle - 1] + lengths[middle]) as f64 / 2.0,
+    }
+}
+
+#[test]
+fn leak_length_report_demotes_pairs_of_short_leaks() {
+    let dir = scratch("leak_lengths");
+    let (content, mut lengths) = leaks_sam();
+    let sam = arg(&dir, "leaks.sam");
+    fs::write(&sam, content).unwrap();
+    let report = arg(&dir, "lengths.tsv");
+    let table = run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &sam]);
+
+    // Every pair with leaked reads, the exact median within a 10 bp bin
+    let with_lengths = run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &sam, "--leak-length-report", &report]);
+    assert_eq!(with_lengths, table, "the pairwise table is unchanged");
+    let rows = read(&report);
+    let rows = rows.lines().collect::<Vec<&str>>();
+    assert_eq!(rows[0], "#from\tto\tleaked_reads\tmedian_leak_len");
+    assert_eq!(rows.len(), 3, "{:?}", rows);
+    for (row, (to, lengths)) in rows[1..].iter().zip([2, 3].into_iter().zip(lengths.iter_mut())) {
+        let fields = row.split('\t').collect::<Vec<&str>>();
+        assert_eq!(fields[..3], ["1", &to.to_string(), &lengths.len().to_string()], "{}", row);
+        let median = fields[3].parse::<f64>().unwrap();
+        assert!((median - exact_median(lengths)).abs() < 10.0, "{}: {:?}", row, lengths);
+    }
+
+    // The short leaks to taxon 2 are kept, in their own section
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &sam, "--leak-length-report", &report, "--min-median-leak-len", "60"]);
+    let demoted = read(&report);
+    let demoted = demoted.lines().collect::<Vec<&str>>();
+    assert_eq!(demoted, [rows[0], rows[2], "#low_credibility", rows[1]]);
+
+    // And the gene leak report of mask_genes gets a median_leak_len row per taxon
+    let gene_leaks = run(env!("CARGO_BIN_EXE_mask_genes"), &["--input", &sam, "--min-median-leak-len", "60"]);
+    let medians = gene_leaks.lines().filter(|line| line.split('\t').nth(3) == Some("median_leak_len")).collect::<Vec<&str>>();
+    assert_eq!(medians.len(), 3, "{}", gene_leaks);
+    assert!(medians.iter().any(|row| row.split('\t').skip(4).all(|median| median == "NA" || median.is_empty())), "taxon 1 has no leaks on it: {:?}", medians);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,82 @@
+//! leak_tracks writes the depth of leaked reads along every reference sequence as a bedGraph and
+//! the masked genes as whole-sequence BED intervals, both within the @SQ lengths.
+
+mod common;
+
+use std::{collections::BTreeMap, fs};
+
+use common::{arg, output, read, run, scratch};
+use fix_gtdb_mg::utils::SplitMix64;
+
+/// @SQ lengths of the synthetic reference, 3 taxa with 3 genes each.
+fn lengths() -> BTreeMap<String, usize> {
+    (1..=3).flat_map(|taxon| (1..=3).map(move |gene| (format!("{}_{}", taxon, gene), 200 + 100 * gene))).collect()
+}
+
+/// Reads at random positions with deletions, insertions and clips, some overhanging the end of
+/// their sequence. Returns the SAM and the per-base depth of the leaked reads.
+fn leaks_sam() -> (String, BTreeMap<String, Vec<u32>>) {
+    let mut random = SplitMix64::new(29);
+    let lengths = lengths();
+    let mut depth = lengths.iter().map(|(name, length)| (name.clone(), vec![0; *length])).collect::<BTreeMap<String, Vec<u32>>>();
+    let mut sam = lengths.iter().map(|(name, length)| format!("@SQ\tSN:{}\tLN:{}\n", name, length)).collect::<String>();
+    for read in 0..300 {
+        let (query, reference) = (random.below(3) + 1, random.below(3) + 1);
+        let name = format!("{}_{}", reference, random.below(3) + 1);
+        let pos = random.below(lengths[&name] as u64) as usize + 1;
+        let (matched, deleted, inserted, clipped) = (20 + random.below(60) as usize, random.below(10) as usize, random.below(5) as usize, random.below(8) as usize);
+        let cigar = format!("{}S{}M{}D{}I{}M", clipped, matched, deleted, inserted, 10);
+        sam += &format!("{}_1_r{}\t0\t{}\t{}\t30\t{}\t*\t0\t0\t*\t*\n", query, read, name, pos, cigar);
+        if query != reference {
+            let sequence = depth.get_mut(&name).unwrap();
+            let end = (pos - 1 + matched + deleted + 10).min(sequence.len());
+            sequence[pos - 1..end].iter_mut().for_each(|base| *base += 1);
+        }
+    }
+    (sam, depth)
+}
+
+#[test]
+fn bedgraph_depth_matches_the_leaked_reads() {
+    let dir = scratch("leak_tracks");
+    let (content, expected) = leaks_sam();
+    let (sam, bedgraph) = (arg(&dir, "leaks.sam"), arg(&dir, "leaks.bedgraph"));
+    fs::write(&sam, content).unwrap();
+    run(env!("CARGO_BIN_EXE_leak_tracks"), &["--input", &sam, "--bedgraph", &bedgraph, "--min_mapq", "0"]);
+
+    // Sorted by sequence then start, non-overlapping, with depth, within the @SQ lengths
+    let tracks = read(&bedgraph);
+    let rows = tracks.lines().map(|line| {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        (fields[0].to_string(), fields[1].parse::<usize>().unwrap(), fields[2].parse::<usize>().unwrap(), fields[3].parse::<u32>().unwrap())
+    }).collect::<Vec<(String, usize, usize, u32)>>();
+    assert!(rows.windows(2).all(|pair| (&pair[0].0, pair[0].2) <= (&pair[1].0, pair[1].1)), "{}", tracks);
+    let lengths = lengths();
+    let mut depth = lengths.iter().map(|(name, length)| (name.clone(), vec![0; *length])).collect::<BTreeMap<String, Vec<u32>>>();
+    for (name, start, end, value) in &rows {
+        assert!(start < end && *end <= lengths[name] && *value > 0, "{}\t{}\t{}\t{}", name, start, end, value);
+        depth.get_mut(name).unwrap()[*start..*end].iter_mut().for_each(|base| *base = *value);
+    }
+    assert_eq!(depth, expected);
+    assert!(expected.values().flatten().any(|base| *base > 1));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn masked_genes_are_whole_sequence_intervals() {
+    let dir = scratch("leak_tracks_mask");
+    let (content, _depth) = leaks_sam();
+    let (sam, bedgraph, mask, bed) = (arg(&dir, "leaks.sam"), arg(&dir, "leaks.bedgraph"), arg(&dir, "mask.tsv"), arg(&dir, "mask.bed"));
+    fs::write(&sam, content).unwrap();
+    // Gene 1 of taxon 5 has no @SQ line
+    fs::write(&mask, "2\t2\n1\t3,1\n5\t1\n").unwrap();
+    let result = output(env!("CARGO_BIN_EXE_leak_tracks"), &["--input", &sam, "--bedgraph", &bedgraph, "--mask", &mask, "--mask-bed", &bed]);
+    assert!(result.status.success());
+    assert_eq!(read(&bed), "1_1\t0\t300\tmasked\n1_3\t0\t500\tmasked\n2_2\t0\t400\tmasked\n");
+    assert!(String::from_utf8_lossy(&result.stderr).contains("1 of 4 masked genes have no @SQ line"));
+
+    let result = output(env!("CARGO_BIN_EXE_leak_tracks"), &["--input", &sam, "--bedgraph", &bedgraph, "--mask-bed", &arg(&dir, "unmasked.bed")]);
+    assert!(result.status.success() && String::from_utf8_lossy(&result.stderr).contains("--mask-bed needs --mask"));
+    assert!(!dir.join("unmasked.bed").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
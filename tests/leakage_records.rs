@@ -0,0 +1,29 @@
+//! The per-read leakage file written by pairwise_leakage --leakage-records reads back into the
+//! per-taxon counters computed directly from the SAM.
+
+mod common;
+
+use std::fs;
+
+use clap::Parser;
+use common::{arg, read, run, scratch, SAM};
+use fix_gtdb_mg::{common::{AnomalyLog, Args}, filter::RecordFilter, leakage::{diff_leakage_counters, read_leakage_counter}, pairwise_leakage::Leakage};
+
+#[test]
+fn leakage_records_read_back_into_the_counters_of_the_sam() {
+    let dir = scratch("leakage_records");
+    for (index, options) in [&[][..], &["--min_mapq", "20"], &["--primary-only"]].into_iter().enumerate() {
+        let path = arg(&dir, &format!("records_{}.tsv", index));
+        run(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&["--input", SAM, "--leakage-records", "--output", &path][..], options].concat());
+        let records = read(&path);
+        assert!(records.lines().all(|line| line.split('\t').count() == 5), "{}", records);
+
+        let args = Args::try_parse_from([&["pairwise_leakage", "--input", SAM][..], options].concat()).unwrap();
+        let direct = Leakage::from_sam(&args, &mut AnomalyLog::from_args(&args)).unwrap().taxon_summary();
+        let round_trip = read_leakage_counter(&path, &mut RecordFilter::from_args(&args)).unwrap();
+        assert!(direct.values().any(|counter| counter.out_incorrect > 0 && counter.correct > 0));
+        assert_eq!(diff_leakage_counters(&direct, &round_trip), vec![], "{:?}", options);
+        assert_eq!(records.lines().count() as u64, direct.values().map(|counter| counter.total).sum::<u64>(), "{:?}", options);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
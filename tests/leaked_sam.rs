@@ -0,0 +1,53 @@
+//! --write-leaked-sam writes the header of the input verbatim and exactly the records counted on
+//! pairs of two different taxa, plain or gzipped.
+
+mod common;
+
+use std::{fs, io::Read};
+
+use common::{arg, output, read, scratch, SAM};
+use flate2::read::MultiGzDecoder;
+
+/// Sum of the totals of the pairwise table on pairs of two different taxa.
+fn leaked_total(table: &str) -> u64 {
+    table.lines().filter(|line| !line.starts_with('#')).map(|line| {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        if fields[0] == fields[1] { 0 } else { fields[2].parse::<u64>().unwrap() }
+    }).sum()
+}
+
+fn content(path: &str) -> String {
+    if !path.ends_with(".gz") {
+        return read(path)
+    }
+    let mut content = String::new();
+    MultiGzDecoder::new(fs::File::open(path).unwrap()).read_to_string(&mut content).unwrap();
+    content
+}
+
+#[test]
+fn leaked_records_are_the_off_diagonal_totals() {
+    let dir = scratch("leaked_sam");
+    let fixture = read(SAM);
+    let (header, records): (Vec<&str>, Vec<&str>) = fixture.lines().partition(|line| line.starts_with('@'));
+    for (file, options) in [("leaked.sam", &[][..]), ("leaked.sam.gz", &[][..]), ("mapq.sam.gz", &["--min_mapq", "40"][..])] {
+        let path = arg(&dir, file);
+        let result = output(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&["--input", SAM, "--write-leaked-sam", &path][..], options].concat());
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert!(result.status.success(), "{}", stderr);
+        let leaked = leaked_total(&String::from_utf8(result.stdout).unwrap());
+        assert!(leaked > 0, "{}", file);
+        assert!(stderr.contains(&format!("{}\t{} leaked records", path, leaked)), "{}: {}", file, stderr);
+
+        let written = content(&path);
+        assert_eq!(written.lines().take(header.len()).collect::<Vec<&str>>(), header, "{}", file);
+        let written_records = written.lines().skip(header.len()).collect::<Vec<&str>>();
+        assert_eq!(written_records.len() as u64, leaked, "{}", file);
+        for record in written_records {
+            assert!(records.contains(&record), "{}: {}", file, record);
+            let fields = record.split('\t').collect::<Vec<&str>>();
+            assert_ne!(fields[0].split('_').next(), fields[2].split('_').next(), "{}: {}", file, record);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
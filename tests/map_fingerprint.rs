@@ -0,0 +1,62 @@
+//! Tables made with --map carry the fingerprint of the map, tables of different maps are not mixed.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, read, run, scratch, LABELS, SAM};
+
+const FINGERPRINT: &str = "#map_fingerprint\t";
+
+fn fingerprint(table: &str) -> Option<String> {
+    table.lines().take_while(|line| line.starts_with('#')).find_map(|line| line.strip_prefix(FINGERPRINT)).map(String::from)
+}
+
+#[test]
+fn tables_made_with_a_map_carry_its_fingerprint() {
+    let dir = scratch("fingerprint_tables");
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--map", LABELS, "--output", &arg(&dir, "pairwise.tsv")]);
+    let expected = fingerprint(&read(dir.join("pairwise.tsv"))).expect("pairwise table has a fingerprint");
+
+    run(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &arg(&dir, "pairwise.tsv"), "--map", LABELS, "--output", &arg(&dir, "normalized.tsv")]);
+    assert_eq!(fingerprint(&read(dir.join("normalized.tsv"))).as_ref(), Some(&expected));
+    assert_eq!(fingerprint(&run(env!("CARGO_BIN_EXE_mask_genes"), &["--input", SAM, "--map", LABELS])).as_ref(), Some(&expected));
+
+    run(env!("CARGO_BIN_EXE_analyze"), &["--input", SAM, "--map", LABELS, "-o", &arg(&dir, "analysis")]);
+    for file in ["tables/pairwise.tsv", "tables/normalized.tsv", "genes/gene_leaks.tsv", "mask/mask.tsv", "mask/mask.v1.tsv", "tables/taxon_summary.tsv"] {
+        assert_eq!(fingerprint(&read(dir.join("analysis").join(file))).as_ref(), Some(&expected), "{}", file);
+    }
+    run(env!("CARGO_BIN_EXE_doctor"), &["--dir", &arg(&dir, "analysis")]);
+
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--output", &arg(&dir, "bare.tsv")]);
+    assert_eq!(fingerprint(&read(dir.join("bare.tsv"))), None);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tables_of_different_maps_are_not_mixed() {
+    let dir = scratch("fingerprint_mixed");
+    let other = arg(&dir, "other_labels.tsv");
+    fs::write(&other, read(LABELS).replace("Alpha one", "Alpha uno")).unwrap();
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--map", LABELS, "--output", &arg(&dir, "first.tsv")]);
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--map", LABELS, "--output", &arg(&dir, "second.tsv.gz")]);
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--map", &other, "--output", &arg(&dir, "other.tsv.gz")]);
+
+    let merged = run(env!("CARGO_BIN_EXE_merge_pairwise"), &[&arg(&dir, "first.tsv"), &arg(&dir, "second.tsv.gz")]);
+    assert_eq!(fingerprint(&merged), fingerprint(&read(dir.join("first.tsv"))));
+    run(env!("CARGO_BIN_EXE_compare_pairwise"), &[&arg(&dir, "first.tsv"), &arg(&dir, "second.tsv.gz")]);
+
+    for (binary, args) in [
+        (env!("CARGO_BIN_EXE_merge_pairwise"), vec![arg(&dir, "first.tsv"), arg(&dir, "other.tsv.gz")]),
+        (env!("CARGO_BIN_EXE_compare_pairwise"), vec![arg(&dir, "first.tsv"), arg(&dir, "other.tsv.gz")]),
+        (env!("CARGO_BIN_EXE_normalize_pairwise"), vec!["--input".to_string(), arg(&dir, "first.tsv"), "--map".to_string(), other.clone()]),
+    ] {
+        let refused = output(binary, &args.iter().map(String::as_str).collect::<Vec<&str>>());
+        assert!(!refused.status.success(), "{} {:?} mixed label maps", binary, args);
+        assert!(String::from_utf8_lossy(&refused.stderr).contains("does not match"), "{}", String::from_utf8_lossy(&refused.stderr));
+    }
+    let forced = output(env!("CARGO_BIN_EXE_merge_pairwise"), &[&arg(&dir, "first.tsv"), &arg(&dir, "other.tsv.gz"), "--ignore-map-fingerprint"]);
+    assert!(forced.status.success());
+    assert_eq!(fingerprint(&String::from_utf8(forced.stdout).unwrap()), None);
+    fs::remove_dir_all(&dir).unwrap();
+}
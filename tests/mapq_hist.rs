@@ -0,0 +1,54 @@
+//! pairwise_leakage --mapq-hist counts the records placed on their own taxon and gene and the
+//! others per MAPQ, with every record filter but --min_mapq.
+
+mod common;
+
+use std::{collections::BTreeMap, fs};
+
+use common::{arg, read, run, scratch, SAM};
+
+/// (correct, incorrect) by MAPQ of the mapped records of a SAM, read names `taxid_gene_read`.
+fn expected(sam: &str) -> BTreeMap<u32, (u64, u64)> {
+    let mut counts = (0..=60).map(|mapq| (mapq, (0, 0))).collect::<BTreeMap<u32, (u64, u64)>>();
+    for line in sam.lines().filter(|line| !line.starts_with('@')) {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        if fields[1].parse::<u16>().unwrap() & 4 != 0 { continue };
+        let query = fields[0].rsplit_once('_').unwrap().0;
+        let entry = counts.entry(fields[4].parse().unwrap()).or_default();
+        match query == fields[2] {
+            true => entry.0 += 1,
+            false => entry.1 += 1,
+        }
+    }
+    counts
+}
+
+fn histogram(table: &str) -> BTreeMap<u32, (u64, u64)> {
+    let mut lines = table.lines();
+    assert_eq!(lines.next(), Some("mapq\tcorrect\tincorrect"));
+    lines.map(|line| {
+        let fields = line.split('\t').map(|field| field.parse::<u64>().unwrap()).collect::<Vec<u64>>();
+        (fields[0] as u32, (fields[1], fields[2]))
+    }).collect()
+}
+
+#[test]
+fn mapq_histogram_counts_every_kept_record() {
+    let dir = scratch("mapq_hist");
+    let fixture = read(SAM);
+    let hist = |input: &str, options: &[&str]| histogram(&run(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&["--input", input, "--mapq-hist"][..], options].concat()));
+    let counts = hist(SAM, &[]);
+    assert_eq!(counts, expected(&fixture));
+    assert!(counts[&5].0 > 0 && counts[&30].1 > 0 && counts[&42].0 > 0);
+    // --min_mapq does not apply, the histogram is there to choose it
+    assert_eq!(hist(SAM, &["--min_mapq", "40"]), counts);
+
+    // MAPQ 255 (unavailable) gets a row past 60, unmapped records none
+    let extra = "1_1_r90\t0\t1_1\t1\t255\t50M\t*\t0\t0\t*\t*\n1_1_r91\t0\t2_1\t1\t255\t50M\t*\t0\t0\t*\t*\n1_1_r92\t0\t1_2\t1\t255\t50M\t*\t0\t0\t*\t*\n1_1_r93\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+    let sam = arg(&dir, "mapq_255.sam");
+    fs::write(&sam, fixture.clone() + extra).unwrap();
+    let counts = hist(&sam, &[]);
+    assert_eq!(counts, expected(&(fixture + extra)));
+    assert_eq!((counts.len(), counts[&255]), (62, (1, 2)));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,56 @@
+//! mask_genes --marker-summary writes the columns of GTDB-Tk's markers_summary.tsv, then the
+//! leaked and masked markers, a row per taxon named by its label.
+
+mod common;
+
+use std::{collections::HashMap, fs};
+
+use common::{arg, read, run, scratch, LABELS, SAM};
+
+/// Header of GTDB-Tk's markers_summary.tsv.
+const GTDBTK_COLUMNS: [&str; 9] = ["name", "number_unique_genes", "number_multiple_genes", "number_multiple_unique_genes", "number_missing_genes", "list_unique_genes", "list_multiple_genes", "list_multiple_unique_genes", "list_missing_genes"];
+
+/// Names of the genes of a taxon in a state, by the mask_state rows of the gene leak report.
+fn genes_in_state(report: &str, state: &str, name: impl Fn(usize) -> String) -> HashMap<String, Vec<String>> {
+    report.lines().filter(|line| line.split('\t').nth(3) == Some("mask_state")).map(|line| {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        let mut genes = fields[4..].iter().enumerate().filter(|(_gene, gene_state)| **gene_state == state).map(|(gene, _state)| name(gene + 1)).collect::<Vec<String>>();
+        genes.sort();
+        (fields[0].to_string(), genes)
+    }).collect()
+}
+
+#[test]
+fn marker_summary_lists_markers_as_gtdbtk_does() {
+    let dir = scratch("marker_summary");
+    let names = arg(&dir, "names.tsv");
+    fs::write(&names, "# gene\tname\n1\tPF00380.20\n2\tPF00410.14\n4\tTIGR00001\n").unwrap();
+    let summary = arg(&dir, "summary.tsv");
+    let report = run(env!("CARGO_BIN_EXE_mask_genes"), &["--input", SAM, "--map", LABELS, "--marker-names", &names, "--marker-summary", &summary]);
+    let content = read(&summary);
+    let mut lines = content.lines().filter(|line| !line.starts_with('#'));
+    let header = lines.next().unwrap().split('\t').collect::<Vec<&str>>();
+    assert_eq!(header[..9], GTDBTK_COLUMNS);
+    assert_eq!(header[9..], ["number_leaked_genes", "number_masked_genes", "list_leaked_genes", "list_masked_genes"]);
+
+    // Gene 3 is not named and keeps its id, gene 4 has no reads and is missing
+    let name = |gene: usize| ["", "PF00380.20", "PF00410.14", "3"][gene].to_string();
+    let masked = genes_in_state(&report, "newly_masked", name);
+    let rows = lines.map(|line| line.split('\t').collect::<Vec<&str>>()).collect::<Vec<Vec<&str>>>();
+    assert_eq!(rows.iter().map(|row| row[0]).collect::<Vec<&str>>(), ["s__Alpha one", "s__Alpha two", "s__Beta three"]);
+    for (row, taxon) in rows.iter().zip(["1", "2", "3"]) {
+        assert_eq!(row.len(), header.len());
+        assert_eq!(row[1..9], ["3", "0", "0", "1", "3,PF00380.20,PF00410.14", "", "", "TIGR00001"], "{:?}", row);
+        let list = |column: usize| row[column].split(',').filter(|gene| !gene.is_empty()).map(str::to_string).collect::<Vec<String>>();
+        assert_eq!(list(12), masked[taxon], "{:?}", row);
+        assert!(list(12).iter().all(|gene| list(11).contains(gene)), "{:?}", row);
+        assert_eq!((row[9], row[10]), (list(11).len().to_string().as_str(), list(12).len().to_string().as_str()));
+    }
+    assert!(rows.iter().any(|row| row[10] != row[1]), "a taxon keeps a marker unmasked: {:?}", rows);
+
+    // Without labels taxa are named by their id
+    run(env!("CARGO_BIN_EXE_mask_genes"), &["--input", SAM, "--marker-names", &names, "--marker-summary", &summary]);
+    let ids = read(&summary).lines().skip(1).map(|line| line.split('\t').next().unwrap().to_string()).collect::<Vec<String>>();
+    assert_eq!(ids, ["1", "2", "3"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
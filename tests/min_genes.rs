@@ -0,0 +1,71 @@
+//! --min-genes-remaining caps masking so every species keeps that many genes, --min-genes-initial
+//! quarantines species that have fewer genes to begin with. At the same value they differ.
+
+mod common;
+
+use std::{collections::HashMap, fs};
+
+use common::{arg, output, read, run, scratch, SAM};
+
+/// The fixture plus taxon 4 with reads on a single gene, some leaked to and from taxon 1.
+fn with_single_gene_taxon() -> String {
+    let fixture = read(SAM);
+    let (header, records): (Vec<&str>, Vec<&str>) = fixture.lines().partition(|line| line.starts_with('@'));
+    let taxon_4 = [
+        "4_1_r1\t0\t4_1\t1\t42\t50M\t*\t0\t0\t*\t*",
+        "4_1_r2\t0\t4_1\t51\t42\t50M\t*\t0\t0\t*\t*",
+        "4_1_r3\t0\t4_1\t101\t42\t50M\t*\t0\t0\t*\t*",
+        "4_1_r4\t0\t1_1\t1\t30\t50M\t*\t0\t0\t*\t*",
+        "1_1_r90\t0\t4_1\t151\t30\t50M\t*\t0\t0\t*\t*",
+        "1_1_r91\t0\t4_1\t1\t30\t50M\t*\t0\t0\t*\t*",
+    ];
+    [&header[..], &["@SQ\tSN:4_1\tLN:200"], &records[..], &taxon_4[..]].concat().join("\n") + "\n"
+}
+
+/// Mask state of every gene by taxon.
+fn mask_states(report: &str) -> HashMap<String, Vec<String>> {
+    report.lines().filter(|line| line.split('\t').nth(3) == Some("mask_state")).map(|line| {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        (fields[0].to_string(), fields[4..].iter().map(|state| state.to_string()).collect())
+    }).collect()
+}
+
+/// Genes of a taxon, of the same state.
+fn count(states: &[String], state: &str) -> usize {
+    states.iter().filter(|gene| *gene == state).count()
+}
+
+#[test]
+fn remaining_and_initial_minimums_diverge() {
+    let dir = scratch("min_genes");
+    let sam = arg(&dir, "leaks.sam");
+    fs::write(&sam, with_single_gene_taxon()).unwrap();
+    let mask = |options: &[&str]| mask_states(&run(env!("CARGO_BIN_EXE_mask_genes"), &[&["--input", &sam][..], options].concat()));
+    let default = mask(&[]);
+    assert!(count(&default["1"], "newly_masked") == 3 && count(&default["4"], "newly_masked") == 1, "{:?}", default);
+
+    // Every species keeps 2 genes unmasked, or all of its genes when it has fewer
+    let remaining = mask(&["--min-genes-remaining", "2"]);
+    for (taxon, states) in &remaining {
+        let genes = states.iter().filter(|state| *state != "None").count();
+        assert!(genes - count(states, "newly_masked") >= genes.min(2), "{}: {:?}", taxon, states);
+        assert_eq!(count(states, "quarantined"), 0, "{}: {:?}", taxon, states);
+    }
+    assert_eq!(count(&remaining["4"], "capped_by_min_genes"), 1);
+    assert_eq!(count(&remaining["1"], "newly_masked"), 1);
+
+    // Only the species with a single gene is quarantined, the others are masked as by default
+    let initial = mask(&["--min-genes-initial", "2"]);
+    assert_eq!(initial["4"][0], "quarantined");
+    for taxon in ["1", "2", "3"] {
+        assert_eq!(initial[taxon], default[taxon], "{}", taxon);
+    }
+    assert!(initial.values().all(|states| count(states, "capped_by_min_genes") == 0), "{:?}", initial);
+    assert_ne!(initial, remaining);
+
+    let result = output(env!("CARGO_BIN_EXE_mask_genes"), &["--input", &sam, "--min_genes", "2"]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(!result.status.success());
+    assert!(stderr.contains("--min-genes-remaining") && stderr.contains("--min-genes-initial"), "{}", stderr);
+    fs::remove_dir_all(&dir).unwrap();
+}
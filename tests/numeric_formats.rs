@@ -0,0 +1,78 @@
+//! Numeric columns are written in fixed notation and read strictly: scientific notation, leading
+//! plus signs and thousands separators are refused with an error naming the column and line.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, read, run, scratch, SAM};
+
+const HEADER: &str = "#pairs\tdirected\tgene_base=1\n";
+const FRACTION_HEADER: &str = "#pairs\tdirected\tgene_base=1\tweights=fraction\n";
+
+/// Runs normalize_pairwise and merge_pairwise on a table, returns their exit codes and stderr.
+fn read_table(dir: &std::path::Path, content: &str) -> Vec<(Option<i32>, String)> {
+    let table = arg(dir, "table.tsv");
+    let normalized = arg(dir, "normalized.tsv");
+    fs::write(&table, content).unwrap();
+    let runs = [
+        (env!("CARGO_BIN_EXE_normalize_pairwise"), vec!["--input", table.as_str(), "--output", &normalized]),
+        (env!("CARGO_BIN_EXE_merge_pairwise"), vec![table.as_str(), table.as_str()]),
+    ];
+    runs.into_iter().map(|(binary, args)| {
+        let result = output(binary, &args);
+        (result.status.code(), String::from_utf8_lossy(&result.stderr).into_owned())
+    }).collect()
+}
+
+#[test]
+fn malformed_numbers_are_refused_naming_column_and_line() {
+    let dir = scratch("numeric_refused");
+    let cases = [
+        (format!("{}1\t2\t3\t2\t1\n1\t3\t1e3\t1\n", HEADER), "Line 3, column total: expected an integer count, found '1e3'"),
+        (format!("{}1\t2\t+3\t2\t1\n", HEADER), "Line 2, column total: expected an integer count, found '+3'"),
+        (format!("{}1\t2\t1,003\t1,002\t1\n", HEADER), "Line 2, column total: expected an integer count, found '1,003'"),
+        (format!("{}1\t2\t3\t2\t0\n", HEADER), "Line 2, column gene_2: expected a positive integer count or -1, found '0'"),
+        (format!("{}1\t2\n", HEADER), "Line 2: expected at least 3 columns, found 2"),
+        (format!("{}1\t2\t1.5\t5e-1\t1\n", FRACTION_HEADER), "Line 2, column gene_1: expected a fixed-point number, found '5e-1'"),
+        (format!("{}1\t2\t+1.5\t0.5\t1\n", FRACTION_HEADER), "Line 2, column total: expected a fixed-point number, found '+1.5'"),
+        (format!("{}1\t2\t1,500.5\t1,500\t0.5\n", FRACTION_HEADER), "Line 2, column total: expected a fixed-point number, found '1,500.5'"),
+    ];
+    for (content, message) in cases {
+        for (code, stderr) in read_table(&dir, &content) {
+            assert_eq!(code, Some(1), "{:?}: {}", content, stderr);
+            assert!(stderr.contains(message), "{:?}: {}", content, stderr);
+            assert!(!stderr.contains("panicked"), "{:?}: {}", content, stderr);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn plain_numbers_are_accepted() {
+    let dir = scratch("numeric_accepted");
+    for content in [format!("{}1\t2\t3\t2\t1\n2\t1\t4\t-1\t4\n", HEADER), format!("{}1\t2\t1.5\t0.5\t1\n2\t1\t0.25\t-1\t0.25\n", FRACTION_HEADER)] {
+        for (code, stderr) in read_table(&dir, &content) {
+            assert_eq!(code, Some(0), "{:?}: {}", content, stderr);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn written_tables_have_no_exponents() {
+    let dir = scratch("numeric_written");
+    let pairwise = arg(&dir, "pairwise.tsv");
+    let normalized = arg(&dir, "normalized.tsv");
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--multimap-weighting", "fraction", "--output", &pairwise]);
+    run(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &pairwise, "--output", &normalized, "--with-denominators"]);
+    for path in [&pairwise, &normalized] {
+        let content = read(path);
+        let tokens = content.lines().filter(|line| !line.starts_with('#')).flat_map(|line| line.split('\t')).collect::<Vec<&str>>();
+        assert!(!tokens.is_empty());
+        for token in tokens {
+            assert!(token == "NA" || token.bytes().all(|b| b.is_ascii_digit() || b == b'.' || b == b'-'), "{}: '{}'", path, token);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,62 @@
+//! --panel sets the gene columns of the gene leak report, the gene id bounds and the markers of
+//! the marker summary, for embedded panels and panel files alike.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, run, scratch, SAM};
+
+/// Gene columns of the report header.
+fn gene_columns(report: &str) -> Vec<String> {
+    let header = report.lines().find(|line| line.starts_with("#taxid\t")).unwrap_or_else(|| panic!("No header in {}", report));
+    header.split('\t').skip(4).map(str::to_string).collect()
+}
+
+fn columns(genes: usize) -> Vec<String> {
+    (1..=genes).map(|gene| format!("gene_{}", gene)).collect()
+}
+
+#[test]
+fn panels_set_report_columns_bounds_and_markers() {
+    let dir = scratch("panel");
+    let mask = |options: &[&str]| run(env!("CARGO_BIN_EXE_mask_genes"), &[&["--input", SAM][..], options].concat());
+    let unpaneled = mask(&[]);
+    assert_eq!(gene_columns(&unpaneled), columns(3));
+
+    // Columns up to the last gene of the panel, rows as without it
+    let rows = |report: &str| report.lines().filter(|line| !line.starts_with('#')).map(|line| line.split('\t').take(7).collect::<Vec<&str>>().join("\t")).collect::<Vec<String>>();
+    let bac120 = mask(&["--panel", "bac120"]);
+    assert_eq!(gene_columns(&bac120), columns(120));
+    assert_eq!(rows(&bac120), rows(&unpaneled));
+    let panel = arg(&dir, "five.tsv");
+    fs::write(&panel, "gene_id\tname\texpected_length\tgroup\n1\tPF00380.20\t450\tribosomal\n2\tPF00410.14\t400\tribosomal\n3\tTIGR00001\tNA\t\n4\tTIGR00002\tNA\t\n5\tTIGR00003\tNA\t\n").unwrap();
+    assert_eq!(gene_columns(&mask(&["--panel", &panel])), columns(5));
+
+    // The panel names the markers, its genes without reads are missing
+    let summary = arg(&dir, "summary.tsv");
+    mask(&["--panel", &panel, "--marker-summary", &summary]);
+    let summary = common::read(&summary);
+    let rows = summary.lines().skip(1).map(|line| line.split('\t').collect::<Vec<&str>>()).collect::<Vec<Vec<&str>>>();
+    assert_eq!(rows.iter().map(|row| row[0]).collect::<Vec<&str>>(), ["1", "2", "3"]);
+    for row in &rows {
+        assert_eq!((row[1], row[4], row[5], row[8]), ("3", "2", "PF00380.20,PF00410.14,TIGR00001", "TIGR00002,TIGR00003"), "{:?}", row);
+    }
+    let result = output(env!("CARGO_BIN_EXE_mask_genes"), &["--input", SAM, "--marker-summary", &arg(&dir, "unnamed.tsv")]);
+    assert!(!result.status.success());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("--marker-summary needs --panel or --marker-names"));
+
+    // Gene 3 is beyond a two gene panel, fatal with --strict
+    let two = arg(&dir, "two.tsv");
+    fs::write(&two, "1\tPF00380.20\t450\tribosomal\n2\tPF00410.14\t400\tribosomal\n").unwrap();
+    let result = output(env!("CARGO_BIN_EXE_mask_genes"), &["--input", SAM, "--panel", &two, "--strict"]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(!result.status.success());
+    assert!(stderr.contains("gene id 3 exceeds the gene panel (last gene 2)"), "{}", stderr);
+
+    let result = output(env!("CARGO_BIN_EXE_mask_genes"), &["--input", SAM, "--panel", &arg(&dir, "absent.tsv")]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(!result.status.success());
+    assert!(stderr.contains("Cannot read panel") && stderr.contains("embedded panels: bac120, ar53"), "{}", stderr);
+    fs::remove_dir_all(&dir).unwrap();
+}
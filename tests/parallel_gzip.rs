@@ -0,0 +1,65 @@
+//! Gzip outputs written with --threads decompress to exactly the bytes of a single-threaded run.
+
+mod common;
+
+use std::{fs, io::{Read, Write}, path::Path};
+
+use common::{arg, run, scratch, SAM};
+use fix_gtdb_mg::utils::ParallelGzWriter;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+
+fn gunzip(compressed: &[u8]) -> Vec<u8> {
+    let mut content = Vec::new();
+    MultiGzDecoder::new(compressed).read_to_end(&mut content).unwrap();
+    content
+}
+
+fn gzip_members(compressed: &[u8]) -> usize {
+    // Every member starts with the magic bytes and the deflate method
+    compressed.windows(3).filter(|window| *window == [0x1f, 0x8b, 0x08]).count()
+}
+
+/// Lines of varying length, enough for many chunks of a few kilobytes.
+fn content() -> Vec<u8> {
+    (0..20_000).map(|i| format!("{}\t{}\t{}\n", i, i * 7 % 113, "x".repeat(i % 17))).collect::<String>().into_bytes()
+}
+
+#[test]
+fn parallel_members_decompress_to_the_serial_bytes() {
+    let content = content();
+    let mut serial = GzEncoder::new(Vec::new(), Compression::new(6));
+    serial.write_all(&content).unwrap();
+    let serial = serial.finish().unwrap();
+
+    for (threads, chunk_size) in [(1, 4096), (4, 4096), (4, 997), (3, content.len()), (8, content.len() * 2)] {
+        let mut compressed = Vec::new();
+        let mut parallel = ParallelGzWriter::with_chunk_size(&mut compressed, threads, Compression::new(6), chunk_size);
+        // Odd write sizes so writes straddle chunk boundaries
+        for piece in content.chunks(1000 + threads) {
+            parallel.write_all(piece).unwrap();
+        }
+        parallel.try_finish().unwrap();
+        drop(parallel);
+        assert_eq!(gunzip(&compressed), gunzip(&serial), "{} threads, chunks of {}", threads, chunk_size);
+        assert_eq!(gunzip(&compressed), content);
+        if chunk_size < content.len() {
+            assert!(gzip_members(&compressed) > 1, "{} threads, chunks of {}: a single member", threads, chunk_size);
+        }
+    }
+}
+
+#[test]
+fn threaded_gz_outputs_match_single_threaded_ones() {
+    let dir = scratch("parallel_gzip");
+    let table = |name: &str, threads: &str| {
+        let path = arg(&dir, name);
+        run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--threads", threads, "--compression-level", "9", "--output", &path]);
+        fs::read(Path::new(&path)).unwrap()
+    };
+    let plain = table("plain.tsv", "1");
+    let serial = table("serial.tsv.gz", "1");
+    let parallel = table("parallel.tsv.gz", "4");
+    assert_eq!(gunzip(&serial), plain);
+    assert_eq!(gunzip(&parallel), plain);
+    fs::remove_dir_all(&dir).unwrap();
+}
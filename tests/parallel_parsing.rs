@@ -0,0 +1,160 @@
+//! Pairwise tables counted on several parsing threads are byte for byte those of a serial run,
+//! from plain, gzipped and several inputs read at the same time, with their anomaly reports and
+//! record counts.
+
+mod common;
+
+use std::{fs, io::Write, path::Path};
+
+use common::{arg, output, read, scratch};
+use fix_gtdb_mg::utils::SplitMix64;
+use flate2::{write::GzEncoder, Compression};
+
+/// Header and records of `reads` reads over 30 taxa of 4 genes, a fifth of them leaked, some
+/// unmapped or secondary, with MAPQs from 0 to 59. More than one chunk of the parsing threads.
+fn synthetic_sam(reads: u64, seed: u64) -> (String, Vec<String>) {
+    let (taxa, genes) = (30, 4);
+    let mut random = SplitMix64::new(seed);
+    let header = (1..=taxa).flat_map(|taxon| (1..=genes).map(move |gene| format!("@SQ\tSN:{}_{}\tLN:500\n", taxon, gene))).collect();
+    let records = (0..reads).map(|read| {
+        let (taxon, gene) = (random.below(taxa) + 1, random.below(genes) + 1);
+        let (flag, reference) = match random.below(20) {
+            0 => (4, "*".to_string()),
+            1..=4 => (256 * random.below(2), format!("{}_{}", random.below(taxa) + 1, random.below(genes) + 1)),
+            _ => (16 * random.below(2), format!("{}_{}", taxon, gene)),
+        };
+        format!("{}_{}_r{}\t{}\t{}\t{}\t{}\t50M\t*\t0\t0\t*\t*\n", taxon, gene, read, flag, reference, random.below(400) + 1, random.below(60))
+    }).collect();
+    (header, records)
+}
+
+fn gzip(path: &str, content: &str) {
+    let mut encoder = GzEncoder::new(fs::File::create(path).unwrap(), Compression::fast());
+    encoder.write_all(content.as_bytes()).unwrap();
+    encoder.finish().unwrap();
+}
+
+/// stdout, anomaly report and the record counts of pairwise_leakage with `threads`, and its stderr.
+fn count(dir: &Path, inputs: &[&str], options: &[&str], threads: &str) -> ((Vec<u8>, String, String), String) {
+    let anomalies = arg(dir, &format!("anomalies_{}.tsv", threads));
+    let mut args = vec!["--input", inputs[0], "--threads", threads, "--anomaly-log", &anomalies];
+    if inputs.len() > 1 {
+        args.push("--inputs");
+        args.extend(&inputs[1..]);
+    }
+    let result = output(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&args[..], options].concat());
+    let stderr = String::from_utf8_lossy(&result.stderr).into_owned();
+    assert!(result.status.success(), "{:?} {:?}: {}", inputs, options, stderr);
+    let records = stderr.lines().filter(|line| line.starts_with("Records: ")).collect::<Vec<&str>>().join("\n");
+    ((result.stdout, read(&anomalies), records), stderr)
+}
+
+#[test]
+fn parallel_counts_match_serial_counts() {
+    let dir = scratch("parallel_parsing");
+    let (header, records) = synthetic_sam(30_000, 17);
+    let (first, second) = records.split_at(12_000);
+    let plain = arg(&dir, "reads.sam");
+    fs::write(&plain, format!("{}{}", header, records.concat())).unwrap();
+    let gzipped = arg(&dir, "reads.sam.gz");
+    gzip(&gzipped, &format!("{}{}", header, records.concat()));
+    let (part_1, part_2) = (arg(&dir, "part_1.sam"), arg(&dir, "part_2.sam.gz"));
+    fs::write(&part_1, format!("{}{}", header, first.concat())).unwrap();
+    gzip(&part_2, &format!("{}{}", header, second.concat()));
+    // An invalid record and a read name without a gene among the records
+    let broken = arg(&dir, "broken.sam");
+    fs::write(&broken, format!("{}{}broken record\n{}r1\t0\t1_1\t1\t30\t50M\t*\t0\t0\t*\t*\n{}", header, first[..5000].concat(), first[5000..].concat(), second.concat())).unwrap();
+    // Inputs read at the same time, the anomalies of the later ones numbered after the earlier
+    // ones; the first lacks its last newline
+    let (broken_1, broken_2) = (arg(&dir, "broken_1.sam"), arg(&dir, "broken_2.sam.gz"));
+    fs::write(&broken_1, [format!("{}{}", header, first[..100].concat()).as_bytes(), b"\xff invalid\n", first[100..].concat().trim_end().as_bytes()].concat()).unwrap();
+    gzip(&broken_2, &format!("{}{}broken record\nr1\t0\t1_1\t1\t30\t50M\t*\t0\t0\t*\t*\n{}", header, second[..7000].concat(), second[7000..].concat()));
+
+    // (inputs, options, parsing threads capped, inputs read at a time)
+    let cases: [(&[&str], &[&str], bool, usize); 8] = [
+        (&[&plain], &[], false, 1),
+        (&[&plain], &["--min_mapq", "20", "--strand-bias"], false, 1),
+        (&[&gzipped], &[], true, 1),
+        (&[&part_1, &part_2], &[], false, 2),
+        (&[&part_2, &part_2], &[], false, 2),
+        (&[&part_2, &part_2, &part_2], &["--min_mapq", "20"], false, 3),
+        (&[&broken], &["--skip-invalid"], false, 1),
+        (&[&broken_1, &broken_2, &part_1], &["--skip-invalid"], false, 3),
+    ];
+    for (inputs, options, capped, readers) in cases {
+        let (serial, _stderr) = count(&dir, inputs, options, "1");
+        let (parallel, stderr) = count(&dir, inputs, options, "4");
+        assert!(serial.0.len() > 1000, "{:?}", inputs);
+        assert!(serial.0 == parallel.0, "{:?} {:?}: tables differ", inputs, options);
+        assert_eq!(serial.1, parallel.1, "{:?} {:?}", inputs, options);
+        assert_eq!(serial.2, parallel.2, "{:?} {:?}", inputs, options);
+        assert_eq!(stderr.contains("Parsing records on 2 of --threads 4 threads"), capped, "{:?}: {}", inputs, stderr);
+        assert_eq!(stderr.contains(&format!("Reading {} of {} inputs at a time", readers, inputs.len())), readers > 1, "{:?}: {}", inputs, stderr);
+    }
+    let (broken, _stderr) = count(&dir, &[&broken], &["--skip-invalid"], "4");
+    assert!(broken.1.contains("invalid_record\t1\t") && broken.1.contains("unparseable_name\t1\t"), "{}", broken.1);
+    let (broken, _stderr) = count(&dir, &[&broken_1, &broken_2, &part_1], &["--skip-invalid"], "4");
+    assert!(broken.1.contains("invalid_record\t2\t") && broken.1.contains("unparseable_name\t1\t"), "{}", broken.1);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Exit status and last line of stderr of a pairwise_leakage run expected to fail.
+fn failure(inputs: &[&str], options: &[&str], threads: &str) -> (Option<i32>, String) {
+    let mut args = vec!["--input", inputs[0], "--threads", threads];
+    if inputs.len() > 1 {
+        args.push("--inputs");
+        args.extend(&inputs[1..]);
+    }
+    let result = output(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&args[..], options].concat());
+    let stderr = String::from_utf8_lossy(&result.stderr).into_owned();
+    (result.status.code(), stderr.lines().last().unwrap_or_default().to_string())
+}
+
+#[test]
+fn strict_parallel_runs_fail_with_the_serial_anomaly() {
+    let dir = scratch("parallel_parsing_strict");
+    let (header, records) = synthetic_sam(20_000, 23);
+    let (first, second) = records.split_at(10_000);
+    let part_1 = arg(&dir, "part_1.sam");
+    fs::write(&part_1, format!("{}{}", header, first.concat())).unwrap();
+    // Both have an invalid record and, later, a read name without a gene
+    let (broken_1, broken_2) = (arg(&dir, "broken_1.sam"), arg(&dir, "broken_2.sam.gz"));
+    let broken = |records: &[String]| format!("{}{}broken record\n{}r1\t0\t1_1\t1\t30\t50M\t*\t0\t0\t*\t*\n{}", header, records[..3000].concat(), records[3000..6000].concat(), records[6000..].concat());
+    fs::write(&broken_1, broken(second)).unwrap();
+    gzip(&broken_2, &broken(first));
+
+    let cases: [(&[&str], &[&str]); 4] = [
+        (&[&broken_1], &[]),
+        (&[&broken_1], &["--skip-invalid", "--strict"]),
+        (&[&part_1, &broken_2, &broken_1], &[]),
+        (&[&part_1, &broken_1, &broken_2], &["--skip-invalid", "--strict"]),
+    ];
+    for (inputs, options) in cases {
+        let serial = failure(inputs, options, "1");
+        assert_ne!(serial.0, Some(0), "{:?} {:?}", inputs, options);
+        assert_eq!(serial, failure(inputs, options, "4"), "{:?} {:?}", inputs, options);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn strict_parallel_runs_stop_reading_at_a_fatal_anomaly() {
+    let (header, records) = synthetic_sam(10_000, 29);
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_pairwise_leakage"))
+        .args(["--input", "-", "--threads", "4"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut written = stdin.write_all(format!("{}{}broken record\n", header, records[..100].concat()).as_bytes()).is_ok();
+    // 200 times the records after the invalid one, far more than the run reads before it stops
+    let (mut batches, block) = (0, records.concat());
+    while written && batches < 200 {
+        written = stdin.write_all(block.as_bytes()).is_ok();
+        batches += 1;
+    }
+    drop(stdin);
+    assert_eq!(child.wait().unwrap().code(), Some(1));
+    assert!(!written, "The whole input was read, {} batches", batches);
+}
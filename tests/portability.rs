@@ -0,0 +1,73 @@
+//! Inputs with CRLF line endings or uppercase extensions read like their plain counterparts, and
+//! missing inputs end the run with an error naming them instead of a panic.
+
+mod common;
+
+use std::{fs, io::Write};
+
+use common::{arg, output, read, run, scratch, SAM};
+use flate2::{write::GzEncoder, Compression};
+
+fn crlf(content: &str) -> String {
+    content.lines().map(|line| format!("{}\r\n", line)).collect()
+}
+
+fn gzip(path: &str, content: &str) {
+    let mut encoder = GzEncoder::new(fs::File::create(path).unwrap(), Compression::default());
+    encoder.write_all(content.as_bytes()).unwrap();
+    encoder.finish().unwrap();
+}
+
+#[test]
+fn crlf_inputs_read_like_lf_inputs() {
+    let dir = scratch("portability_crlf");
+    let out = |file: &str| arg(&dir, file);
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--output", &out("lf.tsv")]);
+
+    fs::write(out("crlf.sam"), crlf(&read(SAM))).unwrap();
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &out("crlf.sam"), "--output", &out("from_crlf.tsv")]);
+    assert_eq!(read(dir.join("from_crlf.tsv")), read(dir.join("lf.tsv")));
+
+    fs::write(out("crlf.tsv"), crlf(&read(dir.join("lf.tsv")))).unwrap();
+    run(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &out("lf.tsv"), "--output", &out("lf_normalized.tsv")]);
+    run(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &out("crlf.tsv"), "--output", &out("crlf_normalized.tsv")]);
+    assert_eq!(read(dir.join("crlf_normalized.tsv")), read(dir.join("lf_normalized.tsv")));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn uppercase_gz_extensions_are_compressed() {
+    let dir = scratch("portability_gz");
+    let out = |file: &str| arg(&dir, file);
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--output", &out("plain.tsv")]);
+
+    gzip(&out("LEAKS.SAM.GZ"), &crlf(&read(SAM)));
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &out("LEAKS.SAM.GZ"), "--output", &out("PAIRWISE.TSV.GZ")]);
+    let compressed = fs::read(dir.join("PAIRWISE.TSV.GZ")).unwrap();
+    assert_eq!(compressed[..2], [0x1f, 0x8b], "an uppercase .GZ output is gzip compressed");
+
+    run(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &out("plain.tsv"), "--output", &out("plain_normalized.tsv")]);
+    run(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &out("PAIRWISE.TSV.GZ"), "--output", &out("gz_normalized.tsv")]);
+    assert_eq!(read(dir.join("gz_normalized.tsv")), read(dir.join("plain_normalized.tsv")));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn missing_inputs_are_errors_not_panics() {
+    let dir = scratch("portability_missing");
+    let missing = arg(&dir, "missing.sam");
+    let runs: [(&str, Vec<&str>); 4] = [
+        (env!("CARGO_BIN_EXE_pairwise_leakage"), vec!["--input", &missing]),
+        (env!("CARGO_BIN_EXE_normalize_pairwise"), vec!["--input", &missing]),
+        (env!("CARGO_BIN_EXE_mask_genes"), vec!["--input", &missing]),
+        (env!("CARGO_BIN_EXE_analyze"), vec!["--input", &missing, "-o", dir.to_str().unwrap()]),
+    ];
+    for (binary, args) in runs {
+        let result = output(binary, &args);
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert_eq!(result.status.code(), Some(1), "{}: {}", binary, stderr);
+        assert!(stderr.contains(&format!("Cannot open {}", missing)), "{}: {}", binary, stderr);
+        assert!(!stderr.contains("panicked"), "{}: {}", binary, stderr);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,65 @@
+//! --flush-every writes the pairs with the largest totals counted so far to --preliminary-output,
+//! marked partial, and leaves the final table as it is without it.
+
+mod common;
+
+use std::{collections::HashMap, fs, path::Path};
+
+use common::{arg, read, run, scratch, SAM};
+use fix_gtdb_mg::utils::SplitMix64;
+
+/// Records of `reads` reads between 40 taxa, one gene each, over more than 100 pairs.
+fn many_pairs_sam(dir: &Path, reads: u64) -> String {
+    let mut random = SplitMix64::new(13);
+    let header = (1..=40).map(|taxon| format!("@SQ\tSN:{}_1\tLN:500\n", taxon)).collect::<String>();
+    let records = (0..reads).map(|read| {
+        let taxon = random.below(40) + 1;
+        let reference = if random.below(3) == 0 { random.below(40) + 1 } else { taxon };
+        format!("{}_1_r{}\t0\t{}_1\t1\t30\t50M\t*\t0\t0\t*\t*\n", taxon, read, reference)
+    }).collect::<String>();
+    let path = arg(dir, "many_pairs.sam");
+    fs::write(&path, header + &records).unwrap();
+    path
+}
+
+/// (from, to) -> total of the rows of a table.
+fn totals(table: &str) -> HashMap<(String, String), u64> {
+    table.lines().filter(|line| !line.starts_with('#')).map(|line| {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        ((fields[0].to_string(), fields[1].to_string()), fields[2].parse().unwrap())
+    }).collect()
+}
+
+#[test]
+fn preliminary_pairs_are_partial_and_the_final_table_unchanged() {
+    let dir = scratch("preliminary_output");
+    let many_pairs = many_pairs_sam(&dir, 5_000);
+    // (input, records, --flush-every)
+    for (input, records, every) in [(SAM, 54, 10), (many_pairs.as_str(), 5_000, 1_500)] {
+        for options in [&[][..], &["--no-genes"][..]] {
+            let final_table = run(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&["--input", input][..], options].concat());
+            let preliminary = arg(&dir, "preliminary.tsv");
+            let flushed = run(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&["--input", input, "--flush-every", &every.to_string(), "--preliminary-output", &preliminary][..], options].concat());
+            assert_eq!(flushed, final_table, "{} {:?}", input, options);
+
+            let partial = read(&preliminary);
+            let (final_totals, partial_totals) = (totals(&final_table), totals(&partial));
+            let last_flush = records / every * every;
+            assert!(last_flush < records);
+            let header = partial.lines().next().unwrap();
+            assert_eq!(header, format!("#partial\trecords={}\tpairs={}", last_flush, partial_totals.len()), "{}", input);
+            // The self-pair and pair headers of the final table follow
+            assert_eq!(partial.lines().skip(1).take(2).collect::<Vec<&str>>(), final_table.lines().take(2).collect::<Vec<&str>>());
+            assert_eq!(partial_totals.len(), final_totals.len().min(100), "{}", input);
+            assert!(partial_totals.values().sum::<u64>() <= last_flush);
+            for (pair, total) in &partial_totals {
+                assert!(*total <= final_totals[pair], "{:?}", pair);
+            }
+            // Largest totals first
+            let rows = partial.lines().filter(|line| !line.starts_with('#')).map(|line| line.split('\t').nth(2).unwrap().parse::<u64>().unwrap()).collect::<Vec<u64>>();
+            assert!(rows.windows(2).all(|pair| pair[0] >= pair[1]), "{:?}", rows);
+            fs::remove_file(&preliminary).unwrap();
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,60 @@
+//! --primary-only counts every aligned read once, whatever the number of alignments reported for
+//! it (bowtie2 -k, minimap2 -N), as the primary alignments alone would.
+
+mod common;
+
+use std::{collections::HashSet, fs};
+
+use common::{arg, run, scratch};
+use fix_gtdb_mg::utils::SplitMix64;
+
+/// A SAM reporting up to 5 alignments per read (-k 5): the primary one, secondary ones to random
+/// genes and now and then a supplementary one. Some reads are unmapped.
+fn multimapped_sam(reads: u64) -> String {
+    let (taxa, genes) = (6, 3);
+    let mut random = SplitMix64::new(11);
+    let mut sam = (1..=taxa).flat_map(|taxon| (1..=genes).map(move |gene| format!("@SQ\tSN:{}_{}\tLN:1000\n", taxon, gene))).collect::<String>();
+    for read in 0..reads {
+        let qname = format!("{}_{}_r{}", random.below(taxa) + 1, random.below(genes) + 1, read);
+        if random.below(10) == 0 {
+            sam.push_str(&format!("{}\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n", qname));
+            continue
+        }
+        let alignments = random.below(5) + 1;
+        for alignment in 0..alignments {
+            let flag = match alignment {
+                0 => 16 * random.below(2),
+                _ if random.below(4) == 0 => 2048,
+                _ => 256,
+            };
+            sam.push_str(&format!("{}\t{}\t{}_{}\t{}\t{}\t50M\t*\t0\t0\t*\t*\n", qname, flag, random.below(taxa) + 1, random.below(genes) + 1, random.below(900) + 1, random.below(60)));
+        }
+    }
+    sam
+}
+
+/// Sum of the totals of a pairwise table, every counted record being in one pair.
+fn counted(table: &str) -> u64 {
+    table.lines().filter(|line| !line.starts_with('#')).map(|line| line.split('\t').nth(2).unwrap().parse::<u64>().unwrap()).sum()
+}
+
+#[test]
+fn primary_only_counts_each_aligned_read_once() {
+    let dir = scratch("primary_only");
+    let sam = multimapped_sam(2_000);
+    let path = arg(&dir, "k5.sam");
+    fs::write(&path, &sam).unwrap();
+    let records = sam.lines().filter(|line| !line.starts_with('@')).map(|line| line.split('\t').collect::<Vec<&str>>()).collect::<Vec<_>>();
+    let aligned_reads = records.iter().filter(|fields| fields[1] != "4").map(|fields| fields[0]).collect::<HashSet<&str>>().len() as u64;
+    let alignments = records.iter().filter(|fields| fields[1] != "4").count() as u64;
+    assert!(alignments > aligned_reads * 2);
+
+    // Every MAPQ passes, only --primary-only skips records
+    for threads in ["1", "4"] {
+        let primary = run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &path, "--min_mapq", "0", "--primary-only", "--threads", threads]);
+        assert_eq!(counted(&primary), aligned_reads, "--threads {}", threads);
+        let all = run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", &path, "--min_mapq", "0", "--threads", threads]);
+        assert_eq!(counted(&all), alignments, "--threads {}", threads);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,115 @@
+//! The query layer answers from an analyze results directory what its pairwise table holds, and
+//! the C ABI of the ffi feature answers the same.
+
+mod common;
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use common::{arg, read, run, scratch, SAM};
+use fix_gtdb_mg::query::{Direction, ResultsQuery};
+
+/// (from, to) -> (total, gene counts) of the rows of a pairwise table.
+type Pairs = BTreeMap<(usize, usize), (u64, Vec<(usize, u64)>)>;
+
+/// Rows of a pairwise table with gene base 1.
+fn pairs(table: &str) -> Pairs {
+    assert!(table.contains("gene_base=1"));
+    table.lines().filter(|line| !line.starts_with('#')).map(|line| {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        let genes = fields[3..].iter().enumerate().filter(|(_gene, count)| **count != "-1").map(|(gene, count)| (gene + 1, count.parse().unwrap())).collect();
+        ((fields[0].parse().unwrap(), fields[1].parse().unwrap()), (fields[2].parse().unwrap(), genes))
+    }).collect()
+}
+
+/// The results of analyze on the fixture, with the pairs of its table.
+fn analyzed(dir: &Path) -> (String, Pairs) {
+    let results = arg(dir, "analysis");
+    run(env!("CARGO_BIN_EXE_analyze"), &["--input", SAM, "-o", &results]);
+    let table = pairs(&read(Path::new(&results).join("tables/pairwise.tsv")));
+    (results, table)
+}
+
+/// Partners of a taxon by the table, as the query layer sorts them.
+fn expected(table: &Pairs, taxon: usize, direction: Direction, min_total: u64) -> Vec<(usize, u64)> {
+    let mut partners = table.iter()
+        .filter(|((from, to), (total, _genes))| from != to && *total >= min_total && match direction {
+            Direction::Incoming => *to == taxon,
+            Direction::Outgoing => *from == taxon,
+        })
+        .map(|((from, to), (total, _genes))| (if *to == taxon { *from } else { *to }, *total))
+        .collect::<Vec<(usize, u64)>>();
+    partners.sort_by(|(a, a_total), (b, b_total)| b_total.cmp(a_total).then(a.cmp(b)));
+    partners
+}
+
+fn gene_profile(table: &Pairs, taxon: usize) -> Vec<(usize, u64)> {
+    let mut profile = BTreeMap::new();
+    for (_pair, (_total, genes)) in table.iter().filter(|((from, to), _counts)| from != to && *to == taxon) {
+        genes.iter().for_each(|(gene, count)| *profile.entry(*gene).or_default() += count);
+    }
+    profile.into_iter().collect()
+}
+
+#[test]
+fn queries_answer_what_the_pairwise_table_holds() {
+    let dir = scratch("query");
+    let (results, table) = analyzed(&dir);
+    let query = ResultsQuery::open(&results).unwrap();
+    for taxon in 1..=4 {
+        assert_eq!(query.incoming(taxon), expected(&table, taxon, Direction::Incoming, 0), "{}", taxon);
+        for direction in [Direction::Incoming, Direction::Outgoing] {
+            for min_total in [0, 3, 5] {
+                assert_eq!(query.pairs_for(taxon, direction, min_total), expected(&table, taxon, direction, min_total), "{} {:?} {}", taxon, direction, min_total);
+            }
+        }
+        assert_eq!(query.gene_profile(taxon), gene_profile(&table, taxon), "{}", taxon);
+    }
+    assert!(!query.incoming(1).is_empty() && !query.gene_profile(1).is_empty());
+
+    // Only completed runs are opened
+    fs::remove_file(Path::new(&results).join("manifest.json")).unwrap();
+    assert!(ResultsQuery::open(&results).is_err());
+    assert!(ResultsQuery::open(dir.join("missing")).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn the_c_abi_answers_as_the_query_layer() {
+    use std::{ffi::CString, slice};
+
+    use fix_gtdb_mg::{ffi::*, query::QUERY_API_VERSION};
+
+    /// Copies an array out and frees it.
+    fn pairs(array: FgmArray) -> Vec<(usize, u64)> {
+        let pairs = match array.len {
+            0 => Vec::new(),
+            len => unsafe { slice::from_raw_parts(array.ids, len).iter().zip(slice::from_raw_parts(array.values, len)).map(|(id, value)| (*id as usize, *value)).collect() },
+        };
+        unsafe { fgm_array_free(array) };
+        pairs
+    }
+
+    let dir = scratch("query_ffi");
+    let (results, _table) = analyzed(&dir);
+    let query = ResultsQuery::open(&results).unwrap();
+    assert_eq!(fgm_api_version(), QUERY_API_VERSION);
+    let path = CString::new(results.clone()).unwrap();
+    let handle = unsafe { fgm_open(path.as_ptr()) };
+    assert!(!handle.is_null());
+    for taxon in 1..=4 {
+        assert_eq!(pairs(unsafe { fgm_incoming(handle, taxon as u64) }), query.incoming(taxon));
+        assert_eq!(pairs(unsafe { fgm_pairs_for(handle, taxon as u64, 0, 3) }), query.pairs_for(taxon, Direction::Incoming, 3));
+        assert_eq!(pairs(unsafe { fgm_pairs_for(handle, taxon as u64, 1, 0) }), query.pairs_for(taxon, Direction::Outgoing, 0));
+        assert_eq!(pairs(unsafe { fgm_gene_profile(handle, taxon as u64) }), query.gene_profile(taxon));
+    }
+    // Invalid directions and null handles give empty arrays, unopenable directories null
+    assert_eq!(pairs(unsafe { fgm_pairs_for(handle, 1, 2, 0) }), Vec::new());
+    assert_eq!(pairs(unsafe { fgm_incoming(std::ptr::null(), 1) }), Vec::new());
+    unsafe { fgm_close(handle) };
+    let missing = CString::new(arg(&dir, "missing")).unwrap();
+    assert!(unsafe { fgm_open(missing.as_ptr()) }.is_null());
+    assert!(unsafe { fgm_open(std::ptr::null()) }.is_null());
+    unsafe { fgm_close(std::ptr::null_mut()) };
+    fs::remove_dir_all(&dir).unwrap();
+}
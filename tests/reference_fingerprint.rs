@@ -0,0 +1,47 @@
+//! analyze fingerprints the reference of the SAM in the mask header, check_reference --mask
+//! refuses a mask derived from another reference build.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{arg, output, read, run, scratch, SAM};
+
+/// FASTA of the given taxid_gene names, in that order.
+fn fasta(names: &[&str]) -> String {
+    names.iter().map(|name| format!(">{} marker\nACGTACGT\n", name)).collect()
+}
+
+#[test]
+fn masks_are_checked_against_their_reference() {
+    let dir = scratch("reference_fingerprint");
+    let results = arg(&dir, "analysis");
+    run(env!("CARGO_BIN_EXE_analyze"), &["--input", SAM, "-o", &results]);
+    let mask = Path::new(&results).join("mask/mask.tsv").to_str().unwrap().to_string();
+    assert!(read(&mask).lines().take_while(|line| line.starts_with('#')).any(|line| line.starts_with("#reference_fingerprint\t")));
+
+    // The keys of the @SQ lines in another order and with descriptions are the same reference
+    let reference = arg(&dir, "reference.fasta");
+    fs::write(&reference, fasta(&["3_3", "3_2", "3_1", "2_3", "2_2", "2_1", "1_3", "1_2", "1_1"])).unwrap();
+    run(env!("CARGO_BIN_EXE_check_reference"), &["--reference", &reference, "--mask", &mask]);
+
+    // A subset is another build, refused unless --force
+    let subset = arg(&dir, "subset.fasta");
+    fs::write(&subset, fasta(&["1_1", "1_2", "1_3", "2_1", "2_2", "2_3", "3_1", "3_2"])).unwrap();
+    let result = output(env!("CARGO_BIN_EXE_check_reference"), &["--reference", &subset, "--mask", &mask]);
+    assert!(!result.status.success());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("the mask was derived from another reference build"));
+    let report = run(env!("CARGO_BIN_EXE_check_reference"), &["--reference", &subset, "--mask", &mask, "--force", "--sam", SAM]);
+    assert!(report.contains("only_mask_reference\t1\t3_3\nonly_fasta\t0\t\n"), "{}", report);
+
+    // Keys in only one reference are listed on both sides
+    let disjoint = arg(&dir, "disjoint.fasta");
+    fs::write(&disjoint, fasta(&["1_1", "4_1"])).unwrap();
+    let report = run(env!("CARGO_BIN_EXE_check_reference"), &["--reference", &disjoint, "--mask", &mask, "--force", "--sam", SAM]);
+    assert!(report.contains("only_mask_reference\t8\t1_2,1_3,2_1,2_2,2_3,3_1,3_2,3_3\nonly_fasta\t1\t4_1\n"), "{}", report);
+
+    // Without the SAM the hash alone cannot list the keys
+    let result = output(env!("CARGO_BIN_EXE_check_reference"), &["--reference", &subset, "--mask", &mask, "--force"]);
+    assert!(result.status.success() && String::from_utf8_lossy(&result.stderr).contains("Pass --sam"));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,85 @@
+//! --release-tag records the release of a pairwise table in its header. merge_pairwise merges
+//! tables release by release and compare_pairwise labels its columns with the releases, and both
+//! refuse to mix tagged with untagged tables.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, run, scratch, SAM};
+
+/// Rows of a table, sorted.
+fn rows(table: &str) -> Vec<String> {
+    let mut rows = table.lines().filter(|line| !line.starts_with('#')).map(str::to_string).collect::<Vec<String>>();
+    rows.sort();
+    rows
+}
+
+/// Rows of a merged table of the release, without their source column.
+fn release_rows(table: &str, release: &str) -> Vec<String> {
+    let mut rows = table.lines().filter_map(|line| line.strip_prefix(&format!("{}\t", release))).map(str::to_string).collect::<Vec<String>>();
+    rows.sort();
+    rows
+}
+
+fn refusal(binary: &str, args: &[&str]) -> String {
+    let result = output(binary, args);
+    let stderr = String::from_utf8_lossy(&result.stderr).into_owned();
+    assert_eq!(result.status.code(), Some(1), "{:?}: {}", args, stderr);
+    stderr
+}
+
+#[test]
+fn tables_are_merged_and_compared_release_by_release() {
+    let dir = scratch("release_tags");
+    let (merge, compare) = (env!("CARGO_BIN_EXE_merge_pairwise"), env!("CARGO_BIN_EXE_compare_pairwise"));
+    let table = |file: &str, options: &[&str]| {
+        let path = arg(&dir, file);
+        fs::write(&path, run(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&["--input", SAM][..], options].concat())).unwrap();
+        path
+    };
+    let untagged = table("untagged.tsv", &[]);
+    let strict = table("strict.tsv", &["--min_mapq", "40"]);
+    let r214 = table("r214.tsv", &["--release-tag", "r214"]);
+    let r220 = table("r220.tsv", &["--release-tag", "r220", "--min_mapq", "40"]);
+    let tagged = common::read(&r214);
+    assert_eq!(tagged.lines().rfind(|line| line.starts_with('#')), Some("#release\tr214"));
+    assert_eq!(rows(&tagged), rows(&common::read(&untagged)));
+
+    // Tables of one release merge as untagged ones do, led by their release, and merge again
+    let merged = run(merge, &[&r214, &r214]);
+    assert!(merged.lines().any(|line| line == "#releases\tr214"), "{}", merged);
+    assert_eq!(release_rows(&merged, "r214"), rows(&run(merge, &[&untagged, &untagged])));
+    assert_eq!(release_rows(&merged, "r214").len(), rows(&merged).len());
+    let doubled = arg(&dir, "doubled.tsv");
+    fs::write(&doubled, &merged).unwrap();
+    assert_eq!(release_rows(&run(merge, &[&doubled, &r214]), "r214"), rows(&run(merge, &[&untagged, &untagged, &untagged])));
+
+    // Tables of several releases are merged per release, every row led by its release
+    let merged = run(merge, &[&r214, &r220, &r214]);
+    assert!(merged.lines().any(|line| line == "#releases\tr214\tr220"), "{}", merged);
+    assert_eq!(release_rows(&merged, "r214"), rows(&run(merge, &[&untagged, &untagged])));
+    assert_eq!(release_rows(&merged, "r220"), rows(&common::read(&strict)));
+    assert_eq!(rows(&merged).len(), release_rows(&merged, "r214").len() + release_rows(&merged, "r220").len());
+    let releases = arg(&dir, "releases.tsv");
+    fs::write(&releases, &merged).unwrap();
+    let stderr = refusal(merge, &[&releases, &r214]);
+    assert!(stderr.contains(&format!("{}: holds the releases r214, r220, split it by its source column first", releases)), "{}", stderr);
+
+    // Differences are labelled with the releases, before and after for untagged tables
+    let differences = run(compare, &[&r214, &r220]);
+    assert_eq!(differences.lines().next(), Some("pair\tfield\tr214\tr220"));
+    assert_eq!(differences.lines().skip(1).collect::<Vec<&str>>(), run(compare, &[&untagged, &strict]).lines().skip(1).collect::<Vec<&str>>());
+    assert_eq!(run(compare, &[&untagged, &strict]).lines().next(), Some("pair\tfield\tbefore\tafter"));
+    assert_eq!(run(compare, &[&r214, &r214]), "pair\tfield\tbefore\tafter\n");
+
+    for binary in [merge, compare] {
+        let stderr = refusal(binary, &[&r214, &untagged]);
+        assert!(stderr.contains(&format!("{} has no release tag but {} is tagged r214; tag all tables (--release-tag) or none", untagged, r214)), "{}", stderr);
+    }
+    for tag in ["", "r2\t20"] {
+        let result = output(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--release-tag", tag]);
+        assert!(!result.status.success() && String::from_utf8_lossy(&result.stderr).contains("Invalid release tag"), "{:?}", tag);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
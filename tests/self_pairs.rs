@@ -0,0 +1,206 @@
+//! Every output follows the self-pair policy: pairwise tables drop self-pairs only when they are
+//! excluded from the output, normalized values and rates divide by donor totals with or without
+//! them, and every table states the policy it was written with.
+
+mod common;
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use common::{arg, read, run, scratch, SAM};
+
+/// The four combinations of --exclude-self-pairs and --exclude-self-from-denominators, with
+/// (output, denominators) included.
+const POLICIES: [(&[&str], bool, bool); 4] = [
+    (&[], true, true),
+    (&["--exclude-self-pairs"], false, true),
+    (&["--exclude-self-from-denominators"], true, false),
+    (&["--exclude-self-pairs", "--exclude-self-from-denominators"], false, false),
+];
+
+fn header(output: bool, denominators: bool) -> String {
+    let inclusion = |included: bool| if included { "included" } else { "excluded" };
+    format!("#self_pairs\toutput={}\tdenominators={}", inclusion(output), inclusion(denominators))
+}
+
+fn data_lines(table: &str) -> Vec<&str> {
+    table.lines().filter(|line| !line.starts_with('#')).collect()
+}
+
+/// Gene counts per (from, to) of a pairwise table, -1 for genes without reads.
+fn pairs(table: &str) -> BTreeMap<(u32, u32), Vec<f64>> {
+    data_lines(table).into_iter().map(|line| {
+        let fields = line.split('\t').map(|field| field.parse::<f64>().unwrap()).collect::<Vec<f64>>();
+        ((fields[0] as u32, fields[1] as u32), fields[3..].to_vec())
+    }).collect()
+}
+
+/// Gene values per recipient of a normalized table, or the single total with `totals`.
+fn normalized(table: &str, totals: bool) -> BTreeMap<u32, Vec<f64>> {
+    data_lines(table).into_iter().map(|line| {
+        let fields = line.split('\t').map(|field| field.parse::<f64>().unwrap()).collect::<Vec<f64>>();
+        (fields[0] as u32, if totals { fields[1..].to_vec() } else { fields[2..].to_vec() })
+    }).collect()
+}
+
+/// Normalized leakage per recipient and gene computed from all pairs: reads of a gene over the
+/// donor's reads of that gene, summed over donors, as `Leakage::normalize_incoming` does.
+/// Genes with reads but a zero denominator are 0, genes without reads -1.
+fn expected_normalized(all: &BTreeMap<(u32, u32), Vec<f64>>, output: bool, denominators: bool) -> BTreeMap<u32, Vec<f64>> {
+    let mut outgoing: BTreeMap<u32, Vec<f64>> = BTreeMap::new();
+    for ((from, to), genes) in all {
+        let totals = outgoing.entry(*from).or_default();
+        if denominators || from != to {
+            totals.resize(totals.len().max(genes.len()), 0.0);
+            genes.iter().enumerate().filter(|(_gene, reads)| **reads > 0.0).for_each(|(gene, reads)| totals[gene] += reads);
+        }
+    }
+    let mut result: BTreeMap<u32, Vec<f64>> = BTreeMap::new();
+    for ((from, to), genes) in all.iter().filter(|((from, to), _genes)| output || from != to) {
+        let values = result.entry(*to).or_default();
+        for (gene, reads) in genes.iter().enumerate().filter(|(_gene, reads)| **reads > 0.0) {
+            values.resize(values.len().max(gene + 1), -1.0);
+            values[gene] = values[gene].max(0.0);
+            let denominator = outgoing[from].get(gene).copied().unwrap_or(0.0);
+            if denominator > 0.0 {
+                values[gene] += reads / denominator;
+            }
+        }
+    }
+    result
+}
+
+/// Pair totals over donor totals summed per recipient, as `LeakageTotals::normalize_incoming`.
+fn expected_totals(all: &BTreeMap<(u32, u32), Vec<f64>>, output: bool, denominators: bool) -> BTreeMap<u32, Vec<f64>> {
+    let total = |genes: &Vec<f64>| genes.iter().filter(|reads| **reads > 0.0).sum::<f64>();
+    let mut outgoing: BTreeMap<u32, f64> = BTreeMap::new();
+    for ((from, to), genes) in all {
+        *outgoing.entry(*from).or_default() += if denominators || from != to { total(genes) } else { 0.0 };
+    }
+    let mut result: BTreeMap<u32, Vec<f64>> = BTreeMap::new();
+    for ((from, to), genes) in all.iter().filter(|((from, to), _genes)| output || from != to) {
+        let value = result.entry(*to).or_insert_with(|| vec![0.0]);
+        if outgoing[from] > 0.0 {
+            value[0] += total(genes) / outgoing[from];
+        }
+    }
+    result
+}
+
+fn assert_close(found: &BTreeMap<u32, Vec<f64>>, expected: &BTreeMap<u32, Vec<f64>>, context: &str) {
+    assert_eq!(found.keys().collect::<Vec<_>>(), expected.keys().collect::<Vec<_>>(), "{}", context);
+    for (recipient, values) in found {
+        let trim = |values: &[f64]| values[..values.iter().rposition(|value| *value != -1.0).map_or(0, |last| last + 1)].to_vec();
+        let (values, expected) = (trim(values), trim(&expected[recipient]));
+        assert_eq!(values.len(), expected.len(), "{}: recipient {}: {:?} vs {:?}", context, recipient, values, expected);
+        for (value, expected) in values.iter().zip(&expected) {
+            assert!((value - expected).abs() <= 1e-12 * expected.abs().max(1.0), "{}: recipient {}: {:?} vs {:?}", context, recipient, values, expected);
+        }
+    }
+}
+
+/// The table of all pairs, the reference every policy is computed from.
+fn all_pairs(dir: &Path) -> (String, BTreeMap<(u32, u32), Vec<f64>>) {
+    let path = arg(dir, "all.tsv");
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--output", &path]);
+    let all = pairs(&read(&path));
+    assert!(all.keys().any(|(from, to)| from == to) && all.keys().any(|(from, to)| from != to));
+    (path, all)
+}
+
+#[test]
+fn pairwise_tables_drop_self_pairs_only_from_the_output() {
+    let dir = scratch("self_pairs_pairwise");
+    let (_path, all) = all_pairs(&dir);
+    for (flags, output, denominators) in POLICIES {
+        for genes in [&[][..], &["--no-genes"]] {
+            let table = run(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&["--input", SAM][..], flags, genes].concat());
+            assert!(table.lines().any(|line| line == header(output, denominators)), "{:?}: {}", flags, table);
+            let mut found = data_lines(&table).into_iter().map(|line| {
+                let fields = line.split('\t').take(2).map(|field| field.parse::<u32>().unwrap()).collect::<Vec<u32>>();
+                (fields[0], fields[1])
+            }).collect::<Vec<_>>();
+            found.sort();
+            let expected = all.keys().filter(|(from, to)| output || from != to).copied().collect::<Vec<_>>();
+            assert_eq!(found, expected, "{:?} {:?}", flags, genes);
+        }
+        let matrix = run(env!("CARGO_BIN_EXE_ambiguity_matrix"), &[&["--input", SAM][..], flags].concat());
+        assert!(matrix.lines().any(|line| line == header(output, denominators)), "{:?}: {}", flags, matrix);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn normalized_values_follow_both_settings() {
+    let dir = scratch("self_pairs_normalized");
+    let (path, all) = all_pairs(&dir);
+    // --streaming needs the rows sorted by donor
+    let sorted = arg(&dir, "sorted.tsv");
+    let table = read(&path);
+    let mut rows = data_lines(&table);
+    rows.sort_by_key(|line| line.split('\t').next().unwrap().parse::<u32>().unwrap());
+    let headers = table.lines().filter(|line| line.starts_with('#')).collect::<Vec<&str>>();
+    fs::write(&sorted, [headers, rows].concat().join("\n") + "\n").unwrap();
+
+    for (flags, output, denominators) in POLICIES {
+        let expected = expected_normalized(&all, output, denominators);
+        for (input, mode) in [(&path, &[][..]), (&sorted, &["--streaming"][..])] {
+            let table = run(env!("CARGO_BIN_EXE_normalize_pairwise"), &[&["--input", input.as_str()][..], flags, mode].concat());
+            assert!(table.lines().any(|line| line == header(output, denominators)), "{:?}: {}", flags, table);
+            assert_close(&normalized(&table, false), &expected, &format!("{:?} {:?}", flags, mode));
+        }
+        let table = run(env!("CARGO_BIN_EXE_normalize_pairwise"), &[&["--input", path.as_str(), "--no-genes"][..], flags].concat());
+        assert!(table.lines().any(|line| line == header(output, denominators)), "{:?}: {}", flags, table);
+        assert_close(&normalized(&table, true), &expected_totals(&all, output, denominators), &format!("{:?} --no-genes", flags));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn deep_dive_rates_divide_by_the_policy_totals() {
+    let dir = scratch("self_pairs_deep_dive");
+    let (_path, all) = all_pairs(&dir);
+    let taxa = arg(&dir, "taxa.txt");
+    fs::write(&taxa, "1\n2\n3\n").unwrap();
+    for (flags, output, denominators) in POLICIES {
+        let table = run(env!("CARGO_BIN_EXE_deep_dive"), &[&["--input", SAM, "--taxa", &taxa][..], flags].concat());
+        assert!(table.lines().any(|line| line == header(output, denominators)), "{:?}: {}", flags, table);
+        for line in data_lines(&table).into_iter().skip(1) {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            let (from, reads) = (fields[0].parse::<u32>().unwrap(), fields[4].parse::<f64>().unwrap());
+            let outgoing = all.iter()
+                .filter(|((donor, to), _genes)| *donor == from && (denominators || donor != to))
+                .map(|(_pair, genes)| genes.iter().filter(|reads| **reads > 0.0).sum::<f64>())
+                .sum::<f64>();
+            match outgoing > 0.0 {
+                true => assert!((fields[5].parse::<f64>().unwrap() - reads / outgoing).abs() < 1e-12, "{:?}: {}", flags, line),
+                false => assert_eq!(fields[5], "NA", "{:?}: {}", flags, line),
+            }
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn analyze_outputs_match_the_separate_binaries_under_every_policy() {
+    let dir = scratch("self_pairs_analyze");
+    let (path, _all) = all_pairs(&dir);
+    let body = |table: String| data_lines(&table).into_iter().map(str::to_string).collect::<Vec<String>>();
+    let sorted = |table: String| { let mut lines = body(table); lines.sort(); lines };
+    let mut summaries = Vec::new();
+    for (index, (flags, output, denominators)) in POLICIES.into_iter().enumerate() {
+        let results = dir.join(format!("analysis_{}", index));
+        run(env!("CARGO_BIN_EXE_analyze"), &[&["--input", SAM, "-o", results.to_str().unwrap()][..], flags].concat());
+        for file in ["tables/pairwise.tsv", "tables/normalized.tsv"] {
+            assert!(read(results.join(file)).lines().any(|line| line == header(output, denominators)), "{:?}: {}", flags, file);
+        }
+        let pairwise = run(env!("CARGO_BIN_EXE_pairwise_leakage"), &[&["--input", SAM][..], flags].concat());
+        assert_eq!(sorted(read(results.join("tables/pairwise.tsv"))), sorted(pairwise), "{:?}", flags);
+        let normalized = run(env!("CARGO_BIN_EXE_normalize_pairwise"), &[&["--input", path.as_str()][..], flags].concat());
+        assert_eq!(body(read(results.join("tables/normalized.tsv"))), body(normalized), "{:?}", flags);
+        run(env!("CARGO_BIN_EXE_doctor"), &["--dir", results.to_str().unwrap()]);
+        summaries.push(read(results.join("tables/taxon_summary.tsv")));
+    }
+    // The taxon summary always counts self-pairs as correct reads
+    assert!(summaries.iter().all(|summary| *summary == summaries[0]));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,55 @@
+//! mask_genes --shard-by-prefix splits the gene leak report into shards that together hold
+//! exactly its rows, in its order, each under its headers, with an index of the shard of every
+//! taxon.
+
+mod common;
+
+use std::{collections::HashMap, fs, io::Read};
+
+use common::{arg, read, run, scratch, LABELS, SAM};
+use flate2::read::MultiGzDecoder;
+
+fn content(path: &str) -> String {
+    if !path.ends_with(".gz") {
+        return read(path)
+    }
+    let mut content = String::new();
+    MultiGzDecoder::new(fs::File::open(path).unwrap()).read_to_string(&mut content).unwrap();
+    content
+}
+
+#[test]
+fn shards_hold_the_rows_of_the_unsharded_report() {
+    let dir = scratch("sharded_report");
+    for (extension, options) in [("tsv", &[][..]), ("tsv", &["--map", LABELS, "--shard-key", "genus"][..]), ("tsv.gz", &["--map", LABELS][..])] {
+        // Unsharded, the report is written to stdout
+        let report = run(env!("CARGO_BIN_EXE_mask_genes"), &[&["--input", SAM][..], options].concat());
+        let (headers, rows): (Vec<&str>, Vec<&str>) = report.lines().partition(|line| line.starts_with('#'));
+        assert!(rows.len() > 10);
+
+        for shards in [1, 2, 3, 5] {
+            let output = arg(&dir, &format!("sharded_{}.{}", shards, extension));
+            run(env!("CARGO_BIN_EXE_mask_genes"), &[&["--input", SAM, "--output", &output, "--shard-by-prefix", &shards.to_string(), "--threads", "3"][..], options].concat());
+            let index = read(dir.join(format!("sharded_{}.index.tsv", shards)));
+            let shard_of = index.lines().skip(1).map(|line| {
+                let fields = line.split('\t').collect::<Vec<&str>>();
+                (fields[0].to_string(), (fields[1].parse::<usize>().unwrap(), fields[2].to_string()))
+            }).collect::<HashMap<String, (usize, String)>>();
+
+            let mut sharded_rows = 0;
+            for shard in 0..shards {
+                let path = arg(&dir, &format!("sharded_{}.shard{}.{}", shards, shard, extension));
+                let written = content(&path);
+                let (shard_headers, shard_rows): (Vec<&str>, Vec<&str>) = written.lines().partition(|line| line.starts_with('#'));
+                assert_eq!(shard_headers, headers, "{:?} shard {} of {}", options, shard, shards);
+                // The rows of its taxa in the order of the report
+                let expected = rows.iter().filter(|row| shard_of[row.split('\t').next().unwrap()] == (shard, path.clone())).copied().collect::<Vec<&str>>();
+                assert_eq!(shard_rows, expected, "{:?} shard {} of {}", options, shard, shards);
+                sharded_rows += shard_rows.len();
+            }
+            assert_eq!(sharded_rows, rows.len(), "{:?} {} shards", options, shards);
+            assert!(shard_of.values().all(|(shard, _path)| *shard < shards));
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,64 @@
+//! simulate_mask replays a name-grouped SAM with and without the alignments to masked genes and
+//! counts how the reads of every taxon change state.
+
+mod common;
+
+use std::fs;
+
+use common::{arg, output, read, run, scratch};
+
+/// Reads of known outcome under a mask of gene 1 of taxa 2 and 3: r1 rescued, r2 unassigned,
+/// r3 leaking elsewhere, r4 correct and r5 ambiguous throughout.
+const SAM: &str = "\
+@SQ\tSN:1_1\tLN:500
+@SQ\tSN:1_2\tLN:500
+@SQ\tSN:2_1\tLN:500
+@SQ\tSN:3_1\tLN:500
+@SQ\tSN:3_2\tLN:500
+1_1_r1\t0\t2_1\t1\t40\t50M\t*\t0\t0\t*\t*
+1_1_r1\t256\t1_1\t1\t20\t50M\t*\t0\t0\t*\t*
+2_1_r2\t0\t2_1\t1\t30\t50M\t*\t0\t0\t*\t*
+1_2_r3\t0\t2_1\t1\t40\t50M\t*\t0\t0\t*\t*
+1_2_r3\t256\t3_2\t1\t30\t50M\t*\t0\t0\t*\t*
+1_2_r4\t0\t1_2\t1\t30\t50M\t*\t0\t0\t*\t*
+3_2_r5\t0\t3_2\t1\t30\t50M\t*\t0\t0\t*\t*
+3_2_r5\t256\t1_1\t1\t30\t50M\t*\t0\t0\t*\t*
+";
+
+#[test]
+fn masks_rescue_lose_and_move_reads() {
+    let dir = scratch("simulate_mask");
+    let (sam, mask, confusion) = (arg(&dir, "reads.sam"), arg(&dir, "mask.tsv"), arg(&dir, "confusion.tsv"));
+    fs::write(&sam, SAM).unwrap();
+    fs::write(&mask, "2\t1\n3\t1\n").unwrap();
+    let taxa = run(env!("CARGO_BIN_EXE_simulate_mask"), &["--input", &sam, "--mask", &mask, "--confusion", &confusion]);
+    assert_eq!(taxa.lines().skip(1).collect::<Vec<&str>>(), [
+        "1\t3\t1\t2\t0\t0\t2\t1\t0\t0\t1\t1\t0",
+        "2\t1\t1\t0\t0\t0\t0\t0\t0\t1\t0\t0\t1",
+        "3\t1\t0\t0\t1\t0\t0\t0\t1\t0\t0\t0\t0",
+    ]);
+    let summary = read(&confusion);
+    for line in ["correct\t1\t0\t0\t1", "leaked\t1\t1\t0\t0", "#rescued\t1", "#leaked_elsewhere\t1", "#lost\t1", "#masked_alignments\t3", "#skipped_reads\t0"] {
+        assert!(summary.lines().any(|summary_line| summary_line == line), "{} in {}", line, summary);
+    }
+
+    // Without --confusion the summary goes to stderr, an empty mask changes nothing
+    fs::write(&mask, "").unwrap();
+    let result = output(env!("CARGO_BIN_EXE_simulate_mask"), &["--input", &sam, "--mask", &mask]);
+    let (stdout, stderr) = (String::from_utf8_lossy(&result.stdout), String::from_utf8_lossy(&result.stderr));
+    assert!(result.status.success());
+    for row in stdout.lines().skip(1) {
+        let fields = row.split('\t').collect::<Vec<&str>>();
+        assert_eq!(fields[2..6], fields[6..10], "{}", row);
+        assert_eq!(fields[10..], ["0", "0", "0"], "{}", row);
+    }
+    assert!(stderr.contains("#masked_alignments\t0"), "{}", stderr);
+
+    // Reads with more alignments than --max-group-records are left out
+    let result = output(env!("CARGO_BIN_EXE_simulate_mask"), &["--input", &sam, "--mask", &mask, "--max-group-records", "1"]);
+    let (stdout, stderr) = (String::from_utf8_lossy(&result.stdout), String::from_utf8_lossy(&result.stderr));
+    assert!(result.status.success());
+    assert!(stderr.contains("#skipped_reads\t3") && stderr.contains("oversized_group"), "{}", stderr);
+    assert_eq!(stdout.lines().skip(1).map(|row| row.split('\t').nth(1).unwrap()).collect::<Vec<&str>>(), ["1", "1"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
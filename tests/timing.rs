@@ -0,0 +1,97 @@
+//! --timing breaks the wall time of a run down into read, decompress, parse, count and other,
+//! printed to stderr and kept in the manifest of analyze.
+
+mod common;
+
+use std::{fs, io::Write, process::{Command, Stdio}, thread, time::Duration};
+
+use common::{arg, output, read, run, scratch, SAM};
+use fix_gtdb_mg::utils::SplitMix64;
+use flate2::{write::GzEncoder, Compression};
+
+const PHASES: [&str; 5] = ["read", "decompress", "parse", "count", "other"];
+
+/// Wall seconds and (phase, seconds, percent) of the `Timing:` line of stderr.
+fn breakdown(stderr: &str) -> (f64, Vec<(String, f64, f64)>) {
+    let line = stderr.lines().find_map(|line| line.strip_prefix("Timing: ")).unwrap_or_else(|| panic!("No timing in {}", stderr));
+    let mut parts = line.split(", ");
+    let wall: f64 = parts.next().unwrap().strip_suffix('s').unwrap().parse().unwrap();
+    let phases = parts.map(|part| {
+        let (name, rest) = part.split_once(' ').unwrap();
+        let (seconds, percent) = rest.split_once("s (").unwrap();
+        (name.to_string(), seconds.parse().unwrap(), percent.strip_suffix("%)").unwrap().parse().unwrap())
+    }).collect::<Vec<(String, f64, f64)>>();
+    assert_eq!(phases.iter().map(|(name, _seconds, _percent)| name.as_str()).collect::<Vec<&str>>(), PHASES, "{}", line);
+    // Every phase rounds to the millisecond and the tenth of a percent
+    assert!((phases.iter().map(|phase| phase.1).sum::<f64>() - wall).abs() <= 0.003, "{}", line);
+    assert!((phases.iter().map(|phase| phase.2).sum::<f64>() - 100.0).abs() <= 0.3, "{}", line);
+    (wall, phases)
+}
+
+fn seconds(phases: &[(String, f64, f64)], name: &str) -> f64 {
+    phases.iter().find(|(phase, _seconds, _percent)| phase == name).unwrap().1
+}
+
+/// A SAM of 40 taxa with `reads` reads.
+fn synthetic_sam(reads: u64) -> String {
+    let mut random = SplitMix64::new(17);
+    let header = (1..=40).map(|taxon| format!("@SQ\tSN:{}_1\tLN:500\n", taxon)).collect::<String>();
+    header + &(0..reads).map(|read| format!("{}_1_r{}\t0\t{}_1\t1\t30\t50M\t*\t0\t0\t*\t*\n", random.below(40) + 1, read, random.below(40) + 1)).collect::<String>()
+}
+
+#[test]
+fn timing_breaks_down_the_wall_time() {
+    let dir = scratch("timing");
+    let binary = env!("CARGO_BIN_EXE_pairwise_leakage");
+    let untimed = output(binary, &["--input", SAM]);
+    assert!(!String::from_utf8_lossy(&untimed.stderr).contains("Timing:"));
+
+    // Plain input is not decompressed, gzipped input is
+    let sam = synthetic_sam(50_000);
+    let (plain, gzipped) = (arg(&dir, "reads.sam"), arg(&dir, "reads.sam.gz"));
+    fs::write(&plain, &sam).unwrap();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(sam.as_bytes()).unwrap();
+    fs::write(&gzipped, encoder.finish().unwrap()).unwrap();
+    let timed = |input: &str| {
+        let result = output(binary, &["--input", input, "--timing"]);
+        assert!(result.status.success());
+        // The table is the one of an untimed run
+        assert_eq!(result.stdout, output(binary, &["--input", input]).stdout);
+        breakdown(&String::from_utf8_lossy(&result.stderr))
+    };
+    let (_wall, phases) = timed(&plain);
+    assert_eq!(seconds(&phases, "decompress"), 0.0);
+    assert!(seconds(&phases, "parse") > 0.0, "{:?}", phases);
+    let (_wall, phases) = timed(&gzipped);
+    assert!(seconds(&phases, "decompress") > 0.0 && seconds(&phases, "parse") > 0.0, "{:?}", phases);
+
+    // Input that arrives in bursts is mostly waited for
+    let mut child = Command::new(binary).args(["--input", "-", "--timing"]).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped()).spawn().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let lines = read(SAM);
+    let (header, records) = lines.split_at(lines.find("1_1_r1").unwrap());
+    stdin.write_all(header.as_bytes()).unwrap();
+    for burst in records.lines().collect::<Vec<&str>>().chunks(10) {
+        thread::sleep(Duration::from_millis(100));
+        stdin.write_all((burst.join("\n") + "\n").as_bytes()).unwrap();
+        stdin.flush().unwrap();
+    }
+    drop(stdin);
+    let result = child.wait_with_output().unwrap();
+    assert!(result.status.success());
+    let (wall, phases) = breakdown(&String::from_utf8_lossy(&result.stderr));
+    assert!(wall >= 0.5 && seconds(&phases, "read") > wall / 2.0, "{:?} of {}s", phases, wall);
+
+    // analyze keeps the breakdown in its manifest
+    run(env!("CARGO_BIN_EXE_analyze"), &["--input", SAM, "-o", &arg(&dir, "analysis"), "--timing"]);
+    let manifest = read(dir.join("analysis").join("manifest.json"));
+    let timing = &manifest[manifest.find("\"timing\": {\"wall_ns\": ").unwrap_or_else(|| panic!("No timing in {}", manifest))..];
+    for phase in PHASES {
+        assert!(timing.contains(&format!("\"{}\": {{\"ns\": ", phase)), "{} in {}", phase, timing);
+    }
+    run(env!("CARGO_BIN_EXE_analyze"), &["--input", SAM, "-o", &arg(&dir, "untimed")]);
+    assert!(!read(dir.join("untimed").join("manifest.json")).contains("\"timing\""));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
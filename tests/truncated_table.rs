@@ -0,0 +1,57 @@
+//! A pairwise table cut off mid-stream is logged as truncated input, an error under --strict.
+
+mod common;
+
+use std::{fs, io::Write, path::Path};
+
+use common::{arg, output, read, run, scratch, SAM};
+use flate2::{write::GzEncoder, Compression};
+
+/// A gz pairwise table of the fixture SAM, sorted by the from column for --streaming, with its
+/// last bytes cut off.
+fn truncated_table(dir: &Path) -> String {
+    let table = arg(dir, "pairwise.tsv");
+    run(env!("CARGO_BIN_EXE_pairwise_leakage"), &["--input", SAM, "--output", &table]);
+    let content = read(&table);
+    let (mut header, mut rows): (Vec<&str>, Vec<&str>) = content.lines().partition(|line| line.starts_with('#'));
+    rows.sort_by_key(|row| row.split('\t').next().unwrap().parse::<u64>().unwrap());
+    header.append(&mut rows);
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(format!("{}\n", header.join("\n")).as_bytes()).unwrap();
+    let bytes = encoder.finish().unwrap();
+    let truncated = arg(dir, "truncated.tsv.gz");
+    fs::write(&truncated, &bytes[..bytes.len() - 12]).unwrap();
+    truncated
+}
+
+#[test]
+fn truncated_tables_fail_under_strict() {
+    let dir = scratch("truncated_strict");
+    let table = truncated_table(&dir);
+    let normalize = ["--input", table.as_str(), "--output", &arg(&dir, "normalized.tsv"), "--strict"];
+    let runs: [(&str, Vec<&str>); 4] = [
+        (env!("CARGO_BIN_EXE_normalize_pairwise"), normalize.to_vec()),
+        (env!("CARGO_BIN_EXE_normalize_pairwise"), [&normalize[..], &["--streaming"]].concat()),
+        (env!("CARGO_BIN_EXE_normalize_pairwise"), [&normalize[..], &["--no-genes"]].concat()),
+        (env!("CARGO_BIN_EXE_merge_pairwise"), vec!["--strict", &table, &table]),
+    ];
+    for (binary, args) in runs {
+        let result = output(binary, &args);
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert_eq!(result.status.code(), Some(1), "{} {:?}: {}", binary, args, stderr);
+        assert!(stderr.contains(&format!("truncated_input in {}", table)), "{} {:?}: {}", binary, args, stderr);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn truncated_tables_are_logged_without_strict() {
+    let dir = scratch("truncated_lenient");
+    let table = truncated_table(&dir);
+    let result = output(env!("CARGO_BIN_EXE_normalize_pairwise"), &["--input", &table, "--output", &arg(&dir, "normalized.tsv")]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.lines().any(|line| line.starts_with("truncated_input\t1\t")), "{}", stderr);
+    fs::remove_dir_all(&dir).unwrap();
+}